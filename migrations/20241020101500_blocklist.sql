@@ -0,0 +1,10 @@
+-- Domains loaded from the blocklists, deduplicated by the primary key
+CREATE TABLE IF NOT EXISTS blocked_domains (
+    domain VARCHAR(256) PRIMARY KEY NOT NULL,
+    source VARCHAR(1024) NOT NULL
+);
+
+-- Domains that are never blocked, even if present in a blocklist
+CREATE TABLE IF NOT EXISTS allowed_domains (
+    domain VARCHAR(256) PRIMARY KEY NOT NULL
+);
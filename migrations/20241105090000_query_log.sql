@@ -0,0 +1,15 @@
+-- Queries answered, written when the query log is enabled with the "sqlite" target
+CREATE TABLE IF NOT EXISTS query_log (
+    id INTEGER PRIMARY KEY,
+    timestamp TIMESTAMP NOT NULL,
+    client VARCHAR(39) NOT NULL,
+    qname VARCHAR(256) NOT NULL,
+    qtype VARCHAR(16) NOT NULL,
+    rcode VARCHAR(16) NOT NULL,
+    answers INTEGER NOT NULL,
+    latency_ms REAL NOT NULL,
+    cache_hit BOOLEAN NOT NULL
+);
+
+-- The old entries are removed by timestamp
+CREATE INDEX IF NOT EXISTS query_log_timestamp ON query_log (timestamp);
@@ -0,0 +1,14 @@
+-- Queries counted by domain, client and query type, in buckets of an hour
+-- rolled up into buckets of a day; `bucket` is the start of the bucket
+-- and `span` its length, in seconds
+CREATE TABLE IF NOT EXISTS query_stats (
+    bucket INTEGER NOT NULL,
+    span INTEGER NOT NULL,
+    kind VARCHAR(16) NOT NULL,
+    key VARCHAR(256) NOT NULL,
+    queries INTEGER NOT NULL,
+    blocked INTEGER NOT NULL,
+    PRIMARY KEY (bucket, span, kind, key)
+);
+
+CREATE INDEX IF NOT EXISTS query_stats_kind ON query_stats (kind, bucket);
@@ -0,0 +1,4 @@
+-- Network of the clients a cached record may be served to, as `<address>/<prefix>`,
+-- the answers given for a client subnet (EDNS Client Subnet) are only valid within it;
+-- NULL for the records valid for every client
+ALTER TABLE entries ADD COLUMN ecs_network VARCHAR(43);
@@ -0,0 +1,13 @@
+-- A record is cached once per network it's valid for, resolving it again refreshes it:
+-- the duplicates are removed, the last one cached is kept
+DELETE FROM entries WHERE id NOT IN (
+    SELECT MAX(id) FROM entries
+    GROUP BY domain COLLATE NOCASE, record_type, COALESCE(address, ''), COALESCE(host, ''), COALESCE(ecs_network, '')
+);
+CREATE UNIQUE INDEX IF NOT EXISTS entries_record ON entries (
+    domain COLLATE NOCASE,
+    record_type,
+    COALESCE(address, ''),
+    COALESCE(host, ''),
+    COALESCE(ecs_network, '')
+);
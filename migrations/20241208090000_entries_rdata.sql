@@ -0,0 +1,12 @@
+-- Records of every type are cached: their data section is stored as it's written
+-- on the wire, `address`, `host` and `priority` are kept for the ones reading them.
+-- The entries cached so far lack it, the cache starts over
+DELETE FROM entries;
+ALTER TABLE entries ADD COLUMN rdata BLOB NOT NULL DEFAULT x'';
+DROP INDEX IF EXISTS entries_record;
+CREATE UNIQUE INDEX IF NOT EXISTS entries_record ON entries (
+    domain COLLATE NOCASE,
+    record_type,
+    rdata,
+    COALESCE(ecs_network, '')
+);
@@ -0,0 +1,13 @@
+-- Block and allow rules added through the API, each applies to a domain and its subdomains
+CREATE TABLE IF NOT EXISTS managed_rules (
+    domain VARCHAR(256) PRIMARY KEY NOT NULL,
+    action VARCHAR(8) NOT NULL
+);
+
+-- Addresses answered for a name, added through the API
+CREATE TABLE IF NOT EXISTS local_records (
+    name VARCHAR(256) NOT NULL,
+    address VARCHAR(64) NOT NULL,
+    ttl INTEGER NOT NULL,
+    PRIMARY KEY (name, address)
+);
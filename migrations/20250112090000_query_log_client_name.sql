@@ -0,0 +1,2 @@
+-- Name of the client in the client table, when it has one
+ALTER TABLE query_log ADD COLUMN client_name VARCHAR(253);
@@ -0,0 +1,40 @@
+-- The cache and the statistics shared by the replicas of the resolver,
+-- the same tables the SQLite database of each server has.
+-- A record is cached once per network it's valid for, `rdata` is its data section
+-- as it's written on the wire, `address`, `host` and `priority` are kept for the ones reading them
+CREATE TABLE IF NOT EXISTS entries (
+    id BIGSERIAL PRIMARY KEY,
+    address VARCHAR(45),
+    host VARCHAR(256),
+    priority INTEGER,
+    domain VARCHAR(256) NOT NULL,
+    expiration_date TIMESTAMPTZ NOT NULL,
+    ttl BIGINT NOT NULL,
+    record_type INTEGER NOT NULL,
+    ecs_network VARCHAR(43),
+    rdata BYTEA NOT NULL
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS entries_record ON entries (
+    LOWER(domain),
+    record_type,
+    rdata,
+    COALESCE(ecs_network, '')
+);
+
+CREATE INDEX IF NOT EXISTS entries_expiration ON entries (expiration_date);
+
+-- Queries counted by domain, client and query type, in buckets of an hour
+-- rolled up into buckets of a day; `bucket` is the start of the bucket
+-- and `span` its length, in seconds
+CREATE TABLE IF NOT EXISTS query_stats (
+    bucket BIGINT NOT NULL,
+    span BIGINT NOT NULL,
+    kind VARCHAR(16) NOT NULL,
+    key VARCHAR(256) NOT NULL,
+    queries BIGINT NOT NULL,
+    blocked BIGINT NOT NULL,
+    PRIMARY KEY (bucket, span, kind, key)
+);
+
+CREATE INDEX IF NOT EXISTS query_stats_kind ON query_stats (kind, bucket);
@@ -0,0 +1,6 @@
+-- The network a record is served to is also kept as the range of its addresses,
+-- IPv4 ones mapped to IPv6, compared byte by byte: the cache is searched for the records
+-- valid for the client of a query. The records given for a subnet so far lack it
+DELETE FROM entries WHERE ecs_network IS NOT NULL;
+ALTER TABLE entries ADD COLUMN ecs_first BYTEA;
+ALTER TABLE entries ADD COLUMN ecs_last BYTEA;
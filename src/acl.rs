@@ -0,0 +1,74 @@
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+use serde::Deserialize;
+
+use crate::configuration::Settings;
+
+/// # `DeniedAction`
+///
+/// What happens to the queries of the clients that aren't allowed to query us.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DeniedAction {
+    /// The client receives a REFUSED response.
+    #[default]
+    Refused,
+    /// The query is silently dropped.
+    Drop,
+}
+
+/// # `NetworkList`
+///
+/// Allow and deny lists of networks, the deny list takes precedence
+/// and an empty allow list allows every client.
+#[derive(Debug, Clone, Default)]
+pub struct NetworkList {
+    pub allow: Vec<IpNet>,
+    pub deny: Vec<IpNet>,
+}
+
+impl NetworkList {
+    /// # `permits`
+    ///
+    /// Returns true if `client` is allowed by the list.
+    pub fn permits(&self, client: IpAddr) -> bool {
+        !self.deny.iter().any(|net| net.contains(&client))
+            && (self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&client)))
+    }
+}
+
+/// # `Acl`
+///
+/// Access control for the clients: who can query the server and who can
+/// ask for recursion.
+#[derive(Debug, Clone, Default)]
+pub struct Acl {
+    pub query: NetworkList,
+    pub recursion: NetworkList,
+    pub denied_action: DeniedAction,
+}
+
+impl Acl {
+    pub fn from_settings(settings: &Settings) -> Self {
+        Acl {
+            query: settings.get_query_acl(),
+            recursion: settings.get_recursion_acl(),
+            denied_action: settings.get_denied_action(),
+        }
+    }
+
+    /// # `may_query`
+    ///
+    /// Returns true if `client` is allowed to query the server.
+    pub fn may_query(&self, client: IpAddr) -> bool {
+        self.query.permits(client)
+    }
+
+    /// # `may_recurse`
+    ///
+    /// Returns true if `client` is allowed to ask for recursion.
+    pub fn may_recurse(&self, client: IpAddr) -> bool {
+        self.recursion.permits(client)
+    }
+}
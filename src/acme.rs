@@ -0,0 +1,820 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    io::Write,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{extract::State, http::StatusCode, routing::get, Router};
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use reqwest::header::{CONTENT_TYPE, LOCATION};
+use ring::{
+    digest,
+    rand::SystemRandom,
+    signature::{
+        EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING, ECDSA_P256_SHA256_FIXED_SIGNING,
+    },
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use tokio::{net::TcpListener, time::sleep};
+use tokio_rustls::rustls::pki_types::{pem::PemObject, CertificateDer};
+
+use crate::{
+    certificates::CertificateStore,
+    configuration::Settings,
+    dnssec::ZoneSigner,
+    structs::{
+        auxiliaries::{CResult, DnsError},
+        questions_and_records::Record,
+    },
+    zones::ZoneStore,
+};
+
+/// Time waited before trying to obtain a certificate again after a failure.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Longest time between two checks of the expiration of the certificate,
+/// it may be replaced by someone else in the meantime.
+const CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Time between two checks of the state of an authorization or of an order.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// Checks of the state of an authorization or of an order before giving up.
+const POLL_ATTEMPTS: u32 = 90;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Times a request is sent again when the CA refuses its nonce.
+const NONCE_ATTEMPTS: u32 = 3;
+const BAD_NONCE: &str = "urn:ietf:params:acme:error:badNonce";
+/// TXT records, not among the types we know of.
+const TXT: u16 = 16;
+/// TTL of the TXT records of the DNS-01 challenges.
+const CHALLENGE_TTL: u32 = 60;
+
+// DER encoding of the certificate requests (RFC 2986)
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+const INTEGER: u8 = 0x02;
+const BIT_STRING: u8 = 0x03;
+const OCTET_STRING: u8 = 0x04;
+const OBJECT_IDENTIFIER: u8 = 0x06;
+const UTC_TIME: u8 = 0x17;
+const GENERALIZED_TIME: u8 = 0x18;
+/// `[0]`, the attributes of a request and the version of a certificate.
+const CONTEXT_0: u8 = 0xa0;
+/// `[2]`, a dNSName of the subject alternative names.
+const DNS_NAME: u8 = 0x82;
+/// 1.2.840.10045.2.1
+const EC_PUBLIC_KEY: [u8; 7] = [0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+/// 1.2.840.10045.3.1.7
+const PRIME256V1: [u8; 8] = [0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+/// 1.2.840.10045.4.3.2
+const ECDSA_WITH_SHA256: [u8; 8] = [0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+/// 1.2.840.113549.1.9.14
+const EXTENSION_REQUEST: [u8; 9] = [0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x0e];
+/// 2.5.29.17
+const SUBJECT_ALT_NAME: [u8; 3] = [0x55, 0x1d, 0x11];
+
+/// # `AcmeChallenge`
+///
+/// How the CA checks that we control the names of the certificate (RFC 8555 section 8).
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AcmeChallenge {
+    /// A file served by the dashboard under `/.well-known/acme-challenge/`,
+    /// the dashboard has to be reachable on port 80 of the names.
+    #[default]
+    #[serde(rename = "http-01")]
+    Http01,
+    /// A TXT record added to the zone holding the names, the zone has to be
+    /// served by us to the CA.
+    #[serde(rename = "dns-01")]
+    Dns01,
+}
+
+impl AcmeChallenge {
+    /// Type of the challenge in the authorizations.
+    fn name(self) -> &'static str {
+        match self {
+            AcmeChallenge::Http01 => "http-01",
+            AcmeChallenge::Dns01 => "dns-01",
+        }
+    }
+}
+
+/// # `Http01Responses`
+///
+/// Key authorizations of the HTTP-01 challenges in progress by their token,
+/// served by `serve_http01`.
+#[derive(Debug, Default)]
+pub struct Http01Responses {
+    tokens: Mutex<HashMap<String, String>>,
+}
+
+impl Http01Responses {
+    pub fn get(&self, token: &str) -> Option<String> {
+        self.tokens.lock().unwrap().get(token).cloned()
+    }
+
+    fn insert(&self, token: &str, key_authorization: String) {
+        self.tokens
+            .lock()
+            .unwrap()
+            .insert(token.to_string(), key_authorization);
+    }
+
+    fn remove(&self, token: &str) {
+        self.tokens.lock().unwrap().remove(token);
+    }
+}
+
+/// # `serve_http01`
+///
+/// Answers the HTTP-01 challenges in progress on `addr`, under
+/// `GET /.well-known/acme-challenge/{token}` (RFC 8555 section 8.3).
+/// It's the only endpoint served there, as the CA has to reach it from the internet.
+pub async fn serve_http01(addr: SocketAddr, challenges: Arc<Http01Responses>) -> io::Result<()> {
+    let app = Router::new()
+        .route("/.well-known/acme-challenge/:token", get(http01_response))
+        .with_state(challenges);
+    let listener = TcpListener::bind(addr).await?;
+    tracing::info!("Answering the HTTP-01 challenges on {}", addr);
+    axum::serve(listener, app).await
+}
+
+async fn http01_response(
+    State(challenges): State<Arc<Http01Responses>>,
+    axum::extract::Path(token): axum::extract::Path<String>,
+) -> Result<String, StatusCode> {
+    challenges.get(&token).ok_or(StatusCode::NOT_FOUND)
+}
+
+/// # `Acme`
+///
+/// Obtains the certificate of the DoH endpoint from an ACME CA (RFC 8555), as Let's Encrypt,
+/// and renews it before it expires. The certificate and its key are written where the
+/// endpoint reads them, the key of the account at the CA is kept in its own file.
+pub struct Acme {
+    directory: String,
+    contact: Option<String>,
+    domains: Vec<String>,
+    challenge: AcmeChallenge,
+    account_key: PathBuf,
+    renew_before: Duration,
+    certificate: PathBuf,
+    private_key: PathBuf,
+    certificates: Arc<CertificateStore>,
+    http01: Arc<Http01Responses>,
+    zones: Arc<ZoneStore>,
+    signer: Arc<ZoneSigner>,
+}
+
+impl Acme {
+    /// # `from_settings`
+    ///
+    /// `None` if the certificate isn't obtained with ACME.
+    pub fn from_settings(
+        settings: &Settings,
+        certificates: Arc<CertificateStore>,
+        http01: Arc<Http01Responses>,
+        zones: Arc<ZoneStore>,
+        signer: Arc<ZoneSigner>,
+    ) -> Option<Self> {
+        if !settings.get_acme_enabled() {
+            return None;
+        }
+        Some(Acme {
+            directory: settings.get_acme_directory().to_string(),
+            contact: settings.get_acme_contact().map(str::to_string),
+            domains: settings.get_acme_domains().to_vec(),
+            challenge: settings.get_acme_challenge(),
+            account_key: settings.get_acme_account_key(),
+            renew_before: settings.get_acme_renew_before(),
+            certificate: settings.get_doh_certificate(),
+            private_key: settings.get_doh_private_key(),
+            certificates,
+            http01,
+            zones,
+            signer,
+        })
+    }
+
+    /// # `run`
+    ///
+    /// Obtains a certificate whenever the current one is missing or expires within
+    /// the renewal period, the attempts that fail are repeated every `RETRY_INTERVAL`.
+    #[tracing::instrument(name = "Obtaining certificates with ACME", skip(self), fields(domains = ?self.domains))]
+    pub async fn run(self) {
+        loop {
+            let remaining = expiration(&self.certificate)
+                .and_then(|expiration| (expiration - Utc::now()).to_std().ok())
+                .unwrap_or(Duration::ZERO);
+            let wait = remaining.saturating_sub(self.renew_before);
+            if !wait.is_zero() {
+                sleep(wait.min(CHECK_INTERVAL)).await;
+                continue;
+            }
+            match self.issue().await {
+                Ok(()) => tracing::info!("Obtained a certificate for {:?}", self.domains),
+                Err(e) => {
+                    tracing::error!(
+                        "Unable to obtain a certificate for {:?}, trying again in {} minutes: {}",
+                        self.domains,
+                        RETRY_INTERVAL.as_secs() / 60,
+                        e
+                    );
+                    sleep(RETRY_INTERVAL).await;
+                }
+            }
+        }
+    }
+
+    /// # `issue`
+    ///
+    /// Orders a certificate for the domains, completes the challenges of its authorizations,
+    /// then stores the certificate along with its new key and presents it.
+    async fn issue(&self) -> CResult<()> {
+        let account =
+            Account::open(&self.directory, &self.account_key, self.contact.as_deref()).await?;
+        let identifiers: Vec<Value> = self
+            .domains
+            .iter()
+            .map(|domain| json!({ "type": "dns", "value": domain }))
+            .collect();
+        let (order, order_url) = account
+            .request::<Order>(
+                &account.directory.new_order,
+                Some(&json!({ "identifiers": identifiers })),
+            )
+            .await?;
+        let order_url = order_url.ok_or("The CA didn't tell where the order is")?;
+        for authorization in &order.authorizations {
+            self.authorize(&account, authorization).await?;
+        }
+        let order = account.poll_order(&order_url, "ready").await?;
+
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng)
+            .map_err(|_| "Failed to generate the key of the certificate")?;
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref(), &rng)
+            .map_err(|e| format!("Invalid key for the certificate: {}", e))?;
+        let csr = certificate_request(&self.domains, &key)?;
+        account
+            .post(
+                &order.finalize,
+                Some(&json!({ "csr": URL_SAFE_NO_PAD.encode(csr) })),
+            )
+            .await?;
+        let order = account.poll_order(&order_url, "valid").await?;
+        let certificate_url = order
+            .certificate
+            .ok_or("The CA didn't tell where the certificate is")?;
+        let chain = account.post(&certificate_url, None).await?.text().await?;
+
+        write_private(
+            &self.private_key,
+            pem("PRIVATE KEY", pkcs8.as_ref()).as_bytes(),
+        )?;
+        write_private(&self.certificate, chain.as_bytes())?;
+        self.certificates.reload()
+    }
+
+    /// # `authorize`
+    ///
+    /// Proves to the CA that we control the domain of the authorization at `url`,
+    /// with the challenge chosen in the settings.
+    async fn authorize(&self, account: &Account, url: &str) -> CResult<()> {
+        let (authorization, _) = account.request::<Authorization>(url, None).await?;
+        if authorization.status == "valid" {
+            return Ok(());
+        }
+        let domain = authorization.identifier.value;
+        let challenge = authorization
+            .challenges
+            .into_iter()
+            .find(|challenge| challenge.kind == self.challenge.name())
+            .ok_or(format!(
+                "The CA doesn't offer the {} challenge for {}",
+                self.challenge.name(),
+                domain
+            ))?;
+        let key_authorization = format!("{}.{}", challenge.token, account.thumbprint);
+        let txt_name = format!("_acme-challenge.{}", domain);
+        let txt_value = URL_SAFE_NO_PAD.encode(digest::digest(
+            &digest::SHA256,
+            key_authorization.as_bytes(),
+        ));
+        match self.challenge {
+            AcmeChallenge::Http01 => self.http01.insert(&challenge.token, key_authorization),
+            AcmeChallenge::Dns01 => self.set_txt(&txt_name, &txt_value, true).await?,
+        }
+        let validated = async {
+            account.post(&challenge.url, Some(&json!({}))).await?;
+            account.poll_authorization(url).await
+        }
+        .await;
+        match self.challenge {
+            AcmeChallenge::Http01 => self.http01.remove(&challenge.token),
+            AcmeChallenge::Dns01 => {
+                if let Err(e) = self.set_txt(&txt_name, &txt_value, false).await {
+                    tracing::warn!("Unable to remove the TXT record of {}: {}", txt_name, e);
+                }
+            }
+        }
+        validated
+    }
+
+    /// # `set_txt`
+    ///
+    /// Adds, or removes, the TXT record of a DNS-01 challenge to the zone served by us
+    /// that contains `name`, signing the zone again if it's signed.
+    async fn set_txt(&self, name: &str, value: &str, present: bool) -> CResult<()> {
+        let mut zone = self
+            .zones
+            .zone_for(name)
+            .ok_or(format!("No zone served by us contains {}", name))?;
+        let mut data = vec![value.len() as u8];
+        data.extend_from_slice(value.as_bytes());
+        let record = Record::UNKNOWN {
+            domain: name.into(),
+            qtype: TXT,
+            data,
+            ttl: CHALLENGE_TTL,
+        };
+        zone.records.retain(|r| r != &record);
+        if present {
+            zone.records.push(record);
+        }
+        if zone.is_signed() {
+            let signer = self.signer.clone();
+            zone = tokio::task::spawn_blocking(move || signer.sign_zone(&mut zone).map(|_| zone))
+                .await
+                .map_err(|e| DnsError::Other(e.to_string()))??;
+        }
+        self.zones.insert(zone);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Order {
+    status: String,
+    #[serde(default)]
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+    error: Option<Problem>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Authorization {
+    status: String,
+    identifier: Identifier,
+    #[serde(default)]
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    #[serde(default)]
+    token: String,
+    error: Option<Problem>,
+}
+
+/// # `Problem`
+///
+/// Why the CA refused a request (RFC 7807).
+#[derive(Debug, Deserialize, Default)]
+struct Problem {
+    #[serde(rename = "type", default)]
+    kind: String,
+    #[serde(default)]
+    detail: String,
+}
+
+/// # `Account`
+///
+/// Our account at the CA, the requests are signed with its key (RFC 8555 section 6.2).
+struct Account {
+    client: reqwest::Client,
+    directory: Directory,
+    key: EcdsaKeyPair,
+    jwk: Value,
+    /// Thumbprint of the key (RFC 7638), part of the key authorizations.
+    thumbprint: String,
+    /// URL of the account, `None` until it has been found or created.
+    kid: Option<String>,
+    nonce: Mutex<Option<String>>,
+}
+
+impl Account {
+    /// # `open`
+    ///
+    /// Finds the account of the key stored at `key_path` at the CA, creating
+    /// the account, and the key if the file doesn't exist.
+    async fn open(directory_url: &str, key_path: &Path, contact: Option<&str>) -> CResult<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+        let directory = client
+            .get(directory_url)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        let directory: Directory = serde_json::from_slice(&directory)
+            .map_err(|e| format!("Invalid ACME directory at {}: {}", directory_url, e))?;
+        let key = load_or_generate(key_path)?;
+        // The uncompressed point: 0x04, then the coordinates
+        let point = key.public_key().as_ref();
+        let (x, y) = (
+            URL_SAFE_NO_PAD.encode(&point[1..33]),
+            URL_SAFE_NO_PAD.encode(&point[33..]),
+        );
+        // The members of the JWK in lexicographic order, as the thumbprint requires
+        let thumbprint = format!(r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#, x, y);
+        let mut account = Account {
+            client,
+            directory,
+            key,
+            jwk: json!({ "crv": "P-256", "kty": "EC", "x": x, "y": y }),
+            thumbprint: URL_SAFE_NO_PAD
+                .encode(digest::digest(&digest::SHA256, thumbprint.as_bytes())),
+            kid: None,
+            nonce: Mutex::new(None),
+        };
+        let mut new_account = json!({ "termsOfServiceAgreed": true });
+        if let Some(contact) = contact {
+            new_account["contact"] = json!([format!("mailto:{}", contact)]);
+        }
+        // An account that already exists is returned as well
+        let response = account
+            .post(&account.directory.new_account, Some(&new_account))
+            .await?;
+        account.kid = Some(location(&response).ok_or("The CA didn't tell where the account is")?);
+        Ok(account)
+    }
+
+    /// # `request`
+    ///
+    /// Sends a signed request and parses the JSON it's answered with, along with
+    /// the `Location` of the resource it refers to.
+    async fn request<T: DeserializeOwned>(
+        &self,
+        url: &str,
+        payload: Option<&Value>,
+    ) -> CResult<(T, Option<String>)> {
+        let response = self.post(url, payload).await?;
+        let location = location(&response);
+        let body = response.bytes().await?;
+        let parsed = serde_json::from_slice(&body)
+            .map_err(|e| format!("Unexpected response from {}: {}", url, e))?;
+        Ok((parsed, location))
+    }
+
+    /// # `post`
+    ///
+    /// Sends `payload` to `url` signed with the key of the account, without a payload
+    /// the request is a POST-as-GET. Fails if the CA answers with an error.
+    async fn post(&self, url: &str, payload: Option<&Value>) -> CResult<reqwest::Response> {
+        let mut problem = Problem::default();
+        for _ in 0..NONCE_ATTEMPTS {
+            let nonce = self.nonce().await?;
+            let body = self.sign(url, &nonce, payload)?;
+            let response = self
+                .client
+                .post(url)
+                .header(CONTENT_TYPE, "application/jose+json")
+                .body(body)
+                .send()
+                .await?;
+            *self.nonce.lock().unwrap() = replay_nonce(&response);
+            if response.status().is_success() {
+                return Ok(response);
+            }
+            let status = response.status();
+            problem = serde_json::from_slice(&response.bytes().await?).unwrap_or_default();
+            // A nonce that's been refused is replaced by the one of the response
+            if problem.kind != BAD_NONCE {
+                return Err(format!("{} answered {}: {}", url, status, problem.detail).into());
+            }
+        }
+        Err(format!("{} kept refusing our nonces: {}", url, problem.detail).into())
+    }
+
+    /// # `nonce`
+    ///
+    /// The nonce received with the last response, a new one if it's been used.
+    async fn nonce(&self) -> CResult<String> {
+        if let Some(nonce) = self.nonce.lock().unwrap().take() {
+            return Ok(nonce);
+        }
+        let response = self
+            .client
+            .head(&self.directory.new_nonce)
+            .send()
+            .await?
+            .error_for_status()?;
+        replay_nonce(&response).ok_or("The CA didn't provide a nonce".into())
+    }
+
+    /// # `sign`
+    ///
+    /// The JWS carrying `payload` in the flattened JSON serialization, identifying
+    /// the account by its URL once known, by its key before.
+    fn sign(&self, url: &str, nonce: &str, payload: Option<&Value>) -> CResult<String> {
+        let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+        match &self.kid {
+            Some(kid) => protected["kid"] = json!(kid),
+            None => protected["jwk"] = self.jwk.clone(),
+        }
+        let protected = URL_SAFE_NO_PAD.encode(protected.to_string());
+        let payload = match payload {
+            Some(payload) => URL_SAFE_NO_PAD.encode(payload.to_string()),
+            None => String::new(),
+        };
+        let signature = self
+            .key
+            .sign(
+                &SystemRandom::new(),
+                format!("{}.{}", protected, payload).as_bytes(),
+            )
+            .map_err(|_| "Failed to sign an ACME request")?;
+        Ok(json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": URL_SAFE_NO_PAD.encode(signature.as_ref()),
+        })
+        .to_string())
+    }
+
+    /// # `poll_authorization`
+    ///
+    /// Waits for the CA to validate the authorization at `url`.
+    async fn poll_authorization(&self, url: &str) -> CResult<()> {
+        for _ in 0..POLL_ATTEMPTS {
+            let (authorization, _) = self.request::<Authorization>(url, None).await?;
+            match authorization.status.as_str() {
+                "valid" => return Ok(()),
+                "pending" => sleep(POLL_INTERVAL).await,
+                status => {
+                    let detail = authorization
+                        .challenges
+                        .iter()
+                        .find_map(|challenge| challenge.error.as_ref())
+                        .map(|problem| problem.detail.as_str())
+                        .unwrap_or_default();
+                    return Err(format!(
+                        "The authorization of {} is {}: {}",
+                        authorization.identifier.value, status, detail
+                    )
+                    .into());
+                }
+            }
+        }
+        Err(format!("The authorization at {} is still pending", url).into())
+    }
+
+    /// # `poll_order`
+    ///
+    /// Waits for the order at `url` to reach `status`.
+    async fn poll_order(&self, url: &str, status: &str) -> CResult<Order> {
+        for _ in 0..POLL_ATTEMPTS {
+            let (order, _) = self.request::<Order>(url, None).await?;
+            if order.status == status {
+                return Ok(order);
+            }
+            if !matches!(order.status.as_str(), "pending" | "ready" | "processing") {
+                let detail = order
+                    .error
+                    .map(|problem| problem.detail)
+                    .unwrap_or_default();
+                return Err(format!("The order is {}: {}", order.status, detail).into());
+            }
+            sleep(POLL_INTERVAL).await;
+        }
+        Err(format!("The order at {} isn't {} yet", url, status).into())
+    }
+}
+
+fn location(response: &reqwest::Response) -> Option<String> {
+    response
+        .headers()
+        .get(LOCATION)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+fn replay_nonce(response: &reqwest::Response) -> Option<String> {
+    response
+        .headers()
+        .get("Replay-Nonce")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+/// # `load_or_generate`
+///
+/// Loads the account key stored in PKCS#8 format at `path`, a new key is generated
+/// and stored there if the file doesn't exist.
+fn load_or_generate(path: &Path) -> CResult<EcdsaKeyPair> {
+    let rng = SystemRandom::new();
+    let pkcs8 = match fs::read(path) {
+        Ok(pkcs8) => pkcs8,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            tracing::info!("Generating a new ACME account key in {}", path.display());
+            let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+                .map_err(|_| "Failed to generate an ACME account key")?;
+            write_private(path, pkcs8.as_ref())?;
+            pkcs8.as_ref().to_vec()
+        }
+        Err(e) => return Err(e.into()),
+    };
+    EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8, &rng)
+        .map_err(|e| format!("Invalid ACME account key in {}: {}", path.display(), e).into())
+}
+
+/// # `write_private`
+///
+/// Replaces the content of `path` at once, readable only by us.
+pub(crate) fn write_private(path: &Path, content: &[u8]) -> CResult<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let temporary = path.with_extension("tmp");
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(&temporary)?.write_all(content)?;
+    fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+fn pem(label: &str, der: &[u8]) -> String {
+    let encoded = STANDARD.encode(der);
+    let mut pem = format!("-----BEGIN {}-----\n", label);
+    for line in encoded.as_bytes().chunks(64) {
+        pem.push_str(&String::from_utf8_lossy(line));
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {}-----\n", label));
+    pem
+}
+
+/// # `certificate_request`
+///
+/// PKCS#10 request (RFC 2986) for a certificate of `domains`, signed with `key`.
+/// The names are only in the subject alternative names, the subject is empty.
+fn certificate_request(domains: &[String], key: &EcdsaKeyPair) -> CResult<Vec<u8>> {
+    let names: Vec<u8> = domains
+        .iter()
+        .flat_map(|domain| der(DNS_NAME, domain.as_bytes()))
+        .collect();
+    let subject_alt_name = [
+        der(OBJECT_IDENTIFIER, &SUBJECT_ALT_NAME),
+        der(OCTET_STRING, &der(SEQUENCE, &names)),
+    ]
+    .concat();
+    let extensions = der(SEQUENCE, &der(SEQUENCE, &subject_alt_name));
+    let attribute = der(
+        SEQUENCE,
+        &[
+            der(OBJECT_IDENTIFIER, &EXTENSION_REQUEST),
+            der(SET, &extensions),
+        ]
+        .concat(),
+    );
+    let algorithm = der(
+        SEQUENCE,
+        &[
+            der(OBJECT_IDENTIFIER, &EC_PUBLIC_KEY),
+            der(OBJECT_IDENTIFIER, &PRIME256V1),
+        ]
+        .concat(),
+    );
+    // Bit strings start with the count of unused bits
+    let public_key = [&[0u8][..], key.public_key().as_ref()].concat();
+    let public_key_info = der(
+        SEQUENCE,
+        &[algorithm, der(BIT_STRING, &public_key)].concat(),
+    );
+    let info = der(
+        SEQUENCE,
+        &[
+            der(INTEGER, &[0]),
+            der(SEQUENCE, &[]),
+            public_key_info,
+            der(CONTEXT_0, &attribute),
+        ]
+        .concat(),
+    );
+    let signature = key
+        .sign(&SystemRandom::new(), &info)
+        .map_err(|_| "Failed to sign the certificate request")?;
+    let signature = [&[0u8][..], signature.as_ref()].concat();
+    Ok(der(
+        SEQUENCE,
+        &[
+            info,
+            der(SEQUENCE, &der(OBJECT_IDENTIFIER, &ECDSA_WITH_SHA256)),
+            der(BIT_STRING, &signature),
+        ]
+        .concat(),
+    ))
+}
+
+/// DER encoding of a value with `tag`, the length in its shortest form.
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![tag];
+    if content.len() < 0x80 {
+        encoded.push(content.len() as u8);
+    } else {
+        let len: Vec<u8> = content
+            .len()
+            .to_be_bytes()
+            .into_iter()
+            .skip_while(|byte| *byte == 0)
+            .collect();
+        encoded.push(0x80 | len.len() as u8);
+        encoded.extend(len);
+    }
+    encoded.extend_from_slice(content);
+    encoded
+}
+
+/// # `expiration`
+///
+/// When the first certificate of the PEM file at `path` expires, `None` if
+/// there's no certificate that can be read.
+fn expiration(path: &Path) -> Option<DateTime<Utc>> {
+    let certificate = CertificateDer::pem_file_iter(path).ok()?.next()?.ok()?;
+    let (_, certificate, _) = read_der(&certificate)?;
+    let (_, tbs, _) = read_der(certificate)?;
+    // The version is optional
+    let (tag, _, mut fields) = read_der(tbs)?;
+    if tag != CONTEXT_0 {
+        fields = tbs;
+    }
+    // Serial number, signature algorithm and issuer come before the validity
+    for _ in 0..3 {
+        fields = read_der(fields)?.2;
+    }
+    let (_, validity, _) = read_der(fields)?;
+    let (_, _, not_after) = read_der(validity)?;
+    let (tag, not_after, _) = read_der(not_after)?;
+    let format = match tag {
+        UTC_TIME => "%y%m%d%H%M%SZ",
+        GENERALIZED_TIME => "%Y%m%d%H%M%SZ",
+        _ => return None,
+    };
+    NaiveDateTime::parse_from_str(std::str::from_utf8(not_after).ok()?, format)
+        .ok()
+        .map(|not_after| not_after.and_utc())
+}
+
+/// Splits the first DER value of `data` into its tag and content, along with what follows it.
+fn read_der(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, data) = data.split_first()?;
+    let (&first, mut data) = data.split_first()?;
+    let len = if first < 0x80 {
+        first as usize
+    } else {
+        let len_len = (first & 0x7f) as usize;
+        if len_len == 0 || len_len > 4 || data.len() < len_len {
+            return None;
+        }
+        let (len, rest) = data.split_at(len_len);
+        data = rest;
+        len.iter().fold(0, |len, byte| (len << 8) | *byte as usize)
+    };
+    if data.len() < len {
+        return None;
+    }
+    let (content, rest) = data.split_at(len);
+    Some((tag, content, rest))
+}
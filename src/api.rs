@@ -0,0 +1,314 @@
+use std::{io, net::SocketAddr, sync::Arc};
+
+use axum::{
+    extract::{Path, Query, Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+    Json, Router,
+};
+use chrono::{DateTime, Duration, Local};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::net::TcpListener;
+
+use crate::{
+    clients::ClientName,
+    control::Reloader,
+    idn,
+    overrides::{LocalRecord, ManagedRule},
+    stats::{self, StatKind, StatRow},
+    structs::{auxiliaries::DnsError, db_queries::CachedRecord, questions_and_records::QueryType},
+    workers::ServerState,
+    zones::is_subdomain,
+};
+
+/// Entries listed for each ranking of `GET /api/stats`.
+const TOP: u32 = 10;
+
+/// # `ApiState`
+///
+/// What the handlers of the management API work on.
+pub struct ApiState {
+    server: Arc<ServerState>,
+    token: String,
+    reloader: Option<Reloader>,
+}
+
+impl ApiState {
+    pub fn new(server: Arc<ServerState>, token: String, reloader: Option<Reloader>) -> Self {
+        ApiState {
+            server,
+            token,
+            reloader,
+        }
+    }
+}
+
+/// # `ApiError`
+///
+/// A request that failed, answered with its status and a JSON body
+/// holding the reason: `{"error": "..."}`.
+#[derive(Debug)]
+pub struct ApiError(StatusCode, String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(json!({ "error": self.1 }))).into_response()
+    }
+}
+
+impl From<DnsError> for ApiError {
+    fn from(e: DnsError) -> Self {
+        let status = match e {
+            // The other errors are ours, not the client's
+            DnsError::InvalidName(_) => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        ApiError(status, e.to_string())
+    }
+}
+
+/// # `CacheFilter`
+///
+/// Which entries of the cache a request concerns, every entry if empty.
+#[derive(Debug, Deserialize, Default)]
+pub struct CacheFilter {
+    /// Only the entries of this domain and its subdomains.
+    pub domain: Option<String>,
+}
+
+/// # `CacheEntry`
+///
+/// A record of the cache as the API lists it.
+#[derive(Debug, Serialize)]
+pub struct CacheEntry {
+    pub domain: String,
+    #[serde(rename = "type")]
+    pub record_type: String,
+    pub ttl: u32,
+    pub data: String,
+    pub expires: DateTime<Local>,
+    /// The network the record is served to, every client if missing.
+    pub network: Option<String>,
+}
+
+/// # `StatsPeriod`
+///
+/// The hours `GET /api/stats` reports, the last 24 if missing.
+#[derive(Debug, Deserialize, Default)]
+pub struct StatsPeriod {
+    pub hours: Option<u32>,
+}
+
+/// # `StatsReport`
+///
+/// The queries answered over a period and the rankings of `rusty-dnsctl stats`.
+#[derive(Debug, Serialize)]
+pub struct StatsReport {
+    pub since: DateTime<Local>,
+    pub queries: i64,
+    pub blocked: i64,
+    pub top_domains: Vec<StatRow>,
+    pub top_blocked_domains: Vec<StatRow>,
+    pub top_clients: Vec<StatRow>,
+}
+
+/// # `serve`
+///
+/// Serves the management API on `addr`, every request has to carry
+/// `Authorization: Bearer <token>`, 401 otherwise; the bodies are JSON:
+/// - `GET /api/cache` lists the records of the cache, `DELETE /api/cache` deletes them;
+///   the `domain` parameter restricts both to a domain and its subdomains;
+/// - `GET /api/stats` reports the queries of the last `hours`, 24 by default,
+///   and the top domains and clients, along with the names of the clients;
+/// - `GET /api/clients` lists the clients with a name and where the name comes from;
+/// - `GET /api/rules` lists the block and allow rules, `POST /api/rules` adds one,
+///   `{"domain": ..., "action": "block" | "allow"}`, `DELETE /api/rules/<domain>`
+///   removes one;
+/// - `GET /api/records` lists the local records, `POST /api/records` adds one,
+///   `{"name": ..., "address": ..., "ttl": ...}`, `DELETE /api/records/<name>`
+///   removes the ones of a name;
+/// - `POST /api/reload` reads the configuration again, as SIGHUP does.
+pub async fn serve(addr: SocketAddr, state: ApiState) -> io::Result<()> {
+    let state = Arc::new(state);
+    let app = Router::new()
+        .route("/api/cache", get(list_cache).delete(flush_cache))
+        .route("/api/stats", get(query_stats))
+        .route("/api/clients", get(list_clients))
+        .route("/api/rules", get(list_rules).post(add_rule))
+        .route("/api/rules/:domain", delete(remove_rule))
+        .route("/api/records", get(list_records).post(add_record))
+        .route("/api/records/:name", delete(remove_records))
+        .route("/api/reload", post(reload))
+        .layer(middleware::from_fn_with_state(state.clone(), authenticate))
+        .with_state(state);
+    let listener = TcpListener::bind(addr).await?;
+    tracing::info!("Serving the management API on {}", addr);
+    axum::serve(listener, app).await
+}
+
+async fn authenticate(
+    State(state): State<Arc<ApiState>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match token {
+        Some(token) if same_token(token, &state.token) => Ok(next.run(request).await),
+        _ => Err(ApiError(
+            StatusCode::UNAUTHORIZED,
+            "Missing or wrong token".into(),
+        )),
+    }
+}
+
+/// Compares the tokens in a time that doesn't depend on where they differ.
+fn same_token(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+async fn list_cache(
+    State(state): State<Arc<ApiState>>,
+    Query(filter): Query<CacheFilter>,
+) -> Result<Json<Vec<CacheEntry>>, ApiError> {
+    let domain = filter.domain.as_deref().map(idn::to_ascii).transpose()?;
+    let records = CachedRecord::fetch_all(state.server.resolver.storage.as_ref()).await?;
+    let entries = records
+        .into_iter()
+        .filter(|record| {
+            domain
+                .as_deref()
+                .is_none_or(|domain| is_subdomain(&record.domain, domain))
+        })
+        .map(|record| CacheEntry {
+            data: match record.record_from_cache() {
+                Ok(cached) => cached.rdata().to_string(),
+                Err(e) => e.to_string(),
+            },
+            domain: record.domain.to_string(),
+            record_type: format!("{:?}", QueryType::from_num(record.record_type)),
+            ttl: record.ttl,
+            expires: record.expiration_date,
+            network: record.ecs_network,
+        })
+        .collect();
+    Ok(Json(entries))
+}
+
+async fn flush_cache(
+    State(state): State<Arc<ApiState>>,
+    Query(filter): Query<CacheFilter>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let domain = filter.domain.as_deref().map(idn::to_ascii).transpose()?;
+    let deleted =
+        CachedRecord::flush(state.server.resolver.storage.as_ref(), domain.as_deref()).await?;
+    Ok(Json(json!({ "deleted": deleted })))
+}
+
+async fn query_stats(
+    State(state): State<Arc<ApiState>>,
+    Query(period): Query<StatsPeriod>,
+) -> Result<Json<StatsReport>, ApiError> {
+    let storage = state.server.resolver.storage.as_ref();
+    let since = Local::now() - Duration::hours(period.hours.unwrap_or(24) as i64);
+    let (queries, blocked) = stats::totals(storage, since).await?;
+    let mut top_clients = stats::top(storage, StatKind::Client, since, TOP).await?;
+    for row in &mut top_clients {
+        row.name = row
+            .key
+            .parse()
+            .ok()
+            .and_then(|client| state.server.clients.name_of(client));
+    }
+    Ok(Json(StatsReport {
+        since,
+        queries,
+        blocked,
+        top_domains: stats::top(storage, StatKind::Domain, since, TOP).await?,
+        top_blocked_domains: stats::top_blocked(storage, StatKind::Domain, since, TOP).await?,
+        top_clients,
+    }))
+}
+
+async fn list_clients(State(state): State<Arc<ApiState>>) -> Json<Vec<ClientName>> {
+    Json(state.server.clients.list())
+}
+
+async fn list_rules(State(state): State<Arc<ApiState>>) -> Json<Vec<ManagedRule>> {
+    Json(state.server.overrides.rules())
+}
+
+async fn add_rule(
+    State(state): State<Arc<ApiState>>,
+    Json(rule): Json<ManagedRule>,
+) -> Result<StatusCode, ApiError> {
+    tracing::info!("Adding the {:?} rule of {}", rule.action, rule.domain);
+    state.server.overrides.set_rule(rule).await?;
+    Ok(StatusCode::CREATED)
+}
+
+async fn remove_rule(
+    State(state): State<Arc<ApiState>>,
+    Path(domain): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    if state.server.overrides.remove_rule(&domain).await? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(ApiError(
+            StatusCode::NOT_FOUND,
+            format!("No rule for {}", domain),
+        ))
+    }
+}
+
+async fn list_records(State(state): State<Arc<ApiState>>) -> Json<Vec<LocalRecord>> {
+    Json(state.server.overrides.records())
+}
+
+async fn add_record(
+    State(state): State<Arc<ApiState>>,
+    Json(record): Json<LocalRecord>,
+) -> Result<StatusCode, ApiError> {
+    tracing::info!("Adding the local record {} {}", record.name, record.address);
+    state.server.overrides.add_record(record).await?;
+    Ok(StatusCode::CREATED)
+}
+
+async fn remove_records(
+    State(state): State<Arc<ApiState>>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    if state.server.overrides.remove_records(&name).await? > 0 {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(ApiError(
+            StatusCode::NOT_FOUND,
+            format!("No local record for {}", name),
+        ))
+    }
+}
+
+async fn reload(State(state): State<Arc<ApiState>>) -> Result<StatusCode, ApiError> {
+    let reloader = state.reloader.as_ref().ok_or_else(|| {
+        ApiError(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "The server isn't accepting reloads".into(),
+        )
+    })?;
+    reloader
+        .reload()
+        .await
+        .map_err(|e| ApiError(StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    Ok(StatusCode::ACCEPTED)
+}
@@ -0,0 +1,128 @@
+use std::{
+    error::Error,
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use clap::Parser;
+use dns::{
+    idn,
+    structs::{json::DohJson, packet::Packet, questions_and_records::QueryType},
+};
+use ring::rand::{SecureRandom, SystemRandom};
+
+/// # `Dig`
+///
+/// Sends a query to a name server and prints the response, sections as `dig` does.
+#[derive(Debug, Parser)]
+#[command(version, about = "Queries a name server, as dig does", long_about = None)]
+struct Dig {
+    /// Name to query
+    name: String,
+    /// Type to query, as a mnemonic (`MX`) or a number
+    #[arg(default_value = "A")]
+    qtype: QueryType,
+    /// Server to query
+    #[arg(short, long, value_name = "ADDR", default_value = "127.0.0.1:53")]
+    server: SocketAddr,
+    /// Query over TCP, otherwise over UDP and again over TCP if the response is truncated
+    #[arg(long)]
+    tcp: bool,
+    /// Don't ask for recursion
+    #[arg(long)]
+    norecurse: bool,
+    /// Print the response in the JSON of the DNS over HTTPS APIs
+    #[arg(long)]
+    json: bool,
+    /// Seconds to wait for the response
+    #[arg(long, value_name = "SECONDS", default_value_t = 5)]
+    timeout: u64,
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let dig = Dig::parse();
+    let timeout = Duration::from_secs(dig.timeout);
+
+    let query = Packet::builder()
+        .id(random_id()?)
+        .recursion_desired(!dig.norecurse)
+        .question(idn::to_ascii(&dig.name)?, dig.qtype)
+        .build()?;
+    let request = query.to_vec()?;
+
+    let unreachable = |e: std::io::Error| format!("Unable to reach {}: {}", dig.server, e);
+    let started = Instant::now();
+    let mut over_tcp = dig.tcp;
+    let mut response = if over_tcp {
+        query_tcp(&request, dig.server, timeout).map_err(unreachable)?
+    } else {
+        query_udp(&request, dig.server, timeout).map_err(unreachable)?
+    };
+    if response.len() > 2 && response[2] & 0x02 != 0 && !over_tcp {
+        println!(";; Truncated, retrying in TCP mode.");
+        over_tcp = true;
+        response = query_tcp(&request, dig.server, timeout).map_err(unreachable)?;
+    }
+    let elapsed = started.elapsed();
+
+    let packet = Packet::from_bytes(&response).map_err(|e| format!("Malformed response: {}", e))?;
+    if packet.header.id != query.header.id {
+        return Err("The response doesn't match the query".into());
+    }
+    if dig.json {
+        println!("{}", serde_json::to_string_pretty(&DohJson::from(&packet))?);
+        return Ok(());
+    }
+    println!("{}", packet);
+    println!(";; Query time: {} msec", elapsed.as_millis());
+    println!(
+        ";; SERVER: {}({})",
+        dig.server,
+        if over_tcp { "TCP" } else { "UDP" }
+    );
+    println!(";; MSG SIZE  rcvd: {}", response.len());
+    Ok(())
+}
+
+/// # `random_id`
+///
+/// An unpredictable query ID, the responses are harder to spoof.
+fn random_id() -> Result<u16, Box<dyn Error>> {
+    let mut id = [0u8; 2];
+    SystemRandom::new()
+        .fill(&mut id)
+        .map_err(|_| "Unable to generate a query ID")?;
+    Ok(u16::from_be_bytes(id))
+}
+
+fn query_udp(request: &[u8], server: SocketAddr, timeout: Duration) -> std::io::Result<Vec<u8>> {
+    let bind_addr: SocketAddr = if server.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    };
+    let sock = UdpSocket::bind(bind_addr)?;
+    sock.set_read_timeout(Some(timeout))?;
+    sock.connect(server)?;
+    sock.send(request)?;
+    let mut response = vec![0; 65535];
+    let len = sock.recv(&mut response)?;
+    response.truncate(len);
+    Ok(response)
+}
+
+/// # `query_tcp`
+///
+/// Sends the request prefixed by its length, as the stream transports require.
+fn query_tcp(request: &[u8], server: SocketAddr, timeout: Duration) -> std::io::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect_timeout(&server, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.write_all(&(request.len() as u16).to_be_bytes())?;
+    stream.write_all(request)?;
+    let mut len = [0; 2];
+    stream.read_exact(&mut len)?;
+    let mut response = vec![0; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut response)?;
+    Ok(response)
+}
@@ -0,0 +1,283 @@
+use std::{
+    error::Error,
+    io::{Read, Write},
+    path::PathBuf,
+};
+
+use clap::Parser;
+use reqwest::{Method, StatusCode};
+use serde_json::{json, Value};
+
+/// # `Ctl`
+///
+/// Sends a command to the control socket of a running server, or to its management API,
+/// and prints the output.
+#[derive(Debug, Parser)]
+#[command(version, about = "Controls a running rusty_dns", long_about = None)]
+struct Ctl {
+    /// Control socket of the server
+    #[arg(
+        short,
+        long,
+        value_name = "PATH",
+        default_value = "instance/control.sock"
+    )]
+    socket: PathBuf,
+    /// Base URL of the management API of the server, the commands are sent to it
+    /// instead of the control socket
+    #[arg(long, value_name = "URL")]
+    api: Option<String>,
+    /// Token of the management API, read from `RUSTY_DNS_TOKEN` if missing
+    #[arg(long, value_name = "TOKEN")]
+    token: Option<String>,
+    /// Print the responses of the management API as JSON instead of tables
+    #[arg(long)]
+    json: bool,
+    /// The command: `cache flush [<domain>]`, `cache check [delete]`, `cache dump`,
+    /// `cache export [json|csv]`, `cache import <path>`, `reload` or `stats`;
+    /// through the management API `cache list [<domain>]`, `stats [<hours>]`,
+    /// `rules`, `block add|remove <domain>`, `allow add|remove <domain>`,
+    /// `records`, `records add <name> A|AAAA <address> [<ttl>]`
+    /// and `records remove <name>` as well
+    #[arg(required = true, num_args = 1..)]
+    command: Vec<String>,
+}
+
+/// TTL of the local records added without one, in seconds.
+const RECORD_TTL: u32 = 300;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let ctl = Ctl::parse();
+    match ctl.api.clone() {
+        Some(api) => tokio::runtime::Runtime::new()?.block_on(send_to_api(&ctl, &api)),
+        None => send_to_socket(ctl),
+    }
+}
+
+/// # `send_to_socket`
+///
+/// Sends the command to the control socket and prints its output.
+#[cfg(unix)]
+fn send_to_socket(mut ctl: Ctl) -> Result<(), Box<dyn Error>> {
+    if let Some(word) = ctl
+        .command
+        .first()
+        .filter(|word| ["rules", "block", "allow", "records"].contains(&word.as_str()))
+    {
+        return Err(format!("`{}` needs the management API, see --api", word).into());
+    }
+    // The server reads the dump, from its own working directory
+    if let [cache, import, path] = ctl.command.as_mut_slice() {
+        if cache == "cache" && import == "import" {
+            *path = std::fs::canonicalize(&*path)
+                .map_err(|e| format!("Unable to find {}: {}", path, e))?
+                .display()
+                .to_string();
+        }
+    }
+    let mut stream = std::os::unix::net::UnixStream::connect(&ctl.socket)
+        .map_err(|e| format!("Unable to connect to {}: {}", ctl.socket.display(), e))?;
+    writeln!(stream, "{}", ctl.command.join(" "))?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    match response.strip_prefix("ok\n") {
+        Some(output) => {
+            print!("{}", output);
+            Ok(())
+        }
+        None => {
+            eprint!("{}", response);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn send_to_socket(_ctl: Ctl) -> Result<(), Box<dyn Error>> {
+    Err("The control socket is only supported on Unix, see --api".into())
+}
+
+/// # `send_to_api`
+///
+/// Sends the request the command stands for to the management API at `api`
+/// and prints the response, as tables unless JSON is asked for.
+async fn send_to_api(ctl: &Ctl, api: &str) -> Result<(), Box<dyn Error>> {
+    let token = match ctl.token.clone() {
+        Some(token) => token,
+        None => std::env::var("RUSTY_DNS_TOKEN")
+            .map_err(|_| "The management API needs a token, see --token")?,
+    };
+    let (method, path, body) = api_request(&ctl.command)?;
+    let mut request = reqwest::Client::new()
+        .request(method, format!("{}{}", api.trim_end_matches('/'), path))
+        .bearer_auth(token);
+    if let Some(body) = body {
+        request = request
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.to_string());
+    }
+    let response = request
+        .send()
+        .await
+        .map_err(|e| format!("Unable to reach {}: {}", api, e))?;
+    let status = response.status();
+    let bytes = response.bytes().await?;
+    let value: Value = serde_json::from_slice(&bytes).unwrap_or(Value::Null);
+    if !status.is_success() {
+        let reason = value["error"].as_str().unwrap_or_else(|| status.as_str());
+        eprintln!("error: {}", reason);
+        std::process::exit(1);
+    }
+    if ctl.json {
+        if !value.is_null() {
+            println!("{}", serde_json::to_string_pretty(&value)?);
+        }
+    } else if value.is_null() {
+        println!("{}", done_message(status));
+    } else {
+        print!("{}", table(&value));
+    }
+    Ok(())
+}
+
+/// # `api_request`
+///
+/// The method, the path and the body of the request the command stands for.
+fn api_request(command: &[String]) -> Result<(Method, String, Option<Value>), String> {
+    let words: Vec<&str> = command.iter().map(String::as_str).collect();
+    let request = match words.as_slice() {
+        ["cache", "flush"] => (Method::DELETE, "/api/cache".into(), None),
+        ["cache", "flush", domain] => (
+            Method::DELETE,
+            format!("/api/cache?domain={}", domain),
+            None,
+        ),
+        ["cache", "list" | "dump"] => (Method::GET, "/api/cache".into(), None),
+        ["cache", "list", domain] => (Method::GET, format!("/api/cache?domain={}", domain), None),
+        ["stats"] => (Method::GET, "/api/stats".into(), None),
+        ["stats", hours] => {
+            let hours: u32 = hours
+                .parse()
+                .map_err(|_| format!("Not a number of hours: {}", hours))?;
+            (Method::GET, format!("/api/stats?hours={}", hours), None)
+        }
+        ["rules"] => (Method::GET, "/api/rules".into(), None),
+        [action @ ("block" | "allow"), "add", domain] => (
+            Method::POST,
+            "/api/rules".into(),
+            Some(json!({ "domain": domain, "action": action })),
+        ),
+        ["block" | "allow", "remove", domain] => {
+            (Method::DELETE, format!("/api/rules/{}", domain), None)
+        }
+        ["records"] => (Method::GET, "/api/records".into(), None),
+        ["records", "add", name, qtype, address, ttl @ ..] => {
+            let address: std::net::IpAddr = address
+                .parse()
+                .map_err(|_| format!("Not an address: {}", address))?;
+            let family_matches = match qtype.to_uppercase().as_str() {
+                "A" => address.is_ipv4(),
+                "AAAA" => address.is_ipv6(),
+                _ => {
+                    return Err(format!(
+                        "Only A and AAAA records can be added, not {}",
+                        qtype
+                    ))
+                }
+            };
+            if !family_matches {
+                return Err(format!(
+                    "{} isn't an address of a {} record",
+                    address, qtype
+                ));
+            }
+            let ttl = match ttl {
+                [] => RECORD_TTL,
+                [ttl] => ttl.parse().map_err(|_| format!("Not a TTL: {}", ttl))?,
+                _ => return Err(format!("Unknown command: {}", command.join(" "))),
+            };
+            (
+                Method::POST,
+                "/api/records".into(),
+                Some(json!({ "name": name, "address": address, "ttl": ttl })),
+            )
+        }
+        ["records", "remove", name] => (Method::DELETE, format!("/api/records/{}", name), None),
+        ["reload"] => (Method::POST, "/api/reload".into(), None),
+        _ => return Err(format!("Unknown command: {}", command.join(" "))),
+    };
+    Ok(request)
+}
+
+/// What a request answered without a body did.
+fn done_message(status: StatusCode) -> &'static str {
+    match status {
+        StatusCode::CREATED => "Added",
+        StatusCode::NO_CONTENT => "Removed",
+        StatusCode::ACCEPTED => "Reloading the configuration",
+        _ => "Done",
+    }
+}
+
+/// # `table`
+///
+/// Lays out a response of the API: a list of objects as a table with a column per field,
+/// the fields of an object one per line, its lists as tables of their own.
+fn table(value: &Value) -> String {
+    match value {
+        Value::Array(rows) => rows_table(rows),
+        Value::Object(fields) => {
+            let mut output = String::new();
+            for (key, field) in fields.iter().filter(|(_, field)| !field.is_array()) {
+                output.push_str(&format!("{}: {}\n", key, cell(field)));
+            }
+            for (key, field) in fields {
+                if let Value::Array(rows) = field {
+                    output.push_str(&format!("\n{}:\n{}", key, rows_table(rows)));
+                }
+            }
+            output
+        }
+        value => format!("{}\n", cell(value)),
+    }
+}
+
+/// The rows aligned in columns, under a header with the names of the fields of the first.
+fn rows_table(rows: &[Value]) -> String {
+    let Some(Value::Object(first)) = rows.first() else {
+        return String::new();
+    };
+    let columns: Vec<&String> = first.keys().collect();
+    let lines: Vec<Vec<String>> =
+        std::iter::once(columns.iter().map(|column| column.to_uppercase()).collect())
+            .chain(rows.iter().map(|row| {
+                columns
+                    .iter()
+                    .map(|column| cell(&row[column.as_str()]))
+                    .collect()
+            }))
+            .collect();
+    let widths: Vec<usize> = (0..columns.len())
+        .map(|i| lines.iter().map(|line| line[i].len()).max().unwrap_or(0))
+        .collect();
+    let mut output = String::new();
+    for line in lines {
+        let cells: Vec<String> = line
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+        output.push_str(cells.join("  ").trim_end());
+        output.push('\n');
+    }
+    output
+}
+
+/// A value of the API as a cell of a table, the strings without their quotes.
+fn cell(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => "-".into(),
+        value => value.to_string(),
+    }
+}
@@ -0,0 +1,455 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use ipnet::IpNet;
+use serde::Deserialize;
+use sqlx::{QueryBuilder, Sqlite, SqlitePool};
+
+use crate::{
+    blocklist::rules::BlockRules,
+    configuration::Settings,
+    idn,
+    structs::{
+        auxiliaries::CResult,
+        header::ResultCode,
+        packet::Packet,
+        questions_and_records::{QueryType, Record},
+    },
+};
+
+pub mod rules;
+
+/// Primary server of the SOA attached to the negative answers for blocked domains.
+const BLOCKED_SOA_MNAME: &str = "blocked.invalid";
+/// Mailbox of the SOA attached to the negative answers for blocked domains.
+const BLOCKED_SOA_RNAME: &str = "hostmaster.blocked.invalid";
+
+/// Number of domains inserted or deleted with a single statement.
+const INSERT_CHUNK: usize = 500;
+
+/// Maximum time the download of a list is allowed to take.
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Names that appear in the hosts files but are never meant to be blocked.
+const HOSTS_RESERVED: [&str; 8] = [
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+    "ip6-allnodes",
+    "ip6-allrouters",
+];
+
+/// # `BlockingMode`
+///
+/// How the queries for blocked domains are answered.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BlockingMode {
+    /// The domain doesn't exist.
+    #[default]
+    NxDomain,
+    /// The query is refused.
+    Refused,
+    /// A and AAAA queries are answered with the unspecified address (0.0.0.0 and ::).
+    Null,
+    /// A and AAAA queries are answered with the addresses of a block page.
+    Custom,
+}
+
+/// # `BlockedAnswer`
+///
+/// Answer given to the queries for blocked domains.
+#[derive(Debug, Clone)]
+pub struct BlockedAnswer {
+    pub mode: BlockingMode,
+    /// Address of the block page, used by `BlockingMode::Custom`.
+    pub ipv4: Option<Ipv4Addr>,
+    /// Address of the block page, used by `BlockingMode::Custom`.
+    pub ipv6: Option<Ipv6Addr>,
+    /// TTL of the addresses and negative TTL of the other answers.
+    pub ttl: u32,
+}
+
+/// # `PolicyGroup`
+///
+/// Clients whose blocked queries receive a dedicated answer.
+#[derive(Debug, Clone)]
+pub struct PolicyGroup {
+    pub name: String,
+    pub clients: Vec<IpNet>,
+    pub answer: BlockedAnswer,
+}
+
+/// # `BlockingPolicy`
+///
+/// Answers given to the queries for blocked domains: the one of the first group
+/// the client belongs to or the default one.
+#[derive(Debug, Clone)]
+pub struct BlockingPolicy {
+    pub default: BlockedAnswer,
+    pub groups: Vec<PolicyGroup>,
+}
+
+impl BlockingPolicy {
+    /// # `answer_for`
+    ///
+    /// Returns the answer that applies to `client`.
+    pub fn answer_for(&self, client: IpAddr) -> &BlockedAnswer {
+        self.groups
+            .iter()
+            .find(|g| g.clients.iter().any(|net| net.contains(&client)))
+            .map_or(&self.default, |g| &g.answer)
+    }
+}
+
+/// # `Blocklist`
+///
+/// Pi-hole style blocking layer, the domains of the lists are stored deduplicated
+/// in the database and answered locally instead of being resolved.
+/// Wildcard and regex rules are kept in memory.
+pub struct Blocklist {
+    db_pool: SqlitePool,
+    lists: Vec<PathBuf>,
+    urls: Vec<String>,
+    rules: BlockRules,
+    allowlist: Vec<String>,
+    policy: BlockingPolicy,
+    update_interval: Option<Duration>,
+}
+
+impl Blocklist {
+    pub fn new(
+        db_pool: SqlitePool,
+        lists: Vec<PathBuf>,
+        urls: Vec<String>,
+        rules: BlockRules,
+        allowlist: Vec<String>,
+        policy: BlockingPolicy,
+        update_interval: Option<Duration>,
+    ) -> Self {
+        Blocklist {
+            db_pool,
+            lists,
+            urls,
+            rules,
+            allowlist,
+            policy,
+            update_interval,
+        }
+    }
+
+    /// # `from_settings`
+    ///
+    /// Fails if one of the wildcard or regex rules is invalid or if a block page
+    /// has been requested without providing its address.
+    pub fn from_settings(settings: &Settings, db_pool: SqlitePool) -> CResult<Self> {
+        let rules = BlockRules::new(
+            &settings.get_blocking_wildcards(),
+            &settings.get_blocking_regexes(),
+        )?;
+        let policy = BlockingPolicy {
+            default: settings.get_blocked_answer(),
+            groups: settings.get_policy_groups(),
+        };
+        for (name, answer) in std::iter::once(("default", &policy.default))
+            .chain(policy.groups.iter().map(|g| (g.name.as_str(), &g.answer)))
+        {
+            if answer.mode == BlockingMode::Custom && answer.ipv4.is_none() && answer.ipv6.is_none()
+            {
+                return Err(format!(
+                    "The blocking policy {} requires the address of the block page",
+                    name
+                )
+                .into());
+            }
+        }
+        Ok(Blocklist::new(
+            db_pool,
+            settings.get_blocklists(),
+            settings.get_blocklist_urls(),
+            rules,
+            settings.get_allowlist(),
+            policy,
+            settings.get_blocklist_update_interval(),
+        ))
+    }
+
+    /// # `run`
+    ///
+    /// Loads the lists and, if an update interval has been configured,
+    /// keeps them up to date downloading them again every time it elapses.
+    pub async fn run(self: Arc<Self>) {
+        loop {
+            if let Err(e) = self.reload().await {
+                tracing::error!("Failed to load the blocklists: {}", e);
+            }
+            match self.update_interval {
+                Some(interval) => tokio::time::sleep(interval).await,
+                None => break,
+            }
+        }
+    }
+
+    /// # `reload`
+    ///
+    /// Reads the files and downloads the URLs of the lists, then brings the database
+    /// in line with their content, together with the allowlist.
+    /// Only the differences are written, in a single transaction, so the queries
+    /// keep seeing the previous set until the new one is in place.
+    /// The domains of the lists that can't be obtained are kept as they are.
+    #[tracing::instrument(name = "Loading the blocklists", skip(self))]
+    pub async fn reload(&self) -> CResult<()> {
+        // Domain and the source it comes from
+        let mut domains: HashMap<String, String> = HashMap::new();
+        let mut failed: Vec<String> = Vec::new();
+        for path in &self.lists {
+            let source = path.display().to_string();
+            match tokio::fs::read_to_string(path).await {
+                Ok(content) => add_domains(&mut domains, &content, &source),
+                Err(e) => {
+                    tracing::warn!("Unable to read the blocklist {}: {}", source, e);
+                    failed.push(source);
+                }
+            }
+        }
+        if !self.urls.is_empty() {
+            let client = reqwest::Client::builder()
+                .timeout(DOWNLOAD_TIMEOUT)
+                .build()?;
+            for url in &self.urls {
+                match download(&client, url).await {
+                    Ok(content) => add_domains(&mut domains, &content, url),
+                    Err(e) => {
+                        tracing::warn!("Unable to download the blocklist {}: {}", url, e);
+                        failed.push(url.clone());
+                    }
+                }
+            }
+        }
+
+        let stored: Vec<(String, String)> =
+            sqlx::query_as(r#"SELECT domain, source FROM blocked_domains"#)
+                .fetch_all(&self.db_pool)
+                .await?;
+        for (domain, source) in &stored {
+            if failed.contains(source) {
+                domains
+                    .entry(domain.clone())
+                    .or_insert_with(|| source.clone());
+            }
+        }
+        let stored: HashSet<String> = stored.into_iter().map(|(d, _)| d).collect();
+        let removed: Vec<&String> = stored
+            .iter()
+            .filter(|d| !domains.contains_key(*d))
+            .collect();
+        let added: Vec<(&String, &String)> = domains
+            .iter()
+            .filter(|(d, _)| !stored.contains(*d))
+            .collect();
+
+        let mut transaction = self.db_pool.begin().await?;
+        for chunk in removed.chunks(INSERT_CHUNK) {
+            let mut query: QueryBuilder<Sqlite> =
+                QueryBuilder::new("DELETE FROM blocked_domains WHERE domain IN (");
+            let mut separated = query.separated(", ");
+            for domain in chunk {
+                separated.push_bind(*domain);
+            }
+            separated.push_unseparated(")");
+            query.build().execute(&mut *transaction).await?;
+        }
+        for chunk in added.chunks(INSERT_CHUNK) {
+            let mut query: QueryBuilder<Sqlite> =
+                QueryBuilder::new("INSERT OR IGNORE INTO blocked_domains (domain, source) ");
+            query.push_values(chunk, |mut row, (domain, source)| {
+                row.push_bind(*domain).push_bind(*source);
+            });
+            query.build().execute(&mut *transaction).await?;
+        }
+        sqlx::query(r#"DELETE FROM allowed_domains"#)
+            .execute(&mut *transaction)
+            .await?;
+        for domain in &self.allowlist {
+            sqlx::query(r#"INSERT OR IGNORE INTO allowed_domains (domain) VALUES ($1)"#)
+                .bind(domain)
+                .execute(&mut *transaction)
+                .await?;
+        }
+        transaction.commit().await?;
+
+        tracing::info!(
+            "Blocking {} domains from {} lists: {} added, {} removed",
+            domains.len(),
+            self.lists.len() + self.urls.len(),
+            added.len(),
+            removed.len()
+        );
+        Ok(())
+    }
+
+    /// # `is_blocked`
+    ///
+    /// Returns true if `qname` is present in one of the lists or is matched by one
+    /// of the rules, and isn't allowlisted.
+    pub async fn is_blocked(&self, qname: &str) -> CResult<bool> {
+        let qname = qname.trim_end_matches('.').to_lowercase();
+        if self.rules.matches(&qname) {
+            return Ok(!self.allowlist.contains(&qname));
+        }
+        if self.lists.is_empty() && self.urls.is_empty() {
+            return Ok(false);
+        }
+        let blocked: bool = sqlx::query_scalar(
+            r#"SELECT EXISTS(SELECT 1 FROM blocked_domains WHERE domain = $1)
+            AND NOT EXISTS(SELECT 1 FROM allowed_domains WHERE domain = $1)"#,
+        )
+        .bind(&qname)
+        .fetch_one(&self.db_pool)
+        .await?;
+        Ok(blocked)
+    }
+
+    /// # `blocked_zone`
+    ///
+    /// The apex of the zone we pretend to be authoritative for when blocking `qname`:
+    /// the suffix of the wildcard that matches it, or the name itself for the domains
+    /// of the lists and the names matched by a regex.
+    fn blocked_zone(&self, qname: &str) -> String {
+        let qname = qname.trim_end_matches('.').to_lowercase();
+        self.rules.wildcard_zone(&qname).unwrap_or(qname)
+    }
+
+    /// # `blocked_response`
+    ///
+    /// Composes the response for a query whose name is blocked, following the policy
+    /// of the first group `client` belongs to or the default one.
+    /// Negative answers carry a SOA owned by the apex of the blocked zone, so that
+    /// the clients cache them for the configured TTL (RFC 2308).
+    pub fn blocked_response(&self, request: &Packet, client: IpAddr) -> Packet {
+        let answer = self.policy.answer_for(client);
+
+        let mut response = Packet::new();
+        response.add_info(
+            request.header.id,
+            request.header.recursion_desired,
+            true,
+            true,
+            ResultCode::NOERROR,
+        );
+        response.questions = request.questions.clone();
+        let question = match request.questions.first() {
+            Some(q) => q,
+            None => return response,
+        };
+        let (ipv4, ipv6) = match answer.mode {
+            BlockingMode::Refused => {
+                response.header.rescode = ResultCode::REFUSED;
+                return response;
+            }
+            BlockingMode::NxDomain => {
+                response.header.rescode = ResultCode::NXDOMAIN;
+                (None, None)
+            }
+            BlockingMode::Null => (Some(Ipv4Addr::UNSPECIFIED), Some(Ipv6Addr::UNSPECIFIED)),
+            BlockingMode::Custom => (answer.ipv4, answer.ipv6),
+        };
+        match (question.qtype, ipv4, ipv6) {
+            (QueryType::A, Some(addr), _) => response.answers.push(Record::A {
+                domain: question.qname.clone(),
+                addr,
+                ttl: answer.ttl,
+            }),
+            (QueryType::AAAA, _, Some(addr)) => response.answers.push(Record::AAAA {
+                domain: question.qname.clone(),
+                addr,
+                ttl: answer.ttl,
+            }),
+            // NXDOMAIN or no data of the requested type
+            _ => response.authorities.push(Record::SOA {
+                domain: self.blocked_zone(&question.qname).into(),
+                mname: BLOCKED_SOA_MNAME.into(),
+                rname: BLOCKED_SOA_RNAME.into(),
+                serial: 1,
+                refresh: 3600,
+                retry: 600,
+                expire: 86400,
+                minimum: answer.ttl,
+                ttl: answer.ttl,
+            }),
+        }
+        response
+    }
+}
+
+/// # `download`
+///
+/// Fetches the content of a list published over HTTP.
+async fn download(client: &reqwest::Client, url: &str) -> CResult<String> {
+    let response = client.get(url).send().await?.error_for_status()?;
+    Ok(response.text().await?)
+}
+
+/// # `add_domains`
+///
+/// Adds the domains of a list to `domains`, the ones already present keep their source.
+fn add_domains(domains: &mut HashMap<String, String>, content: &str, source: &str) {
+    for domain in parse_list(content) {
+        domains.entry(domain).or_insert_with(|| source.to_string());
+    }
+}
+
+/// # `parse_list`
+///
+/// Extracts the domains from a blocklist, both the hosts format (`0.0.0.0 ads.example.com`)
+/// and the plain list of domains are supported, comments start with `#`;
+/// the Unicode names are converted to A-labels.
+pub fn parse_list(content: &str) -> Vec<String> {
+    let mut domains = Vec::new();
+    for line in content.lines() {
+        let line = match line.split_once('#') {
+            Some((data, _)) => data,
+            None => line,
+        };
+        let mut tokens = line.split_whitespace().peekable();
+        // In the hosts format the names follow the address
+        if let Some(first) = tokens.peek() {
+            if first.parse::<IpAddr>().is_ok() {
+                tokens.next();
+            }
+        }
+        for token in tokens {
+            let Ok(domain) = idn::to_ascii(token) else {
+                continue;
+            };
+            if is_valid_domain(&domain) && !HOSTS_RESERVED.contains(&domain.as_str()) {
+                domains.push(domain);
+            }
+        }
+    }
+    domains
+}
+
+/// # `is_valid_domain`
+///
+/// Basic sanity check on the names found in the lists.
+fn is_valid_domain(domain: &str) -> bool {
+    !domain.is_empty()
+        && domain.len() <= 253
+        && domain.parse::<IpAddr>().is_err()
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+}
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+
+use regex::RegexSet;
+
+use crate::structs::auxiliaries::{CResult, DnsError};
+
+/// # `BlockRules`
+///
+/// Blocking rules that match more than a single name: wildcards (`*.doubleclick.net`),
+/// kept in a trie of labels, and regular expressions, compiled into a single set.
+/// Both are evaluated in memory, a lookup costs one visit of the trie and one pass
+/// of the regex set over the name.
+#[derive(Debug)]
+pub struct BlockRules {
+    wildcards: LabelTrie,
+    regexes: RegexSet,
+}
+
+impl BlockRules {
+    /// # `new`
+    ///
+    /// Fails if a wildcard doesn't start with `*.` or if a regex can't be compiled.
+    pub fn new(wildcards: &[String], regexes: &[String]) -> CResult<Self> {
+        let mut trie = LabelTrie::default();
+        for wildcard in wildcards {
+            let suffix = wildcard
+                .strip_prefix("*.")
+                .ok_or_else(|| DnsError::Config(format!("Invalid wildcard rule: {}", wildcard)))?;
+            let suffix = suffix.trim_end_matches('.').to_lowercase();
+            if suffix.is_empty() || suffix.contains('*') {
+                return Err(DnsError::Config(format!(
+                    "Invalid wildcard rule: {}",
+                    wildcard
+                )));
+            }
+            trie.insert(&suffix);
+        }
+        let regexes = RegexSet::new(regexes).map_err(|e| DnsError::Config(e.to_string()))?;
+        Ok(BlockRules {
+            wildcards: trie,
+            regexes,
+        })
+    }
+
+    /// # `matches`
+    ///
+    /// Returns true if `qname`, lowercase and without the trailing dot,
+    /// is matched by one of the rules.
+    pub fn matches(&self, qname: &str) -> bool {
+        self.wildcards.matches_subdomain(qname) || self.regexes.is_match(qname)
+    }
+
+    /// # `wildcard_zone`
+    ///
+    /// The suffix of the wildcard that matches `qname`, lowercase and without
+    /// the trailing dot, if any: `doubleclick.net` for `*.doubleclick.net`.
+    pub fn wildcard_zone(&self, qname: &str) -> Option<String> {
+        self.wildcards.matching_suffix(qname)
+    }
+}
+
+/// # `LabelTrie`
+///
+/// Domain suffixes stored label by label, starting from the rightmost one.
+#[derive(Debug, Default)]
+struct LabelTrie {
+    children: HashMap<String, LabelTrie>,
+    /// A suffix ends here.
+    terminal: bool,
+}
+
+impl LabelTrie {
+    fn insert(&mut self, suffix: &str) {
+        let mut node = self;
+        for label in suffix.rsplit('.') {
+            node = node.children.entry(label.to_string()).or_default();
+        }
+        node.terminal = true;
+    }
+
+    /// # `matches_subdomain`
+    ///
+    /// Returns true if `name` is a proper subdomain of one of the stored suffixes,
+    /// the suffix itself isn't matched, as the wildcard requires at least a label.
+    fn matches_subdomain(&self, name: &str) -> bool {
+        self.matching_suffix(name).is_some()
+    }
+
+    /// # `matching_suffix`
+    ///
+    /// The shortest stored suffix `name` is a proper subdomain of.
+    fn matching_suffix(&self, name: &str) -> Option<String> {
+        let mut node = self;
+        let mut matched = Vec::new();
+        let mut labels = name.rsplit('.').peekable();
+        while let Some(label) = labels.next() {
+            node = node.children.get(label)?;
+            matched.push(label);
+            if node.terminal && labels.peek().is_some() {
+                matched.reverse();
+                return Some(matched.join("."));
+            }
+        }
+        None
+    }
+}
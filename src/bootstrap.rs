@@ -0,0 +1,96 @@
+use std::{path::Path, time::Duration};
+
+use serde::Deserialize;
+
+use crate::{
+    cachewriter::CacheEntry,
+    clock::Clock,
+    storage::Storage,
+    structs::{
+        auxiliaries::{CResult, DnsError},
+        name::DnsName,
+        questions_and_records::Record,
+    },
+};
+
+/// # `BootstrapRecord`
+///
+/// A record of a bootstrap file, its data written as in a zone file.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct BootstrapRecord {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub qtype: String,
+    pub data: String,
+    /// The TTL of the bootstrap settings if missing.
+    pub ttl: Option<u32>,
+}
+
+/// # `BootstrapFile`
+///
+/// The records cached at startup, a TOML or JSON file with a `records` array.
+#[derive(Debug, Deserialize, Default)]
+pub struct BootstrapFile {
+    #[serde(default)]
+    pub records: Vec<BootstrapRecord>,
+}
+
+impl BootstrapFile {
+    /// # `read`
+    ///
+    /// Reads the file at `path`, in JSON if it ends with `.json`, otherwise in TOML.
+    pub fn read(path: &Path) -> CResult<Self> {
+        config::Config::builder()
+            .add_source(config::File::from(path))
+            .build()
+            .and_then(|file| file.try_deserialize())
+            .map_err(|e| DnsError::Config(format!("Invalid bootstrap file: {}", e)))
+    }
+
+    /// # `records`
+    ///
+    /// The records of the file, the ones without a TTL get `ttl`;
+    /// fails on the first record that isn't valid.
+    pub fn records(&self, ttl: u32) -> CResult<Vec<Record>> {
+        self.records
+            .iter()
+            .map(|record| {
+                let invalid = |e: String| {
+                    DnsError::Config(format!("Invalid record of {}: {}", record.name, e))
+                };
+                DnsName::validate(&record.name).map_err(|e| invalid(e.to_string()))?;
+                Record::from_presentation(
+                    record.name.trim_end_matches('.').to_lowercase().into(),
+                    record.qtype.parse().map_err(invalid)?,
+                    record.ttl.unwrap_or(ttl),
+                    &record.data,
+                )
+                .map_err(invalid)
+            })
+            .collect()
+    }
+}
+
+/// # `prefill`
+///
+/// Caches the records of the bootstrap file at `path`, the ones without a TTL
+/// for `ttl` seconds; returns how many have been cached.
+pub async fn prefill(
+    storage: &dyn Storage,
+    path: &Path,
+    ttl: u32,
+    clock: &dyn Clock,
+) -> CResult<usize> {
+    let now = clock.now();
+    let batch: Vec<CacheEntry> = BootstrapFile::read(path)?
+        .records(ttl)?
+        .into_iter()
+        .map(|record| CacheEntry {
+            expiration_date: now + Duration::from_secs(record.get_ttl() as u64),
+            record,
+            ecs_network: None,
+        })
+        .collect();
+    storage.write_cache(&batch).await?;
+    Ok(batch.len())
+}
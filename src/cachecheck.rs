@@ -0,0 +1,110 @@
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+use crate::{
+    storage::Storage,
+    structs::{
+        auxiliaries::CResult,
+        name::DnsName,
+        questions_and_records::{QueryType, Record},
+    },
+};
+
+/// # `CacheRow`
+///
+/// A row of the cache as it's stored, nothing decoded, so that the malformed ones
+/// can be read as well.
+#[derive(Debug, Clone, Default, sqlx::FromRow)]
+pub struct CacheRow {
+    pub id: i64,
+    pub domain: Option<String>,
+    pub record_type: Option<i64>,
+    pub ttl: Option<i64>,
+    pub address: Option<String>,
+    pub host: Option<String>,
+    pub ecs_network: Option<String>,
+    pub rdata: Option<Vec<u8>>,
+}
+
+/// # `Malformed`
+///
+/// A row of the cache that can't be served, and why.
+#[derive(Debug, Clone)]
+pub struct Malformed {
+    pub row: CacheRow,
+    pub problem: String,
+}
+
+impl CacheRow {
+    /// # `problem`
+    ///
+    /// What prevents the row from being served, `None` if it's well formed:
+    /// a name that isn't valid, a missing type, TTL or data, data that can't be read
+    /// as a record of its type, an address that doesn't match the data or a network
+    /// that can't be parsed.
+    pub fn problem(&self) -> Option<String> {
+        let Some(domain) = self.domain.as_deref() else {
+            return Some("no domain".into());
+        };
+        if let Err(e) = DnsName::validate(domain) {
+            return Some(format!("invalid domain: {}", e));
+        }
+        let Some(record_type) = self.record_type else {
+            return Some("no type".into());
+        };
+        let Ok(record_type) = u16::try_from(record_type) else {
+            return Some(format!("invalid type {}", record_type));
+        };
+        let Some(ttl) = self.ttl.and_then(|ttl| u32::try_from(ttl).ok()) else {
+            return Some(format!("invalid TTL {:?}", self.ttl));
+        };
+        let rdata = match self.rdata.as_deref() {
+            None | Some([]) => return Some("no data".into()),
+            Some(rdata) => rdata,
+        };
+        let qtype = QueryType::from_num(record_type);
+        let record = match Record::from_wire_rdata(domain.into(), qtype, ttl, rdata) {
+            Ok(record) => record,
+            Err(e) => return Some(format!("unreadable data: {}", e)),
+        };
+        if let Some(address) = self.address.as_deref() {
+            let stored = match &record {
+                Record::A { addr, .. } => Some(IpAddr::V4(*addr)),
+                Record::AAAA { addr, .. } => Some(IpAddr::V6(*addr)),
+                _ => None,
+            };
+            match address.parse::<IpAddr>() {
+                Err(_) => return Some(format!("unparseable address {}", address)),
+                Ok(address) if Some(address) != stored => {
+                    return Some(format!("address {} doesn't match the data", address))
+                }
+                Ok(_) => {}
+            }
+        }
+        if let Some(network) = self.ecs_network.as_deref() {
+            if network.parse::<IpNet>().is_err() {
+                return Some(format!("unparseable network {}", network));
+            }
+        }
+        None
+    }
+}
+
+/// # `check`
+///
+/// The rows of the cache that can't be served, deleted from it if `delete`.
+pub async fn check(storage: &dyn Storage, delete: bool) -> CResult<Vec<Malformed>> {
+    let malformed: Vec<Malformed> = storage
+        .cached_rows()
+        .await?
+        .into_iter()
+        .filter_map(|row| row.problem().map(|problem| Malformed { row, problem }))
+        .collect();
+    if delete {
+        for entry in &malformed {
+            storage.delete_cached(entry.row.id).await?;
+        }
+    }
+    Ok(malformed)
+}
@@ -0,0 +1,214 @@
+use std::{net::IpAddr, path::Path, str::FromStr};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Local};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cachewriter::CacheEntry,
+    clock::Clock,
+    storage::Storage,
+    structs::{
+        auxiliaries::{CResult, DnsError},
+        db_queries::CachedRecord,
+        name::DnsName,
+        questions_and_records::{QueryType, Record},
+    },
+    workers::InfraCache,
+};
+
+/// # `DumpFormat`
+///
+/// How the records of a dump are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DumpFormat {
+    /// An array of `DumpEntry`.
+    #[default]
+    Json,
+    /// A line per `DumpEntry`, after the names of the fields.
+    Csv,
+}
+
+impl FromStr for DumpFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(DumpFormat::Json),
+            "csv" => Ok(DumpFormat::Csv),
+            _ => Err(format!("Unknown format: {}, expected json or csv", s)),
+        }
+    }
+}
+
+impl DumpFormat {
+    /// # `from_path`
+    ///
+    /// The format of the file at `path` by its extension, JSON unless it's `.csv`.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension() {
+            Some(extension) if extension.eq_ignore_ascii_case("csv") => DumpFormat::Csv,
+            _ => DumpFormat::Json,
+        }
+    }
+}
+
+/// # `Origin`
+///
+/// Which cache a record of a dump has been taken from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Origin {
+    /// The cache database.
+    Database,
+    /// The addresses of the name servers kept in memory, `InfraCache`.
+    Memory,
+}
+
+/// # `DumpEntry`
+///
+/// A record of the cache as it's exported: `data` is in the presentation format,
+/// for the ones reading the dump, `rdata` is the data section in base64,
+/// what is read back when the dump is imported.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DumpEntry {
+    pub origin: Origin,
+    pub domain: String,
+    #[serde(rename = "type")]
+    pub qtype: QueryType,
+    pub ttl: u32,
+    pub expires: DateTime<Local>,
+    /// Network of the clients the record is served to, every client if `None`.
+    pub ecs_network: Option<String>,
+    pub data: String,
+    pub rdata: String,
+}
+
+/// # `export`
+///
+/// The records of the storage and the addresses of the name servers
+/// kept in memory that haven't expired according to `clock`, in `format`.
+pub async fn export(
+    storage: &dyn Storage,
+    infra: &InfraCache,
+    clock: &dyn Clock,
+    format: DumpFormat,
+) -> CResult<String> {
+    let mut entries = Vec::new();
+    for cached in CachedRecord::fetch_all(storage).await? {
+        if !cached.is_valid(clock) {
+            continue;
+        }
+        let record = match cached.record_from_cache() {
+            Ok(record) => record,
+            Err(e) => {
+                tracing::warn!("Left a record of {} out of the dump: {}", cached.domain, e);
+                continue;
+            }
+        };
+        entries.push(DumpEntry {
+            origin: Origin::Database,
+            domain: cached.domain.to_string(),
+            qtype: record.get_qtype(),
+            ttl: cached.ttl,
+            expires: cached.expiration_date,
+            ecs_network: cached.ecs_network.clone(),
+            data: record.rdata().to_string(),
+            rdata: STANDARD.encode(&cached.rdata),
+        });
+    }
+    for (host, addrs, valid_for) in infra.entries() {
+        for addr in addrs {
+            let (domain, ttl) = (host.as_str().into(), valid_for.as_secs() as u32);
+            let record = match addr {
+                IpAddr::V4(addr) => Record::A { domain, addr, ttl },
+                IpAddr::V6(addr) => Record::AAAA { domain, addr, ttl },
+            };
+            entries.push(DumpEntry {
+                origin: Origin::Memory,
+                domain: host.clone(),
+                qtype: record.get_qtype(),
+                ttl: record.get_ttl(),
+                expires: clock.now() + valid_for,
+                ecs_network: None,
+                data: record.rdata().to_string(),
+                rdata: STANDARD.encode(record.wire_rdata()?),
+            });
+        }
+    }
+
+    match format {
+        DumpFormat::Json => {
+            let mut output = serde_json::to_string_pretty(&entries)
+                .map_err(|e| DnsError::Other(format!("Unable to write the dump: {}", e)))?;
+            output.push('\n');
+            Ok(output)
+        }
+        DumpFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(Vec::new());
+            for entry in &entries {
+                writer
+                    .serialize(entry)
+                    .map_err(|e| DnsError::Other(format!("Unable to write the dump: {}", e)))?;
+            }
+            let output = writer
+                .into_inner()
+                .map_err(|e| DnsError::Other(format!("Unable to write the dump: {}", e)))?;
+            String::from_utf8(output)
+                .map_err(|e| DnsError::Other(format!("Unable to write the dump: {}", e)))
+        }
+    }
+}
+
+/// # `import`
+///
+/// Writes the records of the dump `content`, in `format`, in the storage,
+/// the ones of the memory included; returns how many have been written.
+/// The records expired according to `clock` are skipped, a record that can't be
+/// read fails the whole import.
+pub async fn import(
+    storage: &dyn Storage,
+    content: &str,
+    format: DumpFormat,
+    clock: &dyn Clock,
+) -> CResult<usize> {
+    let entries: Vec<DumpEntry> = match format {
+        DumpFormat::Json => serde_json::from_str(content)
+            .map_err(|e| DnsError::Other(format!("Invalid dump: {}", e)))?,
+        DumpFormat::Csv => csv::Reader::from_reader(content.as_bytes())
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .map_err(|e| DnsError::Other(format!("Invalid dump: {}", e)))?,
+    };
+    let now = clock.now();
+    let mut batch = Vec::new();
+    for entry in entries.into_iter().filter(|entry| entry.expires > now) {
+        DnsName::validate(&entry.domain)?;
+        let rdata = STANDARD.decode(&entry.rdata).map_err(|e| {
+            DnsError::Other(format!(
+                "Invalid data of a record of {}: {}",
+                entry.domain, e
+            ))
+        })?;
+        let ecs_network = entry
+            .ecs_network
+            .as_deref()
+            .map(str::parse::<IpNet>)
+            .transpose()
+            .map_err(|e| {
+                DnsError::Other(format!(
+                    "Invalid network of a record of {}: {}",
+                    entry.domain, e
+                ))
+            })?;
+        let record = Record::from_wire_rdata(entry.domain.into(), entry.qtype, entry.ttl, &rdata)?;
+        batch.push(CacheEntry {
+            record,
+            expiration_date: entry.expires,
+            ecs_network,
+        });
+    }
+    storage.write_cache(&batch).await?;
+    Ok(batch.len())
+}
@@ -0,0 +1,84 @@
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Local};
+use ipnet::IpNet;
+use tokio::{sync::mpsc, task::JoinHandle};
+
+use crate::{storage::Storage, structs::questions_and_records::Record};
+
+/// Entries waiting to be written, the ones exceeding it are dropped.
+const QUEUE: usize = 4096;
+/// Largest number of entries written in a transaction.
+const BATCH: usize = 256;
+
+/// # `CacheEntry`
+///
+/// A record to be cached, served only to the clients in `ecs_network`, if any.
+#[derive(Debug, Clone)]
+pub struct CacheEntry {
+    pub record: Record,
+    pub expiration_date: DateTime<Local>,
+    pub ecs_network: Option<IpNet>,
+}
+
+/// # `CacheWriter`
+///
+/// Writes the records obtained from the other name servers in the storage.
+/// The entries are queued and written in batches, a transaction per batch,
+/// by a task: the resolution never waits for the database, when the task falls behind
+/// the new entries are dropped and the names are resolved again next time.
+#[derive(Debug)]
+pub struct CacheWriter {
+    queue: Mutex<Option<mpsc::Sender<CacheEntry>>>,
+    task: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl CacheWriter {
+    /// # `spawn`
+    ///
+    /// Starts the task writing the entries in `storage`.
+    pub fn spawn(storage: Arc<dyn Storage>) -> Self {
+        let (tx, rx) = mpsc::channel(QUEUE);
+        let task = tokio::spawn(write_entries(rx, storage));
+        CacheWriter {
+            queue: Mutex::new(Some(tx)),
+            task: Mutex::new(Some(task)),
+        }
+    }
+
+    /// # `write`
+    ///
+    /// Queues `entry`, unless the queue is full or the writer has been closed.
+    pub fn write(&self, entry: CacheEntry) {
+        if let Some(queue) = self.queue.lock().unwrap().as_ref() {
+            if queue.try_send(entry).is_err() {
+                tracing::debug!("The cache writer is falling behind, dropped an entry");
+            }
+        }
+    }
+
+    /// # `close`
+    ///
+    /// Stops accepting entries and waits for the task to write the ones queued.
+    pub async fn close(&self) {
+        self.queue.lock().unwrap().take();
+        let task = self.task.lock().unwrap().take();
+        if let Some(task) = task {
+            let _ = task.await;
+        }
+    }
+}
+
+/// # `write_entries`
+///
+/// `CacheWriter`'s task, writes the entries as they are queued.
+async fn write_entries(mut rx: mpsc::Receiver<CacheEntry>, storage: Arc<dyn Storage>) {
+    let mut batch = Vec::with_capacity(BATCH);
+    while rx.recv_many(&mut batch, BATCH).await > 0 {
+        match storage.write_cache(&batch).await {
+            Ok(()) => tracing::info!("Registered {} new entries in the cache", batch.len()),
+            Err(e) => tracing::warn!("Unable to cache {} entries: {}", batch.len(), e),
+        }
+        batch.clear();
+    }
+}
@@ -0,0 +1,170 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use tokio_rustls::rustls::{
+    crypto::ring::sign::any_supported_type,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ServerConfig,
+};
+
+use crate::structs::auxiliaries::{CResult, DnsError};
+
+/// Time between two checks of the files of a certificate.
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// # `CertificateStore`
+///
+/// The certificate a listener presents, read again when its files change or when
+/// the configuration is reloaded: the handshakes that follow present the new one,
+/// the connections already open aren't touched. A certificate that can't be read
+/// leaves the current one in place.
+#[derive(Debug)]
+pub struct CertificateStore {
+    certificate: PathBuf,
+    private_key: PathBuf,
+    /// `None` until a certificate that's still to be obtained has been, the handshakes fail.
+    current: RwLock<Option<Arc<CertifiedKey>>>,
+    /// Modification times of the files the current certificate has been read from.
+    modified: Mutex<[Option<SystemTime>; 2]>,
+}
+
+impl CertificateStore {
+    /// # `load`
+    ///
+    /// Reads the PEM certificate chain at `certificate`, the certificate first,
+    /// and the PEM key at `private_key` it's signed with.
+    pub fn load(certificate: PathBuf, private_key: PathBuf) -> CResult<Self> {
+        let modified = modification_times(&certificate, &private_key);
+        let current = certified_key(&certificate, &private_key)?;
+        Ok(CertificateStore {
+            certificate,
+            private_key,
+            current: RwLock::new(Some(Arc::new(current))),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    /// # `awaiting`
+    ///
+    /// A store without a certificate yet, as when it's obtained with ACME:
+    /// it's presented once written at `certificate` and `private_key`.
+    pub fn awaiting(certificate: PathBuf, private_key: PathBuf) -> Self {
+        tracing::info!(
+            "No certificate in {} yet, the TLS handshakes fail until it's obtained",
+            certificate.display()
+        );
+        CertificateStore {
+            certificate,
+            private_key,
+            current: RwLock::new(None),
+            modified: Mutex::new([None, None]),
+        }
+    }
+
+    /// # `server_config`
+    ///
+    /// TLS configuration presenting the certificate of the store, offering the protocols
+    /// of `alpn`.
+    pub fn server_config(self: &Arc<Self>, alpn: &[&[u8]]) -> Arc<ServerConfig> {
+        let mut config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone());
+        config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+        Arc::new(config)
+    }
+
+    /// # `reload`
+    ///
+    /// Reads the certificate and its key again and presents them from now on.
+    pub fn reload(&self) -> CResult<()> {
+        let modified = modification_times(&self.certificate, &self.private_key);
+        let current = certified_key(&self.certificate, &self.private_key)?;
+        *self.current.write().unwrap() = Some(Arc::new(current));
+        *self.modified.lock().unwrap() = modified;
+        tracing::info!("Loaded the certificate {}", self.certificate.display());
+        Ok(())
+    }
+
+    /// # `watch`
+    ///
+    /// Reloads the certificate whenever its files change, as when it's renewed, checking
+    /// them every `POLL_INTERVAL`. While the new files can't be used, as when only one of
+    /// them has been replaced yet, the current certificate is kept and they are read
+    /// again at the next check.
+    pub async fn watch(self: Arc<Self>) {
+        let mut poll = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            poll.tick().await;
+            let modified = modification_times(&self.certificate, &self.private_key);
+            if modified == *self.modified.lock().unwrap() {
+                continue;
+            }
+            if let Err(e) = self.reload() {
+                tracing::error!(
+                    "Unable to reload the certificate {}, keeping the current one: {}",
+                    self.certificate.display(),
+                    e
+                );
+            }
+        }
+    }
+}
+
+impl ResolvesServerCert for CertificateStore {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.current.read().unwrap().clone()
+    }
+}
+
+/// # `certified_key`
+///
+/// The certificate chain of the PEM file `certificate` along with the key of
+/// the PEM file `private_key`, which has to be the key of the certificate.
+fn certified_key(certificate: &Path, private_key: &Path) -> CResult<CertifiedKey> {
+    let chain = CertificateDer::pem_file_iter(certificate)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| {
+            DnsError::Config(format!(
+                "Unable to read the certificates of {}: {}",
+                certificate.display(),
+                e
+            ))
+        })?;
+    if chain.is_empty() {
+        return Err(DnsError::Config(format!(
+            "{} holds no certificate",
+            certificate.display()
+        )));
+    }
+    let key = PrivateKeyDer::from_pem_file(private_key).map_err(|e| {
+        DnsError::Config(format!(
+            "Unable to read the private key of {}: {}",
+            private_key.display(),
+            e
+        ))
+    })?;
+    let key = any_supported_type(&key)
+        .map_err(|e| DnsError::Config(format!("Unusable private key: {}", e)))?;
+    let certified = CertifiedKey::new(chain, key);
+    // A key that isn't the one of the certificate would fail every handshake
+    certified.keys_match().map_err(|e| {
+        DnsError::Config(format!(
+            "The private key of {} doesn't match the certificate of {}: {}",
+            private_key.display(),
+            certificate.display(),
+            e
+        ))
+    })?;
+    Ok(certified)
+}
+
+/// Modification times of the files of a certificate, `None` for the ones
+/// that can't be read.
+fn modification_times(certificate: &Path, private_key: &Path) -> [Option<SystemTime>; 2] {
+    [certificate, private_key].map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+}
@@ -0,0 +1,68 @@
+use std::{error::Error, net::Ipv4Addr, path::PathBuf};
+
+use clap::Parser;
+
+use crate::configuration::{get_settings, get_settings_from, Settings};
+
+/// # `Cli`
+///
+/// Command line arguments, they take precedence over the configuration file.
+#[derive(Debug, Clone, Parser)]
+#[command(version, about = "A recursive DNS server", long_about = None)]
+pub struct Cli {
+    /// Configuration file, read instead of the files in `configuration/`
+    #[arg(short = 'f', long = "config", value_name = "PATH")]
+    pub config: Option<PathBuf>,
+    /// Address the server listens on
+    #[arg(short, long, value_name = "ADDR")]
+    pub address: Option<Ipv4Addr>,
+    /// Port the server listens on
+    #[arg(short, long)]
+    pub port: Option<u16>,
+    /// Log level, or any filter accepted by `RUST_LOG`, which takes precedence
+    #[arg(short, long, value_name = "FILTER", default_value = "info")]
+    pub log_level: String,
+    /// Don't read or write the cache database
+    #[arg(short = 'c', long = "no-cache")]
+    pub no_cache: bool,
+    /// Detach from the terminal and run in the background,
+    /// the output keeps going where it was redirected
+    #[arg(short, long)]
+    pub daemon: bool,
+    /// Write the process id to this file, removed on exit;
+    /// the server refuses to start if the process it names is still running
+    #[arg(long = "pid-file", value_name = "PATH")]
+    pub pid_file: Option<PathBuf>,
+    /// Run as a Windows service, started by the service control manager;
+    /// the output goes to the event log
+    #[arg(long, conflicts_with = "daemon")]
+    pub service: bool,
+    /// Check the configuration and what it points to, then exit without serving;
+    /// the exit status tells whether it's valid
+    #[arg(long = "check-config", conflicts_with_all = ["daemon", "service"])]
+    pub check_config: bool,
+}
+
+impl Cli {
+    /// # `settings`
+    ///
+    /// Reads the configuration file, applies the arguments on top of it and
+    /// validates the result, called again to reload the configuration.
+    pub fn settings(&self) -> Result<Settings, Box<dyn Error>> {
+        let mut settings = match &self.config {
+            Some(path) => get_settings_from(path)?,
+            None => get_settings()?,
+        };
+        if let Some(addr) = self.address {
+            settings.set_local_server_addr(addr);
+        }
+        if let Some(port) = self.port {
+            settings.set_local_server_port(port);
+        }
+        if self.no_cache {
+            settings.disable_cache();
+        }
+        settings.validate()?;
+        Ok(settings)
+    }
+}
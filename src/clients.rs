@@ -0,0 +1,177 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::{
+    configuration::Settings,
+    structs::questions_and_records::{QueryType, Question, Record},
+    zones::ZoneStore,
+};
+
+/// Time a name found in a PTR record, or its absence, is trusted before looking again.
+const PTR_REFRESH: Duration = Duration::from_secs(3600);
+/// Addresses whose PTR lookups are remembered, they are forgotten at once when exceeded.
+const MAX_PTR_ENTRIES: usize = 65536;
+
+/// # `ClientSource`
+///
+/// Where the name of a client comes from, the configured names take precedence
+/// over the DHCP leases, which take precedence over the PTR records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientSource {
+    Configured,
+    Dhcp,
+    Ptr,
+}
+
+/// # `ClientName`
+///
+/// An entry of the client table.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ClientName {
+    pub address: IpAddr,
+    pub name: String,
+    pub source: ClientSource,
+}
+
+/// # `ClientNames`
+///
+/// Friendly names of the clients, shown in the statistics, the query log, the logs and
+/// the dashboard along with their addresses: the ones configured, the hostnames of
+/// the DHCP leases and, if enabled, the PTR records of the zones we serve.
+pub struct ClientNames {
+    configured: HashMap<IpAddr, String>,
+    dhcp: RwLock<HashMap<IpAddr, String>>,
+    /// Present if the names are looked up in the PTR records of `ZoneStore`.
+    zones: Option<Arc<ZoneStore>>,
+    /// The PTR lookups done, along with when.
+    ptr: Mutex<HashMap<IpAddr, (Option<String>, Instant)>>,
+}
+
+impl fmt::Debug for ClientNames {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientNames")
+            .field("configured", &self.configured)
+            .field("dhcp", &self.dhcp)
+            .field("learn_ptr", &self.zones.is_some())
+            .field("ptr", &self.ptr)
+            .finish()
+    }
+}
+
+impl ClientNames {
+    pub fn from_settings(settings: &Settings, zones: Arc<ZoneStore>) -> Self {
+        ClientNames {
+            configured: settings.get_client_names(),
+            dhcp: RwLock::new(HashMap::new()),
+            zones: settings.get_clients_learn_ptr().then_some(zones),
+            ptr: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// # `name_of`
+    ///
+    /// The name of the client at `address`, if it has one.
+    pub fn name_of(&self, address: IpAddr) -> Option<String> {
+        self.lookup(address).map(|(name, _)| name)
+    }
+
+    /// # `set_dhcp`
+    ///
+    /// Replaces the names learned from the DHCP leases.
+    pub fn set_dhcp(&self, names: HashMap<IpAddr, String>) {
+        *self.dhcp.write().unwrap() = names;
+    }
+
+    /// # `list`
+    ///
+    /// Every client with a name, sorted by address; the PTR records are only looked up
+    /// for the addresses already seen.
+    pub fn list(&self) -> Vec<ClientName> {
+        let mut addresses: Vec<IpAddr> = self.configured.keys().copied().collect();
+        addresses.extend(self.dhcp.read().unwrap().keys());
+        addresses.extend(self.ptr.lock().unwrap().keys());
+        addresses.sort();
+        addresses.dedup();
+        addresses
+            .into_iter()
+            .filter_map(|address| {
+                let (name, source) = self.lookup(address)?;
+                Some(ClientName {
+                    address,
+                    name,
+                    source,
+                })
+            })
+            .collect()
+    }
+
+    fn lookup(&self, address: IpAddr) -> Option<(String, ClientSource)> {
+        if let Some(name) = self.configured.get(&address) {
+            return Some((name.clone(), ClientSource::Configured));
+        }
+        if let Some(name) = self.dhcp.read().unwrap().get(&address) {
+            return Some((name.clone(), ClientSource::Dhcp));
+        }
+        self.ptr_name(address).map(|name| (name, ClientSource::Ptr))
+    }
+
+    /// # `ptr_name`
+    ///
+    /// The host of the PTR record of `address` in the zones we serve, looked up
+    /// again once `PTR_REFRESH` has passed.
+    fn ptr_name(&self, address: IpAddr) -> Option<String> {
+        let zones = self.zones.as_ref()?;
+        let mut ptr = self.ptr.lock().unwrap();
+        if let Some((name, looked_up)) = ptr.get(&address) {
+            if looked_up.elapsed() < PTR_REFRESH {
+                return name.clone();
+            }
+        }
+        let name = zones
+            .answer(&Question::new(reverse_name(address), QueryType::PTR))
+            .and_then(|response| {
+                response
+                    .answers
+                    .into_iter()
+                    .find_map(|record| match record {
+                        Record::PTR { host, .. } => {
+                            Some(host.as_str().trim_end_matches('.').to_string())
+                        }
+                        _ => None,
+                    })
+            });
+        if ptr.len() >= MAX_PTR_ENTRIES {
+            ptr.clear();
+        }
+        ptr.insert(address, (name.clone(), Instant::now()));
+        name
+    }
+}
+
+/// # `reverse_name`
+///
+/// The name of the PTR record of `address`, under `in-addr.arpa` or `ip6.arpa`.
+pub fn reverse_name(address: IpAddr) -> String {
+    match address {
+        IpAddr::V4(address) => {
+            let [a, b, c, d] = address.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", d, c, b, a)
+        }
+        IpAddr::V6(address) => {
+            let mut name = String::with_capacity(72);
+            for byte in address.octets().iter().rev() {
+                name.push_str(&format!("{:x}.{:x}.", byte & 0x0f, byte >> 4));
+            }
+            name.push_str("ip6.arpa");
+            name
+        }
+    }
+}
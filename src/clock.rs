@@ -0,0 +1,86 @@
+use std::{fmt::Debug, sync::Mutex, time::Duration};
+
+use chrono::{DateTime, Local};
+
+/// # `Clock`
+///
+/// Where the expiration of the cached records is measured against,
+/// replaced in the tests to make it deterministic.
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> DateTime<Local>;
+}
+
+/// # `SystemClock`
+///
+/// The time of the system.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Local> {
+        Local::now()
+    }
+}
+
+/// # `ManualClock`
+///
+/// A clock frozen at the time it's given, it only moves when it's told to.
+#[derive(Debug)]
+pub struct ManualClock {
+    now: Mutex<DateTime<Local>>,
+}
+
+impl ManualClock {
+    pub fn new(now: DateTime<Local>) -> Self {
+        ManualClock {
+            now: Mutex::new(now),
+        }
+    }
+
+    pub fn set(&self, now: DateTime<Local>) {
+        *self.now.lock().unwrap() = now;
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Local> {
+        *self.now.lock().unwrap()
+    }
+}
+
+/// # `TokioClock`
+///
+/// The time of the system when the clock was created, moved forward as the time
+/// of the tokio runtime goes by: once the time of the runtime is paused
+/// (`tokio::time::pause`) the clock follows `tokio::time::advance`, and the timers
+/// of the runtime stay in step with the expiration of the records.
+#[derive(Debug)]
+pub struct TokioClock {
+    started: DateTime<Local>,
+    start: tokio::time::Instant,
+}
+
+impl TokioClock {
+    pub fn new() -> Self {
+        TokioClock {
+            started: Local::now(),
+            start: tokio::time::Instant::now(),
+        }
+    }
+}
+
+impl Default for TokioClock {
+    fn default() -> Self {
+        TokioClock::new()
+    }
+}
+
+impl Clock for TokioClock {
+    fn now(&self) -> DateTime<Local> {
+        self.started + self.start.elapsed()
+    }
+}
@@ -1,56 +1,1790 @@
-use std::{env, error::Error, net::Ipv4Addr};
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    error::Error,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
 use config::Config;
+use ipnet::IpNet;
+use regex::Regex;
 use serde::Deserialize;
 
+use crate::{
+    acl::{DeniedAction, NetworkList},
+    acme::AcmeChallenge,
+    blocklist::{rules::BlockRules, BlockedAnswer, BlockingMode, PolicyGroup},
+    database::{JournalMode, Synchronous},
+    ddr::{DesignatedResolver, EncryptedProtocol, DEFAULT_DOHPATH},
+    dhcp::LeaseFormat,
+    domainpolicy::{pinned_record, DomainAction, DomainPolicy},
+    idn,
+    mdns::is_local,
+    outbound::{
+        DnsStamp, DnscryptTransport, SignedTransport, Socks5Proxy, TcpTransport, TlsTransport,
+        Transport, TransportKind,
+    },
+    querylog::QueryLogTarget,
+    specialuse::default_names,
+    storage::StorageKind,
+    structs::questions_and_records::QueryType,
+    telemetry::{LogFormat, LogOptions, SamplingRule},
+    tsig::{Keyring, TsigAlgorithm, TsigKey},
+    workers::{AnyPolicy, MiddlewareKind, OverflowPolicy, TldPolicy},
+};
+
+/// TTL of the answers for blocked domains, kept short so that changes to the
+/// lists take effect quickly on the clients.
+const DEFAULT_BLOCKED_TTL: u32 = 2;
+
+/// Time a connection waits for the database locked by another one, in milliseconds.
+const DEFAULT_BUSY_TIMEOUT: u64 = 5000;
+
+/// Connections to the database kept open at most.
+const DEFAULT_MAX_CONNECTIONS: u32 = 10;
+
+/// Longest time a server failure may be cached for, in milliseconds (RFC 2308, section 7.1).
+const MAX_SERVFAIL_TTL: u64 = 300_000;
+
 #[derive(Debug, Deserialize)]
 pub struct Settings {
     local_server: ServerSettings,
     root_server: ServerSettings,
     database: DatabaseSettings,
+    #[serde(default)]
+    notify: NotifySettings,
+    #[serde(default)]
+    secondary_zones: Vec<SecondaryZoneSettings>,
+    #[serde(default)]
+    tsig_keys: Vec<TsigKeySettings>,
+    #[serde(default)]
+    dnssec: DnssecSettings,
+    #[serde(default)]
+    blocking: BlockingSettings,
+    #[serde(default)]
+    safe_search: SafeSearchSettings,
+    #[serde(default)]
+    acl: AclSettings,
+    #[serde(default)]
+    limits: LimitsSettings,
+    #[serde(default)]
+    tarpit: TarpitSettings,
+    #[serde(default)]
+    upstream: UpstreamSettings,
+    #[serde(default)]
+    udp: UdpSettings,
+    #[serde(default)]
+    tcp: TcpSettings,
+    #[serde(default)]
+    telemetry: TelemetrySettings,
+    #[serde(default)]
+    query_log: QueryLogSettings,
+    #[serde(default)]
+    stats: StatsSettings,
+    #[serde(default)]
+    control: ControlSettings,
+    #[serde(default)]
+    dashboard: DashboardSettings,
+    #[serde(default)]
+    api: ApiSettings,
+    #[serde(default)]
+    doh: DohSettings,
+    #[serde(default)]
+    acme: AcmeSettings,
+    #[serde(default)]
+    logging: LoggingSettings,
+    #[serde(default)]
+    health: HealthSettings,
+    #[serde(default)]
+    dhcp: DhcpSettings,
+    #[serde(default)]
+    clients: ClientsSettings,
+    #[serde(default)]
+    mdns: MdnsSettings,
+    #[serde(default)]
+    special_use: SpecialUseSettings,
+    #[serde(default)]
+    ddr: DdrSettings,
+    #[serde(default)]
+    ecs: EcsSettings,
+    #[serde(default)]
+    any: AnySettings,
+    #[serde(default)]
+    tld: TldSettings,
+    #[serde(default)]
+    rotation: RotationSettings,
+    #[serde(default)]
+    pipeline: PipelineSettings,
+    #[serde(default)]
+    scripting: ScriptingSettings,
+    #[serde(default)]
+    cache: CacheSettings,
+    #[serde(default)]
+    domain_policies: Vec<DomainPolicySettings>,
 }
 
-impl Settings {
-    pub fn get_local_server_full_domain(&self) -> String {
-        self.local_server.get_full_domain()
+impl Settings {
+    pub fn get_local_server_full_domain(&self) -> String {
+        self.local_server.get_full_domain()
+    }
+
+    pub fn get_root_server_full_domain(&self) -> String {
+        self.root_server.get_full_domain()
+    }
+
+    pub fn get_local_server_addr(&self) -> Ipv4Addr {
+        self.local_server.get_addr()
+    }
+
+    pub fn get_root_server_addr(&self) -> Ipv4Addr {
+        self.root_server.get_addr()
+    }
+
+    /// # `get_root_server_port`
+    ///
+    /// The port every name server is queried on, not only the root.
+    pub fn get_root_server_port(&self) -> u16 {
+        self.root_server.port
+    }
+
+    pub fn get_db_url(&self) -> String {
+        self.database.get_db_url()
+    }
+
+    pub fn get_migrations_dir(&self) -> String {
+        self.database.get_migrations_dir()
+    }
+
+    /// # `get_db_journal_mode`
+    pub fn get_db_journal_mode(&self) -> JournalMode {
+        self.database.journal_mode
+    }
+
+    /// # `get_db_synchronous`
+    pub fn get_db_synchronous(&self) -> Synchronous {
+        self.database.synchronous
+    }
+
+    /// # `get_db_busy_timeout`
+    ///
+    /// Time a connection waits for the database locked by another one before failing.
+    pub fn get_db_busy_timeout(&self) -> Duration {
+        Duration::from_millis(self.database.busy_timeout.unwrap_or(DEFAULT_BUSY_TIMEOUT))
+    }
+
+    /// # `get_db_max_connections`
+    ///
+    /// Size of the pool of connections to the database.
+    pub fn get_db_max_connections(&self) -> u32 {
+        self.database
+            .max_connections
+            .unwrap_or(DEFAULT_MAX_CONNECTIONS)
+    }
+
+    /// # `get_db_kind`
+    ///
+    /// The database the cache and the statistics are kept in.
+    pub fn get_db_kind(&self) -> StorageKind {
+        self.database.kind
+    }
+
+    /// # `get_db_postgres_url`
+    ///
+    /// URL of the PostgreSQL database, for the `postgres` kind.
+    pub fn get_db_postgres_url(&self) -> Option<String> {
+        self.database.url.clone()
+    }
+
+    /// # `set_local_server_addr`
+    ///
+    /// Replaces the address the server listens on.
+    pub fn set_local_server_addr(&mut self, addr: Ipv4Addr) {
+        self.local_server.addr = addr;
+    }
+
+    /// # `set_local_server_port`
+    ///
+    /// Replaces the port the server listens on.
+    pub fn set_local_server_port(&mut self, port: u16) {
+        self.local_server.port = port;
+    }
+
+    /// # `get_cache_enabled`
+    ///
+    /// Whether the records obtained from the other name servers are cached in the database.
+    pub fn get_cache_enabled(&self) -> bool {
+        !self.database.disable_cache
+    }
+
+    /// # `get_cache_min_ttl`
+    ///
+    /// Shortest TTL of the records cached and served from the resolution, in seconds.
+    pub fn get_cache_min_ttl(&self) -> u32 {
+        self.cache.min_ttl
+    }
+
+    /// # `get_cache_max_ttl`
+    ///
+    /// Longest TTL of the records cached and served from the resolution, in seconds.
+    pub fn get_cache_max_ttl(&self) -> u32 {
+        self.cache.max_ttl
+    }
+
+    /// # `get_cache_bootstrap`
+    ///
+    /// File of the records cached at startup, see `bootstrap::BootstrapFile`.
+    pub fn get_cache_bootstrap(&self) -> Option<&Path> {
+        self.cache.bootstrap.as_deref()
+    }
+
+    /// # `get_cache_bootstrap_ttl`
+    ///
+    /// TTL of the records of the bootstrap file that don't have one, in seconds.
+    pub fn get_cache_bootstrap_ttl(&self) -> u32 {
+        self.cache.bootstrap_ttl
+    }
+
+    /// # `get_domain_policies`
+    ///
+    /// Overrides of the resolution for some domains and the names below them.
+    pub fn get_domain_policies(&self) -> Vec<DomainPolicy> {
+        self.domain_policies
+            .iter()
+            .map(|policy| {
+                // The stamp has been validated
+                let (upstream, transport, tls_name) =
+                    policy
+                        .forwarding()
+                        .unwrap_or((Vec::new(), TransportKind::Udp, None));
+                DomainPolicy {
+                    suffix: normalize_name(&policy.suffix),
+                    action: if policy.block {
+                        DomainAction::Block
+                    } else if !policy.local.is_empty() {
+                        DomainAction::Local(policy.local.clone())
+                    } else if !policy.records.is_empty() {
+                        // The records have been validated
+                        DomainAction::Pinned(
+                            policy
+                                .records
+                                .iter()
+                                .filter_map(|line| pinned_record(line).ok())
+                                .collect(),
+                        )
+                    } else if !upstream.is_empty() {
+                        DomainAction::Forward(upstream)
+                    } else {
+                        DomainAction::Resolve
+                    },
+                    transport: {
+                        // The proxy has been validated
+                        let proxy = policy
+                            .proxy
+                            .as_deref()
+                            .and_then(|proxy| proxy.parse::<Socks5Proxy>().ok());
+                        // The key has been validated
+                        let key = policy
+                            .tsig_key
+                            .as_deref()
+                            .and_then(|name| self.tsig_key(name));
+                        match (transport, tls_name.as_deref()) {
+                            (TransportKind::Udp, _) => key.map(|key| {
+                                Arc::new(SignedTransport::new(key)) as Arc<dyn Transport>
+                            }),
+                            (TransportKind::Tcp, _) if key.is_some() => key.map(|key| {
+                                Arc::new(SignedTransport::new(key).over_tcp(proxy))
+                                    as Arc<dyn Transport>
+                            }),
+                            (TransportKind::Tcp, _) => {
+                                let transport = TcpTransport::new();
+                                Some(Arc::new(match proxy {
+                                    Some(proxy) => transport.with_proxy(proxy),
+                                    None => transport,
+                                }) as Arc<dyn Transport>)
+                            }
+                            // The name has been validated
+                            (TransportKind::Tls, name) => name
+                                .and_then(|name| TlsTransport::new(name).ok())
+                                .map(|transport| match proxy {
+                                    Some(proxy) => transport.with_proxy(proxy),
+                                    None => transport,
+                                })
+                                .map(|transport| Arc::new(transport) as Arc<dyn Transport>),
+                            // The stamp has been validated
+                            (TransportKind::Dnscrypt, name) => {
+                                name.zip(policy.stamp.as_deref()).and_then(|(name, stamp)| {
+                                    let stamp = stamp.parse::<DnsStamp>().ok()?;
+                                    let transport = DnscryptTransport::new(
+                                        name,
+                                        stamp.public_key()?,
+                                        stamp.addr()?.port(),
+                                    );
+                                    Some(Arc::new(transport) as Arc<dyn Transport>)
+                                })
+                            }
+                        }
+                    },
+                    ttl: policy.ttl,
+                    no_cache: policy.no_cache,
+                    exact: policy.exact,
+                }
+            })
+            .collect()
+    }
+
+    /// # `disable_cache`
+    ///
+    /// Stops the server from reading and writing the cache database.
+    pub fn disable_cache(&mut self) {
+        self.database.disable_cache = true;
+    }
+
+    /// # `set_root_server`
+    ///
+    /// The resolution starts from `addr` and the name servers are queried on `port`,
+    /// as the fake name servers of the tests need.
+    pub fn set_root_server(&mut self, addr: Ipv4Addr, port: u16) {
+        self.root_server = ServerSettings { addr, port };
+    }
+
+    /// # `add_tsig_key`
+    ///
+    /// Adds the TSIG key `name`, whose secret is base64 encoded, to the configured ones.
+    pub fn add_tsig_key(&mut self, name: &str, algorithm: TsigAlgorithm, secret: &str) {
+        self.tsig_keys.push(TsigKeySettings {
+            name: name.to_string(),
+            algorithm,
+            secret: secret.to_string(),
+        });
+    }
+
+    /// # `serve_doh`
+    ///
+    /// Serves DNS over HTTPS on `addr` with the certificate and the private key
+    /// in the PEM files given.
+    pub fn serve_doh(&mut self, addr: SocketAddr, certificate: &str, private_key: &str) {
+        self.doh.address = Some(addr);
+        self.doh.certificate = certificate.to_string();
+        self.doh.private_key = private_key.to_string();
+    }
+
+    /// # `deny_recursion`
+    ///
+    /// Adds `network` to the clients denied recursion.
+    pub fn deny_recursion(&mut self, network: IpNet) {
+        self.acl.deny_recursion.push(network);
+    }
+
+    // # `set_test_db`
+    //
+    // Genetare a random name for a test database the will be used instead of the name provided in
+    // the config.
+    pub fn set_test_db(&mut self) {
+        self.database.set_test_env();
+    }
+
+    /// # `get_db_path`
+    ///
+    /// Obtains the path to the database file
+    pub fn get_db_path(&self) -> String {
+        self.database.get_path()
+    }
+
+    /// # `get_notify_secondaries`
+    ///
+    /// Secondary servers that receive a NOTIFY when a hosted zone changes.
+    pub fn get_notify_secondaries(&self) -> Vec<SocketAddr> {
+        self.notify.secondaries.clone()
+    }
+
+    /// # `get_notify_primaries`
+    ///
+    /// Primary servers whose NOTIFY messages are accepted.
+    pub fn get_notify_primaries(&self) -> Vec<IpAddr> {
+        self.notify.primaries.clone()
+    }
+
+    /// # `get_secondary_zones`
+    ///
+    /// Zones pulled from a primary and served authoritatively.
+    pub fn get_secondary_zones(&self) -> &[SecondaryZoneSettings] {
+        &self.secondary_zones
+    }
+
+    /// # `get_notify_tsig_key`
+    ///
+    /// Name of the TSIG key used to sign the NOTIFY messages we send and
+    /// required on the ones we receive.
+    pub fn get_notify_tsig_key(&self) -> Option<String> {
+        self.notify.tsig_key.clone()
+    }
+
+    /// # `get_dnssec_keys_dir`
+    ///
+    /// Directory where the signing keys of the zones are stored.
+    pub fn get_dnssec_keys_dir(&self) -> PathBuf {
+        PathBuf::from(&self.dnssec.keys_dir)
+    }
+
+    /// # `get_signature_validity`
+    ///
+    /// How long the signatures we produce are valid for.
+    pub fn get_signature_validity(&self) -> Duration {
+        Duration::from_secs(self.dnssec.signature_validity * 24 * 60 * 60)
+    }
+
+    /// # `get_blocklists`
+    ///
+    /// Files containing the domains to block, in hosts or domain-list format.
+    pub fn get_blocklists(&self) -> Vec<PathBuf> {
+        self.blocking.lists.iter().map(PathBuf::from).collect()
+    }
+
+    /// # `get_blocklist_urls`
+    ///
+    /// Lists downloaded over HTTP, in the same formats of the files.
+    pub fn get_blocklist_urls(&self) -> Vec<String> {
+        self.blocking.urls.clone()
+    }
+
+    /// # `get_blocklist_update_interval`
+    ///
+    /// How often the lists are loaded again, `None` if they are loaded only at startup.
+    pub fn get_blocklist_update_interval(&self) -> Option<Duration> {
+        match self.blocking.update_interval {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        }
+    }
+
+    /// # `get_blocking_wildcards`
+    ///
+    /// Rules in the form `*.example.com`, blocking every subdomain of `example.com`.
+    pub fn get_blocking_wildcards(&self) -> Vec<String> {
+        self.blocking.wildcards.clone()
+    }
+
+    /// # `get_blocking_regexes`
+    ///
+    /// Regular expressions matched against the queried names.
+    pub fn get_blocking_regexes(&self) -> Vec<String> {
+        self.blocking.regexes.clone()
+    }
+
+    /// # `get_allowlist`
+    ///
+    /// Domains that are never blocked, lowercase and without the trailing dot.
+    pub fn get_allowlist(&self) -> Vec<String> {
+        self.blocking
+            .allowlist
+            .iter()
+            .map(|d| d.trim_end_matches('.').to_lowercase())
+            .collect()
+    }
+
+    /// # `get_blocked_answer`
+    ///
+    /// How the queries for blocked domains are answered, unless the client
+    /// belongs to a policy group.
+    pub fn get_blocked_answer(&self) -> BlockedAnswer {
+        BlockedAnswer {
+            mode: self.blocking.mode,
+            ipv4: self.blocking.block_ipv4,
+            ipv6: self.blocking.block_ipv6,
+            ttl: self.get_blocked_ttl(),
+        }
+    }
+
+    /// # `get_policy_groups`
+    ///
+    /// Groups of clients whose blocked queries are answered differently,
+    /// in the order they are matched.
+    pub fn get_policy_groups(&self) -> Vec<PolicyGroup> {
+        self.blocking
+            .groups
+            .iter()
+            .map(|group| PolicyGroup {
+                name: group.name.clone(),
+                clients: group.clients.clone(),
+                answer: BlockedAnswer {
+                    mode: group.mode,
+                    ipv4: group.block_ipv4,
+                    ipv6: group.block_ipv6,
+                    ttl: self.get_blocked_ttl(),
+                },
+            })
+            .collect()
+    }
+
+    /// # `get_blocked_ttl`
+    ///
+    /// TTL of the answers for blocked domains, used as negative TTL as well.
+    fn get_blocked_ttl(&self) -> u32 {
+        self.blocking.blocked_ttl.unwrap_or(DEFAULT_BLOCKED_TTL)
+    }
+
+    /// # `get_safe_search`
+    ///
+    /// Whether the search engines are forced into their safe mode.
+    pub fn get_safe_search(&self) -> bool {
+        self.safe_search.enabled
+    }
+
+    /// # `get_query_acl`
+    ///
+    /// Networks allowed and denied to query the server.
+    pub fn get_query_acl(&self) -> NetworkList {
+        NetworkList {
+            allow: self.acl.allow_query.clone(),
+            deny: self.acl.deny_query.clone(),
+        }
+    }
+
+    /// # `get_recursion_acl`
+    ///
+    /// Networks allowed and denied to ask for recursion.
+    pub fn get_recursion_acl(&self) -> NetworkList {
+        NetworkList {
+            allow: self.acl.allow_recursion.clone(),
+            deny: self.acl.deny_recursion.clone(),
+        }
+    }
+
+    /// # `get_denied_action`
+    ///
+    /// What happens to the queries of the clients that aren't allowed to query us.
+    pub fn get_denied_action(&self) -> DeniedAction {
+        self.acl.denied_action
+    }
+
+    /// # `get_max_in_flight_queries`
+    ///
+    /// Maximum number of queries handled at the same time.
+    pub fn get_max_in_flight_queries(&self) -> usize {
+        self.limits.max_in_flight_queries
+    }
+
+    /// # `get_overflow_policy`
+    ///
+    /// What happens to the queries exceeding `get_max_in_flight_queries`.
+    pub fn get_overflow_policy(&self) -> OverflowPolicy {
+        self.limits.overflow_policy
+    }
+
+    /// # `get_shutdown_timeout`
+    ///
+    /// Time given to the queries being handled to complete on shutdown.
+    pub fn get_shutdown_timeout(&self) -> Duration {
+        Duration::from_millis(self.limits.shutdown_timeout)
+    }
+
+    /// # `get_tarpit_enabled`
+    ///
+    /// Whether the abusive clients are penalized.
+    pub fn get_tarpit_enabled(&self) -> bool {
+        self.tarpit.enabled
+    }
+
+    /// # `get_tarpit_rate`
+    ///
+    /// Queries a client may send per second.
+    pub fn get_tarpit_rate(&self) -> u32 {
+        self.tarpit.rate
+    }
+
+    /// # `get_tarpit_burst`
+    ///
+    /// Queries a client may send at once, above its rate.
+    pub fn get_tarpit_burst(&self) -> u32 {
+        self.tarpit.burst
+    }
+
+    /// # `get_tarpit_delay`
+    ///
+    /// Delay of the queries of a client at its first strike.
+    pub fn get_tarpit_delay(&self) -> Duration {
+        Duration::from_millis(self.tarpit.delay)
+    }
+
+    /// # `get_tarpit_drop_after`
+    ///
+    /// Strikes from which the queries of a client are dropped.
+    pub fn get_tarpit_drop_after(&self) -> u32 {
+        self.tarpit.drop_after
+    }
+
+    /// # `get_tarpit_forget_after`
+    ///
+    /// Time a client has to behave for its strikes to be forgotten.
+    pub fn get_tarpit_forget_after(&self) -> Duration {
+        Duration::from_secs(self.tarpit.forget_after)
+    }
+
+    /// # `get_upstream_attempt_timeout`
+    ///
+    /// Time waited for the response of a name server to a single attempt.
+    pub fn get_upstream_attempt_timeout(&self) -> Duration {
+        Duration::from_millis(self.upstream.attempt_timeout)
+    }
+
+    /// # `get_upstream_attempts`
+    ///
+    /// Maximum number of attempts for a single query to the name servers, at least one.
+    pub fn get_upstream_attempts(&self) -> u32 {
+        self.upstream.attempts.max(1)
+    }
+
+    /// # `get_upstream_initial_backoff`
+    ///
+    /// Pause before retrying a query, doubled at every attempt.
+    pub fn get_upstream_initial_backoff(&self) -> Duration {
+        Duration::from_millis(self.upstream.initial_backoff)
+    }
+
+    /// # `get_query_deadline`
+    ///
+    /// Time available for the whole resolution of a client query, from its arrival,
+    /// the attempts sent on its behalf included.
+    pub fn get_query_deadline(&self) -> Duration {
+        Duration::from_millis(self.upstream.query_deadline)
+    }
+
+    /// # `get_upstream_failure_threshold`
+    ///
+    /// Consecutive failures after which a name server isn't queried for a while, at least one.
+    pub fn get_upstream_failure_threshold(&self) -> u32 {
+        self.upstream.failure_threshold.max(1)
+    }
+
+    /// # `get_upstream_cooldown`
+    ///
+    /// Time a failing name server isn't queried for.
+    pub fn get_upstream_cooldown(&self) -> Duration {
+        Duration::from_millis(self.upstream.cooldown)
+    }
+
+    /// # `get_servfail_ttl`
+    ///
+    /// Time a failed resolution is remembered for, RFC 2308 caps it at five minutes.
+    pub fn get_servfail_ttl(&self) -> Duration {
+        Duration::from_millis(self.upstream.servfail_ttl.min(MAX_SERVFAIL_TTL))
+    }
+
+    /// # `get_upstream_sockets`
+    ///
+    /// Number of sockets the queries to the name servers are sent through.
+    pub fn get_upstream_sockets(&self) -> usize {
+        self.upstream.sockets
+    }
+
+    /// # `get_upstream_hedge_delay`
+    ///
+    /// Pause after which the following name server of a delegation is queried as well,
+    /// `None` if the queries aren't hedged.
+    pub fn get_upstream_hedge_delay(&self) -> Option<Duration> {
+        (self.upstream.hedge_delay > 0).then(|| Duration::from_millis(self.upstream.hedge_delay))
+    }
+
+    /// # `get_upstream_ipv6`
+    ///
+    /// Whether the name servers are queried on their IPv6 addresses too.
+    pub fn get_upstream_ipv6(&self) -> bool {
+        self.upstream.ipv6
+    }
+
+    /// # `get_upstream_ipv6_head_start`
+    ///
+    /// Time an IPv6 address of a name server is given to answer before an IPv4 one
+    /// is queried as well.
+    pub fn get_upstream_ipv6_head_start(&self) -> Duration {
+        Duration::from_millis(self.upstream.ipv6_head_start)
+    }
+
+    /// # `get_udp_batch_size`
+    ///
+    /// Datagrams received or sent with a single system call, at least one.
+    pub fn get_udp_batch_size(&self) -> usize {
+        self.udp.batch_size.max(1)
+    }
+
+    /// # `get_strict_parsing`
+    ///
+    /// Whether the queries that aren't well formed are answered with FORMERR,
+    /// instead of answering what can be parsed of them.
+    pub fn get_strict_parsing(&self) -> bool {
+        self.udp.strict_parsing
+    }
+
+    /// # `get_tcp_enabled`
+    ///
+    /// Whether the queries are received over TCP too, on the address of UDP.
+    pub fn get_tcp_enabled(&self) -> bool {
+        self.tcp.enabled
+    }
+
+    /// # `get_tcp_idle_timeout`
+    ///
+    /// Time a TCP connection is kept open without queries, advertised to the clients
+    /// that ask for it (RFC 7828).
+    pub fn get_tcp_idle_timeout(&self) -> Duration {
+        Duration::from_millis(self.tcp.idle_timeout)
+    }
+
+    /// # `get_tcp_read_timeout`
+    ///
+    /// Time a client is given to send a whole message once it has started sending it.
+    pub fn get_tcp_read_timeout(&self) -> Duration {
+        Duration::from_millis(self.tcp.read_timeout)
+    }
+
+    /// # `get_tcp_max_connections`
+    ///
+    /// TCP connections open at the same time at most.
+    pub fn get_tcp_max_connections(&self) -> usize {
+        self.tcp.max_connections
+    }
+
+    /// # `get_tcp_max_connections_per_ip`
+    ///
+    /// TCP connections a single client address may keep open at the same time.
+    pub fn get_tcp_max_connections_per_ip(&self) -> usize {
+        self.tcp.max_connections_per_ip
+    }
+
+    /// # `get_metrics_address`
+    ///
+    /// Address serving the metrics to Prometheus, `None` if they aren't exported.
+    pub fn get_metrics_address(&self) -> Option<SocketAddr> {
+        self.telemetry.metrics_address
+    }
+
+    /// # `get_query_log_target`
+    ///
+    /// Where the query log is written, `None` if it's disabled.
+    pub fn get_query_log_target(&self) -> Option<QueryLogTarget> {
+        self.query_log.enabled.then_some(self.query_log.target)
+    }
+
+    /// # `get_query_log_directory`
+    ///
+    /// Directory of the files of the query log, when written to files.
+    pub fn get_query_log_directory(&self) -> PathBuf {
+        PathBuf::from(&self.query_log.directory)
+    }
+
+    /// # `get_query_log_retention_days`
+    ///
+    /// Days the entries of the query log are kept for.
+    pub fn get_query_log_retention_days(&self) -> u32 {
+        self.query_log.retention_days
+    }
+
+    /// # `get_stats_enabled`
+    ///
+    /// Whether the queries are counted for the statistics.
+    pub fn get_stats_enabled(&self) -> bool {
+        self.stats.enabled
+    }
+
+    /// # `get_stats_retention_days`
+    ///
+    /// Days the statistics are kept for.
+    pub fn get_stats_retention_days(&self) -> u32 {
+        self.stats.retention_days
+    }
+
+    /// # `get_control_socket`
+    ///
+    /// Path of the control socket, `None` if it's disabled.
+    pub fn get_control_socket(&self) -> Option<PathBuf> {
+        self.control
+            .enabled
+            .then(|| PathBuf::from(&self.control.socket))
+    }
+
+    /// # `get_dashboard_address`
+    ///
+    /// Address serving the endpoints of the dashboard, `None` if they aren't served.
+    pub fn get_dashboard_address(&self) -> Option<SocketAddr> {
+        self.dashboard.address
+    }
+
+    /// # `get_api_address`
+    ///
+    /// Address serving the management API, `None` if it isn't served.
+    pub fn get_api_address(&self) -> Option<SocketAddr> {
+        self.api.address
+    }
+
+    /// # `get_api_token`
+    ///
+    /// Bearer token the requests to the management API have to carry.
+    pub fn get_api_token(&self) -> Option<&str> {
+        self.api.token.as_deref()
+    }
+
+    /// # `get_doh_address`
+    ///
+    /// Address serving DNS over HTTPS, `None` if it isn't served.
+    pub fn get_doh_address(&self) -> Option<SocketAddr> {
+        self.doh.address
+    }
+
+    /// # `get_doh_certificate`
+    ///
+    /// PEM file holding the certificate chain of the DoH endpoint, the certificate first.
+    pub fn get_doh_certificate(&self) -> PathBuf {
+        PathBuf::from(&self.doh.certificate)
+    }
+
+    /// # `get_doh_private_key`
+    ///
+    /// PEM file holding the private key of the certificate of the DoH endpoint.
+    pub fn get_doh_private_key(&self) -> PathBuf {
+        PathBuf::from(&self.doh.private_key)
+    }
+
+    /// # `get_doh_path`
+    ///
+    /// Path the DoH queries are received on.
+    pub fn get_doh_path(&self) -> &str {
+        &self.doh.path
+    }
+
+    /// # `get_doh_max_streams`
+    ///
+    /// Requests an HTTP/2 connection to the DoH endpoint may carry at the same time.
+    pub fn get_doh_max_streams(&self) -> u32 {
+        self.doh.max_streams
+    }
+
+    /// # `get_acme_enabled`
+    ///
+    /// Whether the certificate of the DoH endpoint is obtained and renewed with ACME.
+    pub fn get_acme_enabled(&self) -> bool {
+        self.acme.enabled
+    }
+
+    /// # `get_acme_directory`
+    ///
+    /// URL of the directory of the ACME CA.
+    pub fn get_acme_directory(&self) -> &str {
+        &self.acme.directory
+    }
+
+    /// # `get_acme_contact`
+    ///
+    /// Email address the CA may write to about the account.
+    pub fn get_acme_contact(&self) -> Option<&str> {
+        self.acme.contact.as_deref()
+    }
+
+    /// # `get_acme_domains`
+    ///
+    /// Names the certificate is valid for.
+    pub fn get_acme_domains(&self) -> &[String] {
+        &self.acme.domains
+    }
+
+    /// # `get_acme_challenge`
+    ///
+    /// How the CA checks that we control the names.
+    pub fn get_acme_challenge(&self) -> AcmeChallenge {
+        self.acme.challenge
+    }
+
+    /// # `get_acme_http01_address`
+    ///
+    /// Address answering the HTTP-01 challenges, `None` if they aren't used.
+    pub fn get_acme_http01_address(&self) -> Option<SocketAddr> {
+        self.acme
+            .http01_address
+            .filter(|_| self.acme.enabled && self.acme.challenge == AcmeChallenge::Http01)
+    }
+
+    /// # `get_acme_account_key`
+    ///
+    /// File holding the key of the account at the CA, PKCS#8 encoded.
+    pub fn get_acme_account_key(&self) -> PathBuf {
+        PathBuf::from(&self.acme.account_key)
+    }
+
+    /// # `get_acme_renew_before`
+    ///
+    /// How long before its expiration the certificate is renewed.
+    pub fn get_acme_renew_before(&self) -> Duration {
+        Duration::from_secs(self.acme.renew_before * 24 * 60 * 60)
+    }
+
+    /// # `get_self_test_name`
+    ///
+    /// Name resolved at startup before the server reports it's ready,
+    /// `None` if the server is ready as soon as it starts.
+    pub fn get_self_test_name(&self) -> Option<String> {
+        self.health.self_test.clone()
+    }
+
+    /// # `get_dhcp_leases`
+    ///
+    /// Lease files of the DHCP servers whose devices are published, along with their format.
+    pub fn get_dhcp_leases(&self) -> Vec<(PathBuf, LeaseFormat)> {
+        self.dhcp
+            .leases
+            .iter()
+            .map(|file| (PathBuf::from(&file.path), file.format))
+            .collect()
+    }
+
+    /// # `get_dhcp_domain`
+    ///
+    /// Zone the devices are published in, lowercase and without the trailing dot.
+    pub fn get_dhcp_domain(&self) -> String {
+        self.dhcp.domain.trim_end_matches('.').to_lowercase()
+    }
+
+    /// # `get_dhcp_ttl`
+    ///
+    /// TTL of the records of the devices, in seconds.
+    pub fn get_dhcp_ttl(&self) -> u32 {
+        self.dhcp.ttl
+    }
+
+    /// # `get_client_names`
+    ///
+    /// Names given to the clients by their address.
+    pub fn get_client_names(&self) -> HashMap<IpAddr, String> {
+        self.clients
+            .names
+            .iter()
+            .map(|client| (client.address, client.name.clone()))
+            .collect()
+    }
+
+    /// # `get_clients_learn_ptr`
+    ///
+    /// Whether the clients without a name are named after the PTR records of our zones.
+    pub fn get_clients_learn_ptr(&self) -> bool {
+        self.clients.learn_ptr
+    }
+
+    pub fn get_mdns_enabled(&self) -> bool {
+        self.mdns.enabled
+    }
+
+    /// # `get_mdns_records`
+    ///
+    /// Names announced by mDNS, along with their addresses.
+    pub fn get_mdns_records(&self) -> Vec<(String, IpAddr)> {
+        self.mdns
+            .records
+            .iter()
+            .map(|r| (normalize_name(&r.name), r.address))
+            .collect()
+    }
+
+    /// # `get_mdns_interface`
+    ///
+    /// Address of the interface mDNS runs on, unspecified for the default one.
+    pub fn get_mdns_interface(&self) -> Ipv4Addr {
+        self.mdns.interface
+    }
+
+    /// # `get_mdns_bridge_timeout`
+    ///
+    /// Time waited for the network to answer a `.local` query received by unicast,
+    /// `None` if they aren't bridged to mDNS.
+    pub fn get_mdns_bridge_timeout(&self) -> Option<Duration> {
+        self.mdns
+            .bridge
+            .then(|| Duration::from_millis(self.mdns.bridge_timeout))
+    }
+
+    /// # `get_ddr_resolvers`
+    ///
+    /// The encrypted endpoints advertised to the clients, on the default port
+    /// of their protocol and, for DNS over HTTPS, on the default path unless set.
+    pub fn get_ddr_resolvers(&self) -> Vec<DesignatedResolver> {
+        self.ddr
+            .endpoints
+            .iter()
+            .map(|endpoint| DesignatedResolver {
+                target: normalize_name(&endpoint.target),
+                protocol: endpoint.protocol,
+                port: endpoint
+                    .port
+                    .unwrap_or_else(|| endpoint.protocol.default_port()),
+                dohpath: match endpoint.protocol {
+                    EncryptedProtocol::Dot => None,
+                    EncryptedProtocol::Doh => Some(
+                        endpoint
+                            .dohpath
+                            .clone()
+                            .unwrap_or_else(|| DEFAULT_DOHPATH.to_string()),
+                    ),
+                },
+                ipv4hint: endpoint.ipv4hint.clone(),
+                ipv6hint: endpoint.ipv6hint.clone(),
+            })
+            .collect()
+    }
+
+    pub fn get_special_use_enabled(&self) -> bool {
+        self.special_use.enabled
+    }
+
+    /// # `get_special_use_extra`
+    ///
+    /// Names answered locally besides the special-use ones, lowercase and without the trailing dot.
+    pub fn get_special_use_extra(&self) -> Vec<String> {
+        self.special_use
+            .extra
+            .iter()
+            .map(|name| normalize_name(name))
+            .collect()
+    }
+
+    /// # `get_special_use_disabled`
+    ///
+    /// Special-use names resolved as any other name, lowercase and without the trailing dot.
+    pub fn get_special_use_disabled(&self) -> Vec<String> {
+        self.special_use
+            .disabled
+            .iter()
+            .map(|name| normalize_name(name))
+            .collect()
+    }
+
+    pub fn get_ecs_enabled(&self) -> bool {
+        self.ecs.enabled
+    }
+
+    /// # `get_ecs_ipv4_prefix`
+    ///
+    /// Longest prefix of the IPv4 subnets sent on behalf of the clients.
+    pub fn get_ecs_ipv4_prefix(&self) -> u8 {
+        self.ecs.ipv4_prefix
+    }
+
+    /// # `get_ecs_ipv6_prefix`
+    ///
+    /// Longest prefix of the IPv6 subnets sent on behalf of the clients.
+    pub fn get_ecs_ipv6_prefix(&self) -> u8 {
+        self.ecs.ipv6_prefix
+    }
+
+    /// # `get_ecs_zones`
+    ///
+    /// Zones whose name servers are sent the subnets, along with the ones below them,
+    /// lowercase and without the trailing dot.
+    pub fn get_ecs_zones(&self) -> Vec<String> {
+        self.ecs
+            .zones
+            .iter()
+            .map(|zone| normalize_name(zone))
+            .collect()
+    }
+
+    /// # `get_any_policy`
+    ///
+    /// How the queries of type ANY are answered.
+    pub fn get_any_policy(&self) -> AnyPolicy {
+        self.any.policy
+    }
+
+    /// # `get_tld_policy`
+    ///
+    /// How the queries for the root and the top level domains are answered.
+    pub fn get_tld_policy(&self) -> TldPolicy {
+        self.tld.policy
+    }
+
+    /// # `get_rotation_enabled`
+    ///
+    /// Whether the order of the addresses answered changes at every response.
+    pub fn get_rotation_enabled(&self) -> bool {
+        self.rotation.enabled
     }
 
-    pub fn get_root_server_full_domain(&self) -> String {
-        self.root_server.get_full_domain()
+    /// # `get_middlewares`
+    ///
+    /// The middlewares the client queries go through, in order.
+    pub fn get_middlewares(&self) -> &[MiddlewareKind] {
+        &self.pipeline.middlewares
     }
 
-    pub fn get_local_server_addr(&self) -> Ipv4Addr {
-        self.local_server.get_addr()
+    /// # `get_script_path`
+    ///
+    /// The Lua script run by the `script` middleware, if any.
+    pub fn get_script_path(&self) -> Option<&Path> {
+        self.scripting.path.as_deref()
     }
 
-    pub fn get_root_server_addr(&self) -> Ipv4Addr {
-        self.root_server.get_addr()
+    /// # `get_log_options`
+    ///
+    /// Format of the events and sampling rules.
+    pub fn get_log_options(&self) -> LogOptions {
+        LogOptions {
+            format: self.logging.format,
+            sampling: self.logging.sampling.clone(),
+        }
     }
 
-    pub fn get_db_url(&self) -> String {
-        self.database.get_db_url()
+    /// # `get_otlp_endpoint`
+    ///
+    /// OpenTelemetry collector the spans are sent to, `None` if they aren't exported.
+    pub fn get_otlp_endpoint(&self) -> Option<String> {
+        self.telemetry.otlp_endpoint.clone()
     }
 
-    pub fn get_migrations_dir(&self) -> String {
-        self.database.get_migrations_dir()
+    /// # `get_keyring`
+    ///
+    /// Builds the collection of the configured TSIG keys,
+    /// fails if one of the secrets isn't valid base64.
+    pub fn get_keyring(&self) -> Result<Keyring, Box<dyn Error>> {
+        let mut keys = Vec::new();
+        for key in &self.tsig_keys {
+            keys.push(TsigKey::new(&key.name, key.algorithm, &key.secret)?);
+        }
+        Ok(Keyring::new(keys))
     }
 
-    // # `set_test_db`
-    //
-    // Genetare a random name for a test database the will be used instead of the name provided in
-    // the config.
-    pub fn set_test_db(&mut self) {
-        self.database.set_test_env();
+    /// # `tsig_key`
+    ///
+    /// The TSIG key named `name`, if it's configured and valid.
+    fn tsig_key(&self, name: &str) -> Option<TsigKey> {
+        let name = name.trim_end_matches('.').to_lowercase();
+        self.tsig_keys
+            .iter()
+            .filter_map(|key| TsigKey::new(&key.name, key.algorithm, &key.secret).ok())
+            .find(|key| key.name == name)
     }
 
-    /// # `get_db_path`
+    /// # `validate`
     ///
-    /// Obtains the path to the database file
-    pub fn get_db_path(&self) -> String {
-        self.database.get_path()
+    /// Checks what deserializing the settings can't, so that the server doesn't
+    /// fail later while running; every problem found is reported,
+    /// along with the path of the field it concerns.
+    pub fn validate(&self) -> Result<(), InvalidSettings> {
+        let mut problems: Vec<(String, String)> = Vec::new();
+        let mut report = |field: String, message: String| problems.push((field, message));
+
+        for (name, server) in [
+            ("local_server", &self.local_server),
+            ("root_server", &self.root_server),
+        ] {
+            if server.port == 0 {
+                report(
+                    format!("{}.port", name),
+                    "must be between 1 and 65535".into(),
+                );
+            }
+        }
+        if self.root_server.addr.is_unspecified() || self.root_server.addr.is_broadcast() {
+            report(
+                "root_server.addr".into(),
+                format!("{} can't be queried", self.root_server.addr),
+            );
+        }
+
+        let db_dir = Path::new(&self.database.path)
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty());
+        if let Some(dir) = db_dir.filter(|dir| !dir.is_dir()) {
+            report(
+                "database.path".into(),
+                format!("the directory {} doesn't exist", dir.display()),
+            );
+        }
+
+        if self.database.max_connections == Some(0) {
+            report(
+                "database.max_connections".into(),
+                "must be at least 1".into(),
+            );
+        }
+        if self.database.kind == StorageKind::Postgres {
+            match self.database.url.as_deref() {
+                None => report(
+                    "database.url".into(),
+                    "is needed by the postgres kind".into(),
+                ),
+                // The URL may carry a password, it isn't repeated
+                Some(url)
+                    if !url.starts_with("postgres://") && !url.starts_with("postgresql://") =>
+                {
+                    report("database.url".into(), "must be a postgres:// URL".into())
+                }
+                Some(_) => {}
+            }
+        }
+
+        let mut key_names = HashSet::new();
+        for (i, key) in self.tsig_keys.iter().enumerate() {
+            if let Err(e) = TsigKey::new(&key.name, key.algorithm, &key.secret) {
+                report(format!("tsig_keys[{}].secret", i), e.to_string());
+            }
+            if !key_names.insert(key.name.trim_end_matches('.').to_lowercase()) {
+                report(
+                    format!("tsig_keys[{}].name", i),
+                    format!("the key {} is defined more than once", key.name),
+                );
+            }
+        }
+        let mut check_key = |field: String, name: &Option<String>| {
+            if let Some(name) = name {
+                if !key_names.contains(&name.trim_end_matches('.').to_lowercase()) {
+                    report(field, format!("there is no TSIG key named {}", name));
+                }
+            }
+        };
+        check_key("notify.tsig_key".into(), &self.notify.tsig_key);
+        for (i, policy) in self.domain_policies.iter().enumerate() {
+            check_key(format!("domain_policies[{}].tsig_key", i), &policy.tsig_key);
+        }
+        for (i, zone) in self.secondary_zones.iter().enumerate() {
+            check_key(format!("secondary_zones[{}].tsig_key", i), &zone.tsig_key);
+        }
+        for (i, zone) in self.secondary_zones.iter().enumerate() {
+            if zone.get_name().is_empty() {
+                report(
+                    format!("secondary_zones[{}].name", i),
+                    "can't be empty".into(),
+                );
+            }
+            if zone.primaries.is_empty() {
+                report(
+                    format!("secondary_zones[{}].primaries", i),
+                    "at least one primary is required".into(),
+                );
+            }
+        }
+
+        for (i, list) in self.blocking.lists.iter().enumerate() {
+            if !Path::new(list).is_file() {
+                report(
+                    format!("blocking.lists[{}]", i),
+                    format!("{} doesn't exist", list),
+                );
+            }
+        }
+        for (i, url) in self.blocking.urls.iter().enumerate() {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                report(
+                    format!("blocking.urls[{}]", i),
+                    format!("{} isn't an HTTP or HTTPS URL", url),
+                );
+            }
+        }
+        for (i, wildcard) in self.blocking.wildcards.iter().enumerate() {
+            if BlockRules::new(std::slice::from_ref(wildcard), &[]).is_err() {
+                report(
+                    format!("blocking.wildcards[{}]", i),
+                    format!("{} doesn't have the form *.example.com", wildcard),
+                );
+            }
+        }
+        for (i, regex) in self.blocking.regexes.iter().enumerate() {
+            if let Err(e) = Regex::new(regex) {
+                report(format!("blocking.regexes[{}]", i), e.to_string());
+            }
+        }
+        let answers = std::iter::once((
+            "blocking".to_string(),
+            self.blocking.mode,
+            self.blocking.block_ipv4,
+            self.blocking.block_ipv6,
+        ))
+        .chain(self.blocking.groups.iter().enumerate().map(|(i, group)| {
+            (
+                format!("blocking.groups[{}]", i),
+                group.mode,
+                group.block_ipv4,
+                group.block_ipv6,
+            )
+        }));
+        for (field, mode, ipv4, ipv6) in answers {
+            if mode == BlockingMode::Custom && ipv4.is_none() && ipv6.is_none() {
+                report(
+                    format!("{}.mode", field),
+                    "custom requires block_ipv4 or block_ipv6".into(),
+                );
+            }
+        }
+
+        if let Some(endpoint) = &self.telemetry.otlp_endpoint {
+            if !endpoint.starts_with("http://") && !endpoint.starts_with("https://") {
+                report(
+                    "telemetry.otlp_endpoint".into(),
+                    format!("{} isn't an HTTP or HTTPS URL", endpoint),
+                );
+            }
+        }
+
+        if self.query_log.enabled && self.query_log.retention_days == 0 {
+            report(
+                "query_log.retention_days".into(),
+                "the entries need to be kept for at least a day".into(),
+            );
+        }
+
+        if self.stats.enabled && self.stats.retention_days == 0 {
+            report(
+                "stats.retention_days".into(),
+                "the statistics need to be kept for at least a day".into(),
+            );
+        }
+
+        for (i, rule) in self.logging.sampling.iter().enumerate() {
+            if !(rule.rate > 0.0 && rule.rate <= 1.0) {
+                report(
+                    format!("logging.sampling[{}].rate", i),
+                    "must be greater than 0 and at most 1".into(),
+                );
+            }
+        }
+
+        if self
+            .health
+            .self_test
+            .as_ref()
+            .is_some_and(|name| name.trim_end_matches('.').is_empty())
+        {
+            report("health.self_test".into(), "can't be empty".into());
+        }
+
+        let mut named_clients = HashSet::new();
+        for (i, client) in self.clients.names.iter().enumerate() {
+            if client.name.trim().is_empty() {
+                report(
+                    format!("clients.names[{}].name", i),
+                    "can't be empty".into(),
+                );
+            }
+            if !named_clients.insert(client.address) {
+                report(
+                    format!("clients.names[{}].address", i),
+                    format!("{} has already been named", client.address),
+                );
+            }
+        }
+
+        if !self.dhcp.leases.is_empty() {
+            let domain = self.get_dhcp_domain();
+            if domain.is_empty() {
+                report("dhcp.domain".into(), "can't be empty".into());
+            } else if self
+                .secondary_zones
+                .iter()
+                .any(|zone| zone.get_name() == domain)
+            {
+                report(
+                    "dhcp.domain".into(),
+                    format!("{} is already a secondary zone", domain),
+                );
+            }
+        }
+
+        for (i, record) in self.mdns.records.iter().enumerate() {
+            if let Err(e) = idn::to_ascii(&record.name) {
+                report(format!("mdns.records[{}].name", i), e.to_string());
+            }
+            if !is_local(&record.name) || record.name.trim_end_matches('.') == "local" {
+                report(
+                    format!("mdns.records[{}].name", i),
+                    format!("{} doesn't belong to .local", record.name),
+                );
+            }
+        }
+        if self.mdns.bridge && self.mdns.bridge_timeout == 0 {
+            report(
+                "mdns.bridge_timeout".into(),
+                "no response could ever arrive in time with 0".into(),
+            );
+        }
+
+        for (i, endpoint) in self.ddr.endpoints.iter().enumerate() {
+            if endpoint.target.trim_end_matches('.').is_empty() {
+                report(
+                    format!("ddr.endpoints[{}].target", i),
+                    "the name of the endpoint is needed".into(),
+                );
+            } else if let Err(e) = idn::to_ascii(&endpoint.target) {
+                report(format!("ddr.endpoints[{}].target", i), e.to_string());
+            }
+            if endpoint.port == Some(0) {
+                report(
+                    format!("ddr.endpoints[{}].port", i),
+                    "0 isn't a port the clients can connect to".into(),
+                );
+            }
+            match (endpoint.protocol, endpoint.dohpath.as_deref()) {
+                (EncryptedProtocol::Doh, Some(path))
+                    if !path.starts_with('/') || !path.contains("{?dns}") =>
+                {
+                    report(
+                        format!("ddr.endpoints[{}].dohpath", i),
+                        format!("{} must be a path with the {{?dns}} variable", path),
+                    )
+                }
+                (EncryptedProtocol::Dot, Some(_)) => report(
+                    format!("ddr.endpoints[{}].dohpath", i),
+                    "only the doh endpoints have a path".into(),
+                ),
+                _ => {}
+            }
+        }
+
+        let defaults = default_names();
+        for (i, name) in self.get_special_use_disabled().iter().enumerate() {
+            if !defaults.contains(name) {
+                report(
+                    format!("special_use.disabled[{}]", i),
+                    format!("{} isn't one of the special-use names", name),
+                );
+            }
+        }
+        for (i, name) in self.special_use.extra.iter().enumerate() {
+            if let Err(e) = idn::to_ascii(name) {
+                report(format!("special_use.extra[{}]", i), e.to_string());
+            }
+        }
+        for (i, name) in self.get_special_use_extra().iter().enumerate() {
+            if name.is_empty() {
+                report(
+                    format!("special_use.extra[{}]", i),
+                    "the root can't be answered locally".into(),
+                );
+            }
+        }
+
+        let mut suffixes = HashSet::new();
+        for (i, policy) in self.domain_policies.iter().enumerate() {
+            let actions = [
+                policy.block,
+                !policy.local.is_empty(),
+                !policy.records.is_empty(),
+                !policy.upstream.is_empty() || policy.stamp.is_some(),
+            ];
+            if actions.iter().filter(|set| **set).count() > 1 {
+                report(
+                    format!("domain_policies[{}]", i),
+                    "only one of block, local, records and upstream can be set".into(),
+                );
+            }
+            let mut cname = false;
+            for (j, line) in policy.records.iter().enumerate() {
+                match pinned_record(line) {
+                    Ok(record) => cname |= record.get_qtype() == QueryType::CNAME,
+                    Err(e) => report(format!("domain_policies[{}].records[{}]", i, j), e),
+                }
+            }
+            if cname && policy.records.len() > 1 {
+                report(
+                    format!("domain_policies[{}].records", i),
+                    "a CNAME can't be pinned along with other records".into(),
+                );
+            }
+            if policy.stamp.is_some()
+                && (!policy.upstream.is_empty()
+                    || policy.transport != TransportKind::Udp
+                    || policy.tls_name.is_some())
+            {
+                report(
+                    format!("domain_policies[{}].stamp", i),
+                    "it sets upstream, transport and tls_name itself".into(),
+                );
+            }
+            let forwarding = policy.forwarding();
+            if let Err(e) = &forwarding {
+                report(format!("domain_policies[{}].stamp", i), e.clone());
+            }
+            if policy.transport == TransportKind::Dnscrypt {
+                report(
+                    format!("domain_policies[{}].transport", i),
+                    "the DNSCrypt servers are given by their stamp".into(),
+                );
+            } else if policy.transport != TransportKind::Udp && policy.upstream.is_empty() {
+                report(
+                    format!("domain_policies[{}].transport", i),
+                    "only the policies with an upstream can set a transport".into(),
+                );
+            }
+            if policy.tsig_key.is_some() {
+                let transport = forwarding
+                    .as_ref()
+                    .map_or(policy.transport, |(_, kind, _)| *kind);
+                if !matches!(transport, TransportKind::Udp | TransportKind::Tcp) {
+                    report(
+                        format!("domain_policies[{}].tsig_key", i),
+                        "only the udp and tcp transports can sign the queries".into(),
+                    );
+                } else if forwarding
+                    .as_ref()
+                    .is_ok_and(|(upstream, _, _)| upstream.is_empty())
+                {
+                    report(
+                        format!("domain_policies[{}].tsig_key", i),
+                        "only the queries sent to an upstream can be signed".into(),
+                    );
+                }
+            }
+            match (policy.transport, policy.tls_name.as_deref()) {
+                (TransportKind::Tls, None) => report(
+                    format!("domain_policies[{}].tls_name", i),
+                    "the name of the certificate of the upstream is needed by tls".into(),
+                ),
+                (TransportKind::Tls, Some(name)) if TlsTransport::new(name).is_err() => report(
+                    format!("domain_policies[{}].tls_name", i),
+                    format!("{} isn't a valid name", name),
+                ),
+                (TransportKind::Tls, Some(_)) | (_, None) => {}
+                (_, Some(_)) => report(
+                    format!("domain_policies[{}].tls_name", i),
+                    "only the tls transport uses it".into(),
+                ),
+            }
+            if let Some(proxy) = policy.proxy.as_deref() {
+                let transport = forwarding.map_or(policy.transport, |(_, kind, _)| kind);
+                if !matches!(transport, TransportKind::Tcp | TransportKind::Tls) {
+                    report(
+                        format!("domain_policies[{}].proxy", i),
+                        "only the tcp and tls transports can go through a proxy".into(),
+                    );
+                }
+                // The proxy itself stays out of the message, it may carry a password
+                if let Err(e) = proxy.parse::<Socks5Proxy>() {
+                    report(format!("domain_policies[{}].proxy", i), e);
+                }
+            }
+            if let Err(e) = idn::to_ascii(&policy.suffix) {
+                report(format!("domain_policies[{}].suffix", i), e.to_string());
+            }
+            if !suffixes.insert(normalize_name(&policy.suffix)) {
+                report(
+                    format!("domain_policies[{}].suffix", i),
+                    format!("{} has more than one policy", policy.suffix),
+                );
+            }
+        }
+
+        if self.cache.min_ttl > self.cache.max_ttl {
+            report(
+                "cache.min_ttl".into(),
+                format!(
+                    "{} exceeds cache.max_ttl, {}",
+                    self.cache.min_ttl, self.cache.max_ttl
+                ),
+            );
+        }
+        if let Some(path) = self.cache.bootstrap.as_ref().filter(|path| !path.is_file()) {
+            report(
+                "cache.bootstrap".into(),
+                format!("{} isn't a file", path.display()),
+            );
+        }
+
+        if self.ecs.ipv4_prefix > 32 {
+            report(
+                "ecs.ipv4_prefix".into(),
+                format!(
+                    "{} exceeds the 32 bits of an IPv4 address",
+                    self.ecs.ipv4_prefix
+                ),
+            );
+        }
+        if self.ecs.ipv6_prefix > 128 {
+            report(
+                "ecs.ipv6_prefix".into(),
+                format!(
+                    "{} exceeds the 128 bits of an IPv6 address",
+                    self.ecs.ipv6_prefix
+                ),
+            );
+        }
+
+        if self.limits.max_in_flight_queries == 0 {
+            report(
+                "limits.max_in_flight_queries".into(),
+                "no query could ever be handled with 0".into(),
+            );
+        }
+        if self.tcp.enabled {
+            if self.tcp.max_connections == 0 {
+                report(
+                    "tcp.max_connections".into(),
+                    "no connection could ever be accepted with 0".into(),
+                );
+            }
+            if self.tcp.max_connections_per_ip == 0 {
+                report(
+                    "tcp.max_connections_per_ip".into(),
+                    "no connection could ever be accepted with 0".into(),
+                );
+            }
+            if self.tcp.read_timeout == 0 {
+                report(
+                    "tcp.read_timeout".into(),
+                    "no message could ever be read with 0".into(),
+                );
+            }
+        }
+        if self.tarpit.enabled {
+            if self.tarpit.rate == 0 {
+                report(
+                    "tarpit.rate".into(),
+                    "every query would be an offense with 0".into(),
+                );
+            }
+            if self.tarpit.burst == 0 {
+                report("tarpit.burst".into(), "must allow at least a query".into());
+            }
+            if self.tarpit.drop_after == 0 {
+                report(
+                    "tarpit.drop_after".into(),
+                    "every query would be dropped with 0".into(),
+                );
+            }
+            if self.tarpit.forget_after == 0 {
+                report(
+                    "tarpit.forget_after".into(),
+                    "the strikes would never count with 0".into(),
+                );
+            }
+        }
+        if self.upstream.attempt_timeout == 0 {
+            report(
+                "upstream.attempt_timeout".into(),
+                "no response could ever arrive in time with 0".into(),
+            );
+        }
+        if self.upstream.query_deadline < self.upstream.attempt_timeout {
+            report(
+                "upstream.query_deadline".into(),
+                "must be at least as long as upstream.attempt_timeout".into(),
+            );
+        }
+        if self.pipeline.middlewares.contains(&MiddlewareKind::Script) {
+            if !cfg!(feature = "scripting") {
+                report(
+                    "pipeline.middlewares".into(),
+                    "script needs the server to be built with the scripting feature".into(),
+                );
+            }
+            match &self.scripting.path {
+                Some(path) if !path.is_file() => report(
+                    "scripting.path".into(),
+                    format!("{} isn't a file", path.display()),
+                ),
+                Some(_) => {}
+                None => report(
+                    "scripting.path".into(),
+                    "must be set for the script middleware".into(),
+                ),
+            }
+        }
+        if self.api.address.is_some() && self.api.token.as_deref().unwrap_or("").is_empty() {
+            report("api.token".into(), "must be set when api.address is".into());
+        }
+        if self.doh.address.is_some() {
+            if !self.doh.path.starts_with('/') {
+                report("doh.path".into(), "must start with /".into());
+            }
+            if self.doh.max_streams == 0 {
+                report(
+                    "doh.max_streams".into(),
+                    "no request could ever be served with 0".into(),
+                );
+            }
+        }
+        if self.acme.enabled {
+            if self.doh.address.is_none() {
+                report(
+                    "acme.enabled".into(),
+                    "the certificate is obtained for doh.address, which isn't set".into(),
+                );
+            }
+            if !self.acme.directory.starts_with("https://") {
+                report("acme.directory".into(), "must be an https URL".into());
+            }
+            if self.acme.domains.is_empty() {
+                report(
+                    "acme.domains".into(),
+                    "at least a name is needed for the certificate".into(),
+                );
+            }
+            for (i, domain) in self.acme.domains.iter().enumerate() {
+                if domain.starts_with("*.") && self.acme.challenge != AcmeChallenge::Dns01 {
+                    report(
+                        format!("acme.domains[{}]", i),
+                        "wildcard names can only be validated with dns-01".into(),
+                    );
+                }
+            }
+            if self.acme.challenge == AcmeChallenge::Http01 {
+                match self.acme.http01_address {
+                    None => report(
+                        "acme.http01_address".into(),
+                        "http-01 needs an address the CA can reach".into(),
+                    ),
+                    Some(addr)
+                        if [self.dashboard.address, self.api.address].contains(&Some(addr)) =>
+                    {
+                        report(
+                            "acme.http01_address".into(),
+                            "the dashboard and the API mustn't be reachable by the CA".into(),
+                        )
+                    }
+                    Some(_) => {}
+                }
+            }
+            if self.acme.renew_before == 0 {
+                report(
+                    "acme.renew_before".into(),
+                    "the certificate would expire before being renewed".into(),
+                );
+            }
+        }
+        for (i, kind) in self.pipeline.middlewares.iter().enumerate() {
+            if self.pipeline.middlewares[..i].contains(kind) {
+                report(
+                    format!("pipeline.middlewares[{}]", i),
+                    format!("{:?} is already in the pipeline", kind).to_lowercase(),
+                );
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(InvalidSettings { problems })
+        }
+    }
+}
+
+/// # `InvalidSettings`
+///
+/// Problems found by `Settings::validate`, as pairs of field path and description.
+#[derive(Debug)]
+pub struct InvalidSettings {
+    pub problems: Vec<(String, String)>,
+}
+
+impl fmt::Display for InvalidSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The configuration is invalid:")?;
+        for (field, message) in &self.problems {
+            write!(f, "\n  {}: {}", field, message)?;
+        }
+        Ok(())
     }
 }
 
+impl Error for InvalidSettings {}
+
 #[derive(Debug, Deserialize)]
 struct ServerSettings {
     addr: Ipv4Addr,
@@ -70,6 +1804,17 @@ impl ServerSettings {
 struct DatabaseSettings {
     path: String,
     migrations_dir: String,
+    #[serde(default)]
+    disable_cache: bool,
+    #[serde(default)]
+    journal_mode: JournalMode,
+    #[serde(default)]
+    synchronous: Synchronous,
+    busy_timeout: Option<u64>,
+    max_connections: Option<u32>,
+    #[serde(default)]
+    kind: StorageKind,
+    url: Option<String>,
 }
 
 impl DatabaseSettings {
@@ -99,10 +1844,694 @@ impl DatabaseSettings {
     }
 }
 
+#[derive(Debug, Deserialize, Default)]
+struct NotifySettings {
+    #[serde(default)]
+    secondaries: Vec<SocketAddr>,
+    #[serde(default)]
+    primaries: Vec<IpAddr>,
+    tsig_key: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SecondaryZoneSettings {
+    name: String,
+    primaries: Vec<SocketAddr>,
+    tsig_key: Option<String>,
+    #[serde(default)]
+    sign: bool,
+}
+
+impl SecondaryZoneSettings {
+    /// # `get_name`
+    ///
+    /// Name of the zone, lowercase and without the trailing dot.
+    pub fn get_name(&self) -> String {
+        self.name.trim_end_matches('.').to_lowercase()
+    }
+
+    /// # `get_primaries`
+    ///
+    /// Servers the zone is transferred from, tried in order.
+    pub fn get_primaries(&self) -> Vec<SocketAddr> {
+        self.primaries.clone()
+    }
+
+    /// # `get_tsig_key`
+    ///
+    /// Name of the TSIG key used to authenticate the transfers, if any.
+    pub fn get_tsig_key(&self) -> Option<String> {
+        self.tsig_key.clone()
+    }
+
+    /// # `get_sign`
+    ///
+    /// Whether the zone is signed with our own keys before being served.
+    pub fn get_sign(&self) -> bool {
+        self.sign
+    }
+}
+
+/// Durations are expressed in milliseconds.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct UpstreamSettings {
+    attempt_timeout: u64,
+    attempts: u32,
+    initial_backoff: u64,
+    query_deadline: u64,
+    failure_threshold: u32,
+    cooldown: u64,
+    servfail_ttl: u64,
+    sockets: usize,
+    hedge_delay: u64,
+    ipv6: bool,
+    ipv6_head_start: u64,
+}
+
+impl Default for UpstreamSettings {
+    fn default() -> Self {
+        UpstreamSettings {
+            attempt_timeout: 2000,
+            attempts: 3,
+            initial_backoff: 100,
+            query_deadline: 4000,
+            failure_threshold: 5,
+            cooldown: 30000,
+            servfail_ttl: 5000,
+            sockets: 4,
+            hedge_delay: 0,
+            ipv6: true,
+            ipv6_head_start: 50,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct UdpSettings {
+    batch_size: usize,
+    strict_parsing: bool,
+}
+
+impl Default for UdpSettings {
+    fn default() -> Self {
+        UdpSettings {
+            batch_size: 32,
+            strict_parsing: false,
+        }
+    }
+}
+
+/// Durations are expressed in milliseconds.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct TcpSettings {
+    enabled: bool,
+    idle_timeout: u64,
+    read_timeout: u64,
+    max_connections: usize,
+    max_connections_per_ip: usize,
+}
+
+impl Default for TcpSettings {
+    fn default() -> Self {
+        TcpSettings {
+            enabled: true,
+            idle_timeout: 10000,
+            read_timeout: 2000,
+            max_connections: 512,
+            max_connections_per_ip: 16,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct TelemetrySettings {
+    #[serde(default)]
+    metrics_address: Option<SocketAddr>,
+    #[serde(default)]
+    otlp_endpoint: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct QueryLogSettings {
+    enabled: bool,
+    target: QueryLogTarget,
+    directory: String,
+    retention_days: u32,
+}
+
+impl Default for QueryLogSettings {
+    fn default() -> Self {
+        QueryLogSettings {
+            enabled: false,
+            target: QueryLogTarget::default(),
+            directory: "query_logs".to_string(),
+            retention_days: 7,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct StatsSettings {
+    enabled: bool,
+    retention_days: u32,
+}
+
+impl Default for StatsSettings {
+    fn default() -> Self {
+        StatsSettings {
+            enabled: true,
+            retention_days: 30,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct LoggingSettings {
+    #[serde(default)]
+    format: LogFormat,
+    #[serde(default)]
+    sampling: Vec<SamplingRule>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct MdnsSettings {
+    enabled: bool,
+    interface: Ipv4Addr,
+    bridge: bool,
+    bridge_timeout: u64,
+    records: Vec<MdnsRecordSettings>,
+}
+
+impl Default for MdnsSettings {
+    fn default() -> Self {
+        MdnsSettings {
+            enabled: false,
+            interface: Ipv4Addr::UNSPECIFIED,
+            bridge: false,
+            bridge_timeout: 1000,
+            records: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct SpecialUseSettings {
+    enabled: bool,
+    extra: Vec<String>,
+    disabled: Vec<String>,
+}
+
+impl Default for SpecialUseSettings {
+    fn default() -> Self {
+        SpecialUseSettings {
+            enabled: true,
+            extra: Vec::new(),
+            disabled: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct EcsSettings {
+    enabled: bool,
+    ipv4_prefix: u8,
+    ipv6_prefix: u8,
+    zones: Vec<String>,
+}
+
+impl Default for EcsSettings {
+    fn default() -> Self {
+        // The prefixes recommended by RFC 7871 section 11.1
+        EcsSettings {
+            enabled: false,
+            ipv4_prefix: 24,
+            ipv6_prefix: 56,
+            zones: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct CacheSettings {
+    min_ttl: u32,
+    max_ttl: u32,
+    bootstrap: Option<PathBuf>,
+    bootstrap_ttl: u32,
+}
+
+impl Default for CacheSettings {
+    fn default() -> Self {
+        CacheSettings {
+            min_ttl: 0,
+            max_ttl: 86400,
+            bootstrap: None,
+            bootstrap_ttl: 604800,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct RotationSettings {
+    enabled: bool,
+}
+
+impl Default for RotationSettings {
+    fn default() -> Self {
+        RotationSettings { enabled: true }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct PipelineSettings {
+    middlewares: Vec<MiddlewareKind>,
+}
+
+impl Default for PipelineSettings {
+    fn default() -> Self {
+        PipelineSettings {
+            middlewares: vec![
+                MiddlewareKind::Querylog,
+                MiddlewareKind::Rotate,
+                MiddlewareKind::Blocklist,
+                MiddlewareKind::Safesearch,
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct ScriptingSettings {
+    #[serde(default)]
+    path: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct AnySettings {
+    #[serde(default)]
+    policy: AnyPolicy,
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct TldSettings {
+    #[serde(default)]
+    policy: TldPolicy,
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct DdrSettings {
+    #[serde(default)]
+    endpoints: Vec<DdrEndpointSettings>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DdrEndpointSettings {
+    target: String,
+    protocol: EncryptedProtocol,
+    port: Option<u16>,
+    dohpath: Option<String>,
+    #[serde(default)]
+    ipv4hint: Vec<Ipv4Addr>,
+    #[serde(default)]
+    ipv6hint: Vec<Ipv6Addr>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MdnsRecordSettings {
+    name: String,
+    address: IpAddr,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct DhcpSettings {
+    leases: Vec<LeaseFileSettings>,
+    domain: String,
+    ttl: u32,
+}
+
+impl Default for DhcpSettings {
+    fn default() -> Self {
+        DhcpSettings {
+            leases: Vec::new(),
+            domain: "lan".to_string(),
+            ttl: 60,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct ClientsSettings {
+    names: Vec<ClientNameSettings>,
+    learn_ptr: bool,
+}
+
+impl Default for ClientsSettings {
+    fn default() -> Self {
+        ClientsSettings {
+            names: Vec::new(),
+            learn_ptr: true,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ClientNameSettings {
+    address: IpAddr,
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct DomainPolicySettings {
+    suffix: String,
+    #[serde(default)]
+    upstream: Vec<IpAddr>,
+    ttl: Option<u32>,
+    #[serde(default)]
+    block: bool,
+    #[serde(default)]
+    local: Vec<IpAddr>,
+    #[serde(default)]
+    records: Vec<String>,
+    #[serde(default)]
+    no_cache: bool,
+    #[serde(default)]
+    exact: bool,
+    #[serde(default)]
+    transport: TransportKind,
+    tls_name: Option<String>,
+    proxy: Option<String>,
+    stamp: Option<String>,
+    tsig_key: Option<String>,
+}
+
+impl DomainPolicySettings {
+    /// # `forwarding`
+    ///
+    /// The forwarders, their transport and the name of their certificate,
+    /// from the stamp when there's one.
+    fn forwarding(&self) -> Result<(Vec<IpAddr>, TransportKind, Option<String>), String> {
+        match self.stamp.as_deref() {
+            Some(stamp) => {
+                let (addr, kind, tls_name) = stamp.parse::<DnsStamp>()?.forwarder()?;
+                Ok((vec![addr], kind, tls_name))
+            }
+            None => Ok((self.upstream.clone(), self.transport, self.tls_name.clone())),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct LeaseFileSettings {
+    path: String,
+    format: LeaseFormat,
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct HealthSettings {
+    #[serde(default)]
+    self_test: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct DashboardSettings {
+    #[serde(default)]
+    address: Option<SocketAddr>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct ApiSettings {
+    #[serde(default)]
+    address: Option<SocketAddr>,
+    #[serde(default)]
+    token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct DohSettings {
+    address: Option<SocketAddr>,
+    certificate: String,
+    private_key: String,
+    path: String,
+    max_streams: u32,
+}
+
+impl Default for DohSettings {
+    fn default() -> Self {
+        DohSettings {
+            address: None,
+            certificate: "instance/tls/cert.pem".to_string(),
+            private_key: "instance/tls/key.pem".to_string(),
+            path: "/dns-query".to_string(),
+            max_streams: 100,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct AcmeSettings {
+    enabled: bool,
+    directory: String,
+    contact: Option<String>,
+    domains: Vec<String>,
+    challenge: AcmeChallenge,
+    account_key: String,
+    /// Days before the expiration the certificate is renewed
+    renew_before: u64,
+    /// Where the HTTP-01 challenges are answered
+    http01_address: Option<SocketAddr>,
+}
+
+impl Default for AcmeSettings {
+    fn default() -> Self {
+        AcmeSettings {
+            enabled: false,
+            directory: "https://acme-v02.api.letsencrypt.org/directory".to_string(),
+            contact: None,
+            domains: Vec::new(),
+            challenge: AcmeChallenge::default(),
+            account_key: "instance/acme/account.pk8".to_string(),
+            renew_before: 30,
+            http01_address: Some(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 80))),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct ControlSettings {
+    enabled: bool,
+    socket: String,
+}
+
+impl Default for ControlSettings {
+    fn default() -> Self {
+        ControlSettings {
+            enabled: false,
+            socket: "instance/control.sock".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct LimitsSettings {
+    max_in_flight_queries: usize,
+    overflow_policy: OverflowPolicy,
+    shutdown_timeout: u64,
+}
+
+impl Default for LimitsSettings {
+    fn default() -> Self {
+        LimitsSettings {
+            max_in_flight_queries: 1024,
+            overflow_policy: OverflowPolicy::default(),
+            shutdown_timeout: 5000,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct TarpitSettings {
+    enabled: bool,
+    rate: u32,
+    burst: u32,
+    delay: u64,
+    drop_after: u32,
+    forget_after: u64,
+}
+
+impl Default for TarpitSettings {
+    fn default() -> Self {
+        TarpitSettings {
+            enabled: false,
+            rate: 100,
+            burst: 200,
+            delay: 100,
+            drop_after: 8,
+            forget_after: 60,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct AclSettings {
+    #[serde(default)]
+    allow_query: Vec<IpNet>,
+    #[serde(default)]
+    deny_query: Vec<IpNet>,
+    #[serde(default)]
+    allow_recursion: Vec<IpNet>,
+    #[serde(default)]
+    deny_recursion: Vec<IpNet>,
+    #[serde(default)]
+    denied_action: DeniedAction,
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct SafeSearchSettings {
+    #[serde(default)]
+    enabled: bool,
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct BlockingSettings {
+    #[serde(default)]
+    lists: Vec<String>,
+    #[serde(default)]
+    urls: Vec<String>,
+    /// Seconds between two updates of the lists, 0 disables them
+    #[serde(default)]
+    update_interval: u64,
+    #[serde(default)]
+    wildcards: Vec<String>,
+    #[serde(default)]
+    regexes: Vec<String>,
+    #[serde(default)]
+    allowlist: Vec<String>,
+    #[serde(default)]
+    mode: BlockingMode,
+    block_ipv4: Option<Ipv4Addr>,
+    block_ipv6: Option<Ipv6Addr>,
+    blocked_ttl: Option<u32>,
+    #[serde(default)]
+    groups: Vec<PolicyGroupSettings>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PolicyGroupSettings {
+    name: String,
+    clients: Vec<IpNet>,
+    #[serde(default)]
+    mode: BlockingMode,
+    block_ipv4: Option<Ipv4Addr>,
+    block_ipv6: Option<Ipv6Addr>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DnssecSettings {
+    keys_dir: String,
+    /// Validity of the signatures, in days
+    signature_validity: u64,
+}
+
+impl Default for DnssecSettings {
+    fn default() -> Self {
+        DnssecSettings {
+            keys_dir: "instance/keys".to_string(),
+            signature_validity: 14,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TsigKeySettings {
+    name: String,
+    algorithm: TsigAlgorithm,
+    /// Base64 encoded shared secret
+    secret: String,
+}
+
+/// # `Environment`
+///
+/// Environment the server runs in, selected by `APP_ENVIRONMENT`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Environment {
+    Local,
+    Production,
+}
+
+impl Environment {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Environment::Local => "local",
+            Environment::Production => "production",
+        }
+    }
+}
+
+impl TryFrom<String> for Environment {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        match s.to_lowercase().as_str() {
+            "local" => Ok(Environment::Local),
+            "production" => Ok(Environment::Production),
+            other => Err(format!(
+                "{} is not a supported environment, use either `local` or `production`",
+                other
+            )),
+        }
+    }
+}
+
+/// # `normalize_name`
+///
+/// A name of the configuration as it's sent on the wire, A-labels for the Unicode ones,
+/// lowercase and without the trailing dot; the names that aren't valid are reported
+/// by `Settings::validate` and only lowercased.
+fn normalize_name(name: &str) -> String {
+    idn::to_ascii(name).unwrap_or_else(|_| name.trim_end_matches('.').to_lowercase())
+}
+
+/// # `get_settings`
+///
+/// Reads `configuration/base.toml` from the current directory and applies on top of it
+/// the file of the environment selected by `APP_ENVIRONMENT`, `local` by default.
 pub fn get_settings() -> Result<Settings, Box<dyn Error>> {
-    let path = env::current_dir()?.join("Configuration.toml");
+    let configuration_dir = env::current_dir()?.join("configuration");
+    let environment: Environment = env::var("APP_ENVIRONMENT")
+        .unwrap_or_else(|_| "local".into())
+        .try_into()?;
+    let settings = Config::builder()
+        .add_source(config::File::from(configuration_dir.join("base.toml")))
+        .add_source(config::File::from(
+            configuration_dir.join(format!("{}.toml", environment.as_str())),
+        ))
+        .build()?;
+    Ok(settings.try_deserialize::<Settings>()?)
+}
+
+/// # `get_settings_from`
+///
+/// Reads the configuration from the file at `path`.
+pub fn get_settings_from(path: &Path) -> Result<Settings, Box<dyn Error>> {
     let settings = Config::builder()
-        .add_source(config::File::from(path))
+        .add_source(config::File::from(path.to_path_buf()))
         .build()?;
     Ok(settings.try_deserialize::<Settings>()?)
 }
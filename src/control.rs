@@ -0,0 +1,341 @@
+use std::{fmt::Write, path::Path, sync::Arc};
+
+use chrono::{Duration, Local};
+use tokio::sync::mpsc;
+
+use crate::{
+    cachecheck,
+    cachedump::{self, DumpFormat},
+    clock::SystemClock,
+    configuration::Settings,
+    idn,
+    stats::{self, StatKind},
+    storage::Storage,
+    structs::{auxiliaries::CResult, db_queries::CachedRecord, questions_and_records::QueryType},
+    workers::InfraCache,
+};
+
+/// Longest command accepted, in bytes.
+#[cfg(unix)]
+const MAX_COMMAND: u64 = 1024;
+/// Time a client has to send its command.
+#[cfg(unix)]
+const READ_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+/// Entries listed for each ranking of `stats`.
+const TOP: u32 = 10;
+
+/// Reads the configuration again, for `reload`.
+pub type SettingsReader = Box<dyn Fn() -> Result<Settings, String> + Send + Sync>;
+
+/// # `Reloader`
+///
+/// Reads the configuration again and hands it to the server, as SIGHUP does.
+pub struct Reloader {
+    read_settings: SettingsReader,
+    reload: mpsc::Sender<Settings>,
+}
+
+impl Reloader {
+    pub fn new(read_settings: SettingsReader, reload: mpsc::Sender<Settings>) -> Self {
+        Reloader {
+            read_settings,
+            reload,
+        }
+    }
+
+    /// # `reload`
+    ///
+    /// Fails if the configuration can't be read or the server isn't accepting reloads.
+    pub async fn reload(&self) -> Result<(), String> {
+        let settings = (self.read_settings)()?;
+        self.reload
+            .send(settings)
+            .await
+            .map_err(|_| "The server isn't accepting reloads".to_string())
+    }
+}
+
+/// # `ControlHandler`
+///
+/// Executes the commands received on the control socket, analogous to
+/// `rndc` or `unbound-control`:
+/// - `cache flush [<domain>]` deletes the records of `domain` from the cache, or every record;
+/// - `cache check [delete]` lists the rows of the cache that can't be served,
+///   `cachecheck::CacheRow::problem`, and deletes them if asked to;
+/// - `cache dump` lists the records of the cache;
+/// - `cache export [json|csv]` writes the records of the cache database and the addresses
+///   of the name servers kept in memory in a dump, `cachedump::DumpEntry`;
+/// - `cache import <path>` caches the records of the dump at `path`, CSV if it ends with `.csv`;
+/// - `infra` lists the name servers queried and what has been learnt of them, `ServerInfo`;
+/// - `reload` reads the configuration again, as SIGHUP does;
+/// - `stats` reports the queries of the last 24 hours and the top domains and clients.
+pub struct ControlHandler {
+    storage: Arc<dyn Storage>,
+    infra: Arc<InfraCache>,
+    reloader: Reloader,
+}
+
+impl ControlHandler {
+    pub fn new(storage: Arc<dyn Storage>, infra: Arc<InfraCache>, reloader: Reloader) -> Self {
+        ControlHandler {
+            storage,
+            infra,
+            reloader,
+        }
+    }
+
+    /// # `execute`
+    ///
+    /// Executes `command`, returns its output or the reason it failed.
+    #[tracing::instrument("Executing a control command", skip(self))]
+    pub async fn execute(&self, command: &str) -> Result<String, String> {
+        let words: Vec<&str> = command.split_whitespace().collect();
+        match words.as_slice() {
+            ["cache", "flush"] => self.flush(None).await,
+            ["cache", "flush", domain] => self.flush(Some(domain)).await,
+            ["cache", "check"] => self.check(false).await,
+            ["cache", "check", "delete"] => self.check(true).await,
+            ["cache", "dump"] => self.dump().await,
+            ["cache", "export"] => self.export(DumpFormat::Json).await,
+            ["cache", "export", format] => self.export(format.parse()?).await,
+            ["cache", "import", path] => self.import(Path::new(path)).await,
+            ["infra"] => Ok(self.infra()),
+            ["reload"] => {
+                self.reloader.reload().await?;
+                Ok("Reloading the configuration\n".to_string())
+            }
+            ["stats"] => self.stats().await.map_err(|e| e.to_string()),
+            [] => Err("Empty command".to_string()),
+            _ => Err(format!("Unknown command: {}", command.trim())),
+        }
+    }
+
+    async fn flush(&self, domain: Option<&str>) -> Result<String, String> {
+        let domain = domain
+            .map(idn::to_ascii)
+            .transpose()
+            .map_err(|e| e.to_string())?;
+        let deleted = CachedRecord::flush(self.storage.as_ref(), domain.as_deref())
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(format!("Deleted {} records\n", deleted))
+    }
+
+    async fn check(&self, delete: bool) -> Result<String, String> {
+        let malformed = cachecheck::check(self.storage.as_ref(), delete)
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut output = String::new();
+        for entry in &malformed {
+            let _ = writeln!(
+                output,
+                "{}\t{}\t{}\t{}",
+                entry.row.id,
+                entry.row.domain.as_deref().unwrap_or("-"),
+                entry
+                    .row
+                    .record_type
+                    .and_then(|qtype| u16::try_from(qtype).ok())
+                    .map_or("-".to_string(), |qtype| format!(
+                        "{:?}",
+                        QueryType::from_num(qtype)
+                    )),
+                entry.problem
+            );
+        }
+        let _ = writeln!(
+            output,
+            "{} malformed records{}",
+            malformed.len(),
+            if delete && !malformed.is_empty() {
+                ", deleted"
+            } else {
+                ""
+            }
+        );
+        Ok(output)
+    }
+
+    async fn dump(&self) -> Result<String, String> {
+        let records = CachedRecord::fetch_all(self.storage.as_ref())
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut output = String::new();
+        for record in records {
+            let data = match record.record_from_cache() {
+                Ok(cached) => cached.rdata().to_string(),
+                Err(e) => e.to_string(),
+            };
+            let _ = writeln!(
+                output,
+                "{}\t{}\t{:?}\t{}\t{}\t{}",
+                record.domain,
+                record.ttl,
+                QueryType::from_num(record.record_type),
+                data,
+                record.expiration_date.to_rfc3339(),
+                // The network the record is served to, given for a client subnet
+                record.ecs_network.as_deref().unwrap_or("*")
+            );
+        }
+        Ok(output)
+    }
+
+    async fn export(&self, format: DumpFormat) -> Result<String, String> {
+        cachedump::export(self.storage.as_ref(), &self.infra, &SystemClock, format)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    fn infra(&self) -> String {
+        let mut output = String::new();
+        for (server, info) in self.infra.servers() {
+            let _ = writeln!(
+                output,
+                "{}\trtt={}\tedns={}\t{}\t{}",
+                server,
+                info.rtt
+                    .map_or("-".to_string(), |rtt| format!("{}ms", rtt.as_millis())),
+                info.edns
+                    .map_or("-", |edns| if edns { "yes" } else { "no" }),
+                if info.tcp_required { "tcp" } else { "udp" },
+                match self.infra.lame_zones(server) {
+                    zones if zones.is_empty() => "-".to_string(),
+                    zones => format!("lame={}", zones.join(",")),
+                }
+            );
+        }
+        output
+    }
+
+    async fn import(&self, path: &Path) -> Result<String, String> {
+        let content = tokio::fs::read_to_string(path)
+            .await
+            .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+        let imported = cachedump::import(
+            self.storage.as_ref(),
+            &content,
+            DumpFormat::from_path(path),
+            &SystemClock,
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+        Ok(format!("Imported {} records\n", imported))
+    }
+
+    async fn stats(&self) -> CResult<String> {
+        let since = Local::now() - Duration::hours(24);
+        let (queries, blocked) = stats::totals(self.storage.as_ref(), since).await?;
+        let mut output = format!("Last 24 hours: {} queries, {} blocked\n", queries, blocked);
+        let rankings = [
+            (
+                "Top domains",
+                stats::top(self.storage.as_ref(), StatKind::Domain, since, TOP).await?,
+            ),
+            (
+                "Top blocked domains",
+                stats::top_blocked(self.storage.as_ref(), StatKind::Domain, since, TOP).await?,
+            ),
+            (
+                "Top clients",
+                stats::top(self.storage.as_ref(), StatKind::Client, since, TOP).await?,
+            ),
+        ];
+        for (title, rows) in rankings {
+            let _ = writeln!(output, "{}:", title);
+            for row in rows {
+                let _ = writeln!(output, "  {}\t{}\t{}", row.key, row.queries, row.blocked);
+            }
+        }
+        Ok(output)
+    }
+}
+
+/// # `serve`
+///
+/// Accepts the commands on the unix socket at `path`, one per connection:
+/// the client sends a line with the command and receives `ok` followed by the output,
+/// or `error: ` followed by the reason it failed.
+/// The socket is only accessible to the user running the server.
+#[cfg(unix)]
+pub async fn serve(path: &Path, handler: ControlHandler) -> std::io::Result<()> {
+    use std::sync::Arc;
+
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        time::timeout,
+    };
+
+    // A socket left behind by a previous run would prevent binding
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    let listener = bind_private(path)?;
+    let handler = Arc::new(handler);
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(s) => s,
+            Err(e) => {
+                tracing::warn!("Unable to accept a control connection: {}", e);
+                continue;
+            }
+        };
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            let (reader, mut writer) = stream.into_split();
+            let mut command = String::new();
+            let mut reader = BufReader::new(reader.take(MAX_COMMAND));
+            match timeout(READ_TIMEOUT, reader.read_line(&mut command)).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => {
+                    tracing::info!("Unable to read a control command: {}", e);
+                    return;
+                }
+                Err(_) => {
+                    tracing::info!("No control command received within {:?}", READ_TIMEOUT);
+                    return;
+                }
+            }
+            let response = match handler.execute(&command).await {
+                Ok(output) => format!("ok\n{}", output),
+                Err(e) => format!("error: {}\n", e),
+            };
+            if let Err(e) = writer.write_all(response.as_bytes()).await {
+                tracing::info!("Unable to answer a control command: {}", e);
+            }
+        });
+    }
+}
+
+/// # `bind_private`
+///
+/// Binds the socket in a directory only the user running the server can enter,
+/// restricts it to that user and only then moves it to `path`:
+/// nobody else can connect to it in the meantime.
+#[cfg(unix)]
+fn bind_private(path: &Path) -> std::io::Result<tokio::net::UnixListener> {
+    use std::{
+        fs::{self, DirBuilder, Permissions},
+        os::unix::fs::{DirBuilderExt, PermissionsExt},
+    };
+
+    let dir = path.with_extension(format!("{}.tmp", std::process::id()));
+    DirBuilder::new().mode(0o700).create(&dir)?;
+    let staged = dir.join("control.sock");
+    let bound = tokio::net::UnixListener::bind(&staged).and_then(|listener| {
+        fs::set_permissions(&staged, Permissions::from_mode(0o600))?;
+        fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_file(&staged);
+    let _ = fs::remove_dir(&dir);
+    bound
+}
+
+#[cfg(not(unix))]
+pub async fn serve(_path: &Path, _handler: ControlHandler) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "The control socket is only supported on Unix",
+    ))
+}
@@ -0,0 +1,92 @@
+use std::{convert::Infallible, io, net::IpAddr, net::SocketAddr, sync::Arc};
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::sse::{Event, KeepAlive, Sse},
+    routing::get,
+    Router,
+};
+use futures::{Stream, StreamExt};
+use serde::Deserialize;
+use tokio::net::TcpListener;
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+
+use crate::{querylog::QueryLogEntry, workers::ServerState};
+
+/// Queries waiting to be streamed, the clients that fall further behind
+/// skip the ones they missed.
+pub const STREAM_CAPACITY: usize = 1024;
+
+/// # `StreamFilter`
+///
+/// Which queries a client of the stream receives, every query if empty.
+#[derive(Debug, Deserialize, Default)]
+pub struct StreamFilter {
+    /// Only the queries sent by this client.
+    pub client: Option<IpAddr>,
+    /// Only the queries for this domain and its subdomains.
+    pub domain: Option<String>,
+}
+
+impl StreamFilter {
+    pub fn matches(&self, entry: &QueryLogEntry) -> bool {
+        if self.client.is_some_and(|client| client != entry.client) {
+            return false;
+        }
+        match &self.domain {
+            Some(domain) => {
+                let domain = domain.trim_end_matches('.').to_lowercase();
+                let qname = entry.qname.trim_end_matches('.').to_lowercase();
+                qname == domain || qname.ends_with(&format!(".{}", domain))
+            }
+            None => true,
+        }
+    }
+}
+
+/// # `serve`
+///
+/// Serves the HTTP endpoints of the dashboard on `addr`:
+/// - `GET /queries/stream` streams the queries as they are answered,
+///   as Server-Sent Events carrying the JSON of the entries of the query log;
+///   the `client` and `domain` parameters filter them;
+/// - `GET /health/live` answers 200 as long as the server is running;
+/// - `GET /health/ready` answers 200 if the server is ready to answer queries, 503 otherwise.
+///
+/// The endpoints aren't authenticated, `addr` shouldn't be reachable by untrusted clients.
+pub async fn serve(addr: SocketAddr, state: Arc<ServerState>) -> io::Result<()> {
+    let app = Router::new()
+        .route("/queries/stream", get(stream_queries))
+        .route("/health/live", get(|| async { StatusCode::OK }))
+        .route("/health/ready", get(ready))
+        .with_state(state);
+    let listener = TcpListener::bind(addr).await?;
+    tracing::info!("Serving the dashboard on {}", addr);
+    axum::serve(listener, app).await
+}
+
+async fn stream_queries(
+    State(state): State<Arc<ServerState>>,
+    Query(filter): Query<StreamFilter>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let stream = BroadcastStream::new(state.query_stream.subscribe()).filter_map(move |entry| {
+        let event = match entry {
+            Ok(entry) if filter.matches(&entry) => Event::default().json_data(&entry).ok(),
+            Ok(_) => None,
+            Err(BroadcastStreamRecvError::Lagged(missed)) => {
+                Some(Event::default().event("lagged").data(missed.to_string()))
+            }
+        };
+        std::future::ready(event.map(Ok))
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+async fn ready(State(state): State<Arc<ServerState>>) -> StatusCode {
+    if state.is_ready() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
@@ -0,0 +1,109 @@
+use std::path::Path;
+
+use serde::Deserialize;
+use sqlx::{
+    migrate::Migrator,
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous},
+    SqlitePool,
+};
+
+use crate::{configuration::Settings, structs::auxiliaries::CResult};
+
+/// # `JournalMode`
+///
+/// How SQLite keeps the transactions atomic (`PRAGMA journal_mode`):
+/// with the write-ahead log the readers don't wait for the writers,
+/// the cache is read and written by many queries at once.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum JournalMode {
+    Delete,
+    Truncate,
+    Persist,
+    Memory,
+    #[default]
+    Wal,
+    Off,
+}
+
+impl From<JournalMode> for SqliteJournalMode {
+    fn from(mode: JournalMode) -> Self {
+        match mode {
+            JournalMode::Delete => SqliteJournalMode::Delete,
+            JournalMode::Truncate => SqliteJournalMode::Truncate,
+            JournalMode::Persist => SqliteJournalMode::Persist,
+            JournalMode::Memory => SqliteJournalMode::Memory,
+            JournalMode::Wal => SqliteJournalMode::Wal,
+            JournalMode::Off => SqliteJournalMode::Off,
+        }
+    }
+}
+
+/// # `Synchronous`
+///
+/// How often SQLite waits for the writes to reach the disk (`PRAGMA synchronous`),
+/// `normal` is safe with the write-ahead log: a crash may lose the last
+/// transactions, never corrupt the database.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Synchronous {
+    Off,
+    #[default]
+    Normal,
+    Full,
+    Extra,
+}
+
+impl From<Synchronous> for SqliteSynchronous {
+    fn from(synchronous: Synchronous) -> Self {
+        match synchronous {
+            Synchronous::Off => SqliteSynchronous::Off,
+            Synchronous::Normal => SqliteSynchronous::Normal,
+            Synchronous::Full => SqliteSynchronous::Full,
+            Synchronous::Extra => SqliteSynchronous::Extra,
+        }
+    }
+}
+
+/// # `connect`
+///
+/// Opens the database of the settings, creating it if it's missing,
+/// with the pragmas and the size of the pool configured.
+/// The migrations are left to the caller.
+pub async fn connect(settings: &Settings) -> CResult<SqlitePool> {
+    let options = SqliteConnectOptions::new()
+        .filename(settings.get_db_path())
+        .create_if_missing(true)
+        .journal_mode(settings.get_db_journal_mode().into())
+        .synchronous(settings.get_db_synchronous().into())
+        .busy_timeout(settings.get_db_busy_timeout());
+    let db_pool = SqlitePoolOptions::new()
+        .max_connections(settings.get_db_max_connections())
+        .connect_with(options)
+        .await?;
+    Ok(db_pool)
+}
+
+/// # `migrate`
+///
+/// Runs on `db_pool` the migrations of the directory of the settings, read when
+/// the server starts; the ones embedded in the executable if the directory doesn't exist.
+pub async fn migrate(settings: &Settings, db_pool: &SqlitePool) -> CResult<()> {
+    let dir = settings.get_migrations_dir();
+    let migrator = if Path::new(&dir).is_dir() {
+        Migrator::new(Path::new(&dir))
+            .await
+            .map_err(|e| sqlx::Error::Migrate(Box::new(e)))?
+    } else {
+        tracing::info!(
+            "The migrations directory {} doesn't exist, running the embedded migrations",
+            dir
+        );
+        sqlx::migrate!()
+    };
+    migrator
+        .run(db_pool)
+        .await
+        .map_err(|e| sqlx::Error::Migrate(Box::new(e)))?;
+    Ok(())
+}
@@ -0,0 +1,173 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use serde::Deserialize;
+
+use crate::{
+    configuration::Settings,
+    structs::{
+        header::ResultCode,
+        packet::Packet,
+        questions_and_records::{QueryType, Record},
+    },
+};
+
+/// Name the clients ask for the designated resolvers (RFC 9462 section 4).
+pub const DDR_NAME: &str = "_dns.resolver.arpa";
+/// Type of the SVCB records (RFC 9460).
+const SVCB: u16 = 64;
+/// TTL of the answers, the clients ask again once it expires.
+const DDR_TTL: u32 = 300;
+/// Keys of the parameters of the SVCB records (RFC 9460 section 14.3.2, RFC 9461).
+const KEY_ALPN: u16 = 1;
+const KEY_PORT: u16 = 3;
+const KEY_IPV4HINT: u16 = 4;
+const KEY_IPV6HINT: u16 = 6;
+const KEY_DOHPATH: u16 = 7;
+/// Path of the DNS over HTTPS endpoints unless configured.
+pub const DEFAULT_DOHPATH: &str = "/dns-query{?dns}";
+
+/// # `EncryptedProtocol`
+///
+/// The encrypted protocols a designated resolver can speak.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EncryptedProtocol {
+    /// DNS over TLS (RFC 7858).
+    Dot,
+    /// DNS over HTTPS (RFC 8484), over HTTP/2.
+    Doh,
+}
+
+impl EncryptedProtocol {
+    pub fn default_port(self) -> u16 {
+        match self {
+            EncryptedProtocol::Dot => 853,
+            EncryptedProtocol::Doh => 443,
+        }
+    }
+
+    /// # `alpn`
+    ///
+    /// The protocol identifier the clients negotiate over TLS.
+    fn alpn(self) -> &'static [u8] {
+        match self {
+            EncryptedProtocol::Dot => b"dot",
+            EncryptedProtocol::Doh => b"h2",
+        }
+    }
+}
+
+/// # `DesignatedResolver`
+///
+/// An encrypted endpoint of the resolver, reached at `target`, the name its
+/// certificate is valid for, on `port`; `dohpath` is the URI template of the
+/// DNS over HTTPS endpoints.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DesignatedResolver {
+    pub target: String,
+    pub protocol: EncryptedProtocol,
+    pub port: u16,
+    pub dohpath: Option<String>,
+    pub ipv4hint: Vec<Ipv4Addr>,
+    pub ipv6hint: Vec<Ipv6Addr>,
+}
+
+impl DesignatedResolver {
+    /// # `svcb_data`
+    ///
+    /// The data of the SVCB record advertising the endpoint with `priority`,
+    /// its parameters sorted by key as RFC 9460 requires.
+    fn svcb_data(&self, priority: u16) -> Vec<u8> {
+        let mut data = priority.to_be_bytes().to_vec();
+        // The target isn't compressed (RFC 9460 section 2.2)
+        for label in self.target.split('.').filter(|label| !label.is_empty()) {
+            data.push(label.len() as u8);
+            data.extend_from_slice(label.as_bytes());
+        }
+        data.push(0);
+
+        let alpn = self.protocol.alpn();
+        let mut alpn_value = vec![alpn.len() as u8];
+        alpn_value.extend_from_slice(alpn);
+        push_param(&mut data, KEY_ALPN, &alpn_value);
+        push_param(&mut data, KEY_PORT, &self.port.to_be_bytes());
+        if !self.ipv4hint.is_empty() {
+            let hints: Vec<u8> = self.ipv4hint.iter().flat_map(|ip| ip.octets()).collect();
+            push_param(&mut data, KEY_IPV4HINT, &hints);
+        }
+        if !self.ipv6hint.is_empty() {
+            let hints: Vec<u8> = self.ipv6hint.iter().flat_map(|ip| ip.octets()).collect();
+            push_param(&mut data, KEY_IPV6HINT, &hints);
+        }
+        if let Some(dohpath) = &self.dohpath {
+            push_param(&mut data, KEY_DOHPATH, dohpath.as_bytes());
+        }
+        data
+    }
+}
+
+/// # `Ddr`
+///
+/// Discovery of Designated Resolvers (RFC 9462): answers the SVCB queries
+/// for `_dns.resolver.arpa` with the encrypted endpoints of the resolver,
+/// so that the clients knowing only its address can upgrade to them.
+/// The endpoints are the DoH one of the server, see `doh`, or the ones of a proxy
+/// terminating TLS in front of it; their certificate must also be valid for the address
+/// the clients query over plain DNS, as they verify it before the upgrade.
+pub struct Ddr {
+    resolvers: Vec<DesignatedResolver>,
+}
+
+impl Ddr {
+    pub fn new(resolvers: Vec<DesignatedResolver>) -> Self {
+        Ddr { resolvers }
+    }
+
+    pub fn from_settings(settings: &Settings) -> Self {
+        Ddr::new(settings.get_ddr_resolvers())
+    }
+
+    /// # `answer`
+    ///
+    /// The response to `request` if it asks for `_dns.resolver.arpa` and there are
+    /// endpoints to advertise, in the order of the configuration; the other types
+    /// of that name have no records.
+    pub fn answer(&self, request: &Packet) -> Option<Packet> {
+        let question = request.questions.first()?;
+        let qname = question.qname.trim_end_matches('.').to_lowercase();
+        if self.resolvers.is_empty() || qname != DDR_NAME {
+            return None;
+        }
+        let mut response = Packet::new();
+        response.add_info(
+            request.header.id,
+            request.header.recursion_desired,
+            true,
+            true,
+            ResultCode::NOERROR,
+        );
+        // resolver.arpa is served by every resolver itself (RFC 9462 section 4)
+        response.header.authoritative_answer = true;
+        response.questions = request.questions.clone();
+        if question.qtype == QueryType::UNKNOWN(SVCB) {
+            for (i, resolver) in self.resolvers.iter().enumerate() {
+                response.answers.push(Record::UNKNOWN {
+                    domain: question.qname.clone(),
+                    qtype: SVCB,
+                    data: resolver.svcb_data(i as u16 + 1),
+                    ttl: DDR_TTL,
+                });
+            }
+        }
+        Some(response)
+    }
+}
+
+/// # `push_param`
+///
+/// Appends the parameter `key` of an SVCB record, preceded by the length of `value`.
+fn push_param(data: &mut Vec<u8>, key: u16, value: &[u8]) {
+    data.extend_from_slice(&key.to_be_bytes());
+    data.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    data.extend_from_slice(value);
+}
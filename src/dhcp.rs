@@ -0,0 +1,351 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    net::{IpAddr, Ipv4Addr},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::Deserialize;
+
+use crate::{
+    clients::ClientNames,
+    configuration::Settings,
+    structs::questions_and_records::Record,
+    zones::{Zone, ZoneStore},
+};
+
+/// How often the lease files are read again.
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Address, hostname and expiration of a lease as written in a file,
+/// `None` if the lease never expires.
+type Entry = (Ipv4Addr, Option<String>, Option<DateTime<Utc>>);
+
+/// # `LeaseFormat`
+///
+/// The DHCP server that writes a lease file.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LeaseFormat {
+    /// `dnsmasq.leases`: `<expiry> <mac> <address> <hostname> <client id>` per line.
+    Dnsmasq,
+    /// `dhcpd.leases` of the ISC DHCP server, a `lease <address> { ... }` block per lease.
+    Isc,
+    /// The CSV file of the memfile backend of Kea, `kea-leases4.csv`.
+    Kea,
+}
+
+/// # `Lease`
+///
+/// An address handed out to a device that told its name.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Lease {
+    /// First label of the name, lowercase.
+    pub hostname: String,
+    pub addr: Ipv4Addr,
+}
+
+/// # `parse_leases`
+///
+/// Extracts the leases still valid at `now` from the content of a lease file,
+/// the devices that didn't send a valid hostname are left out, as the IPv6 leases.
+/// When an address appears more than once the last entry wins, as the files are appended to.
+pub fn parse_leases(content: &str, format: LeaseFormat, now: DateTime<Utc>) -> Vec<Lease> {
+    let entries = match format {
+        LeaseFormat::Dnsmasq => parse_dnsmasq(content),
+        LeaseFormat::Isc => parse_isc(content),
+        LeaseFormat::Kea => parse_kea(content),
+    };
+    let mut leases = BTreeMap::new();
+    for (addr, hostname, expires) in entries {
+        let hostname = hostname.as_deref().and_then(normalize_hostname);
+        match hostname {
+            Some(hostname) if expires.is_none_or(|expires| expires > now) => {
+                leases.insert(addr, hostname);
+            }
+            // A later entry can also release the address
+            _ => {
+                leases.remove(&addr);
+            }
+        }
+    }
+    leases
+        .into_iter()
+        .map(|(addr, hostname)| Lease { hostname, addr })
+        .collect()
+}
+
+fn parse_dnsmasq(content: &str) -> Vec<Entry> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            // The IPv6 leases follow a `duid` line and carry IPv6 addresses
+            let [expiry, _mac, addr, hostname, ..] = fields.as_slice() else {
+                return None;
+            };
+            let addr = addr.parse().ok()?;
+            let expires = match expiry.parse::<i64>().ok()? {
+                0 => None,
+                expiry => Some(DateTime::from_timestamp(expiry, 0)?),
+            };
+            let hostname = (*hostname != "*").then(|| hostname.to_string());
+            Some((addr, hostname, expires))
+        })
+        .collect()
+}
+
+fn parse_isc(content: &str) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    // The lease being read and whether it's active
+    let mut current: Option<(Entry, bool)> = None;
+    for line in content.lines() {
+        let line = line.trim().trim_end_matches(';');
+        if let Some(rest) = line.strip_prefix("lease ") {
+            current = rest
+                .trim_end_matches('{')
+                .trim()
+                .parse()
+                .ok()
+                .map(|addr| ((addr, None, None), false));
+            continue;
+        }
+        let Some(((addr, hostname, expires), active)) = current.as_mut() else {
+            continue;
+        };
+        if line == "}" {
+            let expires = if *active {
+                *expires
+            } else {
+                Some(DateTime::UNIX_EPOCH)
+            };
+            entries.push((*addr, hostname.take(), expires));
+            current = None;
+        } else if let Some(state) = line.strip_prefix("binding state ") {
+            *active = state.trim() == "active";
+        } else if let Some(name) = line.strip_prefix("client-hostname ") {
+            *hostname = Some(name.trim().trim_matches('"').to_string());
+        } else if let Some(ends) = line.strip_prefix("ends ") {
+            *expires = parse_isc_time(ends.trim());
+        }
+    }
+    entries
+}
+
+/// Reads `ends` of a lease: `never`, `epoch <seconds>` or `<weekday> <yyyy/mm/dd> <hh:mm:ss>` in UTC.
+fn parse_isc_time(ends: &str) -> Option<DateTime<Utc>> {
+    let mut fields = ends.split_whitespace();
+    match fields.next()? {
+        "never" => None,
+        "epoch" => DateTime::from_timestamp(fields.next()?.parse().ok()?, 0),
+        _ => {
+            let time = format!("{} {}", fields.next()?, fields.next()?);
+            // A date that can't be read counts as expired
+            Some(
+                NaiveDateTime::parse_from_str(&time, "%Y/%m/%d %H:%M:%S")
+                    .map(|time| time.and_utc())
+                    .unwrap_or(DateTime::UNIX_EPOCH),
+            )
+        }
+    }
+}
+
+fn parse_kea(content: &str) -> Vec<Entry> {
+    let mut lines = content.lines();
+    let Some(header) = lines.next() else {
+        return Vec::new();
+    };
+    let columns: Vec<&str> = header.split(',').map(str::trim).collect();
+    let column = |name: &str| columns.iter().position(|c| *c == name);
+    let (Some(address), Some(expire), Some(hostname), Some(state)) = (
+        column("address"),
+        column("expire"),
+        column("hostname"),
+        column("state"),
+    ) else {
+        tracing::warn!("The Kea lease file lacks some of the columns needed");
+        return Vec::new();
+    };
+    lines
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(',').collect();
+            let addr = fields.get(address)?.parse().ok()?;
+            // Only the state 0 is an address in use, the others are declined or expired
+            let expires = match *fields.get(state)? {
+                "0" => DateTime::from_timestamp(fields.get(expire)?.parse().ok()?, 0)?,
+                _ => DateTime::UNIX_EPOCH,
+            };
+            let hostname = fields.get(hostname).map(|h| h.to_string());
+            Some((addr, hostname, Some(expires)))
+        })
+        .collect()
+}
+
+/// # `normalize_hostname`
+///
+/// The first label of `name`, lowercase, if it's a valid hostname.
+fn normalize_hostname(name: &str) -> Option<String> {
+    let label = name.trim().split('.').next()?.to_lowercase();
+    let valid = !label.is_empty()
+        && label.len() <= 63
+        && !label.starts_with('-')
+        && !label.ends_with('-')
+        && label
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-');
+    valid.then_some(label)
+}
+
+/// # `LeaseWatcher`
+///
+/// Publishes the devices named in the lease files of a DHCP server as a zone served
+/// authoritatively, `<hostname>.<domain>`, along with the PTR records of their addresses,
+/// each one a zone of its own: the other addresses of their networks are still resolved.
+/// The hostnames name the clients as well.
+/// The files are read every `POLL_INTERVAL`, the zones are replaced when the leases change.
+pub struct LeaseWatcher {
+    files: Vec<(PathBuf, LeaseFormat)>,
+    domain: String,
+    ttl: u32,
+    zones: Arc<ZoneStore>,
+    clients: Arc<ClientNames>,
+    /// Leases published, along with the reverse zones of their addresses.
+    published: Option<(Vec<Lease>, Vec<String>)>,
+}
+
+impl LeaseWatcher {
+    /// # `from_settings`
+    ///
+    /// `None` if no lease file is configured.
+    pub fn from_settings(
+        settings: &Settings,
+        zones: Arc<ZoneStore>,
+        clients: Arc<ClientNames>,
+    ) -> Option<Self> {
+        let files = settings.get_dhcp_leases();
+        (!files.is_empty()).then(|| LeaseWatcher {
+            files,
+            domain: settings.get_dhcp_domain(),
+            ttl: settings.get_dhcp_ttl(),
+            zones,
+            clients,
+            published: None,
+        })
+    }
+
+    /// # `run`
+    ///
+    /// Reads the lease files every `POLL_INTERVAL` and publishes the leases found.
+    pub async fn run(mut self) {
+        let mut poll = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            poll.tick().await;
+            let leases = self.read_leases().await;
+            self.publish(leases);
+        }
+    }
+
+    /// # `read_leases`
+    ///
+    /// The leases of every file, sorted; the files that can't be read are skipped,
+    /// a DHCP server that hasn't handed out any address yet may not have written one.
+    async fn read_leases(&self) -> Vec<Lease> {
+        let now = Utc::now();
+        let mut leases = Vec::new();
+        for (path, format) in &self.files {
+            match tokio::fs::read_to_string(path).await {
+                Ok(content) => leases.extend(parse_leases(&content, *format, now)),
+                Err(e) => tracing::debug!("Unable to read {}: {}", path.display(), e),
+            }
+        }
+        leases.sort();
+        leases.dedup();
+        leases
+    }
+
+    /// # `publish`
+    ///
+    /// Replaces the zones with the ones holding `leases`, if they changed.
+    fn publish(&mut self, leases: Vec<Lease>) {
+        if self
+            .published
+            .as_ref()
+            .is_some_and(|(published, _)| *published == leases)
+        {
+            return;
+        }
+        let serial = Utc::now().timestamp() as u32;
+        let (forward, reverse) = lease_zones(&self.domain, &leases, self.ttl, serial);
+        let reverse_names: Vec<String> = reverse.iter().map(|z| z.name.clone()).collect();
+        self.zones.insert(forward);
+        for zone in reverse {
+            self.zones.insert(zone);
+        }
+        if let Some((_, previous)) = &self.published {
+            for name in previous.iter().filter(|n| !reverse_names.contains(n)) {
+                self.zones.remove(name);
+            }
+        }
+        self.clients.set_dhcp(
+            leases
+                .iter()
+                .map(|lease| (IpAddr::V4(lease.addr), lease.hostname.clone()))
+                .collect(),
+        );
+        tracing::info!("Published {} DHCP leases in {}", leases.len(), self.domain);
+        self.published = Some((leases, reverse_names));
+    }
+}
+
+/// # `lease_zones`
+///
+/// Builds the zone `domain` with the A records of `leases` and the reverse zones
+/// with their PTR records, one per address, the only name it holds.
+pub fn lease_zones(domain: &str, leases: &[Lease], ttl: u32, serial: u32) -> (Zone, Vec<Zone>) {
+    let soa = |name: &str| Record::SOA {
+        domain: name.into(),
+        mname: domain.into(),
+        rname: format!("hostmaster.{}", domain).into(),
+        serial,
+        refresh: 3600,
+        retry: 600,
+        expire: 86400,
+        minimum: ttl,
+        ttl,
+    };
+    let forward = Zone {
+        name: domain.to_string(),
+        soa: soa(domain),
+        records: leases
+            .iter()
+            .map(|lease| Record::A {
+                domain: format!("{}.{}", lease.hostname, domain).into(),
+                addr: lease.addr,
+                ttl,
+            })
+            .collect(),
+    };
+
+    let mut seen = HashSet::new();
+    let reverse = leases
+        .iter()
+        // A device with more than one name gets a single PTR
+        .filter(|lease| seen.insert(lease.addr))
+        .map(|lease| {
+            let [a, b, c, d] = lease.addr.octets();
+            let name = format!("{}.{}.{}.{}.in-addr.arpa", d, c, b, a);
+            Zone {
+                soa: soa(&name),
+                records: vec![Record::PTR {
+                    domain: name.as_str().into(),
+                    host: format!("{}.{}", lease.hostname, domain).into(),
+                    ttl,
+                }],
+                name,
+            }
+        })
+        .collect();
+    (forward, reverse)
+}
@@ -0,0 +1,472 @@
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use ring::{
+    digest,
+    rand::SystemRandom,
+    signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING},
+};
+
+use crate::{
+    acme::write_private,
+    structs::{
+        auxiliaries::CResult,
+        buffer::BytePacketBuffer,
+        questions_and_records::{QueryType, Record},
+    },
+    zones::{is_subdomain, Zone},
+};
+
+/// ECDSA Curve P-256 with SHA-256, the only algorithm used for signing.
+pub const ALGORITHM: u8 = 13;
+/// DNSKEY flag of keys that sign zone data.
+const ZONE_KEY_FLAG: u16 = 256;
+/// DNSKEY flag of keys that act as a secure entry point, the KSK.
+const SEP_FLAG: u16 = 1;
+/// Time subtracted from the inception of signatures, to tolerate clock skew.
+const INCEPTION_OFFSET: u32 = 3600;
+
+/// # `SigningKey`
+///
+/// An ECDSA P-256 key pair used to sign a zone, stored on disk in PKCS#8 format.
+pub struct SigningKey {
+    pub flags: u16,
+    pub key_tag: u16,
+    key_pair: EcdsaKeyPair,
+    /// Public key in the format used by DNSKEY records: the uncompressed point without prefix
+    public_key: Vec<u8>,
+}
+
+impl SigningKey {
+    /// # `load_or_generate`
+    ///
+    /// Loads the key stored at `path`, a new key is generated and stored
+    /// there if the file doesn't exist.
+    pub fn load_or_generate(path: &Path, flags: u16) -> CResult<SigningKey> {
+        let rng = SystemRandom::new();
+        let pkcs8 = match fs::read(path) {
+            Ok(pkcs8) => pkcs8,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                tracing::info!("Generating a new DNSSEC key in {}", path.display());
+                let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+                    .map_err(|_| "Failed to generate a DNSSEC key")?;
+                write_private(path, pkcs8.as_ref())?;
+                pkcs8.as_ref().to_vec()
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let key_pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8, &rng)
+            .map_err(|e| format!("Invalid DNSSEC key in {}: {}", path.display(), e))?;
+        let public_key = key_pair.public_key().as_ref()[1..].to_vec();
+
+        let mut key_rdata = flags.to_be_bytes().to_vec();
+        key_rdata.push(3);
+        key_rdata.push(ALGORITHM);
+        key_rdata.extend_from_slice(&public_key);
+
+        Ok(SigningKey {
+            flags,
+            key_tag: key_tag(&key_rdata),
+            key_pair,
+            public_key,
+        })
+    }
+
+    /// # `dnskey`
+    ///
+    /// DNSKEY record publishing the public part of the key.
+    pub fn dnskey(&self, zone: &str, ttl: u32) -> Record {
+        Record::DNSKEY {
+            domain: zone.into(),
+            flags: self.flags,
+            protocol: 3,
+            algorithm: ALGORITHM,
+            public_key: self.public_key.clone(),
+            ttl,
+        }
+    }
+
+    fn sign(&self, data: &[u8]) -> CResult<Vec<u8>> {
+        let signature = self
+            .key_pair
+            .sign(&SystemRandom::new(), data)
+            .map_err(|_| "Failed to sign an RRset")?;
+        Ok(signature.as_ref().to_vec())
+    }
+}
+
+/// # `ZoneSigner`
+///
+/// Signs the zones we serve: publishes the DNSKEYs of a KSK and of a ZSK,
+/// builds the NSEC chain and produces an RRSIG for every authoritative RRset.
+/// Keys are kept in `keys_dir`, one pair for every zone.
+pub struct ZoneSigner {
+    keys_dir: PathBuf,
+    validity: Duration,
+}
+
+impl ZoneSigner {
+    pub fn new(keys_dir: PathBuf, validity: Duration) -> Self {
+        ZoneSigner { keys_dir, validity }
+    }
+
+    /// # `resign_interval`
+    ///
+    /// How often a zone needs to be checked to avoid serving expired signatures.
+    pub fn resign_interval(&self) -> Duration {
+        self.validity / 4
+    }
+
+    /// # `needs_resign`
+    ///
+    /// Returns true if the zone isn't signed or if half of the validity period
+    /// of one of its signatures has already passed.
+    pub fn needs_resign(&self, zone: &Zone) -> bool {
+        let threshold = now() + (self.validity.as_secs() / 2) as u32;
+        zone.records
+            .iter()
+            .filter_map(|r| match r {
+                Record::RRSIG { expiration, .. } => Some(*expiration),
+                _ => None,
+            })
+            .min()
+            .is_none_or(|expiration| expiration < threshold)
+    }
+
+    /// # `sign_zone`
+    ///
+    /// Replaces every DNSSEC record of the zone with freshly generated ones.
+    #[tracing::instrument(name = "Signing a zone", skip(self, zone), fields(zone = zone.name))]
+    pub fn sign_zone(&self, zone: &mut Zone) -> CResult<()> {
+        let ksk = SigningKey::load_or_generate(
+            &self.keys_dir.join(format!("{}.ksk.pk8", zone.name)),
+            ZONE_KEY_FLAG | SEP_FLAG,
+        )?;
+        let zsk = SigningKey::load_or_generate(
+            &self.keys_dir.join(format!("{}.zsk.pk8", zone.name)),
+            ZONE_KEY_FLAG,
+        )?;
+        let (soa_ttl, negative_ttl) = match zone.soa {
+            Record::SOA { ttl, minimum, .. } => (ttl, minimum.min(ttl)),
+            _ => return Err("The zone has no SOA".into()),
+        };
+
+        zone.records.retain(|r| {
+            !matches!(
+                r,
+                Record::RRSIG { .. } | Record::NSEC { .. } | Record::DNSKEY { .. }
+            )
+        });
+        zone.records.push(ksk.dnskey(&zone.name, soa_ttl));
+        zone.records.push(zsk.dnskey(&zone.name, soa_ttl));
+
+        // Names below a delegation aren't authoritative data, they are not signed
+        let cuts: Vec<String> = zone
+            .records
+            .iter()
+            .filter(|r| {
+                matches!(r, Record::NS { .. }) && !r.get_domain().eq_ignore_ascii_case(&zone.name)
+            })
+            .map(|r| r.get_domain().to_string())
+            .collect();
+        let below_cut = |name: &str| {
+            cuts.iter()
+                .any(|c| !name.eq_ignore_ascii_case(c) && is_subdomain(name, c))
+        };
+
+        // Types present at every authoritative name, ordered canonically
+        let mut names: BTreeMap<CanonicalName, BTreeSet<u16>> = BTreeMap::new();
+        names
+            .entry(CanonicalName(zone.name.clone()))
+            .or_default()
+            .insert(QueryType::SOA.to_num());
+        for record in zone.records.iter().filter(|r| !below_cut(r.get_domain())) {
+            // At a delegation point only NS and DS records are ours
+            if cuts
+                .iter()
+                .any(|c| c.eq_ignore_ascii_case(record.get_domain()))
+                && !matches!(record, Record::NS { .. } | Record::DS { .. })
+            {
+                continue;
+            }
+            names
+                .entry(CanonicalName(record.get_domain().to_string()))
+                .or_default()
+                .insert(record.get_qtype().to_num());
+        }
+
+        // NSEC chain, the last name points back to the apex
+        let owners: Vec<&CanonicalName> = names.keys().collect();
+        for (i, owner) in owners.iter().enumerate() {
+            let next = owners.get(i + 1).unwrap_or(&owners[0]);
+            let mut types: Vec<u16> = names[*owner].iter().copied().collect();
+            types.push(QueryType::RRSIG.to_num());
+            types.push(QueryType::NSEC.to_num());
+            zone.records.push(Record::NSEC {
+                domain: owner.0.as_str().into(),
+                next_domain: next.0.as_str().into(),
+                type_bitmap: type_bitmap(&types),
+                ttl: negative_ttl,
+            });
+        }
+
+        // Grouping the authoritative records into RRsets
+        let mut rrsets: BTreeMap<(CanonicalName, u16), Vec<Record>> = BTreeMap::new();
+        rrsets.insert(
+            (CanonicalName(zone.name.clone()), QueryType::SOA.to_num()),
+            vec![zone.soa.clone()],
+        );
+        for record in &zone.records {
+            let owner = record.get_domain();
+            let is_delegation = cuts.iter().any(|c| c == owner)
+                && !matches!(record, Record::DS { .. } | Record::NSEC { .. });
+            if below_cut(owner) || is_delegation {
+                continue;
+            }
+            rrsets
+                .entry((
+                    CanonicalName(owner.to_string()),
+                    record.get_qtype().to_num(),
+                ))
+                .or_default()
+                .push(record.clone());
+        }
+
+        let inception = now() - INCEPTION_OFFSET;
+        let expiration = now() + self.validity.as_secs() as u32;
+        let mut signatures = Vec::new();
+        for ((owner, qtype), rrset) in rrsets {
+            let key = if qtype == QueryType::DNSKEY.to_num() {
+                &ksk
+            } else {
+                &zsk
+            };
+            signatures.push(sign_rrset(
+                &owner.0, qtype, &rrset, key, &zone.name, inception, expiration,
+            )?);
+        }
+        let count = signatures.len();
+        zone.records.extend(signatures);
+
+        if let Some(ds) = ds_record(&ksk.dnskey(&zone.name, soa_ttl)) {
+            tracing::info!(
+                "Signed the zone {} with {} signatures, DS for the parent zone: {:?}",
+                zone.name,
+                count,
+                ds
+            );
+        }
+        Ok(())
+    }
+}
+
+/// # `sign_rrset`
+///
+/// Produces the RRSIG of an RRset as described in RFC 4034 section 3.1.8.1,
+/// over the data of the records in canonical form.
+fn sign_rrset(
+    owner: &str,
+    qtype: u16,
+    rrset: &[Record],
+    key: &SigningKey,
+    signer_name: &str,
+    inception: u32,
+    expiration: u32,
+) -> CResult<Record> {
+    let original_ttl = rrset.iter().map(|r| r.get_ttl()).min().unwrap_or(0);
+    let labels = owner
+        .split('.')
+        .filter(|l| !l.is_empty() && *l != "*")
+        .count() as u8;
+
+    let mut data = Vec::new();
+    data.extend_from_slice(&qtype.to_be_bytes());
+    data.push(ALGORITHM);
+    data.push(labels);
+    data.extend_from_slice(&original_ttl.to_be_bytes());
+    data.extend_from_slice(&expiration.to_be_bytes());
+    data.extend_from_slice(&inception.to_be_bytes());
+    data.extend_from_slice(&key.key_tag.to_be_bytes());
+    data.extend(name_wire(signer_name)?);
+
+    let owner_wire = name_wire(owner)?;
+    let mut rdatas = rrset
+        .iter()
+        .map(Record::canonical_rdata)
+        .collect::<CResult<Vec<_>>>()?;
+    rdatas.sort();
+    rdatas.dedup();
+    for rd in rdatas {
+        data.extend_from_slice(&owner_wire);
+        data.extend_from_slice(&qtype.to_be_bytes());
+        data.extend_from_slice(&1u16.to_be_bytes());
+        data.extend_from_slice(&original_ttl.to_be_bytes());
+        data.extend_from_slice(&(rd.len() as u16).to_be_bytes());
+        data.extend(rd);
+    }
+
+    Ok(Record::RRSIG {
+        domain: owner.into(),
+        type_covered: qtype,
+        algorithm: ALGORITHM,
+        labels,
+        original_ttl,
+        expiration,
+        inception,
+        key_tag: key.key_tag,
+        signer_name: signer_name.into(),
+        signature: key.sign(&data)?,
+        ttl: original_ttl,
+    })
+}
+
+/// # `ds_record`
+///
+/// Computes the SHA-256 DS record (RFC 4509) of a DNSKEY, the one that
+/// needs to be published in the parent zone to complete the chain of trust.
+pub fn ds_record(dnskey: &Record) -> Option<Record> {
+    if let Record::DNSKEY {
+        domain, algorithm, ..
+    } = dnskey
+    {
+        let key_rdata = dnskey.wire_rdata().ok()?;
+        let mut data = name_wire(domain).ok()?;
+        data.extend_from_slice(&key_rdata);
+        return Some(Record::DS {
+            domain: domain.clone(),
+            key_tag: key_tag(&key_rdata),
+            algorithm: *algorithm,
+            digest_type: 2,
+            digest: digest::digest(&digest::SHA256, &data).as_ref().to_vec(),
+            ttl: dnskey.get_ttl(),
+        });
+    }
+    None
+}
+
+/// # `key_tag`
+///
+/// Key tag of a DNSKEY given its data, as described in RFC 4034 appendix B.
+pub fn key_tag(rdata: &[u8]) -> u16 {
+    let mut acc: u32 = 0;
+    for (i, b) in rdata.iter().enumerate() {
+        acc += if i & 1 == 1 {
+            *b as u32
+        } else {
+            (*b as u32) << 8
+        };
+    }
+    acc += (acc >> 16) & 0xFFFF;
+    (acc & 0xFFFF) as u16
+}
+
+/// # `type_bitmap`
+///
+/// Encodes a list of types in the windowed bitmap format of RFC 4034 section 4.1.2.
+pub fn type_bitmap(types: &[u16]) -> Vec<u8> {
+    let mut windows: BTreeMap<u8, [u8; 32]> = BTreeMap::new();
+    for t in types {
+        let window = windows.entry((t >> 8) as u8).or_insert([0; 32]);
+        let low = (t & 0xFF) as usize;
+        window[low / 8] |= 0x80 >> (low % 8);
+    }
+    let mut bitmap = Vec::new();
+    for (number, window) in windows {
+        let len = window.iter().rposition(|b| *b != 0).map_or(0, |p| p + 1);
+        bitmap.push(number);
+        bitmap.push(len as u8);
+        bitmap.extend_from_slice(&window[..len]);
+    }
+    bitmap
+}
+
+/// # `bitmap_contains`
+///
+/// Returns true if the type bitmap of an NSEC record contains `qtype`.
+pub fn bitmap_contains(bitmap: &[u8], qtype: u16) -> bool {
+    let mut pos = 0;
+    while pos + 2 <= bitmap.len() {
+        let (window, len) = (bitmap[pos], bitmap[pos + 1] as usize);
+        let low = (qtype & 0xFF) as usize;
+        if window == (qtype >> 8) as u8 && low / 8 < len {
+            return bitmap.get(pos + 2 + low / 8).unwrap_or(&0) & (0x80 >> (low % 8)) != 0;
+        }
+        pos += 2 + len;
+    }
+    false
+}
+
+/// # `bitmap_types`
+///
+/// Decodes the types listed in a type bitmap of an NSEC record.
+pub fn bitmap_types(bitmap: &[u8]) -> Vec<u16> {
+    let mut types = Vec::new();
+    let mut pos = 0;
+    while pos + 2 <= bitmap.len() {
+        let (window, len) = (bitmap[pos] as u16, bitmap[pos + 1] as usize);
+        let bytes = bitmap
+            .get(pos + 2..pos + 2 + len)
+            .unwrap_or(&bitmap[pos + 2..]);
+        for (i, byte) in bytes.iter().enumerate() {
+            for bit in 0..8 {
+                if byte & (0x80 >> bit) != 0 {
+                    types.push(window << 8 | (i * 8 + bit) as u16);
+                }
+            }
+        }
+        pos += 2 + len;
+    }
+    types
+}
+
+/// # `canonical_cmp`
+///
+/// Compares two domain names in the canonical order of RFC 4034 section 6.1:
+/// label by label, starting from the rightmost one, case insensitively.
+pub fn canonical_cmp(a: &str, b: &str) -> Ordering {
+    let a_labels = a.split('.').rev().filter(|l| !l.is_empty());
+    let b_labels = b.split('.').rev().filter(|l| !l.is_empty());
+    a_labels
+        .map(|l| l.to_ascii_lowercase().into_bytes())
+        .cmp(b_labels.map(|l| l.to_ascii_lowercase().into_bytes()))
+}
+
+/// # `CanonicalName`
+///
+/// Domain name ordered canonically, used as key of sorted collections.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CanonicalName(String);
+
+impl PartialOrd for CanonicalName {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for CanonicalName {
+    fn cmp(&self, other: &Self) -> Ordering {
+        canonical_cmp(&self.0, &other.0)
+    }
+}
+
+/// # `name_wire`
+///
+/// Canonical wire format of a name: lowercase and uncompressed.
+pub(crate) fn name_wire(name: &str) -> CResult<Vec<u8>> {
+    let mut buffer = BytePacketBuffer::empty();
+    if name.is_empty() {
+        buffer.write_u8(0)?;
+    } else {
+        buffer.write_qname(&name.to_lowercase())?;
+    }
+    Ok(buffer.freeze().into())
+}
+
+fn now() -> u32 {
+    chrono::Utc::now().timestamp() as u32
+}
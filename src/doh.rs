@@ -0,0 +1,321 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use axum::{
+    body::Bytes,
+    extract::{Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Extension, Json, Router,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+    service::TowerToHyperService,
+};
+use serde::Deserialize;
+use tokio::{
+    net::TcpListener,
+    sync::{mpsc, watch},
+    time::timeout,
+};
+use tokio_rustls::TlsAcceptor;
+
+use crate::{
+    certificates::CertificateStore,
+    configuration::Settings,
+    server::handle,
+    structs::{
+        auxiliaries::CResult, buffer::BytePacketBuffer, json::DohJson, packet::Packet,
+        questions_and_records::Record,
+    },
+    udp::Responder,
+    workers::{OverflowPolicy, ServerState},
+};
+
+/// Media type of the DNS messages in the wire format (RFC 8484 section 6).
+pub const DNS_MESSAGE: &str = "application/dns-message";
+/// Media type of the JSON representation of the DNS messages, `DohJson`.
+pub const DNS_JSON: &str = "application/dns-json";
+
+/// Protocols offered in the TLS handshake, HTTP/2 first.
+const ALPN: [&[u8]; 2] = [b"h2", b"http/1.1"];
+
+/// Time a client is given to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// # `Doh`
+///
+/// DNS over HTTPS (RFC 8484) endpoint, the queries go through the query handlers
+/// as the ones received over UDP.
+/// HTTP/2 and HTTP/1.1 are negotiated with ALPN, an HTTP/2 connection carries
+/// up to `max_streams` requests at the same time; HTTP/3 isn't spoken.
+pub struct Doh {
+    listener: TcpListener,
+    certificates: Arc<CertificateStore>,
+    acceptor: TlsAcceptor,
+    path: String,
+    max_streams: u32,
+}
+
+impl Doh {
+    /// # `from_settings`
+    ///
+    /// Binds the address of the endpoint and loads its certificate, unless it's
+    /// still to be obtained with ACME. `None` if DoH isn't served.
+    pub async fn from_settings(settings: &Settings) -> CResult<Option<Self>> {
+        let Some(addr) = settings.get_doh_address() else {
+            return Ok(None);
+        };
+        let (certificate, private_key) = (
+            settings.get_doh_certificate(),
+            settings.get_doh_private_key(),
+        );
+        // The first certificate obtained with ACME is written once the server is running
+        let certificates = if settings.get_acme_enabled() && !certificate.exists() {
+            CertificateStore::awaiting(certificate, private_key)
+        } else {
+            CertificateStore::load(certificate, private_key)?
+        };
+        let certificates = Arc::new(certificates);
+        Ok(Some(Doh {
+            listener: TcpListener::bind(addr).await?,
+            acceptor: TlsAcceptor::from(certificates.server_config(&ALPN)),
+            certificates,
+            path: settings.get_doh_path().to_string(),
+            max_streams: settings.get_doh_max_streams(),
+        }))
+    }
+
+    /// # `certificates`
+    ///
+    /// The certificate of the endpoint, it can be reloaded while serving.
+    pub fn certificates(&self) -> Arc<CertificateStore> {
+        self.certificates.clone()
+    }
+
+    /// # `serve`
+    ///
+    /// Accepts the connections until `closed` is dropped, then the open ones
+    /// complete the requests in progress and are closed.
+    pub async fn serve(
+        self,
+        state: Arc<ServerState>,
+        overflow_policy: OverflowPolicy,
+        mut closed: watch::Receiver<()>,
+    ) {
+        let app = Router::new()
+            .route(&self.path, get(get_query).post(post_query))
+            .with_state(Arc::new(DohState {
+                server: state,
+                overflow_policy,
+            }));
+        let mut http = auto::Builder::new(TokioExecutor::new());
+        http.http2().max_concurrent_streams(self.max_streams);
+        if let Ok(addr) = self.listener.local_addr() {
+            tracing::info!("Serving DNS over HTTPS on {}{}", addr, self.path);
+        }
+        loop {
+            let (stream, src) = tokio::select! {
+                _ = closed.changed() => return,
+                accepted = self.listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        tracing::info!("Failed to accept a DoH connection: {}", e);
+                        continue;
+                    }
+                },
+            };
+            let acceptor = self.acceptor.clone();
+            let http = http.clone();
+            let service = TowerToHyperService::new(app.clone().layer(Extension(src)));
+            let mut closed = closed.clone();
+            tokio::spawn(async move {
+                let tls = match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(tls)) => tls,
+                    Ok(Err(e)) => {
+                        tracing::info!("TLS handshake with {} failed: {}", src, e);
+                        return;
+                    }
+                    Err(_) => {
+                        tracing::info!("TLS handshake with {} timed out", src);
+                        return;
+                    }
+                };
+                let conn = http.serve_connection(TokioIo::new(tls), service);
+                tokio::pin!(conn);
+                let served = tokio::select! {
+                    served = conn.as_mut() => served,
+                    _ = closed.changed() => {
+                        conn.as_mut().graceful_shutdown();
+                        conn.await
+                    }
+                };
+                if let Err(e) = served {
+                    tracing::info!("DoH connection with {} failed: {}", src, e);
+                }
+            });
+        }
+    }
+}
+
+/// What the handlers of the DoH endpoint work on.
+struct DohState {
+    server: Arc<ServerState>,
+    overflow_policy: OverflowPolicy,
+}
+
+#[derive(Debug, Deserialize)]
+struct DohParams {
+    /// The query in the wire format, base64url encoded without padding.
+    dns: String,
+}
+
+/// # `Format`
+///
+/// How the response is represented, chosen from the `Accept` header of the request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Message,
+    Json,
+}
+
+async fn get_query(
+    State(state): State<Arc<DohState>>,
+    Extension(src): Extension<SocketAddr>,
+    headers: HeaderMap,
+    Query(params): Query<DohParams>,
+) -> Response {
+    let format = match negotiate(&headers) {
+        Ok(format) => format,
+        Err(e) => return e.into_response(),
+    };
+    match URL_SAFE_NO_PAD.decode(params.dns.trim_end_matches('=')) {
+        Ok(message) => resolve(&state, src, message, format).await,
+        Err(_) => (StatusCode::BAD_REQUEST, "dns isn't valid base64url").into_response(),
+    }
+}
+
+async fn post_query(
+    State(state): State<Arc<DohState>>,
+    Extension(src): Extension<SocketAddr>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let format = match negotiate(&headers) {
+        Ok(format) => format,
+        Err(e) => return e.into_response(),
+    };
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    if content_type != Some(DNS_MESSAGE) {
+        return (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "the body must be application/dns-message",
+        )
+            .into_response();
+    }
+    resolve(&state, src, body.to_vec(), format).await
+}
+
+/// # `negotiate`
+///
+/// The wire format unless the client accepts JSON and not the wire format,
+/// fails if it accepts neither.
+fn negotiate(headers: &HeaderMap) -> Result<Format, (StatusCode, &'static str)> {
+    let Some(accept) = headers
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return Ok(Format::Message);
+    };
+    let accepted: Vec<&str> = accept
+        .split(',')
+        .filter_map(|range| range.split(';').next())
+        .map(str::trim)
+        .collect();
+    let accepts = |media_type: &str| {
+        accepted
+            .iter()
+            .any(|range| *range == media_type || *range == "*/*" || *range == "application/*")
+    };
+    if accepts(DNS_MESSAGE) {
+        Ok(Format::Message)
+    } else if accepts(DNS_JSON) {
+        Ok(Format::Json)
+    } else {
+        Err((
+            StatusCode::NOT_ACCEPTABLE,
+            "only application/dns-message and application/dns-json are served",
+        ))
+    }
+}
+
+/// # `resolve`
+///
+/// Hands `message` to a query handler, as a datagram of `src`, and responds with
+/// what it answers; the response may be cached by HTTP caches as long as the records
+/// it carries (RFC 8484 section 5.1).
+async fn resolve(state: &DohState, src: SocketAddr, message: Vec<u8>, format: Format) -> Response {
+    let (conn, mut responses) = mpsc::channel(1);
+    let responder = Responder::channel(conn);
+    handle(
+        BytePacketBuffer::from_bytes(&message),
+        src,
+        &responder,
+        &state.server,
+        &state.server.in_flight,
+        state.overflow_policy,
+    )
+    .await;
+    drop(responder);
+    // Nothing comes back for the queries that are dropped
+    let Some(response) = responses.recv().await else {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "the query hasn't been answered",
+        )
+            .into_response();
+    };
+    let packet = Packet::from_bytes(&response).ok();
+    let max_age = packet.as_ref().map(freshness).unwrap_or(0);
+    let cache_control = format!("max-age={}", max_age);
+    match (format, packet) {
+        (Format::Json, Some(packet)) => (
+            [
+                (header::CONTENT_TYPE, DNS_JSON.to_string()),
+                (header::CACHE_CONTROL, cache_control),
+            ],
+            Json(DohJson::from(&packet)),
+        )
+            .into_response(),
+        _ => (
+            [
+                (header::CONTENT_TYPE, DNS_MESSAGE.to_string()),
+                (header::CACHE_CONTROL, cache_control),
+            ],
+            response,
+        )
+            .into_response(),
+    }
+}
+
+/// # `freshness`
+///
+/// Seconds the response stays valid: the lowest TTL of its answers, or, for the
+/// negative responses, the negative TTL of the SOA in the authority section (RFC 2308).
+fn freshness(packet: &Packet) -> u32 {
+    if let Some(ttl) = packet.answers.iter().map(Record::get_ttl).min() {
+        return ttl;
+    }
+    packet
+        .authorities
+        .iter()
+        .find_map(|record| match record {
+            Record::SOA { ttl, minimum, .. } => Some((*ttl).min(*minimum)),
+            _ => None,
+        })
+        .unwrap_or(0)
+}
@@ -0,0 +1,170 @@
+use std::{net::IpAddr, sync::Arc};
+
+use crate::{
+    configuration::Settings,
+    outbound::Transport,
+    structs::{
+        header::ResultCode,
+        packet::Packet,
+        questions_and_records::{QueryType, Record},
+    },
+    zones::is_subdomain,
+};
+
+/// TTL of the local answers of the policies that don't force one.
+const LOCAL_TTL: u32 = 300;
+
+/// # `DomainAction`
+///
+/// What happens to the queries for the names a policy covers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DomainAction {
+    /// Resolved as any other name.
+    Resolve,
+    /// Resolved asking these servers, recursively, instead of starting from the root.
+    Forward(Vec<IpAddr>),
+    /// Answered as the blocked names.
+    Block,
+    /// Answered with these addresses.
+    Local(Vec<IpAddr>),
+    /// Answered with these records, owned by the name asked for.
+    Pinned(Vec<Record>),
+}
+
+/// # `DomainPolicy`
+///
+/// Overrides for a domain and the names below it.
+#[derive(Debug, Clone)]
+pub struct DomainPolicy {
+    /// Lowercase and without the trailing dot.
+    pub suffix: String,
+    pub action: DomainAction,
+    /// How the servers of a `Forward` policy are reached, if not as the other servers.
+    pub transport: Option<Arc<dyn Transport>>,
+    /// TTL of every record served, and cached, for the names covered.
+    pub ttl: Option<u32>,
+    /// Whether the cache is bypassed for the names covered.
+    pub no_cache: bool,
+    /// Whether the policy only covers `suffix` itself, not the names below it.
+    pub exact: bool,
+}
+
+impl DomainPolicy {
+    /// # `local_answer`
+    ///
+    /// `query_handler`'s helper, answers the request with the addresses of a `Local` policy
+    /// of the right family or the records of a `Pinned` one of the right type, a CNAME
+    /// answering every type; if none the name exists without records of that type.
+    /// `None` for the other policies.
+    pub fn local_answer(&self, request: &Packet) -> Option<Packet> {
+        let question = request.questions.first()?;
+        let ttl = self.ttl.unwrap_or(LOCAL_TTL);
+        let answers: Vec<Record> = match &self.action {
+            DomainAction::Local(addrs) => addrs
+                .iter()
+                .filter_map(|addr| match (addr, question.qtype) {
+                    (IpAddr::V4(addr), QueryType::A | QueryType::ANY) => Some(Record::A {
+                        domain: question.qname.clone(),
+                        addr: *addr,
+                        ttl,
+                    }),
+                    (IpAddr::V6(addr), QueryType::AAAA | QueryType::ANY) => Some(Record::AAAA {
+                        domain: question.qname.clone(),
+                        addr: *addr,
+                        ttl,
+                    }),
+                    _ => None,
+                })
+                .collect(),
+            DomainAction::Pinned(records) => records
+                .iter()
+                .filter(|record| {
+                    let qtype = record.get_qtype();
+                    matches!(question.qtype, QueryType::ANY)
+                        || qtype == question.qtype
+                        || qtype == QueryType::CNAME
+                })
+                .map(|record| {
+                    let mut record = record.clone();
+                    if let Some(domain) = record.domain_mut() {
+                        *domain = question.qname.clone();
+                    }
+                    if let Some(record_ttl) = record.ttl_mut() {
+                        *record_ttl = ttl;
+                    }
+                    record
+                })
+                .collect(),
+            _ => return None,
+        };
+        let mut response = Packet::new();
+        response.add_info(
+            request.header.id,
+            request.header.recursion_desired,
+            true,
+            true,
+            ResultCode::NOERROR,
+        );
+        // The answers are the configuration's, not another server's
+        response.header.authoritative_answer = true;
+        response.questions = request.questions.clone();
+        response.answers = answers;
+        Some(response)
+    }
+
+    /// # `covers`
+    ///
+    /// Returns true if the policy applies to `qname`, lowercase and without the trailing dot.
+    pub fn covers(&self, qname: &str) -> bool {
+        if self.exact {
+            qname == self.suffix
+        } else {
+            is_subdomain(qname, &self.suffix)
+        }
+    }
+}
+
+/// # `pinned_record`
+///
+/// The record of a `Pinned` policy written as `<type> <data>`, the data as in a zone file
+/// (`CNAME ntp.example`, `MX 10 mail.example`); its owner is the root until it's answered.
+pub fn pinned_record(line: &str) -> Result<Record, String> {
+    let (qtype, data) = line
+        .trim()
+        .split_once(char::is_whitespace)
+        .ok_or_else(|| format!("{} isn't a type followed by the data", line))?;
+    Record::from_presentation(
+        "".into(),
+        qtype.to_ascii_uppercase().parse()?,
+        LOCAL_TTL,
+        data,
+    )
+}
+
+/// # `DomainPolicies`
+///
+/// The per-domain policies of the configuration, the one of the longest suffix
+/// of a name applies to it.
+#[derive(Debug, Default)]
+pub struct DomainPolicies {
+    policies: Vec<DomainPolicy>,
+}
+
+impl DomainPolicies {
+    pub fn from_settings(settings: &Settings) -> Self {
+        DomainPolicies {
+            policies: settings.get_domain_policies(),
+        }
+    }
+
+    /// # `matching`
+    ///
+    /// The policy of the longest suffix of `qname`, if any.
+    pub fn matching(&self, qname: &str) -> Option<&DomainPolicy> {
+        let qname = qname.trim_end_matches('.').to_lowercase();
+        self.policies
+            .iter()
+            .filter(|policy| policy.covers(&qname))
+            .max_by_key(|policy| policy.suffix.len())
+    }
+}
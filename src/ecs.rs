@@ -0,0 +1,252 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use ipnet::IpNet;
+
+use crate::{
+    configuration::Settings,
+    structs::{
+        packet::Packet,
+        questions_and_records::{EdnsOption, Record, ECS_OPTION},
+    },
+    zones::is_subdomain,
+};
+
+/// # `ClientSubnet`
+///
+/// EDNS Client Subnet option (RFC 7871): the network a query is sent on behalf of
+/// and, in the responses, the length of the prefix the answer is valid for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientSubnet {
+    /// Address of the network, the bits past `source_prefix` are zero.
+    pub addr: IpAddr,
+    pub source_prefix: u8,
+    pub scope_prefix: u8,
+}
+
+impl ClientSubnet {
+    /// # `new`
+    ///
+    /// The network of `addr` long `source_prefix` bits, at most the length of the address.
+    pub fn new(addr: IpAddr, source_prefix: u8) -> Self {
+        // The prefix is valid once clamped to the length of the address
+        let network = IpNet::new(addr, source_prefix.min(max_prefix(addr)))
+            .expect("the prefix doesn't exceed the length of the address")
+            .trunc();
+        ClientSubnet {
+            addr: network.addr(),
+            source_prefix: network.prefix_len(),
+            scope_prefix: 0,
+        }
+    }
+
+    /// # `from_option`
+    ///
+    /// Reads the data of the option, `None` if it's malformed, as when the address
+    /// has bits set past the source prefix (RFC 7871 section 7.1.2).
+    pub fn from_option(data: &[u8]) -> Option<Self> {
+        let [family_hi, family_lo, source_prefix, scope_prefix, address @ ..] = data else {
+            return None;
+        };
+        let (source_prefix, scope_prefix) = (*source_prefix, *scope_prefix);
+        // The address carries only the bytes the source prefix covers
+        if address.len() != (source_prefix as usize).div_ceil(8) {
+            return None;
+        }
+        let addr = match u16::from_be_bytes([*family_hi, *family_lo]) {
+            1 if source_prefix <= 32 => {
+                let mut octets = [0u8; 4];
+                octets[..address.len()].copy_from_slice(address);
+                IpAddr::V4(Ipv4Addr::from(octets))
+            }
+            2 if source_prefix <= 128 => {
+                let mut octets = [0u8; 16];
+                octets[..address.len()].copy_from_slice(address);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => return None,
+        };
+        let subnet = ClientSubnet::new(addr, source_prefix);
+        if subnet.addr != addr {
+            return None;
+        }
+        Some(ClientSubnet {
+            scope_prefix,
+            ..subnet
+        })
+    }
+
+    /// # `is_malformed`
+    ///
+    /// Returns true if the OPT record of `packet` carries the option and it's malformed,
+    /// the query is answered with FORMERR (RFC 7871 section 7.1.2).
+    pub fn is_malformed(packet: &Packet) -> bool {
+        packet
+            .get_edns_option(ECS_OPTION)
+            .is_some_and(|option| ClientSubnet::from_option(&option.data).is_none())
+    }
+
+    /// # `from_packet`
+    ///
+    /// The option carried by the OPT record of `packet`, if any.
+    pub fn from_packet(packet: &Packet) -> Option<Self> {
+        let Some(Record::OPT { options, .. }) = packet.get_edns() else {
+            return None;
+        };
+        options
+            .iter()
+            .find(|option| option.code == ECS_OPTION)
+            .and_then(|option| ClientSubnet::from_option(&option.data))
+    }
+
+    /// # `to_option`
+    ///
+    /// Writes the option, the address is cut to the bytes the source prefix covers.
+    pub fn to_option(&self) -> EdnsOption {
+        let (family, octets): (u16, Vec<u8>) = match self.addr {
+            IpAddr::V4(addr) => (1, addr.octets().to_vec()),
+            IpAddr::V6(addr) => (2, addr.octets().to_vec()),
+        };
+        let mut data = family.to_be_bytes().to_vec();
+        data.push(self.source_prefix);
+        data.push(self.scope_prefix);
+        data.extend_from_slice(&octets[..(self.source_prefix as usize).div_ceil(8)]);
+        EdnsOption {
+            code: ECS_OPTION,
+            data,
+        }
+    }
+
+    pub fn with_scope(self, scope_prefix: u8) -> Self {
+        ClientSubnet {
+            scope_prefix,
+            ..self
+        }
+    }
+
+    /// # `scope_network`
+    ///
+    /// The network an answer for this subnet, with the scope `scope_prefix`, may be
+    /// served to; `None` if it may be served to anyone. A scope longer than the source
+    /// prefix is cut to it, we only know the network we sent (RFC 7871 section 7.3.1).
+    pub fn scope_network(&self, scope_prefix: u8) -> Option<IpNet> {
+        let prefix = scope_prefix.min(self.source_prefix);
+        (prefix > 0).then(|| ClientSubnet::new(self.addr, prefix).network())
+    }
+
+    pub fn network(&self) -> IpNet {
+        IpNet::new(self.addr, self.source_prefix)
+            .expect("the prefix doesn't exceed the length of the address")
+    }
+}
+
+/// # `address_key`
+///
+/// `addr` as the cache compares it with the networks the records are served to,
+/// IPv4 addresses mapped to IPv6: the keys of the addresses of a network fall
+/// between the ones of its first and its last address, byte by byte.
+pub fn address_key(addr: IpAddr) -> Vec<u8> {
+    match addr {
+        IpAddr::V4(addr) => addr.to_ipv6_mapped().octets().to_vec(),
+        IpAddr::V6(addr) => addr.octets().to_vec(),
+    }
+}
+
+fn max_prefix(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+/// # `EcsPolicy`
+///
+/// Which subnet, if any, the queries sent to the authoritative name servers carry
+/// on behalf of a client, so the CDNs can pick the answers nearest to it.
+/// The subnet sent by the client is used if it has one, otherwise the network of
+/// its address; both are cut to the configured prefixes, for the privacy of the client.
+/// The private addresses are never sent, they tell nothing about where a client is.
+/// The subnet only reaches the forwarders and the name servers of the zones configured,
+/// the others along the way, as the root servers, don't need it (RFC 7871 section 12.1).
+#[derive(Debug)]
+pub struct EcsPolicy {
+    enabled: bool,
+    ipv4_prefix: u8,
+    ipv6_prefix: u8,
+    zones: Vec<String>,
+}
+
+impl EcsPolicy {
+    pub fn from_settings(settings: &Settings) -> Self {
+        EcsPolicy {
+            enabled: settings.get_ecs_enabled(),
+            ipv4_prefix: settings.get_ecs_ipv4_prefix(),
+            ipv6_prefix: settings.get_ecs_ipv6_prefix(),
+            zones: settings.get_ecs_zones(),
+        }
+    }
+
+    /// # `zones`
+    ///
+    /// Zones whose name servers are sent the subnet, along with the ones below them.
+    pub fn zones(&self) -> &[String] {
+        &self.zones
+    }
+
+    /// # `subnet_for`
+    ///
+    /// The subnet to send for `request`, received from `src`; `None` if the policy
+    /// is disabled or the client asked for its subnet not to be sent, with a source
+    /// prefix of 0 (RFC 7871 section 7.1.2).
+    pub fn subnet_for(&self, request: &Packet, src: IpAddr) -> Option<ClientSubnet> {
+        if !self.enabled {
+            return None;
+        }
+        let (addr, prefix) = match ClientSubnet::from_packet(request) {
+            Some(subnet) if subnet.source_prefix == 0 => return None,
+            Some(subnet) => (subnet.addr, subnet.source_prefix),
+            None => (src, u8::MAX),
+        };
+        if !is_public(addr) {
+            return None;
+        }
+        let limit = match addr {
+            IpAddr::V4(_) => self.ipv4_prefix,
+            IpAddr::V6(_) => self.ipv6_prefix,
+        };
+        let prefix = prefix.min(limit);
+        (prefix > 0).then(|| ClientSubnet::new(addr, prefix))
+    }
+}
+
+/// # `sends_subnet_to`
+///
+/// Returns true if the name servers of `zone` are sent the subnet of the client:
+/// `None` stands for the forwarders, which always are.
+pub fn sends_subnet_to(zones: &[String], zone: Option<&str>) -> bool {
+    zone.is_none_or(|zone| zones.iter().any(|allowed| is_subdomain(zone, allowed)))
+}
+
+/// Returns true if `addr` may be routed on the internet.
+fn is_public(addr: IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(addr) => {
+            let [first, second, ..] = addr.octets();
+            // 100.64.0.0/10 is the shared address space of the carrier-grade NATs
+            !(addr.is_private()
+                || (first == 100 && second & 0xc0 == 64)
+                || addr.is_loopback()
+                || addr.is_link_local()
+                || addr.is_unspecified()
+                || addr.is_broadcast()
+                || addr.is_documentation())
+        }
+        IpAddr::V6(addr) => {
+            let first = addr.segments()[0];
+            // fc00::/7 is unique local, fe80::/10 link local
+            !(addr.is_loopback()
+                || addr.is_unspecified()
+                || first & 0xfe00 == 0xfc00
+                || first & 0xffc0 == 0xfe80)
+        }
+    }
+}
@@ -0,0 +1,39 @@
+use idna::uts46::{AsciiDenyList, DnsLength, Hyphens, Uts46};
+
+use crate::structs::auxiliaries::{CResult, DnsError};
+
+/// The characters that can't be in a name besides the glyphless ones: the names are
+/// letters, digits and hyphens, with the underscores of the service names,
+/// the asterisks of the wildcards and the slashes of the classless reverse zones.
+const DENIED: AsciiDenyList = AsciiDenyList::new(true, "!\"#$%&'()+,:;<=>?@[\\]^`{|}~");
+
+/// # `to_ascii`
+///
+/// The name as it's sent on the wire: the labels that aren't ASCII are mapped as
+/// UTS 46 prescribes and encoded as A-labels (`xn--` followed by their punycode),
+/// the others are lowercased; without the trailing dot.
+/// Refuses the names with characters that can't be in a name, labels that aren't
+/// valid once decoded and the names or labels too long for the wire.
+pub fn to_ascii(name: &str) -> CResult<String> {
+    let name = name.trim_end_matches('.');
+    if name.is_empty() {
+        return Ok(String::new());
+    }
+    Uts46::new()
+        .to_ascii(name.as_bytes(), DENIED, Hyphens::Allow, DnsLength::Verify)
+        .map(|ascii| ascii.into_owned())
+        .map_err(|_| DnsError::InvalidName(format!("{} isn't a valid domain name", name)))
+}
+
+/// # `to_unicode`
+///
+/// The name as it's shown to the people, the A-labels decoded; the name as it is
+/// if it can't be decoded.
+pub fn to_unicode(name: &str) -> String {
+    let (unicode, result) =
+        Uts46::new().to_unicode(name.as_bytes(), AsciiDenyList::EMPTY, Hyphens::Allow);
+    match result {
+        Ok(()) => unicode.into_owned(),
+        Err(_) => name.to_string(),
+    }
+}
@@ -1,37 +1,48 @@
-use std::{io, sync::Arc};
-
-use configuration::Settings;
-use sqlx::SqlitePool;
-use structs::buffer::BytePacketBuffer;
-use tokio::net::UdpSocket;
-use workers::query_handler;
-
+pub mod acl;
+pub mod acme;
+pub mod api;
+pub mod blocklist;
+pub mod bootstrap;
+pub mod cachecheck;
+pub mod cachedump;
+pub mod cachewriter;
+pub mod certificates;
+pub mod cli;
+pub mod clients;
+pub mod clock;
 pub mod configuration;
+pub mod control;
+pub mod dashboard;
+pub mod database;
+pub mod ddr;
+pub mod dhcp;
+pub mod dnssec;
+pub mod doh;
+pub mod domainpolicy;
+pub mod ecs;
+pub mod idn;
+pub mod mdns;
+pub mod notify;
+pub mod outbound;
+pub mod overrides;
+pub mod pidfile;
+pub mod querylog;
+pub mod resolver;
+pub mod safesearch;
+#[cfg(feature = "scripting")]
+pub mod scripting;
+pub mod server;
+pub mod specialuse;
+pub mod stats;
+pub mod storage;
 pub mod structs;
+pub mod systemd;
+pub mod tarpit;
+pub mod tcp;
 pub mod telemetry;
+#[cfg(feature = "test-support")]
+pub mod testing;
+pub mod tsig;
+pub mod udp;
 pub mod workers;
-
-/// # `run`
-///
-/// Core Business.
-pub async fn run(sock: UdpSocket, settings: Settings, db_pool: SqlitePool) -> io::Result<()> {
-    let sock_ref = Arc::new(sock);
-    loop {
-        let mut req_buffer = BytePacketBuffer::new();
-        let (_, src) = match sock_ref.recv_from(&mut req_buffer.buf).await {
-            Ok(r) => r,
-            Err(e) => {
-                tracing::info!("Received a malformed packet: {}", e);
-                continue;
-            }
-        };
-        let s = sock_ref.clone();
-        tokio::spawn(query_handler(
-            s,
-            req_buffer,
-            src,
-            settings.get_root_server_addr(),
-            db_pool.clone(),
-        ));
-    }
-}
+pub mod zones;
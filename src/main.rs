@@ -1,29 +1,382 @@
-use std::error::Error;
+use std::{error::Error, future::Future};
 
+use clap::Parser;
 use dns::{
-    configuration::get_settings,
-    run,
-    telemetry::{get_subscriber, init_subscriber},
+    blocklist::parse_list,
+    bootstrap::BootstrapFile,
+    cli::Cli,
+    configuration::Settings,
+    control::{self, ControlHandler, Reloader},
+    database,
+    pidfile::PidFile,
+    resolver::Resolver,
+    server::Server,
+    storage, systemd,
+    telemetry::{get_subscriber, init_metrics, init_otlp, init_subscriber, otlp_tracer},
+    workers::Policies,
 };
-use sqlx::{sqlite::SqliteConnectOptions, SqlitePool};
-use tokio::net::UdpSocket;
+use opentelemetry_sdk::trace::Tracer;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+use tokio::{net::UdpSocket, signal, sync::mpsc};
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    let sub = get_subscriber("rusty_dns".into(), "info".into(), std::io::stdout);
-    init_subscriber(sub);
+fn main() -> Result<(), Box<dyn Error>> {
+    let cli = Cli::parse();
+    // Reading the configuration first, its errors can still reach the terminal,
+    // printed in full as they list every field to fix
+    let settings = match cli.settings() {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    if cli.check_config {
+        let checked = tokio::runtime::Runtime::new()?.block_on(check_config(&settings));
+        match checked {
+            Ok(()) => println!("The configuration is valid"),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+    // The process can only be forked before the runtime starts its threads
+    if cli.daemon {
+        daemonize()?;
+    }
+    // Written after forking, it holds the id of the process serving
+    let _pid_file = match cli.pid_file.as_deref().map(PidFile::create).transpose() {
+        Ok(pid_file) => pid_file,
+        Err(e) => {
+            eprintln!("Unable to write the pid file: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if cli.service {
+        return run_service(cli, settings);
+    }
+    let activated = systemd::listen_socket()?;
+    tokio::runtime::Runtime::new()?.block_on(serve(cli, settings, activated, shutdown_signal()))
+}
 
-    let settings = get_settings()?;
+/// # `serve`
+///
+/// Serves on `activated`, the socket passed by systemd, if any,
+/// otherwise on the address configured, until `shutdown` completes.
+async fn serve(
+    cli: Cli,
+    settings: Settings,
+    activated: Option<std::net::UdpSocket>,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), Box<dyn Error>> {
+    let tracer_provider = match settings.get_otlp_endpoint() {
+        Some(endpoint) => Some(init_otlp("rusty_dns".into(), &endpoint)?),
+        None => None,
+    };
+    init_logging(&cli, &settings, tracer_provider.as_ref().map(otlp_tracer))?;
+    if let Some(addr) = settings.get_metrics_address() {
+        init_metrics(addr)?;
+        tracing::info!("Serving the metrics on {}", addr);
+    }
 
     // Inititalizing the database
-    let db_option = SqliteConnectOptions::new()
-        .filename(&settings.get_db_path())
-        .create_if_missing(true);
-    let db_pool = SqlitePool::connect_with(db_option).await?;
-    // TODO: integrate configuration
-    sqlx::migrate!().run(&db_pool).await?;
-
-    let sock = UdpSocket::bind(&settings.get_local_server_full_domain()).await?;
-    run(sock, settings, db_pool).await?;
+    let db_pool = database::connect(&settings).await?;
+    database::migrate(&settings, &db_pool).await?;
+
+    let activated = match activated {
+        Some(sock) => {
+            tracing::info!("Serving on the socket passed by systemd");
+            Some(UdpSocket::from_std(sock)?)
+        }
+        None => None,
+    };
+    if let Some(interval) = systemd::watchdog_interval() {
+        tokio::spawn(systemd::watchdog(interval));
+    }
+    // The control socket inspects the cache and the memory of the resolver
+    let storage = storage::open(&settings, db_pool.clone()).await?;
+    let resolver = Resolver::from_settings(&settings, storage.clone()).await?;
+    let (reload_tx, reload_rx) = mpsc::channel(1);
+    let control_socket = settings.get_control_socket();
+    if let Some(path) = control_socket.clone() {
+        let handler = ControlHandler::new(
+            storage.clone(),
+            resolver.infra.clone(),
+            reloader(&cli, reload_tx.clone()),
+        );
+        tokio::spawn(async move {
+            if let Err(e) = control::serve(&path, handler).await {
+                tracing::error!(
+                    "Unable to serve the control socket {}: {}",
+                    path.display(),
+                    e
+                );
+            }
+        });
+    }
+    let mut builder = Server::builder(settings)
+        .with_cache(db_pool.clone())
+        .with_resolver(resolver)
+        .with_reload(reload_rx)
+        .with_reloader(reloader(&cli, reload_tx.clone()));
+    #[cfg(unix)]
+    tokio::spawn(reload_on_sighup(cli, reload_tx));
+    #[cfg(not(unix))]
+    drop(reload_tx);
+    if let Some(sock) = activated {
+        builder = builder.socket(sock);
+    }
+    let server = builder.build().await?.start()?;
+    shutdown.await;
+    server.shutdown().await?;
+    // The server leaves the database it has been given open
+    storage.close().await;
+    db_pool.close().await;
+    if let Some(path) = control_socket {
+        let _ = std::fs::remove_file(path);
+    }
+    // Sending the spans still waiting
+    if let Some(Err(e)) = tracer_provider.map(|provider| provider.shutdown()) {
+        tracing::warn!("Unable to send the last spans: {}", e);
+    }
+    Ok(())
+}
+
+/// # `reloader`
+///
+/// Reads the configuration the way `cli` says and hands it to the server through `reload_tx`.
+fn reloader(cli: &Cli, reload_tx: mpsc::Sender<Settings>) -> Reloader {
+    let cli = cli.clone();
+    Reloader::new(
+        Box::new(move || cli.settings().map_err(|e| e.to_string())),
+        reload_tx,
+    )
+}
+
+/// # `init_logging`
+///
+/// Sends the output to the standard output, or to the event log for a Windows service.
+fn init_logging(
+    cli: &Cli,
+    settings: &Settings,
+    tracer: Option<Tracer>,
+) -> Result<(), Box<dyn Error>> {
+    #[cfg(windows)]
+    if cli.service {
+        let sink = dns::telemetry::EventLogSink::new(SERVICE_NAME)?;
+        init_subscriber(get_subscriber(
+            "rusty_dns".into(),
+            cli.log_level.clone(),
+            sink,
+            tracer,
+            settings.get_log_options(),
+        ));
+        return Ok(());
+    }
+    init_subscriber(get_subscriber(
+        "rusty_dns".into(),
+        cli.log_level.clone(),
+        std::io::stdout,
+        tracer,
+        settings.get_log_options(),
+    ));
+    Ok(())
+}
+
+/// # `check_config`
+///
+/// Loads what the configuration points to as the server would, without serving:
+/// the migrations run on a throwaway database, kept in memory, and the blocklists
+/// and the bootstrap file are read but not stored; the lists to download are left alone.
+async fn check_config(settings: &Settings) -> Result<(), Box<dyn Error>> {
+    // Every connection would open its own database in memory
+    let db_pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect_with(SqliteConnectOptions::new().in_memory(true))
+        .await?;
+    database::migrate(settings, &db_pool)
+        .await
+        .map_err(|e| format!("The database migrations failed: {}", e))?;
+    settings.get_keyring()?;
+    Policies::from_settings(settings, db_pool.clone())?;
+    for path in settings.get_blocklists() {
+        let content = std::fs::read_to_string(&path)
+            .map_err(|e| format!("Unable to read the blocklist {}: {}", path.display(), e))?;
+        println!("{}: {} domains", path.display(), parse_list(&content).len());
+    }
+    if let Some(path) = settings.get_cache_bootstrap() {
+        let records = BootstrapFile::read(path)?.records(settings.get_cache_bootstrap_ttl())?;
+        println!("{}: {} records", path.display(), records.len());
+    }
+    db_pool.close().await;
+    Ok(())
+}
+
+/// # `daemonize`
+///
+/// Detaches the process from the terminal, the working directory and
+/// the standard streams are kept.
+#[cfg(unix)]
+fn daemonize() -> Result<(), Box<dyn Error>> {
+    // SAFETY: no other thread has been started yet
+    if unsafe { libc::daemon(1, 1) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn daemonize() -> Result<(), Box<dyn Error>> {
+    Err("Running in the background is only supported on Unix".into())
+}
+
+/// Name the Windows service is registered with.
+#[cfg(windows)]
+const SERVICE_NAME: &str = "rusty_dns";
+
+/// What `service_main` serves with, it's called by the service control manager
+/// with the arguments of the service instead of the ones of the process.
+#[cfg(windows)]
+static SERVICE_SETTINGS: std::sync::Mutex<Option<(Cli, Settings)>> = std::sync::Mutex::new(None);
+
+#[cfg(windows)]
+windows_service::define_windows_service!(ffi_service_main, service_main);
+
+/// # `run_service`
+///
+/// Hands the process over to the service control manager, which runs
+/// `service_main` on a thread of its own; returns once the service has stopped.
+#[cfg(windows)]
+fn run_service(cli: Cli, settings: Settings) -> Result<(), Box<dyn Error>> {
+    *SERVICE_SETTINGS.lock().unwrap() = Some((cli, settings));
+    windows_service::service_dispatcher::start(SERVICE_NAME, ffi_service_main)?;
     Ok(())
 }
+
+#[cfg(not(windows))]
+fn run_service(_cli: Cli, _settings: Settings) -> Result<(), Box<dyn Error>> {
+    Err("Running as a service is only supported on Windows".into())
+}
+
+/// # `service_main`
+///
+/// Serves until the service control manager asks the service to stop,
+/// or the system shuts down.
+#[cfg(windows)]
+fn service_main(_arguments: Vec<std::ffi::OsString>) {
+    use windows_service::{
+        service::{
+            ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus,
+            ServiceType,
+        },
+        service_control_handler::{self, ServiceControlHandlerResult},
+    };
+
+    let Some((cli, settings)) = SERVICE_SETTINGS.lock().unwrap().take() else {
+        return;
+    };
+    let (stop_tx, mut stop_rx) = mpsc::unbounded_channel();
+    let handler = move |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            let _ = stop_tx.send(());
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    };
+    let Ok(status) = service_control_handler::register(SERVICE_NAME, handler) else {
+        return;
+    };
+    let set_state = |current_state, controls_accepted, exit_code| {
+        status.set_service_status(ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state,
+            controls_accepted,
+            exit_code,
+            checkpoint: 0,
+            wait_hint: std::time::Duration::default(),
+            process_id: None,
+        })
+    };
+    let _ = set_state(
+        ServiceState::Running,
+        ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+        ServiceExitCode::Win32(0),
+    );
+    let shutdown = async move {
+        stop_rx.recv().await;
+    };
+    let served = tokio::runtime::Runtime::new()
+        .map_err(Box::<dyn Error>::from)
+        .and_then(|runtime| runtime.block_on(serve(cli, settings, None, shutdown)));
+    let exit_code = match served {
+        Ok(()) => ServiceExitCode::Win32(0),
+        Err(e) => {
+            tracing::error!("The service failed: {}", e);
+            ServiceExitCode::ServiceSpecific(1)
+        }
+    };
+    let _ = set_state(
+        ServiceState::Stopped,
+        ServiceControlAccept::empty(),
+        exit_code,
+    );
+}
+
+/// # `reload_on_sighup`
+///
+/// Reads the configuration again every time SIGHUP is received and hands it to the server,
+/// a configuration that can't be read is reported and ignored.
+#[cfg(unix)]
+async fn reload_on_sighup(cli: Cli, reload_tx: mpsc::Sender<Settings>) {
+    let mut sighup = match signal::unix::signal(signal::unix::SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => {
+            tracing::error!("Unable to listen for SIGHUP: {}", e);
+            return;
+        }
+    };
+    while sighup.recv().await.is_some() {
+        let settings = match cli.settings() {
+            Ok(s) => s,
+            Err(e) => {
+                tracing::error!("Unable to read the configuration, ignoring SIGHUP: {}", e);
+                continue;
+            }
+        };
+        if reload_tx.send(settings).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// # `shutdown_signal`
+///
+/// Completes when the process is asked to terminate, by Ctrl+C (SIGINT) or SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = signal::ctrl_c().await {
+            tracing::error!("Unable to listen for Ctrl+C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("Unable to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
@@ -0,0 +1,257 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::Arc,
+    time::Duration,
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{net::UdpSocket, time::timeout_at};
+
+use crate::{
+    configuration::Settings,
+    structs::{
+        auxiliaries::CResult,
+        buffer::BytePacketBuffer,
+        header::ResultCode,
+        packet::Packet,
+        questions_and_records::{QueryType, Question, Record},
+    },
+};
+
+/// Group the mDNS queries and responses are sent to (RFC 6762 section 3).
+pub const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+/// Port of the mDNS responders.
+pub const MDNS_PORT: u16 = 5353;
+/// TTL of the records announced, the one recommended for the address records.
+const RECORD_TTL: u32 = 120;
+/// Largest TTL of the answers to legacy unicast queries (RFC 6762 section 6.7).
+const LEGACY_TTL: u32 = 10;
+/// Largest mDNS message, the size of a jumbo frame.
+const MAX_MESSAGE: usize = 9000;
+
+/// # `is_local`
+///
+/// Returns true if `name` belongs to `.local`, the names resolved by multicast.
+pub fn is_local(name: &str) -> bool {
+    let name = name.trim_end_matches('.').as_bytes();
+    name.eq_ignore_ascii_case(b"local")
+        || name
+            .len()
+            .checked_sub(b".local".len())
+            .is_some_and(|start| name[start..].eq_ignore_ascii_case(b".local"))
+}
+
+/// # `Mdns`
+///
+/// Multicast DNS on the local network: announces the records configured to the mDNS
+/// queries and, if bridging, asks the network by multicast about the `.local` names
+/// that the unicast clients query.
+#[derive(Debug)]
+pub struct Mdns {
+    records: Vec<Record>,
+    interface: Ipv4Addr,
+    /// Time waited for the network to answer a bridged query, `None` if not bridging.
+    bridge_timeout: Option<Duration>,
+}
+
+impl Mdns {
+    /// # `from_settings`
+    ///
+    /// `None` if mDNS is disabled.
+    pub fn from_settings(settings: &Settings) -> Option<Self> {
+        if !settings.get_mdns_enabled() {
+            return None;
+        }
+        let records = settings
+            .get_mdns_records()
+            .into_iter()
+            .map(|(name, addr)| match addr {
+                IpAddr::V4(addr) => Record::A {
+                    domain: name.into(),
+                    addr,
+                    ttl: RECORD_TTL,
+                },
+                IpAddr::V6(addr) => Record::AAAA {
+                    domain: name.into(),
+                    addr,
+                    ttl: RECORD_TTL,
+                },
+            })
+            .collect();
+        Some(Mdns {
+            records,
+            interface: settings.get_mdns_interface(),
+            bridge_timeout: settings.get_mdns_bridge_timeout(),
+        })
+    }
+
+    /// # `records_for`
+    ///
+    /// The records configured that answer `question`.
+    pub fn records_for(&self, question: &Question) -> Vec<Record> {
+        let qname = question.qname.trim_end_matches('.');
+        self.records
+            .iter()
+            .filter(|r| r.get_domain().eq_ignore_ascii_case(qname))
+            .filter(|r| question.qtype == QueryType::ANY || r.get_qtype() == question.qtype)
+            .cloned()
+            .collect()
+    }
+
+    /// # `answer`
+    ///
+    /// `query_handler`'s helper, answers a unicast query for a `.local` name with the
+    /// records configured or, if bridging, with the ones the network responds with.
+    /// The `.local` names are never resolved through the global DNS (RFC 6762 section 22).
+    pub async fn answer(&self, request: &Packet) -> Packet {
+        let mut response = Packet::new();
+        response.add_info(
+            request.header.id,
+            request.header.recursion_desired,
+            true,
+            true,
+            ResultCode::NOERROR,
+        );
+        response.questions = request.questions.clone();
+        let Some(question) = request.questions.first() else {
+            response.header.rescode = ResultCode::FORMERR;
+            return response;
+        };
+        let mut answers = self.records_for(question);
+        if answers.is_empty() {
+            if let Some(wait) = self.bridge_timeout {
+                answers = self
+                    .query_network(question, wait)
+                    .await
+                    .unwrap_or_else(|e| {
+                        tracing::warn!("Unable to query {} by multicast: {}", question.qname, e);
+                        Vec::new()
+                    });
+            }
+        }
+        if answers.is_empty() {
+            response.header.rescode = ResultCode::NXDOMAIN;
+        }
+        response.answers = answers;
+        response
+    }
+
+    /// # `query_network`
+    ///
+    /// Sends `question` to the mDNS group as a legacy unicast query, from a port other
+    /// than 5353, so the responders answer us directly; returns the answers of the first
+    /// response that has any, or nothing once `wait` is over.
+    #[tracing::instrument("Querying the network by multicast", skip(self))]
+    async fn query_network(&self, question: &Question, wait: Duration) -> CResult<Vec<Record>> {
+        let sock = UdpSocket::bind(SocketAddrV4::new(self.interface, 0)).await?;
+        sock.set_multicast_ttl_v4(255)?;
+        let mut query = Packet::builder()
+            .question(&question.qname, question.qtype)
+            .build()?;
+        let mut buffer = BytePacketBuffer::new();
+        query.write(&mut buffer)?;
+        sock.send_to(buffer.written(), SocketAddrV4::new(MDNS_GROUP, MDNS_PORT))
+            .await?;
+
+        let deadline = tokio::time::Instant::now() + wait;
+        loop {
+            let mut buffer = BytePacketBuffer::with_size(MAX_MESSAGE);
+            let received = match timeout_at(deadline, sock.recv_from(&mut buffer.buf)).await {
+                Ok(received) => received?,
+                Err(_) => return Ok(Vec::new()),
+            };
+            let Ok(response) = Packet::from_buffer(&mut buffer) else {
+                tracing::debug!("Received a malformed mDNS response from {}", received.1);
+                continue;
+            };
+            let answers: Vec<Record> = response
+                .answers
+                .into_iter()
+                .filter(|r| r.get_domain().eq_ignore_ascii_case(&question.qname))
+                .filter(|r| question.qtype == QueryType::ANY || r.get_qtype() == question.qtype)
+                .collect();
+            if !answers.is_empty() {
+                return Ok(answers);
+            }
+        }
+    }
+}
+
+/// # `serve`
+///
+/// Answers the mDNS queries of the local network asking for the records configured,
+/// sharing the port with the other responders of the host.
+/// The queries sent from port 5353 are answered by multicast, the legacy unicast ones,
+/// sent from any other port, directly to their sender.
+pub async fn serve(mdns: Arc<Mdns>) -> io::Result<()> {
+    let sock = bind_group(mdns.interface)?;
+    tracing::info!("Answering the mDNS queries on {}", mdns.interface);
+    loop {
+        let mut buffer = BytePacketBuffer::with_size(MAX_MESSAGE);
+        let (_, src) = sock.recv_from(&mut buffer.buf).await?;
+        let Ok(query) = Packet::from_buffer(&mut buffer) else {
+            continue;
+        };
+        if query.header.response || query.header.opcode != 0 {
+            continue;
+        }
+        let legacy = src.port() != MDNS_PORT;
+        let mut answers: Vec<Record> = query
+            .questions
+            .iter()
+            .flat_map(|question| mdns.records_for(question))
+            .collect();
+        // Known-answer suppression, the querier already has them (RFC 6762 section 7.1)
+        answers.retain(|answer| !query.answers.contains(answer));
+        if answers.is_empty() {
+            continue;
+        }
+
+        let mut response = Packet::new();
+        response.header.response = true;
+        response.header.authoritative_answer = true;
+        let dst = if legacy {
+            response.header.id = query.header.id;
+            response.questions = query.questions.clone();
+            for answer in &mut answers {
+                cap_ttl(answer, LEGACY_TTL);
+            }
+            src
+        } else {
+            SocketAddr::from((MDNS_GROUP, MDNS_PORT))
+        };
+        response.answers = answers;
+        let mut buffer = BytePacketBuffer::with_size(MAX_MESSAGE);
+        if let Err(e) = response.write(&mut buffer) {
+            tracing::info!("Unable to write the mDNS response for {}: {}", src, e);
+            continue;
+        }
+        if let Err(e) = sock.send_to(buffer.written(), dst).await {
+            tracing::info!("Unable to send the mDNS response to {}: {}", dst, e);
+        }
+    }
+}
+
+/// Binds the mDNS port, along with the other responders, and joins the group on `interface`.
+fn bind_group(interface: Ipv4Addr) -> io::Result<UdpSocket> {
+    let sock = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    sock.set_reuse_address(true)?;
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    sock.set_reuse_port(true)?;
+    sock.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, MDNS_PORT)).into())?;
+    sock.join_multicast_v4(&MDNS_GROUP, &interface)?;
+    sock.set_multicast_ttl_v4(255)?;
+    sock.set_multicast_loop_v4(true)?;
+    if !interface.is_unspecified() {
+        sock.set_multicast_if_v4(&interface)?;
+    }
+    sock.set_nonblocking(true)?;
+    UdpSocket::from_std(sock.into())
+}
+
+fn cap_ttl(record: &mut Record, cap: u32) {
+    if let Record::A { ttl, .. } | Record::AAAA { ttl, .. } = record {
+        *ttl = (*ttl).min(cap);
+    }
+}
@@ -0,0 +1,242 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use tokio::{net::UdpSocket, sync::broadcast, time::timeout};
+
+use crate::{
+    configuration::Settings,
+    structs::{
+        auxiliaries::CResult,
+        buffer::BytePacketBuffer,
+        header::{OpCode, ResultCode},
+        packet::Packet,
+        questions_and_records::{QueryType, Record},
+    },
+    tsig::{self, Keyring, TsigKey},
+};
+
+/// Number of times a NOTIFY is sent before giving up on a secondary.
+const NOTIFY_ATTEMPTS: u32 = 5;
+/// Time waited for the acknowledgement of a single NOTIFY.
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// # `NotifyHandler`
+///
+/// Keeps track of the servers involved in zone change notifications (RFC 1996):
+/// the secondaries that we notify when one of our zones changes and the primaries
+/// that are allowed to notify us.
+/// Accepted notifications are forwarded to the subscribers obtained through
+/// `NotifyHandler::subscribe`, which are expected to refresh the zone.
+/// When a TSIG key is configured the notifications we send are signed with it
+/// and the ones we receive need to be signed with it.
+pub struct NotifyHandler {
+    secondaries: Vec<SocketAddr>,
+    primaries: Vec<IpAddr>,
+    tsig_key: Option<TsigKey>,
+    refresh_tx: broadcast::Sender<String>,
+}
+
+impl NotifyHandler {
+    pub fn new(
+        secondaries: Vec<SocketAddr>,
+        primaries: Vec<IpAddr>,
+        tsig_key: Option<TsigKey>,
+    ) -> Self {
+        let (refresh_tx, _) = broadcast::channel(64);
+        NotifyHandler {
+            secondaries,
+            primaries,
+            tsig_key,
+            refresh_tx,
+        }
+    }
+
+    /// # `from_settings`
+    ///
+    /// Creates the handler from the configuration, fails if the configured
+    /// TSIG key isn't present in `keyring`.
+    pub fn from_settings(settings: &Settings, keyring: &Keyring) -> CResult<Self> {
+        let tsig_key = match settings.get_notify_tsig_key() {
+            Some(name) => Some(
+                keyring
+                    .get(&name)
+                    .ok_or(format!("Unknown TSIG key for NOTIFY: {}", name))?
+                    .clone(),
+            ),
+            None => None,
+        };
+        Ok(Self::new(
+            settings.get_notify_secondaries(),
+            settings.get_notify_primaries(),
+            tsig_key,
+        ))
+    }
+
+    /// # `subscribe`
+    ///
+    /// Returns a receiver that yields the name of every zone
+    /// for which a primary has sent us an accepted NOTIFY.
+    pub fn subscribe(&self) -> broadcast::Receiver<String> {
+        self.refresh_tx.subscribe()
+    }
+
+    /// # `refresh`
+    ///
+    /// Asks the subscribers to refresh `zone`, as if a primary had notified us.
+    pub fn refresh(&self, zone: &str) {
+        // Nobody to refresh the zone if there are no subscribers
+        let _ = self.refresh_tx.send(zone.to_string());
+    }
+
+    /// # `zone_changed`
+    ///
+    /// Notifies every configured secondary that `zone` has changed,
+    /// `soa` is the new SOA record of the zone, if available.
+    /// Notifications are sent in the background.
+    pub fn zone_changed(&self, zone: &str, soa: Option<Record>) {
+        for target in self.secondaries.clone() {
+            let zone = zone.to_string();
+            let soa = soa.clone();
+            let key = self.tsig_key.clone();
+            tokio::spawn(async move {
+                if let Err(e) = send_notify(zone, soa, target, key).await {
+                    tracing::warn!("Failed to notify {}: {}", target, e);
+                }
+            });
+        }
+    }
+
+    /// # `handle_notify`
+    ///
+    /// Composes the response to a NOTIFY received from `src`, if the sender is one of the
+    /// configured primaries the subscribers are informed that the zone needs a refresh.
+    /// `key_name` is the name of the TSIG key that authenticated the message, if any.
+    #[tracing::instrument(
+        name = "Handling a NOTIFY message",
+        skip(self, request, src),
+        fields(
+            address = %src
+        )
+    )]
+    pub fn handle_notify(
+        &self,
+        request: &Packet,
+        src: SocketAddr,
+        key_name: Option<&str>,
+    ) -> Packet {
+        let mut response = Packet::new();
+        response.add_info(request.header.id, false, false, true, ResultCode::NOERROR);
+        response.header.opcode = OpCode::NOTIFY.to_num();
+        response.header.authoritative_answer = true;
+        response.questions = request.questions.clone();
+
+        if !self.primaries.contains(&src.ip()) {
+            tracing::info!("Refused a NOTIFY from {}, not a configured primary", src);
+            response.header.rescode = ResultCode::REFUSED;
+            return response;
+        }
+        if let Some(key) = &self.tsig_key {
+            if key_name != Some(key.name.as_str()) {
+                tracing::info!(
+                    "Refused a NOTIFY from {}, not signed with {}",
+                    src,
+                    key.name
+                );
+                response.header.rescode = ResultCode::NOTAUTH;
+                return response;
+            }
+        }
+
+        let question = match request.questions.first() {
+            Some(q) if q.qtype == QueryType::SOA => q,
+            _ => {
+                tracing::info!("Received a malformed NOTIFY from {}", src);
+                response.header.rescode = ResultCode::FORMERR;
+                return response;
+            }
+        };
+
+        tracing::info!("Received a NOTIFY for the zone {}", question.qname);
+        if self.refresh_tx.send(question.qname.to_string()).is_err() {
+            tracing::info!("No secondary zone is waiting for a NOTIFY, ignoring it");
+        }
+        response
+    }
+}
+
+/// # `send_notify`
+///
+/// Sends a NOTIFY for `zone` to `target` and waits for its acknowledgement,
+/// the message is retransmitted if no acknowledgement arrives in time.
+/// The message is signed with `key`, if provided.
+#[tracing::instrument(
+    name = "Sending a NOTIFY message",
+    skip(soa, key),
+    fields(
+        target = %target
+    )
+)]
+pub async fn send_notify(
+    zone: String,
+    soa: Option<Record>,
+    target: SocketAddr,
+    key: Option<TsigKey>,
+) -> CResult<()> {
+    let socket = match target {
+        SocketAddr::V4(_) => UdpSocket::bind("0.0.0.0:0").await?,
+        SocketAddr::V6(_) => UdpSocket::bind("[::]:0").await?,
+    };
+
+    let id = (uuid::Uuid::new_v4().as_u128() & 0xFFFF) as u16;
+    let mut builder = Packet::builder()
+        .id(id)
+        .opcode(OpCode::NOTIFY)
+        .authoritative(true)
+        .question(zone.as_str(), QueryType::SOA);
+    if let Some(record) = soa {
+        builder = builder.answer(record);
+    }
+    let mut packet = builder.build()?;
+    let mut req_buffer = BytePacketBuffer::new();
+    packet.write(&mut req_buffer)?;
+    let request_mac = match &key {
+        Some(key) => Some(tsig::sign(&mut req_buffer, key, None)?),
+        None => None,
+    };
+
+    for attempt in 1..=NOTIFY_ATTEMPTS {
+        socket.send_to(req_buffer.written(), target).await?;
+
+        let mut res_buffer = BytePacketBuffer::new();
+        match timeout(NOTIFY_TIMEOUT, socket.recv_from(&mut res_buffer.buf)).await {
+            Ok(Ok((len, src))) if src == target => {
+                if let Some(key) = &key {
+                    let keyring = Keyring::new(vec![key.clone()]);
+                    tsig::verify(&res_buffer.buf[0..len], &keyring, request_mac.as_deref())?;
+                }
+                let response = Packet::from_buffer(&mut res_buffer)?;
+                if response.header.id == id
+                    && response.header.response
+                    && OpCode::from_num(response.header.opcode) == OpCode::NOTIFY
+                {
+                    tracing::info!(
+                        "{} acknowledged the NOTIFY for {} with {:?}",
+                        target,
+                        zone,
+                        response.header.rescode
+                    );
+                    return Ok(());
+                }
+            }
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => return Err(e.into()),
+            Err(_) => {
+                tracing::info!("NOTIFY attempt {} to {} timed out", attempt, target);
+            }
+        }
+    }
+
+    Err(format!("{} never acknowledged the NOTIFY for {}", target, zone).into())
+}
@@ -0,0 +1,469 @@
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use bytes::Buf;
+use futures::future::BoxFuture;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+    sync::oneshot,
+};
+
+use crate::{
+    ecs::ClientSubnet,
+    structs::{
+        auxiliaries::{CResult, DnsError},
+        buffer::BytePacketBuffer,
+        packet::Packet,
+        questions_and_records::QueryType,
+    },
+};
+
+mod dnscrypt;
+#[cfg(feature = "test-support")]
+mod mock;
+mod signed;
+mod socks;
+mod stamp;
+mod tcp;
+mod tls;
+
+pub use dnscrypt::DnscryptTransport;
+#[cfg(feature = "test-support")]
+pub use mock::MockTransport;
+pub use signed::SignedTransport;
+pub use socks::Socks5Proxy;
+pub use stamp::{DnsStamp, StampProtocol};
+pub use tcp::TcpTransport;
+pub use tls::TlsTransport;
+
+/// # `Transport`
+///
+/// How the queries reach the other name servers: a question is sent to a server
+/// and its response is returned, whatever the protocol in between.
+pub trait Transport: Debug + Send + Sync {
+    /// # `port`
+    ///
+    /// The port the name servers listen on for this transport.
+    fn port(&self) -> u16 {
+        53
+    }
+
+    /// # `query`
+    ///
+    /// Queries `server` for `qname` and waits for the response, for as long as it takes:
+    /// the caller is expected to bound the wait.
+    /// The query carries `client_subnet`, if any, in an OPT record.
+    fn query<'a>(
+        &'a self,
+        qname: &'a str,
+        qtype: QueryType,
+        server: SocketAddr,
+        client_subnet: Option<&'a ClientSubnet>,
+    ) -> BoxFuture<'a, CResult<Packet>>;
+}
+
+/// # `TransportKind`
+///
+/// The transports the forwarders can be reached through.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportKind {
+    #[default]
+    Udp,
+    Tcp,
+    /// DNS over TLS (RFC 7858).
+    Tls,
+    /// DNSCrypt v2, the servers given by their stamp.
+    Dnscrypt,
+}
+
+/// # `query_packet`
+///
+/// The query for `qname` the transports send, with ID `id`.
+fn query_packet(
+    id: u16,
+    qname: &str,
+    qtype: QueryType,
+    client_subnet: Option<&ClientSubnet>,
+) -> CResult<Packet> {
+    let mut builder = Packet::builder()
+        .id(id)
+        .recursion_desired(true)
+        .question(qname, qtype);
+    if let Some(subnet) = client_subnet {
+        builder = builder.edns_option(subnet.to_option());
+    }
+    builder.build()
+}
+
+/// # `random_id`
+///
+/// An unpredictable query ID, the responses are harder to spoof.
+fn random_id(rng: &SystemRandom) -> CResult<u16> {
+    let mut id = [0u8; 2];
+    rng.fill(&mut id)
+        .map_err(|_| "Unable to generate a query ID")?;
+    Ok(u16::from_be_bytes(id))
+}
+
+/// # `connect`
+///
+/// Opens a connection to `server` for the stream transports, through `proxy` if any.
+async fn connect(server: SocketAddr, proxy: Option<&Socks5Proxy>) -> CResult<TcpStream> {
+    match proxy {
+        Some(proxy) => proxy.connect(server).await,
+        None => Ok(TcpStream::connect(server).await?),
+    }
+}
+
+/// # `exchange`
+///
+/// Writes `packet` on `stream`, prefixed by its length as the stream transports
+/// require (RFC 1035 section 4.2.2), and reads the response to it.
+async fn exchange<S>(stream: &mut S, mut packet: Packet) -> CResult<Packet>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let id = packet.header.id;
+    let mut req_buffer = BytePacketBuffer::empty();
+    packet.write(&mut req_buffer)?;
+    // Length prefix and message leave with a single vectored write
+    let len_prefix = (req_buffer.pos() as u16).to_be_bytes();
+    let mut request = Buf::chain(&len_prefix[..], req_buffer.freeze());
+    stream.write_all_buf(&mut request).await?;
+
+    let len = stream.read_u16().await? as usize;
+    let mut res_buffer = BytePacketBuffer::with_size(len);
+    stream.read_exact(&mut res_buffer.buf).await?;
+    let response = Packet::from_buffer(&mut res_buffer)?;
+    if response.header.id != id {
+        return Err(DnsError::Upstream(
+            "The response doesn't match the query".to_string(),
+        ));
+    }
+    Ok(response)
+}
+
+/// Address the IPv4 sockets of the pool are bound to, on a random port.
+const UNSPECIFIED_V4: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+/// Address the IPv6 sockets of the pool are bound to, on a random port.
+const UNSPECIFIED_V6: SocketAddr = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0);
+
+/// Identifies the response a query is waiting for.
+type PendingKey = (u16, SocketAddr, String, QueryType);
+/// The waiting queries, each one with a token telling it apart from a later query
+/// that happens to have the same key.
+type PendingQueries = Mutex<HashMap<PendingKey, (u64, oneshot::Sender<Packet>)>>;
+
+/// Queries sent through a socket before it's closed and replaced by one bound to
+/// another random port, the port of the queries stays hard to guess (RFC 5452 section 9.2).
+const QUERIES_PER_SOCKET: u32 = 64;
+/// Time a replaced socket keeps receiving the responses to the queries it sent.
+const RETIRED_LINGER: Duration = Duration::from_secs(10);
+
+/// # `PooledSocket`
+///
+/// A socket of the pool along with the number of queries it has sent;
+/// dropping it lets its receiving task know it has been replaced.
+#[derive(Debug)]
+struct PooledSocket {
+    socket: Arc<UdpSocket>,
+    queries: u32,
+    _retire: oneshot::Sender<()>,
+}
+
+impl PooledSocket {
+    /// # `bind`
+    ///
+    /// Binds a socket on a random port of `addr` and starts receiving on it.
+    fn bind(addr: SocketAddr, pending: &Arc<PendingQueries>) -> io::Result<Self> {
+        let socket = std::net::UdpSocket::bind(addr)?;
+        socket.set_nonblocking(true)?;
+        let socket = Arc::new(UdpSocket::from_std(socket)?);
+        let (retire, retired) = oneshot::channel();
+        tokio::spawn(receive(socket.clone(), pending.clone(), retired));
+        Ok(PooledSocket {
+            socket,
+            queries: 0,
+            _retire: retire,
+        })
+    }
+}
+
+/// # `QueryEngine`
+///
+/// The UDP transport, sends the queries for the other name servers through a small pool of sockets
+/// instead of binding a socket per query, and another one for the IPv6 servers.
+/// Every socket is replaced by one on another random port after `QUERIES_PER_SOCKET`
+/// queries, so spoofed responses still have to guess the port along with the ID.
+/// Every socket has a task receiving its responses, which are handed to the query
+/// waiting for them, matched by ID, server and question; responses nobody is waiting
+/// for are discarded.
+#[derive(Debug)]
+pub struct QueryEngine {
+    sockets: Vec<Mutex<PooledSocket>>,
+    /// Empty if IPv6 is disabled or unavailable.
+    sockets_v6: Vec<Mutex<PooledSocket>>,
+    next_socket: AtomicUsize,
+    next_token: AtomicU64,
+    pending: Arc<PendingQueries>,
+    rng: SystemRandom,
+    port: u16,
+}
+
+impl QueryEngine {
+    /// # `bind`
+    ///
+    /// Binds `sockets` sockets, at least one, and starts receiving on them;
+    /// as many for the IPv6 servers if `ipv6`, unless the host lacks IPv6,
+    /// the queries to those servers then fail.
+    pub async fn bind(sockets: usize, ipv6: bool) -> io::Result<Self> {
+        let pending: Arc<PendingQueries> = Arc::new(Mutex::new(HashMap::new()));
+        let mut bound = Vec::new();
+        for _ in 0..sockets.max(1) {
+            bound.push(Mutex::new(PooledSocket::bind(UNSPECIFIED_V4, &pending)?));
+        }
+        let mut bound_v6 = Vec::new();
+        if ipv6 {
+            for _ in 0..sockets.max(1) {
+                match PooledSocket::bind(UNSPECIFIED_V6, &pending) {
+                    Ok(socket) => bound_v6.push(Mutex::new(socket)),
+                    Err(e) => {
+                        tracing::info!(
+                            "Unable to bind an IPv6 socket, querying over IPv4 only: {}",
+                            e
+                        );
+                        bound_v6.clear();
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(QueryEngine {
+            sockets: bound,
+            sockets_v6: bound_v6,
+            next_socket: AtomicUsize::new(0),
+            next_token: AtomicU64::new(0),
+            pending,
+            rng: SystemRandom::new(),
+            port: 53,
+        })
+    }
+
+    /// # `with_port`
+    ///
+    /// The name servers are queried on `port` instead of 53.
+    pub fn with_port(self, port: u16) -> Self {
+        QueryEngine { port, ..self }
+    }
+
+    /// # `query`
+    ///
+    /// Queries `server` for `qname` and waits for the response, for as long as it takes:
+    /// the caller is expected to bound the wait, dropping the future forgets the query.
+    /// The query carries `client_subnet`, if any, in an OPT record.
+    pub async fn query(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        server: SocketAddr,
+        client_subnet: Option<&ClientSubnet>,
+    ) -> CResult<Packet> {
+        let (tx, rx) = oneshot::channel();
+        let (id, registration) = self.register(qname, qtype, server, tx)?;
+
+        let mut packet = query_packet(id, qname, qtype, client_subnet)?;
+        let mut req_buffer = BytePacketBuffer::new();
+        packet.write(&mut req_buffer)?;
+
+        let sockets = if server.is_ipv6() {
+            &self.sockets_v6
+        } else {
+            &self.sockets
+        };
+        if sockets.is_empty() {
+            return Err(DnsError::Upstream(format!(
+                "IPv6 is unavailable, {} can't be queried",
+                server.ip()
+            )));
+        }
+        let index = self.next_socket.fetch_add(1, Ordering::Relaxed) % sockets.len();
+        let socket = self.take_socket(&sockets[index], server);
+        socket.send_to(req_buffer.written(), server).await?;
+
+        let response = rx
+            .await
+            .map_err(|_| DnsError::Upstream("The query has been discarded".to_string()))?;
+        drop(registration);
+        Ok(response)
+    }
+
+    /// # `take_socket`
+    ///
+    /// The socket of `slot` to send a query to `server` through, replaced first if it
+    /// has sent `QUERIES_PER_SOCKET` queries; if the new one can't be bound the old one
+    /// is kept for a while longer.
+    fn take_socket(&self, slot: &Mutex<PooledSocket>, server: SocketAddr) -> Arc<UdpSocket> {
+        let mut slot = slot.lock().unwrap();
+        if slot.queries >= QUERIES_PER_SOCKET {
+            let addr = if server.is_ipv6() {
+                UNSPECIFIED_V6
+            } else {
+                UNSPECIFIED_V4
+            };
+            match PooledSocket::bind(addr, &self.pending) {
+                Ok(socket) => *slot = socket,
+                Err(e) => {
+                    tracing::warn!("Unable to replace a socket of the pool: {}", e);
+                    slot.queries = 0;
+                }
+            }
+        }
+        slot.queries += 1;
+        slot.socket.clone()
+    }
+
+    /// # `register`
+    ///
+    /// Picks a random ID not in use for `server` and the question,
+    /// the query is forgotten when the returned `Registration` is dropped.
+    fn register(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        server: SocketAddr,
+        tx: oneshot::Sender<Packet>,
+    ) -> CResult<(u16, Registration<'_>)> {
+        let token = self.next_token.fetch_add(1, Ordering::Relaxed);
+        let mut pending = self.pending.lock().unwrap();
+        loop {
+            let key = (random_id(&self.rng)?, server, qname.to_lowercase(), qtype);
+            if pending.contains_key(&key) {
+                continue;
+            }
+            pending.insert(key.clone(), (token, tx));
+            return Ok((
+                key.0,
+                Registration {
+                    pending: &self.pending,
+                    key,
+                    token,
+                },
+            ));
+        }
+    }
+}
+
+impl Transport for QueryEngine {
+    fn port(&self) -> u16 {
+        self.port
+    }
+
+    fn query<'a>(
+        &'a self,
+        qname: &'a str,
+        qtype: QueryType,
+        server: SocketAddr,
+        client_subnet: Option<&'a ClientSubnet>,
+    ) -> BoxFuture<'a, CResult<Packet>> {
+        Box::pin(QueryEngine::query(
+            self,
+            qname,
+            qtype,
+            server,
+            client_subnet,
+        ))
+    }
+}
+
+/// # `Registration`
+///
+/// Removes a pending query when the query is over, whether it got its response,
+/// failed or was abandoned.
+struct Registration<'a> {
+    pending: &'a PendingQueries,
+    key: PendingKey,
+    token: u64,
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        let mut pending = self.pending.lock().unwrap();
+        if pending
+            .get(&self.key)
+            .is_some_and(|(token, _)| *token == self.token)
+        {
+            pending.remove(&self.key);
+        }
+    }
+}
+
+/// # `receive`
+///
+/// Hands the responses received on `socket` to the queries waiting for them;
+/// once `retired` fires, because the socket has been replaced, goes on for `RETIRED_LINGER`
+/// and returns, closing the socket.
+async fn receive(
+    socket: Arc<UdpSocket>,
+    pending: Arc<PendingQueries>,
+    retired: oneshot::Receiver<()>,
+) {
+    let linger = async {
+        let _ = retired.await;
+        tokio::time::sleep(RETIRED_LINGER).await;
+    };
+    tokio::pin!(linger);
+    loop {
+        let mut res_buffer = BytePacketBuffer::new();
+        let received = tokio::select! {
+            received = socket.recv_from(&mut res_buffer.buf) => received,
+            _ = &mut linger => return,
+        };
+        let src = match received {
+            Ok((_, src)) => src,
+            Err(e) => {
+                tracing::info!("Unable to receive a response: {}", e);
+                continue;
+            }
+        };
+        let response = match Packet::from_buffer(&mut res_buffer) {
+            Ok(response) => response,
+            Err(e) => {
+                tracing::info!("Received a malformed response from {}: {}", src, e);
+                continue;
+            }
+        };
+        let question = match response.questions.first() {
+            Some(question) => question,
+            None => {
+                tracing::info!("Received a response without question from {}", src);
+                continue;
+            }
+        };
+        let key = (
+            response.header.id,
+            src,
+            question.qname.to_lowercase(),
+            question.qtype,
+        );
+        let waiting = pending.lock().unwrap().remove(&key);
+        match waiting {
+            Some((_, tx)) => {
+                let _ = tx.send(response);
+            }
+            None => tracing::info!("Discarded an unexpected response from {}", src),
+        }
+    }
+}
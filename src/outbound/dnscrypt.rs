@@ -0,0 +1,349 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crypto_box::{aead::Aead, Nonce, PublicKey, SalsaBox, SecretKey};
+use futures::future::BoxFuture;
+use ring::{
+    rand::{SecureRandom, SystemRandom},
+    signature::{UnparsedPublicKey, ED25519},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+};
+
+use crate::{
+    ecs::ClientSubnet,
+    structs::{
+        auxiliaries::{CResult, DnsError},
+        buffer::BytePacketBuffer,
+        packet::Packet,
+        questions_and_records::{QueryType, Record},
+    },
+};
+
+use super::{query_packet, random_id, Transport};
+
+/// Starts the certificates of the resolvers.
+const CERT_MAGIC: &[u8; 4] = b"DNSC";
+/// The only construction supported, X25519-XSalsa20Poly1305.
+const ES_VERSION: u16 = 1;
+/// Starts the responses of the resolvers.
+const RESOLVER_MAGIC: &[u8; 8] = b"r6fnvWj8";
+/// Length of a certificate without extensions.
+const CERT_LEN: usize = 124;
+/// The queries are padded to a multiple of it.
+const PADDING_BLOCK: usize = 64;
+/// Shortest query sent over UDP, so that the responses don't amplify it.
+const MIN_UDP_QUERY: usize = 256;
+/// Largest response read from a datagram.
+const MAX_UDP_RESPONSE: usize = 4096;
+const HALF_NONCE: usize = 12;
+
+/// # `Certificate`
+///
+/// The key a resolver is encrypting with, valid until `valid_until`, seconds
+/// since the epoch.
+#[derive(Debug, Clone)]
+struct Certificate {
+    resolver_key: PublicKey,
+    client_magic: [u8; 8],
+    serial: u32,
+    valid_until: u32,
+}
+
+/// # `DnscryptTransport`
+///
+/// DNSCrypt v2, the queries travel encrypted with X25519-XSalsa20Poly1305 to a resolver
+/// listening on `port`, over UDP and over TCP when the response doesn't fit in a datagram.
+/// The key of the resolver is taken from the certificates it publishes as TXT records
+/// of `provider_name`, signed with the Ed25519 key of the provider; every query
+/// is sent with a key of its own.
+#[derive(Debug)]
+pub struct DnscryptTransport {
+    provider_name: String,
+    provider_key: [u8; 32],
+    port: u16,
+    /// The certificate in use by server, fetched again once expired.
+    certificates: Mutex<HashMap<SocketAddr, Certificate>>,
+    rng: SystemRandom,
+}
+
+impl DnscryptTransport {
+    pub fn new(provider_name: &str, provider_key: [u8; 32], port: u16) -> Self {
+        DnscryptTransport {
+            provider_name: provider_name.to_string(),
+            provider_key,
+            port,
+            certificates: Mutex::new(HashMap::new()),
+            rng: SystemRandom::new(),
+        }
+    }
+
+    /// # `certificate`
+    ///
+    /// The certificate `server` is using, the one cached unless it has expired.
+    async fn certificate(&self, server: SocketAddr) -> CResult<Certificate> {
+        let now = unix_time();
+        if let Some(certificate) = self.certificates.lock().unwrap().get(&server) {
+            if certificate.valid_until > now {
+                return Ok(certificate.clone());
+            }
+        }
+        let certificate = self.fetch_certificate(server, now).await?;
+        self.certificates
+            .lock()
+            .unwrap()
+            .insert(server, certificate.clone());
+        Ok(certificate)
+    }
+
+    /// # `fetch_certificate`
+    ///
+    /// Asks `server` for its certificates, in plain DNS, and picks the most recent one
+    /// among the valid ones signed by the provider.
+    async fn fetch_certificate(&self, server: SocketAddr, now: u32) -> CResult<Certificate> {
+        let id = random_id(&self.rng)?;
+        let mut packet = query_packet(id, &self.provider_name, QueryType::UNKNOWN(16), None)?;
+        let mut req_buffer = BytePacketBuffer::new();
+        packet.write(&mut req_buffer)?;
+        let socket = udp_socket(server).await?;
+        socket.send(req_buffer.written()).await?;
+        let mut res_buffer = BytePacketBuffer::with_size(MAX_UDP_RESPONSE);
+        let len = socket.recv(&mut res_buffer.buf).await?;
+        res_buffer.buf.truncate(len);
+        let response = Packet::from_buffer(&mut res_buffer)?;
+        if response.header.id != id {
+            return Err(dnscrypt_error("the certificates don't answer the query"));
+        }
+
+        response
+            .answers
+            .iter()
+            .filter_map(|record| match record {
+                Record::UNKNOWN { data, .. } => self.parse_certificate(&txt_bytes(data), now),
+                _ => None,
+            })
+            .max_by_key(|certificate| certificate.serial)
+            .ok_or_else(|| dnscrypt_error("no valid certificate has been published"))
+    }
+
+    /// # `parse_certificate`
+    ///
+    /// The certificate in `bytes` if it's signed by the provider, valid at `now`
+    /// and of the construction we support.
+    fn parse_certificate(&self, bytes: &[u8], now: u32) -> Option<Certificate> {
+        if bytes.len() < CERT_LEN || &bytes[..4] != CERT_MAGIC {
+            return None;
+        }
+        if u16::from_be_bytes([bytes[4], bytes[5]]) != ES_VERSION {
+            return None;
+        }
+        let (signature, signed) = (&bytes[8..72], &bytes[72..]);
+        UnparsedPublicKey::new(&ED25519, self.provider_key)
+            .verify(signed, signature)
+            .ok()?;
+        let number = |at: usize| u32::from_be_bytes(signed[at..at + 4].try_into().unwrap());
+        let (valid_from, valid_until) = (number(44), number(48));
+        if now < valid_from || now >= valid_until {
+            return None;
+        }
+        Some(Certificate {
+            resolver_key: PublicKey::from_slice(&signed[..32]).ok()?,
+            client_magic: signed[32..40].try_into().unwrap(),
+            serial: number(40),
+            valid_until,
+        })
+    }
+
+    /// # `encrypt`
+    ///
+    /// The encrypted query sent for `query`, padded to `min_len` at least,
+    /// with the box the response is decrypted with and the nonce it must carry.
+    fn encrypt(
+        &self,
+        certificate: &Certificate,
+        query: &[u8],
+        min_len: usize,
+    ) -> CResult<(Vec<u8>, SalsaBox, [u8; HALF_NONCE])> {
+        let mut secret = [0u8; 32];
+        let mut client_nonce = [0u8; HALF_NONCE];
+        self.rng
+            .fill(&mut secret)
+            .and_then(|_| self.rng.fill(&mut client_nonce))
+            .map_err(|_| "Unable to generate a DNSCrypt key")?;
+        let secret = SecretKey::from_bytes(secret);
+        let salsa_box = SalsaBox::new(&certificate.resolver_key, &secret);
+
+        let mut padded = query.to_vec();
+        padded.push(0x80);
+        let len = padded.len().max(min_len).next_multiple_of(PADDING_BLOCK);
+        padded.resize(len, 0);
+        let mut nonce = [0u8; 24];
+        nonce[..HALF_NONCE].copy_from_slice(&client_nonce);
+        let encrypted = salsa_box
+            .encrypt(Nonce::from_slice(&nonce), padded.as_slice())
+            .map_err(|_| dnscrypt_error("the query couldn't be encrypted"))?;
+
+        let mut message = Vec::with_capacity(52 + encrypted.len());
+        message.extend_from_slice(&certificate.client_magic);
+        message.extend_from_slice(secret.public_key().as_bytes());
+        message.extend_from_slice(&client_nonce);
+        message.extend_from_slice(&encrypted);
+        Ok((message, salsa_box, client_nonce))
+    }
+
+    /// # `exchange`
+    ///
+    /// Sends `query` to `server`, over UDP or over TCP when `tcp`, and returns
+    /// the decrypted response.
+    async fn exchange(
+        &self,
+        server: SocketAddr,
+        certificate: &Certificate,
+        query: &[u8],
+        tcp: bool,
+    ) -> CResult<Packet> {
+        let min_len = if tcp { 0 } else { MIN_UDP_QUERY };
+        let (message, salsa_box, client_nonce) = self.encrypt(certificate, query, min_len)?;
+        let response = if tcp {
+            let mut stream = TcpStream::connect(server).await?;
+            stream.write_u16(message.len() as u16).await?;
+            stream.write_all(&message).await?;
+            let len = stream.read_u16().await? as usize;
+            let mut response = vec![0u8; len];
+            stream.read_exact(&mut response).await?;
+            response
+        } else {
+            let socket = udp_socket(server).await?;
+            socket.send(&message).await?;
+            let mut response = vec![0u8; MAX_UDP_RESPONSE];
+            let len = socket.recv(&mut response).await?;
+            response.truncate(len);
+            response
+        };
+
+        if response.len() < 32 || &response[..8] != RESOLVER_MAGIC {
+            return Err(dnscrypt_error("the response isn't a DNSCrypt response"));
+        }
+        let nonce = &response[8..32];
+        if nonce[..HALF_NONCE] != client_nonce {
+            return Err(dnscrypt_error("the response doesn't match the query"));
+        }
+        let decrypted = salsa_box
+            .decrypt(Nonce::from_slice(nonce), &response[32..])
+            .map_err(|_| dnscrypt_error("the response couldn't be decrypted"))?;
+        let end = decrypted
+            .iter()
+            .rposition(|byte| *byte != 0)
+            .filter(|end| decrypted[*end] == 0x80)
+            .ok_or_else(|| dnscrypt_error("the response isn't padded"))?;
+        Packet::from_buffer(&mut BytePacketBuffer::from_bytes(&decrypted[..end]))
+    }
+}
+
+impl Transport for DnscryptTransport {
+    fn port(&self) -> u16 {
+        self.port
+    }
+
+    fn query<'a>(
+        &'a self,
+        qname: &'a str,
+        qtype: QueryType,
+        server: SocketAddr,
+        client_subnet: Option<&'a ClientSubnet>,
+    ) -> BoxFuture<'a, CResult<Packet>> {
+        Box::pin(async move {
+            let certificate = self.certificate(server).await?;
+            let id = random_id(&self.rng)?;
+            let mut packet = query_packet(id, qname, qtype, client_subnet)?;
+            let mut req_buffer = BytePacketBuffer::empty();
+            packet.write(&mut req_buffer)?;
+            let query = req_buffer.written();
+
+            let mut stale = Stale {
+                transport: self,
+                server,
+                succeeded: false,
+            };
+            let mut response = self.exchange(server, &certificate, query, false).await?;
+            if response.header.truncated_message {
+                response = self.exchange(server, &certificate, query, true).await?;
+            }
+            stale.succeeded = true;
+            if response.header.id != id {
+                return Err(dnscrypt_error("the response doesn't match the query"));
+            }
+            Ok(response)
+        })
+    }
+}
+
+/// # `Stale`
+///
+/// Forgets the certificate of `server` unless the exchange using it succeeded, failed
+/// or abandoned when it timed out: the resolver may have rotated its key before
+/// the certificate expired, the queries encrypted with the old one going unanswered.
+struct Stale<'a> {
+    transport: &'a DnscryptTransport,
+    server: SocketAddr,
+    succeeded: bool,
+}
+
+impl Drop for Stale<'_> {
+    fn drop(&mut self) {
+        if !self.succeeded {
+            self.transport
+                .certificates
+                .lock()
+                .unwrap()
+                .remove(&self.server);
+        }
+    }
+}
+
+/// # `udp_socket`
+///
+/// A socket of the family of `server`, connected to it.
+async fn udp_socket(server: SocketAddr) -> CResult<UdpSocket> {
+    let local = if server.is_ipv6() {
+        "[::]:0"
+    } else {
+        "0.0.0.0:0"
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(server).await?;
+    Ok(socket)
+}
+
+/// # `txt_bytes`
+///
+/// The character strings of the TXT record `data`, joined.
+fn txt_bytes(data: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(data.len());
+    let mut rest = data;
+    while let Some((&len, tail)) = rest.split_first() {
+        let len = (len as usize).min(tail.len());
+        bytes.extend_from_slice(&tail[..len]);
+        rest = &tail[len..];
+    }
+    bytes
+}
+
+fn unix_time() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs() as u32)
+}
+
+/// # `dnscrypt_error`
+///
+/// The error of an exchange with a DNSCrypt resolver that failed for `reason`.
+fn dnscrypt_error(reason: &str) -> DnsError {
+    DnsError::Upstream(format!("DNSCrypt: {}", reason))
+}
@@ -0,0 +1,83 @@
+use std::{collections::HashMap, net::SocketAddr, sync::Mutex};
+
+use futures::future::BoxFuture;
+
+use crate::{
+    ecs::ClientSubnet,
+    structs::{
+        auxiliaries::CResult,
+        header::ResultCode,
+        packet::Packet,
+        questions_and_records::{QueryType, Question, Record},
+    },
+};
+
+use super::Transport;
+
+/// # `MockTransport`
+///
+/// Answers the queries with the records it has been given, without the network,
+/// for the tests of the resolution; the names it knows nothing about are answered
+/// with NXDOMAIN. The queries received are kept, the tests can check them.
+#[derive(Debug, Default)]
+pub struct MockTransport {
+    answers: Mutex<HashMap<(String, QueryType), Vec<Record>>>,
+    queries: Mutex<Vec<(String, QueryType, SocketAddr)>>,
+}
+
+impl MockTransport {
+    pub fn new() -> Self {
+        MockTransport::default()
+    }
+
+    /// # `answer`
+    ///
+    /// The queries for `qname` of type `qtype` are answered with `records`.
+    pub fn answer(&self, qname: &str, qtype: QueryType, records: Vec<Record>) {
+        self.answers
+            .lock()
+            .unwrap()
+            .insert((qname.to_lowercase(), qtype), records);
+    }
+
+    /// # `queries`
+    ///
+    /// The queries received so far, with the server they were sent to.
+    pub fn queries(&self) -> Vec<(String, QueryType, SocketAddr)> {
+        self.queries.lock().unwrap().clone()
+    }
+}
+
+impl Transport for MockTransport {
+    fn query<'a>(
+        &'a self,
+        qname: &'a str,
+        qtype: QueryType,
+        server: SocketAddr,
+        _client_subnet: Option<&'a ClientSubnet>,
+    ) -> BoxFuture<'a, CResult<Packet>> {
+        Box::pin(async move {
+            self.queries
+                .lock()
+                .unwrap()
+                .push((qname.to_string(), qtype, server));
+            let records = self
+                .answers
+                .lock()
+                .unwrap()
+                .get(&(qname.to_lowercase(), qtype))
+                .cloned();
+            let mut response = Packet::new();
+            response.header.response = true;
+            response.header.authoritative_answer = true;
+            response
+                .questions
+                .push(Question::new(qname.to_string(), qtype));
+            match records {
+                Some(records) => response.answers = records,
+                None => response.header.rescode = ResultCode::NXDOMAIN,
+            }
+            Ok(response)
+        })
+    }
+}
@@ -0,0 +1,171 @@
+use std::net::SocketAddr;
+
+use bytes::Buf;
+use futures::future::BoxFuture;
+use ring::rand::SystemRandom;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UdpSocket,
+};
+
+use crate::{
+    ecs::ClientSubnet,
+    structs::{
+        auxiliaries::{CResult, DnsError},
+        buffer::BytePacketBuffer,
+        packet::Packet,
+        questions_and_records::QueryType,
+    },
+    tsig::{self, TsigError, TsigKey, TSIG_TYPE},
+};
+
+use super::{connect, query_packet, random_id, Socks5Proxy, Transport};
+
+/// # `SignedTransport`
+///
+/// Signs the queries with a TSIG key (RFC 8945) and accepts only the responses signed
+/// with the same key, for the forwarders that only answer the peers they share it with,
+/// as a hidden primary. The queries are sent over UDP, again over TCP when the response
+/// is truncated, or always over TCP, through `proxy` if any.
+#[derive(Debug)]
+pub struct SignedTransport {
+    key: TsigKey,
+    tcp: bool,
+    rng: SystemRandom,
+    proxy: Option<Socks5Proxy>,
+}
+
+impl SignedTransport {
+    pub fn new(key: TsigKey) -> Self {
+        SignedTransport {
+            key,
+            tcp: false,
+            rng: SystemRandom::new(),
+            proxy: None,
+        }
+    }
+
+    /// # `over_tcp`
+    ///
+    /// The queries are sent over TCP alone, through `proxy` if any.
+    pub fn over_tcp(self, proxy: Option<Socks5Proxy>) -> Self {
+        SignedTransport {
+            tcp: true,
+            proxy,
+            ..self
+        }
+    }
+
+    /// # `signed_query`
+    ///
+    /// The query for `qname` signed, along with the MAC the response is signed after.
+    fn signed_query(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        client_subnet: Option<&ClientSubnet>,
+    ) -> CResult<(u16, BytePacketBuffer, Vec<u8>)> {
+        let id = random_id(&self.rng)?;
+        let mut packet = query_packet(id, qname, qtype, client_subnet)?;
+        let mut req_buffer = BytePacketBuffer::empty();
+        packet.write(&mut req_buffer)?;
+        let mac = tsig::sign(&mut req_buffer, &self.key, None)?;
+        Ok((id, req_buffer, mac))
+    }
+
+    async fn query_udp(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        server: SocketAddr,
+        client_subnet: Option<&ClientSubnet>,
+    ) -> CResult<Packet> {
+        let (id, req_buffer, mac) = self.signed_query(qname, qtype, client_subnet)?;
+        let local: SocketAddr = if server.is_ipv6() {
+            "[::]:0".parse().unwrap()
+        } else {
+            "0.0.0.0:0".parse().unwrap()
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(server).await?;
+        socket.send(req_buffer.written()).await?;
+        let mut res_buffer = BytePacketBuffer::new();
+        let len = socket.recv(&mut res_buffer.buf).await?;
+        res_buffer.buf.truncate(len);
+        self.verified(res_buffer, id, &mac)
+    }
+
+    async fn query_tcp(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        server: SocketAddr,
+        client_subnet: Option<&ClientSubnet>,
+    ) -> CResult<Packet> {
+        let (id, req_buffer, mac) = self.signed_query(qname, qtype, client_subnet)?;
+        let mut stream = connect(server, self.proxy.as_ref()).await?;
+        // Length prefix and message leave with a single vectored write
+        let len_prefix = (req_buffer.pos() as u16).to_be_bytes();
+        let mut request = Buf::chain(&len_prefix[..], req_buffer.freeze());
+        stream.write_all_buf(&mut request).await?;
+        let len = stream.read_u16().await? as usize;
+        let mut res_buffer = BytePacketBuffer::with_size(len);
+        stream.read_exact(&mut res_buffer.buf).await?;
+        self.verified(res_buffer, id, &mac)
+    }
+
+    /// # `verified`
+    ///
+    /// The response held by `res_buffer`, if it answers the query `id` and it's signed
+    /// with our key after `request_mac`; its TSIG is left out.
+    fn verified(
+        &self,
+        mut res_buffer: BytePacketBuffer,
+        id: u16,
+        request_mac: &[u8],
+    ) -> CResult<Packet> {
+        let response_tsig =
+            tsig::find_tsig(&res_buffer.buf)?.ok_or(DnsError::Tsig(TsigError::FormErr))?;
+        if response_tsig.key_name.trim_end_matches('.') != self.key.name {
+            return Err(DnsError::Tsig(TsigError::BadKey));
+        }
+        tsig::verify_with(
+            &res_buffer.buf,
+            &response_tsig,
+            &self.key,
+            Some(request_mac),
+            &[],
+            false,
+        )?;
+        let mut response = Packet::from_buffer(&mut res_buffer)?;
+        if response.header.id != id {
+            return Err(DnsError::Upstream(
+                "The response doesn't match the query".to_string(),
+            ));
+        }
+        response
+            .resources
+            .retain(|record| record.get_qtype().to_num() != TSIG_TYPE);
+        Ok(response)
+    }
+}
+
+impl Transport for SignedTransport {
+    fn query<'a>(
+        &'a self,
+        qname: &'a str,
+        qtype: QueryType,
+        server: SocketAddr,
+        client_subnet: Option<&'a ClientSubnet>,
+    ) -> BoxFuture<'a, CResult<Packet>> {
+        Box::pin(async move {
+            if !self.tcp {
+                let response = self.query_udp(qname, qtype, server, client_subnet).await?;
+                if !response.header.truncated_message {
+                    return Ok(response);
+                }
+            }
+            self.query_tcp(qname, qtype, server, client_subnet).await
+        })
+    }
+}
@@ -0,0 +1,167 @@
+use std::{fmt, net::SocketAddr, str::FromStr};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::structs::auxiliaries::{CResult, DnsError};
+
+/// Version of the protocol, RFC 1928.
+const VERSION: u8 = 5;
+/// Version of the username/password subnegotiation, RFC 1929.
+const AUTH_VERSION: u8 = 1;
+const NO_AUTH: u8 = 0;
+const USER_PASSWORD: u8 = 2;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+/// # `Socks5Proxy`
+///
+/// A SOCKS5 proxy (RFC 1928) the connections to the name servers are opened through,
+/// e.g. Tor, written `socks5://[user:password@]address:port`; the username and
+/// the password, if any, are sent as RFC 1929 prescribes.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Socks5Proxy {
+    addr: SocketAddr,
+    credentials: Option<(String, String)>,
+}
+
+impl fmt::Debug for Socks5Proxy {
+    // The password stays out of the logs
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Socks5Proxy")
+            .field("addr", &self.addr)
+            .field("user", &self.credentials.as_ref().map(|(user, _)| user))
+            .finish()
+    }
+}
+
+impl FromStr for Socks5Proxy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s
+            .strip_prefix("socks5://")
+            .ok_or("The proxy must be written socks5://address:port")?;
+        let (credentials, addr) = match rest.rsplit_once('@') {
+            Some((credentials, addr)) => {
+                let (user, password) = credentials
+                    .split_once(':')
+                    .ok_or("The credentials of the proxy must be written user:password")?;
+                if user.is_empty() || user.len() > 255 || password.len() > 255 {
+                    return Err(
+                        "The username of the proxy can't be empty, nor longer than 255 bytes, \
+                        nor can the password"
+                            .to_string(),
+                    );
+                }
+                (Some((user.to_string(), password.to_string())), addr)
+            }
+            None => (None, rest),
+        };
+        let addr = addr
+            .trim_end_matches('/')
+            .parse()
+            .map_err(|_| "The proxy must be an IP address and a port".to_string())?;
+        Ok(Socks5Proxy { addr, credentials })
+    }
+}
+
+impl Socks5Proxy {
+    /// # `connect`
+    ///
+    /// Opens a connection to `target` through the proxy, once the proxy has
+    /// connected it the stream carries the bytes of `target`.
+    pub async fn connect(&self, target: SocketAddr) -> CResult<TcpStream> {
+        let mut stream = TcpStream::connect(self.addr).await?;
+        let method = if self.credentials.is_some() {
+            USER_PASSWORD
+        } else {
+            NO_AUTH
+        };
+        stream.write_all(&[VERSION, 1, method]).await?;
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply).await?;
+        match reply {
+            [VERSION, NO_ACCEPTABLE_METHOD] => {
+                return Err(proxy_error("refused to authenticate us".to_string()))
+            }
+            [VERSION, chosen] if chosen == method => {}
+            _ => return Err(proxy_error("answered with an unknown method".to_string())),
+        }
+
+        if let Some((user, password)) = &self.credentials {
+            let mut request = vec![AUTH_VERSION, user.len() as u8];
+            request.extend_from_slice(user.as_bytes());
+            request.push(password.len() as u8);
+            request.extend_from_slice(password.as_bytes());
+            stream.write_all(&request).await?;
+            stream.read_exact(&mut reply).await?;
+            if reply[1] != 0 {
+                return Err(proxy_error("rejected the credentials".to_string()));
+            }
+        }
+
+        let mut request = vec![VERSION, CONNECT, 0];
+        match target {
+            SocketAddr::V4(target) => {
+                request.push(ATYP_IPV4);
+                request.extend_from_slice(&target.ip().octets());
+            }
+            SocketAddr::V6(target) => {
+                request.push(ATYP_IPV6);
+                request.extend_from_slice(&target.ip().octets());
+            }
+        }
+        request.extend_from_slice(&target.port().to_be_bytes());
+        stream.write_all(&request).await?;
+
+        let mut header = [0u8; 4];
+        stream.read_exact(&mut header).await?;
+        if header[1] != 0 {
+            return Err(proxy_error(format!(
+                "couldn't connect to {}: {}",
+                target,
+                reply_message(header[1])
+            )));
+        }
+        // The address the proxy bound, of no use to us
+        let bound = match header[3] {
+            ATYP_IPV4 => 4,
+            ATYP_IPV6 => 16,
+            ATYP_DOMAIN => stream.read_u8().await? as usize,
+            _ => return Err(proxy_error("answered with an unknown address".to_string())),
+        };
+        let mut skipped = vec![0u8; bound + 2];
+        stream.read_exact(&mut skipped).await?;
+        Ok(stream)
+    }
+}
+
+/// # `proxy_error`
+///
+/// The error of a proxy that didn't open the connection, for `reason`.
+fn proxy_error(reason: String) -> DnsError {
+    DnsError::Upstream(format!("The SOCKS5 proxy {}", reason))
+}
+
+/// # `reply_message`
+///
+/// What the reply code of a CONNECT request means, RFC 1928 section 6.
+fn reply_message(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
@@ -0,0 +1,297 @@
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+
+use super::TransportKind;
+
+/// # `StampProtocol`
+///
+/// The protocols a DNS stamp can describe a server for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StampProtocol {
+    /// Plain DNS, over UDP and TCP.
+    Dns,
+    DnsCrypt,
+    /// DNS over HTTPS (RFC 8484).
+    Https,
+    /// DNS over TLS (RFC 7858).
+    Tls,
+    /// DNS over QUIC (RFC 9250).
+    Quic,
+}
+
+impl StampProtocol {
+    fn from_num(num: u8) -> Result<Self, String> {
+        match num {
+            0x00 => Ok(StampProtocol::Dns),
+            0x01 => Ok(StampProtocol::DnsCrypt),
+            0x02 => Ok(StampProtocol::Https),
+            0x03 => Ok(StampProtocol::Tls),
+            0x04 => Ok(StampProtocol::Quic),
+            _ => Err(format!(
+                "The protocol {:#04x} of the stamp isn't known",
+                num
+            )),
+        }
+    }
+
+    /// # `default_port`
+    ///
+    /// The port of the servers whose stamp doesn't carry one.
+    fn default_port(self) -> u16 {
+        match self {
+            StampProtocol::Dns => 53,
+            StampProtocol::DnsCrypt | StampProtocol::Https => 443,
+            StampProtocol::Tls | StampProtocol::Quic => 853,
+        }
+    }
+}
+
+impl fmt::Display for StampProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            StampProtocol::Dns => "plain DNS",
+            StampProtocol::DnsCrypt => "DNSCrypt",
+            StampProtocol::Https => "DNS over HTTPS",
+            StampProtocol::Tls => "DNS over TLS",
+            StampProtocol::Quic => "DNS over QUIC",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// # `DnsStamp`
+///
+/// A server described by a DNS stamp, `sdns://` followed by its protocol, address,
+/// provider name, hashes and path encoded in base64url, as published by the
+/// lists of public resolvers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnsStamp {
+    protocol: StampProtocol,
+    addr: Option<SocketAddr>,
+    provider_name: Option<String>,
+    hashes: Vec<Vec<u8>>,
+    path: Option<String>,
+    public_key: Option<[u8; 32]>,
+}
+
+impl FromStr for DnsStamp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let encoded = s
+            .strip_prefix("sdns://")
+            .ok_or("The stamp must start with sdns://")?;
+        let bytes = URL_SAFE_NO_PAD
+            .decode(encoded.trim_end_matches('='))
+            .map_err(|e| format!("The stamp isn't valid base64url: {}", e))?;
+        let mut reader = Reader { bytes: &bytes };
+
+        let protocol = StampProtocol::from_num(reader.byte()?)?;
+        // The properties (DNSSEC, no logs, no filters) are claims of the provider
+        reader.take(8)?;
+        let addr = parse_addr(&reader.string()?, protocol.default_port())?;
+        let mut public_key = None;
+        let (provider_name, hashes, path) = match protocol {
+            StampProtocol::Dns => (None, Vec::new(), None),
+            StampProtocol::DnsCrypt => {
+                public_key = Some(
+                    reader
+                        .prefixed()?
+                        .try_into()
+                        .map_err(|_| "The public key of the stamp isn't 32 bytes long")?,
+                );
+                (Some(reader.string()?), Vec::new(), None)
+            }
+            StampProtocol::Https => {
+                let hashes = reader.hashes()?;
+                let host = reader.string()?;
+                (Some(host), hashes, Some(reader.string()?))
+            }
+            StampProtocol::Tls | StampProtocol::Quic => {
+                let hashes = reader.hashes()?;
+                (Some(reader.string()?), hashes, None)
+            }
+        };
+        // The bootstrap resolvers that may follow are of no use to us
+
+        Ok(DnsStamp {
+            protocol,
+            addr,
+            provider_name,
+            hashes,
+            path,
+            public_key,
+        })
+    }
+}
+
+impl DnsStamp {
+    pub fn protocol(&self) -> StampProtocol {
+        self.protocol
+    }
+
+    /// # `addr`
+    ///
+    /// The address of the server, missing if the stamp expects the provider name
+    /// to be resolved.
+    pub fn addr(&self) -> Option<SocketAddr> {
+        self.addr
+    }
+
+    /// # `provider_name`
+    ///
+    /// The name of the provider, for TLS the name its certificate is valid for
+    /// followed by the port, if any.
+    pub fn provider_name(&self) -> Option<&str> {
+        self.provider_name.as_deref()
+    }
+
+    /// # `hashes`
+    ///
+    /// The SHA256 digests of the certificates of the chain the server presents.
+    pub fn hashes(&self) -> &[Vec<u8>] {
+        &self.hashes
+    }
+
+    /// # `path`
+    ///
+    /// The path of the DNS over HTTPS endpoint.
+    pub fn path(&self) -> Option<&str> {
+        self.path.as_deref()
+    }
+
+    /// # `public_key`
+    ///
+    /// The Ed25519 key the DNSCrypt provider signs the certificates of its resolvers with.
+    pub fn public_key(&self) -> Option<[u8; 32]> {
+        self.public_key
+    }
+
+    /// # `forwarder`
+    ///
+    /// The address, the transport and the name of the certificate a forwarder
+    /// is reached with, the provider name for DNSCrypt; fails for the servers our
+    /// transports can't reach: the ones of other protocols, DNS over HTTPS among them,
+    /// and, but for DNSCrypt, the ones on a port of their own.
+    pub fn forwarder(&self) -> Result<(IpAddr, TransportKind, Option<String>), String> {
+        let (kind, tls_name) = match self.protocol {
+            StampProtocol::Dns => (TransportKind::Udp, None),
+            StampProtocol::Tls => {
+                let name = self.provider_name.as_deref().unwrap_or_default();
+                let (name, port) = match name.rsplit_once(':') {
+                    Some((name, port)) => (name, port.parse().ok()),
+                    None => (name, Some(self.protocol.default_port())),
+                };
+                if port != Some(self.protocol.default_port()) {
+                    return Err(format!(
+                        "The stamp reaches {} on another port than {}, the only one supported",
+                        name,
+                        self.protocol.default_port()
+                    ));
+                }
+                (TransportKind::Tls, Some(name.to_string()))
+            }
+            StampProtocol::DnsCrypt => (TransportKind::Dnscrypt, self.provider_name.clone()),
+            protocol => {
+                return Err(format!(
+                    "The stamp is of a {} server, only plain DNS, DNS over TLS and DNSCrypt \
+                    are supported",
+                    protocol
+                ))
+            }
+        };
+        match self.addr {
+            Some(addr)
+                if addr.port() == self.protocol.default_port()
+                    || kind == TransportKind::Dnscrypt =>
+            {
+                Ok((addr.ip(), kind, tls_name))
+            }
+            Some(addr) => Err(format!(
+                "The stamp names the port {} of {}, only {} is supported",
+                addr.port(),
+                addr.ip(),
+                self.protocol.default_port()
+            )),
+            None => Err("The stamp must carry the address of the server".to_string()),
+        }
+    }
+}
+
+/// # `Reader`
+///
+/// Reads the fields of a decoded stamp in order.
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], String> {
+        if self.bytes.len() < len {
+            return Err("The stamp ends too early".to_string());
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    /// # `prefixed`
+    ///
+    /// A field preceded by its length.
+    fn prefixed(&mut self) -> Result<&[u8], String> {
+        let len = self.byte()? as usize;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        String::from_utf8(self.prefixed()?.to_vec())
+            .map_err(|_| "The stamp carries a field that isn't UTF-8".to_string())
+    }
+
+    /// # `hashes`
+    ///
+    /// A set of fields preceded by their lengths, the high bit of a length
+    /// telling that another field follows.
+    fn hashes(&mut self) -> Result<Vec<Vec<u8>>, String> {
+        let mut hashes = Vec::new();
+        loop {
+            let len = self.byte()?;
+            let hash = self.take((len & 0x7f) as usize)?;
+            if !hash.is_empty() {
+                hashes.push(hash.to_vec());
+            }
+            if len & 0x80 == 0 {
+                return Ok(hashes);
+            }
+        }
+    }
+}
+
+/// # `parse_addr`
+///
+/// The address of a stamp, `ip`, `ip:port`, `[ipv6]` or `[ipv6]:port`,
+/// on `default_port` when the port is missing.
+fn parse_addr(addr: &str, default_port: u16) -> Result<Option<SocketAddr>, String> {
+    if addr.is_empty() {
+        return Ok(None);
+    }
+    let invalid = || format!("The stamp carries an invalid address, {}", addr);
+    if let Ok(addr) = addr.parse::<SocketAddr>() {
+        return Ok(Some(addr));
+    }
+    let ip = addr
+        .strip_prefix('[')
+        .and_then(|ip| ip.strip_suffix(']'))
+        .unwrap_or(addr);
+    let ip: IpAddr = ip.parse().map_err(|_| invalid())?;
+    Ok(Some(SocketAddr::new(ip, default_port)))
+}
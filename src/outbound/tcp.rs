@@ -0,0 +1,63 @@
+use std::net::SocketAddr;
+
+use futures::future::BoxFuture;
+use ring::rand::SystemRandom;
+
+use crate::{
+    ecs::ClientSubnet,
+    structs::{auxiliaries::CResult, packet::Packet, questions_and_records::QueryType},
+};
+
+use super::{connect, exchange, query_packet, random_id, Socks5Proxy, Transport};
+
+/// # `TcpTransport`
+///
+/// Sends every query on a connection of its own, for the servers that can't
+/// be reached over UDP or whose responses don't fit in a datagram,
+/// through `proxy` if any.
+#[derive(Debug)]
+pub struct TcpTransport {
+    rng: SystemRandom,
+    proxy: Option<Socks5Proxy>,
+}
+
+impl TcpTransport {
+    pub fn new() -> Self {
+        TcpTransport {
+            rng: SystemRandom::new(),
+            proxy: None,
+        }
+    }
+
+    /// # `with_proxy`
+    ///
+    /// The connections are opened through `proxy`.
+    pub fn with_proxy(self, proxy: Socks5Proxy) -> Self {
+        TcpTransport {
+            proxy: Some(proxy),
+            ..self
+        }
+    }
+}
+
+impl Default for TcpTransport {
+    fn default() -> Self {
+        TcpTransport::new()
+    }
+}
+
+impl Transport for TcpTransport {
+    fn query<'a>(
+        &'a self,
+        qname: &'a str,
+        qtype: QueryType,
+        server: SocketAddr,
+        client_subnet: Option<&'a ClientSubnet>,
+    ) -> BoxFuture<'a, CResult<Packet>> {
+        Box::pin(async move {
+            let packet = query_packet(random_id(&self.rng)?, qname, qtype, client_subnet)?;
+            let mut stream = connect(server, self.proxy.as_ref()).await?;
+            exchange(&mut stream, packet).await
+        })
+    }
+}
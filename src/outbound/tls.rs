@@ -0,0 +1,88 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use futures::future::BoxFuture;
+use ring::rand::SystemRandom;
+use tokio_rustls::{
+    rustls::{pki_types::ServerName, ClientConfig, RootCertStore},
+    TlsConnector,
+};
+
+use crate::{
+    ecs::ClientSubnet,
+    structs::{
+        auxiliaries::{CResult, DnsError},
+        packet::Packet,
+        questions_and_records::QueryType,
+    },
+};
+
+use super::{connect, exchange, query_packet, random_id, Socks5Proxy, Transport};
+
+/// # `TlsTransport`
+///
+/// DNS over TLS (RFC 7858), the queries travel encrypted to a forwarder
+/// whose certificate is valid for `server_name`, every query on a connection of its own,
+/// through `proxy` if any.
+#[derive(Debug)]
+pub struct TlsTransport {
+    config: Arc<ClientConfig>,
+    server_name: ServerName<'static>,
+    rng: SystemRandom,
+    proxy: Option<Socks5Proxy>,
+}
+
+impl TlsTransport {
+    /// # `new`
+    ///
+    /// The certificates are verified against the Mozilla root certificates,
+    /// fails if `server_name` isn't a valid name.
+    pub fn new(server_name: &str) -> CResult<Self> {
+        let server_name = ServerName::try_from(server_name.to_string())
+            .map_err(|e| DnsError::Config(format!("Invalid TLS name {}: {}", server_name, e)))?;
+        let roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(TlsTransport {
+            config: Arc::new(config),
+            server_name,
+            rng: SystemRandom::new(),
+            proxy: None,
+        })
+    }
+
+    /// # `with_proxy`
+    ///
+    /// The connections are opened through `proxy`.
+    pub fn with_proxy(self, proxy: Socks5Proxy) -> Self {
+        TlsTransport {
+            proxy: Some(proxy),
+            ..self
+        }
+    }
+}
+
+impl Transport for TlsTransport {
+    fn port(&self) -> u16 {
+        853
+    }
+
+    fn query<'a>(
+        &'a self,
+        qname: &'a str,
+        qtype: QueryType,
+        server: SocketAddr,
+        client_subnet: Option<&'a ClientSubnet>,
+    ) -> BoxFuture<'a, CResult<Packet>> {
+        Box::pin(async move {
+            let packet = query_packet(random_id(&self.rng)?, qname, qtype, client_subnet)?;
+            let stream = connect(server, self.proxy.as_ref()).await?;
+            let mut stream = TlsConnector::from(self.config.clone())
+                .connect(self.server_name.clone(), stream)
+                .await?;
+            exchange(&mut stream, packet).await
+        })
+    }
+}
@@ -0,0 +1,273 @@
+use std::{collections::HashMap, net::IpAddr, sync::RwLock};
+
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+use crate::structs::{
+    auxiliaries::{CResult, DnsError},
+    header::ResultCode,
+    packet::Packet,
+    questions_and_records::{QueryType, Record},
+};
+
+/// # `RuleAction`
+///
+/// What a managed rule does to the queries for its domain and its subdomains.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleAction {
+    /// The queries are blocked, as if the domain were in a blocklist.
+    Block,
+    /// The queries are never blocked, by the blocklists or by a rule of a parent domain.
+    Allow,
+}
+
+impl RuleAction {
+    fn as_str(self) -> &'static str {
+        match self {
+            RuleAction::Block => "block",
+            RuleAction::Allow => "allow",
+        }
+    }
+}
+
+/// # `ManagedRule`
+///
+/// A block or allow rule added at runtime.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ManagedRule {
+    pub domain: String,
+    pub action: RuleAction,
+}
+
+/// # `LocalRecord`
+///
+/// An address answered for a name, added at runtime.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct LocalRecord {
+    pub name: String,
+    pub address: IpAddr,
+    pub ttl: u32,
+}
+
+/// # `Overrides`
+///
+/// The block and allow rules and the local records added at runtime, through the API.
+/// They are kept in the database, surviving the restarts and the reloads of
+/// the configuration, and in memory, where the queries look them up.
+#[derive(Debug)]
+pub struct Overrides {
+    db_pool: SqlitePool,
+    rules: RwLock<HashMap<String, RuleAction>>,
+    records: RwLock<HashMap<String, Vec<LocalRecord>>>,
+}
+
+impl Overrides {
+    /// # `load`
+    ///
+    /// Reads the rules and the records stored in `db_pool`.
+    pub async fn load(db_pool: SqlitePool) -> CResult<Self> {
+        let rules: Vec<(String, String)> =
+            sqlx::query_as("SELECT domain, action FROM managed_rules")
+                .fetch_all(&db_pool)
+                .await?;
+        let rules = rules
+            .into_iter()
+            .map(|(domain, action)| {
+                let action = match action.as_str() {
+                    "allow" => RuleAction::Allow,
+                    _ => RuleAction::Block,
+                };
+                (domain, action)
+            })
+            .collect();
+        let rows: Vec<(String, String, i64)> =
+            sqlx::query_as("SELECT name, address, ttl FROM local_records")
+                .fetch_all(&db_pool)
+                .await?;
+        let mut records: HashMap<String, Vec<LocalRecord>> = HashMap::new();
+        for (name, address, ttl) in rows {
+            let Ok(address) = address.parse() else {
+                tracing::warn!("Skipping the local record of {}: {}", name, address);
+                continue;
+            };
+            records.entry(name.clone()).or_default().push(LocalRecord {
+                name,
+                address,
+                ttl: ttl as u32,
+            });
+        }
+        Ok(Overrides {
+            db_pool,
+            rules: RwLock::new(rules),
+            records: RwLock::new(records),
+        })
+    }
+
+    /// # `rule_for`
+    ///
+    /// The action of the rule of the longest suffix of `qname`, if any.
+    pub fn rule_for(&self, qname: &str) -> Option<RuleAction> {
+        let qname = qname.trim_end_matches('.').to_lowercase();
+        let rules = self.rules.read().unwrap();
+        let mut suffix = qname.as_str();
+        loop {
+            if let Some(action) = rules.get(suffix) {
+                return Some(*action);
+            }
+            suffix = suffix.split_once('.')?.1;
+        }
+    }
+
+    /// # `rules`
+    ///
+    /// Every rule, sorted by domain.
+    pub fn rules(&self) -> Vec<ManagedRule> {
+        let mut rules: Vec<ManagedRule> = self
+            .rules
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(domain, action)| ManagedRule {
+                domain: domain.clone(),
+                action: *action,
+            })
+            .collect();
+        rules.sort_by(|a, b| a.domain.cmp(&b.domain));
+        rules
+    }
+
+    /// # `set_rule`
+    ///
+    /// Adds the rule, replacing the one of the same domain.
+    pub async fn set_rule(&self, rule: ManagedRule) -> CResult<()> {
+        let domain = normalize(&rule.domain)?;
+        sqlx::query(
+            r#"INSERT INTO managed_rules (domain, action) VALUES ($1, $2)
+            ON CONFLICT (domain) DO UPDATE SET action = excluded.action"#,
+        )
+        .bind(&domain)
+        .bind(rule.action.as_str())
+        .execute(&self.db_pool)
+        .await?;
+        self.rules.write().unwrap().insert(domain, rule.action);
+        Ok(())
+    }
+
+    /// # `remove_rule`
+    ///
+    /// Removes the rule of `domain`, returns false if there was none.
+    pub async fn remove_rule(&self, domain: &str) -> CResult<bool> {
+        let domain = normalize(domain)?;
+        sqlx::query("DELETE FROM managed_rules WHERE domain = $1")
+            .bind(&domain)
+            .execute(&self.db_pool)
+            .await?;
+        Ok(self.rules.write().unwrap().remove(&domain).is_some())
+    }
+
+    /// # `records`
+    ///
+    /// Every local record, sorted by name.
+    pub fn records(&self) -> Vec<LocalRecord> {
+        let mut records: Vec<LocalRecord> = self
+            .records
+            .read()
+            .unwrap()
+            .values()
+            .flatten()
+            .cloned()
+            .collect();
+        records.sort_by(|a, b| a.name.cmp(&b.name));
+        records
+    }
+
+    /// # `add_record`
+    ///
+    /// Adds the record, replacing the TTL of the same address for the same name.
+    pub async fn add_record(&self, mut record: LocalRecord) -> CResult<()> {
+        record.name = normalize(&record.name)?;
+        sqlx::query(
+            r#"INSERT INTO local_records (name, address, ttl) VALUES ($1, $2, $3)
+            ON CONFLICT (name, address) DO UPDATE SET ttl = excluded.ttl"#,
+        )
+        .bind(&record.name)
+        .bind(record.address.to_string())
+        .bind(record.ttl as i64)
+        .execute(&self.db_pool)
+        .await?;
+        let mut records = self.records.write().unwrap();
+        let records = records.entry(record.name.clone()).or_default();
+        records.retain(|r| r.address != record.address);
+        records.push(record);
+        Ok(())
+    }
+
+    /// # `remove_records`
+    ///
+    /// Removes the records of `name`, returns how many there were.
+    pub async fn remove_records(&self, name: &str) -> CResult<usize> {
+        let name = normalize(name)?;
+        sqlx::query("DELETE FROM local_records WHERE name = $1")
+            .bind(&name)
+            .execute(&self.db_pool)
+            .await?;
+        Ok(self
+            .records
+            .write()
+            .unwrap()
+            .remove(&name)
+            .map_or(0, |records| records.len()))
+    }
+
+    /// # `answer`
+    ///
+    /// Answers the request with the local records of the name asked for
+    /// of the right family, if none the name exists without records of that type;
+    /// `None` if the name has no local record.
+    pub fn answer(&self, request: &Packet) -> Option<Packet> {
+        let question = request.questions.first()?;
+        let qname = question.qname.trim_end_matches('.').to_lowercase();
+        let records = self.records.read().unwrap();
+        let records = records.get(&qname)?;
+        let mut response = Packet::new();
+        response.add_info(
+            request.header.id,
+            request.header.recursion_desired,
+            true,
+            true,
+            ResultCode::NOERROR,
+        );
+        response.header.authoritative_answer = true;
+        response.questions = request.questions.clone();
+        for record in records {
+            match (record.address, question.qtype) {
+                (IpAddr::V4(addr), QueryType::A | QueryType::ANY) => {
+                    response.answers.push(Record::A {
+                        domain: question.qname.clone(),
+                        addr,
+                        ttl: record.ttl,
+                    })
+                }
+                (IpAddr::V6(addr), QueryType::AAAA | QueryType::ANY) => {
+                    response.answers.push(Record::AAAA {
+                        domain: question.qname.clone(),
+                        addr,
+                        ttl: record.ttl,
+                    })
+                }
+                _ => {}
+            }
+        }
+        Some(response)
+    }
+}
+
+/// The name in its ASCII form, lowercase and without the trailing dot.
+fn normalize(name: &str) -> CResult<String> {
+    let name = crate::idn::to_ascii(name)?.to_lowercase();
+    if name.is_empty() {
+        return Err(DnsError::InvalidName("The name is empty".into()));
+    }
+    Ok(name)
+}
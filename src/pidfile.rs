@@ -0,0 +1,73 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// # `PidFile`
+///
+/// File holding the process id of the server, written when it's created
+/// and removed when it's dropped.
+/// A file left behind by a server that is no longer running is replaced,
+/// the server refuses to start if the process it names is still running.
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    pub fn create(path: &Path) -> io::Result<PidFile> {
+        if let Some(pid) = fs::read_to_string(path)
+            .ok()
+            .and_then(|content| content.trim().parse::<u32>().ok())
+        {
+            if pid != std::process::id() && is_running(pid) {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!(
+                        "{} names the process {}, which is still running",
+                        path.display(),
+                        pid
+                    ),
+                ));
+            }
+        }
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, format!("{}\n", std::process::id()))?;
+        Ok(PidFile {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            tracing::warn!(
+                "Unable to remove the pid file {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // SAFETY: the signal 0 only checks whether the process exists
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return true;
+    }
+    // The process exists but belongs to somebody else
+    io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// The process can't be checked, the file is assumed to be left behind.
+#[cfg(not(unix))]
+fn is_running(_pid: u32) -> bool {
+    false
+}
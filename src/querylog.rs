@@ -0,0 +1,228 @@
+use std::{
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use chrono::{DateTime, Local, NaiveDate};
+use serde::{Deserialize, Serialize};
+use sqlx::{QueryBuilder, Sqlite, SqlitePool};
+use tokio::{fs, io::AsyncWriteExt, sync::mpsc, task::JoinHandle};
+
+use crate::{configuration::Settings, structs::auxiliaries::CResult};
+
+/// Entries waiting to be written, the ones exceeding it are dropped.
+const QUEUE: usize = 4096;
+/// Largest number of entries written at once.
+const BATCH: usize = 256;
+/// How often the entries older than the retention are removed.
+const PURGE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// # `QueryLogTarget`
+///
+/// Where the query log is written.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum QueryLogTarget {
+    /// The `query_log` table of the database.
+    #[default]
+    Sqlite,
+    /// A file of JSON lines per day.
+    File,
+}
+
+/// # `QueryLogEntry`
+///
+/// A query answered.
+#[derive(Debug, Clone, Serialize)]
+pub struct QueryLogEntry {
+    pub timestamp: DateTime<Local>,
+    pub client: IpAddr,
+    /// Name of the client in the client table, if it has one.
+    pub client_name: Option<String>,
+    pub qname: String,
+    pub qtype: String,
+    pub rcode: String,
+    pub answers: usize,
+    pub latency_ms: f64,
+    pub cache_hit: bool,
+}
+
+/// # `QueryLog`
+///
+/// Log of the queries answered, separate from the tracing output.
+/// The entries are queued and written in batches by a task, so logging never
+/// slows a query down: when the task falls behind the new entries are dropped.
+#[derive(Debug)]
+pub struct QueryLog {
+    queue: Mutex<Option<mpsc::Sender<QueryLogEntry>>>,
+}
+
+impl QueryLog {
+    /// # `from_settings`
+    ///
+    /// Starts the task writing the log, returned along with the log;
+    /// `None` if the query log is disabled.
+    pub fn from_settings(
+        settings: &Settings,
+        db_pool: SqlitePool,
+    ) -> Option<(QueryLog, JoinHandle<()>)> {
+        let sink = match settings.get_query_log_target()? {
+            QueryLogTarget::Sqlite => Sink::Database(db_pool),
+            QueryLogTarget::File => Sink::Files {
+                directory: settings.get_query_log_directory(),
+                current: None,
+            },
+        };
+        let (tx, rx) = mpsc::channel(QUEUE);
+        let writer = tokio::spawn(write_entries(
+            rx,
+            sink,
+            settings.get_query_log_retention_days(),
+        ));
+        let log = QueryLog {
+            queue: Mutex::new(Some(tx)),
+        };
+        Some((log, writer))
+    }
+
+    /// # `log`
+    ///
+    /// Queues `entry`, unless the queue is full or the log has been closed.
+    pub fn log(&self, entry: QueryLogEntry) {
+        if let Some(queue) = self.queue.lock().unwrap().as_ref() {
+            if queue.try_send(entry).is_err() {
+                tracing::debug!("The query log is falling behind, dropped an entry");
+            }
+        }
+    }
+
+    /// # `close`
+    ///
+    /// Stops accepting entries, the task writes the ones queued and completes.
+    pub fn close(&self) {
+        self.queue.lock().unwrap().take();
+    }
+}
+
+/// Where the writing task puts the entries.
+enum Sink {
+    Database(SqlitePool),
+    /// The file of the day being written is kept open.
+    Files {
+        directory: PathBuf,
+        current: Option<(NaiveDate, fs::File)>,
+    },
+}
+
+/// # `write_entries`
+///
+/// `QueryLog`'s task, writes the entries as they are queued and removes
+/// the ones older than `retention_days` every `PURGE_INTERVAL`.
+async fn write_entries(mut rx: mpsc::Receiver<QueryLogEntry>, mut sink: Sink, retention_days: u32) {
+    let mut purge = tokio::time::interval(PURGE_INTERVAL);
+    let mut batch = Vec::with_capacity(BATCH);
+    loop {
+        tokio::select! {
+            received = rx.recv_many(&mut batch, BATCH) => {
+                if received == 0 {
+                    break;
+                }
+                if let Err(e) = sink.write(&batch).await {
+                    tracing::warn!("Unable to write {} entries of the query log: {}", batch.len(), e);
+                }
+                batch.clear();
+            }
+            _ = purge.tick() => {
+                let cutoff = Local::now() - chrono::Duration::days(retention_days as i64);
+                if let Err(e) = sink.purge(cutoff).await {
+                    tracing::warn!("Unable to remove the old entries of the query log: {}", e);
+                }
+            }
+        }
+    }
+}
+
+impl Sink {
+    async fn write(&mut self, batch: &[QueryLogEntry]) -> CResult<()> {
+        match self {
+            Sink::Database(db_pool) => {
+                let mut query: QueryBuilder<Sqlite> = QueryBuilder::new(
+                    "INSERT INTO query_log (timestamp, client, client_name, qname, qtype, rcode, answers, latency_ms, cache_hit) ",
+                );
+                query.push_values(batch, |mut row, entry| {
+                    row.push_bind(entry.timestamp)
+                        .push_bind(entry.client.to_string())
+                        .push_bind(&entry.client_name)
+                        .push_bind(&entry.qname)
+                        .push_bind(&entry.qtype)
+                        .push_bind(&entry.rcode)
+                        .push_bind(entry.answers as i64)
+                        .push_bind(entry.latency_ms)
+                        .push_bind(entry.cache_hit);
+                });
+                query.build().execute(&*db_pool).await?;
+            }
+            Sink::Files { directory, current } => {
+                for entry in batch {
+                    let date = entry.timestamp.date_naive();
+                    let file = match current {
+                        Some((day, file)) if *day == date => file,
+                        _ => {
+                            fs::create_dir_all(&*directory).await?;
+                            let file = fs::OpenOptions::new()
+                                .create(true)
+                                .append(true)
+                                .open(file_path(directory, date))
+                                .await?;
+                            &mut current.insert((date, file)).1
+                        }
+                    };
+                    let mut line = serde_json::to_vec(entry)
+                        .map_err(|e| format!("Unable to serialize an entry: {}", e))?;
+                    line.push(b'\n');
+                    file.write_all(&line).await?;
+                }
+                if let Some((_, file)) = current {
+                    file.flush().await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn purge(&self, cutoff: DateTime<Local>) -> CResult<()> {
+        match self {
+            Sink::Database(db_pool) => {
+                sqlx::query(r#"DELETE FROM query_log WHERE (timestamp < $1)"#)
+                    .bind(cutoff)
+                    .execute(db_pool)
+                    .await?;
+            }
+            Sink::Files { directory, .. } => {
+                let mut files = match fs::read_dir(directory).await {
+                    Ok(files) => files,
+                    // Nothing has been logged yet
+                    Err(_) => return Ok(()),
+                };
+                while let Some(file) = files.next_entry().await? {
+                    let name = file.file_name();
+                    let date = name
+                        .to_str()
+                        .and_then(|n| n.strip_prefix("queries-")?.strip_suffix(".log"))
+                        .and_then(|d| NaiveDate::parse_from_str(d, "%Y-%m-%d").ok());
+                    if date.is_some_and(|d| d < cutoff.date_naive()) {
+                        fs::remove_file(file.path()).await?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The file holding the entries of `date`.
+fn file_path(directory: &Path, date: NaiveDate) -> PathBuf {
+    directory.join(format!("queries-{}.log", date.format("%Y-%m-%d")))
+}
@@ -0,0 +1,927 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use futures::future::join_all;
+use ipnet::IpNet;
+use tokio::time::timeout;
+
+use crate::{
+    cachewriter::CacheWriter,
+    clock::{Clock, SystemClock},
+    configuration::Settings,
+    ecs::{sends_subnet_to, ClientSubnet},
+    outbound::{QueryEngine, TcpTransport, Transport},
+    storage::Storage,
+    structs::{
+        auxiliaries::{CResult, DnsError},
+        db_queries::CachedRecord,
+        header::ResultCode,
+        packet::Packet,
+        questions_and_records::{QueryType, Record},
+    },
+    telemetry::{RESOLUTION_DURATION, RESOLUTION_ROUND_TRIPS},
+    workers::{InfraCache, TtlBounds, Upstream, UpstreamHealth, UpstreamPolicy, UpstreamTrace},
+    zones::is_subdomain,
+};
+
+/// Name servers lacking glue resolved at the same time.
+const MAX_PARALLEL_NS: usize = 3;
+
+/// Longest chain of name servers lacking glue that needed each other's resolution.
+const MAX_GLUELESS_DEPTH: usize = 4;
+
+/// # `Resolver`
+///
+/// The iterative resolver the server answers its clients with, usable on its own
+/// by the programs that need to resolve names without running the server.
+/// The answers are cached in `storage`, if the cache is enabled, which needs
+/// the migrations to have been run.
+#[derive(Debug)]
+pub struct Resolver {
+    pub root_addr: Ipv4Addr,
+    pub storage: Arc<dyn Storage>,
+    pub cache_enabled: bool,
+    /// Writes the answers in `storage`, in the background.
+    pub cache_writer: CacheWriter,
+    pub policy: UpstreamPolicy,
+    pub health: UpstreamHealth,
+    /// Shared with the ones inspecting it, as the control socket.
+    pub infra: Arc<InfraCache>,
+    /// Sends the queries to the name servers, over UDP unless replaced.
+    pub transport: Arc<dyn Transport>,
+    /// Sends the queries to the name servers that truncate their responses.
+    pub tcp_transport: Arc<dyn Transport>,
+    /// Tells when the cached records expire, the time of the system unless replaced.
+    pub clock: Arc<dyn Clock>,
+    pub ttl_bounds: TtlBounds,
+}
+
+impl Resolver {
+    /// # `from_settings`
+    ///
+    /// Fails if the sockets the queries are sent through can't be bound.
+    pub async fn from_settings(settings: &Settings, storage: Arc<dyn Storage>) -> io::Result<Self> {
+        Ok(Resolver {
+            root_addr: settings.get_root_server_addr(),
+            cache_writer: CacheWriter::spawn(storage.clone()),
+            storage,
+            cache_enabled: settings.get_cache_enabled(),
+            policy: UpstreamPolicy::from_settings(settings),
+            health: UpstreamHealth::from_settings(settings),
+            infra: Arc::new(InfraCache::new()),
+            transport: Arc::new(
+                QueryEngine::bind(
+                    settings.get_upstream_sockets(),
+                    settings.get_upstream_ipv6(),
+                )
+                .await?
+                .with_port(settings.get_root_server_port()),
+            ),
+            tcp_transport: Arc::new(TcpTransport::new()),
+            ttl_bounds: TtlBounds::from_settings(settings),
+            clock: Arc::new(SystemClock),
+        })
+    }
+
+    /// # `upstream`
+    ///
+    /// Returns the view of the resolver used to resolve a query,
+    /// the queries sent to other name servers are noted in `trace`.
+    pub fn upstream<'a>(&'a self, trace: &'a UpstreamTrace) -> Upstream<'a> {
+        Upstream {
+            policy: &self.policy,
+            health: &self.health,
+            infra: &self.infra,
+            transport: self.transport.as_ref(),
+            tcp_transport: self.tcp_transport.as_ref(),
+            clock: self.clock.as_ref(),
+            use_cache: self.cache_enabled,
+            cache_writer: &self.cache_writer,
+            trace,
+            client_subnet: None,
+            ecs_zones: &[],
+            ttl_bounds: self.ttl_bounds,
+            forwarders: &[],
+            forward_transport: None,
+            deadline: None,
+        }
+    }
+
+    /// # `with_transport`
+    ///
+    /// Replaces the transport the queries are sent with, e.g. with a `MockTransport`
+    /// resolving without the network.
+    pub fn with_transport(self, transport: Arc<dyn Transport>) -> Self {
+        Resolver { transport, ..self }
+    }
+
+    /// # `with_clock`
+    ///
+    /// Replaces the clock the expiration of the cached records is measured against,
+    /// e.g. with a `ManualClock` to test it.
+    pub fn with_clock(self, clock: Arc<dyn Clock>) -> Self {
+        Resolver { clock, ..self }
+    }
+
+    /// # `lookup`
+    ///
+    /// Resolves the records of type `qtype` of `qname`, starting from the root,
+    /// within the deadline of a query.
+    /// The response is returned whatever its result code, NXDOMAIN included.
+    pub async fn lookup(&self, qname: &str, qtype: QueryType) -> CResult<Packet> {
+        let trace = UpstreamTrace::new();
+        let qname = qname.trim_end_matches('.');
+        timeout(
+            self.policy.query_deadline,
+            inquiring(
+                qname,
+                qtype,
+                self.root_addr,
+                self.storage.as_ref(),
+                Upstream {
+                    deadline: Some(Instant::now() + self.policy.query_deadline),
+                    ..self.upstream(&trace)
+                },
+            ),
+        )
+        .await
+        .map_err(|_| DnsError::UpstreamTimeout)?
+    }
+
+    /// # `lookup_a`
+    ///
+    /// The IPv4 addresses of `qname`, the ones of the names it's an alias of included.
+    /// Fails if the name doesn't exist or its resolution fails.
+    pub async fn lookup_a(&self, qname: &str) -> CResult<Vec<Ipv4Addr>> {
+        let response = self.lookup(qname, QueryType::A).await?;
+        Ok(addresses(response)?
+            .into_iter()
+            .filter_map(|addr| match addr {
+                IpAddr::V4(addr) => Some(addr),
+                IpAddr::V6(_) => None,
+            })
+            .collect())
+    }
+
+    /// # `lookup_ip`
+    ///
+    /// The IPv4 and IPv6 addresses of `qname`, resolved at the same time.
+    /// Fails if the name doesn't exist or both resolutions fail.
+    pub async fn lookup_ip(&self, qname: &str) -> CResult<Vec<IpAddr>> {
+        let (v4, v6) = tokio::join!(
+            self.lookup(qname, QueryType::A),
+            self.lookup(qname, QueryType::AAAA)
+        );
+        let (v4, v6) = (v4.and_then(addresses), v6.and_then(addresses));
+        match (v4, v6) {
+            (Err(e), Err(_)) => Err(e),
+            (v4, v6) => Ok(v4
+                .unwrap_or_default()
+                .into_iter()
+                .chain(v6.unwrap_or_default())
+                .collect()),
+        }
+    }
+}
+
+/// # `addresses`
+///
+/// `Resolver`'s helper, the addresses in the `Answer section` of `response`,
+/// fails unless it's a NOERROR one.
+fn addresses(response: Packet) -> CResult<Vec<IpAddr>> {
+    if response.header.rescode != ResultCode::NOERROR {
+        return Err(DnsError::Upstream(format!(
+            "The name servers answered {:?}",
+            response.header.rescode
+        )));
+    }
+    Ok(response
+        .answers
+        .iter()
+        .filter_map(|record| match record {
+            Record::A { addr, .. } => Some(IpAddr::V4(*addr)),
+            Record::AAAA { addr, .. } => Some(IpAddr::V6(*addr)),
+            _ => None,
+        })
+        .collect())
+}
+
+/// # `lookup`
+///
+/// Queries the server provided for the name provided through `transport`,
+/// on behalf of `client_subnet` if any, returns the packet if everything went well.
+#[tracing::instrument(
+    "Inquiring an extername name server",
+    skip(transport, qname, qtype, server, client_subnet),
+    fields(
+        domain_name = qname,
+        server_ip = %server.ip(),
+        server_port = server.port()
+    )
+)]
+pub async fn lookup(
+    transport: &dyn Transport,
+    qname: &str,
+    qtype: QueryType,
+    server: SocketAddr,
+    client_subnet: Option<&ClientSubnet>,
+) -> CResult<Packet> {
+    transport.query(qname, qtype, server, client_subnet).await
+}
+
+/// # `lookup_with_retry`
+///
+/// Performs `lookup` against `servers`, the attempts that fail or don't receive a response
+/// within the attempt timeout of the policy are retried against the next server, after
+/// a pause that doubles at every attempt.
+/// A server answering SERVFAIL or REFUSED isn't queried again, the other servers
+/// are tried right away, the failure is propagated once none of them is left.
+/// The servers considered dead by the circuit breaker are skipped, the others are
+/// queried in the order the infra cache ranks them, and it learns from their responses:
+/// a truncated response is asked again over TCP, a FORMERR to a query carrying
+/// an OPT record again without it.
+/// If the servers have been delegated `zone`, the ones that turn out to be lame for it
+/// are treated as the ones answering REFUSED, `is_lame`.
+/// An IPv6 address is raced against an IPv4 one, given the head start of the policy
+/// (happy eyeballs, RFC 8305); if the policy hedges the queries, every other attempt
+/// queries the following server as well, `hedged`.
+pub async fn lookup_with_retry(
+    qname: &str,
+    qtype: QueryType,
+    servers: &[IpAddr],
+    zone: Option<&str>,
+    upstream: Upstream<'_>,
+) -> CResult<Packet> {
+    let (policy, health, infra) = (upstream.policy, upstream.health, upstream.infra);
+    let mut candidates: Vec<IpAddr> = servers
+        .iter()
+        .copied()
+        .filter(|server| health.is_available(*server))
+        .collect();
+    if candidates.is_empty() && !servers.is_empty() {
+        return Err(DnsError::Upstream(
+            "Every server of the delegation is unavailable".to_string(),
+        ));
+    }
+    infra.rank(&mut candidates, zone);
+    let mut backoff = policy.initial_backoff;
+    let mut last_error = String::from("No server to query");
+    let mut attempt = 0;
+    let mut next = 0;
+    while attempt < policy.attempts && !candidates.is_empty() {
+        // No time is left for another attempt, the client is answered with what we have
+        if upstream.remaining(policy.attempt_timeout).is_zero() {
+            last_error = String::from("The deadline of the query has passed");
+            break;
+        }
+        let index = next % candidates.len();
+        let following = candidates[(index + 1) % candidates.len()];
+        let partner = match candidates[index] {
+            IpAddr::V6(_) => candidates
+                .iter()
+                .copied()
+                .find(IpAddr::is_ipv4)
+                .map(|v4| (v4, policy.ipv6_head_start)),
+            IpAddr::V4(_) => None,
+        }
+        .or(policy
+            .hedge_delay
+            .filter(|_| candidates.len() > 1)
+            .map(|delay| (following, delay)));
+        let (sent, queried) = match partner {
+            Some((partner, delay)) => {
+                let sent = hedged(
+                    qname,
+                    qtype,
+                    (candidates[index], partner),
+                    delay,
+                    zone,
+                    upstream,
+                )
+                .await;
+                // the following server needn't be tried again right away
+                (sent, if partner == following { 2 } else { 1 })
+            }
+            None => (
+                Attempt::send(qname, qtype, candidates[index], zone, upstream).await,
+                1,
+            ),
+        };
+        let server = sent.server;
+        // the response may come from the second server of a hedged attempt
+        let index = candidates
+            .iter()
+            .position(|candidate| *candidate == server)
+            .unwrap_or(index);
+        match sent.response {
+            Ok(response) => {
+                if sent.edns && response.header.rescode == ResultCode::FORMERR {
+                    tracing::info!("{} doesn't understand EDNS, asking again", server);
+                    next = index;
+                    continue;
+                }
+                if response.header.truncated_message && !sent.tcp {
+                    tracing::info!("{} truncated the response, asking again over TCP", server);
+                    next = index;
+                    continue;
+                }
+                if zone.is_some_and(|zone| is_lame(&response, qname, zone)) {
+                    last_error = format!("{} is lame", server);
+                    candidates.remove(index);
+                    next = index;
+                    continue;
+                }
+                if let ResultCode::SERVFAIL | ResultCode::REFUSED = response.header.rescode {
+                    tracing::info!(
+                        "{} answered {:?} for {}, trying the other servers",
+                        server,
+                        response.header.rescode,
+                        qname
+                    );
+                    last_error = format!("{} answered {:?}", server, response.header.rescode);
+                    candidates.remove(index);
+                    // the following server took the place of the removed one
+                    next = index;
+                    continue;
+                }
+                return Ok(response);
+            }
+            Err(e) => last_error = e,
+        }
+        attempt += 1;
+        next = index + queried;
+        tracing::info!("Attempt {} for {} failed: {}", attempt, qname, last_error);
+        if attempt < policy.attempts {
+            tokio::time::sleep(upstream.remaining(backoff)).await;
+            backoff *= 2;
+        }
+    }
+    Err(DnsError::Upstream(format!(
+        "Every attempt failed, last error: {}",
+        last_error
+    )))
+}
+
+/// # `Attempt`
+///
+/// `lookup_with_retry`'s helper, a query sent to a name server, how it was sent
+/// and its response, or why there's none.
+struct Attempt {
+    server: IpAddr,
+    /// Whether the query went over TCP.
+    tcp: bool,
+    /// Whether the query carried an OPT record.
+    edns: bool,
+    response: Result<Packet, String>,
+}
+
+impl Attempt {
+    /// # `send`
+    ///
+    /// Queries `server` within the attempt timeout, over TCP if it truncated a response
+    /// and without the OPT record if it doesn't understand EDNS.
+    /// What the response tells of the server, or the lack of it, is noted in the trace,
+    /// the circuit breaker and the infra cache, whether it's lame for `zone` included.
+    async fn send(
+        qname: &str,
+        qtype: QueryType,
+        server: IpAddr,
+        zone: Option<&str>,
+        upstream: Upstream<'_>,
+    ) -> Attempt {
+        let (policy, health, infra) = (upstream.policy, upstream.health, upstream.infra);
+        let info = infra.server(server);
+        let transport = if info.tcp_required {
+            upstream.tcp_transport
+        } else {
+            upstream.transport
+        };
+        // The servers that don't understand EDNS are spared the OPT record,
+        // the subnet only reaches the servers of the zones configured
+        let client_subnet = upstream
+            .client_subnet
+            .filter(|_| info.edns != Some(false) && sends_subnet_to(upstream.ecs_zones, zone));
+        // The attempt can't outlast the client query
+        let wait = upstream.remaining(policy.attempt_timeout);
+        if wait.is_zero() {
+            return Attempt {
+                server,
+                tcp: info.tcp_required,
+                edns: client_subnet.is_some(),
+                response: Err(String::from("The deadline of the query has passed")),
+            };
+        }
+        let sent = Instant::now();
+        let result = timeout(
+            wait,
+            lookup(
+                transport,
+                qname,
+                qtype,
+                (server, upstream.transport.port()).into(),
+                client_subnet.as_ref(),
+            ),
+        )
+        .await;
+        upstream
+            .trace
+            .record(server, sent.elapsed(), matches!(result, Ok(Ok(_))));
+        let response = match result {
+            Ok(Ok(response)) => {
+                // the server is alive, even if it can't help us
+                health.record_success(server);
+                infra.record_rtt(server, sent.elapsed());
+                if client_subnet.is_some() {
+                    let edns = response.header.rescode != ResultCode::FORMERR
+                        && response.get_edns().is_some();
+                    infra.set_edns(server, edns);
+                }
+                if response.header.truncated_message && !info.tcp_required {
+                    infra.set_tcp_required(server);
+                }
+                if let Some(zone) = zone.filter(|zone| is_lame(&response, qname, zone)) {
+                    infra.mark_lame(server, zone);
+                }
+                Ok(response)
+            }
+            Ok(Err(e)) => {
+                health.record_failure(server);
+                infra.record_timeout(server);
+                Err(e.to_string())
+            }
+            Err(_) => {
+                // A server cut short by the deadline of the query isn't to blame
+                if wait == policy.attempt_timeout {
+                    health.record_failure(server);
+                    infra.record_timeout(server);
+                }
+                Err(format!("{} didn't respond within {:?}", server, wait))
+            }
+        };
+        Attempt {
+            server,
+            tcp: info.tcp_required,
+            edns: client_subnet.is_some(),
+            response,
+        }
+    }
+
+    /// # `is_valid`
+    ///
+    /// Returns true if the response ends the attempts of `lookup_with_retry`,
+    /// whether it answers the query or tells it can't be answered.
+    fn is_valid(&self, qname: &str, zone: Option<&str>) -> bool {
+        let Ok(response) = &self.response else {
+            return false;
+        };
+        // asked again without the OPT record or over TCP
+        let rescode = response.header.rescode;
+        if (self.edns && rescode == ResultCode::FORMERR)
+            || (response.header.truncated_message && !self.tcp)
+        {
+            return false;
+        }
+        !zone.is_some_and(|zone| is_lame(response, qname, zone))
+            && !matches!(rescode, ResultCode::SERVFAIL | ResultCode::REFUSED)
+    }
+}
+
+/// # `hedged`
+///
+/// `lookup_with_retry`'s helper, queries the first of `servers` and, if it hasn't given
+/// a valid response within `delay`, the second one too: the first valid response wins,
+/// the other query is abandoned.
+/// If neither is valid, the attempt of the first server is returned, unless it
+/// got no response and the second one did.
+/// A first server beaten by the second one took at least as long as the second one did
+/// to answer, which is noted as its round trip time.
+async fn hedged(
+    qname: &str,
+    qtype: QueryType,
+    (primary, secondary): (IpAddr, IpAddr),
+    delay: Duration,
+    zone: Option<&str>,
+    upstream: Upstream<'_>,
+) -> Attempt {
+    let started = Instant::now();
+    let first = Attempt::send(qname, qtype, primary, zone, upstream);
+    let second = async {
+        tokio::time::sleep(delay).await;
+        Attempt::send(qname, qtype, secondary, zone, upstream).await
+    };
+    tokio::pin!(first, second);
+    let (first, second) = tokio::select! {
+        first = &mut first => {
+            if first.is_valid(qname, zone) {
+                return first;
+            }
+            (first, second.await)
+        }
+        second = &mut second => {
+            if second.is_valid(qname, zone) {
+                upstream.infra.record_rtt(primary, started.elapsed());
+                return second;
+            }
+            (first.await, second)
+        }
+    };
+    if second.is_valid(qname, zone) || (first.response.is_err() && second.response.is_ok()) {
+        second
+    } else {
+        first
+    }
+}
+
+/// # `is_lame`
+///
+/// `lookup_with_retry`'s helper, returns true if `response` comes from a server that
+/// has been delegated `zone` but doesn't serve it: it refused the query, answered
+/// without authority or referred us to a zone that isn't below `zone`, which
+/// would send the resolution back where it came from.
+fn is_lame(response: &Packet, qname: &str, zone: &str) -> bool {
+    if response.header.rescode == ResultCode::REFUSED {
+        return true;
+    }
+    if !response.answers.is_empty() {
+        return false;
+    }
+    match response.get_referral_zone(qname) {
+        Some(referral) => {
+            let referral = referral.trim_end_matches('.');
+            !is_subdomain(referral, zone) || referral.eq_ignore_ascii_case(zone)
+        }
+        None => {
+            !response.header.authoritative_answer
+                && matches!(
+                    response.header.rescode,
+                    ResultCode::NOERROR | ResultCode::NXDOMAIN
+                )
+        }
+    }
+}
+
+/// # `handling_record`, `inquiring`'s helper function
+///
+/// This function parses a record extracted from the database and check if it is valid.
+/// If its valid:
+///     - creates the response and returns it
+/// else:
+///     - deletes the record from the database, returns `None`
+/// Handles tarcing.
+/// TODO: testing
+pub async fn handling_record(
+    record: &CachedRecord,
+    storage: &dyn Storage,
+    clock: &dyn Clock,
+) -> Option<Packet> {
+    if record.is_valid(clock) {
+        // record is not expired
+        tracing::info!("Found valid record for {} in the cache.", record.domain,);
+
+        let mut response = Packet::new();
+        match response.add_cr_to_answers(&record) {
+            Ok(_) => {
+                return Some(response);
+            }
+            Err(e) => {
+                // If this variant is found it means we have incorrect
+                // data in our chache
+                tracing::error!("Incorrect data has been found in the cache database, it's necessary a debug, the server is still capable of responding to requests from the clients without the cache, but the cache is unreliable, wrong data may be served with this configuration, consider disabling the cache database with `-c` flag. Error:\n{}", e);
+                return None;
+            }
+        };
+    } else {
+        match record.delete_from_db(storage).await {
+            Ok(_) => {
+                tracing::info!(
+                    "Deleted cached entry for \"{}\" from the database",
+                    record.domain
+                );
+            }
+            Err(e) => {
+                tracing::error!("I was unable to cancel an entry from the database, the application needs to be shutdown, error:\n{}", e);
+            }
+        };
+        return None;
+    }
+}
+
+/// # `inquiring`
+///
+/// Receives a query name and a type and performes an iterative lookup starting
+/// from a root server.
+/// The time taken, the queries sent and the name servers they were sent to
+/// are recorded on its span and in the metrics.
+#[tracing::instrument(
+    name = "Resolving a query",
+    skip(qtype, root_addr, storage, upstream),
+    fields(
+        latency_ms = tracing::field::Empty,
+        round_trips = tracing::field::Empty,
+        nameservers = tracing::field::Empty
+    )
+)]
+pub async fn inquiring(
+    qname: &str,
+    qtype: QueryType,
+    root_addr: Ipv4Addr,
+    storage: &dyn Storage,
+    upstream: Upstream<'_>,
+) -> CResult<Packet> {
+    let started = Instant::now();
+    let result = resolve(qname, qtype, root_addr, storage, upstream, 0).await;
+    let elapsed = started.elapsed();
+    let round_trips = upstream.trace.round_trips();
+    let span = tracing::Span::current();
+    span.record("latency_ms", elapsed.as_secs_f64() * 1000.0);
+    span.record("round_trips", round_trips);
+    span.record("nameservers", upstream.trace.servers());
+    metrics::histogram!(RESOLUTION_DURATION).record(elapsed.as_secs_f64());
+    metrics::histogram!(RESOLUTION_ROUND_TRIPS).record(round_trips as f64);
+    result
+}
+
+/// # `resolve`
+///
+/// `inquiring`'s body, `depth` counts the resolutions of name servers
+/// lacking glue that led here, bounding the chains of delegations.
+#[tracing::instrument(
+    name = "Starting the lookup process"
+    skip(qtype, storage, upstream)
+)]
+async fn resolve(
+    qname: &str,
+    qtype: QueryType,
+    root_addr: Ipv4Addr,
+    storage: &dyn Storage,
+    upstream: Upstream<'_>,
+    depth: usize,
+) -> CResult<Packet> {
+    // the current name server that we are using to inquire, a forwarder if there are any
+    let mut current_ns = upstream
+        .forwarders
+        .first()
+        .copied()
+        .unwrap_or(IpAddr::V4(root_addr));
+    // other servers of the same delegation, tried if `current_ns` doesn't respond
+    let mut alternates: Vec<IpAddr> = upstream.forwarders.to_vec();
+    // the zone `current_ns` has been delegated, the root, none for the forwarders
+    let mut zone = upstream.forwarders.is_empty().then(String::new);
+    // the forwarders may be reached through a transport of their own, the servers
+    // they refer us to through the usual one
+    let mut transport = match upstream.forward_transport {
+        Some(transport) if !upstream.forwarders.is_empty() => transport,
+        _ => upstream.transport,
+    };
+
+    // query chace database, the records given for a client subnet only answer the clients in it
+    if upstream.use_cache {
+        tracing::info!("Searching the cache database for {}.", qname);
+        let client = upstream.client_subnet.map(|subnet| subnet.addr);
+        let res = storage.cached(qname, Some(qtype), client).await;
+        match res {
+            Ok(records) => {
+                // The records of the client subnet are more accurate
+                let scoped = records.iter().any(|cr| cr.ecs_network.is_some());
+                // Every address cached for the name, for the clients to pick from
+                let mut cached: Option<(Packet, &CachedRecord)> = None;
+                for cr in records
+                    .iter()
+                    .filter(|cr| cr.ecs_network.is_some() == scoped)
+                {
+                    if let Some(packet) = handling_record(cr, storage, upstream.clock).await {
+                        match cached.as_mut() {
+                            Some((record, _)) => record.answers.extend(packet.answers),
+                            None => cached = Some((packet, cr)),
+                        }
+                    }
+                }
+                if let Some((mut record, cr)) = cached {
+                    // The same record may have been cached more than once
+                    record.answers.sort();
+                    record.answers.dedup();
+                    // The bounds may have changed since the records were cached
+                    upstream.ttl_bounds.apply(&mut record);
+                    // The addresses of the name servers don't answer the client
+                    if depth == 0 {
+                        upstream.trace.record_cache_hit();
+                    }
+                    if let (Some(subnet), Some(network)) = (
+                        upstream.client_subnet,
+                        cr.ecs_network
+                            .as_deref()
+                            .and_then(|n| n.parse::<IpNet>().ok()),
+                    ) {
+                        record.add_edns_option(subnet.with_scope(network.prefix_len()).to_option());
+                    }
+                    return Ok(record);
+                }
+            }
+            Err(e) => {
+                tracing::info!("Couldn't find a valid entry in the cache, error:\n{}", e);
+            }
+        };
+    }
+
+    // Since it might take an arbitrary number of steps, we enter an unbounded loop.
+    loop {
+        // Query the server
+        let servers: Vec<IpAddr> = std::iter::once(current_ns)
+            .chain(alternates.iter().copied().filter(|a| *a != current_ns))
+            .collect();
+        let mut response = lookup_with_retry(
+            qname,
+            qtype,
+            &servers,
+            zone.as_deref(),
+            Upstream {
+                transport,
+                ..upstream
+            },
+        )
+        .await?;
+        upstream.ttl_bounds.apply(&mut response);
+
+        // Entries in the answer section, and no errors, we found the answer.
+        if !response.answers.is_empty() && response.header.rescode == ResultCode::NOERROR {
+            if upstream.use_cache {
+                // The answer is valid for the scope the name server gave it
+                let ecs_network = upstream.client_subnet.and_then(|sent| {
+                    ClientSubnet::from_packet(&response)
+                        .and_then(|answer| sent.scope_network(answer.scope_prefix))
+                });
+                for record in response
+                    .answers
+                    .iter()
+                    .filter(|record| !matches!(record, Record::OPT { .. }))
+                {
+                    record.register_record(upstream.cache_writer, ecs_network, upstream.clock)?;
+                }
+            }
+            return Ok(response);
+        }
+
+        //`NXDOMAIN` reply, which is the authoritative name servers
+        // way of telling us that the name doesn't exist.
+        if response.header.rescode == ResultCode::NXDOMAIN {
+            return Ok(response);
+        }
+
+        // Try to find a new nameserver based on NS and a corresponding A or AAAA
+        // record in the `Additional section`. If this succeeds, we can switch name server
+        // and retry the loop.
+        let referral = response
+            .get_referral_zone(qname)
+            .map(|referral| referral.trim_end_matches('.').to_lowercase());
+        let glue = reachable(response.get_resolved_ns_addrs(qname), upstream);
+        if let Some(addr) = glue.first() {
+            if let (true, Some(record)) = (upstream.use_cache, response.get_resolved_ns(qname)) {
+                record.register_record(upstream.cache_writer, None, upstream.clock)?;
+            }
+            current_ns = *addr;
+            transport = upstream.transport;
+            zone = referral;
+            alternates = glue;
+            for (host, addrs, ttl) in response.get_glue(qname) {
+                upstream.infra.insert(host, addrs, ttl);
+            }
+            continue;
+        }
+
+        // We found no useful resources in the `Additional section`,
+        // so we resolve the addresses of the name servers before resuming the query.
+        // If no NS records exist, we'll go with what the last server told us.
+        let hosts = response.get_ns_hosts(qname);
+        if hosts.is_empty() {
+            return Ok(response);
+        }
+        alternates = resolve_ns_hosts(&hosts, root_addr, storage, upstream, depth).await;
+        transport = upstream.transport;
+        zone = referral;
+        current_ns = match alternates.first() {
+            Some(addr) => *addr,
+            None => {
+                return Err(DnsError::Upstream(format!(
+                    "Unable to resolve the name servers of {}",
+                    qname
+                )))
+            }
+        };
+    }
+}
+
+/// # `reachable`
+///
+/// `resolve`'s helper, leaves the IPv6 addresses out of `addrs` unless they are queried.
+fn reachable(addrs: Vec<IpAddr>, upstream: Upstream<'_>) -> Vec<IpAddr> {
+    addrs
+        .into_iter()
+        .filter(|addr| upstream.policy.ipv6 || addr.is_ipv4())
+        .collect()
+}
+
+/// # `resolve_ns_hosts`
+///
+/// `resolve`'s helper, returns the addresses of the name servers named by a referral
+/// that didn't carry glue, the IPv6 ones too if they are queried.
+/// The addresses already known, from the infra cache or the cache database,
+/// are preferred, otherwise up to `MAX_PARALLEL_NS` servers are resolved at the same time.
+async fn resolve_ns_hosts(
+    hosts: &[String],
+    root_addr: Ipv4Addr,
+    storage: &dyn Storage,
+    upstream: Upstream<'_>,
+    depth: usize,
+) -> Vec<IpAddr> {
+    let qtypes: &[QueryType] = if upstream.policy.ipv6 {
+        &[QueryType::A, QueryType::AAAA]
+    } else {
+        &[QueryType::A]
+    };
+    let mut addrs = reachable(
+        hosts
+            .iter()
+            .filter_map(|host| upstream.infra.get(host))
+            .flatten()
+            .collect(),
+        upstream,
+    );
+    if !addrs.is_empty() {
+        tracing::info!("Found the name servers in the infra cache.");
+        return addrs;
+    }
+
+    if upstream.use_cache {
+        for host in hosts {
+            let mut known = Vec::new();
+            let mut ttl = u32::MAX;
+            for qtype in qtypes {
+                // The addresses given for a client subnet aren't the ones for us
+                let cached = storage
+                    .cached(host, Some(*qtype), None)
+                    .await
+                    .unwrap_or_default();
+                for cr in cached.iter().filter(|cr| cr.is_valid(upstream.clock)) {
+                    if let Some(addr) = cr.address.as_deref().and_then(|a| a.parse().ok()) {
+                        known.push(addr);
+                        ttl = ttl.min(cr.ttl);
+                    }
+                }
+            }
+            upstream.infra.insert(host, known.clone(), ttl);
+            addrs.extend(known);
+        }
+    }
+    if !addrs.is_empty() {
+        tracing::info!("Found the name servers in the cache database.");
+        return addrs;
+    }
+
+    if depth >= MAX_GLUELESS_DEPTH {
+        tracing::warn!("Too many delegations without glue, giving up.");
+        return addrs;
+    }
+    let lookups = hosts.iter().take(MAX_PARALLEL_NS).map(|host| async move {
+        // The resolution of a name server may need the resolution of other name servers
+        // The name servers are resolved for us, not on behalf of the client,
+        // starting from the root
+        let upstream = Upstream {
+            client_subnet: None,
+            forwarders: &[],
+            forward_transport: None,
+            ..upstream
+        };
+        let results = join_all(qtypes.iter().map(|qtype| {
+            Box::pin(resolve(
+                host,
+                *qtype,
+                root_addr,
+                storage,
+                upstream,
+                depth + 1,
+            ))
+        }))
+        .await;
+        let mut addrs = Vec::new();
+        let mut min_ttl = u32::MAX;
+        for result in results.into_iter().flatten() {
+            let (found, ttl) = result.get_addrs(host);
+            if !found.is_empty() {
+                addrs.extend(found);
+                min_ttl = min_ttl.min(ttl);
+            }
+        }
+        upstream.infra.insert(host, addrs.clone(), min_ttl);
+        Some(addrs)
+    });
+    join_all(lookups)
+        .await
+        .into_iter()
+        .flatten()
+        .flatten()
+        .collect()
+}
@@ -0,0 +1,63 @@
+use regex::Regex;
+
+use crate::configuration::Settings;
+
+/// Target enforcing SafeSearch on Google.
+const GOOGLE_TARGET: &str = "forcesafesearch.google.com";
+/// Target enforcing the strict SafeSearch on Bing.
+const BING_TARGET: &str = "strict.bing.com";
+/// Target enforcing the Restricted Mode on YouTube.
+const YOUTUBE_TARGET: &str = "restrict.youtube.com";
+
+const BING_NAMES: [&str; 2] = ["bing.com", "www.bing.com"];
+const YOUTUBE_NAMES: [&str; 5] = [
+    "www.youtube.com",
+    "m.youtube.com",
+    "youtubei.googleapis.com",
+    "youtube.googleapis.com",
+    "www.youtube-nocookie.com",
+];
+
+/// # `SafeSearch`
+///
+/// Policy that forces the search engines into their safe mode: the queries for their names
+/// are answered with a CNAME pointing to the host that enforces it, as documented
+/// by the providers for network wide enforcement.
+pub struct SafeSearch {
+    enabled: bool,
+    /// Google search is served under a domain for almost every country.
+    google: Regex,
+}
+
+impl SafeSearch {
+    pub fn new(enabled: bool) -> Self {
+        SafeSearch {
+            enabled,
+            google: Regex::new(r"^(www\.)?google\.([a-z]{2,3}|com?\.[a-z]{2})$")
+                .expect("Invalid Google domains regex"),
+        }
+    }
+
+    pub fn from_settings(settings: &Settings) -> Self {
+        SafeSearch::new(settings.get_safe_search())
+    }
+
+    /// # `target`
+    ///
+    /// Returns the name `qname` needs to be rewritten to, if any.
+    pub fn target(&self, qname: &str) -> Option<&'static str> {
+        if !self.enabled {
+            return None;
+        }
+        let qname = qname.trim_end_matches('.').to_lowercase();
+        if self.google.is_match(&qname) {
+            Some(GOOGLE_TARGET)
+        } else if BING_NAMES.contains(&qname.as_str()) {
+            Some(BING_TARGET)
+        } else if YOUTUBE_NAMES.contains(&qname.as_str()) {
+            Some(YOUTUBE_TARGET)
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,405 @@
+use std::{
+    net::IpAddr,
+    path::Path,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use futures::future::BoxFuture;
+use mlua::{Function, HookTriggers, Lua, LuaOptions, StdLib, Table, Value};
+
+use crate::{
+    structs::{
+        auxiliaries::{CResult, DnsError},
+        header::ResultCode,
+        packet::Packet,
+        questions_and_records::{Question, Record},
+    },
+    workers::{Middleware, Next, QueryContext},
+};
+
+/// TTL of the records written by the scripts that don't give one.
+const SCRIPT_TTL: u32 = 300;
+/// The hook counting the instructions of a script runs every this many instructions.
+const HOOK_INTERVAL: u32 = 10_000;
+/// Runs of the hook a call of a script may last, a million instructions.
+const HOOK_BUDGET: u32 = 100;
+/// Memory a script may allocate.
+const MEMORY_LIMIT: usize = 16 * 1024 * 1024;
+/// The result codes a script may answer with.
+const RCODES: [ResultCode; 7] = [
+    ResultCode::NOERROR,
+    ResultCode::FORMERR,
+    ResultCode::SERVFAIL,
+    ResultCode::NXDOMAIN,
+    ResultCode::NOTIMP,
+    ResultCode::REFUSED,
+    ResultCode::NOTAUTH,
+];
+
+/// # `Script`
+///
+/// A Lua script hooked into the handling of the client queries, as a middleware.
+/// `on_query(query)` is called with the name, the type and the client of a query
+/// before it's answered, `on_response(query, response)` with its result code and
+/// answers once it has been: returning nothing leaves them as they are, a table
+/// with `name` has the query resolved for another name, one with `rcode` and `answers`
+/// answers the query, or replaces the response, with them.
+/// The records are tables of `name`, `type`, `ttl` and `data`, in the presentation format;
+/// the scripts may write the records of type A, AAAA, CNAME, NS, PTR and MX and
+/// give back the others they have been passed.
+/// The scripts only have the string, table, math and utf8 libraries, and are stopped
+/// after a million instructions; a script failing leaves the query as it was.
+/// The hooks run one at a time on the blocking threads, out of the way of the runtime.
+pub struct Script(Arc<Engine>);
+
+/// # `Engine`
+///
+/// The Lua state of a script.
+struct Engine {
+    name: String,
+    lua: Mutex<Lua>,
+    /// The runs of the hook of the current call.
+    hooks: Arc<AtomicU32>,
+}
+
+impl Script {
+    /// # `load`
+    ///
+    /// Runs the script at `path`, which defines the hooks.
+    pub fn load(path: &Path) -> CResult<Self> {
+        let source = std::fs::read_to_string(path)?;
+        Script::from_source(&path.display().to_string(), &source)
+    }
+
+    /// # `from_source`
+    ///
+    /// Runs the script `source`, named `name` in the errors.
+    pub fn from_source(name: &str, source: &str) -> CResult<Self> {
+        let script_error = |e: mlua::Error| DnsError::Config(format!("The script {}: {}", name, e));
+        let libs = StdLib::STRING | StdLib::TABLE | StdLib::MATH | StdLib::UTF8;
+        let lua = Lua::new_with(libs, LuaOptions::new()).map_err(script_error)?;
+        lua.set_memory_limit(MEMORY_LIMIT).map_err(script_error)?;
+        let hooks = Arc::new(AtomicU32::new(0));
+        let counter = hooks.clone();
+        lua.set_hook(
+            HookTriggers::new().every_nth_instruction(HOOK_INTERVAL),
+            move |_, _| {
+                if counter.fetch_add(1, Ordering::Relaxed) >= HOOK_BUDGET {
+                    return Err(mlua::Error::runtime("too many instructions"));
+                }
+                Ok(())
+            },
+        );
+        lua.load(source)
+            .set_name(name)
+            .exec()
+            .map_err(script_error)?;
+        Ok(Script(Arc::new(Engine {
+            name: name.to_string(),
+            lua: Mutex::new(lua),
+            hooks,
+        })))
+    }
+
+    /// # `run`
+    ///
+    /// Runs `call` with the Lua state on a blocking thread.
+    async fn run<T, F>(&self, call: F) -> Result<T, String>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Engine) -> Result<T, String> + Send + 'static,
+    {
+        let engine = self.0.clone();
+        tokio::task::spawn_blocking(move || call(&engine))
+            .await
+            .unwrap_or_else(|e| Err(e.to_string()))
+    }
+}
+
+impl Engine {
+    /// # `on_query`
+    ///
+    /// What `on_query` makes of `question`, asked by `client`.
+    fn on_query(&self, question: &Question, client: IpAddr) -> Result<Option<Action>, String> {
+        let lua = self.lua.lock().unwrap();
+        let Some(hook) = lua
+            .globals()
+            .get::<_, Option<Function>>("on_query")
+            .map_err(|e| e.to_string())?
+        else {
+            return Ok(None);
+        };
+        let query = query_table(&lua, question, client).map_err(|e| e.to_string())?;
+        self.hooks.store(0, Ordering::Relaxed);
+        let Some(result) = hook
+            .call::<_, Option<Table>>(query)
+            .map_err(|e| e.to_string())?
+        else {
+            return Ok(None);
+        };
+        if let Some(name) = result
+            .get::<_, Option<String>>("name")
+            .map_err(|e| e.to_string())?
+        {
+            return Ok(Some(Action::Rename(name)));
+        }
+        change(&result, &[], &question.qname).map(|change| Some(Action::Answer(change)))
+    }
+
+    /// # `on_response`
+    ///
+    /// What `on_response` makes of `response`, answering `question` for `client`.
+    fn on_response(
+        &self,
+        question: &Question,
+        client: IpAddr,
+        response: &Packet,
+    ) -> Result<Option<Change>, String> {
+        let lua = self.lua.lock().unwrap();
+        let Some(hook) = lua
+            .globals()
+            .get::<_, Option<Function>>("on_response")
+            .map_err(|e| e.to_string())?
+        else {
+            return Ok(None);
+        };
+        let answers: Vec<(RecordView, &Record)> = response
+            .answers
+            .iter()
+            .filter(|record| !matches!(record, Record::OPT { .. }))
+            .map(|record| (RecordView::of(record), record))
+            .collect();
+        let tables = (|| {
+            let query = query_table(&lua, question, client)?;
+            let table = lua.create_table()?;
+            table.set("rcode", format!("{:?}", response.header.rescode))?;
+            let records = lua.create_table()?;
+            for (view, _) in &answers {
+                records.push(view.to_table(&lua)?)?;
+            }
+            table.set("answers", records)?;
+            Ok::<_, mlua::Error>((query, table))
+        })()
+        .map_err(|e| e.to_string())?;
+        self.hooks.store(0, Ordering::Relaxed);
+        let result = hook
+            .call::<_, Option<Table>>(tables)
+            .map_err(|e| e.to_string())?;
+        result
+            .map(|result| change(&result, &answers, &question.qname))
+            .transpose()
+    }
+}
+
+impl Middleware for Script {
+    fn handle<'a>(&'a self, mut ctx: QueryContext<'a>, next: Next<'a>) -> BoxFuture<'a, Packet> {
+        Box::pin(async move {
+            if !ctx.is_query() {
+                return next.run(ctx).await;
+            }
+            let question = ctx.request.questions[0].clone();
+            let client = ctx.src.ip();
+            let asked = question.clone();
+            let action = self
+                .run(move |engine| engine.on_query(&asked, client))
+                .await;
+            match action {
+                Ok(Some(Action::Answer(change))) => {
+                    let mut response = Packet::new();
+                    response.add_info(
+                        ctx.request.header.id,
+                        ctx.request.header.recursion_desired,
+                        true,
+                        true,
+                        ResultCode::NOERROR,
+                    );
+                    response.questions = ctx.request.questions.clone();
+                    change.apply(&mut response);
+                    return response;
+                }
+                Ok(Some(Action::Rename(name))) => {
+                    let name = name.trim_end_matches('.').to_lowercase();
+                    tracing::info!("The script renamed {} to {}", question.qname, name);
+                    ctx.request.questions[0].qname = name.into();
+                }
+                Ok(None) => {}
+                Err(e) => tracing::warn!("on_query of {} failed: {}", self.0.name, e),
+            }
+            let renamed = ctx.request.questions[0].qname.clone();
+            let mut response = next.run(ctx).await;
+            // The client is answered for the name it asked for
+            if renamed != question.qname {
+                response.questions = vec![question.clone()];
+                for domain in response.answers.iter_mut().filter_map(Record::domain_mut) {
+                    if domain.eq_ignore_ascii_case(&renamed) {
+                        *domain = question.qname.clone();
+                    }
+                }
+            }
+            let answered = response.clone();
+            let change = self
+                .run(move |engine| engine.on_response(&question, client, &answered))
+                .await;
+            match change {
+                Ok(Some(change)) => change.apply(&mut response),
+                Ok(None) => {}
+                Err(e) => tracing::warn!("on_response of {} failed: {}", self.0.name, e),
+            }
+            response
+        })
+    }
+}
+
+/// # `Action`
+///
+/// What `on_query` asked for.
+enum Action {
+    /// The query is resolved for another name.
+    Rename(String),
+    /// The query is answered by the script.
+    Answer(Change),
+}
+
+/// # `Change`
+///
+/// The result code and the answers a script replaces the ones of a response with.
+struct Change {
+    rcode: Option<ResultCode>,
+    answers: Option<Vec<Record>>,
+}
+
+impl Change {
+    fn apply(self, response: &mut Packet) {
+        if let Some(rcode) = self.rcode {
+            response.header.rescode = rcode;
+        }
+        if let Some(answers) = self.answers {
+            response.answers = answers;
+        }
+    }
+}
+
+/// # `RecordView`
+///
+/// A record as the scripts see it.
+#[derive(Debug, PartialEq, Eq)]
+struct RecordView {
+    name: String,
+    qtype: String,
+    ttl: u32,
+    data: String,
+}
+
+impl RecordView {
+    fn of(record: &Record) -> Self {
+        RecordView {
+            name: record.get_domain().to_string(),
+            qtype: record.get_qtype().to_string(),
+            ttl: record.get_ttl(),
+            data: record.rdata().to_string(),
+        }
+    }
+
+    fn to_table<'lua>(&self, lua: &'lua Lua) -> mlua::Result<Table<'lua>> {
+        let table = lua.create_table()?;
+        table.set("name", self.name.as_str())?;
+        table.set("type", self.qtype.as_str())?;
+        table.set("ttl", self.ttl)?;
+        table.set("data", self.data.as_str())?;
+        Ok(table)
+    }
+
+    /// # `from_table`
+    ///
+    /// The record of `table`, of `default_name` if it has no name.
+    fn from_table(table: &Table, default_name: &str) -> Result<Self, String> {
+        let field = |key: &str| {
+            table
+                .get::<_, Option<String>>(key)
+                .map_err(|e| format!("the {} of a record: {}", key, e))
+        };
+        Ok(RecordView {
+            name: field("name")?
+                .map(|name| name.trim_end_matches('.').to_lowercase())
+                .unwrap_or_else(|| default_name.to_string()),
+            qtype: field("type")?
+                .ok_or("a record has no type")?
+                .to_ascii_uppercase(),
+            ttl: table
+                .get::<_, Option<u32>>("ttl")
+                .map_err(|e| format!("the ttl of a record: {}", e))?
+                .unwrap_or(SCRIPT_TTL),
+            data: field("data")?.ok_or("a record has no data")?,
+        })
+    }
+
+    /// # `record`
+    ///
+    /// The record, if it's of a type the scripts may write.
+    fn record(&self) -> Result<Record, String> {
+        Record::from_presentation(
+            self.name.as_str().into(),
+            self.qtype.parse()?,
+            self.ttl,
+            &self.data,
+        )
+    }
+}
+
+/// # `query_table`
+///
+/// The query as the scripts see it.
+fn query_table<'lua>(
+    lua: &'lua Lua,
+    question: &Question,
+    client: IpAddr,
+) -> mlua::Result<Table<'lua>> {
+    let table = lua.create_table()?;
+    table.set("name", question.qname.as_str())?;
+    table.set("type", question.qtype.to_string())?;
+    table.set("client", client.to_string())?;
+    Ok(table)
+}
+
+/// # `change`
+///
+/// The change asked for by a hook returning `table`: the records passed to it, `given`,
+/// are given back as they were, the others are written for `qname` unless named.
+fn change(table: &Table, given: &[(RecordView, &Record)], qname: &str) -> Result<Change, String> {
+    let rcode = match table
+        .get::<_, Option<String>>("rcode")
+        .map_err(|e| format!("the rcode: {}", e))?
+    {
+        Some(rcode) => Some(
+            RCODES
+                .into_iter()
+                .find(|code| format!("{:?}", code).eq_ignore_ascii_case(&rcode))
+                .ok_or_else(|| format!("{} isn't a result code", rcode))?,
+        ),
+        None => None,
+    };
+    let answers = match table
+        .get::<_, Option<Table>>("answers")
+        .map_err(|e| format!("the answers: {}", e))?
+    {
+        Some(records) => {
+            let mut answers = Vec::new();
+            for value in records.sequence_values::<Value>() {
+                let Value::Table(record) = value.map_err(|e| e.to_string())? else {
+                    return Err("an answer isn't a table".to_string());
+                };
+                let view = RecordView::from_table(&record, qname)?;
+                match given.iter().find(|(given, _)| *given == view) {
+                    Some((_, record)) => answers.push((*record).clone()),
+                    None => answers.push(view.record()?),
+                }
+            }
+            Some(answers)
+        }
+        None => None,
+    };
+    Ok(Change { rcode, answers })
+}
@@ -0,0 +1,764 @@
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize},
+        Arc, RwLock,
+    },
+};
+
+use futures::future::BoxFuture;
+use sqlx::SqlitePool;
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    sync::{broadcast, mpsc, oneshot, watch, Semaphore},
+    task::JoinHandle,
+    time::{timeout_at, Instant},
+};
+
+use crate::{
+    acme::{self, Acme, Http01Responses},
+    api::{self, ApiState},
+    bootstrap,
+    certificates::CertificateStore,
+    clients::ClientNames,
+    configuration::Settings,
+    control::Reloader,
+    dashboard, database,
+    dhcp::LeaseWatcher,
+    dnssec::ZoneSigner,
+    doh::Doh,
+    mdns::{self, Mdns},
+    notify::NotifyHandler,
+    overrides::Overrides,
+    querylog::QueryLog,
+    resolver::Resolver,
+    stats::QueryStats,
+    storage::{self, StorageKind},
+    structs::{
+        auxiliaries::{CResult, DnsError},
+        buffer::BytePacketBuffer,
+        db_queries::CachedRecord,
+        header::ResultCode,
+        packet::Packet,
+    },
+    tarpit::{Penalty, Tarpit},
+    tcp::{self, TcpLimits},
+    tsig::Keyring,
+    udp::{receive_buffer, recv_batch, Responder},
+    workers::{
+        query_handler, self_test, AfterResolution, BeforeResolution, ErrorResponses, Middleware,
+        OverflowPolicy, Pipeline, Policies, QueryInfo, Resolution, Retransmissions, ServerState,
+        Verdict,
+    },
+    zones::{secondary::SecondaryZone, ZoneStore},
+};
+
+/// # `ServerBuilder`
+///
+/// Puts together a `Server` from its settings, the parts that aren't provided
+/// are set up as the settings say.
+pub struct ServerBuilder {
+    settings: Settings,
+    udp: Option<SocketAddr>,
+    socket: Option<UdpSocket>,
+    db_pool: Option<SqlitePool>,
+    resolver: Option<Resolver>,
+    reload: Option<mpsc::Receiver<Settings>>,
+    reloader: Option<Reloader>,
+    middlewares: Vec<Arc<dyn Middleware>>,
+}
+
+impl ServerBuilder {
+    /// # `udp`
+    ///
+    /// The address the server listens on, instead of the one of the settings.
+    pub fn udp(mut self, addr: SocketAddr) -> Self {
+        self.udp = Some(addr);
+        self
+    }
+
+    /// # `socket`
+    ///
+    /// A socket already bound the server listens on, as the ones passed by systemd.
+    pub fn socket(mut self, socket: UdpSocket) -> Self {
+        self.socket = Some(socket);
+        self
+    }
+
+    /// # `with_cache`
+    ///
+    /// The database the server keeps its cache, and the other data, in;
+    /// its migrations must have been run, it's left open at the shutdown.
+    /// Without one the database of the settings is opened and migrated.
+    pub fn with_cache(mut self, db_pool: SqlitePool) -> Self {
+        self.db_pool = Some(db_pool);
+        self
+    }
+
+    /// # `with_resolver`
+    ///
+    /// The resolver the queries that aren't answered locally are resolved with.
+    pub fn with_resolver(mut self, resolver: Resolver) -> Self {
+        self.resolver = Some(resolver);
+        self
+    }
+
+    /// # `with_reload`
+    ///
+    /// The settings received through `reload` replace the policies of the server,
+    /// the access control, the blocklist and safe search, and the secondary zones
+    /// are refreshed; the other settings are only read at startup.
+    pub fn with_reload(mut self, reload: mpsc::Receiver<Settings>) -> Self {
+        self.reload = Some(reload);
+        self
+    }
+
+    /// # `with_reloader`
+    ///
+    /// How the management API reloads the configuration, it answers its reload requests
+    /// with an error without one.
+    pub fn with_reloader(mut self, reloader: Reloader) -> Self {
+        self.reloader = Some(reloader);
+        self
+    }
+
+    /// # `middleware`
+    ///
+    /// A middleware the client queries go through after the ones of the settings,
+    /// in the order they are added.
+    pub fn middleware(mut self, middleware: Arc<dyn Middleware>) -> Self {
+        self.middlewares.push(middleware);
+        self
+    }
+
+    /// # `on_query`
+    ///
+    /// Calls `hook` with every client query before it's answered, it may let the query
+    /// through, refuse it or answer it; registered as a middleware, after the ones
+    /// added before.
+    pub fn on_query<F>(self, hook: F) -> Self
+    where
+        F: Fn(QueryInfo) -> BoxFuture<'static, Verdict> + Send + Sync + 'static,
+    {
+        self.middleware(Arc::new(BeforeResolution(Arc::new(hook))))
+    }
+
+    /// # `on_response`
+    ///
+    /// Calls `hook` with every response to a client query, along with how it has been
+    /// obtained, and sends the response it returns; registered as a middleware,
+    /// after the ones added before.
+    pub fn on_response<F>(self, hook: F) -> Self
+    where
+        F: Fn(Resolution, Packet) -> BoxFuture<'static, Packet> + Send + Sync + 'static,
+    {
+        self.middleware(Arc::new(AfterResolution(Arc::new(hook))))
+    }
+
+    /// # `build`
+    ///
+    /// Binds the sockets, opens the database and reads the parts of the settings
+    /// that can be invalid, failing if any of them is.
+    pub async fn build(self) -> CResult<Server> {
+        let settings = self.settings;
+        // The server only closes what it opens, the embedder closes its own pool; the cache
+        // of a resolver of ours is kept in that pool too unless it's in another database
+        let close_pool = self.db_pool.is_none();
+        let close_storage = self.resolver.is_none()
+            && (close_pool || settings.get_db_kind() != StorageKind::Sqlite);
+        let sock = match (self.socket, self.udp) {
+            (Some(sock), _) => sock,
+            (None, Some(addr)) => UdpSocket::bind(addr).await?,
+            (None, None) => UdpSocket::bind(&settings.get_local_server_full_domain()).await?,
+        };
+        let doh = Doh::from_settings(&settings).await?;
+        // TCP listens on the same address and port of UDP
+        let tcp = if settings.get_tcp_enabled() {
+            Some(TcpListener::bind(sock.local_addr()?).await?)
+        } else {
+            None
+        };
+        let db_pool = match self.db_pool {
+            Some(db_pool) => db_pool,
+            None => {
+                let db_pool = database::connect(&settings).await?;
+                database::migrate(&settings, &db_pool).await?;
+                db_pool
+            }
+        };
+        let resolver = match self.resolver {
+            Some(resolver) => resolver,
+            None => {
+                let storage = storage::open(&settings, db_pool.clone()).await?;
+                Resolver::from_settings(&settings, storage).await?
+            }
+        };
+        if let Some(path) = settings.get_cache_bootstrap() {
+            let cached = bootstrap::prefill(
+                resolver.storage.as_ref(),
+                path,
+                settings.get_cache_bootstrap_ttl(),
+                resolver.clock.as_ref(),
+            )
+            .await?;
+            tracing::info!("Cached {} records of {}", cached, path.display());
+        }
+        let keyring = settings
+            .get_keyring()
+            .map_err(|e| DnsError::Config(e.to_string()))?;
+        let notify = Arc::new(NotifyHandler::from_settings(&settings, &keyring)?);
+        let zones = Arc::new(ZoneStore::new());
+        let signer = Arc::new(ZoneSigner::new(
+            settings.get_dnssec_keys_dir(),
+            settings.get_signature_validity(),
+        ));
+        let secondaries = settings
+            .get_secondary_zones()
+            .iter()
+            .map(|zone_settings| {
+                SecondaryZone::new(
+                    zone_settings,
+                    zones.clone(),
+                    notify.clone(),
+                    &keyring,
+                    signer.clone(),
+                )
+            })
+            .collect::<CResult<Vec<SecondaryZone>>>()?;
+        let policies = Policies::from_settings(&settings, db_pool.clone())?;
+        let overrides = Overrides::load(db_pool.clone()).await?;
+        let pipeline = self
+            .middlewares
+            .into_iter()
+            .fold(Pipeline::from_settings(&settings)?, Pipeline::with);
+        // Without a sender the configuration is never reloaded
+        let reload = self.reload.unwrap_or_else(|| mpsc::channel(1).1);
+        Ok(Server {
+            sock,
+            tcp,
+            doh,
+            settings,
+            db_pool,
+            resolver,
+            keyring,
+            notify,
+            zones,
+            signer,
+            secondaries,
+            policies,
+            pipeline,
+            overrides,
+            error_responses: ErrorResponses::new()?,
+            reload,
+            reloader: self.reloader,
+            close_pool,
+            close_storage,
+        })
+    }
+}
+
+/// # `Server`
+///
+/// A server ready to serve, built by `ServerBuilder`, `start` starts serving.
+pub struct Server {
+    sock: UdpSocket,
+    /// Present if the queries are received over TCP too.
+    tcp: Option<TcpListener>,
+    /// Present if DNS over HTTPS is served.
+    doh: Option<Doh>,
+    settings: Settings,
+    db_pool: SqlitePool,
+    resolver: Resolver,
+    keyring: Keyring,
+    notify: Arc<NotifyHandler>,
+    zones: Arc<ZoneStore>,
+    signer: Arc<ZoneSigner>,
+    secondaries: Vec<SecondaryZone>,
+    policies: Policies,
+    pipeline: Pipeline,
+    overrides: Overrides,
+    error_responses: ErrorResponses,
+    reload: mpsc::Receiver<Settings>,
+    reloader: Option<Reloader>,
+    /// Whether the database pool has been opened by the server, it's closed at the shutdown.
+    close_pool: bool,
+    /// Whether the storage of the cache has been opened by the server, it's closed at the shutdown.
+    close_storage: bool,
+}
+
+impl Server {
+    pub fn builder(settings: Settings) -> ServerBuilder {
+        ServerBuilder {
+            settings,
+            udp: None,
+            socket: None,
+            db_pool: None,
+            resolver: None,
+            reload: None,
+            reloader: None,
+            middlewares: Vec::new(),
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.sock.local_addr()
+    }
+
+    /// # `start`
+    ///
+    /// Serves in the background until `ServerHandle::shutdown` is called.
+    pub fn start(self) -> io::Result<ServerHandle> {
+        let local_addr = self.local_addr()?;
+        let (shutdown, stop) = oneshot::channel::<()>();
+        let task = tokio::spawn(self.serve(async {
+            // A dropped handle stops the server as well
+            let _ = stop.await;
+        }));
+        Ok(ServerHandle {
+            local_addr,
+            shutdown,
+            task,
+        })
+    }
+
+    /// # `serve`
+    ///
+    /// Core Business.
+    /// Once `shutdown` completes no more queries are accepted, the queries being handled
+    /// are given the shutdown timeout to complete, the background tasks are stopped,
+    /// then the cache is flushed and the database pool is closed, if the server opened them.
+    async fn serve(self, shutdown: impl Future<Output = ()>) -> io::Result<()> {
+        let Server {
+            sock,
+            tcp: tcp_listener,
+            doh,
+            settings,
+            db_pool,
+            resolver,
+            keyring,
+            notify,
+            zones,
+            signer,
+            secondaries,
+            policies,
+            pipeline,
+            overrides,
+            error_responses,
+            mut reload,
+            reloader,
+            close_pool,
+            close_storage,
+        } = self;
+        let sock_ref = Arc::new(sock);
+        // The tasks that live as long as the server, stopped at the shutdown
+        let mut background: Vec<JoinHandle<()>> = Vec::new();
+        for secondary in secondaries {
+            background.push(tokio::spawn(secondary.run()));
+        }
+        let clients = Arc::new(ClientNames::from_settings(&settings, zones.clone()));
+        if let Some(watcher) =
+            LeaseWatcher::from_settings(&settings, zones.clone(), clients.clone())
+        {
+            background.push(tokio::spawn(watcher.run()));
+        }
+        let mut blocklist_task = tokio::spawn(policies.blocklist.clone().run());
+        let (query_log, query_log_task) =
+            QueryLog::from_settings(&settings, db_pool.clone()).unzip();
+        let stats = QueryStats::from_settings(&settings, resolver.storage.clone()).map(Arc::new);
+        let stats_task = stats.clone().map(|stats| tokio::spawn(stats.run()));
+        let mdns = Mdns::from_settings(&settings).map(Arc::new);
+        if let Some(mdns) = mdns.clone() {
+            background.push(tokio::spawn(async move {
+                if let Err(e) = mdns::serve(mdns).await {
+                    tracing::error!("Unable to answer the mDNS queries: {}", e);
+                }
+            }));
+        }
+        let state = Arc::new(ServerState {
+            db_pool,
+            resolver,
+            policies: RwLock::new(Arc::new(policies)),
+            notify,
+            zones,
+            clients,
+            keyring: Arc::new(keyring),
+            error_responses,
+            query_log,
+            stats,
+            query_stream: broadcast::channel(dashboard::STREAM_CAPACITY).0,
+            ready: AtomicBool::new(false),
+            mdns,
+            rotation: AtomicUsize::new(0),
+            strict_parsing: settings.get_strict_parsing(),
+            in_flight: Arc::new(Semaphore::new(settings.get_max_in_flight_queries())),
+            retransmissions: Retransmissions::new(),
+            pipeline,
+            overrides,
+            tarpit: Tarpit::from_settings(&settings).map(Arc::new),
+        });
+        if let Some(tarpit) = state.tarpit.clone() {
+            background.push(tokio::spawn(tarpit.run()));
+        }
+        // Without a self-test the server is ready as soon as it's listening
+        let self_test_task = match settings.get_self_test_name() {
+            Some(name) => Some(tokio::spawn(self_test(state.clone(), name))),
+            None => {
+                state.set_ready(true);
+                None
+            }
+        };
+        let acme_challenges = Arc::new(Http01Responses::default());
+        if let Some(addr) = settings.get_dashboard_address() {
+            let state = state.clone();
+            background.push(tokio::spawn(async move {
+                if let Err(e) = dashboard::serve(addr, state).await {
+                    tracing::error!("Unable to serve the dashboard on {}: {}", addr, e);
+                }
+            }));
+        }
+        if let Some(addr) = settings.get_acme_http01_address() {
+            let acme_challenges = acme_challenges.clone();
+            background.push(tokio::spawn(async move {
+                if let Err(e) = acme::serve_http01(addr, acme_challenges).await {
+                    tracing::error!("Unable to answer the HTTP-01 challenges on {}: {}", addr, e);
+                }
+            }));
+        }
+        if let (Some(addr), Some(token)) = (settings.get_api_address(), settings.get_api_token()) {
+            let api = ApiState::new(state.clone(), token.to_string(), reloader);
+            background.push(tokio::spawn(async move {
+                if let Err(e) = api::serve(addr, api).await {
+                    tracing::error!("Unable to serve the management API on {}: {}", addr, e);
+                }
+            }));
+        }
+        let max_in_flight = settings.get_max_in_flight_queries();
+        let in_flight = state.in_flight.clone();
+        let overflow_policy = settings.get_overflow_policy();
+        // Dropping the sender stops accepting connections and closes the open ones
+        let (listeners_closer, listeners_closed) = watch::channel(());
+        // The listeners stop on their own once closed, they are waited for
+        let mut listeners: Vec<JoinHandle<()>> = Vec::new();
+        if let Some(listener) = tcp_listener {
+            listeners.push(tokio::spawn(tcp::serve(
+                listener,
+                state.clone(),
+                TcpLimits::from_settings(&settings),
+                overflow_policy,
+                listeners_closed.clone(),
+            )));
+        }
+        let certificates = doh.as_ref().map(Doh::certificates);
+        if let Some(certificates) = certificates.clone() {
+            background.push(tokio::spawn(certificates.clone().watch()));
+            if let Some(acme) = Acme::from_settings(
+                &settings,
+                certificates,
+                acme_challenges,
+                state.zones.clone(),
+                signer,
+            ) {
+                background.push(tokio::spawn(acme.run()));
+            }
+        }
+        if let Some(doh) = doh {
+            listeners.push(tokio::spawn(doh.serve(
+                state.clone(),
+                overflow_policy,
+                listeners_closed,
+            )));
+        }
+        let batch_size = settings.get_udp_batch_size();
+        let responder = Responder::new(sock_ref.clone(), batch_size);
+        let mut req_buffers: Vec<BytePacketBuffer> =
+            (0..batch_size).map(|_| receive_buffer()).collect();
+        let mut reload_open = true;
+        tokio::pin!(shutdown);
+        loop {
+            let received = tokio::select! {
+                _ = &mut shutdown => break,
+                new_settings = reload.recv(), if reload_open => {
+                    match new_settings {
+                        Some(new_settings) => {
+                            apply_reload(
+                                &state,
+                                &settings,
+                                &new_settings,
+                                &mut blocklist_task,
+                                certificates.as_deref(),
+                            )
+                        }
+                        None => reload_open = false,
+                    }
+                    continue;
+                }
+                received = recv_batch(&sock_ref, &mut req_buffers) => received,
+            };
+            let received = match received {
+                Ok(r) => r,
+                Err(e) => {
+                    tracing::info!("Received a malformed packet: {}", e);
+                    continue;
+                }
+            };
+            // The buffers filled are handed to the handlers and replaced
+            let filled: Vec<BytePacketBuffer> = req_buffers
+                .splice(0..received.len(), received.iter().map(|_| receive_buffer()))
+                .collect();
+            for (mut req_buffer, received) in filled.into_iter().zip(received) {
+                // The source of the datagram couldn't be read
+                let Some((len, src)) = received else {
+                    continue;
+                };
+                if state.strict_parsing {
+                    req_buffer.buf.truncate(len);
+                }
+                handle(
+                    req_buffer,
+                    src,
+                    &responder,
+                    &state,
+                    &in_flight,
+                    overflow_policy,
+                )
+                .await;
+            }
+        }
+
+        tracing::info!("Shutting down");
+        state.set_ready(false);
+        drop(listeners_closer);
+        if let Some(task) = self_test_task {
+            task.abort();
+        }
+        let deadline = Instant::now() + settings.get_shutdown_timeout();
+        // Every permit is back once the handlers are done
+        let drained = timeout_at(deadline, in_flight.acquire_many(max_in_flight as u32)).await;
+        if drained.is_err() {
+            tracing::warn!(
+                "{} queries were still being handled at the shutdown deadline",
+                max_in_flight - in_flight.available_permits()
+            );
+        }
+        if timeout_at(deadline, responder.close()).await.is_err() {
+            tracing::warn!("Some responses were still queued at the shutdown deadline");
+        }
+        for listener in listeners {
+            let abort = listener.abort_handle();
+            if timeout_at(deadline, listener).await.is_err() {
+                tracing::warn!("A listener was still closing at the shutdown deadline");
+                abort.abort();
+            }
+        }
+        blocklist_task.abort();
+        background.push(blocklist_task);
+        for task in &background {
+            task.abort();
+        }
+        for task in background {
+            let _ = task.await;
+        }
+        if let (Some(query_log), Some(task)) = (&state.query_log, query_log_task) {
+            query_log.close();
+            if timeout_at(deadline, task).await.is_err() {
+                tracing::warn!(
+                    "Some entries of the query log were still queued at the shutdown deadline"
+                );
+            }
+        }
+        if let (Some(stats), Some(task)) = (&state.stats, stats_task) {
+            task.abort();
+            if let Err(e) = stats.flush().await {
+                tracing::warn!("Unable to write the query statistics: {}", e);
+            }
+        }
+        if timeout_at(deadline, state.resolver.cache_writer.close())
+            .await
+            .is_err()
+        {
+            tracing::warn!("Some entries of the cache were still queued at the shutdown deadline");
+        }
+        match CachedRecord::purge_expired(
+            state.resolver.storage.as_ref(),
+            state.resolver.clock.as_ref(),
+        )
+        .await
+        {
+            Ok(purged) => tracing::info!("Purged {} expired entries from the cache", purged),
+            Err(e) => tracing::warn!("Unable to purge the cache: {}", e),
+        }
+        if close_storage {
+            state.resolver.storage.close().await;
+        }
+        if close_pool {
+            state.db_pool.close().await;
+        }
+        Ok(())
+    }
+}
+
+/// # `ServerHandle`
+///
+/// A server serving in the background, returned by `Server::start`.
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    shutdown: oneshot::Sender<()>,
+    task: JoinHandle<io::Result<()>>,
+}
+
+impl ServerHandle {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// # `shutdown`
+    ///
+    /// Stops the server and waits for it to complete its shutdown.
+    pub async fn shutdown(self) -> io::Result<()> {
+        let _ = self.shutdown.send(());
+        self.task.await.map_err(io::Error::other)?
+    }
+}
+
+/// # `apply_reload`
+///
+/// `serve`'s helper, replaces the policies of the server with the ones of `new_settings`,
+/// asks for a refresh of the secondary zones configured at startup and reads the
+/// certificate of the DoH endpoint again.
+/// If the new policies are invalid the current ones are kept, and so is the certificate
+/// if it can't be read.
+fn apply_reload(
+    state: &ServerState,
+    settings: &Settings,
+    new_settings: &Settings,
+    blocklist_task: &mut JoinHandle<()>,
+    certificates: Option<&CertificateStore>,
+) {
+    tracing::info!("Reloading the configuration");
+    match Policies::from_settings(new_settings, state.db_pool.clone()) {
+        Ok(policies) => {
+            // The new blocklist loads its lists and takes over the updates
+            blocklist_task.abort();
+            *blocklist_task = tokio::spawn(policies.blocklist.clone().run());
+            state.set_policies(Arc::new(policies));
+        }
+        Err(e) => tracing::error!(
+            "The new configuration is invalid, keeping the current policies: {}",
+            e
+        ),
+    }
+    for zone_settings in settings.get_secondary_zones() {
+        state.notify.refresh(&zone_settings.get_name());
+    }
+    if let Some(Err(e)) = certificates.map(CertificateStore::reload) {
+        tracing::error!(
+            "Unable to reload the certificate, keeping the current one: {}",
+            e
+        );
+    }
+}
+
+/// # `handle`
+///
+/// `serve`'s helper, hands a datagram, or a message received over TCP, to a new query
+/// handler, at once or after the delay the tarpit imposes on its client, unless
+/// the tarpit drops it.
+pub(crate) async fn handle(
+    req_buffer: BytePacketBuffer,
+    src: SocketAddr,
+    responder: &Responder,
+    state: &Arc<ServerState>,
+    in_flight: &Arc<Semaphore>,
+    overflow_policy: OverflowPolicy,
+) {
+    let Some(tarpit) = &state.tarpit else {
+        dispatch(
+            req_buffer,
+            src,
+            responder,
+            state,
+            in_flight,
+            overflow_policy,
+        )
+        .await;
+        return;
+    };
+    match tarpit.admit(src.ip()) {
+        Penalty::None => {
+            dispatch(
+                req_buffer,
+                src,
+                responder,
+                state,
+                in_flight,
+                overflow_policy,
+            )
+            .await
+        }
+        Penalty::Delay(delay) => {
+            // The query waits without holding a permit, the well-behaved clients get them
+            let Some(slot) = tarpit.delay_slot() else {
+                return;
+            };
+            let responder = responder.clone();
+            let state = state.clone();
+            let in_flight = in_flight.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                drop(slot);
+                dispatch(
+                    req_buffer,
+                    src,
+                    &responder,
+                    &state,
+                    &in_flight,
+                    overflow_policy,
+                )
+                .await;
+            });
+        }
+        Penalty::Drop => {}
+    }
+}
+
+/// # `dispatch`
+///
+/// `handle`'s helper, hands the datagram to a new query handler,
+/// unless too many queries are being handled already.
+async fn dispatch(
+    req_buffer: BytePacketBuffer,
+    src: SocketAddr,
+    responder: &Responder,
+    state: &Arc<ServerState>,
+    in_flight: &Arc<Semaphore>,
+    overflow_policy: OverflowPolicy,
+) {
+    let permit = match in_flight.clone().try_acquire_owned() {
+        Ok(p) => p,
+        Err(_) => {
+            tracing::warn!(
+                "Too many queries in flight, rejecting the query from {}",
+                src
+            );
+            if overflow_policy == OverflowPolicy::ServFail {
+                let id = match req_buffer.buf.get(..2) {
+                    Some(&[high, low]) => u16::from_be_bytes([high, low]),
+                    _ => 0,
+                };
+                state
+                    .error_responses
+                    .send(responder, src, id, ResultCode::SERVFAIL)
+                    .await;
+            }
+            return;
+        }
+    };
+    let responder = responder.clone();
+    let state = state.clone();
+    tokio::spawn(async move {
+        query_handler(responder, req_buffer, src, state).await;
+        drop(permit);
+    });
+}
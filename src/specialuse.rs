@@ -0,0 +1,177 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use crate::{
+    configuration::Settings,
+    structs::{
+        header::ResultCode,
+        packet::Packet,
+        questions_and_records::{QueryType, Question, Record},
+    },
+    zones::is_subdomain,
+};
+
+/// TTL of the answers, and of the negative answers, for the special-use names.
+const SPECIAL_USE_TTL: u32 = 3600;
+/// Name server of the SOA of the negative answers.
+const SOA_MNAME: &str = "localhost";
+/// Mailbox of the SOA of the negative answers.
+const SOA_RNAME: &str = "nobody.invalid";
+/// `localhost` in the reverse zones.
+const LOOPBACK_V4_PTR: &str = "1.0.0.127.in-addr.arpa";
+const LOOPBACK_V6_PTR: &str =
+    "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.ip6.arpa";
+
+/// Names that mean nothing outside of the local network, or nothing at all:
+/// RFC 6761 (`localhost`, `invalid`, `test`), RFC 7686 (`onion`), RFC 6762 (`local`),
+/// RFC 8375 (`home.arpa`), RFC 9462 (`resolver.arpa`, but the designated resolvers)
+/// and the reverse zones of the addresses that aren't globally routed (RFC 6303).
+const DEFAULT_NAMES: [&str; 21] = [
+    "localhost",
+    "invalid",
+    "test",
+    "onion",
+    "local",
+    "home.arpa",
+    "resolver.arpa",
+    // 0.0.0.0/8, 127.0.0.0/8, 10.0.0.0/8, 169.254.0.0/16, 192.168.0.0/16 and 255.255.255.255
+    "0.in-addr.arpa",
+    "127.in-addr.arpa",
+    "10.in-addr.arpa",
+    "254.169.in-addr.arpa",
+    "168.192.in-addr.arpa",
+    "255.255.255.255.in-addr.arpa",
+    // 192.0.2.0/24, 198.51.100.0/24 and 203.0.113.0/24, reserved for documentation (RFC 5737)
+    "2.0.192.in-addr.arpa",
+    "100.51.198.in-addr.arpa",
+    "113.0.203.in-addr.arpa",
+    // fd00::/8 (unique local), fe80::/10 (link local) and 2001:db8::/32 (documentation)
+    "d.f.ip6.arpa",
+    "8.e.f.ip6.arpa",
+    "9.e.f.ip6.arpa",
+    "a.e.f.ip6.arpa",
+    "b.e.f.ip6.arpa",
+];
+
+/// # `default_names`
+///
+/// The special-use names answered locally unless disabled, `DEFAULT_NAMES` along with
+/// the reverse zones of 172.16.0.0/12, 100.64.0.0/10 (the shared address space of
+/// RFC 7793), `::1`, `::` and 2001:db8::/32.
+pub fn default_names() -> Vec<String> {
+    DEFAULT_NAMES
+        .iter()
+        .map(|name| name.to_string())
+        .chain((16..32).map(|octet| format!("{}.172.in-addr.arpa", octet)))
+        .chain((64..128).map(|octet| format!("{}.100.in-addr.arpa", octet)))
+        .chain([
+            LOOPBACK_V6_PTR.to_string(),
+            format!("{}0.ip6.arpa", "0.".repeat(31)),
+            "8.b.d.0.1.0.0.2.ip6.arpa".to_string(),
+        ])
+        .collect()
+}
+
+/// # `SpecialUse`
+///
+/// Policy answering locally the queries for the special-use names instead of asking
+/// the root servers, who would only answer NXDOMAIN after having seen them:
+/// `localhost` resolves to the loopback addresses, the other names don't exist.
+/// The names of the zones we serve, as the local ones of the DHCP leases,
+/// are answered from the zones before this policy is consulted, and the records
+/// configured for a name, as the PTRs of the local network, are answered in its place.
+pub struct SpecialUse {
+    /// Lowercase and without the trailing dot.
+    names: Vec<String>,
+}
+
+impl SpecialUse {
+    pub fn new(names: Vec<String>) -> Self {
+        SpecialUse { names }
+    }
+
+    /// # `from_settings`
+    ///
+    /// The default names less the disabled ones, plus the ones added;
+    /// none if the policy is disabled.
+    pub fn from_settings(settings: &Settings) -> Self {
+        if !settings.get_special_use_enabled() {
+            return SpecialUse::new(Vec::new());
+        }
+        let disabled = settings.get_special_use_disabled();
+        let names = default_names()
+            .into_iter()
+            .filter(|name| !disabled.contains(name))
+            .chain(settings.get_special_use_extra())
+            .collect();
+        SpecialUse::new(names)
+    }
+
+    /// # `name_of`
+    ///
+    /// Returns the special-use name `qname` belongs to, the most specific one, if any.
+    pub fn name_of(&self, qname: &str) -> Option<&str> {
+        let qname = qname.trim_end_matches('.').to_lowercase();
+        self.names
+            .iter()
+            .filter(|name| is_subdomain(&qname, name))
+            .max_by_key(|name| name.len())
+            .map(|name| name.as_str())
+    }
+
+    /// # `answer`
+    ///
+    /// `query_handler`'s helper, answers `question`, whose name belongs to the special-use
+    /// name `name`.
+    pub fn answer(&self, request: &Packet, question: &Question, name: &str) -> Packet {
+        let mut response = Packet::new();
+        response.add_info(
+            request.header.id,
+            request.header.recursion_desired,
+            true,
+            true,
+            ResultCode::NOERROR,
+        );
+        // The special-use names are served locally, we are their authority (RFC 6303)
+        response.header.authoritative_answer = true;
+        response.questions = request.questions.clone();
+        let qname = question.qname.trim_end_matches('.').to_lowercase();
+        let domain = question.qname.clone();
+        let ttl = SPECIAL_USE_TTL;
+        match (name, question.qtype) {
+            // Every name below localhost is the loopback (RFC 6761 section 6.3)
+            ("localhost", QueryType::A) => response.answers.push(Record::A {
+                domain,
+                addr: Ipv4Addr::LOCALHOST,
+                ttl,
+            }),
+            ("localhost", QueryType::AAAA) => response.answers.push(Record::AAAA {
+                domain,
+                addr: Ipv6Addr::LOCALHOST,
+                ttl,
+            }),
+            ("localhost", _) => {}
+            (_, QueryType::PTR) if qname == LOOPBACK_V4_PTR || qname == LOOPBACK_V6_PTR => {
+                response.answers.push(Record::PTR {
+                    domain,
+                    host: "localhost".into(),
+                    ttl,
+                })
+            }
+            _ => response.header.rescode = ResultCode::NXDOMAIN,
+        }
+        if response.answers.is_empty() {
+            response.authorities.push(Record::SOA {
+                domain: name.into(),
+                mname: SOA_MNAME.into(),
+                rname: SOA_RNAME.into(),
+                serial: 1,
+                refresh: 3600,
+                retry: 600,
+                expire: 86400,
+                minimum: ttl,
+                ttl,
+            });
+        }
+        response
+    }
+}
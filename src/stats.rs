@@ -0,0 +1,215 @@
+use std::{
+    collections::HashMap,
+    mem,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::{DateTime, Local, Utc};
+use serde::Serialize;
+
+use crate::{
+    configuration::Settings,
+    storage::{StatCount, Storage},
+    structs::auxiliaries::CResult,
+};
+
+/// How often the counts kept in memory are added to the database.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+/// How often the hourly counts are rolled up and the old counts removed.
+const ROLLUP_INTERVAL: Duration = Duration::from_secs(3600);
+/// Length of the buckets the counts are written to, in seconds.
+pub const HOUR: i64 = 3600;
+/// Length of the buckets the hourly counts are rolled up into, in seconds.
+pub const DAY: i64 = 86400;
+/// Number of keys counted in memory between two flushes, the keys beyond it are
+/// counted as `OVERFLOW_KEY`: a flood of random names can't exhaust the memory.
+const MAX_PENDING_KEYS: usize = 100_000;
+/// Key the queries are counted under once `MAX_PENDING_KEYS` is reached.
+const OVERFLOW_KEY: &str = "(other)";
+/// Age after which the hourly counts are rolled up into daily ones, in seconds.
+const HOURLY_RETENTION: i64 = 2 * DAY;
+
+/// # `StatKind`
+///
+/// What the queries are counted by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StatKind {
+    Domain,
+    Client,
+    QType,
+}
+
+impl StatKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StatKind::Domain => "domain",
+            StatKind::Client => "client",
+            StatKind::QType => "qtype",
+        }
+    }
+}
+
+/// # `StatRow`
+///
+/// The queries counted for a domain, a client or a query type.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, sqlx::FromRow)]
+pub struct StatRow {
+    pub key: String,
+    pub queries: i64,
+    pub blocked: i64,
+    /// Name of the client in the client table, for the rows of the clients.
+    #[sqlx(skip)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+/// # `QueryStats`
+///
+/// Counts of the queries answered, by domain, client and query type, along with
+/// how many of them were blocked.
+/// The counts are kept in memory and added to the hourly buckets of the storage
+/// every `FLUSH_INTERVAL`; the hourly buckets are rolled up into daily ones once they
+/// are `HOURLY_RETENTION` old, the daily ones are kept for the retention configured.
+#[derive(Debug)]
+pub struct QueryStats {
+    storage: Arc<dyn Storage>,
+    retention_days: u32,
+    pending: Mutex<HashMap<(StatKind, String), (i64, i64)>>,
+}
+
+impl QueryStats {
+    pub fn new(storage: Arc<dyn Storage>, retention_days: u32) -> Self {
+        QueryStats {
+            storage,
+            retention_days,
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// # `from_settings`
+    ///
+    /// `None` if the statistics are disabled.
+    pub fn from_settings(settings: &Settings, storage: Arc<dyn Storage>) -> Option<Self> {
+        settings
+            .get_stats_enabled()
+            .then(|| QueryStats::new(storage, settings.get_stats_retention_days()))
+    }
+
+    /// # `record`
+    ///
+    /// Counts a query for `qname` of type `qtype` sent by `client`.
+    /// Past `MAX_PENDING_KEYS` the keys not counted yet are counted as `OVERFLOW_KEY`.
+    pub fn record(&self, qname: &str, qtype: &str, client: IpAddr, blocked: bool) {
+        let blocked = blocked as i64;
+        let mut pending = self.pending.lock().unwrap();
+        for key in [
+            (StatKind::Domain, qname.trim_end_matches('.').to_lowercase()),
+            (StatKind::Client, client.to_string()),
+            (StatKind::QType, qtype.to_string()),
+        ] {
+            let key = if pending.len() >= MAX_PENDING_KEYS && !pending.contains_key(&key) {
+                (key.0, OVERFLOW_KEY.to_string())
+            } else {
+                key
+            };
+            let count = pending.entry(key).or_default();
+            count.0 += 1;
+            count.1 += blocked;
+        }
+    }
+
+    /// # `run`
+    ///
+    /// Writes the counts every `FLUSH_INTERVAL` and rolls them up every `ROLLUP_INTERVAL`.
+    pub async fn run(self: Arc<Self>) {
+        let mut flush = tokio::time::interval(FLUSH_INTERVAL);
+        let mut rollup = tokio::time::interval(ROLLUP_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = flush.tick() => {
+                    if let Err(e) = self.flush().await {
+                        tracing::warn!("Unable to write the query statistics: {}", e);
+                    }
+                }
+                _ = rollup.tick() => {
+                    if let Err(e) = self.rollup().await {
+                        tracing::warn!("Unable to roll up the query statistics: {}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// # `flush`
+    ///
+    /// Adds the counts kept in memory to the bucket of the current hour.
+    /// The counts are lost if they can't be written.
+    pub async fn flush(&self) -> CResult<()> {
+        let pending = mem::take(&mut *self.pending.lock().unwrap());
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let now = Utc::now().timestamp();
+        let counts: Vec<StatCount> = pending.into_iter().collect();
+        self.storage.add_stats(now - now % HOUR, &counts).await
+    }
+
+    /// # `rollup`
+    ///
+    /// Rolls the hourly counts older than `HOURLY_RETENTION` up into daily ones
+    /// and removes the counts older than the retention.
+    #[tracing::instrument("Rolling up the query statistics", skip(self))]
+    pub async fn rollup(&self) -> CResult<()> {
+        let now = Utc::now().timestamp();
+        self.storage
+            .rollup_stats(
+                now - HOURLY_RETENTION,
+                now - self.retention_days as i64 * DAY,
+            )
+            .await
+    }
+}
+
+/// # `top`
+///
+/// Returns the `limit` keys of `kind` with the most queries since `since`,
+/// along with their counts. The counts not yet written aren't included and
+/// `since` is rounded down to the hour; the counts older than two days are kept
+/// by day, a day is only included if it starts after `since`.
+pub async fn top(
+    storage: &dyn Storage,
+    kind: StatKind,
+    since: DateTime<Local>,
+    limit: u32,
+) -> CResult<Vec<StatRow>> {
+    storage
+        .stats_top(kind, bucket_of(since), limit, false)
+        .await
+}
+
+/// # `top_blocked`
+///
+/// Like `top`, by the number of queries blocked, the keys never blocked are left out.
+pub async fn top_blocked(
+    storage: &dyn Storage,
+    kind: StatKind,
+    since: DateTime<Local>,
+    limit: u32,
+) -> CResult<Vec<StatRow>> {
+    storage.stats_top(kind, bucket_of(since), limit, true).await
+}
+
+/// # `totals`
+///
+/// Returns the number of queries answered since `since` and how many of them were blocked.
+pub async fn totals(storage: &dyn Storage, since: DateTime<Local>) -> CResult<(i64, i64)> {
+    storage.stats_totals(bucket_of(since)).await
+}
+
+/// The start of the hour `time` falls in.
+fn bucket_of(time: DateTime<Local>) -> i64 {
+    let time = time.timestamp();
+    time - time % HOUR
+}
@@ -0,0 +1,130 @@
+use std::{fmt::Debug, net::IpAddr, sync::Arc};
+
+use chrono::{DateTime, Local};
+use futures::future::BoxFuture;
+use serde::Deserialize;
+use sqlx::SqlitePool;
+
+use crate::{
+    cachecheck::CacheRow,
+    cachewriter::CacheEntry,
+    configuration::Settings,
+    stats::{StatKind, StatRow},
+    structs::{auxiliaries::CResult, db_queries::CachedRecord, questions_and_records::QueryType},
+};
+
+mod postgres;
+mod sqlite;
+
+pub use postgres::PostgresStorage;
+pub use sqlite::SqliteStorage;
+
+/// Queries counted for a key of a kind, along with how many of them were blocked.
+pub type StatCount = ((StatKind, String), (i64, i64));
+
+/// # `StorageKind`
+///
+/// The databases the cache and the statistics can be kept in.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageKind {
+    /// The SQLite database of the server, where the blocklist and the query log are.
+    #[default]
+    Sqlite,
+    /// A PostgreSQL server, shared by the replicas of the resolver.
+    Postgres,
+}
+
+/// # `Storage`
+///
+/// Where the records cached and the counts of the queries are kept: the SQLite
+/// database of the server or a database shared by several servers.
+/// The expired records are returned as well, the callers check them.
+pub trait Storage: Debug + Send + Sync {
+    /// # `cached`
+    ///
+    /// The records of `domain`, whatever its case, of type `qtype`, or of every type if `None`,
+    /// that may be served to `client`: the ones given for a client subnet only
+    /// if `client` is in it, none of them if `client` is `None`.
+    fn cached<'a>(
+        &'a self,
+        domain: &'a str,
+        qtype: Option<QueryType>,
+        client: Option<IpAddr>,
+    ) -> BoxFuture<'a, CResult<Vec<CachedRecord>>>;
+
+    /// # `cached_all`
+    ///
+    /// Every record of the cache, ordered by domain.
+    fn cached_all(&self) -> BoxFuture<'_, CResult<Vec<CachedRecord>>>;
+
+    /// # `cached_rows`
+    ///
+    /// Every row of the cache as it's stored, the malformed ones included, ordered by id.
+    fn cached_rows(&self) -> BoxFuture<'_, CResult<Vec<CacheRow>>>;
+
+    /// # `write_cache`
+    ///
+    /// Writes `batch` in a transaction, refreshing the records cached already.
+    fn write_cache<'a>(&'a self, batch: &'a [CacheEntry]) -> BoxFuture<'a, CResult<()>>;
+
+    /// # `delete_cached`
+    ///
+    /// Deletes the record whose id is `id`.
+    fn delete_cached(&self, id: i64) -> BoxFuture<'_, CResult<()>>;
+
+    /// # `flush_cache`
+    ///
+    /// Deletes the records of `domain`, every record if `None`,
+    /// returns how many have been deleted.
+    fn flush_cache<'a>(&'a self, domain: Option<&'a str>) -> BoxFuture<'a, CResult<u64>>;
+
+    /// # `purge_expired`
+    ///
+    /// Deletes the records expired before `now`, returns how many have been deleted.
+    fn purge_expired(&self, now: DateTime<Local>) -> BoxFuture<'_, CResult<u64>>;
+
+    /// # `add_stats`
+    ///
+    /// Adds `counts` to the hourly bucket starting at `bucket`.
+    fn add_stats<'a>(&'a self, bucket: i64, counts: &'a [StatCount]) -> BoxFuture<'a, CResult<()>>;
+
+    /// # `rollup_stats`
+    ///
+    /// Rolls the hourly buckets starting before `hourly_cutoff` up into daily ones
+    /// and removes the buckets starting before `cutoff`.
+    fn rollup_stats(&self, hourly_cutoff: i64, cutoff: i64) -> BoxFuture<'_, CResult<()>>;
+
+    /// # `stats_totals`
+    ///
+    /// The queries counted in the buckets starting from `since` and how many of them were blocked.
+    fn stats_totals(&self, since: i64) -> BoxFuture<'_, CResult<(i64, i64)>>;
+
+    /// # `stats_top`
+    ///
+    /// The `limit` keys of `kind` with the most queries in the buckets starting from `since`,
+    /// or with the most queries blocked if `blocked`, leaving out the ones never blocked.
+    fn stats_top(
+        &self,
+        kind: StatKind,
+        since: i64,
+        limit: u32,
+        blocked: bool,
+    ) -> BoxFuture<'_, CResult<Vec<StatRow>>>;
+
+    /// # `close`
+    ///
+    /// Closes the connections, waiting for the queries running.
+    fn close(&self) -> BoxFuture<'_, ()>;
+}
+
+/// # `open`
+///
+/// The storage of the settings: `db_pool`, the database of the server, already migrated,
+/// unless another one is configured, connected to and migrated here.
+pub async fn open(settings: &Settings, db_pool: SqlitePool) -> CResult<Arc<dyn Storage>> {
+    Ok(match settings.get_db_kind() {
+        StorageKind::Sqlite => Arc::new(SqliteStorage::new(db_pool)),
+        StorageKind::Postgres => Arc::new(PostgresStorage::connect(settings).await?),
+    })
+}
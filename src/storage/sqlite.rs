@@ -0,0 +1,257 @@
+use std::net::IpAddr;
+
+use chrono::{DateTime, Local};
+use futures::future::BoxFuture;
+use sqlx::{QueryBuilder, Sqlite, SqlitePool};
+
+use crate::{
+    cachecheck::CacheRow,
+    cachewriter::CacheEntry,
+    ecs::address_key,
+    stats::{StatKind, StatRow, DAY, HOUR},
+    structs::{
+        auxiliaries::CResult,
+        db_queries::CachedRecord,
+        questions_and_records::{QueryType, Record},
+    },
+};
+
+use super::{StatCount, Storage};
+
+/// Rows written by a single statement.
+const INSERT_CHUNK: usize = 128;
+
+/// # `SqliteStorage`
+///
+/// Keeps the cache and the statistics in the SQLite database of the server.
+#[derive(Debug, Clone)]
+pub struct SqliteStorage {
+    db_pool: SqlitePool,
+}
+
+impl SqliteStorage {
+    pub fn new(db_pool: SqlitePool) -> Self {
+        SqliteStorage { db_pool }
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn cached<'a>(
+        &'a self,
+        domain: &'a str,
+        qtype: Option<QueryType>,
+        client: Option<IpAddr>,
+    ) -> BoxFuture<'a, CResult<Vec<CachedRecord>>> {
+        Box::pin(async move {
+            let client = client.map(address_key);
+            let records = match qtype {
+                Some(qtype) => sqlx::query_as::<_, CachedRecord>(r#"SELECT id, address, host, priority, domain, expiration_date, ttl, record_type, ecs_network, rdata FROM entries WHERE (domain = $1 COLLATE NOCASE AND record_type = $2 AND (ecs_network IS NULL OR (ecs_first <= $3 AND ecs_last >= $3)))"#)
+                    .bind(domain)
+                    .bind(qtype.to_num())
+                    .bind(&client)
+                    .fetch_all(&self.db_pool)
+                    .await?,
+                None => sqlx::query_as::<_, CachedRecord>(r#"SELECT id, address, host, priority, domain, expiration_date, ttl, record_type, ecs_network, rdata FROM entries WHERE (domain = $1 COLLATE NOCASE AND (ecs_network IS NULL OR (ecs_first <= $2 AND ecs_last >= $2)))"#)
+                    .bind(domain)
+                    .bind(&client)
+                    .fetch_all(&self.db_pool)
+                    .await?,
+            };
+            Ok(records)
+        })
+    }
+
+    fn cached_all(&self) -> BoxFuture<'_, CResult<Vec<CachedRecord>>> {
+        Box::pin(async move {
+            let records = sqlx::query_as::<_, CachedRecord>(r#"SELECT id, address, host, priority, domain, expiration_date, ttl, record_type, ecs_network, rdata FROM entries ORDER BY domain"#)
+                .fetch_all(&self.db_pool)
+                .await?;
+            Ok(records)
+        })
+    }
+
+    fn cached_rows(&self) -> BoxFuture<'_, CResult<Vec<CacheRow>>> {
+        Box::pin(async move {
+            // Cast, the columns may hold values of any type
+            let rows = sqlx::query_as::<_, CacheRow>(r#"SELECT id, CAST(domain AS TEXT) AS domain, CAST(record_type AS INTEGER) AS record_type, CAST(ttl AS INTEGER) AS ttl, CAST(address AS TEXT) AS address, CAST(host AS TEXT) AS host, CAST(ecs_network AS TEXT) AS ecs_network, CAST(rdata AS BLOB) AS rdata FROM entries ORDER BY id"#)
+                .fetch_all(&self.db_pool)
+                .await?;
+            Ok(rows)
+        })
+    }
+
+    fn write_cache<'a>(&'a self, batch: &'a [CacheEntry]) -> BoxFuture<'a, CResult<()>> {
+        Box::pin(async move {
+            let mut transaction = self.db_pool.begin().await?;
+            for entry in batch {
+                let record = &entry.record;
+                // The data is stored whole, the address or the host also apart, for the ones reading them
+                let (address, host, priority) = match record {
+                    Record::A { addr, .. } => (Some(addr.to_string()), None, None),
+                    Record::AAAA { addr, .. } => (Some(addr.to_string()), None, None),
+                    Record::NS { host, .. }
+                    | Record::CNAME { host, .. }
+                    | Record::PTR { host, .. } => (None, Some(host), None),
+                    Record::MX { priority, host, .. } => (None, Some(host), Some(*priority)),
+                    _ => (None, None, None),
+                };
+                // A record cached already is refreshed
+                sqlx::query(r#"INSERT INTO entries (address, host, priority, domain, expiration_date, ttl, record_type, ecs_network, ecs_first, ecs_last, rdata) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+                    ON CONFLICT (domain COLLATE NOCASE, record_type, rdata, COALESCE(ecs_network, ''))
+                    DO UPDATE SET expiration_date = excluded.expiration_date, ttl = excluded.ttl"#)
+                    .bind(address)
+                    .bind(host)
+                    .bind(priority)
+                    .bind(record.get_domain())
+                    .bind(entry.expiration_date)
+                    .bind(record.get_ttl())
+                    .bind(record.get_qtype().to_num())
+                    .bind(entry.ecs_network.map(|network| network.to_string()))
+                    // The range of the network, for the lookups of the clients in it
+                    .bind(entry.ecs_network.map(|network| address_key(network.network())))
+                    .bind(entry.ecs_network.map(|network| address_key(network.broadcast())))
+                    .bind(record.wire_rdata()?)
+                    .execute(&mut *transaction)
+                    .await?;
+            }
+            transaction.commit().await?;
+            Ok(())
+        })
+    }
+
+    fn delete_cached(&self, id: i64) -> BoxFuture<'_, CResult<()>> {
+        Box::pin(async move {
+            sqlx::query(r#"DELETE FROM entries WHERE (id = $1)"#)
+                .bind(id)
+                .execute(&self.db_pool)
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn flush_cache<'a>(&'a self, domain: Option<&'a str>) -> BoxFuture<'a, CResult<u64>> {
+        Box::pin(async move {
+            let result = match domain {
+                Some(domain) => {
+                    sqlx::query(r#"DELETE FROM entries WHERE (domain = $1 COLLATE NOCASE)"#)
+                        .bind(domain)
+                        .execute(&self.db_pool)
+                        .await?
+                }
+                None => {
+                    sqlx::query(r#"DELETE FROM entries"#)
+                        .execute(&self.db_pool)
+                        .await?
+                }
+            };
+            Ok(result.rows_affected())
+        })
+    }
+
+    fn purge_expired(&self, now: DateTime<Local>) -> BoxFuture<'_, CResult<u64>> {
+        Box::pin(async move {
+            let result = sqlx::query(r#"DELETE FROM entries WHERE (expiration_date < $1)"#)
+                .bind(now)
+                .execute(&self.db_pool)
+                .await?;
+            Ok(result.rows_affected())
+        })
+    }
+
+    fn add_stats<'a>(&'a self, bucket: i64, counts: &'a [StatCount]) -> BoxFuture<'a, CResult<()>> {
+        Box::pin(async move {
+            let mut transaction = self.db_pool.begin().await?;
+            for chunk in counts.chunks(INSERT_CHUNK) {
+                let mut query: QueryBuilder<Sqlite> = QueryBuilder::new(
+                    "INSERT INTO query_stats (bucket, span, kind, key, queries, blocked) ",
+                );
+                query.push_values(chunk, |mut row, ((kind, key), (queries, blocked))| {
+                    row.push_bind(bucket)
+                        .push_bind(HOUR)
+                        .push_bind(kind.as_str())
+                        .push_bind(key)
+                        .push_bind(*queries)
+                        .push_bind(*blocked);
+                });
+                query.push(
+                    " ON CONFLICT (bucket, span, kind, key) DO UPDATE SET \
+                    queries = queries + excluded.queries, blocked = blocked + excluded.blocked",
+                );
+                query.build().execute(&mut *transaction).await?;
+            }
+            transaction.commit().await?;
+            Ok(())
+        })
+    }
+
+    fn rollup_stats(&self, hourly_cutoff: i64, cutoff: i64) -> BoxFuture<'_, CResult<()>> {
+        Box::pin(async move {
+            let mut transaction = self.db_pool.begin().await?;
+            sqlx::query(
+                r#"INSERT INTO query_stats (bucket, span, kind, key, queries, blocked)
+                SELECT bucket - bucket % $1, $1, kind, key, SUM(queries), SUM(blocked)
+                FROM query_stats WHERE span = $2 AND bucket < $3
+                GROUP BY bucket - bucket % $1, kind, key
+                ON CONFLICT (bucket, span, kind, key) DO UPDATE SET
+                queries = queries + excluded.queries, blocked = blocked + excluded.blocked"#,
+            )
+            .bind(DAY)
+            .bind(HOUR)
+            .bind(hourly_cutoff)
+            .execute(&mut *transaction)
+            .await?;
+            sqlx::query(r#"DELETE FROM query_stats WHERE span = $1 AND bucket < $2"#)
+                .bind(HOUR)
+                .bind(hourly_cutoff)
+                .execute(&mut *transaction)
+                .await?;
+            sqlx::query(r#"DELETE FROM query_stats WHERE bucket < $1"#)
+                .bind(cutoff)
+                .execute(&mut *transaction)
+                .await?;
+            transaction.commit().await?;
+            Ok(())
+        })
+    }
+
+    fn stats_totals(&self, since: i64) -> BoxFuture<'_, CResult<(i64, i64)>> {
+        Box::pin(async move {
+            let totals: (Option<i64>, Option<i64>) = sqlx::query_as(
+                r#"SELECT SUM(queries), SUM(blocked) FROM query_stats WHERE kind = $1 AND bucket >= $2"#,
+            )
+            .bind(StatKind::QType.as_str())
+            .bind(since)
+            .fetch_one(&self.db_pool)
+            .await?;
+            Ok((totals.0.unwrap_or(0), totals.1.unwrap_or(0)))
+        })
+    }
+
+    fn stats_top(
+        &self,
+        kind: StatKind,
+        since: i64,
+        limit: u32,
+        blocked: bool,
+    ) -> BoxFuture<'_, CResult<Vec<StatRow>>> {
+        Box::pin(async move {
+            let column = if blocked { "blocked" } else { "queries" };
+            let rows = sqlx::query_as::<_, StatRow>(&format!(
+                r#"SELECT key, SUM(queries) AS queries, SUM(blocked) AS blocked FROM query_stats
+                WHERE kind = $1 AND bucket >= $2 GROUP BY key HAVING SUM({0}) > 0
+                ORDER BY SUM({0}) DESC, key LIMIT $3"#,
+                column
+            ))
+            .bind(kind.as_str())
+            .bind(since)
+            .bind(limit)
+            .fetch_all(&self.db_pool)
+            .await?;
+            Ok(rows)
+        })
+    }
+
+    fn close(&self) -> BoxFuture<'_, ()> {
+        Box::pin(self.db_pool.close())
+    }
+}
@@ -1,3 +1,79 @@
-use std::error::Error;
+use std::io;
 
-pub type CResult<T> = std::result::Result<T, Box<dyn Error>>;
+use tokio::time::error::Elapsed;
+
+use crate::{structs::header::ResultCode, tsig::TsigError};
+
+pub type CResult<T> = std::result::Result<T, DnsError>;
+
+/// # `DnsError`
+///
+/// Reasons why handling a message, or some work around it, failed.
+#[derive(Debug, thiserror::Error)]
+pub enum DnsError {
+    /// A read or a write went past the end of the buffer.
+    #[error("End of buffer")]
+    EndOfBuffer,
+    /// The message doesn't follow the wire format.
+    #[error("Malformed packet: {0}")]
+    Malformed(String),
+    /// The name server queried didn't respond in time.
+    #[error("The upstream server didn't respond in time")]
+    UpstreamTimeout,
+    /// The other name servers couldn't resolve the query.
+    #[error("Upstream failure: {0}")]
+    Upstream(String),
+    /// The cache holds something it shouldn't.
+    #[error("Corrupted cache: {0}")]
+    CacheCorruption(String),
+    /// The configuration provided can't be used.
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("HTTP error: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("TSIG error: {0}")]
+    Tsig(#[from] TsigError),
+    /// The content of a transferred zone doesn't match its ZONEMD (RFC 8976).
+    #[error("ZONEMD verification failed: {0}")]
+    Zonemd(String),
+    /// A name given to us, as through the API, isn't a valid domain name.
+    #[error("{0}")]
+    InvalidName(String),
+    #[error("{0}")]
+    Other(String),
+}
+
+impl DnsError {
+    /// # `rescode`
+    ///
+    /// The response code telling the client about the error.
+    pub fn rescode(&self) -> ResultCode {
+        match self {
+            DnsError::EndOfBuffer | DnsError::Malformed(_) => ResultCode::FORMERR,
+            DnsError::Tsig(_) => ResultCode::NOTAUTH,
+            _ => ResultCode::SERVFAIL,
+        }
+    }
+}
+
+impl From<Elapsed> for DnsError {
+    fn from(_: Elapsed) -> Self {
+        DnsError::UpstreamTimeout
+    }
+}
+
+impl From<&str> for DnsError {
+    fn from(e: &str) -> Self {
+        DnsError::Other(e.to_string())
+    }
+}
+
+impl From<String> for DnsError {
+    fn from(e: String) -> Self {
+        DnsError::Other(e)
+    }
+}
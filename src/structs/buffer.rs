@@ -1,26 +1,77 @@
-use super::{auxiliaries::CResult, header::ResultCode, packet::Packet};
+use std::collections::HashMap;
+
+use bytes::{Bytes, BytesMut};
+
+use super::{
+    auxiliaries::{CResult, DnsError},
+    header::ResultCode,
+    name::{DnsName, MAX_LABEL_LEN, MAX_NAME_LEN},
+    packet::Packet,
+};
 
 /// # `BytePacketBuffer`
 ///
-/// Buffer that contains the binary form of a packet
+/// Buffer that contains the binary form of a packet.
+/// Reads are bound to the bytes in the buffer, writes grow it as needed,
+/// so that messages larger than a UDP datagram can be composed.
+/// The bytes written can be handed over without copying them through `freeze`.
 pub struct BytePacketBuffer {
     /// The bytes of the packet
-    pub buf: [u8; 512],
+    pub buf: BytesMut,
     /// Value that keeps track of the position in the buffer
     pos: usize,
+    /// Names read so far by the offset they start at, the names compressed
+    /// with a pointer to one of them share its text
+    names: HashMap<usize, DnsName>,
 }
 
 impl BytePacketBuffer {
     pub fn new() -> Self {
-        let buf = [0; 512];
+        Self::with_size(512)
+    }
+
+    /// # `with_size`
+    ///
+    /// Creates a buffer of `size` bytes, needed to receive messages that don't
+    /// fit in a UDP datagram, like the ones received over TCP.
+    pub fn with_size(size: usize) -> Self {
+        let buf = BytesMut::zeroed(size);
+        let pos = 0;
+        BytePacketBuffer {
+            buf,
+            pos,
+            names: HashMap::new(),
+        }
+    }
+
+    /// # `empty`
+    ///
+    /// Creates an empty buffer, for composing messages of any size.
+    pub fn empty() -> Self {
+        BytePacketBuffer {
+            buf: BytesMut::new(),
+            pos: 0,
+            names: HashMap::new(),
+        }
+    }
+
+    /// # `from_bytes`
+    ///
+    /// Creates a buffer containing a copy of `bytes`, ready to be parsed.
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let buf = BytesMut::from(bytes);
         let pos = 0;
-        BytePacketBuffer { buf, pos }
+        BytePacketBuffer {
+            buf,
+            pos,
+            names: HashMap::new(),
+        }
     }
 
     // TODO: comment
     pub fn new_error_packet(rescode: ResultCode, id: u16) -> CResult<Self> {
         let mut error_packet = Packet::error_packet(rescode, id)?;
-        let mut buffer = Self::new();
+        let mut buffer = Self::empty();
         error_packet.write(&mut buffer)?;
         Ok(buffer)
     }
@@ -30,44 +81,65 @@ impl BytePacketBuffer {
         self.pos
     }
 
+    /// # `written`
+    ///
+    /// The bytes before the current position, the message written so far.
+    pub fn written(&self) -> &[u8] {
+        &self.buf[..self.pos.min(self.buf.len())]
+    }
+
+    /// # `freeze`
+    ///
+    /// Consumes the buffer, returns the bytes before the current position without copying them.
+    pub fn freeze(mut self) -> Bytes {
+        self.buf.truncate(self.pos);
+        self.buf.freeze()
+    }
+
+    /// # `slice`
+    ///
+    /// Borrows `len` bytes starting at `start`, the only place reads check the bounds.
+    fn slice(&self, start: usize, len: usize) -> CResult<&[u8]> {
+        start
+            .checked_add(len)
+            .and_then(|end| self.buf.get(start..end))
+            .ok_or(DnsError::EndOfBuffer)
+    }
+
+    /// # `read_array`
+    ///
+    /// Reads `N` bytes, advances the cursor accordingly.
+    fn read_array<const N: usize>(&mut self) -> CResult<[u8; N]> {
+        let mut res = [0; N];
+        res.copy_from_slice(self.slice(self.pos, N)?);
+        self.pos += N;
+        Ok(res)
+    }
+
     /// Reads one byte, advances the cursor accordingly,
     /// returns the byte read or an error
     /// if tried to read a byte that is out of bound
     pub fn read_u8(&mut self) -> CResult<u8> {
-        if self.pos >= 512 {
-            return Err("End of buffer".into());
-        }
-
-        let res = self.buf[self.pos];
-        self.pos = self.pos + 1;
-        Ok(res)
+        Ok(u8::from_be_bytes(self.read_array()?))
     }
 
     /// Reads two bytes, advances the cursor accordingly,
     /// returns the bytes read or an error
     /// if tried to read a byte that is out of bound
     pub fn read_u16(&mut self) -> CResult<u16> {
-        let res = ((self.read_u8()? as u16) << 8) | (self.read_u8()? as u16);
-        Ok(res)
+        Ok(u16::from_be_bytes(self.read_array()?))
     }
 
     /// Reads four bytes, advances the cursor accordingly,
     /// returns the bytes read or an error
     /// if tried to read a byte that is out of bound
     pub fn read_u32(&mut self) -> CResult<u32> {
-        let res = ((self.read_u8()? as u32) << 24)
-            | ((self.read_u8()? as u32) << 16)
-            | ((self.read_u8()? as u32) << 8)
-            | (self.read_u8()? as u32);
-        Ok(res)
+        Ok(u32::from_be_bytes(self.read_array()?))
     }
 
     /// Get a single byte, without changing the buffer position
     pub fn get(&self, pos: usize) -> CResult<u8> {
-        if pos >= 512 {
-            return Err("End of buffer".into());
-        }
-        Ok(self.buf[pos])
+        Ok(self.slice(pos, 1)?[0])
     }
 
     /// Change buffer position
@@ -78,10 +150,7 @@ impl BytePacketBuffer {
 
     /// Get a range of bytes, doesn't change the current position
     pub fn get_range(&self, start: usize, len: usize) -> CResult<&[u8]> {
-        if start + len >= 512 {
-            return Err("End of buffer".into());
-        }
-        Ok(&self.buf[start..start + len as usize])
+        self.slice(start, len)
     }
 
     /// Step the buffer position forward a specific number of steps
@@ -95,6 +164,8 @@ impl BytePacketBuffer {
     /// Reads domain names, taking labels into consideration.
     /// Will take something like \[3\]www\[6\]google\[3\]com\[0\] and append
     /// www.google.com to the `&mut String` provided.
+    /// The case of the labels is kept as it was sent, the names are compared
+    /// ignoring it.
     pub fn read_qname(&mut self, outstr: &mut String) -> CResult<()> {
         // Since we might encounter jumps, we'll keep track of our position
         // locally as opposed to using the position within the struct. This
@@ -109,11 +180,16 @@ impl BytePacketBuffer {
         // delimiter, starts as an empty str and mutes into "." after the first
         // iteration of the loop
         let mut delim = "";
+        // Length of the name on the wire, the root label included
+        let mut wire_len = 1;
 
         loop {
             // Limiting the maximum number of jumps to avoid eventual infinite cycles
             if jumps_performed > max_jumps {
-                return Err(format!("Limit of {} jumps exceeded", max_jumps).into());
+                return Err(DnsError::Malformed(format!(
+                    "Limit of {} jumps exceeded",
+                    max_jumps
+                )));
             }
             // Beginning of the label, labels strat with length in bytes
             let len = self.get(pos)?;
@@ -144,13 +220,27 @@ impl BytePacketBuffer {
             if len == 0 {
                 break;
             }
+            // The other label types (0x40 and 0x80) aren't in use
+            if len as usize > MAX_LABEL_LEN {
+                return Err(DnsError::Malformed(format!(
+                    "Unsupported label of type {:#04x}",
+                    len & 0xC0
+                )));
+            }
+            wire_len += 1 + len as usize;
+            if wire_len > MAX_NAME_LEN {
+                return Err(DnsError::Malformed(format!(
+                    "The name exceeds {} bytes of length",
+                    MAX_NAME_LEN
+                )));
+            }
 
             // Append the delimiter to our output buffer first.
             outstr.push_str(delim);
             // Extract the actual ASCII bytes for this label and append them
             // to the output buffer.
             let str_buffer = self.get_range(pos, len as usize)?;
-            outstr.push_str(&String::from_utf8_lossy(str_buffer).to_lowercase());
+            outstr.push_str(&String::from_utf8_lossy(str_buffer));
 
             delim = ".";
             // Move forward the full length of the label.
@@ -164,24 +254,54 @@ impl BytePacketBuffer {
         Ok(())
     }
 
+    /// # `read_name`
+    ///
+    /// Reads a domain name as `read_qname` does, the name is shared with the other
+    /// names of the packet that are compressed pointers to the same offset.
+    pub fn read_name(&mut self) -> CResult<DnsName> {
+        let start = self.pos;
+        let first = self.get(start)?;
+        // The whole name is a pointer to a name met before
+        let offset = if (first & 0xC0) == 0xC0 {
+            let offset = (((first as usize) ^ 0xC0) << 8) | self.get(start + 1)? as usize;
+            if let Some(name) = self.names.get(&offset) {
+                let name = name.clone();
+                self.seek(start + 2)?;
+                return Ok(name);
+            }
+            offset
+        } else {
+            start
+        };
+        let mut name = String::new();
+        self.read_qname(&mut name)?;
+        let name = DnsName::from(name);
+        self.names.insert(offset, name.clone());
+        Ok(name)
+    }
+
     pub fn write_u8(&mut self, val: u8) -> CResult<()> {
-        if self.pos >= 512 {
-            return Err("End of buffer".into());
-        }
-        self.buf[self.pos] = val;
-        self.pos = self.pos + 1;
-        Ok(())
+        self.write_bytes(&[val])
     }
 
     pub fn write_u16(&mut self, val: u16) -> CResult<()> {
-        self.write_u8((val >> 8) as u8)?;
-        self.write_u8((val & 0xFF) as u8)?;
-        Ok(())
+        self.write_bytes(&val.to_be_bytes())
     }
 
     pub fn write_u32(&mut self, val: u32) -> CResult<()> {
-        self.write_u16((val >> 16) as u16)?;
-        self.write_u16((val & 0xFFFF) as u16)?;
+        self.write_bytes(&val.to_be_bytes())
+    }
+
+    /// # `write_bytes`
+    ///
+    /// Writes a sequence of raw bytes on the buffer, growing it if needed.
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> CResult<()> {
+        let end = self.pos + bytes.len();
+        if end > self.buf.len() {
+            self.buf.resize(end, 0);
+        }
+        self.buf[self.pos..end].copy_from_slice(bytes);
+        self.pos = end;
         Ok(())
     }
 
@@ -189,16 +309,14 @@ impl BytePacketBuffer {
     ///
     /// Formats and write the provided name on the buffer in the
     /// form of a stream of bytes, if possible.
+    /// The names that don't respect the limits of `DnsName::validate` are refused.
     pub fn write_qname(&mut self, qname: &str) -> CResult<()> {
-        for label in qname.split('.') {
-            let len = label.len();
-            if len > 0x34 {
-                return Err("Single label exceeds 63 characters of length".into());
-            }
-
-            self.write_u8(len as u8)?;
-            for b in label.as_bytes() {
-                self.write_u8(*b)?;
+        DnsName::validate(qname)?;
+        let qname = qname.strip_suffix('.').unwrap_or(qname);
+        if !qname.is_empty() {
+            for label in qname.split('.') {
+                self.write_u8(label.len() as u8)?;
+                self.write_bytes(label.as_bytes())?;
             }
         }
 
@@ -207,18 +325,10 @@ impl BytePacketBuffer {
         Ok(())
     }
 
-    fn set_u8(&mut self, pos: usize, val: u8) -> CResult<()> {
-        if pos >= 512 {
-            return Err("End of buffer".into());
-        }
-        self.buf[pos] = val;
-
-        Ok(())
-    }
-
+    /// Overwrites two bytes already in the buffer, doesn't change the current position
     pub fn set_u16(&mut self, pos: usize, val: u16) -> CResult<()> {
-        self.set_u8(pos, (val >> 8) as u8)?;
-        self.set_u8(pos + 1, (val & 0xFF) as u8)?;
+        self.slice(pos, 2)?;
+        self.buf[pos..pos + 2].copy_from_slice(&val.to_be_bytes());
         Ok(())
     }
 }
@@ -1,36 +1,36 @@
-use std::{
-    error::Error,
-    net::{Ipv4Addr, Ipv6Addr},
-    str::FromStr,
-};
-
 use chrono::{DateTime, Local};
-use sqlx::SqlitePool;
+
+use crate::{clock::Clock, storage::Storage};
 
 use super::{
-    auxiliaries::CResult,
+    auxiliaries::{CResult, DnsError},
+    name::DnsName,
     questions_and_records::{QueryType, Record},
 };
 
 #[derive(Debug, sqlx::FromRow, Clone)]
 pub struct CachedRecord {
-    pub id: u32,
+    pub id: i64,
     pub address: Option<String>,
-    pub host: Option<String>,
+    pub host: Option<DnsName>,
     pub priority: Option<u16>,
-    pub domain: String,
+    pub domain: DnsName,
     pub expiration_date: DateTime<Local>,
     pub ttl: u32,
     pub record_type: u16,
+    /// Network of the clients the record may be served to, every client if `None`.
+    pub ecs_network: Option<String>,
+    /// Data section of the record, as it's written on the wire.
+    pub rdata: Vec<u8>,
 }
 
 impl CachedRecord {
     /// # `is_valid`
     ///
-    /// Returns true if the record isn't expired,
+    /// Returns true if the record isn't expired according to `clock`,
     /// false otherwise.
-    pub fn is_valid(&self) -> bool {
-        let now = Local::now();
+    pub fn is_valid(&self, clock: &dyn Clock) -> bool {
+        let now = clock.now();
         if self.expiration_date >= now {
             return true;
         }
@@ -40,114 +40,56 @@ impl CachedRecord {
     /// # `record_from_cache`
     ///
     /// This method returns a `Result` that may contain a `Record` ready
-    /// to be inserted into a `Packet`.
-    /// This method consumes the `CachedRecord` instance.
+    /// to be inserted into a `Packet`, read from the data section stored.
     /// If an error is returned from this method it means that we have records
     /// in our cache that are wrongly formatted, meaning we have a serious problem.
-    pub fn record_from_cache(&self) -> Result<Record, Box<dyn Error>> {
-        let record_type = QueryType::from_num(self.record_type);
-        match record_type {
-            QueryType::A => {
-                let raw_addr = match &self.address {
-                    Some(ra) => ra,
-                    None => {
-                        return Err("Some records haven't been stored correctly".into());
-                    }
-                };
-                let addr = match Ipv4Addr::from_str(raw_addr) {
-                    Ok(a) => a,
-                    Err(e) => {
-                        return Err(e.into());
-                    }
-                };
-                return Ok(Record::A {
-                    domain: self.domain.clone(),
-                    addr,
-                    ttl: self.ttl,
-                });
-            }
-            QueryType::CNAME => {
-                let host = match &self.host {
-                    Some(h) => h.clone(),
-                    None => {
-                        return Err("Some records haven't been stored correctly".into());
-                    }
-                };
-                return Ok(Record::CNAME {
-                    domain: self.domain.clone(),
-                    host,
-                    ttl: self.ttl,
-                });
-            }
-            QueryType::MX => {
-                let host = match &self.host {
-                    Some(h) => h,
-                    None => {
-                        return Err("Some records haven't been stored correctly".into());
-                    }
-                };
-                let priority = match self.priority {
-                    Some(p) => p,
-                    None => {
-                        return Err("Some records haven't been stored correctly".into());
-                    }
-                };
-                return Ok(Record::MX {
-                    domain: self.domain.clone(),
-                    priority,
-                    host: host.clone(),
-                    ttl: self.ttl,
-                });
-            }
-            QueryType::AAAA => {
-                let raw_addr = match &self.address {
-                    Some(ra) => ra,
-                    None => {
-                        return Err("Some records haven't been stored correctly".into());
-                    }
-                };
-                let addr = match Ipv6Addr::from_str(raw_addr) {
-                    Ok(a) => a,
-                    Err(e) => {
-                        return Err(e.into());
-                    }
-                };
-                return Ok(Record::AAAA {
-                    domain: self.domain.clone(),
-                    addr,
-                    ttl: self.ttl,
-                });
-            }
-            _other => {
-                return Err("This should not happen.".into());
-            }
-        };
+    pub fn record_from_cache(&self) -> CResult<Record> {
+        Record::from_wire_rdata(
+            self.domain.clone(),
+            QueryType::from_num(self.record_type),
+            self.ttl,
+            &self.rdata,
+        )
+        .map_err(|e| DnsError::CacheCorruption(e.to_string()))
     }
 
     /// `Delete From DB`
     ///
-    /// Deletes the cached record from the database
+    /// Deletes the cached record from the storage
     #[tracing::instrument(
         "Deleting entry from the cache database."
-        skip(self, db_pool)
+        skip(self, storage)
         fields(
-            domain_name = self.domain,
+            domain_name = self.domain.as_str(),
         )
     )]
-    pub async fn delete_from_db(&self, db_pool: &SqlitePool) -> CResult<()> {
-        match sqlx::query(r#"DELETE FROM entries WHERE (id = $1)"#)
-            .bind(self.id)
-            .execute(db_pool)
+    pub async fn delete_from_db(&self, storage: &dyn Storage) -> CResult<()> {
+        storage.delete_cached(self.id).await
+    }
+
+    /// # `fetch_all`
+    ///
+    /// Returns every record of the cache, the expired ones included.
+    pub async fn fetch_all(storage: &dyn Storage) -> CResult<Vec<CachedRecord>> {
+        storage.cached_all().await
+    }
+
+    /// # `flush`
+    ///
+    /// Deletes the records of `domain` from the cache, every record
+    /// if `domain` is `None`, returns how many have been deleted.
+    pub async fn flush(storage: &dyn Storage, domain: Option<&str>) -> CResult<u64> {
+        storage
+            .flush_cache(domain.map(|domain| domain.trim_end_matches('.')))
             .await
-        {
-            Ok(_) => {
-                return Ok(());
-            }
-            Err(e) => {
-                // If this variant is found it means we have incorrect
-                // data in our chache
-                return Err(e.into());
-            }
-        }
+    }
+
+    /// # `purge_expired`
+    ///
+    /// Deletes the records expired according to `clock` from the cache,
+    /// returns how many have been deleted.
+    #[tracing::instrument("Purging the expired entries of the cache.", skip(storage, clock))]
+    pub async fn purge_expired(storage: &dyn Storage, clock: &dyn Clock) -> CResult<u64> {
+        storage.purge_expired(clock.now()).await
     }
 }
@@ -1,6 +1,10 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
 use super::{auxiliaries::CResult, buffer::BytePacketBuffer};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Header {
     /// A random identifier assigned to query packets.
     /// Response packets must reply with the same id.
@@ -143,7 +147,43 @@ impl Header {
     }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+impl fmt::Display for Header {
+    /// The two lines of the header `dig` prints.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let opcode = match OpCode::from_num(self.opcode) {
+            OpCode::UNKNOWN(num) => format!("OPCODE{}", num),
+            opcode => format!("{:?}", opcode),
+        };
+        writeln!(
+            f,
+            ";; ->>HEADER<<- opcode: {}, status: {:?}, id: {}",
+            opcode, self.rescode, self.id
+        )?;
+        let flags: Vec<&str> = [
+            (self.response, "qr"),
+            (self.authoritative_answer, "aa"),
+            (self.truncated_message, "tc"),
+            (self.recursion_desired, "rd"),
+            (self.recursion_available, "ra"),
+            (self.authed_data, "ad"),
+            (self.checking_disabled, "cd"),
+        ]
+        .into_iter()
+        .filter_map(|(set, flag)| set.then_some(flag))
+        .collect();
+        write!(
+            f,
+            ";; flags: {}; QUERY: {}, ANSWER: {}, AUTHORITY: {}, ADDITIONAL: {}",
+            flags.join(" "),
+            self.questions,
+            self.answers,
+            self.authoritative_entries,
+            self.resource_entries
+        )
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ResultCode {
     NOERROR = 0,
     FORMERR = 1,
@@ -151,6 +191,7 @@ pub enum ResultCode {
     NXDOMAIN = 3,
     NOTIMP = 4,
     REFUSED = 5,
+    NOTAUTH = 9,
 }
 
 impl ResultCode {
@@ -161,7 +202,45 @@ impl ResultCode {
             3 => ResultCode::NXDOMAIN,
             4 => ResultCode::NOTIMP,
             5 => ResultCode::REFUSED,
+            9 => ResultCode::NOTAUTH,
             0 | _ => ResultCode::NOERROR,
         }
     }
 }
+
+/// # `OpCode`
+///
+/// Kind of operation requested by a packet, stored in `Header::opcode`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OpCode {
+    QUERY,  // 0
+    IQUERY, // 1
+    STATUS, // 2
+    NOTIFY, // 4
+    UPDATE, // 5
+    UNKNOWN(u8),
+}
+
+impl OpCode {
+    pub fn from_num(num: u8) -> OpCode {
+        match num {
+            0 => OpCode::QUERY,
+            1 => OpCode::IQUERY,
+            2 => OpCode::STATUS,
+            4 => OpCode::NOTIFY,
+            5 => OpCode::UPDATE,
+            _ => OpCode::UNKNOWN(num),
+        }
+    }
+
+    pub fn to_num(&self) -> u8 {
+        match *self {
+            OpCode::QUERY => 0,
+            OpCode::IQUERY => 1,
+            OpCode::STATUS => 2,
+            OpCode::NOTIFY => 4,
+            OpCode::UPDATE => 5,
+            OpCode::UNKNOWN(x) => x,
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use serde::{Deserialize, Serialize};
+
+use super::{
+    name::absolute,
+    packet::Packet,
+    questions_and_records::{Question, Record},
+};
+
+/// # `DohJson`
+///
+/// The JSON shape of the responses of the DNS over HTTPS JSON APIs
+/// (`application/dns-json`), as Google and Cloudflare serve them: the record data
+/// is in the presentation format and the types are numbers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct DohJson {
+    pub status: u16,
+    #[serde(rename = "TC")]
+    pub truncated: bool,
+    #[serde(rename = "RD")]
+    pub recursion_desired: bool,
+    #[serde(rename = "RA")]
+    pub recursion_available: bool,
+    #[serde(rename = "AD")]
+    pub authed_data: bool,
+    #[serde(rename = "CD")]
+    pub checking_disabled: bool,
+    pub question: Vec<DohQuestion>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub answer: Vec<DohRecord>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub authority: Vec<DohRecord>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub additional: Vec<DohRecord>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DohQuestion {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub qtype: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DohRecord {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub qtype: u16,
+    #[serde(rename = "TTL")]
+    pub ttl: u32,
+    pub data: String,
+}
+
+impl From<&Packet> for DohJson {
+    /// The OPT pseudo record is left out, its extended response code is part of `Status`.
+    fn from(packet: &Packet) -> Self {
+        let extended_rcode = match packet.get_edns() {
+            Some(Record::OPT { extended_rcode, .. }) => *extended_rcode as u16,
+            _ => 0,
+        };
+        let records = |records: &[Record]| -> Vec<DohRecord> {
+            records
+                .iter()
+                .filter(|record| !matches!(record, Record::OPT { .. }))
+                .map(DohRecord::from)
+                .collect()
+        };
+        DohJson {
+            status: extended_rcode << 4 | packet.header.rescode as u16,
+            truncated: packet.header.truncated_message,
+            recursion_desired: packet.header.recursion_desired,
+            recursion_available: packet.header.recursion_available,
+            authed_data: packet.header.authed_data,
+            checking_disabled: packet.header.checking_disabled,
+            question: packet.questions.iter().map(DohQuestion::from).collect(),
+            answer: records(&packet.answers),
+            authority: records(&packet.authorities),
+            additional: records(&packet.resources),
+        }
+    }
+}
+
+impl From<&Question> for DohQuestion {
+    fn from(question: &Question) -> Self {
+        DohQuestion {
+            name: absolute(&question.qname),
+            qtype: question.qtype.to_num(),
+        }
+    }
+}
+
+impl From<&Record> for DohRecord {
+    fn from(record: &Record) -> Self {
+        DohRecord {
+            name: absolute(record.get_domain()),
+            qtype: record.get_qtype().to_num(),
+            ttl: record.get_ttl(),
+            data: record.rdata().to_string(),
+        }
+    }
+}
@@ -2,5 +2,7 @@ pub mod packet;
 pub mod auxiliaries;
 pub mod buffer;
 pub mod header;
+pub mod name;
 pub mod questions_and_records;
 pub mod db_queries;
+pub mod json;
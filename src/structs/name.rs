@@ -0,0 +1,218 @@
+use std::{
+    cmp::Ordering,
+    fmt,
+    hash::{Hash, Hasher},
+    ops::Deref,
+    sync::Arc,
+};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sqlx::{
+    encode::IsNull,
+    error::BoxDynError,
+    sqlite::{SqliteArgumentValue, SqliteTypeInfo, SqliteValueRef},
+    Decode, Encode, Sqlite, Type,
+};
+
+use super::auxiliaries::{CResult, DnsError};
+
+/// Longest name on the wire, the length bytes and the root label included
+/// (RFC 1035 section 2.3.4).
+pub const MAX_NAME_LEN: usize = 255;
+/// Longest label.
+pub const MAX_LABEL_LEN: usize = 63;
+
+/// # `DnsName`
+///
+/// Domain name, cheap to clone as the text is shared between the clones.
+/// Comparisons ignore the ASCII case, as the names do (RFC 4343),
+/// while the text keeps the case it has been created with.
+/// It dereferences to `str`, so it can be used wherever a name is read.
+#[derive(Clone, Default)]
+pub struct DnsName(Arc<str>);
+
+impl DnsName {
+    pub fn new(name: &str) -> Self {
+        DnsName(Arc::from(name))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// # `validate`
+    ///
+    /// Checks that `name` can be written on the wire: labels not empty and up to
+    /// 63 bytes, in names up to 255 bytes. The trailing dot is optional,
+    /// the root is an empty name or a single dot.
+    pub fn validate(name: &str) -> CResult<()> {
+        let name = name.strip_suffix('.').unwrap_or(name);
+        if name.is_empty() {
+            return Ok(());
+        }
+        // Length on the wire: a length byte per label and the root label
+        if name.len() + 2 > MAX_NAME_LEN {
+            return Err(DnsError::Malformed(format!(
+                "The name {} is too long",
+                name
+            )));
+        }
+        for label in name.split('.') {
+            if label.is_empty() || label.len() > MAX_LABEL_LEN {
+                return Err(DnsError::Malformed(format!(
+                    "The name {} has a label that is empty or longer than 63 bytes",
+                    name
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// # `shares`
+    ///
+    /// Returns true if the two names share the same text, as the clones do.
+    pub fn shares(&self, other: &DnsName) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// # `absolute`
+///
+/// `name` with the trailing dot, as the names are written in the zone files.
+pub fn absolute(name: &str) -> String {
+    format!("{}.", name.trim_end_matches('.'))
+}
+
+impl Deref for DnsName {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for DnsName {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<&str> for DnsName {
+    fn from(name: &str) -> Self {
+        DnsName::new(name)
+    }
+}
+
+impl From<&String> for DnsName {
+    fn from(name: &String) -> Self {
+        DnsName::new(name)
+    }
+}
+
+impl From<String> for DnsName {
+    fn from(name: String) -> Self {
+        DnsName(Arc::from(name))
+    }
+}
+
+impl From<&DnsName> for DnsName {
+    fn from(name: &DnsName) -> Self {
+        name.clone()
+    }
+}
+
+impl fmt::Display for DnsName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Serialize for DnsName {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for DnsName {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(DnsName::from)
+    }
+}
+
+impl fmt::Debug for DnsName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&*self.0, f)
+    }
+}
+
+impl PartialEq for DnsName {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.eq_ignore_ascii_case(&other.0)
+    }
+}
+
+impl Eq for DnsName {}
+
+impl PartialEq<str> for DnsName {
+    fn eq(&self, other: &str) -> bool {
+        self.0.eq_ignore_ascii_case(other)
+    }
+}
+
+impl PartialEq<&str> for DnsName {
+    fn eq(&self, other: &&str) -> bool {
+        self.0.eq_ignore_ascii_case(other)
+    }
+}
+
+impl PartialEq<String> for DnsName {
+    fn eq(&self, other: &String) -> bool {
+        self.0.eq_ignore_ascii_case(other)
+    }
+}
+
+impl Hash for DnsName {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for b in self.0.bytes() {
+            state.write_u8(b.to_ascii_lowercase());
+        }
+        state.write_u8(0xff);
+    }
+}
+
+impl PartialOrd for DnsName {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for DnsName {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0
+            .bytes()
+            .map(|b| b.to_ascii_lowercase())
+            .cmp(other.0.bytes().map(|b| b.to_ascii_lowercase()))
+    }
+}
+
+impl Type<Sqlite> for DnsName {
+    fn type_info() -> SqliteTypeInfo {
+        <str as Type<Sqlite>>::type_info()
+    }
+
+    fn compatible(ty: &SqliteTypeInfo) -> bool {
+        <str as Type<Sqlite>>::compatible(ty)
+    }
+}
+
+impl<'q> Encode<'q, Sqlite> for DnsName {
+    fn encode_by_ref(&self, buf: &mut Vec<SqliteArgumentValue<'q>>) -> Result<IsNull, BoxDynError> {
+        <String as Encode<'q, Sqlite>>::encode(self.0.to_string(), buf)
+    }
+}
+
+impl<'r> Decode<'r, Sqlite> for DnsName {
+    fn decode(value: SqliteValueRef<'r>) -> Result<Self, BoxDynError> {
+        Ok(DnsName::new(<&str as Decode<'r, Sqlite>>::decode(value)?))
+    }
+}
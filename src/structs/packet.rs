@@ -1,14 +1,28 @@
-use std::net::Ipv4Addr;
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr},
+};
+
+use serde::{Deserialize, Serialize};
 
 use super::{
-    auxiliaries::CResult,
+    auxiliaries::{CResult, DnsError},
     buffer::BytePacketBuffer,
     db_queries::CachedRecord,
-    header::{Header, ResultCode},
-    questions_and_records::{QueryType, Question, Record},
+    header::{Header, OpCode, ResultCode},
+    name::DnsName,
+    questions_and_records::{EdnsOption, QueryType, Question, Record, DO_FLAG, EDE_OPTION},
 };
 
-#[derive(Debug, Clone)]
+/// UDP payload size we advertise in our OPT records, the size of our receive buffers.
+const EDNS_PACKET_LEN: u16 = 512;
+/// Largest response sent over UDP whatever the client accepts, the datagrams
+/// larger than that risk being fragmented.
+const MAX_UDP_RESPONSE: usize = 1232;
+/// Size of the responses over UDP to the clients without EDNS (RFC 1035 section 4.2.1).
+const UDP_RESPONSE_LEN: usize = 512;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Packet {
     pub header: Header,
     pub questions: Vec<Question>,
@@ -28,6 +42,13 @@ impl Packet {
         }
     }
 
+    /// # `builder`
+    ///
+    /// Composes a packet whose header always agrees with its sections.
+    pub fn builder() -> PacketBuilder {
+        PacketBuilder::default()
+    }
+
     /// `Add Info`
     ///
     /// Helper function that modifies specific informations on the header
@@ -95,6 +116,41 @@ impl Packet {
         Ok(result)
     }
 
+    /// # `from_buffer_strict`
+    ///
+    /// Parses the message as `from_buffer` does, `buffer` holding the message and
+    /// nothing else, but refuses what `from_buffer` lets through: bytes after the
+    /// last record, as when the counts of the header are lower than the records sent,
+    /// and names with labels longer than 63 bytes, longer than 255 bytes or with
+    /// characters other than letters, digits, `-`, `_`, `*` and `/`.
+    pub fn from_buffer_strict(buffer: &mut BytePacketBuffer) -> CResult<Packet> {
+        let packet = Packet::from_buffer(buffer)?;
+        if buffer.pos() != buffer.buf.len() {
+            return Err(DnsError::Malformed(format!(
+                "{} bytes follow the last record",
+                buffer.buf.len().saturating_sub(buffer.pos())
+            )));
+        }
+        for name in packet.names() {
+            DnsName::validate(name)?;
+            let allowed = |c: char| c.is_ascii_alphanumeric() || "-_*/.".contains(c);
+            if !name.chars().all(allowed) {
+                return Err(DnsError::Malformed(format!(
+                    "The name {} has characters that aren't allowed",
+                    name
+                )));
+            }
+        }
+        Ok(packet)
+    }
+
+    /// # `from_bytes`
+    ///
+    /// Parses the message contained in `bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> CResult<Packet> {
+        Packet::from_buffer(&mut BytePacketBuffer::from_bytes(bytes))
+    }
+
     /// # `write`
     ///
     /// Provided a `BytePacketBuffer` this method writes all the Informations
@@ -106,14 +162,56 @@ impl Packet {
         // initialized to 0, since we require it to be so
 
         // Completing the header
-        self.header.questions = self.questions.len() as u16;
-        self.header.answers = self.answers.len() as u16;
-        self.header.authoritative_entries = self.authorities.len() as u16;
-        self.header.resource_entries = self.resources.len() as u16;
+        self.header = self.counted_header();
 
         // Writing the header to buffer
         self.header.write(buffer)?;
+        self.write_sections(buffer)
+    }
 
+    /// # `to_vec`
+    ///
+    /// The message in the wire format, the counts of the header are those of the sections.
+    pub fn to_vec(&self) -> CResult<Vec<u8>> {
+        let mut buffer = BytePacketBuffer::empty();
+        self.counted_header().write(&mut buffer)?;
+        self.write_sections(&mut buffer)?;
+        Ok(buffer.written().to_vec())
+    }
+
+    /// # `names`
+    ///
+    /// The names of the questions and the owners of the records.
+    fn names(&self) -> impl Iterator<Item = &str> {
+        self.questions
+            .iter()
+            .map(|question| question.qname.as_str())
+            .chain(
+                self.answers
+                    .iter()
+                    .chain(&self.authorities)
+                    .chain(&self.resources)
+                    .filter(|record| !matches!(record, Record::OPT { .. }))
+                    .map(|record| record.get_domain()),
+            )
+    }
+
+    /// # `counted_header`
+    ///
+    /// The header with the counts of the sections the packet has.
+    fn counted_header(&self) -> Header {
+        let mut header = self.header.clone();
+        header.questions = self.questions.len() as u16;
+        header.answers = self.answers.len() as u16;
+        header.authoritative_entries = self.authorities.len() as u16;
+        header.resource_entries = self.resources.len() as u16;
+        header
+    }
+
+    /// # `write_sections`
+    ///
+    /// Writes the sections that follow the header.
+    fn write_sections(&self, buffer: &mut BytePacketBuffer) -> CResult<()> {
         // Writing `Question section`
         for question in &self.questions {
             question.write(buffer)?;
@@ -155,6 +253,133 @@ impl Packet {
         Ok(error_packet)
     }
 
+    /// # `get_edns`
+    ///
+    /// Returns the OPT record of the packet, if the sender supports EDNS.
+    pub fn get_edns(&self) -> Option<&Record> {
+        self.resources
+            .iter()
+            .find(|r| matches!(r, Record::OPT { .. }))
+    }
+
+    /// # `get_edns_option`
+    ///
+    /// Returns the first option with `code` carried by the OPT record of the packet.
+    pub fn get_edns_option(&self, code: u16) -> Option<&EdnsOption> {
+        match self.get_edns() {
+            Some(Record::OPT { options, .. }) => options.iter().find(|o| o.code == code),
+            _ => None,
+        }
+    }
+
+    /// # `add_edns_option`
+    ///
+    /// Attaches `option` to the OPT record of the packet, adding the record
+    /// if the packet doesn't have one yet.
+    pub fn add_edns_option(&mut self, option: EdnsOption) {
+        if self.get_edns().is_none() {
+            self.resources.push(Record::OPT {
+                packet_len: EDNS_PACKET_LEN,
+                extended_rcode: 0,
+                version: 0,
+                flags: 0,
+                options: Vec::new(),
+            });
+        }
+        if let Some(Record::OPT { options, .. }) = self
+            .resources
+            .iter_mut()
+            .find(|r| matches!(r, Record::OPT { .. }))
+        {
+            options.push(option);
+        }
+    }
+
+    /// # `dnssec_ok`
+    ///
+    /// Whether the sender asks for the DNSSEC records, setting DO in its OPT record.
+    pub fn dnssec_ok(&self) -> bool {
+        matches!(self.get_edns(), Some(Record::OPT { flags, .. }) if flags & DO_FLAG != 0)
+    }
+
+    /// # `set_dnssec_ok`
+    ///
+    /// Copies the DO bit of the query into the OPT record of the response,
+    /// if it has one (RFC 3225 section 3).
+    pub fn set_dnssec_ok(&mut self, dnssec_ok: bool) {
+        for record in self.resources.iter_mut() {
+            if let Record::OPT { flags, .. } = record {
+                *flags = if dnssec_ok {
+                    *flags | DO_FLAG
+                } else {
+                    *flags & !DO_FLAG
+                };
+            }
+        }
+    }
+
+    /// # `strip_dnssec`
+    ///
+    /// Removes the RRSIG and NSEC records, meant for the clients that didn't set DO,
+    /// save the ones of the type asked for (RFC 4035 section 3.2.1).
+    pub fn strip_dnssec(&mut self) {
+        let asked: Vec<QueryType> = self.questions.iter().map(|q| q.qtype).collect();
+        let keep = |record: &Record| {
+            let qtype = record.get_qtype();
+            !matches!(qtype, QueryType::RRSIG | QueryType::NSEC) || asked.contains(&qtype)
+        };
+        self.answers.retain(keep);
+        self.authorities.retain(keep);
+        self.resources.retain(keep);
+    }
+
+    /// # `udp_payload_limit`
+    ///
+    /// Largest response over UDP the sender of the packet accepts: the payload size of
+    /// its OPT record, no less than 512 and no more than `MAX_UDP_RESPONSE`,
+    /// or 512 without EDNS (RFC 6891 section 6.2.5).
+    pub fn udp_payload_limit(&self) -> usize {
+        match self.get_edns() {
+            Some(Record::OPT { packet_len, .. }) => {
+                (*packet_len as usize).clamp(UDP_RESPONSE_LEN, MAX_UDP_RESPONSE)
+            }
+            _ => UDP_RESPONSE_LEN,
+        }
+    }
+
+    /// # `strip_additional`
+    ///
+    /// Removes the records of the additional section but the OPT record, the first
+    /// step of fitting a response in a datagram: it doesn't need TC (RFC 2181 section 9).
+    pub fn strip_additional(&mut self) {
+        self.resources
+            .retain(|record| matches!(record, Record::OPT { .. }));
+    }
+
+    /// # `truncate`
+    ///
+    /// Leaves the questions and the OPT record alone and sets TC,
+    /// for a response that doesn't fit in a datagram: the client asks again over TCP.
+    pub fn truncate(&mut self) {
+        self.answers.clear();
+        self.authorities.clear();
+        self.strip_additional();
+        self.header.truncated_message = true;
+    }
+
+    /// # `add_ede`
+    ///
+    /// Attaches an Extended DNS Error (RFC 8914) to the packet.
+    /// It should only be used for responses to requests that carried an OPT record.
+    pub fn add_ede(&mut self, info_code: u16, extra_text: &str) {
+        let mut data = info_code.to_be_bytes().to_vec();
+        data.extend_from_slice(extra_text.as_bytes());
+        self.add_edns_option(EdnsOption {
+            code: EDE_OPTION,
+            data,
+        });
+    }
+
     /// #`get_resolved_ns`
     ///
     /// Some name servers when queried for an NS record often return
@@ -183,6 +408,91 @@ impl Packet {
             .next()
     }
 
+    /// # `get_resolved_ns_addrs`
+    ///
+    /// Like `get_resolved_ns`, returns the addresses of every authoritative server
+    /// found in the `Additional section`, the IPv6 ones included.
+    pub fn get_resolved_ns_addrs(&self, qname: &str) -> Vec<IpAddr> {
+        self.get_ns(qname)
+            .flat_map(|(_, host)| {
+                self.resources
+                    .iter()
+                    .filter_map(move |record| match record {
+                        Record::A { domain, addr, .. } if domain == host => Some(IpAddr::V4(*addr)),
+                        Record::AAAA { domain, addr, .. } if domain == host => {
+                            Some(IpAddr::V6(*addr))
+                        }
+                        _ => None,
+                    })
+            })
+            .collect()
+    }
+
+    /// # `get_glue`
+    ///
+    /// Returns the addresses found in the `Additional section` for every authoritative
+    /// server, IPv4 and IPv6, with the lowest TTL among them.
+    pub fn get_glue<'a>(&'a self, qname: &'a str) -> Vec<(&'a str, Vec<IpAddr>, u32)> {
+        self.get_ns(qname)
+            .map(|(_, host)| {
+                let (addrs, ttl) = Self::addrs(&self.resources, host);
+                (host, addrs, ttl)
+            })
+            .filter(|(_, addrs, _)| !addrs.is_empty())
+            .collect()
+    }
+
+    /// # `get_ns_hosts`
+    ///
+    /// Returns the names of every server authoritative to our query.
+    pub fn get_ns_hosts(&self, qname: &str) -> Vec<String> {
+        let mut hosts: Vec<String> = Vec::new();
+        for (_, host) in self.get_ns(qname) {
+            if !hosts.iter().any(|h| h == host) {
+                hosts.push(host.to_string());
+            }
+        }
+        hosts
+    }
+
+    /// # `get_referral_zone`
+    ///
+    /// Returns the zone the servers authoritative to our query are delegated, if any.
+    pub fn get_referral_zone<'a>(&'a self, qname: &'a str) -> Option<&'a str> {
+        self.get_ns(qname).map(|(domain, _)| domain).next()
+    }
+
+    /// # `get_addrs`
+    ///
+    /// Returns the addresses of the A and AAAA records for `domain` in the `Answer section`,
+    /// with the lowest TTL among them.
+    pub fn get_addrs(&self, domain: &str) -> (Vec<IpAddr>, u32) {
+        Self::addrs(&self.answers, domain)
+    }
+
+    /// # `addrs`
+    ///
+    /// `get_glue`'s and `get_addrs`'s helper function.
+    fn addrs(records: &[Record], name: &str) -> (Vec<IpAddr>, u32) {
+        let mut addrs = Vec::new();
+        let mut min_ttl = u32::MAX;
+        for record in records {
+            let (domain, addr, ttl) = match record {
+                Record::A { domain, addr, ttl } => (domain, IpAddr::V4(*addr), ttl),
+                Record::AAAA { domain, addr, ttl } => (domain, IpAddr::V6(*addr), ttl),
+                _ => continue,
+            };
+            if domain.eq_ignore_ascii_case(name) {
+                addrs.push(addr);
+                min_ttl = min_ttl.min(*ttl);
+            }
+        }
+        if addrs.is_empty() {
+            min_ttl = 0;
+        }
+        (addrs, min_ttl)
+    }
+
     /// # `get_ns`
     ///
     /// `get_resolved_ns`'s and `get_unresolved_ns`'s helper function which
@@ -199,7 +509,11 @@ impl Packet {
                 _ => None,
             })
             // Discard servers which aren't authoritative to our query
-            .filter(move |(domain, _)| qname.ends_with(*domain))
+            .filter(move |(domain, _)| {
+                qname.len() >= domain.len()
+                    && qname.as_bytes()[qname.len() - domain.len()..]
+                        .eq_ignore_ascii_case(domain.as_bytes())
+            })
     }
 
     /// #`get_unresolved_ns`
@@ -225,16 +539,207 @@ impl Packet {
             .next()
     }
 
-    /// #`get_random_a_rec`
+    /// #`get_a_recs`
     ///
-    /// Gets a random A record from the packet, if there is one
-    pub fn get_random_a_rec(&self) -> Option<Record> {
+    /// Gets the A records of the `Answer section`.
+    pub fn get_a_recs(&self) -> impl Iterator<Item = &Record> {
         self.answers
             .iter()
-            .filter_map(|record| match record {
-                Record::A { .. } => Some(record.clone()),
-                _ => None,
-            })
-            .next()
+            .filter(|record| matches!(record, Record::A { .. }))
+    }
+
+    /// # `rotate_answers`
+    ///
+    /// Rotates the addresses of every A and AAAA RRset of the `Answer section` by `offset`,
+    /// in the positions they already take, so the clients that pick the first address
+    /// spread over all of them.
+    pub fn rotate_answers(&mut self, offset: usize) {
+        let mut rotated = vec![false; self.answers.len()];
+        for first in 0..self.answers.len() {
+            if rotated[first]
+                || !matches!(self.answers[first], Record::A { .. } | Record::AAAA { .. })
+            {
+                continue;
+            }
+            let (qtype, domain) = (
+                self.answers[first].get_qtype(),
+                self.answers[first].get_domain().to_lowercase(),
+            );
+            let positions: Vec<usize> = (first..self.answers.len())
+                .filter(|&i| {
+                    self.answers[i].get_qtype() == qtype
+                        && self.answers[i].get_domain().eq_ignore_ascii_case(&domain)
+                })
+                .collect();
+            let mut rrset: Vec<Record> = positions
+                .iter()
+                .map(|&i| {
+                    rotated[i] = true;
+                    self.answers[i].clone()
+                })
+                .collect();
+            let len = rrset.len();
+            rrset.rotate_left(offset % len);
+            for (i, record) in positions.into_iter().zip(rrset) {
+                self.answers[i] = record;
+            }
+        }
+    }
+}
+
+/// # `PacketBuilder`
+///
+/// Composes a packet section by section, the counts of the header are those of
+/// the sections rather than set by hand; `build` refuses the packets that
+/// couldn't be sent as they are.
+#[derive(Debug)]
+pub struct PacketBuilder {
+    packet: Packet,
+}
+
+impl Default for PacketBuilder {
+    fn default() -> Self {
+        PacketBuilder {
+            packet: Packet::new(),
+        }
+    }
+}
+
+impl PacketBuilder {
+    pub fn id(mut self, id: u16) -> Self {
+        self.packet.header.id = id;
+        self
+    }
+
+    pub fn opcode(mut self, opcode: OpCode) -> Self {
+        self.packet.header.opcode = opcode.to_num();
+        self
+    }
+
+    /// # `response`
+    ///
+    /// Marks the packet as a response, the packets are queries otherwise.
+    pub fn response(mut self) -> Self {
+        self.packet.header.response = true;
+        self
+    }
+
+    pub fn rcode(mut self, rcode: ResultCode) -> Self {
+        self.packet.header.rescode = rcode;
+        self
+    }
+
+    pub fn recursion_desired(mut self, recursion_desired: bool) -> Self {
+        self.packet.header.recursion_desired = recursion_desired;
+        self
+    }
+
+    pub fn recursion_available(mut self, recursion_available: bool) -> Self {
+        self.packet.header.recursion_available = recursion_available;
+        self
+    }
+
+    pub fn authoritative(mut self, authoritative: bool) -> Self {
+        self.packet.header.authoritative_answer = authoritative;
+        self
+    }
+
+    pub fn question(mut self, qname: impl Into<DnsName>, qtype: QueryType) -> Self {
+        self.packet.questions.push(Question::new(qname, qtype));
+        self
+    }
+
+    pub fn answer(mut self, record: Record) -> Self {
+        self.packet.answers.push(record);
+        self
+    }
+
+    pub fn authority(mut self, record: Record) -> Self {
+        self.packet.authorities.push(record);
+        self
+    }
+
+    pub fn additional(mut self, record: Record) -> Self {
+        self.packet.resources.push(record);
+        self
+    }
+
+    /// # `edns_option`
+    ///
+    /// Attaches `option` to the OPT record, added if there's none yet.
+    pub fn edns_option(mut self, option: EdnsOption) -> Self {
+        self.packet.add_edns_option(option);
+        self
+    }
+
+    /// # `build`
+    ///
+    /// The packet, if the names are valid, there's at most an OPT record and
+    /// it's in the additional section, and it's a response if it carries an error.
+    pub fn build(mut self) -> CResult<Packet> {
+        let packet = &mut self.packet;
+        for name in packet.names() {
+            DnsName::validate(name)?;
+        }
+        let opts = |records: &[Record]| {
+            records
+                .iter()
+                .filter(|record| matches!(record, Record::OPT { .. }))
+                .count()
+        };
+        if opts(&packet.answers) + opts(&packet.authorities) > 0 {
+            return Err(DnsError::Malformed(
+                "The OPT record belongs to the additional section".to_string(),
+            ));
+        }
+        if opts(&packet.resources) > 1 {
+            return Err(DnsError::Malformed(
+                "A packet carries a single OPT record".to_string(),
+            ));
+        }
+        if !packet.header.response && packet.header.rescode != ResultCode::NOERROR {
+            return Err(DnsError::Malformed(
+                "Only a response carries a response code".to_string(),
+            ));
+        }
+
+        packet.header = packet.counted_header();
+        Ok(self.packet)
+    }
+}
+
+impl fmt::Display for Packet {
+    /// The packet as `dig` shows it: the header, the EDNS pseudo section and
+    /// the sections that aren't empty.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.counted_header())?;
+        if let Some(opt) = self.get_edns() {
+            write!(f, "\n;; OPT PSEUDOSECTION:\n{}\n", opt)?;
+        }
+
+        write!(f, "\n;; QUESTION SECTION:\n")?;
+        for question in &self.questions {
+            writeln!(f, ";{}", question)?;
+        }
+        let additional: Vec<&Record> = self
+            .resources
+            .iter()
+            .filter(|record| !matches!(record, Record::OPT { .. }))
+            .collect();
+        let sections: [(&str, Vec<&Record>); 3] = [
+            ("ANSWER", self.answers.iter().collect()),
+            ("AUTHORITY", self.authorities.iter().collect()),
+            ("ADDITIONAL", additional),
+        ];
+        for (name, records) in sections {
+            if records.is_empty() {
+                continue;
+            }
+            write!(f, "\n;; {} SECTION:\n", name)?;
+            for record in records {
+                writeln!(f, "{}", record)?;
+            }
+        }
+        Ok(())
     }
 }
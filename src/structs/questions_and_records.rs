@@ -1,22 +1,39 @@
 use std::{
+    fmt,
     net::{Ipv4Addr, Ipv6Addr},
+    str::FromStr,
     time::Duration,
 };
 
-use chrono::Local;
-use sqlx::SqlitePool;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::DateTime;
+use ipnet::IpNet;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 
-use super::{auxiliaries::CResult, buffer::BytePacketBuffer};
+use crate::{
+    cachewriter::{CacheEntry, CacheWriter},
+    clock::Clock,
+    dnssec::bitmap_types,
+};
+
+use super::{
+    auxiliaries::{CResult, DnsError},
+    buffer::BytePacketBuffer,
+    name::{absolute, DnsName},
+};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Question {
-    pub qname: String,
+    pub qname: DnsName,
     pub qtype: QueryType,
 }
 
 impl Question {
-    pub fn new(qname: String, qtype: QueryType) -> Question {
-        Question { qname, qtype }
+    pub fn new(qname: impl Into<DnsName>, qtype: QueryType) -> Question {
+        Question {
+            qname: qname.into(),
+            qtype,
+        }
     }
 
     /// # `read`
@@ -26,7 +43,7 @@ impl Question {
     /// and `BytePacketBuffer.pos` needs to be in the correct positon, if something
     /// goes wrong an error is returned.
     pub fn read(&mut self, buffer: &mut BytePacketBuffer) -> CResult<()> {
-        buffer.read_qname(&mut self.qname)?;
+        self.qname = buffer.read_name()?;
         self.qtype = QueryType::from_num(buffer.read_u16()?);
         let _ = buffer.read_u16()?; // This is always 1
 
@@ -48,14 +65,32 @@ impl Question {
     }
 }
 
+impl fmt::Display for Question {
+    /// As in the question section of `dig`, without the leading `;`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}\tIN\t{}", absolute(&self.qname), self.qtype)
+    }
+}
+
 #[derive(PartialEq, Debug, Eq, Clone, Hash, Copy)]
 pub enum QueryType {
     UNKNOWN(u16),
-    A,     // 1
-    NS,    // 2
-    CNAME, // 5
-    MX,    // 15
-    AAAA,  // 28
+    A,      // 1
+    NS,     // 2
+    CNAME,  // 5
+    SOA,    // 6
+    PTR,    // 12
+    HINFO,  // 13
+    MX,     // 15
+    AAAA,   // 28
+    OPT,    // 41
+    DS,     // 43
+    RRSIG,  // 46
+    NSEC,   // 47
+    DNSKEY, // 48
+    IXFR,   // 251
+    AXFR,   // 252
+    ANY,    // 255
 }
 
 impl QueryType {
@@ -64,8 +99,19 @@ impl QueryType {
             1 => QueryType::A,
             2 => QueryType::NS,
             5 => QueryType::CNAME,
+            6 => QueryType::SOA,
+            12 => QueryType::PTR,
+            13 => QueryType::HINFO,
             15 => QueryType::MX,
             28 => QueryType::AAAA,
+            41 => QueryType::OPT,
+            43 => QueryType::DS,
+            46 => QueryType::RRSIG,
+            47 => QueryType::NSEC,
+            48 => QueryType::DNSKEY,
+            251 => QueryType::IXFR,
+            252 => QueryType::AXFR,
+            255 => QueryType::ANY,
             _ => QueryType::UNKNOWN(num),
         }
     }
@@ -76,48 +122,219 @@ impl QueryType {
             QueryType::A => 1,
             QueryType::NS => 2,
             QueryType::CNAME => 5,
+            QueryType::SOA => 6,
+            QueryType::PTR => 12,
+            QueryType::HINFO => 13,
             QueryType::MX => 15,
             QueryType::AAAA => 28,
+            QueryType::OPT => 41,
+            QueryType::DS => 43,
+            QueryType::RRSIG => 46,
+            QueryType::NSEC => 47,
+            QueryType::DNSKEY => 48,
+            QueryType::IXFR => 251,
+            QueryType::AXFR => 252,
+            QueryType::ANY => 255,
+        }
+    }
+}
+
+impl FromStr for QueryType {
+    type Err = String;
+
+    /// # `from_str`
+    ///
+    /// The mnemonic of the type, in any case, its number or its generic
+    /// representation `TYPE<number>` (RFC 3597).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let upper = s.to_ascii_uppercase();
+        let qtype = match upper.as_str() {
+            "A" => QueryType::A,
+            "NS" => QueryType::NS,
+            "CNAME" => QueryType::CNAME,
+            "SOA" => QueryType::SOA,
+            "PTR" => QueryType::PTR,
+            "HINFO" => QueryType::HINFO,
+            "MX" => QueryType::MX,
+            "AAAA" => QueryType::AAAA,
+            "OPT" => QueryType::OPT,
+            "DS" => QueryType::DS,
+            "RRSIG" => QueryType::RRSIG,
+            "NSEC" => QueryType::NSEC,
+            "DNSKEY" => QueryType::DNSKEY,
+            "IXFR" => QueryType::IXFR,
+            "AXFR" => QueryType::AXFR,
+            "ANY" => QueryType::ANY,
+            _ => {
+                let num = upper.strip_prefix("TYPE").unwrap_or(&upper);
+                QueryType::from_num(
+                    num.parse()
+                        .map_err(|_| format!("Unknown record type: {}", s))?,
+                )
+            }
+        };
+        Ok(qtype)
+    }
+}
+
+impl fmt::Display for QueryType {
+    /// The mnemonic of the type, `TYPE<number>` for the unknown ones.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryType::UNKNOWN(num) => write!(f, "TYPE{}", num),
+            qtype => write!(f, "{:?}", qtype),
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+impl Serialize for QueryType {
+    /// As its mnemonic, the way it's written in the zone files.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for QueryType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(tag = "type")]
 pub enum Record {
+    /// A record of a type we don't parse, its data kept as it is (RFC 3597).
     UNKNOWN {
-        domain: String,
+        domain: DnsName,
         qtype: u16,
-        data_len: u16,
+        #[serde(with = "base64_bytes")]
+        data: Vec<u8>,
         ttl: u32,
     }, // 0
     A {
-        domain: String,
+        domain: DnsName,
         addr: Ipv4Addr,
         ttl: u32,
     }, // 1
     NS {
-        domain: String,
-        host: String,
+        domain: DnsName,
+        host: DnsName,
         ttl: u32,
     }, // 2
     CNAME {
-        domain: String,
-        host: String,
+        domain: DnsName,
+        host: DnsName,
         ttl: u32,
     }, // 5
+    SOA {
+        domain: DnsName,
+        mname: DnsName,
+        rname: DnsName,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+        ttl: u32,
+    }, // 6
+    PTR {
+        domain: DnsName,
+        host: DnsName,
+        ttl: u32,
+    }, // 12
+    /// Host information, answered to the ANY queries as RFC 8482 suggests.
+    HINFO {
+        domain: DnsName,
+        cpu: String,
+        os: String,
+        ttl: u32,
+    }, // 13
     MX {
-        domain: String,
+        domain: DnsName,
         priority: u16,
-        host: String,
+        host: DnsName,
         ttl: u32,
     }, // 15
     AAAA {
-        domain: String,
+        domain: DnsName,
         addr: Ipv6Addr,
         ttl: u32,
     }, // 28
+    DS {
+        domain: DnsName,
+        key_tag: u16,
+        algorithm: u8,
+        digest_type: u8,
+        #[serde(with = "base64_bytes")]
+        digest: Vec<u8>,
+        ttl: u32,
+    }, // 43
+    RRSIG {
+        domain: DnsName,
+        type_covered: u16,
+        algorithm: u8,
+        labels: u8,
+        original_ttl: u32,
+        expiration: u32,
+        inception: u32,
+        key_tag: u16,
+        signer_name: DnsName,
+        #[serde(with = "base64_bytes")]
+        signature: Vec<u8>,
+        ttl: u32,
+    }, // 46
+    NSEC {
+        domain: DnsName,
+        next_domain: DnsName,
+        /// Types present at `domain`, in the type bitmap format of RFC 4034 section 4.1.2
+        #[serde(with = "base64_bytes")]
+        type_bitmap: Vec<u8>,
+        ttl: u32,
+    }, // 47
+    DNSKEY {
+        domain: DnsName,
+        flags: u16,
+        protocol: u8,
+        algorithm: u8,
+        #[serde(with = "base64_bytes")]
+        public_key: Vec<u8>,
+        ttl: u32,
+    }, // 48
+    /// EDNS pseudo record (RFC 6891), owned by the root, it is carried
+    /// in the additional section.
+    OPT {
+        /// Maximum size of the UDP payloads of the sender, it takes the place of the class.
+        packet_len: u16,
+        /// Upper 8 bits of the extended response code.
+        extended_rcode: u8,
+        version: u8,
+        /// DO bit and reserved flags.
+        flags: u16,
+        options: Vec<EdnsOption>,
+    }, // 41
+}
+
+/// # `EdnsOption`
+///
+/// Option carried by the OPT record, the data is left uninterpreted.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct EdnsOption {
+    pub code: u16,
+    #[serde(with = "base64_bytes")]
+    pub data: Vec<u8>,
 }
 
+/// Code of the Extended DNS Error option (RFC 8914).
+pub const EDE_OPTION: u16 = 15;
+/// Code of the EDNS Client Subnet option (RFC 7871).
+pub const ECS_OPTION: u16 = 8;
+/// Code of the edns-tcp-keepalive option (RFC 7828).
+pub const TCP_KEEPALIVE_OPTION: u16 = 11;
+/// Flag of the OPT record asking for the DNSSEC records, the DO bit (RFC 3225).
+pub const DO_FLAG: u16 = 0x8000;
+
 impl Record {
     /// `read`
     ///
@@ -126,11 +343,10 @@ impl Record {
     /// and `BytePacketBuffer.pos` needs to be in the correct positon, if something
     /// goes wrong an error is returned.
     pub fn read(buffer: &mut BytePacketBuffer) -> CResult<Record> {
-        let mut domain = String::new();
-        buffer.read_qname(&mut domain)?;
+        let domain = buffer.read_name()?;
         let qtype_num = buffer.read_u16()?;
         let qtype = QueryType::from_num(qtype_num);
-        let _ = buffer.read_u16()?;
+        let class = buffer.read_u16()?;
         let ttl = buffer.read_u32()?;
         let data_len = buffer.read_u16()?;
 
@@ -164,8 +380,7 @@ impl Record {
                 Ok(Record::AAAA { domain, addr, ttl })
             }
             QueryType::NS => {
-                let mut ns = String::new();
-                buffer.read_qname(&mut ns)?;
+                let ns = buffer.read_name()?;
                 Ok(Record::NS {
                     domain,
                     host: ns,
@@ -173,18 +388,51 @@ impl Record {
                 })
             }
             QueryType::CNAME => {
-                let mut cname = String::new();
-                buffer.read_qname(&mut cname)?;
+                let cname = buffer.read_name()?;
                 Ok(Record::CNAME {
                     domain,
                     host: cname,
                     ttl,
                 })
             }
+            QueryType::PTR => {
+                let host = buffer.read_name()?;
+                Ok(Record::PTR { domain, host, ttl })
+            }
+            QueryType::HINFO => {
+                let cpu = read_character_string(buffer)?;
+                let os = read_character_string(buffer)?;
+                Ok(Record::HINFO {
+                    domain,
+                    cpu,
+                    os,
+                    ttl,
+                })
+            }
+            QueryType::SOA => {
+                let mname = buffer.read_name()?;
+                let rname = buffer.read_name()?;
+                let serial = buffer.read_u32()?;
+                let refresh = buffer.read_u32()?;
+                let retry = buffer.read_u32()?;
+                let expire = buffer.read_u32()?;
+                let minimum = buffer.read_u32()?;
+
+                Ok(Record::SOA {
+                    domain,
+                    mname,
+                    rname,
+                    serial,
+                    refresh,
+                    retry,
+                    expire,
+                    minimum,
+                    ttl,
+                })
+            }
             QueryType::MX => {
                 let priority = buffer.read_u16()?;
-                let mut mx = String::new();
-                buffer.read_qname(&mut mx)?;
+                let mx = buffer.read_name()?;
 
                 Ok(Record::MX {
                     domain,
@@ -193,13 +441,108 @@ impl Record {
                     ttl,
                 })
             }
-            QueryType::UNKNOWN(_) => {
+            QueryType::DS => {
+                let start = buffer.pos();
+                let key_tag = buffer.read_u16()?;
+                let algorithm = buffer.read_u8()?;
+                let digest_type = buffer.read_u8()?;
+                let digest = read_rest(buffer, start, data_len)?;
+
+                Ok(Record::DS {
+                    domain,
+                    key_tag,
+                    algorithm,
+                    digest_type,
+                    digest,
+                    ttl,
+                })
+            }
+            QueryType::RRSIG => {
+                let start = buffer.pos();
+                let type_covered = buffer.read_u16()?;
+                let algorithm = buffer.read_u8()?;
+                let labels = buffer.read_u8()?;
+                let original_ttl = buffer.read_u32()?;
+                let expiration = buffer.read_u32()?;
+                let inception = buffer.read_u32()?;
+                let key_tag = buffer.read_u16()?;
+                let signer_name = buffer.read_name()?;
+                let signature = read_rest(buffer, start, data_len)?;
+
+                Ok(Record::RRSIG {
+                    domain,
+                    type_covered,
+                    algorithm,
+                    labels,
+                    original_ttl,
+                    expiration,
+                    inception,
+                    key_tag,
+                    signer_name,
+                    signature,
+                    ttl,
+                })
+            }
+            QueryType::NSEC => {
+                let start = buffer.pos();
+                let next_domain = buffer.read_name()?;
+                let type_bitmap = read_rest(buffer, start, data_len)?;
+
+                Ok(Record::NSEC {
+                    domain,
+                    next_domain,
+                    type_bitmap,
+                    ttl,
+                })
+            }
+            QueryType::DNSKEY => {
+                let start = buffer.pos();
+                let flags = buffer.read_u16()?;
+                let protocol = buffer.read_u8()?;
+                let algorithm = buffer.read_u8()?;
+                let public_key = read_rest(buffer, start, data_len)?;
+
+                Ok(Record::DNSKEY {
+                    domain,
+                    flags,
+                    protocol,
+                    algorithm,
+                    public_key,
+                    ttl,
+                })
+            }
+            QueryType::OPT => {
+                let end = buffer.pos() + data_len as usize;
+                let mut options = Vec::new();
+                while buffer.pos() < end {
+                    let code = buffer.read_u16()?;
+                    let len = buffer.read_u16()?;
+                    let data = buffer.get_range(buffer.pos(), len as usize)?.to_vec();
+                    buffer.step(len as usize)?;
+                    options.push(EdnsOption { code, data });
+                }
+                if buffer.pos() != end {
+                    return Err(DnsError::Malformed(
+                        "EDNS options exceed the length of the OPT record".to_string(),
+                    ));
+                }
+
+                Ok(Record::OPT {
+                    packet_len: class,
+                    extended_rcode: (ttl >> 24) as u8,
+                    version: (ttl >> 16) as u8,
+                    flags: ttl as u16,
+                    options,
+                })
+            }
+            QueryType::UNKNOWN(_) | QueryType::IXFR | QueryType::AXFR | QueryType::ANY => {
+                let data = buffer.get_range(buffer.pos(), data_len as usize)?.to_vec();
                 buffer.step(data_len as usize)?;
 
                 Ok(Record::UNKNOWN {
                     domain,
                     qtype: qtype_num,
-                    data_len,
+                    data,
                     ttl,
                 })
             }
@@ -267,6 +610,74 @@ impl Record {
                 let size = buffer.pos() - (pos + 2);
                 buffer.set_u16(pos, size as u16)?;
             }
+            Record::PTR {
+                ref domain,
+                ref host,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::PTR.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname(host)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            Record::HINFO {
+                ref domain,
+                ref cpu,
+                ref os,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::HINFO.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                write_character_string(buffer, cpu)?;
+                write_character_string(buffer, os)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            Record::SOA {
+                ref domain,
+                ref mname,
+                ref rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::SOA.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname(mname)?;
+                buffer.write_qname(rname)?;
+                buffer.write_u32(serial)?;
+                buffer.write_u32(refresh)?;
+                buffer.write_u32(retry)?;
+                buffer.write_u32(expire)?;
+                buffer.write_u32(minimum)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
             Record::MX {
                 ref domain,
                 priority,
@@ -302,8 +713,133 @@ impl Record {
                     buffer.write_u16(*octet)?;
                 }
             }
-            Record::UNKNOWN { .. } => {
-                tracing::info!("Skipping record: {:?}", self);
+            Record::DS {
+                ref domain,
+                key_tag,
+                algorithm,
+                digest_type,
+                ref digest,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::DS.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(4 + digest.len() as u16)?;
+
+                buffer.write_u16(key_tag)?;
+                buffer.write_u8(algorithm)?;
+                buffer.write_u8(digest_type)?;
+                buffer.write_bytes(digest)?;
+            }
+            Record::RRSIG {
+                ref domain,
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                expiration,
+                inception,
+                key_tag,
+                ref signer_name,
+                ref signature,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::RRSIG.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_u16(type_covered)?;
+                buffer.write_u8(algorithm)?;
+                buffer.write_u8(labels)?;
+                buffer.write_u32(original_ttl)?;
+                buffer.write_u32(expiration)?;
+                buffer.write_u32(inception)?;
+                buffer.write_u16(key_tag)?;
+                buffer.write_qname(signer_name)?;
+                buffer.write_bytes(signature)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            Record::NSEC {
+                ref domain,
+                ref next_domain,
+                ref type_bitmap,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::NSEC.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname(next_domain)?;
+                buffer.write_bytes(type_bitmap)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            Record::DNSKEY {
+                ref domain,
+                flags,
+                protocol,
+                algorithm,
+                ref public_key,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::DNSKEY.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(4 + public_key.len() as u16)?;
+
+                buffer.write_u16(flags)?;
+                buffer.write_u8(protocol)?;
+                buffer.write_u8(algorithm)?;
+                buffer.write_bytes(public_key)?;
+            }
+            Record::OPT {
+                packet_len,
+                extended_rcode,
+                version,
+                flags,
+                ref options,
+            } => {
+                // The owner is the root
+                buffer.write_u8(0)?;
+                buffer.write_u16(QueryType::OPT.to_num())?;
+                buffer.write_u16(packet_len)?;
+                buffer.write_u32(
+                    (extended_rcode as u32) << 24 | (version as u32) << 16 | flags as u32,
+                )?;
+                let data_len: usize = options.iter().map(|o| 4 + o.data.len()).sum();
+                buffer.write_u16(data_len as u16)?;
+
+                for option in options {
+                    buffer.write_u16(option.code)?;
+                    buffer.write_u16(option.data.len() as u16)?;
+                    buffer.write_bytes(&option.data)?;
+                }
+            }
+            Record::UNKNOWN {
+                ref domain,
+                qtype,
+                ref data,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(qtype)?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(data.len() as u16)?;
+                buffer.write_bytes(data)?;
             }
         }
 
@@ -322,7 +858,7 @@ impl Record {
             Record::UNKNOWN {
                 domain: _,
                 qtype: _,
-                data_len: _,
+                data: _,
                 ttl,
             } => ttl.to_owned(),
             Record::A {
@@ -340,6 +876,9 @@ impl Record {
                 host: _,
                 ttl,
             } => ttl.to_owned(),
+            Record::SOA { ttl, .. } | Record::PTR { ttl, .. } | Record::HINFO { ttl, .. } => {
+                ttl.to_owned()
+            }
             Record::MX {
                 domain: _,
                 priority: _,
@@ -351,40 +890,460 @@ impl Record {
                 addr: _,
                 ttl,
             } => ttl.to_owned(),
+            Record::DS { ttl, .. }
+            | Record::RRSIG { ttl, .. }
+            | Record::NSEC { ttl, .. }
+            | Record::DNSKEY { ttl, .. } => ttl.to_owned(),
+            // The TTL field of OPT carries flags, it is never cached
+            Record::OPT { .. } => 0,
+        }
+    }
+
+    /// # `ttl_mut`
+    ///
+    /// The time to live field of a record, `None` for OPT whose TTL field carries flags.
+    pub fn ttl_mut(&mut self) -> Option<&mut u32> {
+        match self {
+            Record::UNKNOWN { ttl, .. }
+            | Record::A { ttl, .. }
+            | Record::NS { ttl, .. }
+            | Record::CNAME { ttl, .. }
+            | Record::SOA { ttl, .. }
+            | Record::PTR { ttl, .. }
+            | Record::HINFO { ttl, .. }
+            | Record::MX { ttl, .. }
+            | Record::AAAA { ttl, .. }
+            | Record::DS { ttl, .. }
+            | Record::RRSIG { ttl, .. }
+            | Record::NSEC { ttl, .. }
+            | Record::DNSKEY { ttl, .. } => Some(ttl),
+            Record::OPT { .. } => None,
+        }
+    }
+
+    /// # `rdata`
+    ///
+    /// The data of the record as it's written in the zone files, empty for OPT.
+    pub fn rdata(&self) -> RData<'_> {
+        RData(self)
+    }
+
+    /// # `get_domain`
+    ///
+    /// Gives back the domain name that owns the record.
+    pub fn get_domain(&self) -> &str {
+        match self {
+            Record::UNKNOWN { domain, .. }
+            | Record::A { domain, .. }
+            | Record::NS { domain, .. }
+            | Record::CNAME { domain, .. }
+            | Record::SOA { domain, .. }
+            | Record::PTR { domain, .. }
+            | Record::HINFO { domain, .. }
+            | Record::MX { domain, .. }
+            | Record::AAAA { domain, .. }
+            | Record::DS { domain, .. }
+            | Record::RRSIG { domain, .. }
+            | Record::NSEC { domain, .. }
+            | Record::DNSKEY { domain, .. } => domain,
+            Record::OPT { .. } => "",
+        }
+    }
+
+    /// # `domain_mut`
+    ///
+    /// The domain name that owns the record, `None` for OPT which has none.
+    pub fn domain_mut(&mut self) -> Option<&mut DnsName> {
+        match self {
+            Record::UNKNOWN { domain, .. }
+            | Record::A { domain, .. }
+            | Record::NS { domain, .. }
+            | Record::CNAME { domain, .. }
+            | Record::SOA { domain, .. }
+            | Record::PTR { domain, .. }
+            | Record::HINFO { domain, .. }
+            | Record::MX { domain, .. }
+            | Record::AAAA { domain, .. }
+            | Record::DS { domain, .. }
+            | Record::RRSIG { domain, .. }
+            | Record::NSEC { domain, .. }
+            | Record::DNSKEY { domain, .. } => Some(domain),
+            Record::OPT { .. } => None,
+        }
+    }
+
+    /// # `get_qtype`
+    ///
+    /// Gives back the type of the record.
+    pub fn get_qtype(&self) -> QueryType {
+        match self {
+            Record::UNKNOWN { qtype, .. } => QueryType::from_num(*qtype),
+            Record::A { .. } => QueryType::A,
+            Record::NS { .. } => QueryType::NS,
+            Record::CNAME { .. } => QueryType::CNAME,
+            Record::SOA { .. } => QueryType::SOA,
+            Record::PTR { .. } => QueryType::PTR,
+            Record::HINFO { .. } => QueryType::HINFO,
+            Record::MX { .. } => QueryType::MX,
+            Record::AAAA { .. } => QueryType::AAAA,
+            Record::DS { .. } => QueryType::DS,
+            Record::RRSIG { .. } => QueryType::RRSIG,
+            Record::NSEC { .. } => QueryType::NSEC,
+            Record::DNSKEY { .. } => QueryType::DNSKEY,
+            Record::OPT { .. } => QueryType::OPT,
+        }
+    }
+
+    /// # `wire_rdata`
+    ///
+    /// The data section of the record as it's written on the wire, names uncompressed.
+    pub fn wire_rdata(&self) -> CResult<Vec<u8>> {
+        let mut owner = BytePacketBuffer::empty();
+        owner.write_qname(self.get_domain())?;
+        let owner_len = owner.pos();
+        let mut buffer = BytePacketBuffer::empty();
+        let len = self.write(&mut buffer)?;
+        // Skipping owner, type, class, ttl and length of the data
+        Ok(buffer
+            .get_range(owner_len + 10, len - owner_len - 10)?
+            .to_vec())
+    }
+
+    /// # `canonical_rdata`
+    ///
+    /// The data section of the record in canonical form, as it's signed and digested:
+    /// the names it contains in lowercase (RFC 4034 section 6.2, NSEC excluded as
+    /// RFC 6840 section 5.1 requires).
+    pub fn canonical_rdata(&self) -> CResult<Vec<u8>> {
+        let mut record = self.clone();
+        match &mut record {
+            Record::NS { host, .. }
+            | Record::CNAME { host, .. }
+            | Record::PTR { host, .. }
+            | Record::MX { host, .. } => *host = host.to_lowercase().as_str().into(),
+            Record::SOA { mname, rname, .. } => {
+                *mname = mname.to_lowercase().as_str().into();
+                *rname = rname.to_lowercase().as_str().into();
+            }
+            Record::RRSIG { signer_name, .. } => {
+                *signer_name = signer_name.to_lowercase().as_str().into()
+            }
+            _ => {}
         }
+        record.wire_rdata()
+    }
+
+    /// # `from_wire_rdata`
+    ///
+    /// The record of `domain` of type `qtype` whose data section, as `wire_rdata`
+    /// gives it, is `rdata`.
+    pub fn from_wire_rdata(
+        domain: DnsName,
+        qtype: QueryType,
+        ttl: u32,
+        rdata: &[u8],
+    ) -> CResult<Record> {
+        let len = u16::try_from(rdata.len())
+            .map_err(|_| DnsError::Malformed("The data of the record is too long".to_string()))?;
+        let mut buffer = BytePacketBuffer::empty();
+        buffer.write_qname(&domain)?;
+        buffer.write_u16(qtype.to_num())?;
+        buffer.write_u16(1)?;
+        buffer.write_u32(ttl)?;
+        buffer.write_u16(len)?;
+        buffer.write_bytes(rdata)?;
+        buffer.seek(0)?;
+        Record::read(&mut buffer)
+    }
+
+    /// # `from_presentation`
+    ///
+    /// The record of `domain` of type `qtype` whose data is written as in a zone file,
+    /// `data`: an address, a name, or the priority and the name of an MX record;
+    /// fails for the other types.
+    pub fn from_presentation(
+        domain: DnsName,
+        qtype: QueryType,
+        ttl: u32,
+        data: &str,
+    ) -> Result<Record, String> {
+        let data = data.trim();
+        let host = || data.trim_end_matches('.').to_lowercase().into();
+        let invalid = || format!("{} isn't valid data for {}", data, qtype);
+        let record = match qtype {
+            QueryType::A => Record::A {
+                domain,
+                addr: data.parse::<Ipv4Addr>().map_err(|_| invalid())?,
+                ttl,
+            },
+            QueryType::AAAA => Record::AAAA {
+                domain,
+                addr: data.parse::<Ipv6Addr>().map_err(|_| invalid())?,
+                ttl,
+            },
+            QueryType::CNAME => Record::CNAME {
+                domain,
+                host: host(),
+                ttl,
+            },
+            QueryType::NS => Record::NS {
+                domain,
+                host: host(),
+                ttl,
+            },
+            QueryType::PTR => Record::PTR {
+                domain,
+                host: host(),
+                ttl,
+            },
+            QueryType::MX => {
+                let (priority, host) = data.split_once(' ').ok_or_else(invalid)?;
+                Record::MX {
+                    domain,
+                    priority: priority.parse().map_err(|_| invalid())?,
+                    host: host.trim().trim_end_matches('.').to_lowercase().into(),
+                    ttl,
+                }
+            }
+            qtype => return Err(format!("the records of type {} aren't supported", qtype)),
+        };
+        Ok(record)
     }
 
     /// # `register_record`
     ///
-    /// This method registers the record in the cache database.
+    /// This method queues the record to be registered in the cache database,
+    /// to be served only to the clients in `ecs_network`, if any,
+    /// expiring its TTL after the time of `clock`.
+    /// The OPT pseudo record isn't data, it can't be cached.
     #[tracing::instrument(
         name = "Registering a new record in the cache database",
-        skip(self, db_pool)
+        skip(self, cache, clock)
     )]
-    pub async fn register_record(&self, db_pool: &SqlitePool) -> CResult<Ipv4Addr> {
-        match self {
-            // TODO: we need to think about different record types
-            Record::A { domain, addr, ttl } => {
-                // Using the newly find server as name server
-                let expiration_date = Local::now() + Duration::from_secs(*ttl as u64);
-                sqlx::query(r#"INSERT INTO entries (address, domain, expiration_date, ttl, record_type) VALUES ($1, $2, $3, $4, $5)"#)
-                            .bind(addr.to_string())
-                            .bind(domain)
-                            .bind(expiration_date)
-                            .bind(ttl)
-                            .bind(1)
-                            .execute(db_pool)
-                            .await?;
-                tracing::info!("Registerd a new entry for the domain {}", domain);
-                return Ok(addr.clone());
-            }
-            _other => {
-                // TODO: if this happens, it means that we have received a malformed packet
-                // from one of the servers that we have encoutered, we need to investigate what the
-                // correct response is in this situation, for the time being we are going to
-                // responsd with a server fail error
-                return Err("Expected a A Record from a name server, got something else. Responding to the client with a Server Fail packet.".into());
+    pub fn register_record(
+        &self,
+        cache: &CacheWriter,
+        ecs_network: Option<IpNet>,
+        clock: &dyn Clock,
+    ) -> CResult<()> {
+        if let Record::OPT { .. } = self {
+            return Err(DnsError::Upstream(
+                "The OPT pseudo record can't be cached".to_string(),
+            ));
+        }
+        let ttl = self.get_ttl();
+        cache.write(CacheEntry {
+            record: self.clone(),
+            expiration_date: clock.now() + Duration::from_secs(ttl as u64),
+            ecs_network,
+        });
+        Ok(())
+    }
+}
+
+impl fmt::Display for Record {
+    /// The record as a line of a zone file, the OPT pseudo record as `dig` shows it.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Record::OPT {
+            packet_len,
+            version,
+            flags,
+            options,
+            ..
+        } = self
+        {
+            let do_bit = if flags & DO_FLAG != 0 { " do" } else { "" };
+            write!(
+                f,
+                "; EDNS: version: {}, flags:{}; udp: {}",
+                version, do_bit, packet_len
+            )?;
+            for option in options {
+                write!(f, "\n; OPTION {}: {}", option.code, hex(&option.data))?;
+            }
+            return Ok(());
+        }
+
+        write!(
+            f,
+            "{}\t{}\tIN\t{}\t{}",
+            absolute(self.get_domain()),
+            self.get_ttl(),
+            self.get_qtype(),
+            self.rdata()
+        )
+    }
+}
+
+/// # `RData`
+///
+/// The data of a record in the presentation format, as `Record::rdata` gives it.
+pub struct RData<'a>(&'a Record);
+
+impl fmt::Display for RData<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Record::UNKNOWN { data, .. } if data.is_empty() => write!(f, "\\# 0"),
+            Record::UNKNOWN { data, .. } => write!(f, "\\# {} {}", data.len(), hex(data)),
+            Record::A { addr, .. } => write!(f, "{}", addr),
+            Record::AAAA { addr, .. } => write!(f, "{}", addr),
+            Record::NS { host, .. } | Record::CNAME { host, .. } | Record::PTR { host, .. } => {
+                write!(f, "{}", absolute(host))
+            }
+            Record::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ..
+            } => write!(
+                f,
+                "{} {} {} {} {} {} {}",
+                absolute(mname),
+                absolute(rname),
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum
+            ),
+            Record::HINFO { cpu, os, .. } => write!(f, "{:?} {:?}", cpu, os),
+            Record::MX { priority, host, .. } => write!(f, "{} {}", priority, absolute(host)),
+            Record::DS {
+                key_tag,
+                algorithm,
+                digest_type,
+                digest,
+                ..
+            } => write!(
+                f,
+                "{} {} {} {}",
+                key_tag,
+                algorithm,
+                digest_type,
+                hex(digest)
+            ),
+            Record::RRSIG {
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                expiration,
+                inception,
+                key_tag,
+                signer_name,
+                signature,
+                ..
+            } => write!(
+                f,
+                "{} {} {} {} {} {} {} {} {}",
+                QueryType::from_num(*type_covered),
+                algorithm,
+                labels,
+                original_ttl,
+                timestamp(*expiration),
+                timestamp(*inception),
+                key_tag,
+                absolute(signer_name),
+                STANDARD.encode(signature)
+            ),
+            Record::NSEC {
+                next_domain,
+                type_bitmap,
+                ..
+            } => {
+                write!(f, "{}", absolute(next_domain))?;
+                for qtype in bitmap_types(type_bitmap) {
+                    write!(f, " {}", QueryType::from_num(qtype))?;
+                }
+                Ok(())
             }
+            Record::DNSKEY {
+                flags,
+                protocol,
+                algorithm,
+                public_key,
+                ..
+            } => write!(
+                f,
+                "{} {} {} {}",
+                flags,
+                protocol,
+                algorithm,
+                STANDARD.encode(public_key)
+            ),
+            Record::OPT { .. } => Ok(()),
         }
     }
 }
+
+/// # `read_rest`
+///
+/// `Record::read`'s helper, reads the bytes left in the data section of a record
+/// that started at `start` and is `data_len` bytes long.
+fn read_rest(buffer: &mut BytePacketBuffer, start: usize, data_len: u16) -> CResult<Vec<u8>> {
+    let end = start + data_len as usize;
+    if buffer.pos() > end {
+        return Err(DnsError::Malformed(
+            "Record data exceeds its declared length".to_string(),
+        ));
+    }
+    let rest = buffer.get_range(buffer.pos(), end - buffer.pos())?.to_vec();
+    buffer.seek(end)?;
+    Ok(rest)
+}
+
+/// # `read_character_string`
+///
+/// `Record::read`'s helper, reads a `<character-string>`: a length byte followed
+/// by that many bytes, decoded lossily.
+fn read_character_string(buffer: &mut BytePacketBuffer) -> CResult<String> {
+    let len = buffer.read_u8()? as usize;
+    let bytes = buffer.get_range(buffer.pos(), len)?;
+    let string = String::from_utf8_lossy(bytes).into_owned();
+    buffer.step(len)?;
+    Ok(string)
+}
+
+/// # `write_character_string`
+///
+/// `Record::write`'s helper, writes a `<character-string>`, cut to 255 bytes.
+fn write_character_string(buffer: &mut BytePacketBuffer, string: &str) -> CResult<()> {
+    let bytes = &string.as_bytes()[..string.len().min(255)];
+    buffer.write_u8(bytes.len() as u8)?;
+    buffer.write_bytes(bytes)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02X}", byte)).collect()
+}
+
+/// # `timestamp`
+///
+/// The signature times of the RRSIG records as `YYYYMMDDHHmmSS`, in UTC.
+fn timestamp(secs: u32) -> String {
+    DateTime::from_timestamp(secs as i64, 0)
+        .map(|time| time.format("%Y%m%d%H%M%S").to_string())
+        .unwrap_or_else(|| secs.to_string())
+}
+
+/// # `base64_bytes`
+///
+/// Serializes the binary data of the records as base64 rather than as an array of numbers.
+mod base64_bytes {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        STANDARD
+            .decode(String::deserialize(deserializer)?)
+            .map_err(de::Error::custom)
+    }
+}
@@ -0,0 +1,137 @@
+use std::{env, io, time::Duration};
+
+/// First file descriptor passed by socket activation (`SD_LISTEN_FDS_START`).
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// # `listen_socket`
+///
+/// Takes the UDP socket passed by systemd socket activation, `None` if the process
+/// hasn't been started that way. Only the first socket passed is used.
+/// The variables describing the sockets are removed, so they aren't inherited;
+/// the environment can only be changed safely before the runtime starts its threads.
+#[cfg(unix)]
+pub fn listen_socket() -> io::Result<Option<std::net::UdpSocket>> {
+    use std::os::fd::FromRawFd;
+
+    let for_us = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_some_and(|pid| pid == std::process::id());
+    let fds = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|fds| fds.parse::<i32>().ok())
+        .unwrap_or(0);
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    if !for_us || fds < 1 {
+        return Ok(None);
+    }
+
+    let fd = LISTEN_FDS_START;
+    let mut kind: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: `kind` and `len` outlive the call and `len` is the size of `kind`
+    let checked = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            &mut kind as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if checked != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if kind != libc::SOCK_DGRAM {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The socket passed by systemd isn't a datagram socket",
+        ));
+    }
+    // SAFETY: systemd passed the descriptor to this process, nothing else owns it
+    let sock = unsafe { std::net::UdpSocket::from_raw_fd(fd) };
+    sock.set_nonblocking(true)?;
+    Ok(Some(sock))
+}
+
+#[cfg(not(unix))]
+pub fn listen_socket() -> io::Result<Option<std::net::UdpSocket>> {
+    Ok(None)
+}
+
+/// # `notify`
+///
+/// Sends `state` to the service manager, as `sd_notify` does: `READY=1`, `STOPPING=1`,
+/// `WATCHDOG=1`...
+/// Returns false if the process isn't supervised by systemd.
+#[cfg(unix)]
+pub fn notify(state: &str) -> io::Result<bool> {
+    use std::os::unix::net::UnixDatagram;
+
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(false);
+    };
+    let sock = UnixDatagram::unbound()?;
+    let path = path.to_string_lossy();
+    match path.strip_prefix('@') {
+        // A socket in the abstract namespace
+        Some(name) => {
+            #[cfg(target_os = "linux")]
+            {
+                use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+                let addr = SocketAddr::from_abstract_name(name.as_bytes())?;
+                sock.send_to_addr(state.as_bytes(), &addr)?;
+            }
+            #[cfg(not(target_os = "linux"))]
+            {
+                let _ = name;
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "Abstract sockets are only supported on Linux",
+                ));
+            }
+        }
+        None => {
+            sock.send_to(state.as_bytes(), &*path)?;
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(not(unix))]
+pub fn notify(_state: &str) -> io::Result<bool> {
+    Ok(false)
+}
+
+/// # `watchdog_interval`
+///
+/// How often the watchdog expects to hear from us, half of the timeout configured
+/// by `WatchdogSec=`; `None` if the watchdog isn't enabled for this process.
+pub fn watchdog_interval() -> Option<Duration> {
+    let for_us = env::var("WATCHDOG_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_none_or(|pid| pid == std::process::id());
+    let usec = env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse::<u64>().ok())
+        .filter(|usec| *usec > 0)?;
+    for_us.then(|| Duration::from_micros(usec / 2))
+}
+
+/// # `watchdog`
+///
+/// Keeps the watchdog of the service manager fed every `interval`.
+pub async fn watchdog(interval: Duration) {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        if let Err(e) = notify("WATCHDOG=1") {
+            tracing::warn!("Unable to feed the systemd watchdog: {}", e);
+        }
+    }
+}
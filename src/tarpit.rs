@@ -0,0 +1,245 @@
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::BuildHasher,
+    net::{IpAddr, Ipv6Addr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::{
+    configuration::Settings,
+    telemetry::{TARPIT_CLIENTS, TARPIT_OFFENSES, TARPIT_PENALTIES},
+};
+
+/// A client earns at most a strike per interval, the penalties grow with the length
+/// of the abuse rather than with its volume.
+const STRIKE_INTERVAL: Duration = Duration::from_secs(1);
+/// Clients the tarpit keeps track of; past that the well-behaved ones are forgotten,
+/// and if the penalized ones are as many, the new clients aren't tracked.
+const MAX_CLIENTS: usize = 65536;
+/// The clients are split among as many maps, each with a lock of its own.
+const SHARDS: usize = 16;
+
+/// # `Offense`
+///
+/// What a client did wrong.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Offense {
+    /// It sent more queries than its rate allows.
+    Rate,
+    /// It sent a packet that isn't a DNS message.
+    Malformed,
+}
+
+impl Offense {
+    fn as_str(self) -> &'static str {
+        match self {
+            Offense::Rate => "rate",
+            Offense::Malformed => "malformed",
+        }
+    }
+}
+
+/// # `Penalty`
+///
+/// What happens to a query of a client, given its strikes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Penalty {
+    /// The query is handled right away.
+    None,
+    /// The query is handled once the delay is over.
+    Delay(Duration),
+    /// The query is dropped, without a response.
+    Drop,
+}
+
+/// What the tarpit knows of a client.
+#[derive(Debug)]
+struct Client {
+    /// Queries the client may still send at once.
+    tokens: f64,
+    refilled: Instant,
+    strikes: u32,
+    last_offense: Option<Instant>,
+}
+
+/// # `Tarpit`
+///
+/// Escalating penalties for the abusive clients. A client earns a strike every second it
+/// sends more queries than `rate` per second, past a burst of `burst`, or malformed packets;
+/// its queries are delayed by `delay` at the first strike, the delay doubling at every
+/// following one, and dropped from the `drop_after`th on. The strikes are forgotten once
+/// the client has behaved for `forget_after`.
+/// The IPv6 clients are told apart by their /64, the network a host usually gets,
+/// otherwise a single host could earn a clean slate with every address.
+#[derive(Debug)]
+pub struct Tarpit {
+    rate: f64,
+    burst: f64,
+    delay: Duration,
+    drop_after: u32,
+    forget_after: Duration,
+    clients: Vec<Mutex<HashMap<IpAddr, Client>>>,
+    /// Picks the map of a client.
+    hasher: RandomState,
+    /// Bounds the queries waiting for their delay to be over.
+    delayed: Arc<Semaphore>,
+}
+
+impl Tarpit {
+    /// # `from_settings`
+    ///
+    /// `None` if the tarpit is disabled.
+    pub fn from_settings(settings: &Settings) -> Option<Self> {
+        if !settings.get_tarpit_enabled() {
+            return None;
+        }
+        Some(Tarpit {
+            rate: settings.get_tarpit_rate() as f64,
+            burst: settings.get_tarpit_burst() as f64,
+            delay: settings.get_tarpit_delay(),
+            drop_after: settings.get_tarpit_drop_after(),
+            forget_after: settings.get_tarpit_forget_after(),
+            clients: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+            hasher: RandomState::new(),
+            delayed: Arc::new(Semaphore::new(settings.get_max_in_flight_queries())),
+        })
+    }
+
+    /// # `admit`
+    ///
+    /// Counts a query of `client` against its rate, returns the penalty it gets.
+    pub fn admit(&self, client: IpAddr) -> Penalty {
+        let now = Instant::now();
+        self.with_client(client, now, |state| self.admit_client(state, now))
+    }
+
+    fn admit_client(&self, state: &mut Client, now: Instant) -> Penalty {
+        let elapsed = now.duration_since(state.refilled).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * self.rate).min(self.burst);
+        state.refilled = now;
+        if state.tokens >= 1.0 {
+            state.tokens -= 1.0;
+        } else {
+            self.strike(state, Offense::Rate, now);
+        }
+        let penalty = self.penalty(state, now);
+        match penalty {
+            Penalty::None => {}
+            Penalty::Delay(_) => {
+                metrics::counter!(TARPIT_PENALTIES, "penalty" => "delay").increment(1)
+            }
+            Penalty::Drop => metrics::counter!(TARPIT_PENALTIES, "penalty" => "drop").increment(1),
+        }
+        penalty
+    }
+
+    /// # `offend`
+    ///
+    /// Notes that `client` committed `offense`.
+    pub fn offend(&self, client: IpAddr, offense: Offense) {
+        let now = Instant::now();
+        self.with_client(client, now, |state| self.strike(state, offense, now));
+    }
+
+    /// # `delay_slot`
+    ///
+    /// A slot for a query to wait for its delay to be over in, `None` if too many
+    /// are waiting already: the query is dropped.
+    pub fn delay_slot(&self) -> Option<OwnedSemaphorePermit> {
+        self.delayed.clone().try_acquire_owned().ok()
+    }
+
+    /// # `run`
+    ///
+    /// Forgets the clients that have behaved for `forget_after`, every `forget_after`.
+    pub async fn run(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(self.forget_after);
+        loop {
+            interval.tick().await;
+            self.prune(Instant::now());
+        }
+    }
+
+    /// # `with_client`
+    ///
+    /// Calls `f` with what the tarpit knows of `client`, a new client is added unless
+    /// `MAX_CLIENTS` are known: the ones without strikes are forgotten to make room,
+    /// if there's still none `f` gets a client that is forgotten right after.
+    fn with_client<R>(&self, client: IpAddr, now: Instant, f: impl FnOnce(&mut Client) -> R) -> R {
+        let key = client_key(client);
+        let shard = self.hasher.hash_one(key) as usize % SHARDS;
+        let mut clients = self.clients[shard].lock().unwrap();
+        if !clients.contains_key(&key) && clients.len() >= MAX_CLIENTS / SHARDS {
+            clients.retain(|_, client| client.strikes > 0);
+            if clients.len() >= MAX_CLIENTS / SHARDS {
+                return f(&mut self.new_client(now));
+            }
+        }
+        f(clients.entry(key).or_insert_with(|| self.new_client(now)))
+    }
+
+    fn new_client(&self, now: Instant) -> Client {
+        Client {
+            tokens: self.burst,
+            refilled: now,
+            strikes: 0,
+            last_offense: None,
+        }
+    }
+
+    fn prune(&self, now: Instant) {
+        let mut penalized = 0;
+        for clients in &self.clients {
+            let mut clients = clients.lock().unwrap();
+            clients.retain(|_, client| {
+                now.duration_since(client.refilled) < self.forget_after
+                    || client
+                        .last_offense
+                        .is_some_and(|last| now.duration_since(last) < self.forget_after)
+            });
+            penalized += clients.values().filter(|client| client.strikes > 0).count();
+        }
+        metrics::gauge!(TARPIT_CLIENTS).set(penalized as f64);
+    }
+
+    fn strike(&self, client: &mut Client, offense: Offense, now: Instant) {
+        metrics::counter!(TARPIT_OFFENSES, "kind" => offense.as_str()).increment(1);
+        match client.last_offense {
+            Some(last) if now.duration_since(last) < STRIKE_INTERVAL => return,
+            Some(last) if now.duration_since(last) >= self.forget_after => client.strikes = 1,
+            _ => client.strikes += 1,
+        }
+        client.last_offense = Some(now);
+    }
+
+    fn penalty(&self, client: &mut Client, now: Instant) -> Penalty {
+        if client
+            .last_offense
+            .is_some_and(|last| now.duration_since(last) >= self.forget_after)
+        {
+            client.strikes = 0;
+            client.last_offense = None;
+        }
+        match client.strikes {
+            0 => Penalty::None,
+            strikes if strikes >= self.drop_after => Penalty::Drop,
+            strikes => Penalty::Delay(self.delay.saturating_mul(1 << (strikes - 1).min(16))),
+        }
+    }
+}
+
+/// # `client_key`
+///
+/// What tells a client apart: its IPv4 address or the /64 of its IPv6 address.
+fn client_key(client: IpAddr) -> IpAddr {
+    match client {
+        IpAddr::V4(_) => client,
+        IpAddr::V6(addr) => {
+            let network = u128::from(addr) & !(u64::MAX as u128);
+            IpAddr::V6(Ipv6Addr::from(network))
+        }
+    }
+}
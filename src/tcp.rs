@@ -0,0 +1,334 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use bytes::Buf;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{tcp::OwnedWriteHalf, TcpListener, TcpStream},
+    sync::{mpsc, oneshot, watch, OwnedSemaphorePermit, Semaphore},
+    time::timeout,
+};
+
+use crate::{
+    configuration::Settings,
+    server::handle,
+    structs::buffer::BytePacketBuffer,
+    udp::Responder,
+    workers::{OverflowPolicy, ServerState},
+};
+
+/// Responses of a connection waiting to be written before its handlers have to wait.
+const RESPONSE_QUEUE: usize = 16;
+
+/// # `TcpLimits`
+///
+/// How long the clients are waited for and how many connections they may open.
+#[derive(Debug, Clone, Copy)]
+pub struct TcpLimits {
+    /// Time a connection is kept open without queries.
+    pub idle_timeout: Duration,
+    /// Time a client is given to send a message once its length has arrived.
+    pub read_timeout: Duration,
+    pub max_connections: usize,
+    pub max_connections_per_ip: usize,
+}
+
+impl TcpLimits {
+    pub fn from_settings(settings: &Settings) -> Self {
+        TcpLimits {
+            idle_timeout: settings.get_tcp_idle_timeout(),
+            read_timeout: settings.get_tcp_read_timeout(),
+            max_connections: settings.get_tcp_max_connections(),
+            max_connections_per_ip: settings.get_tcp_max_connections_per_ip(),
+        }
+    }
+}
+
+/// # `Connections`
+///
+/// The connections open, counted overall and by client address, along with the ones
+/// waiting for a query.
+struct Connections {
+    slots: Arc<Semaphore>,
+    per_ip: Mutex<HashMap<IpAddr, usize>>,
+    max_per_ip: usize,
+    /// The connections waiting for a query, in the order they started waiting,
+    /// each one closed when its sender fires to make room for a new one.
+    idle: Mutex<BTreeMap<u64, oneshot::Sender<()>>>,
+    next_idle: AtomicU64,
+}
+
+/// # `Refusal`
+///
+/// Why a connection can't be counted.
+enum Refusal {
+    /// Its client has too many open already.
+    PerIp,
+    /// Too many are open overall.
+    Full,
+}
+
+impl Connections {
+    fn new(limits: &TcpLimits) -> Self {
+        Connections {
+            slots: Arc::new(Semaphore::new(limits.max_connections)),
+            per_ip: Mutex::new(HashMap::new()),
+            max_per_ip: limits.max_connections_per_ip,
+            idle: Mutex::new(BTreeMap::new()),
+            next_idle: AtomicU64::new(0),
+        }
+    }
+
+    /// # `open`
+    ///
+    /// Counts a new connection of `ip`, refused if it would exceed one of the limits.
+    fn open(self: &Arc<Self>, ip: IpAddr) -> Result<Slot, Refusal> {
+        let permit = self
+            .slots
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| Refusal::Full)?;
+        self.count(ip, permit)
+    }
+
+    /// # `open_evicting`
+    ///
+    /// Counts a new connection of `ip` once the connection that has been waiting for
+    /// a query the longest has been closed to make room for it, waiting for that
+    /// at most `wait`; `None` if no connection is waiting for a query.
+    async fn open_evicting(self: &Arc<Self>, ip: IpAddr, wait: Duration) -> Option<Slot> {
+        let oldest = self.idle.lock().unwrap().pop_first();
+        let (_, evict) = oldest?;
+        let _ = evict.send(());
+        let permit = timeout(wait, self.slots.clone().acquire_owned())
+            .await
+            .ok()?
+            .ok()?;
+        self.count(ip, permit).ok()
+    }
+
+    /// # `count`
+    ///
+    /// Counts a connection of `ip` holding `permit`, unless `ip` has too many open already.
+    fn count(self: &Arc<Self>, ip: IpAddr, permit: OwnedSemaphorePermit) -> Result<Slot, Refusal> {
+        let mut per_ip = self.per_ip.lock().unwrap();
+        if per_ip.get(&ip).is_some_and(|open| *open >= self.max_per_ip) {
+            tracing::info!("Too many TCP connections from {}, refusing a new one", ip);
+            return Err(Refusal::PerIp);
+        }
+        *per_ip.entry(ip).or_insert(0) += 1;
+        Ok(Slot {
+            connections: self.clone(),
+            ip,
+            _permit: permit,
+        })
+    }
+
+    /// # `idle`
+    ///
+    /// Notes that a connection is waiting for a query until the returned `Idle` is dropped,
+    /// `evict` fires if it's closed to make room for a new one.
+    fn idle(&self, evict: oneshot::Sender<()>) -> Idle<'_> {
+        let id = self.next_idle.fetch_add(1, Ordering::Relaxed);
+        self.idle.lock().unwrap().insert(id, evict);
+        Idle {
+            connections: self,
+            id,
+        }
+    }
+}
+
+/// # `Idle`
+///
+/// A connection waiting for a query, it's no longer once dropped.
+struct Idle<'a> {
+    connections: &'a Connections,
+    id: u64,
+}
+
+impl Drop for Idle<'_> {
+    fn drop(&mut self) {
+        self.connections.idle.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// # `Slot`
+///
+/// A connection counted by `Connections`, released once dropped.
+struct Slot {
+    connections: Arc<Connections>,
+    ip: IpAddr,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut per_ip = self.connections.per_ip.lock().unwrap();
+        if let Some(open) = per_ip.get_mut(&self.ip) {
+            *open -= 1;
+            if *open == 0 {
+                per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// # `serve`
+///
+/// Accepts the TCP connections of the clients until `closed` is dropped, every connection
+/// carries any number of queries (RFC 7766 section 6.2.1), handled as the datagrams are.
+/// The connections exceeding the limit of their client are closed at once, the ones
+/// exceeding the overall limit take the place of the connection that has been waiting
+/// for a query the longest, if any, otherwise they are closed as well.
+pub async fn serve(
+    listener: TcpListener,
+    state: Arc<ServerState>,
+    limits: TcpLimits,
+    overflow_policy: OverflowPolicy,
+    mut closed: watch::Receiver<()>,
+) {
+    let connections = Arc::new(Connections::new(&limits));
+    loop {
+        let accepted = tokio::select! {
+            _ = closed.changed() => return,
+            accepted = listener.accept() => accepted,
+        };
+        match accepted {
+            Ok((stream, src)) => {
+                let (connections, state, closed) =
+                    (connections.clone(), state.clone(), closed.clone());
+                let slot = match connections.open(src.ip()) {
+                    Ok(slot) => slot,
+                    Err(Refusal::PerIp) => continue,
+                    // The room is made in the background, the listener keeps accepting
+                    Err(Refusal::Full) => {
+                        tokio::spawn(async move {
+                            let ip = src.ip();
+                            match connections.open_evicting(ip, limits.read_timeout).await {
+                                Some(slot) => {
+                                    connection(
+                                        stream,
+                                        src,
+                                        slot,
+                                        state,
+                                        limits,
+                                        overflow_policy,
+                                        closed,
+                                    )
+                                    .await
+                                }
+                                None => tracing::warn!(
+                                    "Too many TCP connections, refusing the one of {}",
+                                    src
+                                ),
+                            }
+                        });
+                        continue;
+                    }
+                };
+                tokio::spawn(connection(
+                    stream,
+                    src,
+                    slot,
+                    state,
+                    limits,
+                    overflow_policy,
+                    closed,
+                ));
+            }
+            Err(e) => tracing::info!("Failed to accept a TCP connection: {}", e),
+        }
+    }
+}
+
+/// # `connection`
+///
+/// `serve`'s helper, reads the queries of a connection and hands them to the query
+/// handlers without waiting for the previous ones to be answered, the responses are
+/// written as they come (RFC 7766 section 7).
+/// The length of a message has to arrive within the idle timeout, the message within
+/// the read timeout. The connection is closed once the client has been idle for too long,
+/// sends too slowly, the listener needs its room or the server shuts down, after
+/// the queries received have been answered.
+async fn connection(
+    stream: TcpStream,
+    src: SocketAddr,
+    slot: Slot,
+    state: Arc<ServerState>,
+    limits: TcpLimits,
+    overflow_policy: OverflowPolicy,
+    mut closed: watch::Receiver<()>,
+) {
+    let (mut reader, writer) = stream.into_split();
+    let (conn, responses) = mpsc::channel(RESPONSE_QUEUE);
+    let writer_task = tokio::spawn(write_responses(writer, responses, src));
+    let responder = Responder::tcp(conn, limits.idle_timeout);
+    loop {
+        let (evict, evicted) = oneshot::channel();
+        let idle = slot.connections.idle(evict);
+        let len = tokio::select! {
+            _ = closed.changed() => break,
+            Ok(()) = evicted => {
+                tracing::info!("Closing the idle TCP connection of {} to make room", src);
+                break;
+            }
+            len = timeout(limits.idle_timeout, reader.read_u16()) => match len {
+                Ok(Ok(len)) => len as usize,
+                // Closed by the client, broken or idle for too long
+                _ => break,
+            },
+        };
+        drop(idle);
+        let mut req_buffer = BytePacketBuffer::with_size(len);
+        if !matches!(
+            timeout(limits.read_timeout, reader.read_exact(&mut req_buffer.buf)).await,
+            Ok(Ok(_))
+        ) {
+            tracing::info!("{} didn't send a whole query in time, closing", src);
+            break;
+        }
+        handle(
+            req_buffer,
+            src,
+            &responder,
+            &state,
+            &state.in_flight,
+            overflow_policy,
+        )
+        .await;
+    }
+    // The writer stops once the handlers still running have sent their responses
+    drop(responder);
+    let _ = writer_task.await;
+}
+
+/// # `write_responses`
+///
+/// `connection`'s task, writes the responses of the query handlers, each one
+/// prefixed by its length, then closes the connection.
+async fn write_responses(
+    mut writer: OwnedWriteHalf,
+    mut responses: mpsc::Receiver<Vec<u8>>,
+    src: SocketAddr,
+) {
+    while let Some(response) = responses.recv().await {
+        let Ok(len) = u16::try_from(response.len()) else {
+            tracing::warn!("A response for {} doesn't fit in a TCP message", src);
+            continue;
+        };
+        let len_prefix = len.to_be_bytes();
+        let mut message = Buf::chain(&len_prefix[..], &response[..]);
+        if let Err(e) = writer.write_all_buf(&mut message).await {
+            tracing::info!("Failed to respond to {} over TCP: {}", src, e);
+            return;
+        }
+    }
+    let _ = writer.shutdown().await;
+}
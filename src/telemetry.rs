@@ -1,9 +1,145 @@
-use tracing::{subscriber::set_global_default, Subscriber};
+use std::{
+    io::IsTerminal,
+    net::SocketAddr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use metrics::{describe_counter, describe_gauge, describe_histogram, Unit};
+use metrics_exporter_prometheus::{BuildError, PrometheusBuilder};
+use opentelemetry::{
+    trace::{TraceError, TracerProvider as _},
+    KeyValue,
+};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    runtime,
+    trace::{Tracer, TracerProvider},
+    Resource,
+};
+use serde::Deserialize;
+use tracing::{subscriber::set_global_default, Event, Level, Subscriber};
 use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorageLayer};
-use tracing_subscriber::{fmt::MakeWriter, layer::SubscriberExt, EnvFilter, Registry};
+use tracing_subscriber::{
+    fmt::MakeWriter,
+    layer::{Context, Layer, SubscriberExt},
+    EnvFilter, Registry,
+};
+
+/// Time taken to respond to a client query, by response code.
+pub const QUERY_DURATION: &str = "dns_query_duration_seconds";
+/// Time taken by the iterative resolutions.
+pub const RESOLUTION_DURATION: &str = "dns_resolution_duration_seconds";
+/// Queries sent to other name servers by a single resolution.
+pub const RESOLUTION_ROUND_TRIPS: &str = "dns_resolution_round_trips";
+/// Queries sent to other name servers, by outcome.
+pub const UPSTREAM_QUERIES: &str = "dns_upstream_queries_total";
+/// Time taken by the other name servers to respond.
+pub const UPSTREAM_RTT: &str = "dns_upstream_rtt_seconds";
+/// Offenses of the clients, by kind.
+pub const TARPIT_OFFENSES: &str = "dns_tarpit_offenses_total";
+/// Queries of the abusive clients delayed or dropped, by penalty.
+pub const TARPIT_PENALTIES: &str = "dns_tarpit_penalties_total";
+/// Clients with strikes not forgotten yet.
+pub const TARPIT_CLIENTS: &str = "dns_tarpit_clients";
+
+/// # `LogFormat`
+///
+/// How the events are written.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// A JSON object per line, in the Bunyan format.
+    #[default]
+    Json,
+    /// Human readable lines.
+    Pretty,
+}
+
+/// # `LogLevel`
+///
+/// Level of the events a sampling rule applies to.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for Level {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => Level::ERROR,
+            LogLevel::Warn => Level::WARN,
+            LogLevel::Info => Level::INFO,
+            LogLevel::Debug => Level::DEBUG,
+            LogLevel::Trace => Level::TRACE,
+        }
+    }
+}
+
+/// # `SamplingRule`
+///
+/// Keeps only the fraction `rate` of the events of the targets starting with `target`
+/// at `level` or less severe; the first rule matching an event applies.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct SamplingRule {
+    pub target: String,
+    pub level: LogLevel,
+    pub rate: f64,
+}
+
+/// # `LogOptions`
+///
+/// How the events are written and which ones are sampled.
+#[derive(Debug, Clone, Default)]
+pub struct LogOptions {
+    pub format: LogFormat,
+    pub sampling: Vec<SamplingRule>,
+}
+
+/// Drops the events left out by the sampling rules, before any other layer sees them
+///
+/// The sampling is deterministic: a rate of 0.01 keeps one event every hundred.
+struct Sampler {
+    rules: Vec<(String, Level, u64, AtomicU64)>,
+}
+
+impl Sampler {
+    fn new(rules: Vec<SamplingRule>) -> Self {
+        Sampler {
+            rules: rules
+                .into_iter()
+                .map(|rule| {
+                    let every = (1.0 / rule.rate).round().max(1.0) as u64;
+                    (rule.target, rule.level.into(), every, AtomicU64::new(0))
+                })
+                .collect(),
+        }
+    }
+}
+
+impl<S: Subscriber> Layer<S> for Sampler {
+    fn event_enabled(&self, event: &Event<'_>, _ctx: Context<'_, S>) -> bool {
+        let metadata = event.metadata();
+        // The more verbose levels are the greater ones
+        let rule = self.rules.iter().find(|(target, level, _, _)| {
+            metadata.target().starts_with(target.as_str()) && metadata.level() >= level
+        });
+        match rule {
+            Some((_, _, every, seen)) => seen.fetch_add(1, Ordering::Relaxed) % every == 0,
+            None => true,
+        }
+    }
+}
 
 /// Compose multiple layesr into a `tracing`'s subscriber
 ///
+/// The spans are also handed to `tracer`, if provided, see `init_otlp`.
+/// `options` choose the format of the events and which ones are sampled.
+///
 /// # Implementation Notes
 ///
 /// We are using `impl Subscriber` as return type to avoid
@@ -16,6 +152,8 @@ pub fn get_subscriber<Sink>(
     name: String,
     env_filter: String,
     sink: Sink,
+    tracer: Option<Tracer>,
+    options: LogOptions,
 ) -> impl Subscriber + Send + Sync
 where
     // This weired syntax is a higher-ranked trait bound (HRTB)
@@ -28,16 +166,33 @@ where
     // Print all spans at info-level or above if RUST_LOG hasn't been set.
     let env_filter =
         EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(env_filter));
-    let formatting_layer = BunyanFormattingLayer::new(
-        name, sink, // Output the formatted span to our sink.
-    );
+    // Only one of the formatting layers is present, the one that owns the sink
+    let (json_layer, pretty_layer) = match options.format {
+        LogFormat::Json => (
+            Some(JsonStorageLayer.and_then(BunyanFormattingLayer::new(
+                name, sink, // Output the formatted span to our sink.
+            ))),
+            None,
+        ),
+        LogFormat::Pretty => (
+            None,
+            // Colors only make sense on a terminal
+            Some(
+                tracing_subscriber::fmt::layer()
+                    .with_ansi(std::io::stdout().is_terminal())
+                    .with_writer(sink),
+            ),
+        ),
+    };
 
     // The `with` method is provided by `SubscriberExt`, an extension trait for `Subscriber`
     // exposed by `tracing_subscriber`.
     Registry::default()
         .with(env_filter)
-        .with(JsonStorageLayer)
-        .with(formatting_layer)
+        .with(Sampler::new(options.sampling))
+        .with(json_layer)
+        .with(pretty_layer)
+        .with(tracer.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer)))
 }
 
 /// Register a subscriber as global default to process span data
@@ -48,3 +203,141 @@ pub fn init_subscriber(subscriber: impl Subscriber + Send + Sync) {
     // to process spans
     set_global_default(subscriber).expect("Failed to set subscriber.");
 }
+
+/// # `init_otlp`
+///
+/// Builds the OTLP exporter, sending the spans to the collector at `endpoint` over gRPC.
+/// The spans are sent in batches by a task of the runtime, so it has to be called
+/// from within the runtime; shutting down the provider returned sends the last ones.
+/// The tracer to pass to `get_subscriber` is obtained from the provider.
+pub fn init_otlp(name: String, endpoint: &str) -> Result<TracerProvider, TraceError> {
+    let exporter = SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(endpoint)
+        .build()?;
+    Ok(TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_resource(Resource::new([KeyValue::new("service.name", name)]))
+        .build())
+}
+
+/// # `otlp_tracer`
+///
+/// Returns the tracer of `provider` for the spans of the server.
+pub fn otlp_tracer(provider: &TracerProvider) -> Tracer {
+    provider.tracer("rusty_dns")
+}
+
+/// # `init_metrics`
+///
+/// Installs the Prometheus exporter, serving the metrics on `addr`.
+/// The metrics are recorded anyway, but they go nowhere until an exporter is installed.
+/// It has to be called from within the runtime, that runs the listener.
+pub fn init_metrics(addr: SocketAddr) -> Result<(), BuildError> {
+    PrometheusBuilder::new()
+        .with_http_listener(addr)
+        .install()?;
+    describe_histogram!(
+        QUERY_DURATION,
+        Unit::Seconds,
+        "Time taken to respond to a client query"
+    );
+    describe_histogram!(
+        RESOLUTION_DURATION,
+        Unit::Seconds,
+        "Time taken by an iterative resolution"
+    );
+    describe_histogram!(
+        RESOLUTION_ROUND_TRIPS,
+        Unit::Count,
+        "Queries sent to other name servers by a resolution"
+    );
+    describe_counter!(
+        UPSTREAM_QUERIES,
+        Unit::Count,
+        "Queries sent to other name servers"
+    );
+    describe_histogram!(
+        UPSTREAM_RTT,
+        Unit::Seconds,
+        "Time taken by the other name servers to respond"
+    );
+    describe_counter!(TARPIT_OFFENSES, Unit::Count, "Offenses of the clients");
+    describe_counter!(
+        TARPIT_PENALTIES,
+        Unit::Count,
+        "Queries of the abusive clients delayed or dropped"
+    );
+    describe_gauge!(
+        TARPIT_CLIENTS,
+        Unit::Count,
+        "Clients with strikes not forgotten yet"
+    );
+    Ok(())
+}
+
+/// # `EventLogSink`
+///
+/// Sink writing the events to the Windows event log, under the source `name`,
+/// for the server running as a service; the level of the entries follows the one
+/// of the events in the JSON format, the other events are logged as information.
+#[cfg(windows)]
+pub struct EventLogSink(eventlog::EventLog);
+
+#[cfg(windows)]
+impl EventLogSink {
+    pub fn new(name: &str) -> Result<Self, eventlog::Error> {
+        // The source can only be registered by an administrator,
+        // the entries are written anyway, with a warning about the missing source
+        let _ = eventlog::register(name);
+        Ok(EventLogSink(eventlog::EventLog::new(
+            name,
+            log::Level::Trace,
+        )?))
+    }
+}
+
+#[cfg(windows)]
+impl<'a> MakeWriter<'a> for EventLogSink {
+    type Writer = EventLogWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        EventLogWriter(&self.0)
+    }
+}
+
+/// Writes an entry of the event log per write, each event is written at once.
+#[cfg(windows)]
+pub struct EventLogWriter<'a>(&'a eventlog::EventLog);
+
+#[cfg(windows)]
+impl std::io::Write for EventLogWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        use log::Log;
+
+        let message = String::from_utf8_lossy(buf);
+        let message = message.trim_end();
+        // Bunyan levels: 50 error, 40 warn, 30 info, 20 debug, 10 trace
+        let level = serde_json::from_str::<serde_json::Value>(message)
+            .ok()
+            .and_then(|event| event.get("level")?.as_u64())
+            .map_or(log::Level::Info, |level| match level {
+                50.. => log::Level::Error,
+                40..=49 => log::Level::Warn,
+                30..=39 => log::Level::Info,
+                20..=29 => log::Level::Debug,
+                _ => log::Level::Trace,
+            });
+        self.0.log(
+            &log::Record::builder()
+                .args(format_args!("{}", message))
+                .level(level)
+                .build(),
+        );
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
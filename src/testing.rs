@@ -0,0 +1,420 @@
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crypto_box::{aead::Aead, Nonce, PublicKey, SalsaBox, SecretKey};
+use ring::{
+    rand::{SecureRandom, SystemRandom},
+    signature::{Ed25519KeyPair, KeyPair},
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    task::JoinHandle,
+};
+
+use crate::{
+    structs::{
+        buffer::BytePacketBuffer,
+        header::ResultCode,
+        packet::Packet,
+        questions_and_records::{QueryType, Question, Record},
+    },
+    zones::is_subdomain,
+};
+
+/// TTL of the records of the delegations.
+const DELEGATION_TTL: u32 = 86400;
+/// Starts the queries encrypted for `FakeDnscryptResolver`.
+const CLIENT_MAGIC: &[u8; 8] = b"fakemagc";
+/// Starts the responses of the DNSCrypt resolvers.
+const RESOLVER_MAGIC: &[u8; 8] = b"r6fnvWj8";
+/// Half of the nonces of `FakeDnscryptResolver`'s responses chosen by the resolver.
+const RESOLVER_NONCE: [u8; 12] = [0x5a; 12];
+
+/// # `FakeNameserver`
+///
+/// An authoritative name server serving scripted records and delegations over UDP
+/// and TCP, in the same process, so the tests can resolve without the internet.
+/// The responses over UDP larger than 512 bytes are truncated.
+/// The resolver queries every name server on the port of the root server, the fake
+/// servers of a test share a port on different loopback addresses, e.g. a root
+/// on `127.0.0.1` delegating a zone to a server on `127.0.0.2`.
+/// Stops serving when dropped.
+pub struct FakeNameserver {
+    addr: SocketAddr,
+    script: Arc<Mutex<Script>>,
+    task: JoinHandle<()>,
+    tcp_task: JoinHandle<()>,
+}
+
+impl FakeNameserver {
+    /// # `bind`
+    ///
+    /// Starts serving on `addr`, nothing is known until it's scripted.
+    pub async fn bind(addr: SocketAddr) -> io::Result<Self> {
+        let sock = UdpSocket::bind(addr).await?;
+        let addr = sock.local_addr()?;
+        let script = Arc::new(Mutex::new(Script::default()));
+        let listener = TcpListener::bind(addr).await?;
+        let task = tokio::spawn(serve(sock, script.clone()));
+        let tcp_task = tokio::spawn(serve_tcp(listener, script.clone()));
+        Ok(FakeNameserver {
+            addr,
+            script,
+            task,
+            tcp_task,
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// # `add_record`
+    ///
+    /// The queries for the name and type of `record` are answered with it,
+    /// along with the other records of the same name and type.
+    pub fn add_record(&self, record: Record) {
+        self.script.lock().unwrap().records.push(record);
+    }
+
+    /// # `delegate`
+    ///
+    /// The queries for `zone` and the names below it are referred to the name server
+    /// `host`, whose address `addr` is given as glue.
+    pub fn delegate(&self, zone: &str, host: &str, addr: Ipv4Addr) {
+        self.script.lock().unwrap().delegations.push(Delegation {
+            zone: zone.trim_end_matches('.').to_lowercase(),
+            host: host.to_string(),
+            addr,
+        });
+    }
+
+    /// # `queries`
+    ///
+    /// The questions received so far.
+    pub fn queries(&self) -> Vec<Question> {
+        self.script.lock().unwrap().queries.clone()
+    }
+}
+
+impl Drop for FakeNameserver {
+    fn drop(&mut self) {
+        self.task.abort();
+        self.tcp_task.abort();
+    }
+}
+
+/// # `FakeDnscryptResolver`
+///
+/// A DNSCrypt v2 resolver over UDP answering with scripted records, as `FakeNameserver`,
+/// in the same process. It publishes a certificate for a key of its own as the TXT record
+/// of its provider name, signed by a provider key it generates; `fault` breaks
+/// its encrypted responses, for the tests of the client.
+/// Stops serving when dropped.
+pub struct FakeDnscryptResolver {
+    addr: SocketAddr,
+    provider_key: [u8; 32],
+    script: Arc<Mutex<Script>>,
+    task: JoinHandle<()>,
+}
+
+/// # `DnscryptFault`
+///
+/// How `FakeDnscryptResolver` breaks its responses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DnscryptFault {
+    /// The responses are well formed.
+    None,
+    /// The responses don't start with the magic of the resolvers.
+    BadMagic,
+    /// The responses aren't padded.
+    BadPadding,
+}
+
+impl FakeDnscryptResolver {
+    /// # `bind`
+    ///
+    /// Starts serving on `addr` the certificate of `provider_name`, nothing else is known
+    /// until it's scripted.
+    pub async fn bind(
+        addr: SocketAddr,
+        provider_name: &str,
+        fault: DnscryptFault,
+    ) -> io::Result<Self> {
+        let rng = SystemRandom::new();
+        let provider = Ed25519KeyPair::generate_pkcs8(&rng)
+            .ok()
+            .and_then(|pkcs8| Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).ok())
+            .ok_or_else(|| io::Error::other("Unable to generate the provider key"))?;
+        let mut secret = [0u8; 32];
+        rng.fill(&mut secret)
+            .map_err(|_| io::Error::other("Unable to generate the resolver key"))?;
+        let secret = SecretKey::from_bytes(secret);
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs() as u32);
+        let mut signed = secret.public_key().as_bytes().to_vec();
+        signed.extend_from_slice(CLIENT_MAGIC);
+        signed.extend_from_slice(&1u32.to_be_bytes());
+        signed.extend_from_slice(&(now - 60).to_be_bytes());
+        signed.extend_from_slice(&(now + 3600).to_be_bytes());
+        let mut certificate = b"DNSC\x00\x01\x00\x00".to_vec();
+        certificate.extend_from_slice(provider.sign(&signed).as_ref());
+        certificate.extend(signed);
+        let mut txt = vec![certificate.len() as u8];
+        txt.extend(certificate);
+
+        let mut script = Script::default();
+        script.records.push(Record::UNKNOWN {
+            domain: provider_name.trim_end_matches('.').into(),
+            qtype: QueryType::UNKNOWN(16).to_num(),
+            data: txt,
+            ttl: DELEGATION_TTL,
+        });
+        let script = Arc::new(Mutex::new(script));
+        let sock = UdpSocket::bind(addr).await?;
+        let addr = sock.local_addr()?;
+        let task = tokio::spawn(serve_dnscrypt(sock, script.clone(), secret, fault));
+        Ok(FakeDnscryptResolver {
+            addr,
+            provider_key: provider.public_key().as_ref().try_into().unwrap(),
+            script,
+            task,
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// # `provider_key`
+    ///
+    /// The Ed25519 key signing the certificate of the resolver.
+    pub fn provider_key(&self) -> [u8; 32] {
+        self.provider_key
+    }
+
+    /// # `add_record`
+    ///
+    /// The queries for the name and type of `record` are answered with it,
+    /// as `FakeNameserver::add_record`.
+    pub fn add_record(&self, record: Record) {
+        self.script.lock().unwrap().records.push(record);
+    }
+}
+
+impl Drop for FakeDnscryptResolver {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+struct Delegation {
+    zone: String,
+    host: String,
+    addr: Ipv4Addr,
+}
+
+#[derive(Default)]
+struct Script {
+    records: Vec<Record>,
+    delegations: Vec<Delegation>,
+    queries: Vec<Question>,
+}
+
+impl Script {
+    /// # `respond`
+    ///
+    /// The referral of the longest delegation covering the name asked, if any,
+    /// otherwise the authoritative answer: the records of the name and type asked,
+    /// NODATA if the name only has records of other types, NXDOMAIN if it has none.
+    fn respond(&mut self, request: &Packet) -> Packet {
+        let mut response = Packet::new();
+        response.header.id = request.header.id;
+        response.header.response = true;
+        response.header.recursion_desired = request.header.recursion_desired;
+        response.questions = request.questions.clone();
+        let Some(question) = request.questions.first() else {
+            response.header.rescode = ResultCode::FORMERR;
+            return response;
+        };
+        self.queries.push(question.clone());
+
+        let qname = question.qname.trim_end_matches('.').to_lowercase();
+        let delegation = self
+            .delegations
+            .iter()
+            .filter(|delegation| is_subdomain(&qname, &delegation.zone))
+            .max_by_key(|delegation| delegation.zone.len());
+        if let Some(delegation) = delegation {
+            response.authorities.push(Record::NS {
+                domain: delegation.zone.as_str().into(),
+                host: delegation.host.as_str().into(),
+                ttl: DELEGATION_TTL,
+            });
+            response.resources.push(Record::A {
+                domain: delegation.host.as_str().into(),
+                addr: delegation.addr,
+                ttl: DELEGATION_TTL,
+            });
+            return response;
+        }
+
+        response.header.authoritative_answer = true;
+        let of_name: Vec<&Record> = self
+            .records
+            .iter()
+            .filter(|record| record.get_domain().eq_ignore_ascii_case(&qname))
+            .collect();
+        if of_name.is_empty() {
+            response.header.rescode = ResultCode::NXDOMAIN;
+        }
+        response.answers = of_name
+            .into_iter()
+            .filter(|record| record.get_qtype() == question.qtype)
+            .cloned()
+            .collect();
+        response
+    }
+}
+
+/// # `serve`
+///
+/// `FakeNameserver`'s task, answers the queries received on `sock`.
+async fn serve(sock: UdpSocket, script: Arc<Mutex<Script>>) {
+    loop {
+        let mut req_buffer = BytePacketBuffer::new();
+        let Ok((_, src)) = sock.recv_from(&mut req_buffer.buf).await else {
+            continue;
+        };
+        let Ok(request) = Packet::from_buffer(&mut req_buffer) else {
+            continue;
+        };
+        let mut response = script.lock().unwrap().respond(&request);
+        let mut res_buffer = BytePacketBuffer::new();
+        if response.write(&mut res_buffer).is_err() {
+            continue;
+        }
+        if res_buffer.pos() > 512 {
+            response.truncate();
+            res_buffer = BytePacketBuffer::new();
+            if response.write(&mut res_buffer).is_err() {
+                continue;
+            }
+        }
+        let _ = sock.send_to(res_buffer.written(), src).await;
+    }
+}
+
+/// # `serve_dnscrypt`
+///
+/// `FakeDnscryptResolver`'s task, answers the queries received on `sock`, the ones
+/// for the certificate in plain DNS and the others encrypted.
+async fn serve_dnscrypt(
+    sock: UdpSocket,
+    script: Arc<Mutex<Script>>,
+    secret: SecretKey,
+    fault: DnscryptFault,
+) {
+    loop {
+        let mut query = vec![0u8; 4096];
+        let Ok((len, src)) = sock.recv_from(&mut query).await else {
+            continue;
+        };
+        query.truncate(len);
+        let response = if query.starts_with(CLIENT_MAGIC) {
+            answer_encrypted(&query, &script, &secret, fault)
+        } else {
+            answer_plain(&query, &script)
+        };
+        if let Some(response) = response {
+            let _ = sock.send_to(&response, src).await;
+        }
+    }
+}
+
+/// # `answer_plain`
+///
+/// The response to the query in `bytes`, none if it can't be read.
+fn answer_plain(bytes: &[u8], script: &Mutex<Script>) -> Option<Vec<u8>> {
+    let request = Packet::from_buffer(&mut BytePacketBuffer::from_bytes(bytes)).ok()?;
+    let mut response = script.lock().unwrap().respond(&request);
+    let mut res_buffer = BytePacketBuffer::empty();
+    response.write(&mut res_buffer).ok()?;
+    Some(res_buffer.written().to_vec())
+}
+
+/// # `answer_encrypted`
+///
+/// The encrypted response to the encrypted `query`, broken as `fault` says,
+/// none if it can't be decrypted.
+fn answer_encrypted(
+    query: &[u8],
+    script: &Mutex<Script>,
+    secret: &SecretKey,
+    fault: DnscryptFault,
+) -> Option<Vec<u8>> {
+    let client_key = PublicKey::from_slice(query.get(8..40)?).ok()?;
+    let salsa_box = SalsaBox::new(&client_key, secret);
+    let mut nonce = [0u8; 24];
+    nonce[..12].copy_from_slice(query.get(40..52)?);
+    let padded = salsa_box
+        .decrypt(Nonce::from_slice(&nonce), &query[52..])
+        .ok()?;
+    let end = padded.iter().rposition(|byte| *byte == 0x80)?;
+
+    let mut response = answer_plain(&padded[..end], script)?;
+    if fault != DnscryptFault::BadPadding {
+        response.push(0x80);
+        response.resize(response.len().next_multiple_of(64), 0);
+    }
+    nonce[12..].copy_from_slice(&RESOLVER_NONCE);
+    let encrypted = salsa_box
+        .encrypt(Nonce::from_slice(&nonce), response.as_slice())
+        .ok()?;
+    let mut message = match fault {
+        DnscryptFault::BadMagic => b"notmagic".to_vec(),
+        _ => RESOLVER_MAGIC.to_vec(),
+    };
+    message.extend_from_slice(&nonce);
+    message.extend(encrypted);
+    Some(message)
+}
+
+/// # `serve_tcp`
+///
+/// `FakeNameserver`'s task for TCP, answers the queries of the connections
+/// accepted on `listener`, a connection at a time.
+async fn serve_tcp(listener: TcpListener, script: Arc<Mutex<Script>>) {
+    loop {
+        let Ok((mut stream, _)) = listener.accept().await else {
+            continue;
+        };
+        while answer_tcp(&mut stream, &script).await.is_ok() {}
+    }
+}
+
+/// # `answer_tcp`
+///
+/// Reads a query from `stream`, prefixed by its length, and writes the response to it.
+async fn answer_tcp(stream: &mut TcpStream, script: &Mutex<Script>) -> io::Result<()> {
+    let len = stream.read_u16().await? as usize;
+    let mut req_buffer = BytePacketBuffer::with_size(len);
+    stream.read_exact(&mut req_buffer.buf).await?;
+    let request = Packet::from_buffer(&mut req_buffer)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    let mut response = script.lock().unwrap().respond(&request);
+    let mut res_buffer = BytePacketBuffer::empty();
+    response
+        .write(&mut res_buffer)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    stream.write_u16(res_buffer.pos() as u16).await?;
+    stream.write_all(res_buffer.written()).await
+}
@@ -0,0 +1,455 @@
+use std::collections::HashMap;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::{Sha256, Sha384, Sha512};
+
+use crate::structs::{
+    auxiliaries::{CResult, DnsError},
+    buffer::BytePacketBuffer,
+    questions_and_records::Record,
+};
+
+/// Type number of the TSIG pseudo record.
+pub const TSIG_TYPE: u16 = 250;
+/// Class of the TSIG pseudo record (ANY).
+const TSIG_CLASS: u16 = 255;
+/// Seconds of difference allowed between our clock and the signer's one.
+const FUDGE: u16 = 300;
+
+/// # `TsigAlgorithm`
+///
+/// HMAC algorithms supported for TSIG.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum TsigAlgorithm {
+    #[serde(rename = "hmac-sha256")]
+    HmacSha256,
+    #[serde(rename = "hmac-sha384")]
+    HmacSha384,
+    #[serde(rename = "hmac-sha512")]
+    HmacSha512,
+}
+
+impl TsigAlgorithm {
+    /// # `name`
+    ///
+    /// Name of the algorithm as it appears in the TSIG record.
+    pub fn name(&self) -> &'static str {
+        match self {
+            TsigAlgorithm::HmacSha256 => "hmac-sha256",
+            TsigAlgorithm::HmacSha384 => "hmac-sha384",
+            TsigAlgorithm::HmacSha512 => "hmac-sha512",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<TsigAlgorithm> {
+        match name {
+            "hmac-sha256" => Some(TsigAlgorithm::HmacSha256),
+            "hmac-sha384" => Some(TsigAlgorithm::HmacSha384),
+            "hmac-sha512" => Some(TsigAlgorithm::HmacSha512),
+            _ => None,
+        }
+    }
+
+    fn mac(&self, secret: &[u8], data: &[u8]) -> Vec<u8> {
+        match self {
+            TsigAlgorithm::HmacSha256 => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes any key");
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+            TsigAlgorithm::HmacSha384 => {
+                let mut mac = Hmac::<Sha384>::new_from_slice(secret).expect("HMAC takes any key");
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+            TsigAlgorithm::HmacSha512 => {
+                let mut mac = Hmac::<Sha512>::new_from_slice(secret).expect("HMAC takes any key");
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+        }
+    }
+}
+
+/// # `TsigKey`
+///
+/// A shared secret used to authenticate messages exchanged with a peer.
+#[derive(Debug, Clone)]
+pub struct TsigKey {
+    pub name: String,
+    pub algorithm: TsigAlgorithm,
+    secret: Vec<u8>,
+}
+
+impl TsigKey {
+    /// # `new`
+    ///
+    /// Creates a key given its name, algorithm and base64 encoded secret.
+    pub fn new(name: &str, algorithm: TsigAlgorithm, secret: &str) -> CResult<TsigKey> {
+        Ok(TsigKey {
+            name: name.trim_end_matches('.').to_lowercase(),
+            algorithm,
+            secret: STANDARD
+                .decode(secret)
+                .map_err(|e| DnsError::Config(format!("Invalid secret for {}: {}", name, e)))?,
+        })
+    }
+}
+
+/// # `Keyring`
+///
+/// Collection of the TSIG keys known to the server, indexed by name.
+#[derive(Debug, Clone, Default)]
+pub struct Keyring {
+    keys: HashMap<String, TsigKey>,
+}
+
+impl Keyring {
+    pub fn new(keys: Vec<TsigKey>) -> Self {
+        Keyring {
+            keys: keys.into_iter().map(|k| (k.name.clone(), k)).collect(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&TsigKey> {
+        self.keys.get(&name.trim_end_matches('.').to_lowercase())
+    }
+}
+
+/// # `TsigError`
+///
+/// Reasons why the TSIG of a message has been rejected, the values
+/// are the ones carried in the error field of the TSIG record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TsigError {
+    /// The message is malformed or isn't signed.
+    FormErr = 1,
+    BadSig = 16,
+    BadKey = 17,
+    BadTime = 18,
+    BadTrunc = 22,
+}
+
+impl std::fmt::Display for TsigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let description = match self {
+            TsigError::FormErr => "missing or malformed TSIG",
+            TsigError::BadSig => "TSIG signature verification failed",
+            TsigError::BadKey => "unknown TSIG key",
+            TsigError::BadTime => "TSIG signed outside of the allowed time window",
+            TsigError::BadTrunc => "TSIG MAC truncated",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+impl std::error::Error for TsigError {}
+
+/// # `Tsig`
+///
+/// Content of a TSIG record found at the end of a message.
+#[derive(Debug, Clone)]
+pub struct Tsig {
+    pub key_name: String,
+    pub algorithm: String,
+    pub time_signed: u64,
+    pub fudge: u16,
+    pub mac: Vec<u8>,
+    pub original_id: u16,
+    pub error: u16,
+    pub other: Vec<u8>,
+    /// Offset of the TSIG record in the message
+    offset: usize,
+}
+
+/// # `find_tsig`
+///
+/// Looks for a TSIG record at the end of the message contained in `raw`,
+/// returns `Ok(None)` if the message isn't signed.
+pub fn find_tsig(raw: &[u8]) -> CResult<Option<Tsig>> {
+    let mut buffer = BytePacketBuffer::from_bytes(raw);
+    buffer.seek(4)?;
+    let questions = buffer.read_u16()?;
+    let records = buffer.read_u16()? as u32 + buffer.read_u16()? as u32 + buffer.read_u16()? as u32;
+    if records == 0 {
+        return Ok(None);
+    }
+    for _ in 0..questions {
+        let mut qname = String::new();
+        buffer.read_qname(&mut qname)?;
+        buffer.step(4)?;
+    }
+    for _ in 0..records - 1 {
+        Record::read(&mut buffer)?;
+    }
+
+    // The TSIG, if present, is the last record of the additional section
+    let offset = buffer.pos();
+    let mut key_name = String::new();
+    buffer.read_qname(&mut key_name)?;
+    if buffer.read_u16()? != TSIG_TYPE {
+        return Ok(None);
+    }
+    buffer.step(8)?; // class, ttl and length of the data
+    let mut algorithm = String::new();
+    buffer.read_qname(&mut algorithm)?;
+    let time_signed = ((buffer.read_u16()? as u64) << 32) | buffer.read_u32()? as u64;
+    let fudge = buffer.read_u16()?;
+    let mac_len = buffer.read_u16()? as usize;
+    let mac = buffer.get_range(buffer.pos(), mac_len)?.to_vec();
+    buffer.step(mac_len)?;
+    let original_id = buffer.read_u16()?;
+    let error = buffer.read_u16()?;
+    let other_len = buffer.read_u16()? as usize;
+    let other = buffer.get_range(buffer.pos(), other_len)?.to_vec();
+
+    Ok(Some(Tsig {
+        key_name,
+        algorithm,
+        time_signed,
+        fudge,
+        mac,
+        original_id,
+        error,
+        other,
+        offset,
+    }))
+}
+
+/// # `sign`
+///
+/// Signs the message contained in `buffer`, from the start up to the current position,
+/// appending a TSIG record to it. `request_mac` is the MAC of the request when signing
+/// a response. Returns the MAC of the message.
+pub fn sign(
+    buffer: &mut BytePacketBuffer,
+    key: &TsigKey,
+    request_mac: Option<&[u8]>,
+) -> CResult<Vec<u8>> {
+    sign_with_error(buffer, key, request_mac, 0, &[])
+}
+
+/// # `sign_with_error`
+///
+/// Like `sign`, but the TSIG carries `error` and `other`, used when a signed request
+/// has been rejected (RFC 8945 section 5.3.2).
+pub fn sign_with_error(
+    buffer: &mut BytePacketBuffer,
+    key: &TsigKey,
+    request_mac: Option<&[u8]>,
+    error: u16,
+    other: &[u8],
+) -> CResult<Vec<u8>> {
+    let end = buffer.pos();
+    let time_signed = chrono::Utc::now().timestamp() as u64;
+
+    let mut data = Vec::new();
+    if let Some(request_mac) = request_mac {
+        data.extend_from_slice(&(request_mac.len() as u16).to_be_bytes());
+        data.extend_from_slice(request_mac);
+    }
+    data.extend_from_slice(buffer.get_range(0, end)?);
+    data.extend(variables(key, time_signed, FUDGE, error, other)?);
+    let mac = key.algorithm.mac(&key.secret, &data);
+
+    let tsig = TsigFields {
+        key_name: &key.name,
+        algorithm: key.algorithm.name(),
+        time_signed,
+        mac: &mac,
+        error,
+        other,
+    };
+    write_tsig(buffer, &tsig)?;
+    Ok(mac)
+}
+
+/// # `sign_rejection`
+///
+/// Appends to the response in `buffer` the TSIG telling why the request signed
+/// with `request` has been rejected for `error` (RFC 8945 section 5.3.2).
+/// A BADTIME response is signed, the MAC of the request being valid, and carries
+/// the time of the server; the others have an empty MAC, the key can't be trusted.
+pub fn sign_rejection(
+    buffer: &mut BytePacketBuffer,
+    keyring: &Keyring,
+    request: &Tsig,
+    error: TsigError,
+) -> CResult<()> {
+    let now = chrono::Utc::now().timestamp() as u64;
+    if let (TsigError::BadTime, Some(key)) = (error, keyring.get(&request.key_name)) {
+        // The time is 48 bits long
+        sign_with_error(
+            buffer,
+            key,
+            Some(&request.mac),
+            error as u16,
+            &now.to_be_bytes()[2..],
+        )?;
+        return Ok(());
+    }
+    let tsig = TsigFields {
+        key_name: &request.key_name,
+        algorithm: &request.algorithm,
+        time_signed: now,
+        mac: &[],
+        error: error as u16,
+        other: &[],
+    };
+    write_tsig(buffer, &tsig)
+}
+
+/// # `TsigFields`
+///
+/// The fields of a TSIG record being written.
+struct TsigFields<'a> {
+    key_name: &'a str,
+    algorithm: &'a str,
+    time_signed: u64,
+    mac: &'a [u8],
+    error: u16,
+    other: &'a [u8],
+}
+
+/// # `write_tsig`
+///
+/// Appends `tsig` to the message in `buffer`, as the last record of its additional section.
+fn write_tsig(buffer: &mut BytePacketBuffer, tsig: &TsigFields) -> CResult<()> {
+    let id = ((buffer.get(0)? as u16) << 8) | buffer.get(1)? as u16;
+    buffer.write_qname(tsig.key_name)?;
+    buffer.write_u16(TSIG_TYPE)?;
+    buffer.write_u16(TSIG_CLASS)?;
+    buffer.write_u32(0)?;
+    let len_pos = buffer.pos();
+    buffer.write_u16(0)?;
+    buffer.write_qname(tsig.algorithm)?;
+    buffer.write_u16((tsig.time_signed >> 32) as u16)?;
+    buffer.write_u32(tsig.time_signed as u32)?;
+    buffer.write_u16(FUDGE)?;
+    buffer.write_u16(tsig.mac.len() as u16)?;
+    buffer.write_bytes(tsig.mac)?;
+    buffer.write_u16(id)?;
+    buffer.write_u16(tsig.error)?;
+    buffer.write_u16(tsig.other.len() as u16)?;
+    buffer.write_bytes(tsig.other)?;
+    let size = buffer.pos() - (len_pos + 2);
+    buffer.set_u16(len_pos, size as u16)?;
+
+    // The TSIG is one more record in the additional section
+    let additional = ((buffer.get(10)? as u16) << 8) | buffer.get(11)? as u16;
+    buffer.set_u16(10, additional + 1)?;
+    Ok(())
+}
+
+/// # `verify`
+///
+/// Verifies the TSIG of the message contained in `raw` using the keys of `keyring`.
+/// `request_mac` is the MAC of our request when verifying a response.
+/// On success the key used and the TSIG are returned, the MAC of the TSIG is needed
+/// to sign the response.
+pub fn verify<'a>(
+    raw: &[u8],
+    keyring: &'a Keyring,
+    request_mac: Option<&[u8]>,
+) -> Result<(&'a TsigKey, Tsig), TsigError> {
+    let tsig = find_tsig(raw)
+        .map_err(|_| TsigError::FormErr)?
+        .ok_or(TsigError::FormErr)?;
+    let key = keyring.get(&tsig.key_name).ok_or(TsigError::BadKey)?;
+    verify_with(raw, &tsig, key, request_mac, &[], false)?;
+    Ok((key, tsig))
+}
+
+/// # `verify_with`
+///
+/// Verifies `tsig`, found in the message `raw`, using `key`.
+/// `prior_mac` is the MAC of the request or of the previous signed message,
+/// `preceding` are the unsigned messages received after it, and `timers_only`
+/// is true for the messages following the first one of a multi message response.
+pub fn verify_with(
+    raw: &[u8],
+    tsig: &Tsig,
+    key: &TsigKey,
+    prior_mac: Option<&[u8]>,
+    preceding: &[u8],
+    timers_only: bool,
+) -> Result<(), TsigError> {
+    if TsigAlgorithm::from_name(&tsig.algorithm) != Some(key.algorithm) {
+        return Err(TsigError::BadKey);
+    }
+
+    let mut data = Vec::new();
+    if let Some(prior_mac) = prior_mac {
+        data.extend_from_slice(&(prior_mac.len() as u16).to_be_bytes());
+        data.extend_from_slice(prior_mac);
+    }
+    data.extend_from_slice(preceding);
+    data.extend(unsigned_message(raw, tsig).map_err(|_| TsigError::FormErr)?);
+    if timers_only {
+        data.extend_from_slice(&((tsig.time_signed >> 32) as u16).to_be_bytes());
+        data.extend_from_slice(&(tsig.time_signed as u32).to_be_bytes());
+        data.extend_from_slice(&tsig.fudge.to_be_bytes());
+    } else {
+        data.extend(
+            variables(key, tsig.time_signed, tsig.fudge, tsig.error, &tsig.other)
+                .map_err(|_| TsigError::FormErr)?,
+        );
+    }
+
+    let expected = key.algorithm.mac(&key.secret, &data);
+    // Truncated MACs are allowed down to half of the output size (RFC 8945 section 5.2.2.1)
+    if tsig.mac.len() < expected.len() / 2 || tsig.mac.len() > expected.len() {
+        return Err(TsigError::BadTrunc);
+    }
+    if !constant_time_eq(&expected[..tsig.mac.len()], &tsig.mac) {
+        return Err(TsigError::BadSig);
+    }
+
+    let now = chrono::Utc::now().timestamp() as u64;
+    if now.abs_diff(tsig.time_signed) > tsig.fudge as u64 {
+        return Err(TsigError::BadTime);
+    }
+    Ok(())
+}
+
+/// # `unsigned_message`
+///
+/// Rebuilds the message as it was before the TSIG was added: without the TSIG record,
+/// with the additional count decremented and with the original id.
+pub fn unsigned_message(raw: &[u8], tsig: &Tsig) -> CResult<Vec<u8>> {
+    let mut buffer = BytePacketBuffer::from_bytes(&raw[..tsig.offset]);
+    let additional = ((buffer.get(10)? as u16) << 8) | buffer.get(11)? as u16;
+    buffer.set_u16(0, tsig.original_id)?;
+    buffer.set_u16(10, additional.saturating_sub(1))?;
+    Ok(buffer.buf.into())
+}
+
+/// # `variables`
+///
+/// TSIG variables that are part of the digest (RFC 8945 section 4.3.3).
+fn variables(
+    key: &TsigKey,
+    time_signed: u64,
+    fudge: u16,
+    error: u16,
+    other: &[u8],
+) -> CResult<Vec<u8>> {
+    let mut buffer = BytePacketBuffer::empty();
+    buffer.write_qname(&key.name)?;
+    buffer.write_u16(TSIG_CLASS)?;
+    buffer.write_u32(0)?;
+    buffer.write_qname(key.algorithm.name())?;
+    buffer.write_u16((time_signed >> 32) as u16)?;
+    buffer.write_u32(time_signed as u32)?;
+    buffer.write_u16(fudge)?;
+    buffer.write_u16(error)?;
+    buffer.write_u16(other.len() as u16)?;
+    buffer.write_bytes(other)?;
+    Ok(buffer.freeze().into())
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,413 @@
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+
+use tokio::{net::UdpSocket, sync::mpsc, task::JoinHandle};
+
+use crate::structs::buffer::BytePacketBuffer;
+
+/// Responses waiting to be sent before the handlers have to wait for the sender.
+const SEND_QUEUE: usize = 1024;
+/// Largest query accepted over UDP, as large as the payloads EDNS clients send in practice.
+pub const MAX_UDP_QUERY: usize = 4096;
+
+/// # `receive_buffer`
+///
+/// A buffer for a datagram, a byte larger than `MAX_UDP_QUERY`:
+/// a datagram that fills it has been truncated by the kernel.
+pub fn receive_buffer() -> BytePacketBuffer {
+    BytePacketBuffer::with_size(MAX_UDP_QUERY + 1)
+}
+
+/// # `recv_batch`
+///
+/// Receives at most `bufs.len()` datagrams, waiting for the first one,
+/// returns the length and the source of the datagrams received, that fill `bufs` in order;
+/// `None` for the datagrams to be skipped: the ones whose source can't be read and
+/// the ones larger than `MAX_UDP_QUERY`, that don't fit a `receive_buffer` whole.
+/// On Linux the datagrams already queued are received with a single `recvmmsg`.
+pub async fn recv_batch(
+    sock: &UdpSocket,
+    bufs: &mut [BytePacketBuffer],
+) -> io::Result<Vec<Option<(usize, SocketAddr)>>> {
+    #[cfg(target_os = "linux")]
+    if bufs.len() > 1 {
+        loop {
+            sock.readable().await?;
+            match sock.try_io(tokio::io::Interest::READABLE, || mmsg::recvmmsg(sock, bufs)) {
+                Ok(received) => return Ok(received.into_iter().map(whole).collect()),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    let received = sock.recv_from(&mut bufs[0].buf).await?;
+    Ok(vec![whole(Some(received))])
+}
+
+/// # `whole`
+///
+/// `recv_batch`'s helper, skips the datagram received if it's larger than `MAX_UDP_QUERY`:
+/// it has been cut, it would fail to parse or to verify.
+fn whole(received: Option<(usize, SocketAddr)>) -> Option<(usize, SocketAddr)> {
+    received.filter(|(len, src)| {
+        if *len > MAX_UDP_QUERY {
+            tracing::info!(
+                "Dropped a datagram from {} larger than {} bytes",
+                src,
+                MAX_UDP_QUERY
+            );
+        }
+        *len <= MAX_UDP_QUERY
+    })
+}
+
+/// # `Responder`
+///
+/// Sends the responses to the clients.
+/// When batching is enabled the responses are queued and a single task sends
+/// the ones queued together, with a single `sendmmsg` on Linux; the errors
+/// of the batched sends are only logged.
+/// The responses to the queries received over TCP are handed to the task writing
+/// on their connection instead, see `tcp`, the ones received over HTTPS to the request
+/// waiting for them, see `doh`.
+#[derive(Debug, Clone)]
+pub struct Responder {
+    channel: Channel,
+    sender: Option<Arc<JoinHandle<()>>>,
+}
+
+#[derive(Debug, Clone)]
+enum Channel {
+    Udp {
+        sock: Arc<UdpSocket>,
+        queue: Option<mpsc::Sender<(Vec<u8>, SocketAddr)>>,
+    },
+    Stream {
+        conn: mpsc::Sender<Vec<u8>>,
+        /// Time the TCP connection is kept open without queries, `None` for HTTPS.
+        idle_timeout: Option<Duration>,
+    },
+}
+
+impl Responder {
+    /// # `new`
+    ///
+    /// Sends the responses through `sock`, up to `batch_size` at a time,
+    /// batching is disabled if `batch_size` is 1.
+    pub fn new(sock: Arc<UdpSocket>, batch_size: usize) -> Self {
+        let (queue, sender) = if batch_size > 1 {
+            let (tx, rx) = mpsc::channel(SEND_QUEUE);
+            let sender = tokio::spawn(send_batches(sock.clone(), rx, batch_size));
+            (Some(tx), Some(Arc::new(sender)))
+        } else {
+            (None, None)
+        };
+        Responder {
+            channel: Channel::Udp { sock, queue },
+            sender,
+        }
+    }
+
+    /// # `tcp`
+    ///
+    /// Hands the responses to `conn`, the queue of the task writing on a TCP connection
+    /// that is closed after `idle_timeout` without queries.
+    pub fn tcp(conn: mpsc::Sender<Vec<u8>>, idle_timeout: Duration) -> Self {
+        Responder {
+            channel: Channel::Stream {
+                conn,
+                idle_timeout: Some(idle_timeout),
+            },
+            sender: None,
+        }
+    }
+
+    /// # `channel`
+    ///
+    /// Hands the responses to `conn`, for the queries that don't arrive over TCP
+    /// nor UDP, as the ones received over HTTPS.
+    pub fn channel(conn: mpsc::Sender<Vec<u8>>) -> Self {
+        Responder {
+            channel: Channel::Stream {
+                conn,
+                idle_timeout: None,
+            },
+            sender: None,
+        }
+    }
+
+    /// # `keepalive`
+    ///
+    /// The idle timeout of the TCP connection the responses are sent on,
+    /// `None` for the other transports.
+    pub fn keepalive(&self) -> Option<Duration> {
+        match &self.channel {
+            Channel::Udp { .. } => None,
+            Channel::Stream { idle_timeout, .. } => *idle_timeout,
+        }
+    }
+
+    /// # `is_udp`
+    ///
+    /// Whether the responses are sent over UDP, they have to fit the size the client accepts.
+    pub fn is_udp(&self) -> bool {
+        matches!(self.channel, Channel::Udp { .. })
+    }
+
+    /// # `send_to`
+    ///
+    /// Sends `data` to `dst`, or queues it if batching is enabled.
+    /// Over the other transports `dst` is where the responses go already.
+    pub async fn send_to(&self, data: &[u8], dst: SocketAddr) -> io::Result<usize> {
+        let gone = || io::Error::new(io::ErrorKind::BrokenPipe, "The sender is gone");
+        match &self.channel {
+            Channel::Udp {
+                queue: Some(queue), ..
+            } => {
+                queue.send((data.to_vec(), dst)).await.map_err(|_| gone())?;
+                Ok(data.len())
+            }
+            Channel::Udp { sock, queue: None } => sock.send_to(data, dst).await,
+            Channel::Stream { conn, .. } => {
+                conn.send(data.to_vec()).await.map_err(|_| gone())?;
+                Ok(data.len())
+            }
+        }
+    }
+
+    /// # `close`
+    ///
+    /// Waits for the queued responses to be sent, if this is the last clone
+    /// of the `Responder`, otherwise the clones left keep sending.
+    pub async fn close(self) {
+        drop(self.channel);
+        if let Some(sender) = self.sender.and_then(Arc::into_inner) {
+            let _ = sender.await;
+        }
+    }
+}
+
+/// # `send_batches`
+///
+/// `Responder`'s task, waits for a response and sends it along with the ones
+/// queued in the meantime, up to `batch_size`.
+async fn send_batches(
+    sock: Arc<UdpSocket>,
+    mut rx: mpsc::Receiver<(Vec<u8>, SocketAddr)>,
+    batch_size: usize,
+) {
+    let mut batch = Vec::with_capacity(batch_size);
+    while rx.recv_many(&mut batch, batch_size).await > 0 {
+        send_batch(&sock, &batch).await;
+        batch.clear();
+    }
+}
+
+/// # `send_batch`
+///
+/// Sends every datagram in `batch`, the ones that can't be sent are skipped
+/// and the following ones are sent all the same.
+async fn send_batch(sock: &UdpSocket, batch: &[(Vec<u8>, SocketAddr)]) {
+    #[cfg(target_os = "linux")]
+    {
+        let mut sent = 0;
+        while sent < batch.len() {
+            if let Err(e) = sock.writable().await {
+                tracing::info!("Failed to send {} responses: {}", batch.len() - sent, e);
+                return;
+            }
+            match sock.try_io(tokio::io::Interest::WRITABLE, || {
+                mmsg::sendmmsg(sock, &batch[sent..])
+            }) {
+                Ok(n) => sent += n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                // `sendmmsg` fails only if the first datagram can't be sent
+                Err(e) => {
+                    tracing::info!("Failed to send a response to {}: {}", batch[sent].1, e);
+                    sent += 1;
+                }
+            }
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        for (data, dst) in batch {
+            if let Err(e) = sock.send_to(data, *dst).await {
+                tracing::info!("Failed to send a response to {}: {}", dst, e);
+            }
+        }
+    }
+}
+
+/// Bindings to the system calls handling more datagrams at once.
+#[cfg(target_os = "linux")]
+mod mmsg {
+    use std::{
+        io, mem,
+        net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+        os::fd::AsRawFd,
+        ptr,
+    };
+
+    use tokio::net::UdpSocket;
+
+    use crate::structs::buffer::BytePacketBuffer;
+
+    /// # `recvmmsg`
+    ///
+    /// Receives the datagrams queued on `sock` without blocking, one per buffer;
+    /// `None` for the ones whose source has an unexpected address family.
+    /// The datagrams truncated to fit their buffer (`MSG_TRUNC`) are given the length
+    /// they had, larger than the buffer.
+    pub fn recvmmsg(
+        sock: &UdpSocket,
+        bufs: &mut [BytePacketBuffer],
+    ) -> io::Result<Vec<Option<(usize, SocketAddr)>>> {
+        // SAFETY: the all zero pattern is a valid `sockaddr_storage`
+        let mut addrs: Vec<libc::sockaddr_storage> = vec![unsafe { mem::zeroed() }; bufs.len()];
+        let mut iovecs: Vec<libc::iovec> = bufs
+            .iter_mut()
+            .map(|b| libc::iovec {
+                iov_base: b.buf.as_mut_ptr() as *mut libc::c_void,
+                iov_len: b.buf.len(),
+            })
+            .collect();
+        let mut headers: Vec<libc::mmsghdr> = iovecs
+            .iter_mut()
+            .zip(addrs.iter_mut())
+            .map(|(iovec, addr)| {
+                // SAFETY: the all zero pattern is a valid `mmsghdr`
+                let mut header: libc::mmsghdr = unsafe { mem::zeroed() };
+                header.msg_hdr.msg_name = addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+                header.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as u32;
+                header.msg_hdr.msg_iov = iovec;
+                header.msg_hdr.msg_iovlen = 1;
+                header
+            })
+            .collect();
+        // SAFETY: every header points to a buffer and an address that outlive the call
+        let received = unsafe {
+            libc::recvmmsg(
+                sock.as_raw_fd(),
+                headers.as_mut_ptr(),
+                headers.len() as libc::c_uint,
+                libc::MSG_DONTWAIT,
+                ptr::null_mut(),
+            )
+        };
+        if received < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(headers
+            .iter()
+            .zip(addrs.iter())
+            .zip(bufs.iter())
+            .take(received as usize)
+            .map(|((header, addr), buf)| {
+                let len = if header.msg_hdr.msg_flags & libc::MSG_TRUNC != 0 {
+                    buf.buf.len() + 1
+                } else {
+                    header.msg_len as usize
+                };
+                from_storage(addr)
+                    .map_err(|e| tracing::info!("Skipped a datagram: {}", e))
+                    .ok()
+                    .map(|addr| (len, addr))
+            })
+            .collect())
+    }
+
+    /// # `sendmmsg`
+    ///
+    /// Sends the datagrams in `batch` without blocking, returns how many have been sent.
+    pub fn sendmmsg(sock: &UdpSocket, batch: &[(Vec<u8>, SocketAddr)]) -> io::Result<usize> {
+        let mut addrs: Vec<(libc::sockaddr_storage, libc::socklen_t)> =
+            batch.iter().map(|(_, dst)| to_storage(dst)).collect();
+        let mut iovecs: Vec<libc::iovec> = batch
+            .iter()
+            .map(|(data, _)| libc::iovec {
+                iov_base: data.as_ptr() as *mut libc::c_void,
+                iov_len: data.len(),
+            })
+            .collect();
+        let mut headers: Vec<libc::mmsghdr> = iovecs
+            .iter_mut()
+            .zip(addrs.iter_mut())
+            .map(|(iovec, (addr, len))| {
+                // SAFETY: the all zero pattern is a valid `mmsghdr`
+                let mut header: libc::mmsghdr = unsafe { mem::zeroed() };
+                header.msg_hdr.msg_name = addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+                header.msg_hdr.msg_namelen = *len;
+                header.msg_hdr.msg_iov = iovec;
+                header.msg_hdr.msg_iovlen = 1;
+                header
+            })
+            .collect();
+        // SAFETY: every header points to data and an address that outlive the call,
+        // the kernel doesn't write to the data
+        let sent = unsafe {
+            libc::sendmmsg(
+                sock.as_raw_fd(),
+                headers.as_mut_ptr(),
+                headers.len() as libc::c_uint,
+                libc::MSG_DONTWAIT,
+            )
+        };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(sent as usize)
+    }
+
+    fn from_storage(addr: &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+        match addr.ss_family as libc::c_int {
+            libc::AF_INET => {
+                // SAFETY: the family tells the storage holds a `sockaddr_in`
+                let addr = unsafe { &*(addr as *const _ as *const libc::sockaddr_in) };
+                Ok(SocketAddr::V4(SocketAddrV4::new(
+                    Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                    u16::from_be(addr.sin_port),
+                )))
+            }
+            libc::AF_INET6 => {
+                // SAFETY: the family tells the storage holds a `sockaddr_in6`
+                let addr = unsafe { &*(addr as *const _ as *const libc::sockaddr_in6) };
+                Ok(SocketAddr::V6(SocketAddrV6::new(
+                    Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                    u16::from_be(addr.sin6_port),
+                    addr.sin6_flowinfo,
+                    addr.sin6_scope_id,
+                )))
+            }
+            family => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unexpected address family: {}", family),
+            )),
+        }
+    }
+
+    fn to_storage(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+        // SAFETY: the all zero pattern is a valid `sockaddr_storage`
+        let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let len = match addr {
+            SocketAddr::V4(addr) => {
+                // SAFETY: `sockaddr_storage` is large and aligned enough for any address
+                let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+                sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                sin.sin_port = addr.port().to_be();
+                sin.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+                mem::size_of::<libc::sockaddr_in>()
+            }
+            SocketAddr::V6(addr) => {
+                // SAFETY: `sockaddr_storage` is large and aligned enough for any address
+                let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+                sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sin6.sin6_port = addr.port().to_be();
+                sin6.sin6_addr.s6_addr = addr.ip().octets();
+                sin6.sin6_flowinfo = addr.flowinfo();
+                sin6.sin6_scope_id = addr.scope_id();
+                mem::size_of::<libc::sockaddr_in6>()
+            }
+        };
+        (storage, len as libc::socklen_t)
+    }
+}
@@ -1,36 +1,399 @@
 use std::{
-    net::{Ipv4Addr, SocketAddr},
-    sync::Arc,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
 };
 
-use helpers::{cached_compose_response, compose_response, goofy_workaround};
+use helpers::{
+    any_response, cached_compose_response, compose_response, is_root_or_tld, tld_response,
+};
+use serde::Deserialize;
 use sqlx::SqlitePool;
-use tokio::net::UdpSocket;
+use tokio::sync::{broadcast, Semaphore};
 
-use crate::structs::{buffer::BytePacketBuffer, header::ResultCode, packet::Packet};
+use crate::{
+    acl::{Acl, DeniedAction},
+    blocklist::Blocklist,
+    cachewriter::CacheWriter,
+    clients::ClientNames,
+    clock::Clock,
+    configuration::Settings,
+    ddr::Ddr,
+    domainpolicy::{DomainAction, DomainPolicies},
+    ecs::{ClientSubnet, EcsPolicy},
+    mdns::{self, Mdns},
+    notify::NotifyHandler,
+    outbound::Transport,
+    overrides::Overrides,
+    querylog::{QueryLog, QueryLogEntry},
+    resolver::Resolver,
+    safesearch::SafeSearch,
+    specialuse::SpecialUse,
+    stats::QueryStats,
+    structs::{
+        auxiliaries::CResult,
+        buffer::BytePacketBuffer,
+        header::{OpCode, ResultCode},
+        packet::Packet,
+        questions_and_records::{EdnsOption, QueryType, Record, TCP_KEEPALIVE_OPTION},
+    },
+    systemd,
+    tarpit::{Offense, Tarpit},
+    telemetry::QUERY_DURATION,
+    tsig::{self, Keyring},
+    udp::Responder,
+    zones::ZoneStore,
+};
 
+mod errors;
+mod health;
 mod helpers;
+mod hooks;
+mod infra;
+mod middleware;
+mod probe;
+mod retransmit;
+mod trace;
+
+pub use errors::ErrorResponses;
+pub use health::UpstreamHealth;
+pub use hooks::{
+    AfterResolution, BeforeResolution, QueryHook, QueryInfo, Resolution, ResponseHook, Verdict,
+};
+pub use infra::{InfraCache, ServerInfo};
+pub use middleware::{Middleware, MiddlewareKind, Next, Pipeline, QueryContext};
+pub use probe::{self_test, HEALTH_CHECK_NAME};
+pub use retransmit::Retransmissions;
+pub use trace::UpstreamTrace;
+
+/// # `OverflowPolicy`
+///
+/// What happens to the queries received while the maximum number
+/// of queries is already being handled.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OverflowPolicy {
+    /// The query is silently dropped, the client will retry.
+    #[default]
+    Drop,
+    /// The client receives a SERVFAIL response.
+    ServFail,
+}
+
+/// # `AnyPolicy`
+///
+/// How the queries of type ANY are answered, they are never resolved (RFC 8482):
+/// they only serve to amplify attacks, the clients asking for every type
+/// are better served by asking for the types they need.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AnyPolicy {
+    /// A single synthesized HINFO record.
+    #[default]
+    Hinfo,
+    /// The records of the name in the cache, a HINFO record if there are none.
+    Cached,
+}
+
+/// # `TldPolicy`
+///
+/// How the queries for the root and for the bare top level domains are answered,
+/// mostly junk or probing traffic that isn't worth a full resolution.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TldPolicy {
+    /// The records in the cache, the name is refused if there are none.
+    /// Only the records of the names asked for by the clients are cached,
+    /// the ones of the delegations followed aren't.
+    Cached,
+    /// The name is refused.
+    Refused,
+    /// The name is resolved as any other.
+    #[default]
+    Resolve,
+}
+
+/// # `UpstreamPolicy`
+///
+/// Limits applied to the queries we send to other name servers.
+#[derive(Debug, Clone)]
+pub struct UpstreamPolicy {
+    /// Time waited for the response to a single attempt.
+    pub attempt_timeout: Duration,
+    /// Maximum number of attempts for a single query.
+    pub attempts: u32,
+    /// Pause before the second attempt, doubled at every following one.
+    pub initial_backoff: Duration,
+    /// Time available for the whole resolution of a client query.
+    pub query_deadline: Duration,
+    /// Pause after which the following server of the delegation is queried as well,
+    /// if the first one hasn't answered yet, the queries aren't hedged if `None`.
+    pub hedge_delay: Option<Duration>,
+    /// Whether the IPv6 addresses of the name servers are queried.
+    pub ipv6: bool,
+    /// Time an IPv6 address is given to answer before an IPv4 one is queried as well.
+    pub ipv6_head_start: Duration,
+}
+
+impl UpstreamPolicy {
+    pub fn from_settings(settings: &Settings) -> Self {
+        UpstreamPolicy {
+            attempt_timeout: settings.get_upstream_attempt_timeout(),
+            attempts: settings.get_upstream_attempts(),
+            initial_backoff: settings.get_upstream_initial_backoff(),
+            query_deadline: settings.get_query_deadline(),
+            hedge_delay: settings.get_upstream_hedge_delay(),
+            ipv6: settings.get_upstream_ipv6(),
+            ipv6_head_start: settings.get_upstream_ipv6_head_start(),
+        }
+    }
+}
+
+/// # `TtlBounds`
+///
+/// Shortest and longest TTL of the records obtained from the other name servers,
+/// applied when they are cached and when they are served.
+#[derive(Debug, Clone, Copy)]
+pub struct TtlBounds {
+    pub min: u32,
+    pub max: u32,
+}
+
+impl TtlBounds {
+    pub fn from_settings(settings: &Settings) -> Self {
+        TtlBounds {
+            min: settings.get_cache_min_ttl(),
+            max: settings.get_cache_max_ttl(),
+        }
+    }
+
+    /// # `apply`
+    ///
+    /// Clamps the TTL of every record of `packet`.
+    pub fn apply(&self, packet: &mut Packet) {
+        let records = packet
+            .answers
+            .iter_mut()
+            .chain(packet.authorities.iter_mut())
+            .chain(packet.resources.iter_mut());
+        for ttl in records.filter_map(Record::ttl_mut) {
+            // `max` prevails on a `min` exceeding it
+            *ttl = (*ttl).max(self.min).min(self.max);
+        }
+    }
+}
+
+/// # `Upstream`
+///
+/// What the resolver needs to talk to other name servers, borrowed from `ServerState`.
+#[derive(Debug, Clone, Copy)]
+pub struct Upstream<'a> {
+    pub policy: &'a UpstreamPolicy,
+    pub health: &'a UpstreamHealth,
+    pub infra: &'a InfraCache,
+    /// Sends the queries to the name servers.
+    pub transport: &'a dyn Transport,
+    /// Sends the queries to the name servers that truncate their responses.
+    pub tcp_transport: &'a dyn Transport,
+    /// Tells when the cached records expire.
+    pub clock: &'a dyn Clock,
+    /// Whether the cache database is read and written.
+    pub use_cache: bool,
+    pub cache_writer: &'a CacheWriter,
+    /// Where the queries sent for the client query being handled are noted.
+    pub trace: &'a UpstreamTrace,
+    /// Subnet sent on behalf of the client, the answers cached are valid for it alone.
+    pub client_subnet: Option<ClientSubnet>,
+    /// Zones whose name servers are sent `client_subnet`, see `EcsPolicy`.
+    pub ecs_zones: &'a [String],
+    pub ttl_bounds: TtlBounds,
+    /// Servers the resolution starts from, recursively, instead of the root.
+    pub forwarders: &'a [IpAddr],
+    /// Sends the queries to the forwarders, if not `transport`.
+    pub forward_transport: Option<&'a dyn Transport>,
+    /// When the client query must be answered by, every query sent on its behalf
+    /// only waits for the time that is left.
+    pub deadline: Option<Instant>,
+}
+
+impl Upstream<'_> {
+    /// # `remaining`
+    ///
+    /// The time left before the deadline, `limit` at most.
+    pub fn remaining(&self, limit: Duration) -> Duration {
+        self.deadline.map_or(limit, |deadline| {
+            deadline
+                .saturating_duration_since(Instant::now())
+                .min(limit)
+        })
+    }
+
+    /// # `expired`
+    ///
+    /// Whether the deadline has passed.
+    pub fn expired(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+}
+
+/// # `Policies`
+///
+/// The parts of the state that follow the configuration, replaced when it's reloaded.
+pub struct Policies {
+    pub acl: Acl,
+    pub blocklist: Arc<Blocklist>,
+    pub safe_search: SafeSearch,
+    pub special_use: SpecialUse,
+    pub ddr: Ddr,
+    pub ecs: EcsPolicy,
+    pub any: AnyPolicy,
+    pub tld: TldPolicy,
+    /// Whether the addresses of the answers are rotated.
+    pub rotate: bool,
+    pub domains: DomainPolicies,
+}
+
+impl Policies {
+    /// # `from_settings`
+    ///
+    /// Fails if the configuration of the blocklist is invalid.
+    pub fn from_settings(settings: &Settings, db_pool: SqlitePool) -> CResult<Self> {
+        Ok(Policies {
+            acl: Acl::from_settings(settings),
+            blocklist: Arc::new(Blocklist::from_settings(settings, db_pool)?),
+            safe_search: SafeSearch::from_settings(settings),
+            special_use: SpecialUse::from_settings(settings),
+            ddr: Ddr::from_settings(settings),
+            ecs: EcsPolicy::from_settings(settings),
+            any: settings.get_any_policy(),
+            tld: settings.get_tld_policy(),
+            rotate: settings.get_rotation_enabled(),
+            domains: DomainPolicies::from_settings(settings),
+        })
+    }
+}
+
+/// # `ServerState`
+///
+/// Services shared by every query handler.
+pub struct ServerState {
+    /// The database of the server, the cache and the statistics are in `resolver.storage`.
+    pub db_pool: SqlitePool,
+    /// Resolves the queries that aren't answered locally.
+    pub resolver: Resolver,
+    pub policies: RwLock<Arc<Policies>>,
+    pub notify: Arc<NotifyHandler>,
+    pub zones: Arc<ZoneStore>,
+    /// Names of the clients, shown along with their addresses.
+    pub clients: Arc<ClientNames>,
+    pub keyring: Arc<Keyring>,
+    pub error_responses: ErrorResponses,
+    pub query_log: Option<QueryLog>,
+    pub stats: Option<Arc<QueryStats>>,
+    /// The queries answered, for the live stream of the dashboard.
+    pub query_stream: broadcast::Sender<QueryLogEntry>,
+    /// Whether the server is ready to answer, for the readiness probes.
+    pub ready: AtomicBool,
+    /// Answers the queries for `.local` names, if mDNS is enabled.
+    pub mdns: Option<Arc<Mdns>>,
+    /// Responses whose addresses have been rotated, the offset of the next rotation.
+    pub rotation: AtomicUsize,
+    /// Whether the requests are parsed with `Packet::from_buffer_strict`,
+    /// their buffers holding the datagram received and nothing else.
+    pub strict_parsing: bool,
+    /// Bounds the queries being handled at once, a spike of traffic can't exhaust our resources.
+    pub in_flight: Arc<Semaphore>,
+    /// The client queries being handled, their retransmissions aren't handled again.
+    pub retransmissions: Retransmissions,
+    /// The middlewares the client queries go through.
+    pub pipeline: Pipeline,
+    /// The rules and the local records added through the management API.
+    pub overrides: Overrides,
+    /// Penalizes the abusive clients, if enabled.
+    pub tarpit: Option<Arc<Tarpit>>,
+}
+
+impl ServerState {
+    /// # `upstream`
+    ///
+    /// Returns the view of the state used to resolve a query,
+    /// the queries sent to other name servers are noted in `trace`.
+    pub fn upstream<'a>(&'a self, trace: &'a UpstreamTrace) -> Upstream<'a> {
+        self.resolver.upstream(trace)
+    }
+
+    /// # `policies`
+    ///
+    /// Returns the policies in force, a query is handled with the same ones
+    /// from start to end even if they are replaced in the meantime.
+    pub fn policies(&self) -> Arc<Policies> {
+        self.policies.read().unwrap().clone()
+    }
+
+    /// # `set_policies`
+    ///
+    /// Replaces the policies, the queries received from now on follow the new ones.
+    pub fn set_policies(&self, policies: Arc<Policies>) {
+        *self.policies.write().unwrap() = policies;
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Relaxed)
+    }
+
+    /// # `set_ready`
+    ///
+    /// Also tells systemd, when it supervises the server, that the server
+    /// is ready or stopping.
+    pub fn set_ready(&self, ready: bool) {
+        if self.ready.swap(ready, Ordering::Relaxed) == ready {
+            return;
+        }
+        let state = if ready { "READY=1" } else { "STOPPING=1" };
+        if let Err(e) = systemd::notify(state) {
+            tracing::warn!("Unable to notify systemd of {}: {}", state, e);
+        }
+    }
+}
 
 /// # `query_handler`
 ///
 /// Handles a single incoming query.
 #[tracing::instrument(
     name = "Responding to a query",
-    skip(sock, req_buffer, src),
+    skip(sock, req_buffer, src, state),
     fields(
-        address = %src
+        address = %src,
+        client = tracing::field::Empty,
+        latency_ms = tracing::field::Empty,
+        rescode = tracing::field::Empty,
+        upstream_round_trips = tracing::field::Empty,
+        nameservers = tracing::field::Empty
     )
 )]
 pub async fn query_handler(
-    sock: Arc<UdpSocket>,
+    sock: Responder,
     mut req_buffer: BytePacketBuffer,
     src: SocketAddr,
-    root_addr: Ipv4Addr,
-    db_pool: SqlitePool,
+    state: Arc<ServerState>,
 ) {
-    let mut success = true;
+    let started = Instant::now();
+    if let Some(name) = state.clients.name_of(src.ip()) {
+        tracing::Span::current().record("client", name.as_str());
+    }
+    let trace = UpstreamTrace::new();
+    let errors = &state.error_responses;
     // Parse raw bytes into a structured object
-    let mut request = match Packet::from_buffer(&mut req_buffer) {
+    let parsed = if state.strict_parsing {
+        Packet::from_buffer_strict(&mut req_buffer)
+    } else {
+        Packet::from_buffer(&mut req_buffer)
+    };
+    let mut request = match parsed {
         Ok(x) => x,
         Err(e) => {
             tracing::info!(
@@ -38,67 +401,337 @@ pub async fn query_handler(
                 src,
                 e
             );
-            // NOTE: we cannot await a future inside here, that's why goofy_workaround
-            success = false;
-            Packet::new()
+            if let Some(tarpit) = &state.tarpit {
+                tarpit.offend(src.ip(), Offense::Malformed);
+            }
+            errors.send(&sock, src, 0, e.rescode()).await;
+            return;
         }
     };
-    if !success {
-        // TODO: rework goofy goofy_workaround
-        goofy_workaround(sock, src, 0, ResultCode::FORMERR).await;
-        return;
-    }
 
-    // packet inside of a block or even inside compose_response
     // NOTE: google's dns ignores the packets that have the header's response field
     // equal to true
     if request.header.response {
         return;
     }
-    let mut response = if !request.header.recursion_desired {
-        cached_compose_response(&mut request, &db_pool).await
-    } else {
-        compose_response(&mut request, root_addr, db_pool).await
+    // A client that retransmits gets the response to the query it sent first
+    let Some(_tracked) = state.retransmissions.track(src, &request) else {
+        tracing::info!("Dropped a retransmission of a query being handled");
+        return;
     };
 
-    let mut res_buffer = BytePacketBuffer::new();
+    let policies = state.policies();
+    // Access control comes before any other work
+    if !policies.acl.may_query(src.ip()) {
+        tracing::info!("Denied a query from {}", src);
+        if policies.acl.denied_action == DeniedAction::Refused {
+            errors
+                .send(&sock, src, request.header.id, ResultCode::REFUSED)
+                .await;
+        }
+        return;
+    }
+    let recursion_allowed = policies.acl.may_recurse(src.ip());
+    // A malformed client subnet is an error whether subnets are sent or not
+    if ClientSubnet::is_malformed(&request) {
+        tracing::info!("Received a malformed client subnet from {}", src);
+        errors
+            .send(&sock, src, request.header.id, ResultCode::FORMERR)
+            .await;
+        return;
+    }
 
-    match response.write(&mut res_buffer) {
-        Ok(_) => {}
-        Err(e) => {
-            tracing::info!("Unable to fullfil a query from {} becouse of: {}", src, e);
-            success = false;
+    // Signed messages need to be authenticated before being processed,
+    // the response will be signed with the same key.
+    let raw_request = req_buffer.written();
+    let signer = match tsig::find_tsig(raw_request) {
+        Ok(Some(request_tsig)) => match tsig::verify(raw_request, &state.keyring, None) {
+            Ok((key, request_tsig)) => Some((key, request_tsig.mac)),
+            Err(e) => {
+                tracing::info!("Rejected a signed message from {}: {}", src, e);
+                errors::send_tsig_rejection(
+                    &sock,
+                    src,
+                    request.header.id,
+                    &state.keyring,
+                    &request_tsig,
+                    e,
+                )
+                .await;
+                return;
+            }
+        },
+        _ => None,
+    };
+
+    let opcode = OpCode::from_num(request.header.opcode);
+    // A query carries a single question (RFC 9619), the policies are only applied
+    // to the first one, the others would escape them
+    request.questions.truncate(1);
+    let mut upstream = Upstream {
+        client_subnet: policies.ecs.subnet_for(&request, src.ip()),
+        ecs_zones: policies.ecs.zones(),
+        ..state.upstream(&trace)
+    };
+    // The client gets a response before its stub resolver gives up and asks again
+    upstream.deadline = Some(started + upstream.policy.query_deadline);
+    // The policy of the longest suffix of the name overrides the normal resolution
+    let domain_policy = request
+        .questions
+        .first()
+        .filter(|_| opcode == OpCode::QUERY)
+        .and_then(|question| policies.domains.matching(&question.qname));
+    if let Some(policy) = domain_policy {
+        if let DomainAction::Forward(servers) = &policy.action {
+            upstream.forwarders = servers;
+            upstream.forward_transport = policy.transport.as_deref();
+        }
+        if let Some(ttl) = policy.ttl {
+            upstream.ttl_bounds = TtlBounds { min: ttl, max: ttl };
         }
+        upstream.use_cache &= !policy.no_cache;
+    }
+    let id = request.header.id;
+    let dnssec_ok = request.dnssec_ok();
+    let checking_disabled = request.header.checking_disabled;
+    let keepalive_asked = request.get_edns_option(TCP_KEEPALIVE_OPTION).is_some();
+    let udp_limit = sock.is_udp().then(|| request.udp_payload_limit());
+    let ctx = QueryContext {
+        request,
+        src,
+        opcode,
+        recursion_allowed,
+        state: &state,
+        policies: &policies,
+        upstream,
+        trace: &trace,
+        started,
+        domain_policy,
+        key_name: signer.as_ref().map(|(key, _)| key.name.as_str()),
     };
-    if !success {
-        goofy_workaround(sock, src, request.header.id, ResultCode::SERVFAIL).await;
-        return;
+    let mut response = state.pipeline.run(ctx).await;
+    record_timing(started, response.header.rescode, &trace);
+    // Nothing is validated, the data is served as obtained whether the client
+    // disabled the checking or not, CD is only echoed (RFC 4035 section 3.1.6)
+    response.header.checking_disabled = checking_disabled;
+    if dnssec_ok {
+        response.set_dnssec_ok(true);
+    } else {
+        response.strip_dnssec();
+    }
+    // The clients over TCP asking for it are told how long the connection
+    // may stay idle, in units of 100 milliseconds (RFC 7828 section 3.3.2)
+    if let (true, Some(idle_timeout)) = (keepalive_asked, sock.keepalive()) {
+        let timeout = (idle_timeout.as_millis() / 100).min(u16::MAX as u128) as u16;
+        response.add_edns_option(EdnsOption {
+            code: TCP_KEEPALIVE_OPTION,
+            data: timeout.to_be_bytes().to_vec(),
+        });
     }
 
-    let len = res_buffer.pos();
-    // NOTE: expecially goofy workaround
-    let data = [0];
-    let data_ref = match res_buffer.get_range(0, len) {
-        Ok(d) => d,
-        Err(e) => {
-            tracing::info!("Failed to respond to the query:\n{}", e);
-            success = false;
-            &data
+    let compose = |response: &mut Packet| {
+        let mut res_buffer = BytePacketBuffer::new();
+        response.write(&mut res_buffer).map_err(|e| {
+            tracing::info!("Unable to fullfil a query from {} becouse of: {}", src, e)
+        })?;
+        if let Some((key, request_mac)) = &signer {
+            tsig::sign(&mut res_buffer, key, Some(request_mac)).map_err(|e| {
+                tracing::info!("Unable to sign the response for {} becouse of: {}", src, e)
+            })?;
         }
+        Ok::<_, ()>(res_buffer)
     };
-    if !success {
-        goofy_workaround(sock, src, request.header.id, ResultCode::SERVFAIL).await;
+    let mut composed = compose(&mut response);
+    // Over UDP the response has to fit the size the client accepts: the additional
+    // records go first, then everything but the questions and TC is set
+    if let Some(limit) = udp_limit {
+        if composed.as_ref().is_ok_and(|buffer| buffer.pos() > limit) {
+            response.strip_additional();
+            composed = compose(&mut response);
+        }
+        if composed.as_ref().is_ok_and(|buffer| buffer.pos() > limit) {
+            tracing::info!(
+                "The response for {} doesn't fit in {} bytes, truncated",
+                src,
+                limit
+            );
+            response.truncate();
+            composed = compose(&mut response);
+        }
+    }
+    let Ok(res_buffer) = composed else {
+        errors.send(&sock, src, id, ResultCode::SERVFAIL).await;
         return;
+    };
+
+    if let Err(e) = sock.send_to(res_buffer.written(), src).await {
+        tracing::info!("Failed to respond to the query:\n{}", e);
     }
+}
 
-    match sock.send_to(data_ref, src).await {
-        Ok(_) => {}
-        Err(e) => {
-            success = false;
-            tracing::info!("Failed to respond to the query:\n{}", e);
+/// # `answer`
+///
+/// The end of the pipeline, answers the query of `ctx` locally, from our zones, mDNS
+/// and the special names, or resolves it.
+async fn answer(ctx: QueryContext<'_>) -> Packet {
+    let QueryContext {
+        mut request,
+        src,
+        opcode,
+        recursion_allowed,
+        state,
+        policies,
+        upstream,
+        trace,
+        domain_policy,
+        key_name,
+        ..
+    } = ctx;
+    if opcode == OpCode::NOTIFY {
+        state.notify.handle_notify(&request, src, key_name)
+    } else if opcode != OpCode::QUERY {
+        tracing::info!("Received an unsupported operation: {:?}", opcode);
+        let mut r = Packet::new();
+        r.add_info(
+            request.header.id,
+            request.header.recursion_desired,
+            true,
+            true,
+            ResultCode::NOTIMP,
+        );
+        r.header.opcode = request.header.opcode;
+        r
+    } else if let Some(question) = request
+        .questions
+        .first()
+        .filter(|question| matches!(question.qtype, QueryType::AXFR | QueryType::IXFR))
+    {
+        // We don't serve zone transfers, and never over UDP (RFC 5936 section 4.2)
+        tracing::warn!(
+            "Refused a zone transfer ({:?}) of {} asked by {}",
+            question.qtype,
+            question.qname,
+            src
+        );
+        let mut r = Packet::new();
+        r.add_info(
+            request.header.id,
+            request.header.recursion_desired,
+            recursion_allowed,
+            true,
+            ResultCode::REFUSED,
+        );
+        r.questions = request.questions.clone();
+        r
+    } else if request
+        .questions
+        .first()
+        .is_some_and(probe::is_health_check)
+    {
+        probe::health_check_response(&request, state.is_ready())
+    } else if let Some(mut answer) = request
+        .questions
+        .first()
+        .and_then(|question| state.zones.answer(question))
+    {
+        // The name belongs to one of our zones, we are the authority
+        answer.header.id = request.header.id;
+        answer.header.recursion_desired = request.header.recursion_desired;
+        answer.header.recursion_available = recursion_allowed;
+        answer
+    } else if let Some(mdns) = state.mdns.as_ref().filter(|_| {
+        request
+            .questions
+            .first()
+            .is_some_and(|question| mdns::is_local(&question.qname))
+    }) {
+        mdns.answer(&request).await
+    } else if let Some(answer) = policies.ddr.answer(&request) {
+        answer
+    } else if let Some((question, name)) = request.questions.first().and_then(|question| {
+        policies
+            .special_use
+            .name_of(&question.qname)
+            .map(|name| (question, name))
+    }) {
+        // Never leaked to the root servers, they don't know these names,
+        // but the records configured for them, as the PTRs of the local network
+        state
+            .overrides
+            .answer(&request)
+            .or_else(|| domain_policy.and_then(|policy| policy.local_answer(&request)))
+            .unwrap_or_else(|| policies.special_use.answer(&request, question, name))
+    } else if !recursion_allowed {
+        // Whatever RD says: the cache isn't theirs to read either
+        tracing::info!("Denied recursion to {}", src);
+        let mut r = Packet::new();
+        r.add_info(
+            request.header.id,
+            request.header.recursion_desired,
+            false,
+            true,
+            ResultCode::REFUSED,
+        );
+        r.questions = request.questions.clone();
+        r
+    } else if let Some(answer) = state.overrides.answer(&request) {
+        answer
+    } else if let Some(answer) = domain_policy.and_then(|policy| policy.local_answer(&request)) {
+        answer
+    } else if policies.tld != TldPolicy::Resolve
+        && request
+            .questions
+            .first()
+            .is_some_and(|question| is_root_or_tld(&question.qname))
+    {
+        tld_response(
+            &mut request,
+            policies.tld,
+            state.resolver.storage.as_ref(),
+            upstream,
+        )
+        .await
+    } else if request
+        .questions
+        .first()
+        .is_some_and(|question| question.qtype == QueryType::ANY)
+    {
+        any_response(
+            &request,
+            policies.any,
+            state.resolver.storage.as_ref(),
+            upstream,
+        )
+        .await
+    } else if !request.header.recursion_desired {
+        let response =
+            cached_compose_response(&mut request, state.resolver.storage.as_ref(), upstream).await;
+        if !response.answers.is_empty() {
+            trace.record_cache_hit();
         }
-    };
-    if !success {
-        goofy_workaround(sock, src, request.header.id, ResultCode::SERVFAIL).await;
+        response
+    } else {
+        compose_response(
+            &mut request,
+            state.resolver.root_addr,
+            state.resolver.storage.as_ref(),
+            upstream,
+        )
+        .await
     }
 }
+
+/// # `record_timing`
+///
+/// `query_handler`'s helper, records how long the query took and what it took
+/// on the span of the query and in the metrics.
+fn record_timing(started: Instant, rescode: ResultCode, trace: &UpstreamTrace) {
+    let elapsed = started.elapsed();
+    let span = tracing::Span::current();
+    span.record("latency_ms", elapsed.as_secs_f64() * 1000.0);
+    span.record("rescode", tracing::field::debug(rescode));
+    span.record("upstream_round_trips", trace.round_trips());
+    span.record("nameservers", trace.servers());
+    metrics::histogram!(QUERY_DURATION, "rescode" => format!("{:?}", rescode))
+        .record(elapsed.as_secs_f64());
+}
@@ -0,0 +1,103 @@
+use std::net::SocketAddr;
+
+use crate::{
+    structs::{auxiliaries::CResult, buffer::BytePacketBuffer, header::ResultCode},
+    tsig::{self, Keyring, Tsig, TsigError},
+    udp::Responder,
+};
+
+/// An error response is a bare header.
+const HEADER_LEN: usize = 12;
+
+/// # `ErrorResponses`
+///
+/// The error responses sent when a query can't be handled, serialized once at startup:
+/// sending one only takes to copy it and write the ID of the query.
+#[derive(Debug)]
+pub struct ErrorResponses {
+    formerr: [u8; HEADER_LEN],
+    servfail: [u8; HEADER_LEN],
+    refused: [u8; HEADER_LEN],
+    notauth: [u8; HEADER_LEN],
+}
+
+impl ErrorResponses {
+    pub fn new() -> CResult<Self> {
+        Ok(ErrorResponses {
+            formerr: template(ResultCode::FORMERR)?,
+            servfail: template(ResultCode::SERVFAIL)?,
+            refused: template(ResultCode::REFUSED)?,
+            notauth: template(ResultCode::NOTAUTH)?,
+        })
+    }
+
+    /// # `response`
+    ///
+    /// Returns the error response with `rescode` for the query `id`,
+    /// `None` if `rescode` doesn't have a template.
+    pub fn response(&self, id: u16, rescode: ResultCode) -> Option<[u8; HEADER_LEN]> {
+        let mut response = match rescode {
+            ResultCode::FORMERR => self.formerr,
+            ResultCode::SERVFAIL => self.servfail,
+            ResultCode::REFUSED => self.refused,
+            ResultCode::NOTAUTH => self.notauth,
+            _ => return None,
+        };
+        response[..2].copy_from_slice(&id.to_be_bytes());
+        Some(response)
+    }
+
+    /// # `send`
+    ///
+    /// Sends the error response with `rescode` for the query `id` to `src`,
+    /// failures are only logged as there is nothing left to tell the client.
+    pub async fn send(&self, sock: &Responder, src: SocketAddr, id: u16, rescode: ResultCode) {
+        let response = match self.response(id, rescode) {
+            Some(r) => r,
+            None => {
+                tracing::warn!("No error response available for {:?}", rescode);
+                return;
+            }
+        };
+        if let Err(e) = sock.send_to(&response, src).await {
+            tracing::info!("Failed to send a {:?} response to {}: {}", rescode, src, e);
+        }
+    }
+}
+
+/// # `send_tsig_rejection`
+///
+/// Sends to `src` the NOTAUTH response for the query `id`, signed with `request`,
+/// that has been rejected for `error`, carrying the TSIG that tells why.
+pub async fn send_tsig_rejection(
+    sock: &Responder,
+    src: SocketAddr,
+    id: u16,
+    keyring: &Keyring,
+    request: &Tsig,
+    error: TsigError,
+) {
+    let response =
+        BytePacketBuffer::new_error_packet(ResultCode::NOTAUTH, id).and_then(|mut buffer| {
+            tsig::sign_rejection(&mut buffer, keyring, request, error)?;
+            Ok(buffer)
+        });
+    let response = match response {
+        Ok(response) => response,
+        Err(e) => {
+            tracing::warn!("Unable to compose the TSIG rejection for {}: {}", src, e);
+            return;
+        }
+    };
+    if let Err(e) = sock.send_to(response.written(), src).await {
+        tracing::info!("Failed to send a TSIG rejection to {}: {}", src, e);
+    }
+}
+
+fn template(rescode: ResultCode) -> CResult<[u8; HEADER_LEN]> {
+    let buffer = BytePacketBuffer::new_error_packet(rescode, 0)?;
+    buffer
+        .written()
+        .try_into()
+        .map_err(|_| "An error response isn't a bare header".into())
+}
@@ -0,0 +1,136 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{configuration::Settings, structs::questions_and_records::QueryType};
+
+/// # `UpstreamHealth`
+///
+/// Failures observed while talking to other name servers.
+/// A server that fails `failure_threshold` times in a row isn't queried for `cooldown`,
+/// after that a single query is let through to probe it, the others waiting for its outcome;
+/// a probe whose outcome never comes is replaced by another one after a `cooldown`.
+/// Queries that ended in SERVFAIL are remembered for `servfail_ttl` (RFC 2308, section 7),
+/// the clients retrying them don't trigger new resolutions.
+#[derive(Debug)]
+pub struct UpstreamHealth {
+    failure_threshold: u32,
+    cooldown: Duration,
+    servfail_ttl: Duration,
+    servers: Mutex<HashMap<IpAddr, ServerHealth>>,
+    servfails: Mutex<HashMap<(String, QueryType), Instant>>,
+}
+
+#[derive(Debug, Default)]
+struct ServerHealth {
+    /// Consecutive failures.
+    failures: u32,
+    /// The server isn't queried until then.
+    open_until: Option<Instant>,
+    /// When the query probing the server, once it cooled down, was let through.
+    probing_since: Option<Instant>,
+}
+
+impl UpstreamHealth {
+    pub fn new(failure_threshold: u32, cooldown: Duration, servfail_ttl: Duration) -> Self {
+        UpstreamHealth {
+            failure_threshold,
+            cooldown,
+            servfail_ttl,
+            servers: Mutex::new(HashMap::new()),
+            servfails: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn from_settings(settings: &Settings) -> Self {
+        UpstreamHealth::new(
+            settings.get_upstream_failure_threshold(),
+            settings.get_upstream_cooldown(),
+            settings.get_servfail_ttl(),
+        )
+    }
+
+    /// # `is_available`
+    ///
+    /// Returns false if `server` failed too many times and is still cooling down,
+    /// or if it has cooled down and another query is already probing it.
+    pub fn is_available(&self, server: IpAddr) -> bool {
+        let now = Instant::now();
+        let mut servers = self.servers.lock().unwrap();
+        let Some(health) = servers.get_mut(&server) else {
+            return true;
+        };
+        match health.open_until {
+            Some(until) if now < until => false,
+            Some(_) => {
+                let probing = health
+                    .probing_since
+                    .is_some_and(|since| now < since + self.cooldown);
+                if !probing {
+                    health.probing_since = Some(now);
+                }
+                !probing
+            }
+            None => true,
+        }
+    }
+
+    /// # `record_success`
+    ///
+    /// `server` responded, its failures are forgotten.
+    pub fn record_success(&self, server: IpAddr) {
+        self.servers.lock().unwrap().remove(&server);
+    }
+
+    /// # `record_failure`
+    ///
+    /// `server` didn't respond, once the threshold is reached it isn't queried for a while,
+    /// a failed probe puts it back to rest immediately.
+    pub fn record_failure(&self, server: IpAddr) {
+        let mut servers = self.servers.lock().unwrap();
+        let health = servers.entry(server).or_default();
+        health.failures += 1;
+        health.probing_since = None;
+        if health.failures >= self.failure_threshold {
+            tracing::warn!(
+                "{} failed {} times in a row, not querying it for {:?}",
+                server,
+                health.failures,
+                self.cooldown
+            );
+            health.open_until = Some(Instant::now() + self.cooldown);
+        }
+    }
+
+    /// # `cached_servfail`
+    ///
+    /// Returns true if the resolution of `qname` recently failed.
+    pub fn cached_servfail(&self, qname: &str, qtype: QueryType) -> bool {
+        let mut servfails = self.servfails.lock().unwrap();
+        let key = (qname.to_lowercase(), qtype);
+        match servfails.get(&key) {
+            Some(expiration) if Instant::now() < *expiration => true,
+            Some(_) => {
+                servfails.remove(&key);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// # `cache_servfail`
+    ///
+    /// Remembers that the resolution of `qname` failed, the expired entries are discarded.
+    pub fn cache_servfail(&self, qname: &str, qtype: QueryType) {
+        if self.servfail_ttl.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+        let mut servfails = self.servfails.lock().unwrap();
+        servfails.retain(|_, expiration| now < *expiration);
+        servfails.insert((qname.to_lowercase(), qtype), now + self.servfail_ttl);
+    }
+}
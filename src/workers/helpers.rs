@@ -1,150 +1,48 @@
 use std::net::Ipv4Addr;
-use std::str::FromStr;
-use std::{net::SocketAddr, sync::Arc};
 
-use sqlx::SqlitePool;
-use tokio::net::UdpSocket;
+use tokio::time::timeout;
 
-use crate::structs::db_queries::CachedRecord;
+use crate::ecs::ClientSubnet;
+use crate::resolver::inquiring;
+use crate::storage::Storage;
 use crate::structs::{
-    auxiliaries::CResult,
-    buffer::BytePacketBuffer,
     header::ResultCode,
     packet::Packet,
-    questions_and_records::{QueryType, Question},
+    questions_and_records::{QueryType, Question, Record},
 };
 
-/// # `lookup`
-///
-/// Opens a new socket with the server provided and queris it
-/// for the name provided, returns the packet if everything went well.
-#[tracing::instrument(
-    "Inquiring an extername name server",
-    skip(qname, qtype, server),
-    fields(
-        domain_name = qname,
-        server_ip = %server.0,
-        server_port = server.1
-    )
-)]
-pub async fn lookup(qname: &str, qtype: QueryType, server: (Ipv4Addr, u16)) -> CResult<Packet> {
-    // Socket
-    let socket = UdpSocket::bind("0.0.0.0:0").await?;
-
-    // Preparing the query packet
-    let mut packet = Packet::new();
-    // TODO: generate a random value maybe
-    packet.header.id = 999;
-    packet.header.questions = 1;
-    packet.header.recursion_desired = true;
-    packet
-        .questions
-        .push(Question::new(qname.to_string(), qtype));
-    let mut req_buffer = BytePacketBuffer::new();
-    packet.write(&mut req_buffer)?;
+use super::{AnyPolicy, TldPolicy, Upstream};
 
-    // Sends the query
-    socket
-        .send_to(&req_buffer.buf[0..req_buffer.pos()], server)
-        .await?;
+/// TTL of the CNAME records produced by the rewrites.
+const REWRITE_TTL: u32 = 300;
 
-    // Receiving a response
-    let mut res_buffer = BytePacketBuffer::new();
-    socket.recv_from(&mut res_buffer.buf).await?;
+/// TTL of the HINFO records answering the ANY queries.
+const ANY_HINFO_TTL: u32 = 3600;
 
-    Packet::from_buffer(&mut res_buffer)
-}
-
-/// `goofy_workaround`
-///
-/// `query_handler`'s helper function, workaround to the fact
-/// that `match` doesn't support awaiting a future inside of one of it's branches.
-pub async fn goofy_workaround(sock: Arc<UdpSocket>, src: SocketAddr, id: u16, rescode: ResultCode) {
-    let mut res_buffer = match BytePacketBuffer::new_error_packet(rescode, id) {
-        Ok(b) => b,
-        Err(_) => {
-            return;
-        }
-    };
-    let _ = sock.send_to(&mut res_buffer.buf, src).await;
-}
+/// Extended DNS Error "No Reachable Authority" (RFC 8914).
+const EDE_NO_REACHABLE_AUTHORITY: u16 = 22;
 
-/// # `handling_record`, `inquiring`'s helper function
+/// # `QuestionOutcome`
 ///
-/// This function parses a record extracted from the database and check if it is valid.
-/// If its valid:
-///     - if `inquiring` is searching for a name server it will updates the relative informations
-///     - creates the response and returns it
-/// else:
-///     - deletes the record from the database, returns `None`
-/// Handles tarcing.
-/// TODO: testing
-pub async fn handling_record(
-    record: &CachedRecord,
-    db_pool: &SqlitePool,
-    search_for_qname: &mut bool,
-    current_ns: &mut Ipv4Addr,
-    currently_quering: &mut String,
-    qname: &str,
-    current_type: &mut QueryType,
-    qtype: &QueryType,
-) -> Option<Packet> {
-    if record.is_valid() {
-        // the stack accordingly
-        // record is not expired
-        tracing::info!("Found valid record for {} in the cache.", record.domain,);
-
-        // sercing for a dns server, updates with the values found
-        if !*search_for_qname {
-            *current_ns = match Ipv4Addr::from_str(&record.domain) {
-                Ok(ip) => ip,
-                Err(e) => {
-                    // IDEA: we may want to delete the malformed entry
-                    tracing::error!("Incorrect data has been found in the cache database, it's necessary a debug, the server is still capable of responding to requests from the clients without the cache, but the cache is unreliable, wrong data may be served with this configuration, consider disabling the cache database with `-c` flag. Error:\n{}", e);
-                    return None;
-                }
-            };
-            *currently_quering = qname.to_string();
-            *current_type = *qtype;
-            *search_for_qname = true;
-            return None;
-        }
-
-        let mut response = Packet::new();
-        match response.add_cr_to_answers(&record) {
-            Ok(_) => {
-                return Some(response);
-            }
-            Err(e) => {
-                // If this variant is found it means we have incorrect
-                // data in our chache
-                tracing::error!("Incorrect data has been found in the cache database, it's necessary a debug, the server is still capable of responding to requests from the clients without the cache, but the cache is unreliable, wrong data may be served with this configuration, consider disabling the cache database with `-c` flag. Error:\n{}", e);
-                return None;
-            }
-        };
-    } else {
-        match record.delete_from_db(db_pool).await {
-            Ok(_) => {
-                tracing::info!(
-                    "Deleted cached entry for \"{}\" from the database",
-                    record.domain
-                );
-            }
-            Err(e) => {
-                tracing::error!("I was unable to cancel an entry from the database, the application needs to be shutdown, error:\n{}", e);
-            }
-        };
-        return None;
-    }
+/// How the resolution of a single question ended.
+enum QuestionOutcome {
+    /// The response of the name servers.
+    Resolved(Packet),
+    /// The resolution failed, or failed a moment ago.
+    Failed,
+    /// The resolution didn't complete before the deadline.
+    Late,
 }
 
 /// # `compose_response`
 ///
 /// `query_handler`'s helper, composes a response packet give a specific request.
+/// The request carries a single question, `query_handler` drops the others.
 pub async fn compose_response(
     request: &mut Packet,
     root_addr: Ipv4Addr,
-    db_pool: SqlitePool,
+    storage: &dyn Storage,
+    upstream: Upstream<'_>,
 ) -> Packet {
     // Composing the packet for the response
     let mut response = Packet::new();
@@ -153,195 +51,156 @@ pub async fn compose_response(
     response.header.recursion_desired = true;
     response.header.recursion_available = true;
     response.header.response = true;
+    // Obtained from the other name servers or the cache, we aren't the authority
+    response.header.authoritative_answer = false;
 
-    // Iterating over  the question section
-    if let Some(question) = request.questions.pop() {
-        tracing::info!("Received query: {:?}", question);
-
-        // Performing a lookup for every question in the packet received
-        if let Ok(result) =
-            inquiring(&question.qname, question.qtype, root_addr.clone(), db_pool).await
-        {
-            response.questions.push(question.clone());
-            response.header.rescode = result.header.rescode;
+    let edns = request.get_edns().is_some();
+    // The subnet of the client is echoed along with the scope of the answer (RFC 7871 section 7.2.1)
+    let echoed_subnet = upstream
+        .client_subnet
+        .and_then(|_| ClientSubnet::from_packet(request));
 
+    let Some(question) = request.questions.pop() else {
+        response.header.rescode = ResultCode::FORMERR;
+        return response;
+    };
+    let mut scope = None;
+    response.header.rescode = match resolve_question(&question, root_addr, storage, upstream).await
+    {
+        QuestionOutcome::Resolved(result) => {
+            scope =
+                Some(ClientSubnet::from_packet(&result).map_or(0, |answer| answer.scope_prefix));
             for rec in result.answers {
-                tracing::info!("Answer: {:?}", rec);
+                tracing::info!("Answer: {}", rec);
                 response.answers.push(rec);
             }
             for rec in result.authorities {
                 tracing::info!("Authority: {:?}", rec);
                 response.authorities.push(rec);
             }
-            for rec in result.resources {
+            // The OPT record of the name server isn't meant for the client
+            for rec in result
+                .resources
+                .into_iter()
+                .filter(|rec| !matches!(rec, Record::OPT { .. }))
+            {
                 tracing::info!("Resouce: {:?}", rec);
                 response.resources.push(rec);
             }
-        } else {
-            response.header.rescode = ResultCode::SERVFAIL;
+            result.header.rescode
         }
-    } else {
-        response.header.rescode = ResultCode::FORMERR;
+        QuestionOutcome::Failed => ResultCode::SERVFAIL,
+        QuestionOutcome::Late => {
+            if edns {
+                response.add_ede(EDE_NO_REACHABLE_AUTHORITY, "Resolution deadline exceeded");
+            }
+            ResultCode::SERVFAIL
+        }
+    };
+    response.questions.push(question);
+    if let (Some(subnet), Some(sent), Some(scope)) = (echoed_subnet, upstream.client_subnet, scope)
+    {
+        response.add_edns_option(subnet.with_scope(scope.min(sent.source_prefix)).to_option());
     }
 
     response
 }
 
-/// # `inquiring`
+/// # `resolve_question`
 ///
-/// Receives a query name and a type and performes an iterative lookup starting
-/// from a root server.
-#[tracing::instrument(
-    name = "Starting the lookup process"
-    skip(qtype, db_pool)
-)]
-pub async fn inquiring(
-    qname: &str,
-    qtype: QueryType,
+/// `compose_response`'s helper, resolves `question` within the deadline of the query,
+/// the failures are remembered for a while.
+async fn resolve_question(
+    question: &Question,
     root_addr: Ipv4Addr,
-    db_pool: SqlitePool,
-) -> CResult<Packet> {
-    // the current name server that we are using to inquire
-    let mut current_ns = root_addr;
-    // the name we are currently querying, the qname required or
-    // a name server.
-    let mut currently_quering = qname.to_string();
-    // current type of the query.
-    let mut current_type = qtype;
-    // indicates if `inquiring` is searching for the qname provided or
-    // for a name server that may have the required information
-    let mut search_for_qname = true;
-
-    // Since it might take an arbitrary number of steps, we enter an unbounded loop.
-    loop {
-        // query chace database
-        // NOTE: `LIMIT 1` improves the performance when using `.fetch_one`
-        tracing::info!("Searching the cache database for {}.", currently_quering);
-        let res = sqlx::query_as::<_, CachedRecord>(r#"SELECT id, address, host, priority, domain, expiration_date, ttl, record_type FROM entries WHERE (domain = $1) LIMIT 1"#)
-            .bind(&currently_quering)
-            .fetch_one(&db_pool)
-            .await;
-        match res {
-            Ok(cr) => {
-                match handling_record(
-                    &cr,
-                    &db_pool,
-                    &mut search_for_qname,
-                    &mut current_ns,
-                    &mut currently_quering,
-                    &qname,
-                    &mut current_type,
-                    &qtype,
-                )
-                .await
-                {
-                    Some(record) => return Ok(record),
-                    None => {}
-                }
-            }
-            Err(e) => {
-                tracing::info!("Couldn't find a valid entry in the cache, error:\n{}", e);
-            }
-        };
-
-        // Query the server
-        let server = (current_ns, 53);
-        let response = lookup(&currently_quering, current_type, server).await?;
-        // We are searching for a dns server
-        if !search_for_qname {
-            if let Some(record) = response.get_random_a_rec() {
-                current_ns = record.register_record(&db_pool).await?;
-                // We found a new dns server to query,
-                // so we resume querying for the qname
-                currently_quering = qname.to_string();
-                current_type = qtype;
-                search_for_qname = true;
-                continue;
-            }
-        }
+    storage: &dyn Storage,
+    upstream: Upstream<'_>,
+) -> QuestionOutcome {
+    tracing::info!("Received query: {}", question);
 
-        // Entries in the answer section, and no errors, we found the answer.
-        if !response.answers.is_empty() && response.header.rescode == ResultCode::NOERROR {
-            let record = response.get_random_a_rec().unwrap();
-            let _ = record.register_record(&db_pool).await?;
-            return Ok(response);
-        }
+    // The resolution failed a moment ago, there is no point in trying again
+    if upstream
+        .health
+        .cached_servfail(&question.qname, question.qtype)
+    {
+        tracing::info!("Answering {} from the SERVFAIL cache", question.qname);
+        return QuestionOutcome::Failed;
+    }
 
-        //`NXDOMAIN` reply, which is the authoritative name servers
-        // way of telling us that the name doesn't exist.
-        if response.header.rescode == ResultCode::NXDOMAIN {
-            return Ok(response);
+    // The whole resolution needs to complete before the deadline
+    let result = timeout(
+        upstream.remaining(upstream.policy.query_deadline),
+        inquiring(
+            &question.qname,
+            question.qtype,
+            root_addr,
+            storage,
+            upstream,
+        ),
+    )
+    .await;
+    let outcome = match result {
+        Ok(Ok(result)) if result.header.rescode != ResultCode::SERVFAIL => {
+            return QuestionOutcome::Resolved(result);
         }
-
-        // Try to find a new nameserver based on NS and a corresponding A
-        // record in the `Additional section`. If this succeeds, we can switch name server
-        // and retry the loop.
-        if let Some(record) = response.get_resolved_ns(&currently_quering) {
-            current_ns = record.register_record(&db_pool).await?;
-            continue;
+        Ok(Ok(result)) => QuestionOutcome::Resolved(result),
+        Ok(Err(_)) if !upstream.expired() => QuestionOutcome::Failed,
+        // Cut short by the deadline, or the attempts stopped at it
+        _ => {
+            tracing::warn!("The resolution of {} exceeded the deadline", question.qname);
+            QuestionOutcome::Late
         }
-
-        // We found no useful resources in the `Additional section`,
-        // so we stop the search for qname and we search for the ip of ns, we
-        // will resume the query for qname once we resolve this one.
-        // If no NS records exist, we'll go with what the last server told us.
-        currently_quering = match response.get_unresolved_ns(&currently_quering) {
-            Some(x) => x.to_string(),
-            None => return Ok(response),
-        };
-        current_type = QueryType::A;
-        search_for_qname = false;
-        current_ns = root_addr;
-    }
+    };
+    // Every failure is remembered
+    upstream
+        .health
+        .cache_servfail(&question.qname, question.qtype);
+    outcome
 }
 
 /// # `cached_compose_response`
 ///
 /// `query_handler`'s helper, composes a response packet give a specific request, obtains data only
-/// from the cache.
+/// from the cache, a disabled cache can't answer anything.
+/// The records given for a client subnet only answer the clients in the subnet of `upstream`.
 /// TODO: test
-pub async fn cached_compose_response(request: &mut Packet, db_pool: &SqlitePool) -> Packet {
+pub async fn cached_compose_response(
+    request: &mut Packet,
+    storage: &dyn Storage,
+    upstream: Upstream<'_>,
+) -> Packet {
+    if !upstream.use_cache {
+        let mut r = Packet::new();
+        r.add_info(request.header.id, false, true, true, ResultCode::SERVFAIL);
+        return r;
+    }
     if let Some(question) = request.questions.pop() {
-        tracing::info!("Received query: {:?}", question);
+        tracing::info!("Received query: {}", question);
         tracing::info!("Searching the cache database for {}.", &question.qname);
-        let res = sqlx::query_as::<_, CachedRecord>(r#"SELECT id, address, host, priority, domain, expiration_date, ttl, record_type FROM entries WHERE (domain = $1) LIMIT 1"#)
-                .bind(&question.qname)
-                .fetch_all(db_pool)
+        let client = upstream.client_subnet.map(|subnet| subnet.addr);
+        let res = storage
+            .cached(&question.qname, Some(question.qtype), client)
             .await;
 
         match res {
             Ok(mut vector) => {
+                // The records of the client subnet are more accurate
+                let scoped = vector.iter().any(|cr| cr.ecs_network.is_some());
+                vector.retain(|cr| cr.ecs_network.is_some() == scoped);
+                // Every address cached for the name, for the clients to pick from
+                let mut response = Packet::new();
                 while let Some(cr) = vector.pop() {
-                    if cr.is_valid() {
+                    if cr.is_valid(upstream.clock) {
                         // record is valid
                         tracing::info!("Found valid record for {} in the cache.", &cr.domain,);
-                        let mut response = Packet::new();
-                        match response.add_cr_to_answers(&cr) {
-                            Ok(_) => {
-                                response.add_info(
-                                    request.header.id,
-                                    false,
-                                    true,
-                                    true,
-                                    response.header.rescode,
-                                );
-                                return response;
-                            }
-                            Err(e) => {
-                                tracing::error!("Incorrect data has been found in the cache database, it's necessary a debug, the server is still capable of responding to requests from the clients without the cache, but the cache is unreliable, wrong data may be served with this configuration, consider disabling the cache database with `-c` flag. Error:\n{}", e);
-                                let mut r = Packet::new();
-                                r.add_info(
-                                    request.header.id,
-                                    false,
-                                    true,
-                                    true,
-                                    ResultCode::SERVFAIL,
-                                );
-                                return r;
-                            }
+                        if let Err(e) = response.add_cr_to_answers(&cr) {
+                            tracing::error!("Incorrect data has been found in the cache database, it's necessary a debug, the server is still capable of responding to requests from the clients without the cache, but the cache is unreliable, wrong data may be served with this configuration, consider disabling the cache database with `-c` flag. Error:\n{}", e);
+                            let mut r = Packet::new();
+                            r.add_info(request.header.id, false, true, true, ResultCode::SERVFAIL);
+                            return r;
                         }
                     } else {
-                        match cr.delete_from_db(db_pool).await {
+                        match cr.delete_from_db(storage).await {
                             Ok(_) => {
                                 tracing::info!(
                                     "Removed expired record for {} from the cache.",
@@ -352,11 +211,23 @@ pub async fn cached_compose_response(request: &mut Packet, db_pool: &SqlitePool)
                                 tracing::error!("The database has failed to cancel an entry, it's necessary a debug, the server is still capable of responding to requests from the clients without the cache, but the cache is unreliable, wrong data may be served with this configuration, consider disabling the cache database with `-c` flag. Error:\n{}", e);
                             }
                         }
-                        let mut r = Packet::new();
-                        r.add_info(request.header.id, false, true, true, ResultCode::SERVFAIL);
-                        return r;
                     };
                 }
+                if !response.answers.is_empty() {
+                    // The same record may have been cached more than once
+                    response.answers.sort();
+                    response.answers.dedup();
+                    // The bounds may have changed since the records were cached
+                    upstream.ttl_bounds.apply(&mut response);
+                    response.add_info(
+                        request.header.id,
+                        false,
+                        true,
+                        true,
+                        response.header.rescode,
+                    );
+                    return response;
+                }
                 let mut r = Packet::new();
                 r.add_info(request.header.id, false, true, true, ResultCode::SERVFAIL);
                 return r;
@@ -374,3 +245,153 @@ pub async fn cached_compose_response(request: &mut Packet, db_pool: &SqlitePool)
     r.add_info(request.header.id, false, true, true, ResultCode::FORMERR);
     r
 }
+
+/// # `rewrite_response`
+///
+/// `query_handler`'s helper, answers the request with a CNAME pointing to `target`
+/// followed by the records of `target`, obtained as for any other query.
+/// If `target` can't be resolved the failure is returned as it is, without the CNAME.
+pub async fn rewrite_response(
+    request: &Packet,
+    target: &str,
+    root_addr: Ipv4Addr,
+    storage: &dyn Storage,
+    upstream: Upstream<'_>,
+) -> Packet {
+    let question = match request.questions.first() {
+        Some(q) => q.clone(),
+        None => {
+            let mut r = Packet::new();
+            r.add_info(request.header.id, false, true, true, ResultCode::FORMERR);
+            return r;
+        }
+    };
+    let mut rewritten = request.clone();
+    rewritten.questions = vec![Question::new(target.to_string(), question.qtype)];
+    let mut response = if question.qtype == QueryType::CNAME {
+        let mut r = Packet::new();
+        r.add_info(
+            request.header.id,
+            request.header.recursion_desired,
+            true,
+            true,
+            ResultCode::NOERROR,
+        );
+        r
+    } else if !request.header.recursion_desired {
+        cached_compose_response(&mut rewritten, storage, upstream).await
+    } else {
+        compose_response(&mut rewritten, root_addr, storage, upstream).await
+    };
+    response.questions = vec![question.clone()];
+    if !matches!(
+        response.header.rescode,
+        ResultCode::NOERROR | ResultCode::NXDOMAIN
+    ) {
+        return response;
+    }
+    response.answers.insert(
+        0,
+        Record::CNAME {
+            domain: question.qname,
+            host: target.into(),
+            ttl: REWRITE_TTL,
+        },
+    );
+    response
+}
+
+/// # `is_root_or_tld`
+///
+/// Whether `qname` is the root or a top level domain, a name of a single label.
+pub fn is_root_or_tld(qname: &str) -> bool {
+    !qname.trim_end_matches('.').contains('.')
+}
+
+/// # `tld_response`
+///
+/// `query_handler`'s helper, answers a query for the root or a top level domain
+/// without resolving it: with the records found in the cache, if `policy` allows it,
+/// otherwise refusing it.
+pub async fn tld_response(
+    request: &mut Packet,
+    policy: TldPolicy,
+    storage: &dyn Storage,
+    upstream: Upstream<'_>,
+) -> Packet {
+    let questions = request.questions.clone();
+    if policy == TldPolicy::Cached && upstream.use_cache {
+        let mut response = cached_compose_response(request, storage, upstream).await;
+        if !response.answers.is_empty() {
+            response.header.recursion_desired = request.header.recursion_desired;
+            response.questions = questions;
+            return response;
+        }
+    }
+    tracing::info!("Refused a query for the root or a top level domain");
+    let mut response = Packet::new();
+    response.add_info(
+        request.header.id,
+        request.header.recursion_desired,
+        true,
+        true,
+        ResultCode::REFUSED,
+    );
+    response.questions = questions;
+    response
+}
+
+/// # `any_response`
+///
+/// `query_handler`'s helper, answers a query of type ANY without resolving it:
+/// with the records of the name found in the cache, if `policy` allows it,
+/// otherwise with a synthesized HINFO record (RFC 8482 section 4.2).
+pub async fn any_response(
+    request: &Packet,
+    policy: AnyPolicy,
+    storage: &dyn Storage,
+    upstream: Upstream<'_>,
+) -> Packet {
+    let mut response = Packet::new();
+    response.add_info(
+        request.header.id,
+        request.header.recursion_desired,
+        true,
+        true,
+        ResultCode::NOERROR,
+    );
+    response.questions = request.questions.clone();
+    let Some(question) = request.questions.first() else {
+        response.header.rescode = ResultCode::FORMERR;
+        return response;
+    };
+    if policy == AnyPolicy::Cached && upstream.use_cache {
+        let client = upstream.client_subnet.map(|subnet| subnet.addr);
+        let res = storage.cached(&question.qname, None, client).await;
+        match res {
+            Ok(records) => {
+                let mut answers: Vec<Record> = records
+                    .iter()
+                    .filter(|cr| cr.is_valid(upstream.clock))
+                    .filter_map(|cr| cr.record_from_cache().ok())
+                    .collect();
+                // The same record may have been cached more than once
+                answers.sort();
+                answers.dedup();
+                response.answers = answers;
+            }
+            Err(e) => {
+                tracing::info!("Unable to read the cache database, error:\n{}", e);
+            }
+        }
+    }
+    if response.answers.is_empty() {
+        response.answers.push(Record::HINFO {
+            domain: question.qname.clone(),
+            cpu: "RFC8482".to_string(),
+            os: String::new(),
+            ttl: ANY_HINFO_TTL,
+        });
+    }
+    response
+}
@@ -0,0 +1,142 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use futures::future::BoxFuture;
+
+use crate::structs::{header::ResultCode, packet::Packet, questions_and_records::Question};
+
+use super::{Middleware, Next, QueryContext};
+
+/// # `QueryInfo`
+///
+/// A client query as the hooks see it.
+#[derive(Debug, Clone)]
+pub struct QueryInfo {
+    pub client: SocketAddr,
+    pub id: u16,
+    pub question: Question,
+    pub recursion_desired: bool,
+}
+
+/// # `Verdict`
+///
+/// What a query hook decides about a query.
+#[derive(Debug, Clone)]
+pub enum Verdict {
+    /// The query is answered as usual.
+    Continue,
+    /// The query is refused.
+    Refuse,
+    /// The query is answered with the packet, whose id and question are the ones
+    /// of the query.
+    Answer(Packet),
+}
+
+/// # `Resolution`
+///
+/// How a client query has been answered, for the response hooks.
+#[derive(Debug, Clone)]
+pub struct Resolution {
+    pub query: QueryInfo,
+    /// Time since the query arrived.
+    pub elapsed: Duration,
+    /// Whether the answer came from the cache.
+    pub cache_hit: bool,
+    /// Whether the query has been answered as blocked.
+    pub blocked: bool,
+    /// Queries sent to other name servers.
+    pub round_trips: usize,
+    /// The name servers contacted, in the order they have been contacted first.
+    pub nameservers: Vec<IpAddr>,
+}
+
+/// # `QueryHook`
+///
+/// Called with every client query before it's answered.
+pub type QueryHook = Arc<dyn Fn(QueryInfo) -> BoxFuture<'static, Verdict> + Send + Sync>;
+
+/// # `ResponseHook`
+///
+/// Called with every response to a client query, returns the response sent.
+pub type ResponseHook = Arc<dyn Fn(Resolution, Packet) -> BoxFuture<'static, Packet> + Send + Sync>;
+
+/// # `query_info`
+///
+/// The query of `ctx` as the hooks see it, `None` if it isn't a standard query.
+fn query_info(ctx: &QueryContext<'_>) -> Option<QueryInfo> {
+    if !ctx.is_query() {
+        return None;
+    }
+    Some(QueryInfo {
+        client: ctx.src,
+        id: ctx.request.header.id,
+        question: ctx.request.questions[0].clone(),
+        recursion_desired: ctx.request.header.recursion_desired,
+    })
+}
+
+/// # `BeforeResolution`
+///
+/// The middleware running a `QueryHook`.
+pub struct BeforeResolution(pub QueryHook);
+
+impl Middleware for BeforeResolution {
+    fn handle<'a>(&'a self, ctx: QueryContext<'a>, next: Next<'a>) -> BoxFuture<'a, Packet> {
+        Box::pin(async move {
+            let Some(query) = query_info(&ctx) else {
+                return next.run(ctx).await;
+            };
+            match (self.0)(query).await {
+                Verdict::Continue => next.run(ctx).await,
+                Verdict::Refuse => {
+                    tracing::info!("A query hook refused a query from {}", ctx.src);
+                    let mut response = Packet::new();
+                    response.add_info(
+                        ctx.request.header.id,
+                        ctx.request.header.recursion_desired,
+                        ctx.recursion_allowed,
+                        true,
+                        ResultCode::REFUSED,
+                    );
+                    response.questions = ctx.request.questions;
+                    response
+                }
+                Verdict::Answer(mut answer) => {
+                    answer.header.id = ctx.request.header.id;
+                    answer.header.response = true;
+                    answer.questions = ctx.request.questions;
+                    answer
+                }
+            }
+        })
+    }
+}
+
+/// # `AfterResolution`
+///
+/// The middleware running a `ResponseHook`.
+pub struct AfterResolution(pub ResponseHook);
+
+impl Middleware for AfterResolution {
+    fn handle<'a>(&'a self, ctx: QueryContext<'a>, next: Next<'a>) -> BoxFuture<'a, Packet> {
+        Box::pin(async move {
+            let Some(query) = query_info(&ctx) else {
+                return next.run(ctx).await;
+            };
+            let (trace, started) = (ctx.trace, ctx.started);
+            let response = next.run(ctx).await;
+            let resolution = Resolution {
+                query,
+                elapsed: started.elapsed(),
+                cache_hit: trace.cache_hit(),
+                blocked: trace.blocked(),
+                round_trips: trace.round_trips(),
+                nameservers: trace.nameservers(),
+            };
+            (self.0)(resolution, response).await
+        })
+    }
+}
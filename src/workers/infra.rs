@@ -0,0 +1,235 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Longest time the address of a name server is remembered for, regardless of its TTL.
+const MAX_INFRA_TTL: Duration = Duration::from_secs(86400);
+/// Time what is known of a name server is remembered for, since it was last learnt.
+const SERVER_TTL: Duration = Duration::from_secs(900);
+/// Round trip time assumed for the servers never heard from, low enough
+/// for them to be tried before the slow ones.
+const UNKNOWN_RTT: Duration = Duration::from_millis(376);
+/// Longest round trip time remembered, the servers that don't respond get there quickly.
+const MAX_RTT: Duration = Duration::from_secs(120);
+/// Time a name server is considered lame for a zone for.
+const LAME_TTL: Duration = Duration::from_secs(900);
+
+/// # `ServerInfo`
+///
+/// What is known of a name server, learnt from the queries sent to it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ServerInfo {
+    /// Smoothed round trip time, `None` until the server responds or fails to.
+    pub rtt: Option<Duration>,
+    /// Whether the server understands EDNS (RFC 6891), `None` until a query with
+    /// an OPT record tells.
+    pub edns: Option<bool>,
+    /// Whether the server truncated a response, it's queried over TCP.
+    pub tcp_required: bool,
+}
+
+/// # `InfraCache`
+///
+/// Addresses of the name servers met while resolving, by host name.
+/// Referrals often name the same servers, knowing their addresses spares us
+/// a resolution from the root when the referral doesn't carry glue.
+/// What is known of the servers, by address, `ServerInfo`, and the zones they
+/// are lame for decide which one of a delegation is queried and how.
+#[derive(Debug, Default)]
+pub struct InfraCache {
+    hosts: Mutex<HashMap<String, (Vec<IpAddr>, Instant)>>,
+    servers: Mutex<HashMap<IpAddr, (ServerInfo, Instant)>>,
+    lame: Mutex<HashMap<(IpAddr, String), Instant>>,
+}
+
+impl InfraCache {
+    pub fn new() -> Self {
+        InfraCache::default()
+    }
+
+    /// # `get`
+    ///
+    /// Returns the addresses of `host`, if they are known and not expired.
+    pub fn get(&self, host: &str) -> Option<Vec<IpAddr>> {
+        let mut hosts = self.hosts.lock().unwrap();
+        let key = host.to_lowercase();
+        match hosts.get(&key) {
+            Some((addrs, expiration)) if Instant::now() < *expiration => Some(addrs.clone()),
+            Some(_) => {
+                hosts.remove(&key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// # `insert`
+    ///
+    /// Remembers the addresses of `host` for `ttl` seconds, the expired entries are discarded.
+    pub fn insert(&self, host: &str, addrs: Vec<IpAddr>, ttl: u32) {
+        if addrs.is_empty() || ttl == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let ttl = Duration::from_secs(ttl as u64).min(MAX_INFRA_TTL);
+        let mut hosts = self.hosts.lock().unwrap();
+        hosts.retain(|_, (_, expiration)| now < *expiration);
+        hosts.insert(host.to_lowercase(), (addrs, now + ttl));
+    }
+
+    /// # `entries`
+    ///
+    /// The hosts known, with their addresses and the time they are still valid for.
+    pub fn entries(&self) -> Vec<(String, Vec<IpAddr>, Duration)> {
+        let now = Instant::now();
+        let hosts = self.hosts.lock().unwrap();
+        let mut entries: Vec<_> = hosts
+            .iter()
+            .filter(|(_, (_, expiration))| now < *expiration)
+            .map(|(host, (addrs, expiration))| (host.clone(), addrs.clone(), *expiration - now))
+            .collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries
+    }
+
+    /// # `server`
+    ///
+    /// What is known of `server`, nothing if it's been forgotten.
+    pub fn server(&self, server: IpAddr) -> ServerInfo {
+        let servers = self.servers.lock().unwrap();
+        match servers.get(&server) {
+            Some((info, expiration)) if Instant::now() < *expiration => *info,
+            _ => ServerInfo::default(),
+        }
+    }
+
+    /// # `servers`
+    ///
+    /// The servers known, by address, with what is known of them.
+    pub fn servers(&self) -> Vec<(IpAddr, ServerInfo)> {
+        let now = Instant::now();
+        let servers = self.servers.lock().unwrap();
+        let mut entries: Vec<_> = servers
+            .iter()
+            .filter(|(_, (_, expiration))| now < *expiration)
+            .map(|(server, (info, _))| (*server, *info))
+            .collect();
+        entries.sort_by_key(|(server, _)| *server);
+        entries
+    }
+
+    /// # `rank`
+    ///
+    /// Sorts `servers` in the order they should be queried: the fastest first,
+    /// the ones lame for `zone`, if any, last, the ones never heard from before the slow ones,
+    /// IPv6 before IPv4 when they are as fast.
+    pub fn rank(&self, servers: &mut [IpAddr], zone: Option<&str>) {
+        servers.sort_by_cached_key(|server| {
+            let lame = zone.is_some_and(|zone| self.is_lame(*server, zone));
+            let rtt = self.server(*server).rtt.unwrap_or(UNKNOWN_RTT);
+            (lame, rtt, server.is_ipv4())
+        });
+    }
+
+    /// # `record_rtt`
+    ///
+    /// `server` responded after `elapsed`, which is averaged into its round trip time.
+    pub fn record_rtt(&self, server: IpAddr, elapsed: Duration) {
+        self.update(server, |info| {
+            // Smoothed as TCP does, RFC 6298
+            info.rtt = Some(match info.rtt {
+                Some(rtt) => (rtt * 7 + elapsed) / 8,
+                None => elapsed,
+            });
+        });
+    }
+
+    /// # `record_timeout`
+    ///
+    /// `server` didn't respond, its round trip time is doubled.
+    pub fn record_timeout(&self, server: IpAddr) {
+        self.update(server, |info| {
+            info.rtt = Some((info.rtt.unwrap_or(UNKNOWN_RTT) * 2).min(MAX_RTT));
+        });
+    }
+
+    /// # `set_edns`
+    ///
+    /// Remembers whether `server` understands EDNS.
+    pub fn set_edns(&self, server: IpAddr, edns: bool) {
+        self.update(server, |info| info.edns = Some(edns));
+    }
+
+    /// # `set_tcp_required`
+    ///
+    /// `server` truncated a response, the following queries are sent over TCP.
+    pub fn set_tcp_required(&self, server: IpAddr) {
+        self.update(server, |info| info.tcp_required = true);
+    }
+
+    /// # `mark_lame`
+    ///
+    /// `server` has been delegated `zone` but doesn't serve it: it refused a query,
+    /// answered without authority or referred us to a zone that isn't below `zone`.
+    /// It's queried last for the names of `zone` for `LAME_TTL`, the expired entries are discarded.
+    pub fn mark_lame(&self, server: IpAddr, zone: &str) {
+        tracing::info!("{} is lame for {}.", server, display_zone(zone));
+        let now = Instant::now();
+        let mut lame = self.lame.lock().unwrap();
+        lame.retain(|_, expiration| now < *expiration);
+        lame.insert((server, zone.to_lowercase()), now + LAME_TTL);
+    }
+
+    /// # `is_lame`
+    ///
+    /// Returns true if `server` has recently been found lame for `zone`.
+    pub fn is_lame(&self, server: IpAddr, zone: &str) -> bool {
+        let lame = self.lame.lock().unwrap();
+        lame.get(&(server, zone.to_lowercase()))
+            .is_some_and(|expiration| Instant::now() < *expiration)
+    }
+
+    /// # `lame_zones`
+    ///
+    /// The zones `server` is lame for, the root as `.`.
+    pub fn lame_zones(&self, server: IpAddr) -> Vec<String> {
+        let now = Instant::now();
+        let lame = self.lame.lock().unwrap();
+        let mut zones: Vec<String> = lame
+            .iter()
+            .filter(|((lame, _), expiration)| *lame == server && now < **expiration)
+            .map(|((_, zone), _)| display_zone(zone).to_string())
+            .collect();
+        zones.sort();
+        zones
+    }
+
+    /// # `update`
+    ///
+    /// Applies `f` to what is known of `server`, which is remembered for `SERVER_TTL` again,
+    /// the expired entries are discarded.
+    fn update(&self, server: IpAddr, f: impl FnOnce(&mut ServerInfo)) {
+        let now = Instant::now();
+        let mut servers = self.servers.lock().unwrap();
+        servers.retain(|_, (_, expiration)| now < *expiration);
+        let (info, expiration) = servers
+            .entry(server)
+            .or_insert_with(|| (ServerInfo::default(), now));
+        f(info);
+        *expiration = now + SERVER_TTL;
+    }
+}
+
+/// # `display_zone`
+///
+/// `zone` as it's shown, the root, the empty name, as `.`.
+fn display_zone(zone: &str) -> &str {
+    if zone.is_empty() {
+        "."
+    } else {
+        zone
+    }
+}
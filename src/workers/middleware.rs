@@ -0,0 +1,325 @@
+use std::{
+    net::SocketAddr,
+    sync::{atomic::Ordering, Arc},
+    time::Instant,
+};
+
+use chrono::Local;
+use futures::future::BoxFuture;
+use serde::Deserialize;
+
+use crate::{
+    configuration::Settings,
+    domainpolicy::{DomainAction, DomainPolicy},
+    overrides::RuleAction,
+    querylog::QueryLogEntry,
+    structs::{
+        auxiliaries::{CResult, DnsError},
+        header::OpCode,
+        packet::Packet,
+    },
+};
+
+use super::{answer, helpers::rewrite_response, Policies, ServerState, Upstream, UpstreamTrace};
+
+/// # `QueryContext`
+///
+/// A client query on its way through the pipeline, along with what has been decided
+/// about it before: the middlewares may change any of it before passing it on.
+pub struct QueryContext<'a> {
+    pub request: Packet,
+    pub src: SocketAddr,
+    pub opcode: OpCode,
+    /// Whether the client may ask for recursion.
+    pub recursion_allowed: bool,
+    pub state: &'a ServerState,
+    /// The policies in force when the query arrived.
+    pub policies: &'a Policies,
+    pub upstream: Upstream<'a>,
+    pub trace: &'a UpstreamTrace,
+    /// When the query arrived.
+    pub started: Instant,
+    /// The policy of the longest suffix of the name asked for, if any.
+    pub domain_policy: Option<&'a DomainPolicy>,
+    /// The name of the key that signed the query, NOTIFY messages need one.
+    pub key_name: Option<&'a str>,
+}
+
+impl QueryContext<'_> {
+    /// # `is_query`
+    ///
+    /// Returns true if the message is a standard query with a question.
+    pub fn is_query(&self) -> bool {
+        self.opcode == OpCode::QUERY && !self.request.questions.is_empty()
+    }
+}
+
+/// # `Middleware`
+///
+/// A step of the handling of the client queries: it may answer a query itself,
+/// change it before passing it on to `next` or change the response `next` gives.
+pub trait Middleware: Send + Sync {
+    fn handle<'a>(&'a self, ctx: QueryContext<'a>, next: Next<'a>) -> BoxFuture<'a, Packet>;
+}
+
+/// # `Next`
+///
+/// The middlewares that follow in the pipeline, the local answers and the resolution
+/// come after the last one.
+pub struct Next<'a> {
+    middlewares: &'a [Arc<dyn Middleware>],
+}
+
+impl<'a> Next<'a> {
+    /// # `run`
+    ///
+    /// Passes `ctx` on to the following middleware and returns its response.
+    pub fn run(self, ctx: QueryContext<'a>) -> BoxFuture<'a, Packet> {
+        match self.middlewares.split_first() {
+            Some((middleware, middlewares)) => middleware.handle(ctx, Next { middlewares }),
+            None => Box::pin(answer(ctx)),
+        }
+    }
+}
+
+/// # `MiddlewareKind`
+///
+/// The middlewares of the server, named in the configuration in the order
+/// the queries go through them.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MiddlewareKind {
+    /// Notes the queries answered in the query log, the statistics and the live stream.
+    Querylog,
+    /// Rotates the addresses of the answers, if enabled.
+    Rotate,
+    /// Answers the names of the blocklist and the ones blocked by a domain policy.
+    Blocklist,
+    /// Rewrites the names of the search engines to their safe search versions.
+    Safesearch,
+    /// Runs the hooks of the Lua script of the configuration, see `dns::scripting`.
+    Script,
+}
+
+impl MiddlewareKind {
+    /// # `middleware`
+    ///
+    /// The middleware of the kind, fails if the script can't be loaded.
+    fn middleware(self, settings: &Settings) -> CResult<Arc<dyn Middleware>> {
+        let middleware: Arc<dyn Middleware> = match self {
+            MiddlewareKind::Querylog => Arc::new(QueryLogging),
+            MiddlewareKind::Rotate => Arc::new(Rotation),
+            MiddlewareKind::Blocklist => Arc::new(Blocking),
+            MiddlewareKind::Safesearch => Arc::new(SafeSearchRewrite),
+            #[cfg(feature = "scripting")]
+            MiddlewareKind::Script => {
+                let path = settings
+                    .get_script_path()
+                    .ok_or_else(|| DnsError::Config("No script has been configured".into()))?;
+                Arc::new(crate::scripting::Script::load(path)?)
+            }
+            #[cfg(not(feature = "scripting"))]
+            MiddlewareKind::Script => {
+                let _ = settings;
+                return Err(DnsError::Config(
+                    "The server has been built without the scripting feature".into(),
+                ));
+            }
+        };
+        Ok(middleware)
+    }
+}
+
+/// # `Pipeline`
+///
+/// The middlewares every client query goes through, in order, before being answered
+/// locally or resolved.
+#[derive(Clone, Default)]
+pub struct Pipeline {
+    middlewares: Vec<Arc<dyn Middleware>>,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Pipeline::default()
+    }
+
+    /// # `from_settings`
+    ///
+    /// The middlewares named in the settings, the ones missing are disabled;
+    /// fails if the script can't be loaded.
+    pub fn from_settings(settings: &Settings) -> CResult<Self> {
+        settings
+            .get_middlewares()
+            .iter()
+            .try_fold(Pipeline::new(), |pipeline, kind| {
+                Ok(pipeline.with(kind.middleware(settings)?))
+            })
+    }
+
+    /// # `with`
+    ///
+    /// Appends `middleware`, the queries go through it after the ones already added.
+    pub fn with(mut self, middleware: Arc<dyn Middleware>) -> Self {
+        self.middlewares.push(middleware);
+        self
+    }
+
+    /// # `run`
+    ///
+    /// The response to the query of `ctx`.
+    pub fn run<'a>(&'a self, ctx: QueryContext<'a>) -> BoxFuture<'a, Packet> {
+        Next {
+            middlewares: &self.middlewares,
+        }
+        .run(ctx)
+    }
+}
+
+/// # `QueryLogging`
+///
+/// Notes every query answered, with its response, in the query log, the statistics
+/// and the live stream of the dashboard.
+struct QueryLogging;
+
+impl Middleware for QueryLogging {
+    fn handle<'a>(&'a self, ctx: QueryContext<'a>, next: Next<'a>) -> BoxFuture<'a, Packet> {
+        Box::pin(async move {
+            // The resolution may consume the question
+            let question = ctx.request.questions.first().cloned();
+            let (state, src, trace, started) = (ctx.state, ctx.src, ctx.trace, ctx.started);
+            let response = next.run(ctx).await;
+            let Some(question) = question else {
+                return response;
+            };
+            if let Some(stats) = &state.stats {
+                stats.record(
+                    &question.qname,
+                    &format!("{:?}", question.qtype),
+                    src.ip(),
+                    trace.blocked(),
+                );
+            }
+            let streamed = state.query_stream.receiver_count() > 0;
+            if state.query_log.is_some() || streamed {
+                let entry = QueryLogEntry {
+                    timestamp: Local::now(),
+                    client: src.ip(),
+                    client_name: state.clients.name_of(src.ip()),
+                    qname: question.qname.to_string(),
+                    qtype: format!("{:?}", question.qtype),
+                    rcode: format!("{:?}", response.header.rescode),
+                    answers: response.answers.len(),
+                    latency_ms: started.elapsed().as_secs_f64() * 1000.0,
+                    cache_hit: trace.cache_hit(),
+                };
+                if streamed {
+                    // Nobody may be listening anymore, that's fine
+                    let _ = state.query_stream.send(entry.clone());
+                }
+                if let Some(query_log) = &state.query_log {
+                    query_log.log(entry);
+                }
+            }
+            response
+        })
+    }
+}
+
+/// # `Rotation`
+///
+/// Round-robin over the addresses of the responses, spreading the clients over them.
+struct Rotation;
+
+impl Middleware for Rotation {
+    fn handle<'a>(&'a self, ctx: QueryContext<'a>, next: Next<'a>) -> BoxFuture<'a, Packet> {
+        Box::pin(async move {
+            let (state, rotate) = (ctx.state, ctx.policies.rotate);
+            let mut response = next.run(ctx).await;
+            if rotate {
+                response.rotate_answers(state.rotation.fetch_add(1, Ordering::Relaxed));
+            }
+            response
+        })
+    }
+}
+
+/// # `Blocking`
+///
+/// Answers the names of the blocklist, the ones blocked by a rule of the API
+/// and the ones of the domains whose policy blocks them, before any resolution happens.
+struct Blocking;
+
+impl Middleware for Blocking {
+    fn handle<'a>(&'a self, ctx: QueryContext<'a>, next: Next<'a>) -> BoxFuture<'a, Packet> {
+        Box::pin(async move {
+            if !ctx.is_query() {
+                return next.run(ctx).await;
+            }
+            let blocklist = &ctx.policies.blocklist;
+            let qname = &ctx.request.questions[0].qname;
+            // The rules added through the API take precedence over the blocklist
+            let blocked = match ctx.state.overrides.rule_for(qname) {
+                Some(RuleAction::Allow) => return next.run(ctx).await,
+                Some(RuleAction::Block) => true,
+                None => match blocklist.is_blocked(qname).await {
+                    Ok(blocked) => blocked,
+                    Err(e) => {
+                        tracing::warn!("Unable to check the blocklist: {}", e);
+                        false
+                    }
+                },
+            };
+            if blocked {
+                tracing::info!("Blocked a query from {}", ctx.src);
+            } else if let Some(policy) = ctx
+                .domain_policy
+                .filter(|policy| policy.action == DomainAction::Block)
+            {
+                tracing::info!(
+                    "Blocked a query from {} by the policy of {}",
+                    ctx.src,
+                    policy.suffix
+                );
+            } else {
+                return next.run(ctx).await;
+            }
+            ctx.trace.record_blocked();
+            blocklist.blocked_response(&ctx.request, ctx.src.ip())
+        })
+    }
+}
+
+/// # `SafeSearchRewrite`
+///
+/// Answers the names of the search engines with the address of their safe search
+/// version, behind a CNAME record.
+struct SafeSearchRewrite;
+
+impl Middleware for SafeSearchRewrite {
+    fn handle<'a>(&'a self, ctx: QueryContext<'a>, next: Next<'a>) -> BoxFuture<'a, Packet> {
+        Box::pin(async move {
+            // The clients denied recursion are refused further on
+            let target = ctx
+                .request
+                .questions
+                .first()
+                .filter(|_| ctx.opcode == OpCode::QUERY && ctx.recursion_allowed)
+                .and_then(|question| ctx.policies.safe_search.target(&question.qname));
+            match target {
+                Some(target) => {
+                    let resolver = &ctx.state.resolver;
+                    rewrite_response(
+                        &ctx.request,
+                        target,
+                        resolver.root_addr,
+                        resolver.storage.as_ref(),
+                        ctx.upstream,
+                    )
+                    .await
+                }
+                None => next.run(ctx).await,
+            }
+        })
+    }
+}
@@ -0,0 +1,94 @@
+use std::{net::Ipv4Addr, sync::Arc, time::Duration};
+
+use crate::structs::{
+    header::ResultCode,
+    packet::Packet,
+    questions_and_records::{QueryType, Question, Record},
+};
+
+use super::{helpers::compose_response, ServerState, UpstreamTrace};
+
+/// Name answered locally with the health of the server, for the probes
+/// that can only speak DNS.
+pub const HEALTH_CHECK_NAME: &str = "health.check";
+/// Pause between two attempts of the self-test.
+const SELF_TEST_RETRY: Duration = Duration::from_secs(5);
+
+/// # `is_health_check`
+///
+/// Returns true if `question` asks for the health of the server.
+pub fn is_health_check(question: &Question) -> bool {
+    question
+        .qname
+        .trim_end_matches('.')
+        .eq_ignore_ascii_case(HEALTH_CHECK_NAME)
+}
+
+/// # `health_check_response`
+///
+/// `query_handler`'s helper, answers `health.check.` with `127.0.0.1`
+/// if the server is ready, with SERVFAIL otherwise.
+pub fn health_check_response(request: &Packet, ready: bool) -> Packet {
+    let mut response = Packet::new();
+    response.add_info(
+        request.header.id,
+        request.header.recursion_desired,
+        true,
+        true,
+        if ready {
+            ResultCode::NOERROR
+        } else {
+            ResultCode::SERVFAIL
+        },
+    );
+    response.header.authoritative_answer = ready;
+    response.questions = request.questions.clone();
+    if let (true, Some(question)) = (ready, request.questions.first()) {
+        if question.qtype == QueryType::A {
+            response.answers.push(Record::A {
+                domain: question.qname.clone(),
+                addr: Ipv4Addr::LOCALHOST,
+                ttl: 0,
+            });
+        }
+    }
+    response
+}
+
+/// # `self_test`
+///
+/// Resolves `name` as a client query would be, every `SELF_TEST_RETRY`
+/// until it succeeds, then marks the server as ready.
+#[tracing::instrument("Running the self-test", skip(state))]
+pub async fn self_test(state: Arc<ServerState>, name: String) {
+    loop {
+        let trace = UpstreamTrace::new();
+        let Ok(mut request) = Packet::builder()
+            .recursion_desired(true)
+            .question(name.as_str(), QueryType::A)
+            .build()
+        else {
+            tracing::error!("{} can't be queried, the self-test is skipped", name);
+            return;
+        };
+        let response = compose_response(
+            &mut request,
+            state.resolver.root_addr,
+            state.resolver.storage.as_ref(),
+            state.upstream(&trace),
+        )
+        .await;
+        if response.header.rescode == ResultCode::NOERROR && !response.answers.is_empty() {
+            tracing::info!("Resolved {}, the server is ready", name);
+            state.set_ready(true);
+            return;
+        }
+        tracing::warn!(
+            "Unable to resolve {}: {:?}, trying again in {:?}",
+            name,
+            response.header.rescode,
+            SELF_TEST_RETRY
+        );
+        tokio::time::sleep(SELF_TEST_RETRY).await;
+    }
+}
@@ -0,0 +1,60 @@
+use std::{collections::HashSet, net::SocketAddr, sync::Mutex};
+
+use crate::structs::{packet::Packet, questions_and_records::QueryType};
+
+/// Identifies a client query: who sent it, its id and its first question.
+type QueryKey = (SocketAddr, u16, Option<(String, QueryType)>);
+
+/// # `Retransmissions`
+///
+/// The client queries being handled. The stub resolvers send a query again when
+/// they get no response within a second or two; the copies of a query still being
+/// handled are dropped, the response to the first one answers them all.
+#[derive(Debug, Default)]
+pub struct Retransmissions {
+    pending: Mutex<HashSet<QueryKey>>,
+}
+
+impl Retransmissions {
+    pub fn new() -> Self {
+        Retransmissions::default()
+    }
+
+    /// # `track`
+    ///
+    /// Notes that `request`, received from `src`, is being handled until the returned
+    /// guard is dropped; returns `None` if it's a retransmission of a query
+    /// that is still being handled.
+    pub fn track(&self, src: SocketAddr, request: &Packet) -> Option<Tracked<'_>> {
+        let question = request
+            .questions
+            .first()
+            .map(|question| (question.qname.to_lowercase(), question.qtype));
+        let key = (src, request.header.id, question);
+        if !self.pending.lock().unwrap().insert(key.clone()) {
+            return None;
+        }
+        Some(Tracked {
+            retransmissions: self,
+            key,
+        })
+    }
+}
+
+/// # `Tracked`
+///
+/// A client query being handled, forgotten once dropped.
+pub struct Tracked<'a> {
+    retransmissions: &'a Retransmissions,
+    key: QueryKey,
+}
+
+impl Drop for Tracked<'_> {
+    fn drop(&mut self) {
+        self.retransmissions
+            .pending
+            .lock()
+            .unwrap()
+            .remove(&self.key);
+    }
+}
@@ -0,0 +1,99 @@
+use std::{
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use crate::telemetry::{UPSTREAM_QUERIES, UPSTREAM_RTT};
+
+/// # `UpstreamTrace`
+///
+/// The queries sent to other name servers while resolving a single client query,
+/// the name servers resolved along the way included, and whether the answer
+/// came from the cache or the query has been blocked instead.
+#[derive(Debug, Default)]
+pub struct UpstreamTrace {
+    queries: Mutex<(usize, Vec<IpAddr>)>,
+    cache_hit: AtomicBool,
+    blocked: AtomicBool,
+}
+
+impl UpstreamTrace {
+    pub fn new() -> Self {
+        UpstreamTrace::default()
+    }
+
+    /// # `record`
+    ///
+    /// Takes note of a query sent to `server` that got a response, or gave up waiting,
+    /// after `elapsed`.
+    pub fn record(&self, server: IpAddr, elapsed: Duration, answered: bool) {
+        let outcome = if answered { "answered" } else { "failed" };
+        metrics::counter!(UPSTREAM_QUERIES, "outcome" => outcome).increment(1);
+        if answered {
+            metrics::histogram!(UPSTREAM_RTT).record(elapsed.as_secs_f64());
+        }
+        let mut queries = self.queries.lock().unwrap();
+        queries.0 += 1;
+        if !queries.1.contains(&server) {
+            queries.1.push(server);
+        }
+    }
+
+    /// # `record_cache_hit`
+    ///
+    /// Takes note that the answer has been found in the cache.
+    pub fn record_cache_hit(&self) {
+        self.cache_hit.store(true, Ordering::Relaxed);
+    }
+
+    /// # `cache_hit`
+    ///
+    /// Returns true if the answer has been found in the cache.
+    pub fn cache_hit(&self) -> bool {
+        self.cache_hit.load(Ordering::Relaxed)
+    }
+
+    /// # `record_blocked`
+    ///
+    /// Takes note that the query has been answered as blocked.
+    pub fn record_blocked(&self) {
+        self.blocked.store(true, Ordering::Relaxed);
+    }
+
+    /// # `blocked`
+    ///
+    /// Returns true if the query has been answered as blocked.
+    pub fn blocked(&self) -> bool {
+        self.blocked.load(Ordering::Relaxed)
+    }
+
+    /// # `round_trips`
+    ///
+    /// Number of queries sent.
+    pub fn round_trips(&self) -> usize {
+        self.queries.lock().unwrap().0
+    }
+
+    /// # `nameservers`
+    ///
+    /// The name servers contacted, in the order they have been contacted first.
+    pub fn nameservers(&self) -> Vec<IpAddr> {
+        self.queries.lock().unwrap().1.clone()
+    }
+
+    /// # `servers`
+    ///
+    /// The name servers contacted, in the order they have been contacted first,
+    /// separated by commas.
+    pub fn servers(&self) -> String {
+        self.nameservers()
+            .iter()
+            .map(|server| server.to_string())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
@@ -0,0 +1,381 @@
+use std::{cmp::Ordering, collections::HashMap, sync::RwLock};
+
+use crate::{
+    dnssec::canonical_cmp,
+    structs::{
+        header::ResultCode,
+        packet::Packet,
+        questions_and_records::{QueryType, Question, Record},
+    },
+};
+
+pub mod secondary;
+pub mod transfer;
+pub mod zonemd;
+
+/// # `Zone`
+///
+/// A zone served authoritatively, its content is kept in memory.
+#[derive(Debug, Clone)]
+pub struct Zone {
+    /// Name of the apex of the zone, lowercase and without the trailing dot.
+    pub name: String,
+    /// SOA record of the zone.
+    pub soa: Record,
+    /// Every record of the zone except for the SOA.
+    pub records: Vec<Record>,
+}
+
+impl Zone {
+    /// # `from_records`
+    ///
+    /// Builds a zone out of the records received from a zone transfer,
+    /// the first SOA found is used as the SOA of the zone.
+    /// The records of the types we don't parse, as TXT, are served as they were received.
+    pub fn from_records(name: &str, records: Vec<Record>) -> Option<Zone> {
+        let soa = records
+            .iter()
+            .find(|r| matches!(r, Record::SOA { .. }))?
+            .clone();
+        let records = records
+            .into_iter()
+            .filter(|r| !matches!(r, Record::SOA { .. }))
+            .collect();
+        Some(Zone {
+            name: name.to_lowercase(),
+            soa,
+            records,
+        })
+    }
+
+    /// # `serial`
+    ///
+    /// Serial number of the zone, taken from its SOA.
+    pub fn serial(&self) -> u32 {
+        match self.soa {
+            Record::SOA { serial, .. } => serial,
+            _ => 0,
+        }
+    }
+
+    /// # `contains`
+    ///
+    /// Returns true if `qname` is the apex of the zone or one of its descendants.
+    pub fn contains(&self, qname: &str) -> bool {
+        is_subdomain(qname, &self.name)
+    }
+
+    /// # `answer`
+    ///
+    /// Composes the authoritative response to `question`, `question.qname` needs to
+    /// be contained in the zone.
+    /// If the zone is signed the response carries the RRSIGs of the RRsets it contains
+    /// and the NSEC records that prove the denial of existence (RFC 4035 section 3.1).
+    pub fn answer(&self, question: &Question) -> Packet {
+        let mut response = Packet::new();
+        response.header.response = true;
+        response.header.authoritative_answer = true;
+        response.questions.push(question.clone());
+        let qname = question.qname.as_str();
+        let signed = self.is_signed();
+
+        // Names below a zone cut are delegated to someone else, we respond with a referral
+        if let Some(cut) = self.find_zone_cut(qname) {
+            response.header.authoritative_answer = false;
+            for ns in self.records_for(&cut, QueryType::NS) {
+                if let Record::NS { host, .. } = ns {
+                    response
+                        .resources
+                        .extend(self.records_for(host, QueryType::A).cloned());
+                    response
+                        .resources
+                        .extend(self.records_for(host, QueryType::AAAA).cloned());
+                }
+                response.authorities.push(ns.clone());
+            }
+            if signed {
+                // Either the DS of the child or the proof that it is unsigned
+                let ds: Vec<Record> = self.records_for(&cut, QueryType::DS).cloned().collect();
+                if ds.is_empty() {
+                    self.push_nsec(&mut response.authorities, &cut);
+                } else {
+                    response.authorities.extend(ds);
+                    response
+                        .authorities
+                        .extend(self.signatures_for(&cut, QueryType::DS).cloned());
+                }
+            }
+            return response;
+        }
+
+        if question.qtype == QueryType::SOA && qname.eq_ignore_ascii_case(&self.name) {
+            response.answers.push(self.soa.clone());
+            if signed {
+                response
+                    .answers
+                    .extend(self.signatures_for(&self.name, QueryType::SOA).cloned());
+            }
+            return response;
+        }
+
+        let mut answers: Vec<Record> = self.records_for(qname, question.qtype).cloned().collect();
+        if answers.is_empty() && question.qtype != QueryType::CNAME {
+            // A CNAME is followed once if its target belongs to the zone
+            if let Some(cname) = self.records_for(qname, QueryType::CNAME).next() {
+                answers.push(cname.clone());
+                if let Record::CNAME { host, .. } = cname {
+                    answers.extend(self.records_for(host, question.qtype).cloned());
+                }
+            }
+        }
+
+        if !answers.is_empty() {
+            if signed {
+                let mut rrsets: Vec<(&str, QueryType)> = Vec::new();
+                for record in &answers {
+                    let rrset = (record.get_domain(), record.get_qtype());
+                    if !rrsets.contains(&rrset) {
+                        rrsets.push(rrset);
+                    }
+                }
+                let signatures: Vec<Record> = rrsets
+                    .into_iter()
+                    .flat_map(|(name, qtype)| self.signatures_for(name, qtype).cloned())
+                    .collect();
+                answers.extend(signatures);
+            }
+            response.answers = answers;
+        } else {
+            let exists = self.name_exists(qname);
+            if !exists {
+                response.header.rescode = ResultCode::NXDOMAIN;
+            }
+            response.authorities.push(self.soa.clone());
+            if signed {
+                response
+                    .authorities
+                    .extend(self.signatures_for(&self.name, QueryType::SOA).cloned());
+                // NODATA is proved by the NSEC of the name, or the one covering it for
+                // empty non-terminals. NXDOMAIN needs to deny the wildcard as well.
+                self.push_nsec(&mut response.authorities, qname);
+                if !exists {
+                    let encloser = self.closest_encloser(qname);
+                    self.push_nsec(&mut response.authorities, &format!("*.{}", encloser));
+                }
+            }
+        }
+        response
+    }
+
+    /// # `is_signed`
+    ///
+    /// Returns true if the zone publishes its DNSKEYs.
+    pub fn is_signed(&self) -> bool {
+        self.records.iter().any(|r| {
+            r.get_domain().eq_ignore_ascii_case(&self.name) && r.get_qtype() == QueryType::DNSKEY
+        })
+    }
+
+    /// # `name_exists`
+    ///
+    /// The name exists if it owns a record or if it is an empty non-terminal.
+    fn name_exists(&self, name: &str) -> bool {
+        name.eq_ignore_ascii_case(&self.name)
+            || self
+                .records
+                .iter()
+                .any(|r| is_subdomain(r.get_domain(), name))
+    }
+
+    /// # `closest_encloser`
+    ///
+    /// Closest existing ancestor of `name`, at worst the apex.
+    fn closest_encloser<'a>(&'a self, name: &'a str) -> &'a str {
+        let mut name = name;
+        while name != self.name {
+            match name.split_once('.') {
+                Some((_, parent)) => name = parent,
+                None => return &self.name,
+            }
+            if self.name_exists(name) {
+                return name;
+            }
+        }
+        &self.name
+    }
+
+    /// # `signatures_for`
+    ///
+    /// Iterator over the RRSIGs covering the RRset owned by `name` with type `qtype`.
+    fn signatures_for<'a>(
+        &'a self,
+        name: &'a str,
+        qtype: QueryType,
+    ) -> impl Iterator<Item = &'a Record> {
+        self.records.iter().filter(move |r| match r {
+            Record::RRSIG {
+                domain,
+                type_covered,
+                ..
+            } => domain == name && *type_covered == qtype.to_num(),
+            _ => false,
+        })
+    }
+
+    /// # `push_nsec`
+    ///
+    /// Adds to `section` the NSEC owned by `name` or, if it doesn't exist, the one whose
+    /// interval covers it, together with its RRSIG. Records already present aren't repeated.
+    fn push_nsec(&self, section: &mut Vec<Record>, name: &str) {
+        let nsec = self.records.iter().find(|r| match r {
+            Record::NSEC {
+                domain,
+                next_domain,
+                ..
+            } => {
+                let after_owner = canonical_cmp(domain, name) != Ordering::Greater;
+                // The last NSEC of the chain points back to the apex
+                let before_next =
+                    canonical_cmp(name, next_domain) == Ordering::Less || next_domain == &self.name;
+                after_owner && before_next
+            }
+            _ => false,
+        });
+        if let Some(nsec) = nsec {
+            if section.contains(nsec) {
+                return;
+            }
+            section.push(nsec.clone());
+            section.extend(
+                self.signatures_for(nsec.get_domain(), QueryType::NSEC)
+                    .cloned(),
+            );
+        }
+    }
+
+    /// # `records_for`
+    ///
+    /// Iterator over the records of the zone owned by `name` with type `qtype`.
+    fn records_for<'a>(
+        &'a self,
+        name: &'a str,
+        qtype: QueryType,
+    ) -> impl Iterator<Item = &'a Record> {
+        self.records
+            .iter()
+            .filter(move |r| r.get_domain().eq_ignore_ascii_case(name) && r.get_qtype() == qtype)
+    }
+
+    /// # `find_zone_cut`
+    ///
+    /// Returns the closest name between the apex (excluded) and `qname` that owns NS records,
+    /// meaning that `qname` has been delegated to another server.
+    fn find_zone_cut(&self, qname: &str) -> Option<String> {
+        let mut name = qname;
+        while name != self.name && !name.is_empty() {
+            if self.records_for(name, QueryType::NS).next().is_some() {
+                return Some(name.to_string());
+            }
+            name = match name.split_once('.') {
+                Some((_, parent)) => parent,
+                None => "",
+            };
+        }
+        None
+    }
+}
+
+/// # `ZoneStore`
+///
+/// Collection of the zones served authoritatively, shared between the tasks
+/// that keep the zones up to date and the ones answering the queries.
+#[derive(Default)]
+pub struct ZoneStore {
+    zones: RwLock<HashMap<String, Zone>>,
+}
+
+impl ZoneStore {
+    pub fn new() -> Self {
+        ZoneStore {
+            zones: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// # `insert`
+    ///
+    /// Adds a zone to the store, replacing the previous version if present.
+    pub fn insert(&self, zone: Zone) {
+        let mut zones = self.zones.write().expect("Zone store lock poisoned");
+        zones.insert(zone.name.clone(), zone);
+    }
+
+    /// # `remove`
+    ///
+    /// Stops serving the zone named `name`.
+    pub fn remove(&self, name: &str) -> Option<Zone> {
+        let mut zones = self.zones.write().expect("Zone store lock poisoned");
+        zones.remove(name)
+    }
+
+    /// # `get`
+    ///
+    /// Returns a copy of the zone named `name`, if served.
+    pub fn get(&self, name: &str) -> Option<Zone> {
+        let zones = self.zones.read().expect("Zone store lock poisoned");
+        zones.get(name).cloned()
+    }
+
+    /// # `get_soa`
+    ///
+    /// Returns the SOA of the zone named `name`, if served.
+    pub fn get_soa(&self, name: &str) -> Option<Record> {
+        let zones = self.zones.read().expect("Zone store lock poisoned");
+        zones.get(name).map(|z| z.soa.clone())
+    }
+
+    /// # `zone_for`
+    ///
+    /// Returns a copy of the most specific zone containing `name`, if served.
+    pub fn zone_for(&self, name: &str) -> Option<Zone> {
+        let zones = self.zones.read().expect("Zone store lock poisoned");
+        zones
+            .values()
+            .filter(|z| z.contains(name))
+            .max_by_key(|z| z.name.len())
+            .cloned()
+    }
+
+    /// # `answer`
+    ///
+    /// Answers `question` from the most specific zone containing its name,
+    /// returns `None` if the name doesn't belong to any of our zones.
+    pub fn answer(&self, question: &Question) -> Option<Packet> {
+        let zones = self.zones.read().expect("Zone store lock poisoned");
+        zones
+            .values()
+            .filter(|z| z.contains(&question.qname))
+            .max_by_key(|z| z.name.len())
+            .map(|z| z.answer(question))
+    }
+}
+
+/// # `is_subdomain`
+///
+/// Returns true if `name` is equal to `parent` or is one of its descendants,
+/// ignoring the case.
+pub fn is_subdomain(name: &str, parent: &str) -> bool {
+    let (name, parent) = (name.as_bytes(), parent.as_bytes());
+    parent.is_empty()
+        || name.eq_ignore_ascii_case(parent)
+        || (name.len() > parent.len()
+            && name[name.len() - parent.len()..].eq_ignore_ascii_case(parent)
+            && name[name.len() - parent.len() - 1] == b'.')
+}
+
+/// # `serial_is_newer`
+///
+/// Compares two SOA serial numbers using serial number arithmetic (RFC 1982),
+/// returns true if `a` is more recent than `b`.
+pub fn serial_is_newer(a: u32, b: u32) -> bool {
+    a != b && (a.wrapping_sub(b) as i32) > 0
+}
@@ -0,0 +1,253 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use tokio::{
+    sync::broadcast::error::RecvError,
+    time::{sleep_until, Instant},
+};
+
+use crate::{
+    configuration::SecondaryZoneSettings,
+    dnssec::ZoneSigner,
+    notify::NotifyHandler,
+    structs::{
+        auxiliaries::{CResult, DnsError},
+        questions_and_records::Record,
+    },
+    tsig::{Keyring, TsigKey},
+};
+
+use super::{
+    serial_is_newer,
+    transfer::{axfr, fetch_soa, ixfr, Transfer},
+    Zone, ZoneStore,
+};
+
+/// Time waited before retrying the first transfer of a zone, when we don't
+/// have a SOA that tells us how long to wait yet.
+const INITIAL_RETRY: Duration = Duration::from_secs(60);
+
+/// # `SecondaryZone`
+///
+/// Keeps a zone pulled from its primaries up to date, following the
+/// refresh, retry and expire timers of its SOA (RFC 1034 section 4.3.5).
+pub struct SecondaryZone {
+    name: String,
+    primaries: Vec<SocketAddr>,
+    tsig_key: Option<TsigKey>,
+    zones: Arc<ZoneStore>,
+    notify: Arc<NotifyHandler>,
+    /// Present if the zone is signed by us before being served.
+    signer: Option<Arc<ZoneSigner>>,
+}
+
+impl SecondaryZone {
+    /// # `new`
+    ///
+    /// Fails if the TSIG key required by the zone isn't present in `keyring`.
+    /// `signer` is used only if signing has been enabled for the zone.
+    pub fn new(
+        settings: &SecondaryZoneSettings,
+        zones: Arc<ZoneStore>,
+        notify: Arc<NotifyHandler>,
+        keyring: &Keyring,
+        signer: Arc<ZoneSigner>,
+    ) -> CResult<Self> {
+        let tsig_key = match settings.get_tsig_key() {
+            Some(name) => Some(
+                keyring
+                    .get(&name)
+                    .ok_or(format!(
+                        "Unknown TSIG key for {}: {}",
+                        settings.get_name(),
+                        name
+                    ))?
+                    .clone(),
+            ),
+            None => None,
+        };
+        Ok(SecondaryZone {
+            name: settings.get_name(),
+            primaries: settings.get_primaries(),
+            tsig_key,
+            zones,
+            notify,
+            signer: settings.get_sign().then_some(signer),
+        })
+    }
+
+    /// # `run`
+    ///
+    /// Refreshes the zone forever: when the refresh timer fires or when a primary sends
+    /// a NOTIFY for the zone.
+    /// If no primary can be reached before the expire timer fires the zone stops being served.
+    #[tracing::instrument(name = "Maintaining a secondary zone", skip(self), fields(zone = self.name))]
+    pub async fn run(self) {
+        let mut notifications = self.notify.subscribe();
+        let mut last_success: Option<Instant> = None;
+
+        loop {
+            let next_refresh = match self.refresh().await {
+                Ok(()) => {
+                    last_success = Some(Instant::now());
+                    let refresh = self.timer(|refresh, _, _| refresh);
+                    // Signatures need to be renewed before they expire, even if the zone doesn't change
+                    match &self.signer {
+                        Some(signer) => refresh.min(signer.resign_interval()),
+                        None => refresh,
+                    }
+                }
+                Err(e) => {
+                    tracing::warn!("Failed to refresh the zone {}: {}", self.name, e);
+                    if let Some(success) = last_success {
+                        let expire = self.timer(|_, _, expire| expire);
+                        if success.elapsed() >= expire && self.zones.remove(&self.name).is_some() {
+                            tracing::error!(
+                                "The zone {} expired, it will not be served until a primary is reachable again",
+                                self.name
+                            );
+                            last_success = None;
+                        }
+                    }
+                    self.timer(|_, retry, _| retry)
+                }
+            };
+
+            // Waiting for the timer or for a NOTIFY, whatever comes first
+            let deadline = Instant::now() + next_refresh;
+            loop {
+                tokio::select! {
+                    _ = sleep_until(deadline) => break,
+                    notification = notifications.recv() => match notification {
+                        Ok(zone) if zone == self.name => break,
+                        Ok(_) | Err(RecvError::Lagged(_)) => {}
+                        Err(RecvError::Closed) => {
+                            sleep_until(deadline).await;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// # `refresh`
+    ///
+    /// Checks the serial held by the primaries and transfers the zone if ours is outdated,
+    /// the primaries are tried in order until one succeeds.
+    async fn refresh(&self) -> CResult<()> {
+        let mut last_error = String::from("No primary has been configured");
+        for primary in &self.primaries {
+            match self.refresh_from(*primary).await {
+                Ok(()) => return Ok(()),
+                // The transferred version isn't served, the operator of the primary needs to know
+                Err(e @ DnsError::Zonemd(_)) => {
+                    tracing::error!(
+                        "Refusing the zone {} transferred from {}: {}, check the zone and its ZONEMD on the primary",
+                        self.name,
+                        primary,
+                        e
+                    );
+                    last_error = e.to_string();
+                }
+                Err(e) => {
+                    tracing::info!("Primary {} failed: {}", primary, e);
+                    last_error = e.to_string();
+                }
+            }
+        }
+        Err(last_error.into())
+    }
+
+    async fn refresh_from(&self, primary: SocketAddr) -> CResult<()> {
+        let current = self.zones.get(&self.name);
+        let zone = match current {
+            None => axfr(&self.name, primary, self.tsig_key.as_ref()).await?,
+            Some(current) => {
+                let soa = fetch_soa(&self.name, primary, self.tsig_key.as_ref()).await?;
+                let serial = match soa {
+                    Record::SOA { serial, .. } => serial,
+                    _ => current.serial(),
+                };
+                if !serial_is_newer(serial, current.serial()) {
+                    tracing::info!("The zone {} is up to date", self.name);
+                    return self.resign_if_needed(current).await;
+                }
+                let transfer = match ixfr(&current, primary, self.tsig_key.as_ref()).await {
+                    Ok(t) => Some(t),
+                    Err(e) => {
+                        tracing::info!("IXFR failed, falling back to AXFR: {}", e);
+                        None
+                    }
+                };
+                match transfer {
+                    Some(Transfer::Updated(zone)) => zone,
+                    Some(Transfer::UpToDate) => return self.resign_if_needed(current).await,
+                    None => axfr(&self.name, primary, self.tsig_key.as_ref()).await?,
+                }
+            }
+        };
+
+        tracing::info!(
+            "Loaded the zone {} with serial {}, {} records",
+            zone.name,
+            zone.serial(),
+            zone.records.len()
+        );
+        let zone = self.sign(zone).await?;
+        let soa = zone.soa.clone();
+        self.zones.insert(zone);
+        // Our own secondaries need to know about the change as well
+        self.notify.zone_changed(&self.name, Some(soa));
+        Ok(())
+    }
+
+    /// # `resign_if_needed`
+    ///
+    /// Renews the signatures of an unchanged zone when they are about to expire.
+    async fn resign_if_needed(&self, zone: Zone) -> CResult<()> {
+        match &self.signer {
+            Some(signer) if signer.needs_resign(&zone) => {
+                let zone = self.sign(zone).await?;
+                self.zones.insert(zone);
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// # `sign`
+    ///
+    /// Signs `zone` if signing is enabled for it, the signatures are computed
+    /// on a blocking thread since large zones can take a while.
+    async fn sign(&self, mut zone: Zone) -> CResult<Zone> {
+        let signer = match &self.signer {
+            Some(signer) => signer.clone(),
+            None => return Ok(zone),
+        };
+        tokio::task::spawn_blocking(move || signer.sign_zone(&mut zone).map(|_| zone))
+            .await
+            .map_err(|e| DnsError::Other(e.to_string()))?
+    }
+
+    /// # `timer`
+    ///
+    /// Extracts one of the timers (refresh, retry, expire) from the SOA of the zone.
+    fn timer<F>(&self, pick: F) -> Duration
+    where
+        F: Fn(Duration, Duration, Duration) -> Duration,
+    {
+        match self.zones.get_soa(&self.name) {
+            Some(Record::SOA {
+                refresh,
+                retry,
+                expire,
+                ..
+            }) => pick(
+                Duration::from_secs(refresh as u64),
+                Duration::from_secs(retry as u64),
+                Duration::from_secs(expire as u64),
+            ),
+            _ => INITIAL_RETRY,
+        }
+    }
+}
@@ -0,0 +1,273 @@
+use std::{net::SocketAddr, time::Duration};
+
+use bytes::Buf;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+
+use crate::{
+    structs::{
+        auxiliaries::{CResult, DnsError},
+        buffer::BytePacketBuffer,
+        header::ResultCode,
+        packet::Packet,
+        questions_and_records::{QueryType, Record},
+    },
+    tsig::{self, TsigError, TsigKey},
+};
+
+use super::{zonemd, Zone};
+
+/// Maximum time a whole zone transfer is allowed to take.
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// # `Transfer`
+///
+/// Outcome of a zone transfer.
+pub enum Transfer {
+    /// The zone held by the primary has the same serial of ours.
+    UpToDate,
+    /// The new version of the zone.
+    Updated(Zone),
+}
+
+/// # `fetch_soa`
+///
+/// Queries `primary` over TCP for the SOA of `zone`.
+/// The exchange is authenticated with `key`, if provided, and so are the following ones.
+#[tracing::instrument(name = "Fetching the SOA of a zone", skip(primary, key), fields(primary = %primary))]
+pub async fn fetch_soa(zone: &str, primary: SocketAddr, key: Option<&TsigKey>) -> CResult<Record> {
+    let packet = transfer_query(zone, QueryType::SOA, None)?;
+    let messages = timeout(
+        TRANSFER_TIMEOUT,
+        exchange_tcp(&packet, primary, key, |_, _| true),
+    )
+    .await??;
+    let response = messages.into_iter().next().ok_or_else(|| {
+        DnsError::Upstream("The primary closed the connection without responding".to_string())
+    })?;
+    if response.header.rescode != ResultCode::NOERROR {
+        return Err(DnsError::Upstream(format!(
+            "The primary responded with {:?}",
+            response.header.rescode
+        )));
+    }
+    response
+        .answers
+        .into_iter()
+        .find(|r| matches!(r, Record::SOA { .. }))
+        .ok_or_else(|| DnsError::Upstream("The primary didn't provide a SOA record".to_string()))
+}
+
+/// # `axfr`
+///
+/// Performs a full zone transfer (RFC 5936) of `zone` from `primary`,
+/// the zone is verified against its ZONEMD, if it publishes one.
+#[tracing::instrument(name = "Performing an AXFR", skip(primary, key), fields(primary = %primary))]
+pub async fn axfr(zone: &str, primary: SocketAddr, key: Option<&TsigKey>) -> CResult<Zone> {
+    let packet = transfer_query(zone, QueryType::AXFR, None)?;
+    let records = receive_transfer(&packet, primary, key).await?;
+    zonemd::verify(zone, &records)?;
+    Zone::from_records(zone, records).ok_or_else(|| "The transfer contained no SOA".into())
+}
+
+/// # `ixfr`
+///
+/// Performs an incremental zone transfer (RFC 1995) of `current` from `primary`,
+/// the primary is free to respond with the whole zone instead of the differences.
+/// The zone the differences lead to is verified against its ZONEMD, if it publishes one.
+#[tracing::instrument(
+    name = "Performing an IXFR",
+    skip(current, primary, key),
+    fields(zone = current.name, primary = %primary)
+)]
+pub async fn ixfr(current: &Zone, primary: SocketAddr, key: Option<&TsigKey>) -> CResult<Transfer> {
+    let packet = transfer_query(&current.name, QueryType::IXFR, Some(current.soa.clone()))?;
+    let records = receive_transfer(&packet, primary, key).await?;
+
+    let new_soa = match records.first() {
+        Some(soa @ Record::SOA { .. }) => soa.clone(),
+        _ => return Err("The transfer didn't start with a SOA".into()),
+    };
+    // A single SOA means that we are already up to date
+    if records.len() == 1 {
+        return Ok(Transfer::UpToDate);
+    }
+    // If the second record isn't a SOA the primary is sending the whole zone
+    if !matches!(records.get(1), Some(Record::SOA { .. })) {
+        zonemd::verify(&current.name, &records)?;
+        return Zone::from_records(&current.name, records)
+            .map(Transfer::Updated)
+            .ok_or_else(|| "The transfer contained no SOA".into());
+    }
+
+    // Incremental transfer: sequences of deletions, each one introduced by the old SOA,
+    // followed by additions, introduced by the new SOA.
+    let mut zone = current.clone();
+    let mut deleting = false;
+    for record in &records[1..records.len() - 1] {
+        match record {
+            Record::SOA { .. } => {
+                deleting = !deleting;
+                if !deleting {
+                    zone.soa = record.clone();
+                }
+            }
+            _ if deleting => zone.records.retain(|r| r != record),
+            _ => zone.records.push(record.clone()),
+        }
+    }
+    zone.soa = new_soa;
+    let records: Vec<Record> = std::iter::once(zone.soa.clone())
+        .chain(zone.records.iter().cloned())
+        .collect();
+    zonemd::verify(&zone.name, &records)?;
+    Ok(Transfer::Updated(zone))
+}
+
+/// # `transfer_query`
+///
+/// Prepares the query packet for a zone transfer, `soa` is placed in the authority
+/// section, as required by IXFR.
+fn transfer_query(zone: &str, qtype: QueryType, soa: Option<Record>) -> CResult<Packet> {
+    let mut builder = Packet::builder()
+        .id((uuid::Uuid::new_v4().as_u128() & 0xFFFF) as u16)
+        .question(zone, qtype);
+    if let Some(soa) = soa {
+        builder = builder.authority(soa);
+    }
+    builder.build()
+}
+
+/// # `receive_transfer`
+///
+/// Sends a transfer query and collects the records of the answer sections
+/// until the closing SOA is received.
+async fn receive_transfer(
+    packet: &Packet,
+    primary: SocketAddr,
+    key: Option<&TsigKey>,
+) -> CResult<Vec<Record>> {
+    let messages = timeout(
+        TRANSFER_TIMEOUT,
+        exchange_tcp(packet, primary, key, |messages, last| {
+            last.header.rescode != ResultCode::NOERROR || closes_transfer(packet, messages, last)
+        }),
+    )
+    .await??;
+
+    if let Some(m) = messages
+        .iter()
+        .find(|m| m.header.rescode != ResultCode::NOERROR)
+    {
+        return Err(DnsError::Upstream(format!(
+            "The primary responded with {:?}",
+            m.header.rescode
+        )));
+    }
+    Ok(messages.into_iter().flat_map(|m| m.answers).collect())
+}
+
+/// # `closes_transfer`
+///
+/// Returns true if `last` is the final message of the transfer requested by `query`,
+/// `messages` are the ones received before it.
+/// A transfer ends when the SOA it started with is sent again, incremental transfers
+/// send it one more time as the header of the last group of additions.
+/// An IXFR made only of the SOA means that we are up to date.
+fn closes_transfer(query: &Packet, messages: &[Packet], last: &Packet) -> bool {
+    let records: Vec<&Record> = messages
+        .iter()
+        .chain(std::iter::once(last))
+        .flat_map(|m| m.answers.iter())
+        .collect();
+    let first = match records.first() {
+        Some(first @ Record::SOA { .. }) => *first,
+        _ => return false,
+    };
+    if records.len() == 1 {
+        return query.authorities.first() == Some(first);
+    }
+    let incremental = matches!(records[1], Record::SOA { .. });
+    let occurrences = records.iter().filter(|r| **r == first).count();
+    last.answers.last() == Some(first) && occurrences == if incremental { 3 } else { 2 }
+}
+
+/// # `exchange_tcp`
+///
+/// Sends `packet` to `server` over TCP and reads the response messages
+/// until `is_done` returns true or the connection is closed.
+/// `is_done` receives the messages received so far and the last one.
+/// If `key` is provided the query is signed and the responses need to be signed as well,
+/// only the first and the last message are required to carry a TSIG (RFC 8945 section 5.3.1).
+async fn exchange_tcp<F>(
+    packet: &Packet,
+    server: SocketAddr,
+    key: Option<&TsigKey>,
+    is_done: F,
+) -> CResult<Vec<Packet>>
+where
+    F: Fn(&[Packet], &Packet) -> bool,
+{
+    let mut stream = TcpStream::connect(server).await?;
+
+    let mut req_buffer = BytePacketBuffer::empty();
+    packet.clone().write(&mut req_buffer)?;
+    // MAC of the last signed message, the digest of the next one depends on it
+    let mut prior_mac = match key {
+        Some(key) => Some(tsig::sign(&mut req_buffer, key, None)?),
+        None => None,
+    };
+    // Unsigned messages received since the last signed one
+    let mut unsigned = Vec::new();
+    // Length prefix and message leave with a single vectored write
+    let len_prefix = (req_buffer.pos() as u16).to_be_bytes();
+    let mut request = Buf::chain(&len_prefix[..], req_buffer.freeze());
+    stream.write_all_buf(&mut request).await?;
+
+    let mut messages = Vec::new();
+    loop {
+        let len = match stream.read_u16().await {
+            Ok(len) => len as usize,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        };
+        let mut res_buffer = BytePacketBuffer::with_size(len);
+        stream.read_exact(&mut res_buffer.buf).await?;
+        if let Some(key) = key {
+            match tsig::find_tsig(&res_buffer.buf)? {
+                Some(message_tsig) => {
+                    tsig::verify_with(
+                        &res_buffer.buf,
+                        &message_tsig,
+                        key,
+                        prior_mac.as_deref(),
+                        &unsigned,
+                        !messages.is_empty(),
+                    )?;
+                    prior_mac = Some(message_tsig.mac);
+                    unsigned.clear();
+                }
+                None if messages.is_empty() => return Err(DnsError::Tsig(TsigError::FormErr)),
+                None => unsigned.extend_from_slice(&res_buffer.buf),
+            }
+        }
+        let message = Packet::from_buffer(&mut res_buffer)?;
+        if message.header.id != packet.header.id {
+            return Err(DnsError::Upstream(
+                "The server responded with a mismatching id".to_string(),
+            ));
+        }
+        let done = is_done(&messages, &message);
+        messages.push(message);
+        if done {
+            break;
+        }
+    }
+    if !unsigned.is_empty() {
+        return Err(DnsError::Tsig(TsigError::FormErr));
+    }
+    Ok(messages)
+}
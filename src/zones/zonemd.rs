@@ -0,0 +1,135 @@
+use std::cmp::Ordering;
+
+use ring::digest;
+
+use crate::{
+    dnssec::{canonical_cmp, name_wire},
+    structs::{
+        auxiliaries::{CResult, DnsError},
+        questions_and_records::Record,
+    },
+};
+
+use super::is_subdomain;
+
+/// Type of the ZONEMD records, not parsed, they reach us as `Record::UNKNOWN`.
+pub const ZONEMD: u16 = 63;
+/// The only scheme defined, the digest of the whole zone in canonical order.
+const SCHEME_SIMPLE: u8 = 1;
+const HASH_SHA384: u8 = 1;
+const HASH_SHA512: u8 = 2;
+
+/// # `is_apex_zonemd`
+///
+/// Returns true if `record` is a ZONEMD owned by the apex of `zone`,
+/// the only ones that carry the digest of the zone.
+pub fn is_apex_zonemd(zone: &str, record: &Record) -> bool {
+    matches!(record, Record::UNKNOWN { qtype, .. } if *qtype == ZONEMD)
+        && record.get_domain().eq_ignore_ascii_case(zone)
+}
+
+/// # `verify`
+///
+/// Verifies the ZONEMD digests (RFC 8976) published at the apex of `zone`,
+/// `records` is the content of the zone as transferred, SOA included.
+/// A zone without ZONEMD, or whose digests all use schemes or algorithms we
+/// don't support, is accepted as it is.
+pub fn verify(zone: &str, records: &[Record]) -> CResult<()> {
+    let serial = records
+        .iter()
+        .find_map(|r| match r {
+            Record::SOA { serial, .. } => Some(*serial),
+            _ => None,
+        })
+        .ok_or_else(|| DnsError::Zonemd(format!("the zone {} has no SOA", zone)))?;
+
+    let mut supported = Vec::new();
+    for record in records.iter().filter(|r| is_apex_zonemd(zone, r)) {
+        let Record::UNKNOWN { data, .. } = record else {
+            continue;
+        };
+        if data.len() < 6 {
+            return Err(DnsError::Zonemd(format!(
+                "the ZONEMD of {} is truncated",
+                zone
+            )));
+        }
+        let zonemd_serial = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+        let (scheme, algorithm, digest) = (data[4], data[5], &data[6..]);
+        if zonemd_serial != serial {
+            return Err(DnsError::Zonemd(format!(
+                "the ZONEMD of {} refers to serial {} but the SOA has serial {}, \
+                 the primary needs to recompute the digest after every change",
+                zone, zonemd_serial, serial
+            )));
+        }
+        let algorithm = match (scheme, algorithm) {
+            (SCHEME_SIMPLE, HASH_SHA384) => &digest::SHA384,
+            (SCHEME_SIMPLE, HASH_SHA512) => &digest::SHA512,
+            _ => continue,
+        };
+        if supported.iter().any(|(a, _)| *a == algorithm) {
+            return Err(DnsError::Zonemd(format!(
+                "the zone {} publishes more than one ZONEMD with the same scheme and algorithm",
+                zone
+            )));
+        }
+        supported.push((algorithm, digest));
+    }
+
+    if supported.is_empty() {
+        return Ok(());
+    }
+    let data = digest_input(zone, records)?;
+    for (algorithm, expected) in supported {
+        if digest::digest(algorithm, &data).as_ref() != expected {
+            return Err(DnsError::Zonemd(format!(
+                "the digest of {} with serial {} doesn't match its ZONEMD, \
+                 the zone has been altered or the primary published a stale digest",
+                zone, serial
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// # `digest_input`
+///
+/// Data hashed by the simple scheme: every record of the zone in canonical
+/// form and order, without duplicates, except for the apex ZONEMD RRset and
+/// the RRSIGs covering it (RFC 8976 section 3.3.1).
+fn digest_input(zone: &str, records: &[Record]) -> CResult<Vec<u8>> {
+    let mut entries = Vec::new();
+    for record in records {
+        if !is_subdomain(record.get_domain(), zone) || is_apex_zonemd(zone, record) {
+            continue;
+        }
+        if let Record::RRSIG { type_covered, .. } = record {
+            if *type_covered == ZONEMD && record.get_domain().eq_ignore_ascii_case(zone) {
+                continue;
+            }
+        }
+        entries.push((
+            record.get_domain(),
+            record.get_qtype().to_num(),
+            record.get_ttl(),
+            record.canonical_rdata()?,
+        ));
+    }
+    entries.sort_by(|a, b| match canonical_cmp(a.0, b.0) {
+        Ordering::Equal => (a.1, &a.3).cmp(&(b.1, &b.3)),
+        other => other,
+    });
+    entries.dedup_by(|a, b| a.0.eq_ignore_ascii_case(b.0) && a.1 == b.1 && a.3 == b.3);
+
+    let mut data = Vec::new();
+    for (owner, qtype, ttl, rdata) in entries {
+        data.extend(name_wire(owner)?);
+        data.extend_from_slice(&qtype.to_be_bytes());
+        data.extend_from_slice(&1u16.to_be_bytes());
+        data.extend_from_slice(&ttl.to_be_bytes());
+        data.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        data.extend(rdata);
+    }
+    Ok(data)
+}
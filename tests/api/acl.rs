@@ -0,0 +1,64 @@
+use dns::structs::{header::ResultCode, packet::Packet};
+use tokio::net::UdpSocket;
+
+use crate::helpers::{get_query_packet, get_response_packet, spawn_configured_app};
+
+/// # `ask_from`
+///
+/// Asks the server at `addr` for the address of `domain` from a socket bound to `client`,
+/// with RD set to `recursion_desired`.
+async fn ask_from(addr: &str, client: &str, domain: &str, recursion_desired: bool) -> Packet {
+    let client_sock = UdpSocket::bind(client)
+        .await
+        .expect("Failed to create the client socket.");
+    client_sock
+        .connect(addr)
+        .await
+        .expect("Fail to connect the client socket to the server socket");
+    let mut query = get_query_packet(1234, domain);
+    query.header.recursion_desired = recursion_desired;
+    let query_buffer = query
+        .to_vec()
+        .expect("Failed to generate the query buffer.");
+    get_response_packet(client_sock, &query_buffer)
+        .await
+        .expect("Failed to get the response packet")
+}
+
+/// # `clients_denied_recursion_cant_read_the_cache`
+///
+/// A client denied recursion is refused a name another client made us cache,
+/// whether it sets RD or not.
+#[tokio::test]
+async fn clients_denied_recursion_cant_read_the_cache() {
+    let test_app = spawn_configured_app(
+        |settings| settings.deny_recursion("127.0.0.1/32".parse().unwrap()),
+        Vec::new(),
+    )
+    .await
+    .expect("Failed to spawn the app.");
+    let allowed = ask_from(&test_app.addr, "127.0.0.3:0", "wiki.archlinux.org", true).await;
+    assert_eq!(allowed.header.rescode, ResultCode::NOERROR);
+    test_app.wait_for_cache("wiki.archlinux.org").await;
+
+    for recursion_desired in [true, false] {
+        let denied = ask_from(
+            &test_app.addr,
+            "127.0.0.1:0",
+            "wiki.archlinux.org",
+            recursion_desired,
+        )
+        .await;
+        assert_eq!(denied.header.rescode, ResultCode::REFUSED);
+        assert!(denied.answers.is_empty());
+        assert!(!denied.header.recursion_available);
+    }
+    // The client allowed still reads the cache
+    let cached = ask_from(&test_app.addr, "127.0.0.3:0", "wiki.archlinux.org", false).await;
+    assert_eq!(cached.header.rescode, ResultCode::NOERROR);
+    assert!(!cached.answers.is_empty());
+
+    // Graceful shutdown
+    test_app.cancellation_token.cancel();
+    let _ = test_app.handle.await;
+}
@@ -0,0 +1,337 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use dns::{
+    blocklist::{
+        parse_list, rules::BlockRules, BlockedAnswer, BlockingMode, BlockingPolicy, Blocklist,
+        PolicyGroup,
+    },
+    structs::{
+        header::ResultCode,
+        packet::Packet,
+        questions_and_records::{QueryType, Record},
+    },
+};
+use sqlx::SqlitePool;
+use uuid::Uuid;
+
+use crate::helpers::{get_query_packet, spawn_database};
+
+/// Address of the block page of the clients of `custom`.
+const BLOCK_PAGE: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 80);
+
+/// # `answer`
+///
+/// A blocked answer of mode `mode`, with the block page of `BLOCK_PAGE` on IPv4 only.
+fn answer(mode: BlockingMode) -> BlockedAnswer {
+    BlockedAnswer {
+        mode,
+        ipv4: Some(BLOCK_PAGE),
+        ipv6: None,
+        ttl: 60,
+    }
+}
+
+/// # `policy`
+///
+/// NXDOMAIN for the clients of no group, the null addresses for 10.0.0.0/8
+/// and the block page for 192.168.0.0/16.
+fn policy() -> BlockingPolicy {
+    BlockingPolicy {
+        default: answer(BlockingMode::NxDomain),
+        groups: vec![
+            PolicyGroup {
+                name: "null".to_string(),
+                clients: vec!["10.0.0.0/8".parse().unwrap()],
+                answer: answer(BlockingMode::Null),
+            },
+            PolicyGroup {
+                name: "custom".to_string(),
+                clients: vec!["192.168.0.0/16".parse().unwrap()],
+                answer: answer(BlockingMode::Custom),
+            },
+        ],
+    }
+}
+
+/// # `blocklist`
+///
+/// A blocklist of the domains of `list`, written to a file of its own,
+/// loaded in `db_pool`; the file is removed once loaded.
+async fn blocklist(
+    db_pool: &SqlitePool,
+    list: &str,
+    rules: BlockRules,
+    allowlist: &[&str],
+) -> Blocklist {
+    let path = std::env::temp_dir().join(format!("blocklist-{}.txt", Uuid::new_v4()));
+    std::fs::write(&path, list).expect("Failed to write the blocklist.");
+    let blocklist = Blocklist::new(
+        db_pool.clone(),
+        vec![path.clone()],
+        Vec::new(),
+        rules,
+        allowlist.iter().map(|domain| domain.to_string()).collect(),
+        policy(),
+        None,
+    );
+    let loaded = blocklist.reload().await;
+    let _ = std::fs::remove_file(&path);
+    loaded.expect("Failed to load the blocklist.");
+    blocklist
+}
+
+/// # `no_rules`
+///
+/// Neither wildcards nor regexes, only the lists block.
+fn no_rules() -> BlockRules {
+    BlockRules::new(&[], &[]).expect("Failed to build the rules.")
+}
+
+/// # `blocked`
+///
+/// The response of `blocklist` to `client` asking for the records of type `qtype` of `domain`.
+fn blocked(blocklist: &Blocklist, domain: &str, qtype: QueryType, client: &str) -> Packet {
+    let mut query = get_query_packet(1234, domain);
+    query.questions[0].qtype = qtype;
+    blocklist.blocked_response(&query, client.parse::<IpAddr>().unwrap())
+}
+
+/// # `soa_owner`
+///
+/// The owner of the SOA in the authority section of `response`, if any.
+fn soa_owner(response: &Packet) -> Option<String> {
+    response.authorities.iter().find_map(|record| match record {
+        Record::SOA { domain, .. } => Some(domain.to_string()),
+        _ => None,
+    })
+}
+
+/// # `parse_list_reads_hosts_and_plain_lists`
+///
+/// The names of the hosts files follow the address, the plain lists only have names;
+/// comments, the names of the machine itself and the invalid ones are skipped.
+#[test]
+fn parse_list_reads_hosts_and_plain_lists() {
+    let hosts = "# Ads\n\
+        127.0.0.1 localhost\n\
+        0.0.0.0 ads.example.com tracker.example.com # inline comment\n\
+        :: ipv6.example.com\n\
+        0.0.0.0 not_a..domain\n";
+    assert_eq!(
+        parse_list(hosts),
+        vec!["ads.example.com", "tracker.example.com", "ipv6.example.com"]
+    );
+
+    let plain = "ads.example.net\n\n  # indented comment\nbücher.example\n";
+    assert_eq!(
+        parse_list(plain),
+        vec!["ads.example.net", "xn--bcher-kva.example"]
+    );
+}
+
+/// # `allowlist_overrides_the_lists`
+///
+/// The names of the allowlist aren't blocked even if a list has them,
+/// the names below the ones of a list aren't blocked either.
+#[tokio::test]
+async fn allowlist_overrides_the_lists() {
+    let database = spawn_database()
+        .await
+        .expect("Failed to spawn the database.");
+    let blocklist = blocklist(
+        &database.db_pool,
+        "0.0.0.0 ads.example.com\n0.0.0.0 tracker.example.com\n",
+        no_rules(),
+        &["tracker.example.com"],
+    )
+    .await;
+
+    assert!(blocklist.is_blocked("ads.example.com").await.unwrap());
+    assert!(blocklist.is_blocked("ADS.example.com.").await.unwrap());
+    assert!(!blocklist.is_blocked("tracker.example.com").await.unwrap());
+    assert!(!blocklist.is_blocked("cdn.ads.example.com").await.unwrap());
+    assert!(!blocklist.is_blocked("example.com").await.unwrap());
+
+    database.close().await;
+}
+
+/// # `blocked_answers_follow_the_mode`
+///
+/// The clients of no group get NXDOMAIN, the ones of the groups the null addresses
+/// or the block page; the negative answers carry a SOA owned by the name blocked.
+#[tokio::test]
+async fn blocked_answers_follow_the_mode() {
+    let database = spawn_database()
+        .await
+        .expect("Failed to spawn the database.");
+    let blocklist = blocklist(&database.db_pool, "ads.example.com\n", no_rules(), &[]).await;
+
+    // NXDOMAIN
+    let response = blocked(&blocklist, "ads.example.com", QueryType::A, "172.16.0.1");
+    assert_eq!(response.header.rescode, ResultCode::NXDOMAIN);
+    assert!(response.answers.is_empty());
+    assert_eq!(soa_owner(&response).as_deref(), Some("ads.example.com"));
+
+    // null
+    let response = blocked(&blocklist, "ads.example.com", QueryType::A, "10.1.2.3");
+    assert_eq!(response.header.rescode, ResultCode::NOERROR);
+    assert!(matches!(
+        response.answers.as_slice(),
+        [Record::A { addr, .. }] if *addr == Ipv4Addr::UNSPECIFIED
+    ));
+    let response = blocked(&blocklist, "ads.example.com", QueryType::AAAA, "10.1.2.3");
+    assert!(matches!(
+        response.answers.as_slice(),
+        [Record::AAAA { addr, .. }] if *addr == Ipv6Addr::UNSPECIFIED
+    ));
+
+    // custom, the block page has no IPv6 address: no data
+    let response = blocked(&blocklist, "ads.example.com", QueryType::A, "192.168.1.1");
+    assert_eq!(response.header.rescode, ResultCode::NOERROR);
+    assert!(matches!(
+        response.answers.as_slice(),
+        [Record::A { addr, .. }] if *addr == BLOCK_PAGE
+    ));
+    let response = blocked(
+        &blocklist,
+        "ads.example.com",
+        QueryType::AAAA,
+        "192.168.1.1",
+    );
+    assert_eq!(response.header.rescode, ResultCode::NOERROR);
+    assert!(response.answers.is_empty());
+    assert_eq!(soa_owner(&response).as_deref(), Some("ads.example.com"));
+
+    database.close().await;
+}
+
+/// # `wildcards_match_the_subdomains_only`
+///
+/// `*.suffix` blocks every name below `suffix`, at any depth, but not `suffix`
+/// itself nor the names that merely end with the same characters.
+#[test]
+fn wildcards_match_the_subdomains_only() {
+    let rules = BlockRules::new(&["*.Doubleclick.net.".to_string()], &[])
+        .expect("Failed to build the rules.");
+
+    assert!(rules.matches("ad.doubleclick.net"));
+    assert!(rules.matches("a.b.doubleclick.net"));
+    assert!(!rules.matches("doubleclick.net"));
+    assert!(!rules.matches("notdoubleclick.net"));
+    assert!(!rules.matches("net"));
+    assert_eq!(
+        rules.wildcard_zone("a.b.doubleclick.net").as_deref(),
+        Some("doubleclick.net")
+    );
+    assert_eq!(rules.wildcard_zone("doubleclick.net"), None);
+
+    assert!(BlockRules::new(&["doubleclick.net".to_string()], &[]).is_err());
+    assert!(BlockRules::new(&["*.ads.*.net".to_string()], &[]).is_err());
+    assert!(BlockRules::new(&[], &["(unclosed".to_string()]).is_err());
+}
+
+/// # `regexes_match_the_names`
+///
+/// The regexes are searched in the name, lowercase and without the trailing dot,
+/// anywhere unless they are anchored.
+#[test]
+fn regexes_match_the_names() {
+    let rules = BlockRules::new(&[], &[r"^ads?[0-9]*\.".to_string(), r"track".to_string()])
+        .expect("Failed to build the rules.");
+
+    assert!(rules.matches("ad.example.com"));
+    assert!(rules.matches("ads42.example.com"));
+    assert!(rules.matches("cdn.tracking.example.org"));
+    assert!(!rules.matches("bad.example.com"));
+    assert!(!rules.matches("example.com"));
+    assert_eq!(rules.wildcard_zone("ads42.example.com"), None);
+}
+
+/// # `allowlist_overrides_the_rules`
+///
+/// The names of the allowlist aren't blocked even if a wildcard or a regex matches them.
+#[tokio::test]
+async fn allowlist_overrides_the_rules() {
+    let database = spawn_database()
+        .await
+        .expect("Failed to spawn the database.");
+    let rules = BlockRules::new(
+        &["*.doubleclick.net".to_string()],
+        &[r"^ads?\.".to_string()],
+    )
+    .expect("Failed to build the rules.");
+    let blocklist = blocklist(
+        &database.db_pool,
+        "",
+        rules,
+        &["static.doubleclick.net", "ads.example.org"],
+    )
+    .await;
+
+    assert!(blocklist.is_blocked("ad.doubleclick.net").await.unwrap());
+    assert!(blocklist.is_blocked("Ad.DoubleClick.net.").await.unwrap());
+    assert!(blocklist.is_blocked("ads.example.com").await.unwrap());
+    assert!(!blocklist
+        .is_blocked("static.doubleclick.net")
+        .await
+        .unwrap());
+    assert!(!blocklist.is_blocked("ads.example.org").await.unwrap());
+    assert!(!blocklist.is_blocked("doubleclick.net").await.unwrap());
+
+    database.close().await;
+}
+
+/// # `wildcards_answer_for_their_apex`
+///
+/// The negative answers for the names a wildcard blocks carry a SOA owned by
+/// the suffix of the wildcard, the zone we pretend to be authoritative for;
+/// the names matched by a regex own theirs.
+#[tokio::test]
+async fn wildcards_answer_for_their_apex() {
+    let database = spawn_database()
+        .await
+        .expect("Failed to spawn the database.");
+    let rules = BlockRules::new(
+        &["*.doubleclick.net".to_string()],
+        &[r"^tracker\.".to_string()],
+    )
+    .expect("Failed to build the rules.");
+    let blocklist = blocklist(&database.db_pool, "", rules, &[]).await;
+
+    // NXDOMAIN
+    let response = blocked(
+        &blocklist,
+        "a.b.DoubleClick.net",
+        QueryType::A,
+        "172.16.0.1",
+    );
+    assert_eq!(response.header.rescode, ResultCode::NXDOMAIN);
+    assert_eq!(soa_owner(&response).as_deref(), Some("doubleclick.net"));
+
+    // null, no data for the types other than A and AAAA
+    let response = blocked(&blocklist, "a.b.doubleclick.net", QueryType::MX, "10.1.2.3");
+    assert_eq!(response.header.rescode, ResultCode::NOERROR);
+    assert!(response.answers.is_empty());
+    assert_eq!(soa_owner(&response).as_deref(), Some("doubleclick.net"));
+
+    // custom, the block page has no IPv6 address
+    let response = blocked(
+        &blocklist,
+        "ad.doubleclick.net",
+        QueryType::AAAA,
+        "192.168.1.1",
+    );
+    assert!(response.answers.is_empty());
+    assert_eq!(soa_owner(&response).as_deref(), Some("doubleclick.net"));
+
+    let response = blocked(
+        &blocklist,
+        "tracker.example.com",
+        QueryType::A,
+        "172.16.0.1",
+    );
+    assert_eq!(response.header.rescode, ResultCode::NXDOMAIN);
+    assert_eq!(soa_owner(&response).as_deref(), Some("tracker.example.com"));
+
+    database.close().await;
+}
@@ -0,0 +1,64 @@
+use std::{os::unix::fs::PermissionsExt, sync::Arc, time::Duration};
+
+use dns::{
+    clock::SystemClock,
+    control::{serve, ControlHandler, Reloader},
+    outbound::MockTransport,
+    workers::InfraCache,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UnixStream,
+    sync::mpsc,
+};
+use uuid::Uuid;
+
+use crate::helpers::spawn_resolver;
+
+/// # `control_socket_is_private`
+///
+/// The control socket is only accessible to the user running the server,
+/// it answers the commands sent along with a newline.
+#[tokio::test]
+async fn control_socket_is_private() {
+    let test_resolver = spawn_resolver(Arc::new(MockTransport::new()), Arc::new(SystemClock))
+        .await
+        .expect("Failed to build the resolver.");
+    let (reload, _reloaded) = mpsc::channel(1);
+    let handler = ControlHandler::new(
+        test_resolver.resolver.storage.clone(),
+        Arc::new(InfraCache::new()),
+        Reloader::new(Box::new(|| Err("Not reloadable".to_string())), reload),
+    );
+    let path = std::env::temp_dir().join(format!("control-{}.sock", Uuid::new_v4()));
+    let server = tokio::spawn({
+        let path = path.clone();
+        async move { serve(&path, handler).await }
+    });
+    let mut stream = loop {
+        if let Ok(stream) = UnixStream::connect(&path).await {
+            break stream;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    };
+
+    let mode = std::fs::metadata(&path)
+        .expect("Failed to read the metadata of the socket.")
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o777, 0o600);
+    stream
+        .write_all(b"infra\n")
+        .await
+        .expect("Failed to send the command.");
+    let mut response = String::new();
+    stream
+        .read_to_string(&mut response)
+        .await
+        .expect("Failed to read the response.");
+    assert!(response.starts_with("ok\n"), "{}", response);
+
+    server.abort();
+    let _ = std::fs::remove_file(&path);
+    test_resolver.close().await;
+}
@@ -0,0 +1,122 @@
+use std::net::Ipv4Addr;
+
+use chrono::{DateTime, Utc};
+use dns::{
+    dhcp::{lease_zones, parse_leases, Lease, LeaseFormat},
+    structs::questions_and_records::{QueryType, Question, Record},
+    zones::ZoneStore,
+};
+
+/// The time the lease files are read at, 2024-01-01T00:00:00Z.
+fn now() -> DateTime<Utc> {
+    DateTime::from_timestamp(1_704_067_200, 0).unwrap()
+}
+
+fn lease(hostname: &str, host: u8) -> Lease {
+    Lease {
+        hostname: hostname.to_string(),
+        addr: Ipv4Addr::new(192, 168, 1, host),
+    }
+}
+
+/// # `dnsmasq_leases_are_read`
+///
+/// The leases still valid, or that never expire, are kept along with their hostname,
+/// the devices that didn't send a valid one and the IPv6 leases are left out.
+#[test]
+fn dnsmasq_leases_are_read() {
+    let content = "\
+1704070800 aa:bb:cc:dd:ee:01 192.168.1.10 Laptop.lan 01:aa:bb:cc:dd:ee:01
+0 aa:bb:cc:dd:ee:02 192.168.1.11 printer *
+1704063600 aa:bb:cc:dd:ee:03 192.168.1.12 phone *
+1704070800 aa:bb:cc:dd:ee:04 192.168.1.13 * *
+1704070800 aa:bb:cc:dd:ee:05 192.168.1.14 -bad- *
+duid 00:01:00:01:2c:aa:bb:cc
+1704070800 1234 fd00::10 desktop 00:01:00:01
+";
+    assert_eq!(
+        parse_leases(content, LeaseFormat::Dnsmasq, now()),
+        vec![lease("laptop", 10), lease("printer", 11)]
+    );
+}
+
+/// # `isc_leases_are_read`
+///
+/// Only the active leases count, the last entry of an address wins.
+#[test]
+fn isc_leases_are_read() {
+    let content = r#"
+lease 192.168.1.20 {
+  starts 1 2023/12/31 22:00:00;
+  ends 1 2024/01/01 02:00:00;
+  binding state active;
+  client-hostname "nas";
+}
+lease 192.168.1.21 {
+  ends never;
+  binding state active;
+  client-hostname "router";
+}
+lease 192.168.1.22 {
+  ends epoch 1704070800;
+  binding state free;
+  client-hostname "gone";
+}
+lease 192.168.1.23 {
+  ends 1 2024/01/01 02:00:00;
+  binding state active;
+  client-hostname "tv";
+}
+lease 192.168.1.23 {
+  ends 1 2024/01/01 02:00:00;
+  binding state free;
+}
+"#;
+    assert_eq!(
+        parse_leases(content, LeaseFormat::Isc, now()),
+        vec![lease("nas", 20), lease("router", 21)]
+    );
+}
+
+/// # `kea_leases_are_read`
+///
+/// The columns are found by their name, only the leases in the default state count.
+#[test]
+fn kea_leases_are_read() {
+    let content = "\
+address,hwaddr,client_id,valid_lifetime,expire,subnet_id,fqdn_fwd,fqdn_rev,hostname,state
+192.168.1.30,aa:bb:cc:dd:ee:30,,3600,1704070800,1,0,0,camera,0
+192.168.1.31,aa:bb:cc:dd:ee:31,,3600,1704070800,1,0,0,declined,1
+192.168.1.32,aa:bb:cc:dd:ee:32,,3600,1704063600,1,0,0,expired,0
+";
+    assert_eq!(
+        parse_leases(content, LeaseFormat::Kea, now()),
+        vec![lease("camera", 30)]
+    );
+}
+
+/// # `only_the_addresses_leased_have_a_ptr`
+///
+/// The reverse zones hold the names of the leased addresses alone, the queries for
+/// the other addresses of their network aren't answered by them.
+#[test]
+fn only_the_addresses_leased_have_a_ptr() {
+    let (forward, reverse) =
+        lease_zones("lan", &[lease("laptop", 10), lease("printer", 11)], 60, 1);
+    let zones = ZoneStore::new();
+    zones.insert(forward);
+    for zone in reverse {
+        zones.insert(zone);
+    }
+
+    let ptr = |name: &str| Question::new(name.to_string(), QueryType::PTR);
+    let response = zones
+        .answer(&ptr("10.1.168.192.in-addr.arpa"))
+        .expect("The PTR of a lease isn't served.");
+    match response.answers.as_slice() {
+        [Record::PTR { host, .. }] => assert_eq!(host, "laptop.lan"),
+        answers => panic!("Unexpected answers: {:?}", answers),
+    }
+    assert!(zones.answer(&ptr("1.1.168.192.in-addr.arpa")).is_none());
+    assert!(zones.answer(&ptr("1.168.192.in-addr.arpa")).is_none());
+}
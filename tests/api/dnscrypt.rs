@@ -0,0 +1,81 @@
+use std::net::Ipv4Addr;
+
+use dns::{
+    outbound::{DnscryptTransport, Transport},
+    structs::{
+        auxiliaries::{CResult, DnsError},
+        header::ResultCode,
+        packet::Packet,
+        questions_and_records::{QueryType, Record},
+    },
+    testing::{DnscryptFault, FakeDnscryptResolver},
+};
+
+/// Provider name of the resolvers of the tests.
+const PROVIDER: &str = "2.dnscrypt-cert.example";
+
+/// # `query_resolver`
+///
+/// Asks a fake resolver broken as `fault` for the address of `example.org`,
+/// trusting `provider_key` to sign its certificate, the one of the resolver if none.
+async fn query_resolver(fault: DnscryptFault, provider_key: Option<[u8; 32]>) -> CResult<Packet> {
+    let resolver = FakeDnscryptResolver::bind((Ipv4Addr::LOCALHOST, 0).into(), PROVIDER, fault)
+        .await
+        .expect("Failed to spawn the resolver.");
+    resolver.add_record(Record::A {
+        domain: "example.org".into(),
+        addr: Ipv4Addr::new(192, 0, 2, 1),
+        ttl: 300,
+    });
+    let provider_key = provider_key.unwrap_or(resolver.provider_key());
+    let transport = DnscryptTransport::new(PROVIDER, provider_key, resolver.addr().port());
+    transport
+        .query("example.org", QueryType::A, resolver.addr(), None)
+        .await
+}
+
+/// # `queries_travel_encrypted_to_dnscrypt_resolvers`
+///
+/// The transport fetches the certificate of the resolver, encrypts the query for its key
+/// and decrypts the response.
+#[tokio::test]
+async fn queries_travel_encrypted_to_dnscrypt_resolvers() {
+    let response = query_resolver(DnscryptFault::None, None)
+        .await
+        .expect("Failed to query the resolver.");
+    assert_eq!(response.header.rescode, ResultCode::NOERROR);
+    match response.answers.as_slice() {
+        [Record::A { domain, addr, .. }] => {
+            assert_eq!(domain, "example.org");
+            assert_eq!(*addr, Ipv4Addr::new(192, 0, 2, 1));
+        }
+        answers => panic!("Unexpected answers: {:?}", answers),
+    }
+}
+
+/// # `certificates_of_other_providers_are_refused`
+///
+/// A certificate that isn't signed by the key of the provider isn't used.
+#[tokio::test]
+async fn certificates_of_other_providers_are_refused() {
+    let response = query_resolver(DnscryptFault::None, Some([7; 32])).await;
+    assert!(matches!(response, Err(DnsError::Upstream(_))));
+}
+
+/// # `responses_without_the_resolver_magic_are_refused`
+///
+/// A response that doesn't start with the magic of the resolvers isn't decrypted.
+#[tokio::test]
+async fn responses_without_the_resolver_magic_are_refused() {
+    let response = query_resolver(DnscryptFault::BadMagic, None).await;
+    assert!(matches!(response, Err(DnsError::Upstream(_))));
+}
+
+/// # `responses_without_padding_are_refused`
+///
+/// A decrypted response has to end with the padding, or it isn't read.
+#[tokio::test]
+async fn responses_without_padding_are_refused() {
+    let response = query_resolver(DnscryptFault::BadPadding, None).await;
+    assert!(matches!(response, Err(DnsError::Upstream(_))));
+}
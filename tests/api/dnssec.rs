@@ -0,0 +1,100 @@
+use std::{fs, time::Duration};
+
+use dns::{
+    dnssec::ZoneSigner,
+    structs::questions_and_records::{QueryType, Record},
+    zones::Zone,
+};
+use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_FIXED};
+
+/// # `name_wire`
+///
+/// `name` in the wire format, uncompressed.
+fn name_wire(name: &str) -> Vec<u8> {
+    let mut wire = Vec::new();
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        wire.push(label.len() as u8);
+        wire.extend_from_slice(label.as_bytes());
+    }
+    wire.push(0);
+    wire
+}
+
+/// # `signatures_cover_the_canonical_rdata`
+///
+/// The names in the data of the records are signed in lowercase, whatever their case
+/// in the zone (RFC 4034 section 6.2): the RRSIG of a CNAME to a mixed-case target
+/// verifies against its canonical form.
+#[test]
+fn signatures_cover_the_canonical_rdata() {
+    let keys_dir = std::env::temp_dir().join(format!("rusty_dns_keys_{}", std::process::id()));
+    let signer = ZoneSigner::new(keys_dir.clone(), Duration::from_secs(86400));
+    let mut zone = Zone {
+        name: "example.com".to_string(),
+        soa: Record::SOA {
+            domain: "example.com".into(),
+            mname: "NS1.Example.COM".into(),
+            rname: "Hostmaster.Example.COM".into(),
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 604800,
+            minimum: 300,
+            ttl: 3600,
+        },
+        records: vec![Record::CNAME {
+            domain: "www.example.com".into(),
+            host: "Target.Example.COM".into(),
+            ttl: 300,
+        }],
+    };
+    let signed = signer.sign_zone(&mut zone);
+    let _ = fs::remove_dir_all(&keys_dir);
+    signed.expect("Failed to sign the zone.");
+
+    let zsk = zone
+        .records
+        .iter()
+        .find_map(|record| match record {
+            Record::DNSKEY {
+                flags: 256,
+                public_key,
+                ..
+            } => Some(public_key.clone()),
+            _ => None,
+        })
+        .expect("The zone has no ZSK.");
+    let rrsig = zone
+        .records
+        .iter()
+        .find(|record| {
+            matches!(record, Record::RRSIG { type_covered, .. }
+                if *type_covered == QueryType::CNAME.to_num())
+        })
+        .expect("The CNAME isn't signed.");
+    let Record::RRSIG {
+        original_ttl,
+        signature,
+        ..
+    } = rrsig
+    else {
+        unreachable!()
+    };
+
+    // The data of the RRSIG but the signature, followed by the CNAME in canonical form
+    let rrsig_rdata = rrsig.wire_rdata().expect("Failed to write the RRSIG.");
+    let mut data = rrsig_rdata[..rrsig_rdata.len() - signature.len()].to_vec();
+    let target = name_wire("target.example.com");
+    data.extend(name_wire("www.example.com"));
+    data.extend_from_slice(&QueryType::CNAME.to_num().to_be_bytes());
+    data.extend_from_slice(&1u16.to_be_bytes());
+    data.extend_from_slice(&original_ttl.to_be_bytes());
+    data.extend_from_slice(&(target.len() as u16).to_be_bytes());
+    data.extend(target);
+
+    let mut public_key = vec![4];
+    public_key.extend(zsk);
+    UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, public_key)
+        .verify(&data, signature)
+        .expect("The signature doesn't cover the canonical data.");
+}
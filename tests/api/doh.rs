@@ -0,0 +1,137 @@
+use std::net::{Ipv4Addr, SocketAddr, TcpListener};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use dns::{
+    doh::DNS_MESSAGE,
+    structs::{header::ResultCode, packet::Packet, questions_and_records::Record},
+};
+use reqwest::{header, Certificate, Client};
+
+use crate::helpers::{get_query_packet, spawn_configured_app, TestApp};
+
+/// Certificate authority of the certificate of the endpoint, valid for 127.0.0.1.
+const CA: &str = "tests/fixtures/tls/ca.pem";
+const CERTIFICATE: &str = "tests/fixtures/tls/cert.pem";
+const PRIVATE_KEY: &str = "tests/fixtures/tls/key.pem";
+
+/// # `spawn_doh_app`
+///
+/// Spawns the server serving DNS over HTTPS too, returns it along with the URL
+/// of the endpoint and a client trusting its certificate.
+async fn spawn_doh_app() -> (TestApp, String, Client) {
+    // A port nobody is listening on, for the endpoint to bind
+    let addr: SocketAddr = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .and_then(|listener| listener.local_addr())
+        .expect("Failed to find a free port.");
+    let test_app = spawn_configured_app(
+        |settings| settings.serve_doh(addr, CERTIFICATE, PRIVATE_KEY),
+        Vec::new(),
+    )
+    .await
+    .expect("Failed to spawn the app.");
+    let ca = std::fs::read(CA).expect("Failed to read the certificate authority.");
+    let client = Client::builder()
+        .add_root_certificate(Certificate::from_pem(&ca).expect("Invalid certificate."))
+        .build()
+        .expect("Failed to build the client.");
+    (test_app, format!("https://{}/dns-query", addr), client)
+}
+
+/// # `send`
+///
+/// Sends `request`, retrying while the endpoint isn't listening yet,
+/// returns the DNS message of the response.
+async fn send(request: reqwest::RequestBuilder) -> Packet {
+    let mut response = None;
+    for _ in 0..100 {
+        match request
+            .try_clone()
+            .expect("Failed to clone the request.")
+            .send()
+            .await
+        {
+            Ok(sent) => {
+                response = Some(sent);
+                break;
+            }
+            Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+        }
+    }
+    let response = response.expect("The DoH endpoint never answered.");
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    assert_eq!(
+        response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok()),
+        Some(DNS_MESSAGE)
+    );
+    let body = response.bytes().await.expect("Failed to read the body.");
+    Packet::from_bytes(&body).expect("The body isn't a DNS message.")
+}
+
+/// # `assert_wiki_answered`
+///
+/// `response` answers the address of `wiki.archlinux.org`.
+fn assert_wiki_answered(response: &Packet) {
+    assert_eq!(response.header.id, 1234);
+    assert_eq!(response.header.rescode, ResultCode::NOERROR);
+    match &response.answers[0] {
+        Record::A { domain, addr, .. } => {
+            assert_eq!(domain, "wiki.archlinux.org");
+            assert_eq!(*addr, Ipv4Addr::new(95, 217, 163, 246));
+        }
+        answer => panic!("Unexpected answer: {:?}", answer),
+    }
+}
+
+/// # `doh_answers_get_requests`
+///
+/// A query sent base64url encoded in the `dns` parameter of a GET request is answered
+/// with the DNS message in the body (RFC 8484 section 4.1).
+#[tokio::test]
+async fn doh_answers_get_requests() {
+    let (test_app, url, client) = spawn_doh_app().await;
+    let query = get_query_packet(1234, "wiki.archlinux.org")
+        .to_vec()
+        .expect("Failed to generate the query buffer.");
+
+    let response = send(
+        client
+            .get(&url)
+            .query(&[("dns", URL_SAFE_NO_PAD.encode(query))])
+            .header(header::ACCEPT, DNS_MESSAGE),
+    )
+    .await;
+    assert_wiki_answered(&response);
+
+    // Graceful shutdown
+    test_app.cancellation_token.cancel();
+    let _ = test_app.handle.await;
+}
+
+/// # `doh_answers_post_requests`
+///
+/// A query sent as the body of a POST request is answered with the DNS message
+/// in the body (RFC 8484 section 4.1).
+#[tokio::test]
+async fn doh_answers_post_requests() {
+    let (test_app, url, client) = spawn_doh_app().await;
+    let query = get_query_packet(1234, "wiki.archlinux.org")
+        .to_vec()
+        .expect("Failed to generate the query buffer.");
+
+    let response = send(
+        client
+            .post(&url)
+            .header(header::CONTENT_TYPE, DNS_MESSAGE)
+            .header(header::ACCEPT, DNS_MESSAGE)
+            .body(query),
+    )
+    .await;
+    assert_wiki_answered(&response);
+
+    // Graceful shutdown
+    test_app.cancellation_token.cancel();
+    let _ = test_app.handle.await;
+}
@@ -0,0 +1,35 @@
+use std::net::{IpAddr, Ipv4Addr};
+
+use dns::ecs::{sends_subnet_to, ClientSubnet};
+
+/// # `client_subnets_with_bits_past_the_prefix_are_malformed`
+///
+/// The address of the option can't have bits set past the source prefix
+/// (RFC 7871 section 7.1.2), the others are read as they are.
+#[test]
+fn client_subnets_with_bits_past_the_prefix_are_malformed() {
+    // 192.0.2.0/24
+    let subnet = ClientSubnet::from_option(&[0, 1, 24, 0, 192, 0, 2])
+        .expect("Failed to read a well formed subnet.");
+    assert_eq!(subnet.addr, IpAddr::V4(Ipv4Addr::new(192, 0, 2, 0)));
+    assert_eq!(subnet.source_prefix, 24);
+    // 192.0.3.0/23, the last bit of the third byte is past the prefix
+    assert!(ClientSubnet::from_option(&[0, 1, 23, 0, 192, 0, 3]).is_none());
+    // More bytes than the prefix covers
+    assert!(ClientSubnet::from_option(&[0, 1, 16, 0, 192, 0, 0]).is_none());
+}
+
+/// # `subnets_only_reach_the_zones_configured`
+///
+/// The forwarders and the name servers of the zones configured, or below them, are sent
+/// the subnet, the root and the top level domains aren't.
+#[test]
+fn subnets_only_reach_the_zones_configured() {
+    let zones = vec!["cdn.example".to_string()];
+    assert!(sends_subnet_to(&zones, None));
+    assert!(sends_subnet_to(&zones, Some("cdn.example")));
+    assert!(sends_subnet_to(&zones, Some("edge.cdn.example")));
+    assert!(!sends_subnet_to(&zones, Some("")));
+    assert!(!sends_subnet_to(&zones, Some("example")));
+    assert!(!sends_subnet_to(&[], Some("cdn.example")));
+}
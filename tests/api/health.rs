@@ -0,0 +1,50 @@
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    thread::sleep,
+    time::Duration,
+};
+
+use dns::workers::UpstreamHealth;
+
+const COOLDOWN: Duration = Duration::from_millis(50);
+const SERVER: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 53));
+
+/// # `a_single_query_probes_a_server_that_cooled_down`
+///
+/// A server failing too many times in a row rests for the cooldown, then a single query
+/// probes it: a failure puts it back to rest, a success opens it to every query.
+#[test]
+fn a_single_query_probes_a_server_that_cooled_down() {
+    let health = UpstreamHealth::new(2, COOLDOWN, Duration::ZERO);
+    health.record_failure(SERVER);
+    assert!(health.is_available(SERVER));
+    health.record_failure(SERVER);
+    assert!(!health.is_available(SERVER));
+
+    sleep(COOLDOWN);
+    assert!(health.is_available(SERVER));
+    assert!(!health.is_available(SERVER));
+    health.record_failure(SERVER);
+    assert!(!health.is_available(SERVER));
+
+    sleep(COOLDOWN);
+    assert!(health.is_available(SERVER));
+    assert!(!health.is_available(SERVER));
+    health.record_success(SERVER);
+    assert!(health.is_available(SERVER));
+    assert!(health.is_available(SERVER));
+}
+
+/// # `a_lost_probe_is_replaced`
+///
+/// A probe whose outcome never comes doesn't keep the server closed forever.
+#[test]
+fn a_lost_probe_is_replaced() {
+    let health = UpstreamHealth::new(1, COOLDOWN, Duration::ZERO);
+    health.record_failure(SERVER);
+    sleep(COOLDOWN);
+    assert!(health.is_available(SERVER));
+    assert!(!health.is_available(SERVER));
+    sleep(COOLDOWN);
+    assert!(health.is_available(SERVER));
+}
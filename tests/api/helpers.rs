@@ -1,21 +1,36 @@
-use std::{error::Error, fs};
+use std::{error::Error, fs, net::Ipv4Addr, sync::Arc, time::Duration};
 
 use dns::{
+    clock::Clock,
     configuration::{get_settings, Settings},
-    run,
+    database,
+    outbound::Transport,
+    resolver::Resolver,
+    server::Server,
+    storage,
     structs::{
-        buffer::BytePacketBuffer,
-        header::ResultCode,
         packet::Packet,
-        questions_and_records::{QueryType, Question},
+        questions_and_records::{EdnsOption, QueryType, Record},
     },
-    telemetry::{get_subscriber, init_subscriber},
+    telemetry::{get_subscriber, init_subscriber, LogOptions},
+    testing::FakeNameserver,
+    tsig::TsigAlgorithm,
+    workers::Middleware,
 };
 use once_cell::sync::Lazy;
-use sqlx::{sqlite::SqliteConnectOptions, SqlitePool};
-use tokio::{net::UdpSocket, select, task::JoinHandle};
+use sqlx::SqlitePool;
+use tokio::{net::UdpSocket, task::JoinHandle};
 use tokio_util::sync::CancellationToken;
 
+/// Addresses of `big.archlinux.org`, see `spawn_nameservers`.
+pub const BIG_RRSET_LEN: usize = 30;
+/// Code of the EDNS padding option (RFC 7830).
+const PADDING_OPTION: u16 = 12;
+/// Name of the TSIG key the test server knows, of algorithm HMAC-SHA256.
+pub const TSIG_KEY_NAME: &str = "test-key";
+/// Secret of `TSIG_KEY_NAME`, base64 encoded.
+pub const TSIG_KEY_SECRET: &str = "dGVzdC1rZXktc2hhcmVkLXdpdGgtdGhlLXNlcnZlcg==";
+
 /// Ensures that the `tracing` stack is only initialised once using `once_cell`
 static TRACING: Lazy<()> = Lazy::new(|| {
     let default_filter_level = "info".to_string();
@@ -25,10 +40,22 @@ static TRACING: Lazy<()> = Lazy::new(|| {
     // therefore they are not the same type.
     // We could work around it, but this is the most straight forward way of moving forward.
     if std::env::var("TEST_LOG").is_ok() {
-        let subscriber = get_subscriber(subscriber_name, default_filter_level, std::io::stdout);
+        let subscriber = get_subscriber(
+            subscriber_name,
+            default_filter_level,
+            std::io::stdout,
+            None,
+            LogOptions::default(),
+        );
         init_subscriber(subscriber);
     } else {
-        let subscriber = get_subscriber(subscriber_name, default_filter_level, std::io::sink);
+        let subscriber = get_subscriber(
+            subscriber_name,
+            default_filter_level,
+            std::io::sink,
+            None,
+            LogOptions::default(),
+        );
         init_subscriber(subscriber);
     }
 });
@@ -47,6 +74,144 @@ pub struct TestApp {
     /// `handle` is needed for the graceful shutdown, `TestApp.handle.await`
     /// needs to be called after having called `TestApp.cancellation_token.cancel`.
     pub handle: JoinHandle<()>,
+    /// `nameservers` are the fake name servers the server resolves against,
+    /// see `spawn_nameservers`.
+    pub nameservers: Vec<FakeNameserver>,
+    /// `db_pool` is the cache of the test server.
+    pub db_pool: SqlitePool,
+}
+
+impl TestApp {
+    /// # `wait_for_cache`
+    ///
+    /// The answers are cached in the background, waits for the ones of `domain`
+    /// to be in the cache; panics after a second.
+    pub async fn wait_for_cache(&self, domain: &str) {
+        wait_for_cache(&self.db_pool, domain).await
+    }
+}
+
+/// # `TestResolver`
+///
+/// A resolver caching in a database of its own, without a server around it.
+pub struct TestResolver {
+    pub resolver: Resolver,
+    db_pool: SqlitePool,
+    db_path: String,
+}
+
+impl TestResolver {
+    /// # `wait_for_cache`
+    ///
+    /// Waits for the answers of `domain` to be in the cache, as `TestApp::wait_for_cache`.
+    pub async fn wait_for_cache(&self, domain: &str) {
+        wait_for_cache(&self.db_pool, domain).await
+    }
+
+    /// # `close`
+    ///
+    /// Closes the database and removes it, needs to be called at the end of the test function.
+    pub async fn close(self) {
+        self.resolver.storage.close().await;
+        self.db_pool.close().await;
+        remove_db(&self.db_path);
+    }
+}
+
+/// # `spawn_resolver`
+///
+/// A resolver built from the settings, with a fresh cache, sending its queries
+/// with `transport` and measuring the expiration of the records against `clock`.
+pub async fn spawn_resolver(
+    transport: Arc<dyn Transport>,
+    clock: Arc<dyn Clock>,
+) -> Result<TestResolver, Box<dyn Error>> {
+    Lazy::force(&TRACING);
+    let mut settings = get_settings()?;
+    settings.set_test_db();
+    settings.validate()?;
+    let db_path = settings.get_db_path();
+    let db_pool = database::connect(&settings).await?;
+    let resolver = async {
+        database::migrate(&settings, &db_pool).await?;
+        let storage = storage::open(&settings, db_pool.clone()).await?;
+        Ok::<_, Box<dyn Error>>(
+            Resolver::from_settings(&settings, storage)
+                .await?
+                .with_transport(transport)
+                .with_clock(clock),
+        )
+    }
+    .await;
+    match resolver {
+        Ok(resolver) => Ok(TestResolver {
+            resolver,
+            db_pool,
+            db_path,
+        }),
+        Err(e) => {
+            db_pool.close().await;
+            remove_db(&db_path);
+            Err(e)
+        }
+    }
+}
+
+/// # `TestDatabase`
+///
+/// A fresh database, migrated, without a server around it.
+pub struct TestDatabase {
+    pub db_pool: SqlitePool,
+    db_path: String,
+}
+
+impl TestDatabase {
+    /// # `close`
+    ///
+    /// Closes the database and removes it, needs to be called at the end of the test function.
+    pub async fn close(self) {
+        self.db_pool.close().await;
+        remove_db(&self.db_path);
+    }
+}
+
+/// # `spawn_database`
+///
+/// A database built from the settings, migrated as the one of the test server.
+pub async fn spawn_database() -> Result<TestDatabase, Box<dyn Error>> {
+    Lazy::force(&TRACING);
+    let mut settings = get_settings()?;
+    settings.set_test_db();
+    settings.validate()?;
+    let db_path = settings.get_db_path();
+    let db_pool = database::connect(&settings).await?;
+    if let Err(e) = database::migrate(&settings, &db_pool).await {
+        db_pool.close().await;
+        remove_db(&db_path);
+        return Err(e.into());
+    }
+    Ok(TestDatabase { db_pool, db_path })
+}
+
+/// # `wait_for_cache`
+///
+/// The answers are cached in the background, waits for the ones of `domain`
+/// to be in the cache of `db_pool`; panics after a second.
+async fn wait_for_cache(db_pool: &SqlitePool, domain: &str) {
+    for _ in 0..100 {
+        let cached: bool = sqlx::query_scalar(
+            r#"SELECT EXISTS(SELECT 1 FROM entries WHERE domain = $1 COLLATE NOCASE)"#,
+        )
+        .bind(domain)
+        .fetch_one(db_pool)
+        .await
+        .expect("Failed to read the cache.");
+        if cached {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("{} hasn't been cached", domain);
 }
 
 /// # `spawn_app`
@@ -56,6 +221,27 @@ pub struct TestApp {
 /// Perform necessary cleanup if something went wrong.
 /// TODO: comments, review, refactoring, then acknoledge the tests that don't work
 pub async fn spawn_app() -> Result<TestApp, Box<dyn Error>> {
+    spawn_app_with(Vec::new()).await
+}
+
+/// # `spawn_app_with`
+///
+/// Spawns the server application as `spawn_app` does, the client queries going through
+/// `middlewares` after the ones of the settings.
+pub async fn spawn_app_with(
+    middlewares: Vec<Arc<dyn Middleware>>,
+) -> Result<TestApp, Box<dyn Error>> {
+    spawn_configured_app(|_| {}, middlewares).await
+}
+
+/// # `spawn_configured_app`
+///
+/// Spawns the server application as `spawn_app_with` does, with the settings
+/// changed by `configure`.
+pub async fn spawn_configured_app(
+    configure: impl FnOnce(&mut Settings),
+    middlewares: Vec<Arc<dyn Middleware>>,
+) -> Result<TestApp, Box<dyn Error>> {
     // The first time `initialize` is invoked the code `TRACING` is executed.
     // All other invocations will instead skip execution.
     Lazy::force(&TRACING);
@@ -67,12 +253,15 @@ pub async fn spawn_app() -> Result<TestApp, Box<dyn Error>> {
         .expect("Failed to bind to port.");
     let port = server_sock.local_addr().unwrap().port();
     let addr = format!("127.0.0.1:{}", port);
+    // Setting up the name servers
+    let nameservers = spawn_nameservers().await?;
+    settings.set_root_server(Ipv4Addr::LOCALHOST, nameservers[0].addr().port());
+    settings.add_tsig_key(TSIG_KEY_NAME, TsigAlgorithm::HmacSha256, TSIG_KEY_SECRET);
+    configure(&mut settings);
     // Setting up the database
     settings.set_test_db();
-    let db_options = SqliteConnectOptions::new()
-        .filename(&settings.get_db_path())
-        .create_if_missing(true);
-    let db_pool = match SqlitePool::connect_with(db_options).await {
+    settings.validate()?;
+    let db_pool = match database::connect(&settings).await {
         Ok(dbp) => dbp,
         Err(e) => {
             match fs::remove_file(&settings.get_db_path()) {
@@ -84,7 +273,7 @@ pub async fn spawn_app() -> Result<TestApp, Box<dyn Error>> {
             return Err(Box::new(e));
         }
     };
-    match sqlx::migrate!("./migrations").run(&db_pool).await {
+    match database::migrate(&settings, &db_pool).await {
         Ok(_) => {}
         Err(e) => {
             db_pool.close().await;
@@ -101,18 +290,47 @@ pub async fn spawn_app() -> Result<TestApp, Box<dyn Error>> {
     let cancellation_token = CancellationToken::new();
     // Spawning the test server and setting up the handle
     let handle = tokio::spawn(switch(
-        db_pool,
+        db_pool.clone(),
         server_sock,
         settings,
+        middlewares,
         cancellation_token.clone(),
     ));
     Ok(TestApp {
         addr,
         cancellation_token,
         handle,
+        nameservers,
+        db_pool,
     })
 }
 
+/// # `spawn_nameservers`
+///
+/// Spawns the fake name servers the tests resolve against, in place of the internet:
+/// a root, on `127.0.0.1`, delegating `archlinux.org` to its name server, on `127.0.0.2`
+/// and the same port, which knows the address of `wiki.archlinux.org` and the
+/// `BIG_RRSET_LEN` addresses of `big.archlinux.org`, too many for a 512 bytes response.
+async fn spawn_nameservers() -> Result<Vec<FakeNameserver>, Box<dyn Error>> {
+    let root = FakeNameserver::bind((Ipv4Addr::LOCALHOST, 0).into()).await?;
+    let authority_addr = Ipv4Addr::new(127, 0, 0, 2);
+    let authority = FakeNameserver::bind((authority_addr, root.addr().port()).into()).await?;
+    root.delegate("archlinux.org", "ns.archlinux.org", authority_addr);
+    authority.add_record(Record::A {
+        domain: "wiki.archlinux.org".into(),
+        addr: Ipv4Addr::new(95, 217, 163, 246),
+        ttl: 300,
+    });
+    for host in 0..BIG_RRSET_LEN {
+        authority.add_record(Record::A {
+            domain: "big.archlinux.org".into(),
+            addr: Ipv4Addr::new(192, 0, 2, host as u8),
+            ttl: 300,
+        });
+    }
+    Ok(vec![root, authority])
+}
+
 /// # `switch`
 ///
 /// This function allows for a gracefull shutdown in a test enviroment.
@@ -120,15 +338,43 @@ async fn switch(
     db_pool: SqlitePool,
     sock: UdpSocket,
     settings: Settings,
+    middlewares: Vec<Arc<dyn Middleware>>,
     token: CancellationToken,
 ) {
     let db_path = settings.get_db_path();
-    select! {
-        _ = token.cancelled() => {
-            db_pool.close().await;
-            fs::remove_file(db_path).expect("Failed to remove temporary db.");
+    // The configuration is never reloaded during the tests
+    let server = middlewares
+        .into_iter()
+        .fold(Server::builder(settings), |builder, middleware| {
+            builder.middleware(middleware)
+        })
+        .socket(sock)
+        .with_cache(db_pool.clone())
+        .build()
+        .await
+        .and_then(|server| Ok(server.start()?));
+    match server {
+        Ok(server) => {
+            token.cancelled().await;
+            if let Err(e) = server.shutdown().await {
+                tracing::warn!("The test server failed:\n{}", e);
+            }
         }
-        _ = run(sock, settings, db_pool.clone()) => {}
+        Err(e) => tracing::warn!("The test server failed:\n{}", e),
+    }
+    // The server leaves the pool it has been given open
+    db_pool.close().await;
+    remove_db(&db_path);
+}
+
+/// # `remove_db`
+///
+/// Removes the temporary database at `db_path`.
+fn remove_db(db_path: &str) {
+    fs::remove_file(db_path).expect("Failed to remove temporary db.");
+    // Left behind by the write-ahead log if a connection is still open
+    for suffix in ["-wal", "-shm"] {
+        let _ = fs::remove_file(format!("{}{}", db_path, suffix));
     }
 }
 
@@ -137,26 +383,35 @@ async fn switch(
 /// Get a properly configured query packet, ready to be turned into a buffer
 pub fn get_query_packet(id: u16, domain: &str) -> Packet {
     // generate query packet
-    let mut query_packet = Packet::new();
-    query_packet.header.id = id;
-    query_packet.header.recursion_desired = true;
-    query_packet.header.truncated_message = false;
-    query_packet.header.authoritative_answer = false;
-    query_packet.header.opcode = 0;
-    query_packet.header.response = false;
-    query_packet.header.rescode = ResultCode::NOERROR;
-    query_packet.header.checking_disabled = false;
+    let mut query_packet = Packet::builder()
+        .id(id)
+        .recursion_desired(true)
+        .question(domain, QueryType::A)
+        .build()
+        .expect("Failed to build the query packet.");
     query_packet.header.authed_data = true;
-    query_packet.header.z = false;
-    query_packet.header.recursion_available = false;
-    query_packet.header.questions = 1;
-    query_packet.header.answers = 0;
-    query_packet.header.authoritative_entries = 0;
-    query_packet.header.resource_entries = 0;
     query_packet
-        .questions
-        .push(Question::new(domain.to_owned(), QueryType::A));
+}
+
+/// # `padded_query`
+///
+/// The query for the address of `domain`, carrying an EDNS padding option (RFC 7830)
+/// of `padding` bytes.
+pub fn padded_query(id: u16, domain: &str, padding: usize) -> Vec<u8> {
+    let mut query_packet = get_query_packet(id, domain);
+    query_packet.resources.push(Record::OPT {
+        packet_len: 1232,
+        extended_rcode: 0,
+        version: 0,
+        flags: 0,
+        options: vec![EdnsOption {
+            code: PADDING_OPTION,
+            data: vec![0; padding],
+        }],
+    });
     query_packet
+        .to_vec()
+        .expect("Failed to generate the query buffer.")
 }
 
 /// # `get_response_packet`
@@ -172,9 +427,9 @@ pub async fn get_response_packet(
     client_sock.send(&mut query_buffer).await?;
 
     // obtaining the response
-    let mut response_buffer = BytePacketBuffer::new();
-    client_sock.recv_from(&mut response_buffer.buf).await?;
-    let response_packet = Packet::from_buffer(&mut response_buffer)?;
+    let mut response = [0; 512];
+    let (len, _) = client_sock.recv_from(&mut response).await?;
+    let response_packet = Packet::from_bytes(&response[..len])?;
     Ok(response_packet)
 }
 
@@ -1,3 +1,19 @@
+pub mod acl;
+pub mod blocklist;
+#[cfg(unix)]
+pub mod control;
+pub mod dnscrypt;
+pub mod dhcp;
+pub mod dnssec;
+pub mod doh;
+pub mod ecs;
+pub mod health;
 pub mod helpers;
+pub mod resolver;
+#[cfg(feature = "scripting")]
+pub mod scripting;
 pub mod tests_that_fail;
 pub mod tests_that_succeede;
+pub mod tsig;
+pub mod zonemd;
+pub mod zones;
@@ -0,0 +1,121 @@
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    sync::Arc,
+    time::Duration,
+};
+
+use chrono::Local;
+use dns::{
+    clock::{ManualClock, SystemClock},
+    outbound::{DnsStamp, MockTransport, TransportKind},
+    structs::{
+        header::ResultCode,
+        questions_and_records::{QueryType, Record},
+    },
+};
+
+use crate::helpers::spawn_resolver;
+
+/// # `example_org`
+///
+/// The address the mock transport answers `example.org` with.
+fn example_org(ttl: u32) -> Vec<Record> {
+    vec![Record::A {
+        domain: "example.org".into(),
+        addr: Ipv4Addr::new(192, 0, 2, 1),
+        ttl,
+    }]
+}
+
+/// # `mock_transport_resolves_without_the_network`
+///
+/// The resolver sends its queries through the mock transport, which answers them
+/// with the records it has been given, and NXDOMAIN for the others.
+#[tokio::test]
+async fn mock_transport_resolves_without_the_network() {
+    let transport = Arc::new(MockTransport::new());
+    transport.answer("example.org", QueryType::A, example_org(300));
+    let test_resolver = spawn_resolver(transport.clone(), Arc::new(SystemClock))
+        .await
+        .expect("Failed to build the resolver.");
+
+    let addrs = test_resolver
+        .resolver
+        .lookup_a("example.org")
+        .await
+        .expect("Failed to resolve example.org.");
+    assert_eq!(addrs, vec![Ipv4Addr::new(192, 0, 2, 1)]);
+    let missing = test_resolver
+        .resolver
+        .lookup("missing.example.org", QueryType::A)
+        .await
+        .expect("Failed to resolve missing.example.org.");
+    assert_eq!(missing.header.rescode, ResultCode::NXDOMAIN);
+    let queries = transport.queries();
+    assert_eq!(queries.len(), 2);
+    assert_eq!(queries[0].0, "example.org");
+    assert_eq!(queries[1].0, "missing.example.org");
+
+    test_resolver.close().await;
+}
+
+/// # `cached_records_expire_with_the_clock`
+///
+/// A record is answered from the cache until its TTL has gone by on the clock
+/// of the resolver, it's asked for again after that.
+#[tokio::test]
+async fn cached_records_expire_with_the_clock() {
+    let transport = Arc::new(MockTransport::new());
+    transport.answer("example.org", QueryType::A, example_org(300));
+    let clock = Arc::new(ManualClock::new(Local::now()));
+    let test_resolver = spawn_resolver(transport.clone(), clock.clone())
+        .await
+        .expect("Failed to build the resolver.");
+    let resolver = &test_resolver.resolver;
+
+    resolver
+        .lookup_a("example.org")
+        .await
+        .expect("Failed to resolve example.org.");
+    test_resolver.wait_for_cache("example.org").await;
+    assert_eq!(transport.queries().len(), 1);
+
+    // Still valid a second before it expires
+    clock.advance(Duration::from_secs(299));
+    let cached = resolver
+        .lookup_a("example.org")
+        .await
+        .expect("Failed to resolve example.org from the cache.");
+    assert_eq!(cached, vec![Ipv4Addr::new(192, 0, 2, 1)]);
+    assert_eq!(transport.queries().len(), 1);
+
+    clock.advance(Duration::from_secs(2));
+    resolver
+        .lookup_a("example.org")
+        .await
+        .expect("Failed to resolve example.org again.");
+    assert_eq!(transport.queries().len(), 2);
+
+    test_resolver.close().await;
+}
+
+/// # `stamps_reach_the_forwarders_over_ipv6`
+///
+/// The stamp of a DNS over TLS server on IPv6 is a forwarder, the one of a
+/// DNS over HTTPS server isn't, there's no transport for it.
+#[test]
+fn stamps_reach_the_forwarders_over_ipv6() {
+    let tls: DnsStamp = "sdns://AwAAAAAAAAAADVsyNjIwOmZlOjpmZV0ADWRucy5xdWFkOS5uZXQ"
+        .parse()
+        .expect("Failed to parse the stamp.");
+    let (addr, kind, tls_name) = tls.forwarder().expect("The stamp isn't a forwarder.");
+    assert_eq!(addr, "2620:fe::fe".parse::<IpAddr>().unwrap());
+    assert_eq!(kind, TransportKind::Tls);
+    assert_eq!(tls_name.as_deref(), Some("dns.quad9.net"));
+
+    let https: DnsStamp = "sdns://AgAAAAAAAAAABzkuOS45LjkADWRucy5xdWFkOS5uZXQKL2Rucy1xdWVyeQ"
+        .parse()
+        .expect("Failed to parse the stamp.");
+    assert_eq!(https.path(), Some("/dns-query"));
+    assert!(https.forwarder().is_err());
+}
@@ -0,0 +1,99 @@
+use std::{net::Ipv4Addr, sync::Arc};
+
+use dns::{
+    scripting::Script,
+    structs::{header::ResultCode, packet::Packet, questions_and_records::Record},
+};
+
+use crate::helpers::{get_client_sock, get_query_packet, get_response_packet, spawn_app_with};
+
+/// # `resolve_with_script`
+///
+/// Asks a server running the script `source` for the address of `domain`.
+async fn resolve_with_script(source: &str, domain: &str) -> Packet {
+    let script = Script::from_source("test.lua", source).expect("Failed to load the script.");
+    let test_app = spawn_app_with(vec![Arc::new(script)])
+        .await
+        .expect("Failed to spawn the app.");
+    let client_sock = get_client_sock(&test_app.addr).await;
+    let query_buffer = get_query_packet(1234, domain)
+        .to_vec()
+        .expect("Failed to generate the query buffer.");
+    let response = get_response_packet(client_sock, &query_buffer)
+        .await
+        .expect("Failed to get the response packet");
+    // Graceful shutdown
+    test_app.cancellation_token.cancel();
+    let _ = test_app.handle.await;
+    response
+}
+
+/// # `scripts_rename_the_queries`
+///
+/// A query renamed by `on_query` is resolved for the new name, and answered for the
+/// name the client asked for.
+#[tokio::test]
+async fn scripts_rename_the_queries() {
+    let source = r#"
+        function on_query(query)
+            if query.name == "alias.archlinux.org" then
+                return { name = "wiki.archlinux.org" }
+            end
+        end
+    "#;
+    let response = resolve_with_script(source, "alias.archlinux.org").await;
+    assert_eq!(response.header.rescode, ResultCode::NOERROR);
+    assert_eq!(response.questions[0].qname, "alias.archlinux.org");
+    match &response.answers[0] {
+        Record::A { domain, addr, .. } => {
+            assert_eq!(domain, "alias.archlinux.org");
+            assert_eq!(*addr, Ipv4Addr::new(95, 217, 163, 246));
+        }
+        answer => panic!("Unexpected answer: {:?}", answer),
+    }
+}
+
+/// # `scripts_answer_the_queries`
+///
+/// A query answered by `on_query` gets the records of the script, written for the name
+/// asked for when they have none.
+#[tokio::test]
+async fn scripts_answer_the_queries() {
+    let source = r#"
+        function on_query(query)
+            return { rcode = "NOERROR", answers = { { type = "A", data = "192.0.2.53" } } }
+        end
+    "#;
+    let response = resolve_with_script(source, "scripted.archlinux.org").await;
+    assert_eq!(response.header.rescode, ResultCode::NOERROR);
+    assert_eq!(response.answers.len(), 1);
+    match &response.answers[0] {
+        Record::A { domain, addr, .. } => {
+            assert_eq!(domain, "scripted.archlinux.org");
+            assert_eq!(*addr, Ipv4Addr::new(192, 0, 2, 53));
+        }
+        answer => panic!("Unexpected answer: {:?}", answer),
+    }
+}
+
+/// # `scripts_are_stopped_past_their_instruction_budget`
+///
+/// A hook that doesn't return is stopped, the query is then answered as if there
+/// were no script.
+#[tokio::test]
+async fn scripts_are_stopped_past_their_instruction_budget() {
+    let source = r#"
+        function on_query(query)
+            while true do end
+        end
+    "#;
+    let response = resolve_with_script(source, "wiki.archlinux.org").await;
+    assert_eq!(response.header.rescode, ResultCode::NOERROR);
+    match &response.answers[0] {
+        Record::A { domain, addr, .. } => {
+            assert_eq!(domain, "wiki.archlinux.org");
+            assert_eq!(*addr, Ipv4Addr::new(95, 217, 163, 246));
+        }
+        answer => panic!("Unexpected answer: {:?}", answer),
+    }
+}
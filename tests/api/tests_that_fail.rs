@@ -1,9 +1,19 @@
 use std::time::Duration;
 
-use dns::structs::{buffer::BytePacketBuffer, header::ResultCode};
+use dns::{
+    structs::{
+        buffer::BytePacketBuffer,
+        header::{OpCode, ResultCode},
+        questions_and_records::QueryType,
+    },
+    tsig::{sign, TsigAlgorithm, TsigKey},
+    udp::MAX_UDP_QUERY,
+};
 use tokio::{select, time::sleep};
 
-use crate::helpers::{get_client_sock, get_query_packet, get_response_packet, spawn_app};
+use crate::helpers::{
+    get_client_sock, get_query_packet, get_response_packet, padded_query, spawn_app,
+};
 
 /// # `sending_a_non_properly_formatted_response_field`
 ///
@@ -26,13 +36,12 @@ async fn sending_a_non_properly_formatted_response_field() {
     // wrong header's field
     query_packet.header.response = true;
     // generate query buffer
-    let mut query_buffer = BytePacketBuffer::new();
-    query_packet
-        .write(&mut query_buffer)
+    let query_buffer = query_packet
+        .to_vec()
         .expect("Failed to generate the query buffer.");
     // send packet and obtaining nothing in response
     let responded = select! {
-        _ = get_response_packet(client_sock, &query_buffer.buf) => {
+        _ = get_response_packet(client_sock, &query_buffer) => {
             true
         }
         _ = sleep(Duration::from_secs(1)) => {
@@ -66,12 +75,11 @@ async fn recursion_desired_false_fails_if_no_cached_entry() {
     // erroneous field
     query_packet.header.recursion_desired = false;
     // generate query buffer
-    let mut query_buffer = BytePacketBuffer::new();
-    query_packet
-        .write(&mut query_buffer)
+    let query_buffer = query_packet
+        .to_vec()
         .expect("Failed to generate the query buffer.");
     // send packet and obtaining the response
-    let response_packet = get_response_packet(client_sock, &query_buffer.buf)
+    let response_packet = get_response_packet(client_sock, &query_buffer)
         .await
         .expect("Failed to get the response packet");
 
@@ -135,3 +143,100 @@ async fn send_unsctructured_too_long_packet() {
     test_app.cancellation_token.cancel();
     let _ = test_app.handle.await;
 }
+
+/// # `notify_from_unknown_primary_is_refused`
+///
+/// A NOTIFY sent by a server that isn't a configured primary is answered with `ResultCode::REFUSED`.
+#[tokio::test]
+async fn notify_from_unknown_primary_is_refused() {
+    // arrangement
+    let test_app = spawn_app().await.expect("Failed to spawn the app.");
+    let client_sock = get_client_sock(&test_app.addr).await;
+
+    // preparing packet
+    let id = 999;
+    let mut query_packet = get_query_packet(id, "example.com");
+    query_packet.header.opcode = OpCode::NOTIFY.to_num();
+    query_packet.header.recursion_desired = false;
+    query_packet.questions[0].qtype = QueryType::SOA;
+    let query_buffer = query_packet
+        .to_vec()
+        .expect("Failed to generate the query buffer.");
+    let response_packet = get_response_packet(client_sock, &query_buffer)
+        .await
+        .expect("Failed to get the response packet");
+
+    // asserts
+    assert_eq!(id, response_packet.header.id);
+    assert!(response_packet.header.response);
+    assert_eq!(
+        OpCode::from_num(response_packet.header.opcode),
+        OpCode::NOTIFY
+    );
+    assert_eq!(response_packet.header.rescode, ResultCode::REFUSED);
+
+    // Cleanup
+    test_app.cancellation_token.cancel();
+    let _ = test_app.handle.await;
+}
+
+/// # `query_signed_with_unknown_tsig_key_is_rejected`
+///
+/// A query signed with a TSIG key the server doesn't know is answered with `ResultCode::NOTAUTH`.
+#[tokio::test]
+async fn query_signed_with_unknown_tsig_key_is_rejected() {
+    // arrangement
+    let test_app = spawn_app().await.expect("Failed to spawn the app.");
+    let client_sock = get_client_sock(&test_app.addr).await;
+
+    // preparing packet
+    let id = 999;
+    let mut query_packet = get_query_packet(id, "wiki.archlinux.org");
+    let mut query_buffer = BytePacketBuffer::new();
+    query_packet
+        .write(&mut query_buffer)
+        .expect("Failed to generate the query buffer.");
+    let key = TsigKey::new("unknown-key", TsigAlgorithm::HmacSha256, "c2VjcmV0")
+        .expect("Failed to create the key.");
+    sign(&mut query_buffer, &key, None).expect("Failed to sign the query.");
+    let response_packet =
+        get_response_packet(client_sock, &query_buffer.buf[0..query_buffer.pos()])
+            .await
+            .expect("Failed to get the response packet");
+
+    // asserts
+    assert_eq!(id, response_packet.header.id);
+    assert_eq!(response_packet.header.rescode, ResultCode::NOTAUTH);
+
+    // Cleanup
+    test_app.cancellation_token.cancel();
+    let _ = test_app.handle.await;
+}
+
+/// # `oversized_datagrams_are_dropped`
+///
+/// A query larger than the datagrams the server accepts would reach it cut,
+/// it gets no response.
+#[tokio::test]
+async fn oversized_datagrams_are_dropped() {
+    // arrangement
+    let test_app = spawn_app().await.expect("Failed to spawn the app.");
+    let client_sock = get_client_sock(&test_app.addr).await;
+    let query_buffer = padded_query(1234, "wiki.archlinux.org", MAX_UDP_QUERY);
+
+    // send packet and obtaining nothing in response
+    let responded = select! {
+        _ = get_response_packet(client_sock, &query_buffer) => {
+            true
+        }
+        _ = sleep(Duration::from_secs(1)) => {
+            false
+        }
+    };
+
+    // asserts
+    assert!(!responded);
+
+    test_app.cancellation_token.cancel();
+    let _ = test_app.handle.await;
+}
@@ -1,8 +1,11 @@
 use core::panic;
+use std::net::Ipv4Addr;
 
-use dns::structs::{buffer::BytePacketBuffer, header::ResultCode};
+use dns::structs::{header::ResultCode, packet::Packet, questions_and_records::Record};
 
-use crate::helpers::{get_client_sock, get_query_packet, get_response_packet, spawn_app};
+use crate::helpers::{
+    get_client_sock, get_query_packet, get_response_packet, padded_query, spawn_app, BIG_RRSET_LEN,
+};
 
 #[tokio::test]
 /// # `sending_a_properly_formatted_query`
@@ -18,14 +21,13 @@ async fn sending_a_properly_formatted_query() {
     let id = 999;
     let query_domain = "wiki.archlinux.org";
     // generate buffer
-    let mut query_packet = get_query_packet(id, query_domain);
+    let query_packet = get_query_packet(id, query_domain);
     // generate query buffer
-    let mut query_buffer = BytePacketBuffer::new();
-    query_packet
-        .write(&mut query_buffer)
+    let query_buffer = query_packet
+        .to_vec()
         .expect("Failed to generate the query buffer.");
     // send packet and obtaining the response
-    let response_packet = get_response_packet(client_sock, &query_buffer.buf)
+    let response_packet = get_response_packet(client_sock, &query_buffer)
         .await
         .expect("Failed to get the response packet");
 
@@ -78,16 +80,16 @@ async fn recursion_desired_false_succeeds() {
     // generate buffer
     let mut query_packet = get_query_packet(id, query_domain);
     // generate query buffer
-    let mut query_buffer = BytePacketBuffer::new();
-    query_packet
-        .write(&mut query_buffer)
+    let query_buffer = query_packet
+        .to_vec()
         .expect("Failed to generate the query buffer.");
     // send packet and obtaining the response
-    let response_packet = get_response_packet(client_sock, &query_buffer.buf)
+    let response_packet = get_response_packet(client_sock, &query_buffer)
         .await
         .expect("Failed to get the response packet");
     // Assert a correct response packet has returned
     assert_eq!(response_packet.header.rescode, ResultCode::NOERROR);
+    test_app.wait_for_cache(query_domain).await;
 
     // New client sock
     let client_sock = get_client_sock(&test_app.addr).await;
@@ -95,12 +97,11 @@ async fn recursion_desired_false_succeeds() {
     // erroneous field
     query_packet.header.recursion_desired = false;
     // generate query buffer
-    let mut query_buffer = BytePacketBuffer::new();
-    query_packet
-        .write(&mut query_buffer)
+    let query_buffer = query_packet
+        .to_vec()
         .expect("Failed to generate the query buffer.");
     // send packet and obtaining the response
-    let cached_response_packet = get_response_packet(client_sock, &query_buffer.buf)
+    let cached_response_packet = get_response_packet(client_sock, &query_buffer)
         .await
         .expect("Failed to get the response packet");
     // the id is the same of the one from the query
@@ -145,3 +146,119 @@ async fn recursion_desired_false_succeeds() {
     test_app.cancellation_token.cancel();
     let _ = test_app.handle.await;
 }
+
+/// # `large_responses_are_truncated_over_udp`
+///
+/// A response that doesn't fit in 512 bytes reaches a client without EDNS truncated,
+/// with TC set, while a client advertising a larger payload size gets all of it.
+#[tokio::test]
+async fn large_responses_are_truncated_over_udp() {
+    // arrangement
+    let test_app = spawn_app().await.expect("Failed to spawn the app.");
+    let query_domain = "big.archlinux.org";
+
+    // Without EDNS the response is cut to 512 bytes
+    let client_sock = get_client_sock(&test_app.addr).await;
+    let query_buffer = get_query_packet(999, query_domain)
+        .to_vec()
+        .expect("Failed to generate the query buffer.");
+    let response_packet = get_response_packet(client_sock, &query_buffer)
+        .await
+        .expect("Failed to get the response packet");
+    assert_eq!(response_packet.header.rescode, ResultCode::NOERROR);
+    assert!(response_packet.header.truncated_message);
+    assert!(response_packet.answers.is_empty());
+    assert_eq!(response_packet.questions.len(), 1);
+
+    // Advertising 4096 bytes, the whole RRset fits
+    let client_sock = get_client_sock(&test_app.addr).await;
+    let mut query_packet = get_query_packet(1000, query_domain);
+    query_packet.resources.push(Record::OPT {
+        packet_len: 4096,
+        extended_rcode: 0,
+        version: 0,
+        flags: 0,
+        options: Vec::new(),
+    });
+    let query_buffer = query_packet
+        .to_vec()
+        .expect("Failed to generate the query buffer.");
+    client_sock
+        .send(&query_buffer)
+        .await
+        .expect("Failed to send the query.");
+    let mut response = [0; 4096];
+    let len = client_sock
+        .recv(&mut response)
+        .await
+        .expect("Failed to receive the response.");
+    assert!(len > 512);
+    let response_packet = Packet::from_bytes(&response[..len]).expect("Malformed response.");
+    assert!(!response_packet.header.truncated_message);
+    assert_eq!(response_packet.answers.len(), BIG_RRSET_LEN);
+
+    // Graceful shutdown
+    test_app.cancellation_token.cancel();
+    let _ = test_app.handle.await;
+}
+
+/// # `top_level_domains_are_resolved_by_default`
+///
+/// With the default configuration a query for a bare top level domain is resolved
+/// as any other name, not refused.
+#[tokio::test]
+async fn top_level_domains_are_resolved_by_default() {
+    // arrangement
+    let test_app = spawn_app().await.expect("Failed to spawn the app.");
+    test_app.nameservers[0].add_record(Record::A {
+        domain: "org".into(),
+        addr: Ipv4Addr::new(192, 0, 2, 7),
+        ttl: 300,
+    });
+    let client_sock = get_client_sock(&test_app.addr).await;
+    let query_buffer = get_query_packet(1234, "org")
+        .to_vec()
+        .expect("Failed to generate the query buffer.");
+
+    let response_packet = get_response_packet(client_sock, &query_buffer)
+        .await
+        .expect("Failed to get the response packet");
+
+    assert_eq!(response_packet.header.rescode, ResultCode::NOERROR);
+    match &response_packet.answers[0] {
+        Record::A { domain, addr, .. } => {
+            assert_eq!(domain, "org");
+            assert_eq!(*addr, Ipv4Addr::new(192, 0, 2, 7));
+        }
+        answer => panic!("Unexpected answer: {:?}", answer),
+    }
+
+    // Graceful shutdown
+    test_app.cancellation_token.cancel();
+    let _ = test_app.handle.await;
+}
+
+/// # `large_queries_are_received_whole`
+///
+/// A query larger than 512 bytes, padded as the EDNS clients may do (RFC 7830),
+/// is received whole and answered.
+#[tokio::test]
+async fn large_queries_are_received_whole() {
+    // arrangement
+    let test_app = spawn_app().await.expect("Failed to spawn the app.");
+    let client_sock = get_client_sock(&test_app.addr).await;
+    let query_buffer = padded_query(1234, "wiki.archlinux.org", 1500);
+    assert!(query_buffer.len() > 1500);
+
+    let response_packet = get_response_packet(client_sock, &query_buffer)
+        .await
+        .expect("Failed to get the response packet");
+
+    assert_eq!(response_packet.header.id, 1234);
+    assert_eq!(response_packet.header.rescode, ResultCode::NOERROR);
+    assert!(!response_packet.answers.is_empty());
+
+    // Graceful shutdown
+    test_app.cancellation_token.cancel();
+    let _ = test_app.handle.await;
+}
@@ -0,0 +1,133 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use dns::{
+    structs::{buffer::BytePacketBuffer, header::ResultCode, packet::Packet},
+    tsig::{find_tsig, sign, verify_with, TsigAlgorithm, TsigError, TsigKey},
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::helpers::{
+    get_client_sock, get_query_packet, spawn_app, TSIG_KEY_NAME, TSIG_KEY_SECRET,
+};
+
+/// # `exchange`
+///
+/// Sends the signed query in `query` to the test server, returns the raw response.
+async fn exchange(query: &[u8]) -> Vec<u8> {
+    let test_app = spawn_app().await.expect("Failed to spawn the app.");
+    let client_sock = get_client_sock(&test_app.addr).await;
+    client_sock
+        .send(query)
+        .await
+        .expect("Failed to send the query.");
+    let mut response = vec![0; 512];
+    let len = client_sock
+        .recv(&mut response)
+        .await
+        .expect("Failed to receive the response.");
+    response.truncate(len);
+    // Graceful shutdown
+    test_app.cancellation_token.cancel();
+    let _ = test_app.handle.await;
+    response
+}
+
+/// # `query`
+///
+/// The query for the address of `wiki.archlinux.org`, unsigned.
+fn query(id: u16) -> BytePacketBuffer {
+    let mut buffer = BytePacketBuffer::empty();
+    get_query_packet(id, "wiki.archlinux.org")
+        .write(&mut buffer)
+        .expect("Failed to generate the query buffer.");
+    buffer
+}
+
+/// # `sign_at`
+///
+/// Signs the query in `buffer` with the key of the test server as if it were
+/// `time_signed`, the way `tsig::sign` would have then (RFC 8945 section 4.3).
+fn sign_at(buffer: &mut BytePacketBuffer, time_signed: u64) -> Vec<u8> {
+    let id = u16::from_be_bytes([buffer.buf[0], buffer.buf[1]]);
+    let mut variables = BytePacketBuffer::empty();
+    variables.write_qname(TSIG_KEY_NAME).unwrap();
+    variables.write_u16(255).unwrap();
+    variables.write_u32(0).unwrap();
+    variables.write_qname("hmac-sha256").unwrap();
+    variables.write_u16((time_signed >> 32) as u16).unwrap();
+    variables.write_u32(time_signed as u32).unwrap();
+    variables.write_u16(300).unwrap();
+    // No error, no other data
+    variables.write_u32(0).unwrap();
+    let secret = STANDARD.decode(TSIG_KEY_SECRET).unwrap();
+    let mut mac = Hmac::<Sha256>::new_from_slice(&secret).unwrap();
+    mac.update(buffer.written());
+    mac.update(variables.written());
+    let mac = mac.finalize().into_bytes().to_vec();
+
+    buffer.write_qname(TSIG_KEY_NAME).unwrap();
+    buffer.write_u16(250).unwrap();
+    buffer.write_u16(255).unwrap();
+    buffer.write_u32(0).unwrap();
+    let mut data = BytePacketBuffer::empty();
+    data.write_qname("hmac-sha256").unwrap();
+    data.write_u16((time_signed >> 32) as u16).unwrap();
+    data.write_u32(time_signed as u32).unwrap();
+    data.write_u16(300).unwrap();
+    data.write_u16(mac.len() as u16).unwrap();
+    data.write_bytes(&mac).unwrap();
+    data.write_u16(id).unwrap();
+    data.write_u32(0).unwrap();
+    buffer.write_u16(data.pos() as u16).unwrap();
+    buffer.write_bytes(data.written()).unwrap();
+    buffer.set_u16(10, 1).unwrap();
+    mac
+}
+
+/// # `queries_with_a_bad_mac_are_rejected_with_badsig`
+///
+/// A query signed with the name of a known key but another secret is answered with
+/// NOTAUTH and a TSIG carrying BADSIG and no MAC, the key can't vouch for the response.
+#[tokio::test]
+async fn queries_with_a_bad_mac_are_rejected_with_badsig() {
+    let mut query = query(4321);
+    let impostor = TsigKey::new(TSIG_KEY_NAME, TsigAlgorithm::HmacSha256, "aW1wb3N0b3I=")
+        .expect("Failed to create the key.");
+    sign(&mut query, &impostor, None).expect("Failed to sign the query.");
+
+    let response = exchange(query.written()).await;
+    let packet = Packet::from_bytes(&response).expect("Failed to parse the response.");
+    assert_eq!(packet.header.id, 4321);
+    assert_eq!(packet.header.rescode, ResultCode::NOTAUTH);
+    let tsig = find_tsig(&response)
+        .expect("Failed to read the TSIG.")
+        .expect("The rejection isn't signed.");
+    assert_eq!(tsig.error, TsigError::BadSig as u16);
+    assert_eq!(tsig.key_name, TSIG_KEY_NAME);
+    assert!(tsig.mac.is_empty());
+}
+
+/// # `queries_signed_long_ago_are_rejected_with_badtime`
+///
+/// A query whose MAC is valid but that has been signed outside of the fudge is answered
+/// with NOTAUTH and a TSIG carrying BADTIME and the time of the server, signed
+/// with the key of the query.
+#[tokio::test]
+async fn queries_signed_long_ago_are_rejected_with_badtime() {
+    let mut query = query(1234);
+    let an_hour_ago = chrono::Utc::now().timestamp() as u64 - 3600;
+    let request_mac = sign_at(&mut query, an_hour_ago);
+
+    let response = exchange(query.written()).await;
+    let packet = Packet::from_bytes(&response).expect("Failed to parse the response.");
+    assert_eq!(packet.header.id, 1234);
+    assert_eq!(packet.header.rescode, ResultCode::NOTAUTH);
+    let tsig = find_tsig(&response)
+        .expect("Failed to read the TSIG.")
+        .expect("The rejection isn't signed.");
+    assert_eq!(tsig.error, TsigError::BadTime as u16);
+    assert_eq!(tsig.other.len(), 6);
+    let key = TsigKey::new(TSIG_KEY_NAME, TsigAlgorithm::HmacSha256, TSIG_KEY_SECRET).unwrap();
+    verify_with(&response, &tsig, &key, Some(&request_mac), &[], false)
+        .expect("Failed to verify the rejection.");
+}
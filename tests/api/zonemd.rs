@@ -0,0 +1,119 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use dns::{
+    structs::{auxiliaries::DnsError, questions_and_records::Record},
+    zones::zonemd::{verify, ZONEMD},
+};
+
+/// Serial of the zone of `simple_example`.
+const SERIAL: u32 = 2018031900;
+/// SHA-384 digest of the zone of `simple_example`, from RFC 8976 Appendix A.1.
+const SIMPLE_DIGEST: &str = "c68090d90a7aed716bc459f9340e3d7c1370d4d24b7e2fc3\
+                             a1ddc0b9a87153b9a9713b3c9ae5cc27777f98b8e730044c";
+
+/// # `zonemd`
+///
+/// A ZONEMD of `example` for `serial`, of the simple scheme with `algorithm`.
+fn zonemd(serial: u32, algorithm: u8, digest: &str) -> Record {
+    let mut data = serial.to_be_bytes().to_vec();
+    data.extend([1, algorithm]);
+    data.extend(
+        (0..digest.len()).step_by(2).map(|i| {
+            u8::from_str_radix(&digest[i..i + 2], 16).expect("Failed to read the digest.")
+        }),
+    );
+    Record::UNKNOWN {
+        domain: "example".into(),
+        qtype: ZONEMD,
+        data,
+        ttl: 86400,
+    }
+}
+
+/// # `simple_example`
+///
+/// The zone of RFC 8976 Appendix A.1, along with `zonemd`.
+fn simple_example(zonemd: Vec<Record>) -> Vec<Record> {
+    let mut records = vec![
+        Record::SOA {
+            domain: "example".into(),
+            mname: "ns1.example".into(),
+            rname: "admin.example".into(),
+            serial: SERIAL,
+            refresh: 1800,
+            retry: 900,
+            expire: 604800,
+            minimum: 86400,
+            ttl: 86400,
+        },
+        Record::NS {
+            domain: "example".into(),
+            host: "ns1.example".into(),
+            ttl: 86400,
+        },
+        Record::NS {
+            domain: "example".into(),
+            host: "ns2.example".into(),
+            ttl: 86400,
+        },
+        Record::A {
+            domain: "ns1.example".into(),
+            addr: Ipv4Addr::new(203, 0, 113, 63),
+            ttl: 3600,
+        },
+        Record::AAAA {
+            domain: "ns2.example".into(),
+            addr: Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0x63),
+            ttl: 3600,
+        },
+    ];
+    records.extend(zonemd);
+    records
+}
+
+/// # `the_simple_example_of_rfc_8976_verifies`
+///
+/// The digest of the example zone is the one published in the RFC, and it no longer
+/// matches once a record has been altered.
+#[test]
+fn the_simple_example_of_rfc_8976_verifies() {
+    let mut zone = simple_example(vec![zonemd(SERIAL, 1, SIMPLE_DIGEST)]);
+    verify("example", &zone).expect("Failed to verify the example zone.");
+
+    zone[3] = Record::A {
+        domain: "ns1.example".into(),
+        addr: Ipv4Addr::new(203, 0, 113, 64),
+        ttl: 3600,
+    };
+    assert!(matches!(verify("example", &zone), Err(DnsError::Zonemd(_))));
+}
+
+/// # `a_zonemd_of_another_serial_is_refused`
+///
+/// The ZONEMD has to refer to the serial of the SOA, the digest of another version
+/// of the zone doesn't vouch for this one.
+#[test]
+fn a_zonemd_of_another_serial_is_refused() {
+    let zone = simple_example(vec![zonemd(SERIAL - 1, 1, SIMPLE_DIGEST)]);
+    assert!(matches!(verify("example", &zone), Err(DnsError::Zonemd(_))));
+}
+
+/// # `duplicate_schemes_and_algorithms_are_refused`
+///
+/// A zone can't publish two ZONEMD with the same scheme and algorithm (RFC 8976
+/// section 2.4), even if one of them matches; the unsupported ones are left out.
+#[test]
+fn duplicate_schemes_and_algorithms_are_refused() {
+    let zone = simple_example(vec![
+        zonemd(SERIAL, 1, SIMPLE_DIGEST),
+        zonemd(SERIAL, 1, &"00".repeat(48)),
+    ]);
+    assert!(matches!(verify("example", &zone), Err(DnsError::Zonemd(_))));
+
+    let zone = simple_example(vec![
+        zonemd(SERIAL, 1, SIMPLE_DIGEST),
+        zonemd(SERIAL, 240, "00"),
+        zonemd(SERIAL, 240, "00"),
+    ]);
+    verify("example", &zone).expect("Failed to leave the unsupported algorithms out.");
+}
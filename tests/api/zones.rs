@@ -0,0 +1,158 @@
+use std::net::{Ipv4Addr, SocketAddr};
+
+use dns::{
+    structs::{
+        buffer::BytePacketBuffer,
+        packet::Packet,
+        questions_and_records::{QueryType, Question, Record},
+    },
+    zones::{
+        serial_is_newer,
+        transfer::{ixfr, Transfer},
+        Zone,
+    },
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+/// Type of the TXT records, not parsed, they reach us as `Record::UNKNOWN`.
+const TXT: u16 = 16;
+
+fn soa(serial: u32) -> Record {
+    Record::SOA {
+        domain: "example.org".into(),
+        mname: "ns1.example.org".into(),
+        rname: "admin.example.org".into(),
+        serial,
+        refresh: 3600,
+        retry: 600,
+        expire: 604800,
+        minimum: 300,
+        ttl: 3600,
+    }
+}
+
+fn www(host: u8) -> Record {
+    Record::A {
+        domain: "www.example.org".into(),
+        addr: Ipv4Addr::new(192, 0, 2, host),
+        ttl: 300,
+    }
+}
+
+fn txt(text: &str) -> Record {
+    let mut data = vec![text.len() as u8];
+    data.extend_from_slice(text.as_bytes());
+    Record::UNKNOWN {
+        domain: "example.org".into(),
+        qtype: TXT,
+        data,
+        ttl: 300,
+    }
+}
+
+/// # `primary`
+///
+/// A primary answering the first transfer asked for with `answers`, in a single message.
+async fn primary(answers: Vec<Record>) -> SocketAddr {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .await
+        .expect("Failed to bind the primary.");
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.expect("Failed to accept.");
+        let len = stream.read_u16().await.expect("Failed to read the query.") as usize;
+        let mut req_buffer = BytePacketBuffer::with_size(len);
+        stream.read_exact(&mut req_buffer.buf).await.unwrap();
+        let request = Packet::from_buffer(&mut req_buffer).expect("Failed to parse the query.");
+        let mut response = Packet::new();
+        response.header.id = request.header.id;
+        response.header.response = true;
+        response.header.authoritative_answer = true;
+        response.questions = request.questions;
+        response.answers = answers;
+        let mut res_buffer = BytePacketBuffer::empty();
+        response.write(&mut res_buffer).unwrap();
+        stream.write_u16(res_buffer.pos() as u16).await.unwrap();
+        stream.write_all(res_buffer.written()).await.unwrap();
+    });
+    addr
+}
+
+/// # `serials_compare_with_serial_number_arithmetic`
+///
+/// The serials wrap around (RFC 1982), a serial is newer than the ones up to 2^31 - 1
+/// behind it.
+#[test]
+fn serials_compare_with_serial_number_arithmetic() {
+    assert!(serial_is_newer(2, 1));
+    assert!(!serial_is_newer(1, 2));
+    assert!(!serial_is_newer(7, 7));
+    assert!(serial_is_newer(0, u32::MAX));
+    assert!(serial_is_newer(5, u32::MAX - 5));
+    assert!(!serial_is_newer(u32::MAX, 0));
+    assert!(serial_is_newer(i32::MAX as u32, 0));
+    assert!(!serial_is_newer(i32::MAX as u32 + 2, 0));
+}
+
+/// # `zones_keep_the_records_of_unknown_types`
+///
+/// The records we don't parse, as TXT, are kept in the zone and answered.
+#[test]
+fn zones_keep_the_records_of_unknown_types() {
+    let zone = Zone::from_records("example.org", vec![soa(1), www(1), txt("v=spf1 -all")])
+        .expect("Failed to build the zone.");
+    let response = zone.answer(&Question::new(
+        "example.org".to_string(),
+        QueryType::UNKNOWN(TXT),
+    ));
+    assert_eq!(response.answers, vec![txt("v=spf1 -all")]);
+}
+
+/// # `ixfr_applies_the_differences`
+///
+/// The records deleted by an incremental transfer leave the zone and the ones added
+/// join it, the records of unknown types as well.
+#[tokio::test]
+async fn ixfr_applies_the_differences() {
+    let current = Zone::from_records("example.org", vec![soa(1), www(1), txt("old")])
+        .expect("Failed to build the zone.");
+    let addr = primary(vec![
+        soa(2),
+        soa(1),
+        www(1),
+        txt("old"),
+        soa(2),
+        www(2),
+        txt("new"),
+        soa(2),
+    ])
+    .await;
+
+    let Transfer::Updated(zone) = ixfr(&current, addr, None)
+        .await
+        .expect("Failed to transfer the zone.")
+    else {
+        panic!("The zone hasn't been updated");
+    };
+    assert_eq!(zone.serial(), 2);
+    assert_eq!(zone.records.len(), 2);
+    assert!(zone.records.contains(&www(2)));
+    assert!(zone.records.contains(&txt("new")));
+}
+
+/// # `ixfr_of_the_current_serial_is_up_to_date`
+///
+/// A primary sending back only our SOA has nothing new.
+#[tokio::test]
+async fn ixfr_of_the_current_serial_is_up_to_date() {
+    let current =
+        Zone::from_records("example.org", vec![soa(1), www(1)]).expect("Failed to build the zone.");
+    let addr = primary(vec![soa(1)]).await;
+    let transfer = ixfr(&current, addr, None)
+        .await
+        .expect("Failed to transfer the zone.");
+    assert!(matches!(transfer, Transfer::UpToDate));
+}